@@ -1,9 +1,21 @@
 use std::process::exit;
 
 fn real_main() -> Result<(), String> {
+    // The descriptors of the services served by grpc-as are embedded for
+    // gRPC reflection.
     #[cfg(feature = "grpc-bin")]
-    tonic_build::compile_protos("../protos/attestation.proto").map_err(|e| format!("{e}"))?;
+    {
+        let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+        tonic_build::configure()
+            .file_descriptor_set_path(out_dir.join("grpc_as_descriptor.bin"))
+            .compile(
+                &["../protos/attestation.proto", "../protos/reference.proto"],
+                &["../protos"],
+            )
+            .map_err(|e| format!("{e}"))?;
+    }
 
+    #[cfg(not(feature = "grpc-bin"))]
     tonic_build::compile_protos("../protos/reference.proto").map_err(|e| format!("{e}"))?;
 
     println!("cargo:rustc-link-lib=python3.11");
@@ -1,6 +1,10 @@
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::Path;
 
 use anyhow::Result;
+use attestation_service::logging::{self, LogFormat};
+use attestation_service::{config::Config, AttestationService};
 use clap::Parser;
 use log::info;
 use shadow_rs::shadow;
@@ -13,6 +17,9 @@ pub mod rvps_api {
     tonic::include_proto!("reference");
 }
 
+/// Descriptors of the services above, served through gRPC reflection.
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("grpc_as_descriptor");
+
 shadow!(build);
 
 mod grpc;
@@ -28,11 +35,39 @@ pub struct Cli {
     /// Socket that the server will listen on to accept requests.
     #[arg(short, long, default_value = "127.0.0.1:3000")]
     pub socket: SocketAddr,
+
+    /// Maximum number of attestations evaluated at the same time.
+    #[arg(long, env = "AS_MAX_CONCURRENT_EVALUATIONS", default_value = "64")]
+    pub max_concurrent_evaluations: NonZeroUsize,
+
+    /// Maximum number of attestations waiting for an evaluation slot. The
+    /// further ones fail with RESOURCE_EXHAUSTED.
+    #[arg(long, env = "AS_MAX_QUEUED_EVALUATIONS", default_value_t = 256)]
+    pub max_queued_evaluations: usize,
+
+    /// Validate the config file and exit instead of serving.
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Evaluate the bundled sample evidence of every verifier, issuing a
+    /// throwaway token, and exit instead of serving.
+    #[arg(long)]
+    pub self_test: bool,
+
+    /// OTLP/gRPC endpoint to export tracing spans to, e.g. http://127.0.0.1:4317.
+    #[cfg(feature = "opentelemetry")]
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+
+    /// Format of the log records written to stderr.
+    #[arg(long, env = "AS_LOG_FORMAT", value_enum, default_value_t)]
+    pub log_format: LogFormat,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+    logging::init(cli.log_format);
 
     let version = format!(
         "\nv{}\ncommit: {}\nbuildtime: {}",
@@ -43,10 +78,41 @@ async fn main() -> Result<()> {
 
     info!("CoCo AS: {version}");
 
-    let cli = Cli::parse();
+    if cli.check_config || cli.self_test {
+        let config = match &cli.config_file {
+            Some(path) => Config::try_from(Path::new(path))?,
+            None => Config::default(),
+        };
+        if cli.check_config {
+            AttestationService::check_config(config).await?;
+            info!("The config is valid.");
+        } else {
+            AttestationService::new(config)
+                .await?
+                .self_test()
+                .await
+                .log()?;
+            info!("The self-test passed.");
+        }
+        return Ok(());
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        attestation_service::telemetry::init(endpoint, "grpc-as")?;
+    }
+
+    let server = grpc::start(
+        cli.socket,
+        cli.config_file,
+        cli.max_concurrent_evaluations,
+        cli.max_queued_evaluations,
+    );
+    let res = tokio::try_join!(server);
 
-    let server = grpc::start(cli.socket, cli.config_file);
-    tokio::try_join!(server)?;
+    #[cfg(feature = "opentelemetry")]
+    attestation_service::telemetry::shutdown();
 
+    res?;
     Ok(())
 }
@@ -1,23 +1,32 @@
 use anyhow::bail;
-use attestation_service::HashAlgorithm;
+use attestation_service::logging::{with_correlation_id, REQUEST_ID_KEY};
 use attestation_service::{
     config::Config, config::ConfigError, AttestationService as Service, ServiceError, Tee,
 };
+use attestation_service::{HashAlgorithm, SubmodEvidence};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
-use log::{debug, info};
+use futures::{Stream, StreamExt};
+use log::{debug, info, warn};
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::RwLock;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tonic::metadata::MetadataMap;
 use tonic::transport::Server;
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response, Status, Streaming};
+use tonic_health::server::HealthReporter;
+use tracing::Instrument;
 
 use crate::as_api::attestation_service_server::{AttestationService, AttestationServiceServer};
 use crate::as_api::{
-    AttestationRequest, AttestationResponse, ChallengeRequest, ChallengeResponse, SetPolicyRequest,
-    SetPolicyResponse,
+    attestation_stream_response, AttestationRequest, AttestationResponse, AttestationStreamRequest,
+    AttestationStreamResponse, ChallengeRequest, ChallengeResponse, EvaluationReportRequest,
+    EvaluationReportResponse, SetPolicyRequest, SetPolicyResponse,
 };
 
 use crate::rvps_api::reference_value_provider_service_server::{
@@ -25,10 +34,15 @@ use crate::rvps_api::reference_value_provider_service_server::{
 };
 
 use crate::rvps_api::{
-    ReferenceValueQueryRequest, ReferenceValueQueryResponse, ReferenceValueRegisterRequest,
-    ReferenceValueRegisterResponse,
+    ReferenceValueDeleteRequest, ReferenceValueDeleteResponse, ReferenceValueQueryRequest,
+    ReferenceValueQueryResponse, ReferenceValueRegisterRequest, ReferenceValueRegisterResponse,
 };
 
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Maximum number of evaluations of one stream running at the same time.
+const MAX_STREAM_EVALUATIONS: usize = 16;
+
 fn to_kbs_tee(tee: &str) -> anyhow::Result<Tee> {
     let tee = match tee {
         "sev" => Tee::Sev,
@@ -48,6 +62,29 @@ fn to_kbs_tee(tee: &str) -> anyhow::Result<Tee> {
     Ok(tee)
 }
 
+/// The correlation ID the client sent in the metadata of its request.
+fn request_id(metadata: &MetadataMap) -> Option<String> {
+    metadata
+        .get(REQUEST_ID_KEY)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Header name/value pairs of the request metadata that may carry a trace
+/// context.
+#[cfg(feature = "opentelemetry")]
+fn trace_headers(metadata: &tonic::metadata::MetadataMap) -> Vec<(String, String)> {
+    metadata
+        .iter()
+        .filter_map(|entry| match entry {
+            tonic::metadata::KeyAndValueRef::Ascii(key, value) => {
+                Some((key.to_string(), value.to_str().ok()?.to_string()))
+            }
+            tonic::metadata::KeyAndValueRef::Binary(..) => None,
+        })
+        .collect()
+}
+
 #[derive(Error, Debug)]
 pub enum GrpcError {
     #[error("Read AS config file failed: {0}")]
@@ -56,14 +93,63 @@ pub enum GrpcError {
     Service(#[from] ServiceError),
     #[error("tonic transport error: {0}")]
     TonicTransport(#[from] tonic::transport::Error),
+    #[error("Building gRPC reflection service failed: {0}")]
+    Reflection(#[from] tonic_reflection::server::Error),
+}
+
+/// Bound on the evaluations of the server, so that a burst of attestations
+/// waits in a queue of bounded length, or is turned away, instead of piling
+/// up verifier tasks until the server runs out of memory.
+struct EvaluationLimiter {
+    /// Permits of the evaluations running.
+    running: Arc<Semaphore>,
+
+    /// Permits of the evaluations running or waiting to.
+    admitted: Arc<Semaphore>,
+}
+
+/// The slot of an evaluation, released on drop.
+type EvaluationPermit = (OwnedSemaphorePermit, OwnedSemaphorePermit);
+
+impl EvaluationLimiter {
+    fn new(max_concurrent: NonZeroUsize, max_queued: usize) -> Self {
+        let max_concurrent = max_concurrent.get().min(Semaphore::MAX_PERMITS);
+        let max_admitted = max_concurrent
+            .saturating_add(max_queued)
+            .min(Semaphore::MAX_PERMITS);
+        Self {
+            running: Arc::new(Semaphore::new(max_concurrent)),
+            admitted: Arc::new(Semaphore::new(max_admitted)),
+        }
+    }
+
+    /// Wait until an evaluation can run. Fails with `RESOURCE_EXHAUSTED` if
+    /// the queue is full.
+    async fn acquire(&self) -> Result<EvaluationPermit, Status> {
+        let admitted = self.admitted.clone().try_acquire_owned().map_err(|_| {
+            Status::resource_exhausted("Too many attestations in progress, retry later")
+        })?;
+        let running = self
+            .running
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| Status::unavailable("Attestation Service is shutting down"))?;
+        Ok((admitted, running))
+    }
 }
 
 pub struct AttestationServer {
     attestation_service: Service,
+    limiter: Arc<EvaluationLimiter>,
 }
 
 impl AttestationServer {
-    pub async fn new(config_path: Option<String>) -> Result<Self, GrpcError> {
+    pub async fn new(
+        config_path: Option<String>,
+        max_concurrent_evaluations: NonZeroUsize,
+        max_queued_evaluations: usize,
+    ) -> Result<Self, GrpcError> {
         let config = match config_path {
             Some(path) => Config::try_from(Path::new(&path)).map_err(GrpcError::Config)?,
             None => Config::default(),
@@ -73,165 +159,307 @@ impl AttestationServer {
 
         Ok(Self {
             attestation_service: service,
+            limiter: Arc::new(EvaluationLimiter::new(
+                max_concurrent_evaluations,
+                max_queued_evaluations,
+            )),
         })
     }
 }
 
+/// Evaluate the evidence of `request` within `span`.
+async fn evaluate(
+    server: &RwLock<AttestationServer>,
+    request: AttestationRequest,
+    span: tracing::Span,
+) -> Result<String, Status> {
+    let limiter = server.read().await.limiter.clone();
+    let _permit = limiter.acquire().await?;
+
+    debug!("Evidence: {}", &request.evidence);
+
+    let tee =
+        to_kbs_tee(&request.tee).map_err(|e| Status::aborted(format!("parse TEE type: {e}")))?;
+    let evidence = URL_SAFE_NO_PAD
+        .decode(request.evidence)
+        .map_err(|e| Status::aborted(format!("Illegal input Evidence: {e}")))?;
+    let submods = request
+        .submods
+        .into_iter()
+        .map(|submod| {
+            let tee = to_kbs_tee(&submod.tee).map_err(|e| {
+                Status::aborted(format!("parse TEE type of submod {}: {e}", submod.name))
+            })?;
+            let evidence = URL_SAFE_NO_PAD.decode(submod.evidence).map_err(|e| {
+                Status::aborted(format!(
+                    "Illegal input Evidence of submod {}: {e}",
+                    submod.name
+                ))
+            })?;
+            Ok(SubmodEvidence {
+                name: submod.name,
+                tee,
+                evidence,
+            })
+        })
+        .collect::<Result<Vec<_>, Status>>()?;
+
+    let runtime_data = match request.runtime_data {
+        Some(runtime_data) => match runtime_data {
+            crate::as_api::attestation_request::RuntimeData::RawRuntimeData(raw) => {
+                let raw_runtime = URL_SAFE_NO_PAD
+                    .decode(raw)
+                    .map_err(|e| Status::aborted(format!("base64 decode runtime data: {e}")))?;
+                Some(attestation_service::Data::Raw(raw_runtime))
+            }
+            crate::as_api::attestation_request::RuntimeData::StructuredRuntimeData(structured) => {
+                let structured = serde_json::from_str(&structured)
+                    .map_err(|e| Status::aborted(format!("parse structured runtime data: {e}")))?;
+                Some(attestation_service::Data::Structured(structured))
+            }
+        },
+        None => None,
+    };
+
+    let init_data = match request.init_data {
+        Some(init_data) => match init_data {
+            crate::as_api::attestation_request::InitData::RawInitData(raw) => {
+                let raw_init = URL_SAFE_NO_PAD
+                    .decode(raw)
+                    .map_err(|e| Status::aborted(format!("base64 decode init data: {e}")))?;
+                Some(attestation_service::Data::Raw(raw_init))
+            }
+            crate::as_api::attestation_request::InitData::StructuredInitData(structured) => {
+                let structured = serde_json::from_str(&structured)
+                    .map_err(|e| Status::aborted(format!("parse structured init data: {e}")))?;
+                Some(attestation_service::Data::Structured(structured))
+            }
+        },
+        None => None,
+    };
+
+    let runtime_data_hash_algorithm = match request.runtime_data_hash_algorithm.is_empty() {
+        false => {
+            HashAlgorithm::try_from(&request.runtime_data_hash_algorithm[..]).map_err(|e| {
+                Status::aborted(format!("parse runtime data HashAlgorithm failed: {e}"))
+            })?
+        }
+        true => {
+            info!("No Runtime Data Hash Algorithm provided, use `sha384` by default.");
+            HashAlgorithm::Sha384
+        }
+    };
+
+    let init_data_hash_algorithm = match request.init_data_hash_algorithm.is_empty() {
+        false => HashAlgorithm::try_from(&request.init_data_hash_algorithm[..])
+            .map_err(|e| Status::aborted(format!("parse init data HashAlgorithm failed: {e}")))?,
+        true => {
+            info!("No Init Data Hash Algorithm provided, use `sha384` by default.");
+            HashAlgorithm::Sha384
+        }
+    };
+
+    let policy_ids = match request.policy_ids.is_empty() {
+        true => vec!["default".into()],
+        false => request.policy_ids,
+    };
+
+    let attestation_token = server
+        .read()
+        .await
+        .attestation_service
+        .evaluate_composite(
+            evidence,
+            tee,
+            submods,
+            runtime_data,
+            runtime_data_hash_algorithm,
+            init_data,
+            init_data_hash_algorithm,
+            policy_ids,
+            (!request.rvps_namespace.is_empty()).then_some(request.rvps_namespace.as_str()),
+            (!request.prior_token.is_empty()).then_some(request.prior_token.as_str()),
+        )
+        .instrument(span)
+        .await
+        .map_err(|e| Status::aborted(format!("Attestation: {e:?}")))?;
+
+    debug!("Attestation Token: {}", &attestation_token);
+    Ok(attestation_token)
+}
+
+/// Evaluate a stream of `requests`, up to `MAX_STREAM_EVALUATIONS` at a time,
+/// answering each of them as soon as its evaluation completes.
+fn evaluate_stream(
+    server: Arc<RwLock<AttestationServer>>,
+    #[cfg(feature = "opentelemetry")] headers: Vec<(String, String)>,
+    correlation_id: Option<String>,
+    requests: impl Stream<Item = Result<AttestationStreamRequest, Status>> + Send + 'static,
+) -> impl Stream<Item = Result<AttestationStreamResponse, Status>> + Send {
+    requests
+        .map(move |request| {
+            let server = server.clone();
+            let correlation_id = correlation_id.clone();
+            #[cfg(feature = "opentelemetry")]
+            let headers = headers.clone();
+            async move {
+                let AttestationStreamRequest { id, request } = request?;
+                let result = match request {
+                    Some(request) => {
+                        let span =
+                            tracing::info_span!("attestation_evaluate", tee = request.tee, id);
+                        #[cfg(feature = "opentelemetry")]
+                        attestation_service::telemetry::set_parent_from_headers(&span, &headers);
+                        let evaluation = evaluate(&server, request, span);
+                        match with_correlation_id(correlation_id, evaluation).await {
+                            Ok(token) => {
+                                attestation_stream_response::Result::AttestationToken(token)
+                            }
+                            Err(status) => {
+                                attestation_stream_response::Result::Error(status.message().into())
+                            }
+                        }
+                    }
+                    None => attestation_stream_response::Result::Error(
+                        "No attestation request given".into(),
+                    ),
+                };
+                Ok(AttestationStreamResponse {
+                    id,
+                    result: Some(result),
+                })
+            }
+        })
+        .buffer_unordered(MAX_STREAM_EVALUATIONS)
+}
+
 #[tonic::async_trait]
 impl AttestationService for Arc<RwLock<AttestationServer>> {
     async fn set_attestation_policy(
         &self,
         request: Request<SetPolicyRequest>,
     ) -> Result<Response<SetPolicyResponse>, Status> {
+        let correlation_id = request_id(request.metadata());
         let request: SetPolicyRequest = request.into_inner();
 
-        info!("SetPolicy API called.");
-        debug!("SetPolicyInput: {request:#?}");
+        with_correlation_id(correlation_id, async move {
+            info!("SetPolicy API called.");
+            debug!("SetPolicyInput: {request:#?}");
 
-        self.write()
-            .await
-            .attestation_service
-            .set_policy(request.policy_id, request.policy)
-            .await
-            .map_err(|e| Status::aborted(format!("Set Attestation Policy Failed: {e}")))?;
+            self.write()
+                .await
+                .attestation_service
+                .set_policy(request.policy_id, request.policy)
+                .await
+                .map_err(|e| Status::aborted(format!("Set Attestation Policy Failed: {e}")))?;
 
-        Ok(Response::new(SetPolicyResponse {}))
+            Ok(Response::new(SetPolicyResponse {}))
+        })
+        .await
     }
 
     async fn attestation_evaluate(
         &self,
         request: Request<AttestationRequest>,
     ) -> Result<Response<AttestationResponse>, Status> {
-        let request: AttestationRequest = request.into_inner();
-
-        info!("AttestationEvaluate API called.");
-        debug!("Evidence: {}", &request.evidence);
-
-        let tee = to_kbs_tee(&request.tee)
-            .map_err(|e| Status::aborted(format!("parse TEE type: {e}")))?;
-        let evidence = URL_SAFE_NO_PAD
-            .decode(request.evidence)
-            .map_err(|e| Status::aborted(format!("Illegal input Evidence: {e}")))?;
-
-        let runtime_data = match request.runtime_data {
-            Some(runtime_data) => match runtime_data {
-                crate::as_api::attestation_request::RuntimeData::RawRuntimeData(raw) => {
-                    let raw_runtime = URL_SAFE_NO_PAD
-                        .decode(raw)
-                        .map_err(|e| Status::aborted(format!("base64 decode runtime data: {e}")))?;
-                    Some(attestation_service::Data::Raw(raw_runtime))
-                }
-                crate::as_api::attestation_request::RuntimeData::StructuredRuntimeData(
-                    structured,
-                ) => {
-                    let structured = serde_json::from_str(&structured).map_err(|e| {
-                        Status::aborted(format!("parse structured runtime data: {e}"))
-                    })?;
-                    Some(attestation_service::Data::Structured(structured))
-                }
-            },
-            None => None,
-        };
-
-        let init_data = match request.init_data {
-            Some(init_data) => match init_data {
-                crate::as_api::attestation_request::InitData::RawInitData(raw) => {
-                    let raw_init = URL_SAFE_NO_PAD
-                        .decode(raw)
-                        .map_err(|e| Status::aborted(format!("base64 decode init data: {e}")))?;
-                    Some(attestation_service::Data::Raw(raw_init))
-                }
-                crate::as_api::attestation_request::InitData::StructuredInitData(structured) => {
-                    let structured = serde_json::from_str(&structured)
-                        .map_err(|e| Status::aborted(format!("parse structured init data: {e}")))?;
-                    Some(attestation_service::Data::Structured(structured))
-                }
-            },
-            None => None,
-        };
-
-        let runtime_data_hash_algorithm = match request.runtime_data_hash_algorithm.is_empty() {
-            false => {
-                HashAlgorithm::try_from(&request.runtime_data_hash_algorithm[..]).map_err(|e| {
-                    Status::aborted(format!("parse runtime data HashAlgorithm failed: {e}"))
-                })?
-            }
-            true => {
-                info!("No Runtime Data Hash Algorithm provided, use `sha384` by default.");
-                HashAlgorithm::Sha384
-            }
-        };
-
-        let init_data_hash_algorithm = match request.init_data_hash_algorithm.is_empty() {
-            false => {
-                HashAlgorithm::try_from(&request.init_data_hash_algorithm[..]).map_err(|e| {
-                    Status::aborted(format!("parse init data HashAlgorithm failed: {e}"))
-                })?
-            }
-            true => {
-                info!("No Init Data Hash Algorithm provided, use `sha384` by default.");
-                HashAlgorithm::Sha384
-            }
-        };
-
-        let policy_ids = match request.policy_ids.is_empty() {
-            true => vec!["default".into()],
-            false => request.policy_ids,
-        };
-
-        let attestation_token = self
-            .read()
-            .await
-            .attestation_service
-            .evaluate(
-                evidence,
-                tee,
-                runtime_data,
-                runtime_data_hash_algorithm,
-                init_data,
-                init_data_hash_algorithm,
-                policy_ids,
-            )
-            .await
-            .map_err(|e| Status::aborted(format!("Attestation: {e:?}")))?;
-
-        debug!("Attestation Token: {}", &attestation_token);
-
+        let span = tracing::info_span!("attestation_evaluate", tee = request.get_ref().tee);
+        #[cfg(feature = "opentelemetry")]
+        attestation_service::telemetry::set_parent_from_headers(
+            &span,
+            &trace_headers(request.metadata()),
+        );
+
+        let correlation_id = request_id(request.metadata());
+        let attestation_token = with_correlation_id(correlation_id, async move {
+            info!("AttestationEvaluate API called.");
+            evaluate(self, request.into_inner(), span).await
+        })
+        .await?;
         let res = AttestationResponse { attestation_token };
         Ok(Response::new(res))
     }
 
+    type AttestationEvaluateStreamStream =
+        Pin<Box<dyn Stream<Item = Result<AttestationStreamResponse, Status>> + Send>>;
+
+    async fn attestation_evaluate_stream(
+        &self,
+        request: Request<Streaming<AttestationStreamRequest>>,
+    ) -> Result<Response<Self::AttestationEvaluateStreamStream>, Status> {
+        info!("AttestationEvaluateStream API called.");
+
+        let responses = evaluate_stream(
+            self.clone(),
+            #[cfg(feature = "opentelemetry")]
+            trace_headers(request.metadata()),
+            request_id(request.metadata()),
+            request.into_inner(),
+        );
+        Ok(Response::new(Box::pin(responses)))
+    }
+
     async fn get_attestation_challenge(
         &self,
         request: Request<ChallengeRequest>,
     ) -> Result<Response<ChallengeResponse>, Status> {
+        let correlation_id = request_id(request.metadata());
         let request: ChallengeRequest = request.into_inner();
-        info!("get_attestation_challenge API called.");
-        debug!("get_attestation_challenge: {request:#?}");
-
-        let inner_tee = request
-            .inner
-            .get("tee")
-            .ok_or(Status::aborted("Error parse inner_tee tee"))?;
-        let tee_params = request
-            .inner
-            .get("tee_params")
-            .map_or(Err(Status::aborted("Error parse inner_tee tee_params")), Ok)?;
-        let tee = to_kbs_tee(&inner_tee)
-            .map_err(|e| Status::aborted(format!("Error parse TEE type: {e}")))?;
-
-        let attestation_challenge = self
-            .read()
-            .await
-            .attestation_service
-            .generate_supplemental_challenge(tee, tee_params.clone())
-            .await
-            .map_err(|e| Status::aborted(format!("Challenge: {e:?}")))?;
 
-        let res = ChallengeResponse {
-            attestation_challenge,
-        };
-        Ok(Response::new(res))
+        with_correlation_id(correlation_id, async move {
+            info!("get_attestation_challenge API called.");
+            debug!("get_attestation_challenge: {request:#?}");
+
+            let inner_tee = request
+                .inner
+                .get("tee")
+                .ok_or(Status::aborted("Error parse inner_tee tee"))?;
+            let tee_params = request
+                .inner
+                .get("tee_params")
+                .map_or(Err(Status::aborted("Error parse inner_tee tee_params")), Ok)?;
+            let tee = to_kbs_tee(&inner_tee)
+                .map_err(|e| Status::aborted(format!("Error parse TEE type: {e}")))?;
+
+            let attestation_challenge = self
+                .read()
+                .await
+                .attestation_service
+                .generate_supplemental_challenge(tee, tee_params.clone())
+                .await
+                .map_err(|e| Status::aborted(format!("Challenge: {e:?}")))?;
+
+            let res = ChallengeResponse {
+                attestation_challenge,
+            };
+            Ok(Response::new(res))
+        })
+        .await
+    }
+
+    async fn get_evaluation_report(
+        &self,
+        request: Request<EvaluationReportRequest>,
+    ) -> Result<Response<EvaluationReportResponse>, Status> {
+        let correlation_id = request_id(request.metadata());
+        let request: EvaluationReportRequest = request.into_inner();
+
+        with_correlation_id(correlation_id, async move {
+            info!("GetEvaluationReport API called.");
+
+            let evaluation_report = self
+                .read()
+                .await
+                .attestation_service
+                .evaluation_report(&request.attestation_token)
+                .map_err(|e| {
+                    Status::invalid_argument(format!("Sign Evaluation Report Failed: {e:#}"))
+                })?;
+
+            Ok(Response::new(EvaluationReportResponse {
+                evaluation_report,
+            }))
+        })
+        .await
     }
 }
 
@@ -251,34 +479,237 @@ impl ReferenceValueProviderService for Arc<RwLock<AttestationServer>> {
         &self,
         request: Request<ReferenceValueRegisterRequest>,
     ) -> Result<Response<ReferenceValueRegisterResponse>, Status> {
+        let correlation_id = request_id(request.metadata());
         let request = request.into_inner();
 
-        info!("RegisterReferenceValue API called.");
-        debug!("registry reference value: {}", request.message);
+        with_correlation_id(correlation_id, async move {
+            info!("RegisterReferenceValue API called.");
+            debug!("registry reference value: {}", request.message);
+
+            let message = serde_json::from_str(&request.message)
+                .map_err(|e| Status::aborted(format!("Parse message: {e}")))?;
+            self.write()
+                .await
+                .attestation_service
+                .register_reference_value(
+                    message,
+                    (!request.namespace.is_empty()).then_some(request.namespace.as_str()),
+                )
+                .await
+                .map_err(|e| Status::aborted(format!("Register reference value: {e}")))?;
+
+            let res = ReferenceValueRegisterResponse {};
+            Ok(Response::new(res))
+        })
+        .await
+    }
 
-        let message = serde_json::from_str(&request.message)
-            .map_err(|e| Status::aborted(format!("Parse message: {e}")))?;
-        self.write()
-            .await
-            .attestation_service
-            .register_reference_value(message)
-            .await
-            .map_err(|e| Status::aborted(format!("Register reference value: {e}")))?;
+    async fn delete_reference_value(
+        &self,
+        _request: Request<ReferenceValueDeleteRequest>,
+    ) -> Result<Response<ReferenceValueDeleteResponse>, Status> {
+        let status =
+            Status::aborted("Cannot delete reference values using RVPS as a submodule in AS.");
 
-        let res = ReferenceValueRegisterResponse {};
-        Ok(Response::new(res))
+        Err(status)
     }
 }
 
-pub async fn start(socket: SocketAddr, config_path: Option<String>) -> Result<(), GrpcError> {
+/// Periodically check the backing services of the AS and report the result
+/// through the gRPC health service.
+async fn report_health(mut reporter: HealthReporter, server: Arc<RwLock<AttestationServer>>) {
+    loop {
+        let health = server.read().await.attestation_service.health_check().await;
+        match health {
+            Ok(()) => {
+                reporter
+                    .set_serving::<AttestationServiceServer<Arc<RwLock<AttestationServer>>>>()
+                    .await
+            }
+            Err(e) => {
+                warn!("Attestation Service is not ready: {e:#}");
+                reporter
+                    .set_not_serving::<AttestationServiceServer<Arc<RwLock<AttestationServer>>>>()
+                    .await
+            }
+        }
+        tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+    }
+}
+
+pub async fn start(
+    socket: SocketAddr,
+    config_path: Option<String>,
+    max_concurrent_evaluations: NonZeroUsize,
+    max_queued_evaluations: usize,
+) -> Result<(), GrpcError> {
     info!("Listen socket: {}", &socket);
 
-    let attestation_server = Arc::new(RwLock::new(AttestationServer::new(config_path).await?));
+    let attestation_server = Arc::new(RwLock::new(
+        AttestationServer::new(
+            config_path,
+            max_concurrent_evaluations,
+            max_queued_evaluations,
+        )
+        .await?,
+    ));
+
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::spawn(report_health(health_reporter, attestation_server.clone()));
+
+    let reflection_service = reflection().build()?;
 
     Server::builder()
+        .add_service(health_service)
+        .add_service(reflection_service)
         .add_service(AttestationServiceServer::new(attestation_server.clone()))
         .add_service(ReferenceValueProviderServiceServer::new(attestation_server))
         .serve(socket)
         .await?;
     Ok(())
 }
+
+/// gRPC reflection of the services of grpc-as.
+fn reflection() -> tonic_reflection::server::Builder<'static> {
+    tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(crate::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_evaluation_limiter() {
+        let limiter = Arc::new(EvaluationLimiter::new(NonZeroUsize::new(1).unwrap(), 1));
+        let running = limiter.acquire().await.unwrap();
+
+        // The second evaluation waits for the first one.
+        let waiting = limiter.clone();
+        let queued = tokio::spawn(async move { waiting.acquire().await.map(drop) });
+        tokio::task::yield_now().await;
+        assert!(!queued.is_finished());
+
+        // The third one is turned away.
+        let rejected = limiter.acquire().await.unwrap_err();
+        assert_eq!(rejected.code(), tonic::Code::ResourceExhausted);
+
+        drop(running);
+        assert!(queued.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            work_dir: dir.path().join("as"),
+            ..Default::default()
+        };
+        config.rvps_config.store_config =
+            serde_json::json!({ "file_path": dir.path().join("rvps") });
+        let server = Arc::new(RwLock::new(AttestationServer {
+            attestation_service: Service::new(config).await.unwrap(),
+            limiter: Arc::new(EvaluationLimiter::new(NonZeroUsize::new(2).unwrap(), 16)),
+        }));
+
+        let evidence =
+            URL_SAFE_NO_PAD.encode(r#"{"svn": "1", "report_data": "", "init_data": ""}"#);
+        let request = |id, tee: &str| AttestationStreamRequest {
+            id,
+            request: Some(AttestationRequest {
+                tee: tee.into(),
+                evidence: evidence.clone(),
+                ..Default::default()
+            }),
+        };
+        let requests = futures::stream::iter([
+            Ok(request(1, "sample")),
+            Ok(AttestationStreamRequest {
+                id: 2,
+                request: None,
+            }),
+            Ok(request(3, "unknown")),
+            Ok(request(4, "sample")),
+        ]);
+
+        let mut responses: Vec<_> = evaluate_stream(
+            server,
+            #[cfg(feature = "opentelemetry")]
+            Vec::new(),
+            None,
+            requests,
+        )
+        .map(|response| response.unwrap())
+        .collect()
+        .await;
+        responses.sort_by_key(|response| response.id);
+
+        // The failed requests get an error without ending the stream.
+        let results: Vec<_> = responses
+            .into_iter()
+            .map(|response| (response.id, response.result.unwrap()))
+            .collect();
+        assert_eq!(results.len(), 4);
+        for (id, result) in results {
+            match result {
+                attestation_stream_response::Result::AttestationToken(token) => {
+                    assert!([1, 4].contains(&id));
+                    assert_eq!(token.split('.').count(), 3);
+                }
+                attestation_stream_response::Result::Error(error) => {
+                    assert!([2, 3].contains(&id), "{error}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reflection() {
+        use tonic_reflection::pb::server_reflection_client::ServerReflectionClient;
+        use tonic_reflection::pb::server_reflection_request::MessageRequest;
+        use tonic_reflection::pb::server_reflection_response::MessageResponse;
+        use tonic_reflection::pb::ServerReflectionRequest;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        tokio::spawn(
+            Server::builder()
+                .add_service(reflection().build().unwrap())
+                .serve_with_incoming(incoming),
+        );
+
+        let mut client = ServerReflectionClient::connect(format!("http://{address}"))
+            .await
+            .unwrap();
+        let request = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(MessageRequest::ListServices(String::new())),
+        };
+        let mut responses = client
+            .server_reflection_info(futures::stream::iter([request]))
+            .await
+            .unwrap()
+            .into_inner();
+        let response = responses.message().await.unwrap().unwrap();
+        let Some(MessageResponse::ListServicesResponse(list)) = response.message_response else {
+            panic!("unexpected reflection response");
+        };
+        let services: Vec<_> = list
+            .service
+            .into_iter()
+            .map(|service| service.name)
+            .collect();
+        for service in [
+            "attestation.AttestationService",
+            "reference.ReferenceValueProviderService",
+            "grpc.health.v1.Health",
+        ] {
+            assert!(services.iter().any(|name| name == service), "{service}");
+        }
+    }
+}
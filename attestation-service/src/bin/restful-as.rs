@@ -2,6 +2,7 @@ use std::{net::SocketAddr, path::Path, sync::Arc};
 
 use actix_web::{web, App, HttpServer};
 use anyhow::Result;
+use attestation_service::logging::{self, LogFormat};
 use attestation_service::{config::Config, config::ConfigError, AttestationService, ServiceError};
 use clap::{arg, command, Parser};
 use log::info;
@@ -13,7 +14,10 @@ use strum::{AsRefStr, EnumString};
 use thiserror::Error;
 use tokio::sync::RwLock;
 
-use crate::restful::{attestation, get_challenge, get_policies, set_policy};
+use crate::restful::{
+    attestation, evaluation_report, get_challenge, get_policies, jwks, metrics,
+    openid_configuration, policy_capture, register_reference_value, set_policy, CapturesToken,
+};
 
 mod restful;
 
@@ -29,15 +33,39 @@ pub struct Cli {
     #[arg(short, long)]
     pub socket: SocketAddr,
 
+    /// Validate the config file and exit instead of serving.
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Evaluate the bundled sample evidence of every verifier, issuing a
+    /// throwaway token, and exit instead of serving.
+    #[arg(long)]
+    pub self_test: bool,
+
     /// Path to the public key cert for HTTPS. Both public key cert and
     /// private key are provided then HTTPS will be enabled.
-    #[arg(short, long)]
+    #[arg(long)]
     pub https_pubkey_cert: Option<String>,
 
     /// Path to the private key for HTTPS. Both public key cert and
     /// private key are provided then HTTPS will be enabled.
-    #[arg(short, long)]
+    #[arg(long)]
     pub https_prikey: Option<String>,
+
+    /// OTLP/gRPC endpoint to export tracing spans to, e.g. http://127.0.0.1:4317.
+    #[cfg(feature = "opentelemetry")]
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+
+    /// Format of the log records written to stderr.
+    #[arg(long, env = "AS_LOG_FORMAT", value_enum, default_value_t)]
+    pub log_format: LogFormat,
+
+    /// Path to a file holding the bearer token required to get the policy
+    /// captures. They aren't served without it, as they hold the claims of
+    /// the evidence.
+    #[arg(long, env = "AS_CAPTURES_TOKEN_FILE")]
+    pub captures_token_file: Option<String>,
 }
 
 #[derive(EnumString, AsRefStr)]
@@ -51,6 +79,24 @@ enum WebApi {
 
     #[strum(serialize = "/challenge")]
     Challenge,
+
+    #[strum(serialize = "/policy-captures/{request_id}")]
+    PolicyCapture,
+
+    #[strum(serialize = "/evaluation-report")]
+    EvaluationReport,
+
+    #[strum(serialize = "/reference-values")]
+    ReferenceValues,
+
+    #[strum(serialize = "/metrics")]
+    Metrics,
+
+    #[strum(serialize = "/.well-known/openid-configuration")]
+    OidcDiscovery,
+
+    #[strum(serialize = "/.well-known/jwks.json")]
+    Jwks,
 }
 
 #[derive(Error, Debug)]
@@ -69,6 +115,8 @@ pub enum RestfulError {
     SetPrivateKey(#[source] openssl::error::ErrorStack),
     #[error("set HTTPS public key cert: {0}")]
     SetHttpsCert(#[source] openssl::error::ErrorStack),
+    #[error("failed to read policy captures token: {0}")]
+    ReadCapturesToken(#[source] std::io::Error),
     #[error("io error: {0}")]
     IO(#[from] std::io::Error),
     #[error(transparent)]
@@ -77,9 +125,13 @@ pub enum RestfulError {
 
 #[actix_web::main]
 async fn main() -> Result<(), RestfulError> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-
     let cli = Cli::parse();
+    logging::init(cli.log_format);
+
+    #[cfg(feature = "opentelemetry")]
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        attestation_service::telemetry::init(endpoint, "restful-as")?;
+    }
 
     let config = match cli.config_file {
         Some(path) => {
@@ -92,11 +144,44 @@ async fn main() -> Result<(), RestfulError> {
         }
     };
 
+    if cli.check_config {
+        AttestationService::check_config(config).await?;
+        info!("The config is valid.");
+        return Ok(());
+    }
+
     let attestation_service = AttestationService::new(config).await?;
 
+    if cli.self_test {
+        attestation_service.self_test().await.log()?;
+        info!("The self-test passed.");
+        return Ok(());
+    }
+
+    let captures_token = match &cli.captures_token_file {
+        Some(path) => Some(
+            tokio::fs::read_to_string(path)
+                .await
+                .map_err(RestfulError::ReadCapturesToken)?
+                .trim()
+                .to_string(),
+        ),
+        None => None,
+    };
+    let captures_token = web::Data::new(CapturesToken(captures_token));
+
     let attestation_service = web::Data::new(Arc::new(RwLock::new(attestation_service)));
     let server = HttpServer::new(move || {
         App::new()
+            .wrap_fn(|request, service| {
+                use actix_web::dev::Service;
+                let correlation_id = request
+                    .headers()
+                    .get(logging::REQUEST_ID_KEY)
+                    .and_then(|id| id.to_str().ok())
+                    .map(str::to_string);
+                logging::with_correlation_id(correlation_id, service.call(request))
+            })
             .service(web::resource(WebApi::Attestation.as_ref()).route(web::post().to(attestation)))
             .service(
                 web::resource(WebApi::Policy.as_ref())
@@ -104,7 +189,25 @@ async fn main() -> Result<(), RestfulError> {
                     .route(web::get().to(get_policies)),
             )
             .service(web::resource(WebApi::Challenge.as_ref()).route(web::post().to(get_challenge)))
+            .service(
+                web::resource(WebApi::EvaluationReport.as_ref())
+                    .route(web::post().to(evaluation_report)),
+            )
+            .service(
+                web::resource(WebApi::PolicyCapture.as_ref()).route(web::get().to(policy_capture)),
+            )
+            .service(
+                web::resource(WebApi::ReferenceValues.as_ref())
+                    .route(web::post().to(register_reference_value)),
+            )
+            .service(web::resource(WebApi::Metrics.as_ref()).route(web::get().to(metrics)))
+            .service(
+                web::resource(WebApi::OidcDiscovery.as_ref())
+                    .route(web::get().to(openid_configuration)),
+            )
+            .service(web::resource(WebApi::Jwks.as_ref()).route(web::get().to(jwks)))
             .app_data(web::Data::clone(&attestation_service))
+            .app_data(web::Data::clone(&captures_token))
     });
 
     let server = match (cli.https_prikey, cli.https_pubkey_cert) {
@@ -134,6 +237,32 @@ async fn main() -> Result<(), RestfulError> {
         }
     };
 
-    server.await?;
+    let res = server.await;
+
+    #[cfg(feature = "opentelemetry")]
+    attestation_service::telemetry::shutdown();
+
+    res?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from([
+            "restful-as",
+            "--https-pubkey-cert",
+            "cert.pem",
+            "--https-prikey",
+            "key.pem",
+        ]);
+        assert_eq!(cli.https_pubkey_cert.as_deref(), Some("cert.pem"));
+        assert_eq!(cli.https_prikey.as_deref(), Some("key.pem"));
+    }
+}
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, sync::Arc};
 
-use actix_web::{body::BoxBody, web, HttpRequest, HttpResponse, ResponseError};
+use actix_web::{body::BoxBody, http::header, web, HttpRequest, HttpResponse, ResponseError};
 use anyhow::{anyhow, bail, Context};
 use attestation_service::{AttestationService, HashAlgorithm};
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
@@ -42,6 +42,22 @@ pub struct AttestationRequest {
     runtime_data_hash_algorithm: Option<String>,
     init_data_hash_algorithm: Option<String>,
     policy_ids: Vec<String>,
+    #[serde(default)]
+    submods: Vec<SubmodEvidence>,
+    /// Namespace of the reference values, the default one if omitted.
+    rvps_namespace: Option<String>,
+    /// Token of a prior attestation of the attester, issued by this service.
+    /// The `tcb-status` of its claims is given to the policies as
+    /// `data.prior`.
+    prior_token: Option<String>,
+}
+
+/// Evidence of a further source, evaluated along with `evidence`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubmodEvidence {
+    name: String,
+    tee: String,
+    evidence: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -92,6 +108,7 @@ fn parse_data(data: Data) -> Result<attestation_service::Data> {
 }
 
 /// This handler uses json extractor
+#[tracing::instrument(skip_all)]
 pub async fn attestation(
     request: web::Json<AttestationRequest>,
     cocoas: web::Data<Arc<RwLock<AttestationService>>>,
@@ -107,6 +124,20 @@ pub async fn attestation(
 
     let tee = to_tee(&request.tee)?;
 
+    let submods = request
+        .submods
+        .into_iter()
+        .map(|submod| {
+            Ok(attestation_service::SubmodEvidence {
+                tee: to_tee(&submod.tee)?,
+                evidence: URL_SAFE_NO_PAD
+                    .decode(&submod.evidence)
+                    .with_context(|| format!("base64 decode evidence of submod {}", submod.name))?,
+                name: submod.name,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
     let runtime_data = request
         .runtime_data
         .map(parse_data)
@@ -149,14 +180,17 @@ pub async fn attestation(
     let token = cocoas
         .read()
         .await
-        .evaluate(
+        .evaluate_composite(
             evidence,
             tee,
+            submods,
             runtime_data,
             runtime_data_hash_algorithm,
             init_data,
             init_data_hash_algorithm,
             policy_ids,
+            request.rvps_namespace.as_deref(),
+            request.prior_token.as_deref(),
         )
         .await
         .context("attestation report evaluate")?;
@@ -265,7 +299,190 @@ pub async fn get_policies(
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct RegisterReferenceValueInput {
+    message: String,
+    /// Namespace to register the reference values in, the default one if
+    /// omitted.
+    namespace: Option<String>,
+}
+
+/// POST /reference-values
+///
+/// Register the reference values of an RVPS message, like
+/// ```json
+/// {"message": "{\"version\":\"0.1.0\",\"type\":\"sample\",\"payload\":\"...\"}"}
+/// ```
+pub async fn register_reference_value(
+    input: web::Json<RegisterReferenceValueInput>,
+    cocoas: web::Data<Arc<RwLock<AttestationService>>>,
+) -> Result<HttpResponse> {
+    info!("Register Reference Value API called.");
+    let input = input.into_inner();
+
+    debug!("register reference value: {}", input.message);
+    cocoas
+        .write()
+        .await
+        .register_reference_value(&input.message, input.namespace.as_deref())
+        .await
+        .context("register reference value")?;
+
+    Ok(HttpResponse::Ok().body(""))
+}
+
+/// GET /metrics
+///
+/// Export the Prometheus metrics of the AS in the text exposition format.
+pub async fn metrics() -> Result<HttpResponse> {
+    let body = attestation_service::metrics::encode().context("encode metrics")?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+/// GET /.well-known/openid-configuration
+///
+/// The OIDC discovery document of the attestation token issuer, when the
+/// tokens are issued as OIDC ID tokens.
+pub async fn openid_configuration(
+    cocoas: web::Data<Arc<RwLock<AttestationService>>>,
+) -> Result<HttpResponse> {
+    match cocoas.read().await.oidc_discovery() {
+        Some(discovery) => Ok(HttpResponse::Ok().json(discovery)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// GET /.well-known/jwks.json
+///
+/// The public keys verifying the attestation tokens.
+pub async fn jwks(cocoas: web::Data<Arc<RwLock<AttestationService>>>) -> Result<HttpResponse> {
+    let jwks = cocoas.read().await.token_jwks().context("get token JWKS")?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(jwks))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EvaluationReportInput {
+    token: String,
+}
+
+/// POST /evaluation-report
+///
+/// Sign the evaluation reports of an attestation token issued by this AS as
+/// a JWS of their own, returned in its compact serialization, like
+/// ```json
+/// {"token": "eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzM4NCIsImtpZCI6Ii4uLiJ9..."}
+/// ```
+pub async fn evaluation_report(
+    input: web::Json<EvaluationReportInput>,
+    cocoas: web::Data<Arc<RwLock<AttestationService>>>,
+) -> Result<HttpResponse> {
+    info!("Evaluation Report API called.");
+    let report = cocoas
+        .read()
+        .await
+        .evaluation_report(&input.token)
+        .context("sign evaluation report")?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/jwt")
+        .body(report))
+}
+
+/// Bearer token required to get the policy captures, which aren't served
+/// if `None`.
+pub struct CapturesToken(pub Option<String>);
+
+impl CapturesToken {
+    /// Whether `request` carries the token.
+    fn authorizes(&self, request: &HttpRequest) -> bool {
+        let Some(token) = &self.0 else {
+            return false;
+        };
+        request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|presented| {
+                presented.len() == token.len()
+                    && openssl::memcmp::eq(presented.as_bytes(), token.as_bytes())
+            })
+    }
+}
+
+/// GET /policy-captures/{request_id}
+///
+/// The policy evaluations captured for the request sent with the
+/// `X-Request-ID` header `request_id`, when `policy_captures` is configured.
+/// The request must carry the bearer token of `--captures-token-file`.
+pub async fn policy_capture(
+    request: HttpRequest,
+    cocoas: web::Data<Arc<RwLock<AttestationService>>>,
+    token: web::Data<CapturesToken>,
+) -> Result<HttpResponse> {
+    if !token.authorizes(&request) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+    let request_id = request.match_info().get("request_id").unwrap_or_default();
+    match cocoas.read().await.policy_capture(request_id) {
+        Some(captures) => Ok(HttpResponse::Ok().json(captures)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RemovePolicyRequest {
     pub policy_ids: Vec<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use attestation_service::config::Config;
+    use base64::engine::general_purpose::STANDARD;
+
+    #[actix_web::test]
+    async fn test_register_reference_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            work_dir: dir.path().join("as"),
+            ..Default::default()
+        };
+        config.rvps_config.store_config = json!({ "file_path": dir.path().join("rvps") });
+        let service = Arc::new(RwLock::new(AttestationService::new(config).await.unwrap()));
+        let app = init_service(App::new().app_data(web::Data::new(service)).route(
+            "/reference-values",
+            web::post().to(register_reference_value),
+        ))
+        .await;
+
+        let message = |extractor: &str| {
+            json!({
+                "version": "0.1.0",
+                "type": extractor,
+                "payload": STANDARD.encode(json!({"svn": ["1"]}).to_string()),
+            })
+            .to_string()
+        };
+        for (input, success) in [
+            (json!({"message": message("sample")}), true),
+            (
+                json!({"message": message("sample"), "namespace": "tenant-a"}),
+                true,
+            ),
+            (json!({"message": message("unknown")}), false),
+        ] {
+            let request = TestRequest::post()
+                .uri("/reference-values")
+                .set_json(input)
+                .to_request();
+            let response = call_service(&app, request).await;
+            assert_eq!(response.status().is_success(), success);
+        }
+    }
+}
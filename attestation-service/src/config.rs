@@ -1,8 +1,9 @@
 use crate::rvps::RvpsConfig;
 use crate::token::{AttestationTokenBrokerType, AttestationTokenConfig};
 
+use ::config::{Environment, File, FileFormat};
 use serde::Deserialize;
-use std::fs::File;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
@@ -10,6 +11,12 @@ use thiserror::Error;
 const AS_WORK_DIR: &str = "AS_WORK_DIR";
 const DEFAULT_WORK_DIR: &str = "/opt/confidential-containers/attestation-service";
 
+/// Prefix and separator of the environment variables overriding the fields
+/// of the configuration file, e.g. `AS__RVPS_CONFIG__REMOTE_ADDR` for
+/// `rvps_config.remote_addr`.
+const ENV_PREFIX: &str = "AS";
+const ENV_SEPARATOR: &str = "__";
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     /// The location for Attestation Service to store data.
@@ -29,18 +36,41 @@ pub struct Config {
 
     /// The Attestation Result Token Broker Config
     pub attestation_token_config: AttestationTokenConfig,
+
+    /// Fail the evaluation of a policy using a claim without reference
+    /// values, instead of comparing the claim against an empty list. A
+    /// policy can override it with its `deny_empty_reference_values` rule.
+    #[serde(default)]
+    pub deny_empty_reference_values: bool,
+
+    /// Start even if the work dir, the token signer key or the KEK are
+    /// accessible to other users, only logging a warning.
+    #[serde(default)]
+    pub insecure_permissions: bool,
+
+    /// File holding the 32 bytes of an AES-256 key encrypting the policies
+    /// stored in the work dir. The token signer key can be encrypted with it
+    /// as well.
+    pub kek_path: Option<PathBuf>,
+
+    /// Read the policies stored in plaintext before `kek_path` was set. They
+    /// are rejected otherwise, so that plaintext files put in the work dir
+    /// can't replace the encrypted policies.
+    #[serde(default)]
+    pub plaintext_migration: bool,
+
+    /// Number of requests whose policy evaluations are captured, with their
+    /// input, data and output documents, for policy authors to reproduce
+    /// failures. The captures of the oldest requests are dropped. `0`
+    /// disables the captures.
+    #[serde(default)]
+    pub policy_captures: usize,
 }
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
-    #[error("io error: {0}")]
-    IO(#[from] std::io::Error),
-    #[error("failed to parse AS config file: {0}")]
-    FileParse(#[source] std::io::Error),
     #[error("failed to parse AS config file: {0}")]
-    JsonFileParse(#[source] serde_json::Error),
-    #[error("Illegal format of the content of the configuration file: {0}")]
-    SerdeJson(#[from] serde_json::Error),
+    Load(#[from] ::config::ConfigError),
 }
 
 impl Default for Config {
@@ -56,12 +86,19 @@ impl Default for Config {
             rvps_config: RvpsConfig::default(),
             attestation_token_broker: AttestationTokenBrokerType::Simple,
             attestation_token_config: AttestationTokenConfig::default(),
+            deny_empty_reference_values: false,
+            insecure_permissions: false,
+            kek_path: None,
+            plaintext_migration: false,
+            policy_captures: 0,
         }
     }
 }
 
 impl TryFrom<&Path> for Config {
-    /// Load `Config` from a configuration file like:
+    /// Load `Config` from a JSON, TOML (`.toml`) or YAML (`.yaml`, `.yml`)
+    /// configuration file, overridden by the `AS__*` environment variables.
+    /// A JSON file looks like:
     ///    {
     ///        "work_dir": "/var/lib/attestation-service/",
     ///        "policy_engine": "opa",
@@ -73,11 +110,79 @@ impl TryFrom<&Path> for Config {
     ///        "attestation_token_broker": "Simple",
     ///        "attestation_token_config": {
     ///            "duration_min": 5
-    ///        }
+    ///        },
+    ///        "deny_empty_reference_values": false,
+    ///        "insecure_permissions": false,
+    ///        "kek_path": "/etc/attestation-service/kek",
+    ///        "plaintext_migration": false,
+    ///        "policy_captures": 0
     ///    }
     type Error = ConfigError;
     fn try_from(config_path: &Path) -> Result<Self, ConfigError> {
-        let file = File::open(config_path)?;
-        serde_json::from_reader::<File, Config>(file).map_err(ConfigError::JsonFileParse)
+        Config::load(config_path, std::env::vars().collect())
+    }
+}
+
+impl Config {
+    /// Load the configuration file at `config_path`, with its fields
+    /// overridden by the `AS__*` variables of `env`.
+    fn load(config_path: &Path, env: HashMap<String, String>) -> Result<Self, ConfigError> {
+        let format = match config_path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => FileFormat::Toml,
+            Some("yaml") | Some("yml") => FileFormat::Yaml,
+            _ => FileFormat::Json,
+        };
+        let config = ::config::Config::builder()
+            .add_source(File::from(config_path).format(format))
+            .add_source(
+                Environment::with_prefix(ENV_PREFIX)
+                    .separator(ENV_SEPARATOR)
+                    .source(Some(env)),
+            )
+            .build()?;
+
+        Ok(config.try_deserialize()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            r#"
+work_dir: /var/lib/attestation-service
+policy_engine: opa
+rvps_config:
+  store_type: LocalFs
+  store_config: {}
+  remote_addr: ""
+attestation_token_broker: Simple
+attestation_token_config:
+  duration_min: 5
+"#,
+        )
+        .unwrap();
+
+        let env = HashMap::from([
+            (
+                "AS__ATTESTATION_TOKEN_CONFIG__DURATION_MIN".into(),
+                "10".into(),
+            ),
+            ("AS__DENY_EMPTY_REFERENCE_VALUES".into(), "true".into()),
+            ("AS_WORK_DIR".into(), "/tmp".into()),
+        ]);
+        let config = Config::load(&path, env).unwrap();
+        assert_eq!(
+            config.work_dir,
+            PathBuf::from("/var/lib/attestation-service")
+        );
+        assert_eq!(config.attestation_token_config.duration_min, 10);
+        assert!(config.deny_empty_reference_values);
     }
 }
@@ -3,29 +3,45 @@
 //! # Features
 //! - `rvps-grpc`: The AS will connect a remote RVPS.
 //! - `rvps-builtin`: The AS will integrate RVPS functionalities itself.
+//! - `opentelemetry`: Export tracing spans over OTLP.
 
 pub mod config;
+pub mod logging;
+pub mod metrics;
 pub mod policy_engine;
 mod rvps;
+mod schema;
+pub mod self_test;
+pub mod storage;
+#[cfg(feature = "opentelemetry")]
+pub mod telemetry;
 mod token;
 mod utils;
 
 use crate::token::AttestationTokenBroker;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use config::Config;
 pub use kbs_types::{Attestation, Tee};
 use log::{debug, info};
+use policy_engine::capture::{PolicyCapture, PolicyCaptures};
 use policy_engine::{PolicyEngine, PolicyEngineType};
 use rvps::{RvpsApi, RvpsError};
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
 use serde_variant::to_variant_name;
+#[cfg(not(feature = "openssl-crypto"))]
 use sha2::{Digest, Sha256, Sha384, Sha512};
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use storage::Kek;
 use strum::{AsRefStr, EnumString};
 use thiserror::Error;
-use tokio::fs;
-use verifier::{InitDataHash, ReportData};
+use tracing::Instrument;
+use verifier::{InitDataHash, ReportData, Verifier};
 
 use crate::utils::flatten_claims;
 
@@ -43,8 +59,9 @@ pub enum HashAlgorithm {
 }
 
 impl HashAlgorithm {
-    fn accumulate_hash(&self, materials: Vec<u8>) -> Vec<u8> {
-        match self {
+    #[cfg(not(feature = "openssl-crypto"))]
+    fn accumulate_hash(&self, materials: Vec<u8>) -> Result<Vec<u8>> {
+        let digest = match self {
             HashAlgorithm::Sha256 => {
                 let mut hasher = Sha256::new();
                 hasher.update(materials);
@@ -60,7 +77,22 @@ impl HashAlgorithm {
                 hasher.update(materials);
                 hasher.finalize().to_vec()
             }
-        }
+        };
+        Ok(digest)
+    }
+
+    /// Hash with OpenSSL, which serves the digests from its FIPS provider
+    /// once FIPS mode is enabled.
+    #[cfg(feature = "openssl-crypto")]
+    fn accumulate_hash(&self, materials: Vec<u8>) -> Result<Vec<u8>> {
+        use openssl::hash::{hash, MessageDigest};
+
+        let digest = match self {
+            HashAlgorithm::Sha256 => MessageDigest::sha256(),
+            HashAlgorithm::Sha384 => MessageDigest::sha384(),
+            HashAlgorithm::Sha512 => MessageDigest::sha512(),
+        };
+        Ok(hash(digest, &materials)?.to_vec())
     }
 }
 
@@ -78,6 +110,40 @@ pub enum Data {
     Structured(Value),
 }
 
+/// Evidence of a further source of a composite attestation, e.g. a device
+/// attached to the TEE or another TEE, evaluated along with the evidence of
+/// the TEE.
+pub struct SubmodEvidence {
+    /// Name of the submodule of the token claims holding the claims and the
+    /// verdict of the evidence, e.g. `gpu0`.
+    pub name: String,
+
+    /// The type of the evidence.
+    pub tee: Tee,
+
+    /// The evidence, as `evidence` of [`AttestationService::evaluate`].
+    pub evidence: Vec<u8>,
+}
+
+/// The claims and the verdict of the evidence of one source.
+struct SourceReport {
+    tee: Tee,
+    tcb_status: Map<String, Value>,
+    reference_data: HashMap<String, Vec<String>>,
+    policies: Vec<Value>,
+}
+
+impl SourceReport {
+    fn claims(&self) -> Result<Value> {
+        Ok(json!({
+            "tee": to_variant_name(&self.tee)?,
+            "evaluation-reports": self.policies,
+            "tcb-status": self.tcb_status,
+            "reference-data": self.reference_data,
+        }))
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ServiceError {
     #[error("io error: {0}")]
@@ -88,6 +154,8 @@ pub enum ServiceError {
     UnsupportedPolicy(#[source] strum::ParseError),
     #[error("Create rvps failed: {0}")]
     Rvps(#[source] RvpsError),
+    #[error("AS storage error: {0}")]
+    Storage(#[source] anyhow::Error),
     #[error(transparent)]
     Anyhow(#[from] anyhow::Error),
 }
@@ -97,20 +165,50 @@ pub struct AttestationService {
     policy_engine: Box<dyn PolicyEngine + Send + Sync>,
     rvps: Box<dyn RvpsApi + Send + Sync>,
     token_broker: Box<dyn AttestationTokenBroker + Send + Sync>,
+
+    /// Verifiers by TEE name, created on first use and shared by the
+    /// evaluations, so that root certificates and collateral clients are
+    /// only loaded once.
+    verifiers: Mutex<HashMap<&'static str, Arc<dyn Verifier + Send + Sync>>>,
+
+    /// Captures of the policy evaluations, if enabled.
+    policy_captures: Option<Arc<PolicyCaptures>>,
 }
 
 impl AttestationService {
     /// Create a new Attestation Service instance.
     pub async fn new(config: Config) -> Result<Self, ServiceError> {
         if !config.work_dir.as_path().exists() {
-            fs::create_dir_all(&config.work_dir)
-                .await
-                .map_err(ServiceError::CreateDir)?;
+            storage::create_dir(&config.work_dir).map_err(ServiceError::CreateDir)?;
         }
 
+        let insecure = config.insecure_permissions;
+        storage::check_permissions(&config.work_dir, insecure).map_err(ServiceError::Storage)?;
+        if let Some(signer) = &config.attestation_token_config.signer {
+            storage::check_permissions(Path::new(&signer.key_path), insecure)
+                .map_err(ServiceError::Storage)?;
+        }
+        let kek = config
+            .kek_path
+            .as_deref()
+            .map(|path| {
+                storage::check_permissions(path, insecure)?;
+                Kek::load(path, config.plaintext_migration)
+            })
+            .transpose()
+            .map_err(ServiceError::Storage)?
+            .map(Arc::new);
+
+        let policy_captures = (config.policy_captures > 0)
+            .then(|| Arc::new(PolicyCaptures::new(config.policy_captures)));
         let policy_engine = PolicyEngineType::from_str(&config.policy_engine)
             .map_err(ServiceError::UnsupportedPolicy)?
-            .to_policy_engine(config.work_dir.as_path())?;
+            .to_policy_engine(
+                config.work_dir.as_path(),
+                config.deny_empty_reference_values,
+                kek.clone(),
+                policy_captures.clone(),
+            )?;
 
         let rvps = rvps::initialize_rvps_client(&config.rvps_config)
             .await
@@ -118,16 +216,35 @@ impl AttestationService {
 
         let token_broker = config
             .attestation_token_broker
-            .to_token_broker(config.attestation_token_config.clone())?;
+            .to_token_broker(config.attestation_token_config.clone(), kek.as_deref())?;
 
         Ok(Self {
             _config: config,
             policy_engine,
             rvps,
             token_broker,
+            verifiers: Mutex::new(HashMap::new()),
+            policy_captures,
         })
     }
 
+    /// Get the verifier of `tee`, creating it if this is its first use. A
+    /// verifier that fails to be created is created again on the next use.
+    fn verifier(&self, tee: &Tee) -> Result<Arc<dyn Verifier + Send + Sync>> {
+        let tee_name = to_variant_name(tee)?;
+        let mut verifiers = self
+            .verifiers
+            .lock()
+            .map_err(|_| anyhow!("verifier cache lock poisoned"))?;
+        if let Some(verifier) = verifiers.get(tee_name) {
+            return Ok(verifier.clone());
+        }
+
+        let verifier: Arc<dyn Verifier + Send + Sync> = verifier::to_verifier(tee)?.into();
+        verifiers.insert(tee_name, verifier.clone());
+        Ok(verifier)
+    }
+
     /// Set Attestation Verification Policy.
     pub async fn set_policy(&mut self, policy_id: String, policy: String) -> Result<()> {
         self.policy_engine.set_policy(policy_id, policy).await?;
@@ -151,6 +268,20 @@ impl AttestationService {
             .context("Cannot Get Policy")
     }
 
+    /// Remove Attestation Verification Policy.
+    pub async fn remove_policy(&mut self, policy_id: String) -> Result<()> {
+        self.policy_engine
+            .remove_policy(policy_id)
+            .await
+            .context("Cannot Remove Policy")
+    }
+
+    /// Get the policy evaluations captured for the request with the
+    /// correlation ID `request_id`, if the captures are enabled.
+    pub fn policy_capture(&self, request_id: &str) -> Option<Vec<PolicyCapture>> {
+        self.policy_captures.as_ref()?.get(request_id)
+    }
+
     /// Evaluate Attestation Evidence.
     /// Issue an attestation results token which contain TCB status and TEE public key. Input parameters:
     /// - `evidence`: TEE evidence bytes. This might not be the raw hardware evidence bytes. Definitions
@@ -178,7 +309,103 @@ impl AttestationService {
         init_data_hash_algorithm: HashAlgorithm,
         policy_ids: Vec<String>,
     ) -> Result<String> {
-        let verifier = verifier::to_verifier(&tee)?;
+        self.evaluate_composite(
+            evidence,
+            tee,
+            Vec::new(),
+            runtime_data,
+            runtime_data_hash_algorithm,
+            init_data,
+            init_data_hash_algorithm,
+            policy_ids,
+            None,
+            None,
+        )
+        .await
+    }
+
+    /// Evaluate the evidence of the TEE along with the evidence of further
+    /// sources, `submods`, as [`Self::evaluate`]. The evidence of every source
+    /// is verified against the same runtime and init data and evaluated
+    /// against the policies on its own. The token carries the claims and the
+    /// verdict of every source in its `submods` claim, the ones of the TEE
+    /// under the name of its type, instead of the `tcb-status` and
+    /// `reference-data` claims. The evaluation fails if the evidence of any
+    /// source fails. The claims are compared against the reference values of
+    /// `rvps_namespace`, e.g. the tenant of the attester, or of the default
+    /// namespace if `None`.
+    ///
+    /// `prior_token` is the token of a prior attestation of the attester, if
+    /// any, which must be signed by the token broker of the service, but may
+    /// be expired. The policies get the `tcb-status` of every source in its
+    /// claims as `data.prior`, e.g. to check that no runtime measurements
+    /// were extended since that attestation. A source without claims of the
+    /// same TEE in them has no `data.prior`.
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(name = "evaluate", skip_all, fields(tee = ?tee))]
+    pub async fn evaluate_composite(
+        &self,
+        evidence: Vec<u8>,
+        tee: Tee,
+        submods: Vec<SubmodEvidence>,
+        runtime_data: Option<Data>,
+        runtime_data_hash_algorithm: HashAlgorithm,
+        init_data: Option<Data>,
+        init_data_hash_algorithm: HashAlgorithm,
+        policy_ids: Vec<String>,
+        rvps_namespace: Option<&str>,
+        prior_token: Option<&str>,
+    ) -> Result<String> {
+        let tee_name = to_variant_name(&tee)?;
+        let result = self
+            .evaluate_inner(
+                evidence,
+                tee,
+                submods,
+                runtime_data,
+                runtime_data_hash_algorithm,
+                init_data,
+                init_data_hash_algorithm,
+                policy_ids,
+                rvps_namespace,
+                prior_token,
+            )
+            .await;
+
+        let status = if result.is_ok() { "success" } else { "failure" };
+        metrics::EVALUATIONS
+            .with_label_values(&[tee_name, status])
+            .inc();
+
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn evaluate_inner(
+        &self,
+        evidence: Vec<u8>,
+        tee: Tee,
+        submods: Vec<SubmodEvidence>,
+        runtime_data: Option<Data>,
+        runtime_data_hash_algorithm: HashAlgorithm,
+        init_data: Option<Data>,
+        init_data_hash_algorithm: HashAlgorithm,
+        policy_ids: Vec<String>,
+        rvps_namespace: Option<&str>,
+        prior_token: Option<&str>,
+    ) -> Result<String> {
+        let tee_name = to_variant_name(&tee)?;
+        for (i, submod) in submods.iter().enumerate() {
+            if submod.name == tee_name || submods[..i].iter().any(|s| s.name == submod.name) {
+                bail!("Duplicate evidence submodule {}", submod.name);
+            }
+        }
+
+        let prior_claims = prior_token
+            .map(|token| self.token_broker.verify_signature(token))
+            .transpose()
+            .context("verify prior token")?;
+        let prior_claims = prior_claims.as_ref();
 
         let (report_data, runtime_data_claims) =
             parse_data(runtime_data, &runtime_data_hash_algorithm).context("parse runtime data")?;
@@ -196,28 +423,124 @@ impl AttestationService {
             None => InitDataHash::NotProvided,
         };
 
+        let report = self
+            .evaluate_source(
+                &evidence,
+                tee,
+                &report_data,
+                &init_data_hash,
+                &policy_ids,
+                rvps_namespace,
+                prior_source_claims(prior_claims, tee_name, tee)?,
+            )
+            .await?;
+        let customized_claims = json!({
+            "init_data": init_data_claims,
+            "runtime_data": runtime_data_claims,
+        });
+
+        let token_claims = if submods.is_empty() {
+            json!({
+                "tee": tee_name,
+                "evaluation-reports": report.policies,
+                "tcb-status": report.tcb_status,
+                "reference-data": report.reference_data,
+                "customized_claims": customized_claims,
+            })
+        } else {
+            let mut claims = Map::new();
+            claims.insert(tee_name.to_string(), report.claims()?);
+            for submod in submods {
+                let submod_report = self
+                    .evaluate_source(
+                        &submod.evidence,
+                        submod.tee,
+                        &report_data,
+                        &init_data_hash,
+                        &policy_ids,
+                        rvps_namespace,
+                        prior_source_claims(prior_claims, &submod.name, submod.tee)?,
+                    )
+                    .await
+                    .with_context(|| format!("Evidence submodule {}", submod.name))?;
+                claims.insert(submod.name, submod_report.claims()?);
+            }
+            json!({
+                "tee": tee_name,
+                "evaluation-reports": report.policies,
+                "submods": claims,
+                "customized_claims": customized_claims,
+            })
+        };
+
+        let attestation_results_token = self.token_broker.issue(token_claims)?;
+        info!(
+            "Attestation Token ({}) generated.",
+            self._config.attestation_token_broker
+        );
+
+        Ok(attestation_results_token)
+    }
+
+    /// Verify the `evidence` of `tee` and evaluate its claims against the
+    /// policies, with the reference values of `rvps_namespace` and the claims
+    /// of the source at a prior attestation, `prior_claims`.
+    #[allow(clippy::too_many_arguments)]
+    async fn evaluate_source(
+        &self,
+        evidence: &[u8],
+        tee: Tee,
+        report_data: &ReportData<'_>,
+        init_data_hash: &InitDataHash<'_>,
+        policy_ids: &[String],
+        rvps_namespace: Option<&str>,
+        prior_claims: Option<&Value>,
+    ) -> Result<SourceReport> {
+        let verifier = self.verifier(&tee)?;
+
+        let timer = metrics::VERIFIER_DURATION
+            .with_label_values(&[to_variant_name(&tee)?])
+            .start_timer();
         let claims_from_tee_evidence = verifier
-            .evaluate(&evidence, &report_data, &init_data_hash)
+            .evaluate(evidence, report_data, init_data_hash)
+            .instrument(tracing::info_span!("verifier_evaluate"))
             .await
-            .map_err(|e| anyhow!("Verifier evaluate failed: {e:?}"))?;
+            .map_err(|e| anyhow!("Verifier evaluate failed: {e:?}"));
+        timer.observe_duration();
+        let claims_from_tee_evidence = claims_from_tee_evidence?;
         info!("{:?} Verifier/endorsement check passed.", tee);
 
-        let flattened_claims = flatten_claims(tee, &claims_from_tee_evidence)?;
+        schema::validate_claims(tee, &claims_from_tee_evidence)?;
+
+        let mut flattened_claims = flatten_claims(tee, &claims_from_tee_evidence)?;
         debug!("flattened_claims: {:#?}", flattened_claims);
 
         let tcb_json = serde_json::to_string(&flattened_claims)?;
 
         let reference_data_map = self
-            .get_reference_data(flattened_claims.keys())
+            .get_reference_data(flattened_claims.keys(), rvps_namespace)
             .await
             .map_err(|e| anyhow!("Generate reference data failed: {:?}", e))?;
         debug!("reference_data_map: {:#?}", reference_data_map);
 
         let evaluation_report = self
             .policy_engine
-            .evaluate(reference_data_map.clone(), tcb_json, policy_ids.clone())
-            .await
-            .map_err(|e| anyhow!("Policy Engine evaluation failed: {e}"))?;
+            .evaluate(
+                reference_data_map.clone(),
+                tcb_json,
+                policy_ids.to_vec(),
+                prior_claims,
+            )
+            .instrument(tracing::info_span!("policy_evaluate"))
+            .await;
+        metrics::POLICY_EVALUATIONS
+            .with_label_values(&[if evaluation_report.is_ok() {
+                "pass"
+            } else {
+                "fail"
+            }])
+            .inc();
+        let evaluation_report = evaluation_report.context("Policy Engine evaluation failed")?;
 
         info!("Policy check passed.");
         let policies: Vec<_> = evaluation_report
@@ -230,49 +553,89 @@ impl AttestationService {
             })
             .collect();
 
+        // The policies see every claim, the tokens only the selected ones.
+        let tcb_claims = &self._config.attestation_token_config.tcb_claims;
+        tcb_claims.retain(&mut flattened_claims);
         let reference_data_map: HashMap<String, Vec<String>> = reference_data_map
             .into_iter()
-            .filter(|it| !it.1.is_empty())
+            .filter(|it| !it.1.is_empty() && tcb_claims.allows(&it.0))
             .collect();
 
-        let token_claims = json!({
-            "tee": to_variant_name(&tee)?,
-            "evaluation-reports": policies,
-            "tcb-status": flattened_claims,
-            "reference-data": reference_data_map,
-            "customized_claims": {
-                "init_data": init_data_claims,
-                "runtime_data": runtime_data_claims,
-            },
-        });
-
-        let attestation_results_token = self.token_broker.issue(token_claims)?;
-        info!(
-            "Attestation Token ({}) generated.",
-            self._config.attestation_token_broker
-        );
-
-        Ok(attestation_results_token)
+        Ok(SourceReport {
+            tee,
+            tcb_status: flattened_claims,
+            reference_data: reference_data_map,
+            policies,
+        })
     }
 
-    async fn get_reference_data<'a, I>(&self, tcb_claims: I) -> Result<HashMap<String, Vec<String>>>
+    #[tracing::instrument(skip_all)]
+    async fn get_reference_data<'a, I>(
+        &self,
+        tcb_claims: I,
+        rvps_namespace: Option<&str>,
+    ) -> Result<HashMap<String, Vec<String>>>
     where
         I: Iterator<Item = &'a String>,
     {
         let mut data = HashMap::new();
         for key in tcb_claims {
-            let reference_value = self.rvps.get_digests(key).await?;
+            let reference_value = self
+                .rvps
+                .get_digests(key, rvps_namespace)
+                .await
+                .inspect_err(|_| {
+                    metrics::RVPS_LOOKUPS.with_label_values(&["error"]).inc();
+                })?;
             if !reference_value.is_empty() {
                 debug!("Successfully get reference values of {key} from RVPS.");
+                metrics::RVPS_LOOKUPS.with_label_values(&["hit"]).inc();
+            } else {
+                metrics::RVPS_LOOKUPS.with_label_values(&["miss"]).inc();
             }
             data.insert(key.to_string(), reference_value);
         }
         Ok(data)
     }
 
+    /// Check that the backing services of the AS, i.e. the RVPS, are ready.
+    pub async fn health_check(&self) -> Result<()> {
+        self.rvps.health_check().await
+    }
+
+    /// Get the public keys verifying the attestation tokens, in JWKS format.
+    pub fn token_jwks(&self) -> Result<String> {
+        self.token_broker.pubkey_jwks()
+    }
+
+    /// Sign the evaluation reports of the attestation token `token`, issued
+    /// by this AS, as a JWS of their own, to forward the verdict without the
+    /// claims of the token.
+    pub fn evaluation_report(&self, token: &str) -> Result<String> {
+        self.token_broker.sign_evaluation_reports(token)
+    }
+
+    /// Get the OIDC discovery document of the attestation token issuer, if
+    /// the tokens are issued as OIDC ID tokens.
+    pub fn oidc_discovery(&self) -> Option<Value> {
+        self.token_broker.oidc_discovery()
+    }
+
+    /// Check that an AS can be created from `config` and that its RVPS is
+    /// ready, without serving.
+    pub async fn check_config(config: Config) -> Result<(), ServiceError> {
+        let service = Self::new(config).await?;
+        service.health_check().await.context("RVPS health check")?;
+        Ok(())
+    }
+
     /// Registry a new reference value
-    pub async fn register_reference_value(&mut self, message: &str) -> Result<()> {
-        self.rvps.verify_and_extract(message).await
+    pub async fn register_reference_value(
+        &mut self,
+        message: &str,
+        rvps_namespace: Option<&str>,
+    ) -> Result<()> {
+        self.rvps.verify_and_extract(message, rvps_namespace).await
     }
 
     pub async fn generate_supplemental_challenge(
@@ -280,13 +643,34 @@ impl AttestationService {
         tee: Tee,
         tee_parameters: String,
     ) -> Result<String> {
-        let verifier = verifier::to_verifier(&tee)?;
+        let verifier = self.verifier(&tee)?;
         verifier
             .generate_supplemental_challenge(tee_parameters)
             .await
     }
 }
 
+/// The `tcb-status` of the source `name` of `tee` in `prior_claims`, the
+/// claims of a prior token: the one of the token, or of its submodule `name`
+/// if the evidence was composite.
+fn prior_source_claims<'a>(
+    prior_claims: Option<&'a Value>,
+    name: &str,
+    tee: Tee,
+) -> Result<Option<&'a Value>> {
+    let Some(prior_claims) = prior_claims else {
+        return Ok(None);
+    };
+    let source = match prior_claims.get("submods") {
+        Some(submods) => submods.get(name),
+        None => Some(prior_claims),
+    };
+    let tee_name = to_variant_name(&tee)?;
+    Ok(source
+        .filter(|source| source["tee"] == tee_name)
+        .and_then(|source| source.get("tcb-status")))
+}
+
 /// Get the expected init/runtime data and potential claims due to the given input
 /// and the hash algorithm
 fn parse_data(
@@ -297,10 +681,10 @@ fn parse_data(
         Some(value) => match value {
             Data::Raw(raw) => Ok((Some(raw), Value::Null)),
             Data::Structured(structured) => {
-                // by default serde_json will enforence the alphabet order for keys
-                let hash_materials =
-                    serde_json::to_vec(&structured).context("parse JSON structured data")?;
-                let digest = hash_algorithm.accumulate_hash(hash_materials);
+                // The canonical form is the one the attester hashed, see
+                // `canonical_json`.
+                let hash_materials = canonical_json::to_vec(&structured);
+                let digest = hash_algorithm.accumulate_hash(hash_materials)?;
                 Ok((Some(digest), structured))
             }
         },
@@ -314,12 +698,15 @@ mod tests {
     use rstest::rstest;
     use serde_json::{json, Value};
 
-    use crate::{Data, HashAlgorithm};
+    use std::sync::Arc;
+
+    use crate::{config::Config, AttestationService, Data, HashAlgorithm, SubmodEvidence, Tee};
 
     #[rstest]
     #[case(Some(Data::Raw(b"aaaaa".to_vec())), Some(b"aaaaa".to_vec()), HashAlgorithm::Sha384, Value::Null)]
     #[case(None, None, HashAlgorithm::Sha384, Value::Null)]
     #[case(Some(Data::Structured(json!({"b": 1, "a": "test", "c": {"d": "e"}}))), Some(hex::decode(b"e71ce8e70d814ba6639c3612ebee0ff1f76f650f8dbb5e47157e0f3f525cd22c4597480a186427c813ca941da78870c3").unwrap()), HashAlgorithm::Sha384, json!({"b": 1, "a": "test", "c": {"d": "e"}}))]
+    #[case(Some(Data::Structured(json!({"b": 1.0, "a": "test", "c": {"d": "e"}}))), Some(hex::decode(b"e71ce8e70d814ba6639c3612ebee0ff1f76f650f8dbb5e47157e0f3f525cd22c4597480a186427c813ca941da78870c3").unwrap()), HashAlgorithm::Sha384, json!({"b": 1.0, "a": "test", "c": {"d": "e"}}))]
     fn parse_data_json_binding(
         #[case] input: Option<Data>,
         #[case] expected_data: Option<Vec<u8>>,
@@ -330,4 +717,164 @@ mod tests {
         assert_eq!(data, expected_data);
         assert_json_eq!(data_claims, expected_claims);
     }
+
+    #[tokio::test]
+    async fn test_verifier_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            work_dir: dir.path().join("as"),
+            ..Default::default()
+        };
+        config.rvps_config.store_config = json!({ "file_path": dir.path().join("rvps") });
+        let service = AttestationService::new(config).await.unwrap();
+
+        let verifier = service.verifier(&Tee::Sample).unwrap();
+        assert!(Arc::ptr_eq(
+            &verifier,
+            &service.verifier(&Tee::Sample).unwrap()
+        ));
+        assert_eq!(service.verifiers.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_work_dir_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            work_dir: dir.path().join("as"),
+            ..Default::default()
+        };
+        config.rvps_config.store_config = json!({ "file_path": dir.path().join("rvps") });
+        AttestationService::new(config.clone()).await.unwrap();
+        let mode = std::fs::metadata(&config.work_dir)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        std::fs::set_permissions(&config.work_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(matches!(
+            AttestationService::new(config.clone()).await,
+            Err(crate::ServiceError::Storage(_))
+        ));
+        config.insecure_permissions = true;
+        assert!(AttestationService::new(config).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_composite() {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            work_dir: dir.path().join("as"),
+            ..Default::default()
+        };
+        config.rvps_config.store_config = json!({ "file_path": dir.path().join("rvps") });
+        let service = AttestationService::new(config).await.unwrap();
+
+        let evidence = |svn: &str| {
+            json!({"svn": svn, "report_data": "", "init_data": ""})
+                .to_string()
+                .into_bytes()
+        };
+        let submod = |name: &str, svn: &str| SubmodEvidence {
+            name: name.into(),
+            tee: Tee::Sample,
+            evidence: evidence(svn),
+        };
+        let service = &service;
+        let evaluate = move |submods, prior_token: Option<String>| async move {
+            service
+                .evaluate_composite(
+                    evidence("1"),
+                    Tee::Sample,
+                    submods,
+                    None,
+                    HashAlgorithm::Sha384,
+                    None,
+                    HashAlgorithm::Sha384,
+                    vec!["default".into()],
+                    None,
+                    prior_token.as_deref(),
+                )
+                .await
+        };
+
+        let token = evaluate(vec![submod("gpu0", "2")], None).await.unwrap();
+        let claims = token.split('.').nth(1).unwrap();
+        let claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(claims["tee"], "sample");
+        assert_eq!(claims["evaluation-reports"][0]["policy-id"], "default");
+        assert!(claims.get("tcb-status").is_none());
+        assert_eq!(claims["submods"]["sample"]["tcb-status"]["sample.svn"], "1");
+        assert_eq!(claims["submods"]["gpu0"]["tee"], "sample");
+        assert_eq!(claims["submods"]["gpu0"]["tcb-status"]["sample.svn"], "2");
+        assert_eq!(
+            claims["submods"]["gpu0"]["evaluation-reports"][0]["policy-id"],
+            "default"
+        );
+
+        assert!(evaluate(vec![submod("sample", "2")], None).await.is_err());
+        assert!(
+            evaluate(vec![submod("gpu0", "2"), submod("gpu0", "3")], None)
+                .await
+                .is_err()
+        );
+
+        // Only a prior token signed by the service is accepted.
+        assert!(evaluate(Vec::new(), Some(token.clone())).await.is_ok());
+        let parts: Vec<_> = token.split('.').collect();
+        let forged = URL_SAFE_NO_PAD.encode(json!({"tee": "sample"}).to_string());
+        let forged = format!("{}.{forged}.{}", parts[0], parts[2]);
+        assert!(evaluate(Vec::new(), Some(forged)).await.is_err());
+    }
+
+    #[rstest]
+    #[case(json!({"tee": "sample", "tcb-status": {"sample.svn": "1"}}), "sample", Some(json!({"sample.svn": "1"})))]
+    #[case(json!({"tee": "tdx", "tcb-status": {"tdx.quote.body.rtmr_3": "00"}}), "sample", None)]
+    #[case(json!({"tee": "sample", "submods": {"gpu0": {"tee": "sample", "tcb-status": {"sample.svn": "2"}}}}), "gpu0", Some(json!({"sample.svn": "2"})))]
+    #[case(json!({"tee": "sample", "submods": {"sample": {"tee": "sample", "tcb-status": {}}}}), "gpu0", None)]
+    fn test_prior_source_claims(
+        #[case] prior_claims: Value,
+        #[case] name: &str,
+        #[case] expected: Option<Value>,
+    ) {
+        let claims = crate::prior_source_claims(Some(&prior_claims), name, Tee::Sample).unwrap();
+        assert_eq!(claims, expected.as_ref());
+    }
+
+    #[tokio::test]
+    async fn test_tcb_claims_filter() {
+        use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            work_dir: dir.path().join("as"),
+            ..Default::default()
+        };
+        config.rvps_config.store_config = json!({ "file_path": dir.path().join("rvps") });
+        config.attestation_token_config.tcb_claims.deny = vec!["sample.*_data".into()];
+        let service = AttestationService::new(config).await.unwrap();
+
+        let evidence = json!({"svn": "1", "report_data": "", "init_data": ""});
+        let token = service
+            .evaluate(
+                evidence.to_string().into_bytes(),
+                Tee::Sample,
+                None,
+                HashAlgorithm::Sha384,
+                None,
+                HashAlgorithm::Sha384,
+                vec!["default".into()],
+            )
+            .await
+            .unwrap();
+        let claims = token.split('.').nth(1).unwrap();
+        let claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(claims["tcb-status"], json!({"sample.svn": "1"}));
+    }
 }
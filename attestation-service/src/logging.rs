@@ -0,0 +1,15 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Log output of the Attestation Service.
+//!
+//! The AS binaries write the log records as text, or as one JSON object per
+//! line for log aggregation. The records written while serving a request
+//! carry the correlation ID its client sent, e.g. the KBS, which is passed on
+//! to the RVPS as well. The implementation is shared with KBS.
+
+pub use correlation_log::{correlation_id, with_correlation_id, REQUEST_ID_KEY};
+
+#[cfg(any(feature = "grpc-bin", feature = "restful-bin"))]
+pub use correlation_log::{init, LogFormat};
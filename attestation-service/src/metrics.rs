@@ -0,0 +1,69 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Prometheus metrics of the Attestation Service.
+//!
+//! All metrics are registered in the default registry of the `prometheus`
+//! crate, so an embedding KBS exports them together with its own.
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+
+lazy_static! {
+    /// Time spent in the verifier of each TEE type.
+    pub static ref VERIFIER_DURATION: HistogramVec = register_histogram_vec!(
+        "attestation_service_verifier_duration_seconds",
+        "Latency of evidence verification by TEE type",
+        &["tee"]
+    )
+    .unwrap();
+
+    /// Evidence evaluations by TEE type and result (`success` or `failure`).
+    pub static ref EVALUATIONS: IntCounterVec = register_int_counter_vec!(
+        "attestation_service_evaluations_total",
+        "Evidence evaluations by TEE type and result",
+        &["tee", "result"]
+    )
+    .unwrap();
+
+    /// Policy engine verdicts (`pass` or `fail`).
+    pub static ref POLICY_EVALUATIONS: IntCounterVec = register_int_counter_vec!(
+        "attestation_service_policy_evaluations_total",
+        "Attestation policy evaluations by result",
+        &["result"]
+    )
+    .unwrap();
+
+    /// Reference value lookups by result (`hit`, `miss` or `error`).
+    pub static ref RVPS_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "attestation_service_rvps_lookups_total",
+        "Reference value lookups by result",
+        &["result"]
+    )
+    .unwrap();
+}
+
+/// Encode all metrics of the default registry in the Prometheus text format.
+pub fn encode() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        EVALUATIONS.with_label_values(&["sample", "success"]).inc();
+        let metrics = encode().unwrap();
+        assert!(metrics
+            .contains(r#"attestation_service_evaluations_total{result="success",tee="sample"}"#));
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Captures of the policy evaluations, for policy authors to reproduce the
+//! failures of a request locally.
+//!
+//! The evaluations of a request are captured under the correlation ID its
+//! client sent, see [`crate::logging`]. Only the captures of the latest
+//! requests are kept, and only the first evaluations of each, so that a
+//! client reusing a correlation ID can't make them grow.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Evaluations captured for a request at most.
+const MAX_CAPTURES_PER_REQUEST: usize = 32;
+
+/// The evaluation of a policy.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PolicyCapture {
+    /// ID of the policy.
+    pub policy_id: String,
+
+    /// The input document, i.e. the flattened claims of the evidence.
+    pub input: Value,
+
+    /// The data document, i.e. the reference values under `reference`.
+    pub data: Value,
+
+    /// The `data.policy` document the policy evaluated to, or `null` if it
+    /// couldn't be evaluated.
+    pub output: Value,
+
+    /// Why the evaluation failed, e.g. the policy denied the input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The policy evaluations of the latest `capacity` requests.
+#[derive(Debug)]
+pub struct PolicyCaptures {
+    capacity: usize,
+    requests: Mutex<VecDeque<(String, Vec<PolicyCapture>)>>,
+}
+
+impl PolicyCaptures {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            requests: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Add `capture` to the evaluations of the request `request_id`, dropping
+    /// the evaluations of the oldest request if there are too many requests.
+    /// The evaluations of a request beyond [`MAX_CAPTURES_PER_REQUEST`] are
+    /// dropped.
+    pub fn record(&self, request_id: &str, capture: PolicyCapture) {
+        let Ok(mut requests) = self.requests.lock() else {
+            return;
+        };
+        match requests.iter_mut().find(|(id, _)| id == request_id) {
+            Some((_, captures)) if captures.len() >= MAX_CAPTURES_PER_REQUEST => {}
+            Some((_, captures)) => captures.push(capture),
+            None => {
+                if requests.len() >= self.capacity {
+                    requests.pop_front();
+                }
+                requests.push_back((request_id.to_string(), vec![capture]));
+            }
+        }
+    }
+
+    /// The evaluations captured for the request `request_id`.
+    pub fn get(&self, request_id: &str) -> Option<Vec<PolicyCapture>> {
+        let requests = self.requests.lock().ok()?;
+        requests
+            .iter()
+            .find(|(id, _)| id == request_id)
+            .map(|(_, captures)| captures.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn capture(policy_id: &str) -> PolicyCapture {
+        PolicyCapture {
+            policy_id: policy_id.into(),
+            input: json!({"svn": "1"}),
+            data: json!({"reference": {"svn": ["1"]}}),
+            output: json!({"allow": true}),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_captures() {
+        let captures = PolicyCaptures::new(2);
+        captures.record("req-1", capture("default"));
+        captures.record("req-1", capture("prod"));
+        captures.record("req-2", capture("default"));
+        assert_eq!(
+            captures.get("req-1").unwrap(),
+            vec![capture("default"), capture("prod")]
+        );
+
+        // The oldest request is dropped.
+        captures.record("req-3", capture("default"));
+        assert_eq!(captures.get("req-1"), None);
+        assert_eq!(captures.get("req-2").unwrap(), vec![capture("default")]);
+        assert_eq!(captures.get("req-3").unwrap(), vec![capture("default")]);
+    }
+
+    #[test]
+    fn test_captures_per_request() {
+        let captures = PolicyCaptures::new(2);
+        for _ in 0..MAX_CAPTURES_PER_REQUEST + 1 {
+            captures.record("req-1", capture("default"));
+        }
+        assert_eq!(
+            captures.get("req-1").unwrap().len(),
+            MAX_CAPTURES_PER_REQUEST
+        );
+    }
+}
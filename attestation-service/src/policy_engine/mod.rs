@@ -1,13 +1,19 @@
 use crate::policy_engine::opa::RegoError;
+use crate::storage::Kek;
 use anyhow::Result;
 use async_trait::async_trait;
 use serde::Deserialize;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::Arc;
 use strum::EnumString;
 
+pub mod capture;
 pub mod opa;
 
+use capture::PolicyCaptures;
+
 #[derive(Debug, EnumString, Deserialize)]
 #[strum(ascii_case_insensitive)]
 pub enum PolicyEngineType {
@@ -15,10 +21,20 @@ pub enum PolicyEngineType {
 }
 
 impl PolicyEngineType {
-    pub fn to_policy_engine(&self, work_dir: &Path) -> Result<Box<dyn PolicyEngine + Send + Sync>> {
+    pub fn to_policy_engine(
+        &self,
+        work_dir: &Path,
+        deny_empty_reference_values: bool,
+        kek: Option<Arc<Kek>>,
+        captures: Option<Arc<PolicyCaptures>>,
+    ) -> Result<Box<dyn PolicyEngine + Send + Sync>> {
         match self {
-            PolicyEngineType::OPA => Ok(Box::new(opa::OPA::new(work_dir.to_path_buf())?)
-                as Box<dyn PolicyEngine + Send + Sync>),
+            PolicyEngineType::OPA => Ok(Box::new(opa::OPA::new(
+                work_dir.to_path_buf(),
+                deny_empty_reference_values,
+                kek,
+                captures,
+            )?) as Box<dyn PolicyEngine + Send + Sync>),
         }
     }
 }
@@ -33,11 +49,16 @@ pub trait PolicyEngine {
     /// The result is a key-value map.
     /// - `key`: the policy id
     /// - `value`: the digest of the policy (using **Sha384**).
+    ///
+    /// `prior_claims` are the claims of the same source at a prior
+    /// attestation of the attester, e.g. its boot-time measurements, for the
+    /// policies to compare the input with.
     async fn evaluate(
         &self,
         reference_data_map: HashMap<String, Vec<String>>,
         input: String,
         policy_ids: Vec<String>,
+        prior_claims: Option<&Value>,
     ) -> Result<HashMap<String, PolicyDigest>, RegoError>;
 
     async fn set_policy(&mut self, policy_id: String, policy: String) -> Result<(), RegoError>;
@@ -47,4 +68,6 @@ pub trait PolicyEngine {
     async fn list_policies(&self) -> Result<HashMap<String, PolicyDigest>, RegoError>;
 
     async fn get_policy(&self, policy_id: String) -> Result<String, RegoError>;
+
+    async fn remove_policy(&mut self, policy_id: String) -> Result<(), RegoError>;
 }
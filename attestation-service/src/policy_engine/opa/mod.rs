@@ -5,18 +5,32 @@
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use base64::Engine;
+use futures::future::try_join_all;
+use serde_json::{json, Value};
 use sha2::{Digest, Sha384};
-use std::collections::HashMap;
-use std::fs;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 use thiserror::Error;
 
+use super::capture::{PolicyCapture, PolicyCaptures};
 use super::{PolicyDigest, PolicyEngine};
+use crate::storage::{self, Kek};
 
 #[derive(Debug, Clone)]
 pub struct OPA {
     policy_dir_path: PathBuf,
+
+    /// Fail the policies using claims without reference values, unless they
+    /// override it.
+    deny_empty_reference_values: bool,
+
+    /// Key encrypting the policy files.
+    kek: Option<Arc<Kek>>,
+
+    /// Captures of the evaluations, if enabled.
+    captures: Option<Arc<PolicyCaptures>>,
 }
 
 #[derive(Error, Debug)]
@@ -31,10 +45,21 @@ pub enum RegoError {
     ReadPolicyFileFailed(#[source] io::Error),
     #[error("Failed to write OPA policy to file: {0}")]
     WritePolicyFileFailed(#[source] io::Error),
+    #[error("Failed to remove OPA policy file: {0}")]
+    RemovePolicyFileFailed(#[source] io::Error),
+    #[error("Failed to encrypt OPA policy: {0}")]
+    EncryptPolicyFailed(#[source] anyhow::Error),
+    #[error("Failed to decrypt OPA policy: {0}")]
+    DecryptPolicyFailed(#[source] anyhow::Error),
     #[error("Failed to load policy: {0}")]
     LoadPolicyFailed(#[source] anyhow::Error),
     #[error("Policy evaluation denied for {policy_id}")]
     PolicyDenied { policy_id: String },
+    #[error("Policy {policy_id} uses claims without reference values: {}", claims.join(", "))]
+    EmptyReferenceValues {
+        policy_id: String,
+        claims: Vec<String>,
+    },
     #[error("Serde json error: {0}")]
     SerdeJsonError(#[from] serde_json::Error),
     #[error("IO error: {0}")]
@@ -54,12 +79,17 @@ pub enum RegoError {
 }
 
 impl OPA {
-    pub fn new(work_dir: PathBuf) -> Result<Self, RegoError> {
+    pub fn new(
+        work_dir: PathBuf,
+        deny_empty_reference_values: bool,
+        kek: Option<Arc<Kek>>,
+        captures: Option<Arc<PolicyCaptures>>,
+    ) -> Result<Self, RegoError> {
         let mut policy_dir_path = work_dir;
 
         policy_dir_path.push("opa");
         if !policy_dir_path.as_path().exists() {
-            fs::create_dir_all(&policy_dir_path).map_err(RegoError::CreatePolicyDirFailed)?;
+            storage::create_dir(&policy_dir_path).map_err(RegoError::CreatePolicyDirFailed)?;
         }
 
         let mut default_policy_path = PathBuf::from(
@@ -69,11 +99,179 @@ impl OPA {
         );
         default_policy_path.push("default.rego");
         if !default_policy_path.as_path().exists() {
-            let policy = std::include_str!("default_policy.rego").to_string();
-            fs::write(&default_policy_path, policy).map_err(RegoError::WriteDefaultPolicyFailed)?;
+            let policy = std::include_str!("default_policy.rego").as_bytes().to_vec();
+            let policy = storage::seal(kek.as_deref(), &policy_aad("default"), policy)
+                .map_err(RegoError::EncryptPolicyFailed)?;
+            storage::write_file(&default_policy_path, &policy)
+                .map_err(RegoError::WriteDefaultPolicyFailed)?;
         }
 
-        Ok(Self { policy_dir_path })
+        Ok(Self {
+            policy_dir_path,
+            deny_empty_reference_values,
+            kek,
+            captures,
+        })
+    }
+
+    /// Read the policy of id `policy_id`, decrypting it if needed.
+    async fn read_policy(&self, policy_id: &str) -> Result<Vec<u8>, RegoError> {
+        let policy_file_path = self.policy_dir_path.join(format!("{policy_id}.rego"));
+        let policy = tokio::fs::read(policy_file_path)
+            .await
+            .map_err(RegoError::ReadPolicyFileFailed)?;
+        let policy = storage::open(self.kek.as_deref(), &policy_aad(policy_id), policy)
+            .map_err(RegoError::DecryptPolicyFailed)?;
+        Ok(policy.to_vec())
+    }
+
+    /// Evaluate the policy `policy` of id `policy_id`. Returns the id and the
+    /// digest of the policy if it allows `input`.
+    ///
+    /// `empty_claims` are the claims of `reference_data` without reference
+    /// values. If `deny_empty_reference_values`, or the policy overrides it,
+    /// the policy fails when it uses one of them.
+    ///
+    /// If `document` is given, the whole `data.policy` document is evaluated
+    /// once, the decision taken from its rules, and kept in `document` for
+    /// the capture of the evaluation.
+    fn evaluate_policy(
+        policy_id: String,
+        policy: String,
+        reference_data: &str,
+        empty_claims: &[String],
+        input: &str,
+        deny_empty_reference_values: bool,
+        document: Option<&mut Value>,
+    ) -> Result<(String, PolicyDigest), RegoError> {
+        let mut engine = regorus::Engine::new();
+
+        let policy_hash = {
+            let mut hasher = Sha384::new();
+            hasher.update(&policy);
+            hex::encode(hasher.finalize())
+        };
+        let referenced_claims = referenced_claims(&policy);
+
+        // Add policy as data
+        engine
+            .add_policy(policy_id.clone(), policy)
+            .map_err(RegoError::LoadPolicyFailed)?;
+
+        let reference_data = regorus::Value::from_json_str(reference_data)
+            .map_err(RegoError::JsonSerializationFailed)?;
+        engine
+            .add_data(reference_data)
+            .map_err(RegoError::LoadReferenceDataFailed)?;
+
+        // Add TCB claims as input
+        engine
+            .set_input_json(input)
+            .context("set input")
+            .map_err(RegoError::SetInputDataFailed)?;
+
+        let document = match document {
+            Some(document) => {
+                *document = Self::policy_document(&mut engine)?;
+                Some(&*document)
+            }
+            None => None,
+        };
+        let rule = |name: &str| -> Result<Option<bool>, RegoError> {
+            match document.and_then(|document| document.get(name)) {
+                Some(value) => Ok(Some(
+                    value
+                        .as_bool()
+                        .with_context(|| format!("{name} is not a boolean"))
+                        .map_err(RegoError::EvalPolicyFailed)?,
+                )),
+                None => Ok(None),
+            }
+        };
+
+        let deny_empty_reference_values = match document {
+            Some(_) => rule("deny_empty_reference_values")?,
+            None => Self::deny_empty_reference_values(&mut engine)?,
+        }
+        .unwrap_or(deny_empty_reference_values);
+        if deny_empty_reference_values {
+            let claims: Vec<_> = empty_claims
+                .iter()
+                .filter(|claim| match &referenced_claims {
+                    Some(referenced_claims) => referenced_claims.contains(*claim),
+                    None => true,
+                })
+                .cloned()
+                .collect();
+            if !claims.is_empty() {
+                return Err(RegoError::EmptyReferenceValues { policy_id, claims });
+            }
+        }
+
+        let allow = match document {
+            // An undefined `allow` denies, as with the query.
+            Some(_) => rule("allow")?.unwrap_or(false),
+            None => engine
+                .eval_bool_query("data.policy.allow".to_string(), false)
+                .map_err(RegoError::EvalPolicyFailed)?,
+        };
+        if !allow {
+            return Err(RegoError::PolicyDenied { policy_id });
+        }
+
+        Ok((policy_id, policy_hash))
+    }
+
+    /// The `deny_empty_reference_values` rule of the policy loaded in
+    /// `engine`, if it is defined.
+    fn deny_empty_reference_values(
+        engine: &mut regorus::Engine,
+    ) -> Result<Option<bool>, RegoError> {
+        let results = engine
+            .eval_query("data.policy.deny_empty_reference_values".to_string(), false)
+            .map_err(RegoError::EvalPolicyFailed)?;
+        match results.result.first().and_then(|r| r.expressions.first()) {
+            Some(expression) => Ok(Some(
+                *expression
+                    .value
+                    .as_bool()
+                    .context("deny_empty_reference_values is not a boolean")
+                    .map_err(RegoError::EvalPolicyFailed)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// The `data.policy` document of the policy loaded in `engine`, with
+    /// every rule of the policy and not only `allow`.
+    fn policy_document(engine: &mut regorus::Engine) -> Result<Value, RegoError> {
+        let document = (|| -> Result<Value> {
+            let results = engine.eval_query("data.policy".to_string(), false)?;
+            match results.result.first().and_then(|r| r.expressions.first()) {
+                Some(expression) => Ok(serde_json::from_str(&expression.value.to_json_str()?)?),
+                None => Ok(Value::Null),
+            }
+        })();
+        document.map_err(RegoError::EvalPolicyFailed)
+    }
+
+    /// Capture the evaluation of the policy of id `policy_id` with
+    /// `reference_data` and `input`, whose `data.policy` document is
+    /// `document`, and which ended with `result`.
+    fn capture_policy(
+        policy_id: String,
+        reference_data: &str,
+        input: &str,
+        document: Value,
+        result: &Result<(String, PolicyDigest), RegoError>,
+    ) -> PolicyCapture {
+        PolicyCapture {
+            policy_id,
+            input: serde_json::from_str(input).unwrap_or_default(),
+            data: serde_json::from_str(reference_data).unwrap_or_default(),
+            output: document,
+            error: result.as_ref().err().map(ToString::to_string),
+        }
     }
 
     fn is_valid_policy_id(policy_id: &str) -> bool {
@@ -83,6 +281,43 @@ impl OPA {
     }
 }
 
+/// Additional data of the policy of id `policy_id` encrypted with the KEK, so
+/// that its file can't be swapped with the one of another policy.
+fn policy_aad(policy_id: &str) -> Vec<u8> {
+    format!("policy/{policy_id}").into_bytes()
+}
+
+/// Claims of the reference data used by `policy`, as `data.reference.claim`
+/// or `data.reference["claim"]`. `None` if the policy may use any of them,
+/// e.g. indexing the reference data with a variable, like the default policy.
+fn referenced_claims(policy: &str) -> Option<HashSet<String>> {
+    const REFERENCE: &str = "data.reference";
+
+    let mut claims = HashSet::new();
+    for line in policy.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        if line.contains("data[") {
+            return None;
+        }
+        for (index, _) in line.match_indices(REFERENCE) {
+            let rest = &line[index + REFERENCE.len()..];
+            let claim = if let Some(rest) = rest.strip_prefix('.') {
+                rest.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .next()
+            } else if let Some(rest) = rest.strip_prefix("[\"") {
+                rest.split_once("\"]").map(|(claim, _)| claim)
+            } else {
+                None
+            };
+            match claim {
+                Some(claim) if !claim.is_empty() => claims.insert(claim.to_string()),
+                _ => return None,
+            };
+        }
+    }
+    Some(claims)
+}
+
 #[async_trait]
 impl PolicyEngine for OPA {
     async fn evaluate(
@@ -90,64 +325,73 @@ impl PolicyEngine for OPA {
         reference_data_map: HashMap<String, Vec<String>>,
         input: String,
         policy_ids: Vec<String>,
+        prior_claims: Option<&Value>,
     ) -> Result<HashMap<String, PolicyDigest>, RegoError> {
-        let mut res = HashMap::new();
-
-        let policy_dir_path = self
-            .policy_dir_path
-            .to_str()
-            .ok_or_else(|| RegoError::PolicyDirPathToStringFailed)?;
-
-        for policy_id in &policy_ids {
+        let mut empty_claims: Vec<_> = reference_data_map
+            .iter()
+            .filter(|(_, values)| values.is_empty())
+            .map(|(claim, _)| claim.clone())
+            .collect();
+        empty_claims.sort();
+        let empty_claims = Arc::new(empty_claims);
+
+        // The claims of the prior attestation are `data.prior`, and only
+        // defined if there was one.
+        let mut reference_data = json!({ "reference": reference_data_map });
+        if let Some(prior_claims) = prior_claims {
+            reference_data["prior"] = prior_claims.clone();
+        }
+        let reference_data = Arc::new(reference_data.to_string());
+        let input = Arc::new(input);
+        let deny_empty_reference_values = self.deny_empty_reference_values;
+        // The evaluations are captured under the correlation ID of the
+        // request, which isn't visible from the blocking threads.
+        let capture = self.captures.clone().zip(crate::logging::correlation_id());
+
+        // The policies are independent, so they are evaluated concurrently on
+        // the blocking threads, failing as soon as one of them fails.
+        let evaluations = policy_ids.into_iter().map(|policy_id| {
+            let reference_data = reference_data.clone();
+            let empty_claims = empty_claims.clone();
             let input = input.clone();
-            let policy_file_path = format!("{policy_dir_path}/{policy_id}.rego");
-
-            let policy = tokio::fs::read_to_string(policy_file_path.clone())
+            let capture = capture.clone();
+            async move {
+                let policy =
+                    String::from_utf8(self.read_policy(&policy_id).await?).map_err(|e| {
+                        RegoError::ReadPolicyFileFailed(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            e,
+                        ))
+                    })?;
+                tokio::task::spawn_blocking(move || {
+                    let mut document = Value::Null;
+                    let result = Self::evaluate_policy(
+                        policy_id.clone(),
+                        policy,
+                        &reference_data,
+                        &empty_claims,
+                        &input,
+                        deny_empty_reference_values,
+                        capture.is_some().then_some(&mut document),
+                    );
+                    if let Some((captures, request_id)) = capture {
+                        let capture = Self::capture_policy(
+                            policy_id,
+                            &reference_data,
+                            &input,
+                            document,
+                            &result,
+                        );
+                        captures.record(&request_id, capture);
+                    }
+                    result
+                })
                 .await
-                .map_err(RegoError::ReadPolicyFileFailed)?;
-
-            let mut engine = regorus::Engine::new();
-
-            let policy_hash = {
-                use sha2::Digest;
-                let mut hasher = sha2::Sha384::new();
-                hasher.update(&policy);
-                let hex = hasher.finalize().to_vec();
-                hex::encode(hex)
-            };
-
-            // Add policy as data
-            engine
-                .add_policy(policy_id.clone(), policy)
-                .map_err(RegoError::LoadPolicyFailed)?;
-
-            let reference_data_map = serde_json::to_string(&reference_data_map)?;
-            let reference_data_map =
-                regorus::Value::from_json_str(&format!("{{\"reference\":{reference_data_map}}}"))
-                    .map_err(RegoError::JsonSerializationFailed)?;
-            engine
-                .add_data(reference_data_map)
-                .map_err(RegoError::LoadReferenceDataFailed)?;
-
-            // Add TCB claims as input
-            engine
-                .set_input_json(&input)
-                .context("set input")
-                .map_err(RegoError::SetInputDataFailed)?;
-
-            let allow = engine
-                .eval_bool_query("data.policy.allow".to_string(), false)
-                .map_err(RegoError::EvalPolicyFailed)?;
-            if !allow {
-                return Err(RegoError::PolicyDenied {
-                    policy_id: policy_id.clone(),
-                });
+                .map_err(|e| RegoError::EvalPolicyFailed(e.into()))?
             }
+        });
 
-            res.insert(policy_id.clone(), policy_hash);
-        }
-
-        Ok(res)
+        Ok(try_join_all(evaluations).await?.into_iter().collect())
     }
 
     async fn set_policy(&mut self, policy_id: String, policy: String) -> Result<(), RegoError> {
@@ -168,8 +412,12 @@ impl PolicyEngine for OPA {
 
         policy_file_path.push(format!("{}.rego", policy_id));
 
-        tokio::fs::write(&policy_file_path, policy_bytes)
+        let policy_bytes =
+            storage::seal(self.kek.as_deref(), &policy_aad(&policy_id), policy_bytes)
+                .map_err(RegoError::EncryptPolicyFailed)?;
+        tokio::task::spawn_blocking(move || storage::write_file(&policy_file_path, &policy_bytes))
             .await
+            .map_err(|e| RegoError::WritePolicyFileFailed(e.into()))?
             .map_err(RegoError::WritePolicyFileFailed)
     }
 
@@ -190,10 +438,7 @@ impl PolicyEngine for OPA {
         let mut policy_list = HashMap::new();
 
         for id in policy_ids.iter() {
-            let policy_file_path = self.policy_dir_path.join(format!("{id}.rego"));
-            let policy = tokio::fs::read(policy_file_path)
-                .await
-                .map_err(RegoError::ReadPolicyFileFailed)?;
+            let policy = self.read_policy(id).await?;
 
             let mut hasher = Sha384::new();
             hasher.update(policy);
@@ -208,19 +453,31 @@ impl PolicyEngine for OPA {
     }
 
     async fn get_policy(&self, policy_id: String) -> Result<String, RegoError> {
-        let policy_file_path = self.policy_dir_path.join(format!("{policy_id}.rego"));
-        let policy = tokio::fs::read(policy_file_path)
-            .await
-            .map_err(RegoError::ReadPolicyFileFailed)?;
+        if !Self::is_valid_policy_id(&policy_id) {
+            return Err(RegoError::InvalidPolicyId);
+        }
+
+        let policy = self.read_policy(&policy_id).await?;
         let base64_policy = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(policy);
         Ok(base64_policy)
     }
+
+    async fn remove_policy(&mut self, policy_id: String) -> Result<(), RegoError> {
+        if !Self::is_valid_policy_id(&policy_id) {
+            return Err(RegoError::InvalidPolicyId);
+        }
+
+        let policy_file_path = self.policy_dir_path.join(format!("{policy_id}.rego"));
+        tokio::fs::remove_file(policy_file_path)
+            .await
+            .map_err(RegoError::RemovePolicyFileFailed)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json::json;
+    use rstest::rstest;
 
     fn dummy_reference(ver: u64) -> String {
         json!({
@@ -242,6 +499,9 @@ mod tests {
     async fn test_evaluate() {
         let opa = OPA {
             policy_dir_path: PathBuf::from("./src/policy_engine/opa"),
+            deny_empty_reference_values: false,
+            kek: None,
+            captures: None,
         };
         let default_policy_id = "default_policy".to_string();
 
@@ -253,6 +513,7 @@ mod tests {
                 reference_data.clone(),
                 dummy_input(5, 5),
                 vec![default_policy_id.clone()],
+                None,
             )
             .await;
         let res = res.expect("OPA execution should succeed");
@@ -261,15 +522,216 @@ mod tests {
         assert_eq!(expected_digest, res["default_policy"]);
 
         let res = opa
-            .evaluate(reference_data, dummy_input(0, 0), vec![default_policy_id])
+            .evaluate(
+                reference_data,
+                dummy_input(0, 0),
+                vec![default_policy_id],
+                None,
+            )
             .await;
 
         res.expect_err("OPA execution should fail");
     }
 
+    #[tokio::test]
+    async fn test_evaluate_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut opa = OPA::new(dir.path().to_path_buf(), false, None, None).unwrap();
+        for (policy_id, allow) in [("allow", true), ("deny", false)] {
+            let policy = format!("package policy\ndefault allow = {allow}");
+            opa.set_policy(
+                policy_id.to_string(),
+                base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(policy),
+            )
+            .await
+            .unwrap();
+        }
+        let reference_data: HashMap<String, Vec<String>> =
+            serde_json::from_str(&dummy_reference(5)).unwrap();
+        let policy_ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect();
+
+        let res = opa
+            .evaluate(
+                reference_data.clone(),
+                dummy_input(5, 5),
+                policy_ids(&["default", "allow"]),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(
+            res["allow"],
+            hex::encode(Sha384::digest("package policy\ndefault allow = true"))
+        );
+
+        let res = opa
+            .evaluate(
+                reference_data,
+                dummy_input(5, 5),
+                policy_ids(&["default", "deny", "allow"]),
+                None,
+            )
+            .await;
+        assert!(matches!(res, Err(RegoError::PolicyDenied { policy_id }) if policy_id == "deny"));
+    }
+
+    #[tokio::test]
+    async fn test_capture_evaluations() {
+        let dir = tempfile::tempdir().unwrap();
+        let captures = Arc::new(PolicyCaptures::new(8));
+        let mut opa = OPA::new(
+            dir.path().to_path_buf(),
+            false,
+            None,
+            Some(captures.clone()),
+        )
+        .unwrap();
+        let policy = "package policy\ndefault allow = false\nsvn_ok { input.svn == \"6\" }";
+        opa.set_policy(
+            "deny".to_string(),
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(policy),
+        )
+        .await
+        .unwrap();
+        let reference_data: HashMap<String, Vec<String>> =
+            serde_json::from_str(&dummy_reference(5)).unwrap();
+
+        // Evaluations outside of a request aren't captured.
+        opa.evaluate(
+            reference_data.clone(),
+            dummy_input(5, 5),
+            vec!["default".to_string()],
+            None,
+        )
+        .await
+        .unwrap();
+        let evaluation = opa.evaluate(
+            reference_data,
+            dummy_input(5, 6),
+            vec!["deny".to_string()],
+            None,
+        );
+        crate::logging::with_correlation_id(Some("req-1".into()), evaluation)
+            .await
+            .unwrap_err();
+
+        let captured = captures.get("req-1").unwrap();
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].policy_id, "deny");
+        assert_eq!(captured[0].input, json!({"productId": "5", "svn": "6"}));
+        assert_eq!(
+            captured[0].data,
+            json!({"reference": {"productId": ["5"], "svn": ["5"]}})
+        );
+        assert_eq!(captured[0].output, json!({"allow": false, "svn_ok": true}));
+        assert_eq!(
+            captured[0].error.as_deref(),
+            Some("Policy evaluation denied for deny")
+        );
+    }
+
+    #[rstest]
+    #[case(Some(json!({"svn": "5"})), true)]
+    #[case(Some(json!({"svn": "4"})), false)]
+    #[case(None, false)]
+    #[tokio::test]
+    async fn test_prior_claims(#[case] prior_claims: Option<Value>, #[case] allowed: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut opa = OPA::new(dir.path().to_path_buf(), false, None, None).unwrap();
+        let policy = "package policy\ndefault allow = false\nallow { input.svn == data.prior.svn }";
+        opa.set_policy(
+            "unchanged".to_string(),
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(policy),
+        )
+        .await
+        .unwrap();
+
+        let res = opa
+            .evaluate(
+                HashMap::new(),
+                dummy_input(5, 5),
+                vec!["unchanged".to_string()],
+                prior_claims.as_ref(),
+            )
+            .await;
+        assert_eq!(res.is_ok(), allowed);
+    }
+
+    #[rstest]
+    #[case("package policy\ndefault allow = true", Some(vec![]))]
+    #[case(
+        "allow { input.svn == data.reference.svn[_] }\n# data.reference[k]",
+        Some(vec!["svn"])
+    )]
+    #[case(
+        r#"allow { input["a.b"] == data.reference["a.b"][_]; data.reference.c }"#,
+        Some(vec!["a.b", "c"])
+    )]
+    #[case("allow { v := data.reference[k] }", None)]
+    #[case("allow { ref := data.reference }", None)]
+    #[case(r#"allow { data["reference"].svn }"#, None)]
+    fn test_referenced_claims(#[case] policy: &str, #[case] expected: Option<Vec<&str>>) {
+        let expected = expected.map(|claims| claims.into_iter().map(str::to_string).collect());
+        assert_eq!(referenced_claims(policy), expected);
+    }
+
+    #[tokio::test]
+    async fn test_deny_empty_reference_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let policies = [
+            ("svn", "package policy\nallow { input.svn == data.reference.svn[_] }\n"),
+            ("version", "package policy\nallow { data.reference.productId == [] }\n"),
+            (
+                "lenient",
+                "package policy\ndeny_empty_reference_values = false\nallow { data.reference.productId == [] }\n",
+            ),
+        ];
+        let mut opa = OPA::new(dir.path().to_path_buf(), true, None, None).unwrap();
+        for (policy_id, policy) in policies {
+            opa.set_policy(
+                policy_id.to_string(),
+                base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(policy),
+            )
+            .await
+            .unwrap();
+        }
+        let reference_data: HashMap<String, Vec<String>> =
+            serde_json::from_str(r#"{"productId": [], "svn": ["5"]}"#).unwrap();
+        let evaluate = |policy_id: &str| {
+            opa.evaluate(
+                reference_data.clone(),
+                dummy_input(5, 5),
+                vec![policy_id.to_string()],
+                None,
+            )
+        };
+
+        // The policy doesn't use `productId`.
+        evaluate("svn").await.unwrap();
+        evaluate("lenient").await.unwrap();
+        for policy_id in ["version", "default"] {
+            let res = evaluate(policy_id).await;
+            assert!(
+                matches!(&res, Err(RegoError::EmptyReferenceValues { claims, .. }) if claims == &["productId"]),
+                "{policy_id}: {res:?}"
+            );
+        }
+
+        opa.deny_empty_reference_values = false;
+        opa.evaluate(
+            reference_data,
+            dummy_input(5, 5),
+            vec!["version".to_string()],
+            None,
+        )
+        .await
+        .unwrap();
+    }
+
     #[tokio::test]
     async fn test_policy_management() {
-        let mut opa = OPA::new(PathBuf::from("tests/tmp")).unwrap();
+        let mut opa = OPA::new(PathBuf::from("tests/tmp"), false, None, None).unwrap();
         let policy = "package policy
 default allow = true"
             .to_string();
@@ -288,5 +750,85 @@ default allow = true"
         let test_policy = opa.get_policy("test".to_string()).await.unwrap();
         assert_eq!(test_policy, get_policy_output);
         assert!(opa.list_policies().await.is_ok());
+
+        opa.remove_policy("test".to_string()).await.unwrap();
+        assert_eq!(opa.list_policies().await.unwrap().len(), 1);
+        assert!(opa.get_policy("test".to_string()).await.is_err());
+        assert!(opa.remove_policy("test".to_string()).await.is_err());
+        assert!(opa.get_policy("../test".to_string()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let kek_path = dir.path().join("kek");
+        std::fs::write(&kek_path, [7; 32]).unwrap();
+        let kek = Some(Arc::new(Kek::load(&kek_path, false).unwrap()));
+
+        let work_dir = dir.path().join("as");
+        let mut opa = OPA::new(work_dir.clone(), false, kek, None).unwrap();
+        // An engine without the KEK can't read the encrypted policies.
+        let plain = OPA::new(work_dir.clone(), false, None, None).unwrap();
+        let policy = "package policy\ndefault allow = true";
+        opa.set_policy(
+            "test".to_string(),
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(policy),
+        )
+        .await
+        .unwrap();
+
+        let stored = std::fs::read(work_dir.join("opa/test.rego")).unwrap();
+        assert!(stored.starts_with(storage::SEALED_MAGIC));
+        assert!(plain.get_policy("test".to_string()).await.is_err());
+        assert_eq!(
+            opa.get_policy("test".to_string()).await.unwrap(),
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(policy)
+        );
+
+        // The digests are the ones of the decrypted policies.
+        let digest = |policy: &str| {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha384::digest(policy))
+        };
+        let policies = opa.list_policies().await.unwrap();
+        assert_eq!(policies["test"], digest(policy));
+        assert_eq!(
+            policies["default"],
+            digest(include_str!("default_policy.rego"))
+        );
+        let res = opa
+            .evaluate(
+                HashMap::new(),
+                "{}".to_string(),
+                vec!["test".to_string()],
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(res["test"], hex::encode(Sha384::digest(policy)));
+
+        // The file of a policy can't be used as the one of another.
+        std::fs::copy(
+            work_dir.join("opa/test.rego"),
+            work_dir.join("opa/copy.rego"),
+        )
+        .unwrap();
+        assert!(matches!(
+            opa.get_policy("copy".to_string()).await,
+            Err(RegoError::DecryptPolicyFailed(_))
+        ));
+
+        // Policies stored in plaintext are only read while migrating.
+        let legacy = "package policy\ndefault allow = false";
+        std::fs::write(work_dir.join("opa/legacy.rego"), legacy).unwrap();
+        assert!(matches!(
+            opa.get_policy("legacy".to_string()).await,
+            Err(RegoError::DecryptPolicyFailed(_))
+        ));
+        let migrating = Some(Arc::new(Kek::load(&kek_path, true).unwrap()));
+        let migrating = OPA::new(work_dir, false, migrating, None).unwrap();
+        assert_eq!(
+            migrating.get_policy("legacy".to_string()).await.unwrap(),
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(legacy)
+        );
     }
 }
@@ -17,15 +17,15 @@ impl Rvps {
 
 #[async_trait]
 impl RvpsApi for Rvps {
-    async fn verify_and_extract(&mut self, message: &str) -> Result<()> {
-        self.core.verify_and_extract(message).await?;
+    async fn verify_and_extract(&mut self, message: &str, namespace: Option<&str>) -> Result<()> {
+        self.core.verify_and_extract(message, namespace).await?;
         Ok(())
     }
 
-    async fn get_digests(&self, name: &str) -> Result<Vec<String>> {
+    async fn get_digests(&self, name: &str, namespace: Option<&str>) -> Result<Vec<String>> {
         let hashes = self
             .core
-            .get_digests(name)
+            .get_digests(name, namespace)
             .await?
             .unwrap_or_default()
             .hash_values;
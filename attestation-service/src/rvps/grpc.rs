@@ -1,6 +1,9 @@
 use crate::rvps::RvpsError;
-use anyhow::{Context, Result};
-use tokio::sync::Mutex;
+use anyhow::{bail, Context, Result};
+use tonic::transport::Channel;
+use tonic_health::pb::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
 
 use self::rvps_api::{
     reference_value_provider_service_client::ReferenceValueProviderServiceClient,
@@ -13,47 +16,173 @@ pub mod rvps_api {
     tonic::include_proto!("reference");
 }
 
+/// Attach the trace context of the current span and the correlation ID of
+/// the request being served to an outgoing request.
+fn new_request<T>(message: T) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    #[cfg(feature = "opentelemetry")]
+    for (key, value) in crate::telemetry::current_trace_context() {
+        if let (Ok(key), Ok(value)) = (
+            tonic::metadata::MetadataKey::from_bytes(key.as_bytes()),
+            value.parse(),
+        ) {
+            request.metadata_mut().insert(key, value);
+        }
+    }
+    if let Some(value) = crate::logging::correlation_id().and_then(|id| id.parse().ok()) {
+        request
+            .metadata_mut()
+            .insert(crate::logging::REQUEST_ID_KEY, value);
+    }
+    request
+}
+
+/// Name the RVPS gRPC service is registered under in the health service.
+const RVPS_SERVICE_NAME: &str = "reference.ReferenceValueProviderService";
+
 pub struct Agent {
-    client: Mutex<ReferenceValueProviderServiceClient<tonic::transport::Channel>>,
+    /// Cloned for every request, so that the queries of concurrent
+    /// evaluations share the channel instead of waiting for each other.
+    client: ReferenceValueProviderServiceClient<Channel>,
+    health: HealthClient<Channel>,
 }
 
 impl Agent {
     pub async fn new(addr: &str) -> Result<Self, RvpsError> {
+        let channel = Channel::from_shared(addr.to_string())
+            .context("invalid RVPS address")?
+            .connect()
+            .await?;
         Ok(Self {
-            client: Mutex::new(
-                ReferenceValueProviderServiceClient::connect(addr.to_string()).await?,
-            ),
+            client: ReferenceValueProviderServiceClient::new(channel.clone()),
+            health: HealthClient::new(channel),
         })
     }
 }
 #[async_trait::async_trait]
 impl RvpsApi for Agent {
-    async fn verify_and_extract(&mut self, message: &str) -> Result<()> {
-        let req = tonic::Request::new(ReferenceValueRegisterRequest {
+    async fn verify_and_extract(&mut self, message: &str, namespace: Option<&str>) -> Result<()> {
+        let req = new_request(ReferenceValueRegisterRequest {
             message: message.to_string(),
+            namespace: namespace.unwrap_or_default().to_string(),
         });
         let _ = self
             .client
-            .lock()
-            .await
+            .clone()
             .register_reference_value(req)
             .await
             .context("register failed")?;
         Ok(())
     }
 
-    async fn get_digests(&self, name: &str) -> Result<Vec<String>> {
-        let req = tonic::Request::new(ReferenceValueQueryRequest {
+    #[tracing::instrument(skip(self))]
+    async fn get_digests(&self, name: &str, namespace: Option<&str>) -> Result<Vec<String>> {
+        let req = new_request(ReferenceValueQueryRequest {
             name: name.to_string(),
+            namespace: namespace.unwrap_or_default().to_string(),
         });
         let res = self
             .client
-            .lock()
-            .await
+            .clone()
             .query_reference_value(req)
             .await?
             .into_inner();
         let trust_digest = serde_json::from_str(&res.reference_value_results)?;
         Ok(trust_digest)
     }
+
+    async fn health_check(&self) -> Result<()> {
+        let status = self
+            .health
+            .clone()
+            .check(HealthCheckRequest {
+                service: RVPS_SERVICE_NAME.to_string(),
+            })
+            .await
+            .context("RVPS health check failed")?
+            .into_inner()
+            .status();
+        if status != ServingStatus::Serving {
+            bail!("RVPS is {}", status.as_str_name());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rvps_api::reference_value_provider_service_server::{
+        ReferenceValueProviderService, ReferenceValueProviderServiceServer,
+    };
+    use super::rvps_api::{
+        ReferenceValueDeleteRequest, ReferenceValueDeleteResponse, ReferenceValueQueryResponse,
+        ReferenceValueRegisterResponse,
+    };
+    use super::*;
+    use std::{sync::Arc, time::Duration};
+    use tokio::sync::Barrier;
+    use tonic::{Request, Response, Status};
+
+    /// An RVPS answering the queries only once `barrier` is reached by all
+    /// of them.
+    struct BarrierRvps {
+        barrier: Arc<Barrier>,
+    }
+
+    #[tonic::async_trait]
+    impl ReferenceValueProviderService for BarrierRvps {
+        async fn query_reference_value(
+            &self,
+            _request: Request<ReferenceValueQueryRequest>,
+        ) -> Result<Response<ReferenceValueQueryResponse>, Status> {
+            self.barrier.wait().await;
+            Ok(Response::new(ReferenceValueQueryResponse {
+                reference_value_results: r#"["aa"]"#.into(),
+            }))
+        }
+
+        async fn register_reference_value(
+            &self,
+            _request: Request<ReferenceValueRegisterRequest>,
+        ) -> Result<Response<ReferenceValueRegisterResponse>, Status> {
+            Err(Status::unimplemented("register"))
+        }
+
+        async fn delete_reference_value(
+            &self,
+            _request: Request<ReferenceValueDeleteRequest>,
+        ) -> Result<Response<ReferenceValueDeleteResponse>, Status> {
+            Err(Status::unimplemented("delete"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_queries() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        let rvps = BarrierRvps {
+            barrier: Arc::new(Barrier::new(2)),
+        };
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(ReferenceValueProviderServiceServer::new(rvps))
+                .serve_with_incoming(incoming),
+        );
+
+        // Both queries are in flight at once, or neither is answered.
+        let agent = Agent::new(&format!("http://{address}")).await.unwrap();
+        let queries = futures::future::join(
+            agent.get_digests("kernel", None),
+            agent.get_digests("kernel", Some("tenant-a")),
+        );
+        let (a, b) = tokio::time::timeout(Duration::from_secs(5), queries)
+            .await
+            .unwrap();
+        assert_eq!(a.unwrap(), ["aa"]);
+        assert_eq!(b.unwrap(), ["aa"]);
+    }
 }
@@ -14,14 +14,24 @@ use thiserror::Error;
 /// * `verify_and_extract` is responsible for verify a message and
 /// store reference values from it.
 /// * `get_digests` gets trusted digests by the artifact's name.
+///
+/// The reference values of a namespace, e.g. of a tenant, are apart from
+/// the ones of the other namespaces. `None` is the default namespace.
+/// * `health_check` checks whether the RVPS is able to serve requests.
 #[async_trait::async_trait]
 pub trait RvpsApi {
-    /// Verify the given message and register the reference value included.
-    async fn verify_and_extract(&mut self, message: &str) -> Result<()>;
+    /// Verify the given message and register the reference value included
+    /// in `namespace`.
+    async fn verify_and_extract(&mut self, message: &str, namespace: Option<&str>) -> Result<()>;
 
     /// Get the reference values / golden values / expected digests in hex of the
-    /// given component name.
-    async fn get_digests(&self, name: &str) -> Result<Vec<String>>;
+    /// given component name in `namespace`.
+    async fn get_digests(&self, name: &str, namespace: Option<&str>) -> Result<Vec<String>>;
+
+    /// A built-in RVPS is always ready.
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[cfg(feature = "rvps-grpc")]
@@ -0,0 +1,463 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Schemas of the claims emitted by the verifiers.
+//!
+//! The claims parsed from the evidence are validated against the schema of
+//! their TEE before the policies are evaluated, so that the policies and the
+//! attestation tokens can rely on the names and types of the claims.
+//!
+//! The schemas are JSON schemas using the `type`, `properties`, `required`,
+//! `additionalProperties`, `items` and `pattern` keywords.
+
+use anyhow::{bail, Result};
+use kbs_types::Tee;
+use regex::Regex;
+use serde_json::{json, Value};
+use verifier::TeeEvidenceParsedClaim;
+
+/// Lowercase hex string, the encoding of the binary fields of the quotes.
+const HEX: &str = "^[0-9a-f]*$";
+
+/// Validate the claims of `tee` against its schema. The claims of a TEE
+/// without a schema are accepted as they are.
+pub fn validate_claims(tee: Tee, claims: &TeeEvidenceParsedClaim) -> Result<()> {
+    let Some(schema) = schema(tee) else {
+        return Ok(());
+    };
+
+    let mut errors = Vec::new();
+    validate(&schema, claims, "", &mut errors)?;
+    if !errors.is_empty() {
+        bail!(
+            "claims do not match the {tee:?} schema: {}",
+            errors.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The schema of the claims of `tee`.
+fn schema(tee: Tee) -> Option<Value> {
+    match tee {
+        Tee::AzSnpVtpm => Some(with_tpm(snp())),
+        Tee::AzTdxVtpm => Some(with_tpm(tdx())),
+        Tee::Cca => Some(json!({
+            "type": "object",
+            "required": ["realm", "platform"],
+            "properties": {
+                "realm": { "type": "object" },
+                "platform": { "type": "object" }
+            }
+        })),
+        Tee::Csv => Some(strings(
+            &[
+                "policy_nodbg",
+                "policy_noks",
+                "policy_es",
+                "policy_nosend",
+                "policy_domain",
+                "policy_csv",
+                "policy_csv3",
+                "policy_asid_reuse",
+                "policy_hsk_version",
+                "policy_cek_version",
+                "policy_api_major",
+                "policy_api_minor",
+                "user_pubkey_digest",
+                "vm_id",
+                "vm_version",
+                "serial_number",
+                "measurement",
+                "report_data",
+            ],
+            json!({ "type": "string" }),
+        )),
+        Tee::Sample => Some(json!({
+            "type": "object",
+            "required": ["svn", "report_data", "init_data"],
+            "additionalProperties": false,
+            "properties": {
+                "svn": { "type": "string" },
+                "report_data": { "type": "string" },
+                "init_data": { "type": "string" },
+                "tcg_eventlog": { "type": "array" }
+            }
+        })),
+        Tee::Se => Some(json!({
+            "type": "object",
+            "required": ["cuid", "user_data", "version", "image_phkh", "attestation_phkh", "tag"],
+            "additionalProperties": false,
+            "properties": {
+                "cuid": { "type": "string", "pattern": HEX },
+                "user_data": { "type": "string" },
+                "version": { "type": "integer" },
+                "image_phkh": { "type": "string", "pattern": HEX },
+                "attestation_phkh": { "type": "string", "pattern": HEX },
+                "tag": { "type": "string", "pattern": HEX }
+            }
+        })),
+        Tee::Sgx => Some(sgx()),
+        Tee::Snp => Some(snp()),
+        Tee::System => Some(json!({
+            "type": "object",
+            "required": ["system_report", "measurements", "mr_register", "report_data"],
+            "additionalProperties": false,
+            "properties": {
+                "system_report": {},
+                "measurements": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string" }
+                },
+                "mr_register": { "type": "string" },
+                "report_data": { "type": "string" }
+            }
+        })),
+        Tee::Tdx => Some(tdx()),
+        Tee::Sev => None,
+    }
+}
+
+/// Schema of an object with the required properties `names`, all of schema
+/// `property`.
+fn strings(names: &[&str], property: Value) -> Value {
+    let properties: serde_json::Map<_, _> = names
+        .iter()
+        .map(|name| (name.to_string(), property.clone()))
+        .collect();
+    json!({
+        "type": "object",
+        "required": names,
+        "additionalProperties": false,
+        "properties": properties
+    })
+}
+
+fn hex_strings(names: &[&str]) -> Value {
+    strings(names, json!({ "type": "string", "pattern": HEX }))
+}
+
+fn snp() -> Value {
+    strings(
+        &[
+            "policy_abi_major",
+            "policy_abi_minor",
+            "policy_smt_allowed",
+            "policy_migrate_ma",
+            "policy_debug_allowed",
+            "policy_single_socket",
+            "reported_tcb_bootloader",
+            "reported_tcb_tee",
+            "reported_tcb_snp",
+            "reported_tcb_microcode",
+            "platform_tsme_enabled",
+            "platform_smt_enabled",
+            "measurement",
+        ],
+        json!({ "type": "string" }),
+    )
+}
+
+fn sgx() -> Value {
+    json!({
+        "type": "object",
+        "required": ["header", "body", "report_data", "init_data"],
+        "additionalProperties": false,
+        "properties": {
+            "header": hex_strings(&[
+                "version",
+                "att_key_type",
+                "att_key_data_0",
+                "qe_svn",
+                "pce_svn",
+                "vendor_id",
+                "user_data",
+            ]),
+            "body": hex_strings(&[
+                "cpu_svn",
+                "misc_select",
+                "reserved1",
+                "isv_ext_prod_id",
+                "attributes.flags",
+                "attributes.xfrm",
+                "mr_enclave",
+                "reserved2",
+                "mr_signer",
+                "reserved3",
+                "config_id",
+                "isv_prod_id",
+                "isv_svn",
+                "config_svn",
+                "reserved4",
+                "isv_family_id",
+                "report_data",
+            ]),
+            "report_data": { "type": "string", "pattern": HEX },
+            "init_data": { "type": "string", "pattern": HEX }
+        }
+    })
+}
+
+fn tdx() -> Value {
+    let mut body = hex_strings(&[
+        "tcb_svn",
+        "mr_seam",
+        "mrsigner_seam",
+        "seam_attributes",
+        "td_attributes",
+        "xfam",
+        "mr_td",
+        "mr_config_id",
+        "mr_owner",
+        "mr_owner_config",
+        "rtmr_0",
+        "rtmr_1",
+        "rtmr_2",
+        "rtmr_3",
+        "report_data",
+    ]);
+    // TDX 1.5 quotes only.
+    for name in ["tee_tcb_svn2", "mr_servicetd"] {
+        body["properties"][name] = json!({ "type": "string", "pattern": HEX });
+    }
+
+    json!({
+        "type": "object",
+        "required": ["quote", "ccel", "report_data", "init_data"],
+        "additionalProperties": false,
+        "properties": {
+            "quote": {
+                "type": "object",
+                "required": ["header", "body"],
+                "additionalProperties": false,
+                "properties": {
+                    "header": hex_strings(&[
+                        "version",
+                        "att_key_type",
+                        "tee_type",
+                        "reserved",
+                        "vendor_id",
+                        "user_data",
+                    ]),
+                    "body": body,
+                    // Quote format V5 only.
+                    "type": { "type": "string", "pattern": HEX },
+                    "size": { "type": "string", "pattern": HEX }
+                }
+            },
+            "ccel": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "kernel": { "type": "string" },
+                    "kernel_parameters": {
+                        "type": "object",
+                        "additionalProperties": { "type": ["string", "null"] }
+                    }
+                }
+            },
+            "aael": {
+                "type": "object",
+                "additionalProperties": {
+                    "type": "array",
+                    "items": { "type": "string" }
+                }
+            },
+            "report_data": { "type": "string", "pattern": HEX },
+            "init_data": { "type": "string", "pattern": HEX }
+        }
+    })
+}
+
+/// Extend `schema` with the PCRs of the vTPM quote of the Azure CVMs.
+fn with_tpm(mut schema: Value) -> Value {
+    schema["required"]
+        .as_array_mut()
+        .expect("schema of an object")
+        .push(json!("tpm"));
+    schema["properties"]["tpm"] = json!({
+        "type": "object",
+        "additionalProperties": { "type": "string", "pattern": HEX }
+    });
+    schema
+}
+
+/// Validate `value` at `path` against `schema`, adding the mismatches to
+/// `errors`.
+fn validate(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) -> Result<()> {
+    if let Some(types) = schema.get("type") {
+        let types: Vec<_> = match types {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            types => types.as_str().into_iter().collect(),
+        };
+        if !types.iter().any(|t| is_type(value, t)) {
+            errors.push(format!(
+                "{}: expected {}",
+                pointer(path),
+                types.join(" or ")
+            ));
+            return Ok(());
+        }
+    }
+
+    if let (Some(pattern), Value::String(value)) = (schema.get("pattern"), value) {
+        let pattern = pattern.as_str().unwrap_or_default();
+        if !Regex::new(pattern)?.is_match(value) {
+            errors.push(format!("{}: does not match {pattern}", pointer(path)));
+        }
+    }
+
+    if let Value::Object(object) = value {
+        for name in schema["required"].as_array().into_iter().flatten() {
+            let name = name.as_str().unwrap_or_default();
+            if !object.contains_key(name) {
+                errors.push(format!("{}: missing {name}", pointer(path)));
+            }
+        }
+        for (name, property) in object {
+            let property_path = format!("{path}/{name}");
+            match (&schema["properties"][name], &schema["additionalProperties"]) {
+                (Value::Null, Value::Bool(false)) => {
+                    errors.push(format!("{property_path}: unexpected claim"))
+                }
+                (Value::Null, additional @ Value::Object(_)) => {
+                    validate(additional, property, &property_path, errors)?
+                }
+                (Value::Null, _) => {}
+                (property_schema, _) => {
+                    validate(property_schema, property, &property_path, errors)?
+                }
+            }
+        }
+    }
+
+    if let (Some(items), Value::Array(array)) = (schema.get("items"), value) {
+        for (index, item) in array.iter().enumerate() {
+            validate(items, item, &format!("{path}/{index}"), errors)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_type(value: &Value, t: &str) -> bool {
+    match t {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
+fn pointer(path: &str) -> &str {
+    match path {
+        "" => "/",
+        path => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn tdx_claims() -> Value {
+        let hex = json!("00ff");
+        let body: serde_json::Map<_, _> = [
+            "tcb_svn",
+            "mr_seam",
+            "mrsigner_seam",
+            "seam_attributes",
+            "td_attributes",
+            "xfam",
+            "mr_td",
+            "mr_config_id",
+            "mr_owner",
+            "mr_owner_config",
+            "rtmr_0",
+            "rtmr_1",
+            "rtmr_2",
+            "rtmr_3",
+            "report_data",
+        ]
+        .into_iter()
+        .map(|name| (name.to_string(), hex.clone()))
+        .collect();
+        json!({
+            "ccel": {
+                "kernel": "5b7aa657",
+                "kernel_parameters": { "console": "hvc0", "rw": null }
+            },
+            "quote": {
+                "header": {
+                    "version": "0400",
+                    "att_key_type": "0200",
+                    "tee_type": "81000000",
+                    "reserved": "00000000",
+                    "vendor_id": "939a7233",
+                    "user_data": "d099bfec"
+                },
+                "body": body
+            },
+            "init_data": hex,
+            "report_data": hex
+        })
+    }
+
+    #[rstest]
+    #[case(Tee::Sample, json!({"svn": "1", "report_data": "", "init_data": ""}), None)]
+    #[case(
+        Tee::Sample,
+        json!({"svn": 1, "report_data": ""}),
+        Some("claims do not match the Sample schema: /: missing init_data, /svn: expected string")
+    )]
+    #[case(
+        Tee::Sample,
+        json!({"svn": "1", "report_data": "", "init_data": "", "debug": true}),
+        Some("claims do not match the Sample schema: /debug: unexpected claim")
+    )]
+    #[case(Tee::Sample, json!("1"), Some("claims do not match the Sample schema: /: expected object"))]
+    #[case(Tee::Sev, json!(null), None)]
+    #[case(
+        Tee::System,
+        json!({"system_report": {}, "measurements": {"kernel": 1}, "mr_register": "", "report_data": ""}),
+        Some("claims do not match the System schema: /measurements/kernel: expected string")
+    )]
+    fn test_validate_claims(
+        #[case] tee: Tee,
+        #[case] claims: Value,
+        #[case] expected: Option<&str>,
+    ) {
+        let res = validate_claims(tee, &claims);
+        match expected {
+            None => res.unwrap(),
+            Some(expected) => assert_eq!(res.unwrap_err().to_string(), expected),
+        }
+    }
+
+    #[test]
+    fn test_validate_tdx_claims() {
+        let mut claims = tdx_claims();
+        validate_claims(Tee::Tdx, &claims).unwrap();
+        validate_claims(Tee::AzTdxVtpm, &claims).unwrap_err();
+
+        claims["tpm"] = json!({"pcr00": "00ff"});
+        validate_claims(Tee::AzTdxVtpm, &claims).unwrap();
+
+        claims["quote"]["body"]["mr_td"] = json!("not hex");
+        claims["ccel"]["kernel_parameters"]["quiet"] = json!(1);
+        assert_eq!(
+            validate_claims(Tee::AzTdxVtpm, &claims)
+                .unwrap_err()
+                .to_string(),
+            "claims do not match the AzTdxVtpm schema: \
+             /ccel/kernel_parameters/quiet: expected string or null, \
+             /quote/body/mr_td: does not match ^[0-9a-f]*$"
+        );
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Smoke check of an attestation service before it serves.
+//!
+//! The RVPS is probed and the bundled sample evidence of every compiled-in
+//! verifier is evaluated against the `default` policy, issuing a token that
+//! is thrown away, so that a broken collateral configuration or token signer
+//! is found before the first attester. The verifiers without sample evidence
+//! are only created. All the checks run even if some fail.
+//!
+//! The sample evidence isn't meant to pass a production policy, so its
+//! denial by the policy passes the check. Only the failures of the verifiers,
+//! the RVPS, the policy engine or the token signer fail it.
+
+use anyhow::{bail, Result};
+use log::{error, info};
+use serde::Serialize;
+use serde_variant::to_variant_name;
+use verifier::samples::{compiled_tees, sample_evidence};
+
+use crate::policy_engine::opa::RegoError;
+use crate::{AttestationService, HashAlgorithm};
+
+/// Policy the sample evidence is evaluated against.
+const SELF_TEST_POLICY: &str = "default";
+
+/// Outcome of a check.
+#[derive(Debug, Serialize)]
+pub struct SelfTestCheck {
+    pub check: String,
+
+    /// Why the check failed, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Outcomes of all the checks of a self-test.
+#[derive(Debug, Default, Serialize)]
+pub struct SelfTestReport {
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    /// Whether no check failed.
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|check| check.error.is_none())
+    }
+
+    /// Log the outcome of every check, and fail if any check failed.
+    pub fn log(&self) -> Result<()> {
+        for check in &self.checks {
+            match &check.error {
+                None => info!("Self-test: {}: ok", check.check),
+                Some(e) => error!("Self-test: {}: {e}", check.check),
+            }
+        }
+        let failed = self.checks.iter().filter(|c| c.error.is_some()).count();
+        if failed > 0 {
+            bail!("{failed} of {} self-test checks failed", self.checks.len());
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, check: String, result: Result<()>) {
+        self.checks.push(SelfTestCheck {
+            check,
+            error: result.err().map(|e| format!("{e:#}")),
+        });
+    }
+}
+
+impl AttestationService {
+    /// Run the self-test of the service.
+    pub async fn self_test(&self) -> SelfTestReport {
+        let mut report = SelfTestReport::default();
+        report.record("check rvps".into(), self.health_check().await);
+
+        for tee in compiled_tees() {
+            let tee_name = to_variant_name(&tee).unwrap_or("unknown");
+            match sample_evidence(&tee) {
+                Some(evidence) => {
+                    let result = self
+                        .evaluate(
+                            evidence,
+                            tee,
+                            None,
+                            HashAlgorithm::Sha384,
+                            None,
+                            HashAlgorithm::Sha384,
+                            vec![SELF_TEST_POLICY.into()],
+                        )
+                        .await
+                        .map(|_token| ())
+                        .or_else(|e| match is_policy_denial(&e) {
+                            true => {
+                                info!("Self-test: sample evidence of {tee_name} denied: {e:#}");
+                                Ok(())
+                            }
+                            false => Err(e),
+                        });
+                    report.record(format!("evaluate sample evidence of {tee_name}"), result);
+                }
+                None => {
+                    let result = self.verifier(&tee).map(|_verifier| ());
+                    report.record(format!("create verifier of {tee_name}"), result);
+                }
+            }
+        }
+
+        report.record("load token keys".into(), self.token_jwks().map(|_jwks| ()));
+        report
+    }
+}
+
+/// Whether `error` is the denial of the evidence by a policy, rather than a
+/// failure to evaluate it.
+fn is_policy_denial(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<RegoError>(),
+            Some(RegoError::PolicyDenied { .. } | RegoError::EmptyReferenceValues { .. })
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use serde_json::json;
+
+    use crate::config::Config;
+    use crate::AttestationService;
+
+    #[tokio::test]
+    async fn test_self_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            work_dir: dir.path().join("as"),
+            ..Default::default()
+        };
+        config.rvps_config.store_config = json!({ "file_path": dir.path().join("rvps") });
+        let service = AttestationService::new(config.clone()).await.unwrap();
+
+        let report = service.self_test().await;
+        let sample = report
+            .checks
+            .iter()
+            .find(|check| check.check == "evaluate sample evidence of sample")
+            .unwrap();
+        assert!(sample.error.is_none());
+
+        // The sample evidence has no reference values to compare with, which
+        // the policy denies without failing the check.
+        config.deny_empty_reference_values = true;
+        let mut service = AttestationService::new(config).await.unwrap();
+        assert!(service.self_test().await.is_ok());
+
+        // A policy that can't be evaluated fails it.
+        let policy = URL_SAFE_NO_PAD.encode("package policy\nallow {");
+        service.set_policy("default".into(), policy).await.unwrap();
+        let report = service.self_test().await;
+        let sample = report
+            .checks
+            .iter()
+            .find(|check| check.check == "evaluate sample evidence of sample")
+            .unwrap();
+        assert!(sample.error.is_some());
+    }
+}
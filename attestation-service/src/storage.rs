@@ -0,0 +1,249 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Files kept by the Attestation Service.
+//!
+//! The work dir and the files written in it are only accessible to the user
+//! running the Attestation Service, which refuses to start if they, or the
+//! token signer key, are accessible to other users. With a key encryption
+//! key (KEK), the policies are encrypted at rest with AES-256-GCM, and the
+//! token signer key can be stored encrypted the same way.
+
+use anyhow::{bail, Context, Result};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::fs::{DirBuilder, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Prefix of the encrypted files, followed by the nonce, the ciphertext and
+/// the tag.
+pub const SEALED_MAGIC: &[u8] = b"ASENC1\n";
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+const DIR_MODE: u32 = 0o700;
+const FILE_MODE: u32 = 0o600;
+
+/// Permission bits of the group and others.
+const SHARED_MODE: u32 = 0o077;
+
+/// The AES-256 key encrypting the files at rest.
+pub struct Kek {
+    key: Zeroizing<Vec<u8>>,
+
+    /// Whether the files written before the KEK was configured are read.
+    plaintext_migration: bool,
+}
+
+impl Kek {
+    /// Load the 32 bytes of the key from `path`. Only with
+    /// `plaintext_migration` are the files stored in plaintext read.
+    pub fn load(path: &Path, plaintext_migration: bool) -> Result<Self> {
+        let key = Zeroizing::new(
+            std::fs::read(path).with_context(|| format!("read KEK {}", path.display()))?,
+        );
+        if key.len() != KEY_LENGTH {
+            bail!("KEK {} must be {KEY_LENGTH} bytes", path.display());
+        }
+        Ok(Self {
+            key,
+            plaintext_migration,
+        })
+    }
+
+    /// Encrypt `data`, bound to `aad` so that it can't be moved to another
+    /// file.
+    pub fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LENGTH];
+        openssl::rand::rand_bytes(&mut nonce)?;
+        let mut tag = [0; TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            aad,
+            data,
+            &mut tag,
+        )?;
+
+        Ok([SEALED_MAGIC, &nonce, &ciphertext, &tag].concat())
+    }
+
+    /// Decrypt `sealed`, as returned by [`Kek::seal`] with the same `aad`.
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let sealed = sealed
+            .strip_prefix(SEALED_MAGIC)
+            .context("content is not encrypted")?;
+        if sealed.len() < NONCE_LENGTH + TAG_LENGTH {
+            bail!("encrypted content is truncated");
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LENGTH);
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LENGTH);
+        let data = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            aad,
+            ciphertext,
+            tag,
+        )
+        .context("decrypt content, the KEK is wrong or the content was modified")?;
+        Ok(Zeroizing::new(data))
+    }
+}
+
+impl std::fmt::Debug for Kek {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Kek(..)")
+    }
+}
+
+/// Encrypt `data` with `kek`, if any.
+pub(crate) fn seal(kek: Option<&Kek>, aad: &[u8], data: Vec<u8>) -> Result<Vec<u8>> {
+    match kek {
+        Some(kek) => kek.seal(aad, &data),
+        None => Ok(data),
+    }
+}
+
+/// Decrypt `content` with `kek`. Contents in plaintext are only returned as
+/// they are without a KEK, or while migrating to one, so that a plaintext
+/// file can't replace an encrypted one.
+pub(crate) fn open(kek: Option<&Kek>, aad: &[u8], content: Vec<u8>) -> Result<Zeroizing<Vec<u8>>> {
+    match (kek, content.starts_with(SEALED_MAGIC)) {
+        (Some(kek), true) => kek.open(aad, &content),
+        (None, true) => bail!("content is encrypted, but no KEK is configured"),
+        (Some(kek), false) if !kek.plaintext_migration => {
+            bail!("content is not encrypted, enable plaintext_migration to read it")
+        }
+        (_, false) => Ok(Zeroizing::new(content)),
+    }
+}
+
+/// Create the directory `path` and its missing parents, only accessible to
+/// the current user.
+pub(crate) fn create_dir(path: &Path) -> io::Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(DIR_MODE)
+        .create(path)
+}
+
+/// Write `data` to the file `path`, only accessible to the current user. An
+/// existing file gets its permissions restricted as well.
+pub(crate) fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(FILE_MODE)
+        .open(path)?;
+    file.set_permissions(Permissions::from_mode(FILE_MODE))?;
+    file.write_all(data)
+}
+
+/// Check that `path`, and everything below it for a directory, is not
+/// accessible to the group or others. With `insecure`, the files that are
+/// are only logged.
+pub(crate) fn check_permissions(path: &Path, insecure: bool) -> Result<()> {
+    let metadata = std::fs::symlink_metadata(path)
+        .with_context(|| format!("read metadata of {}", path.display()))?;
+    let mode = metadata.permissions().mode() & 0o777;
+    // The mode of a link is meaningless, the permissions of its target apply.
+    if !metadata.is_symlink() && mode & SHARED_MODE != 0 {
+        let expected = match metadata.is_dir() {
+            true => DIR_MODE,
+            false => FILE_MODE,
+        };
+        let message = format!(
+            "{} is accessible to other users (mode {mode:o}), restrict it to {expected:o}",
+            path.display()
+        );
+        if !insecure {
+            bail!("{message}");
+        }
+        log::warn!("{message}");
+    }
+
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            check_permissions(&entry?.path(), insecure)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn load_kek(dir: &Path, key: &[u8]) -> Result<Kek> {
+        let path = dir.join("kek");
+        std::fs::write(&path, key).unwrap();
+        Kek::load(&path, false)
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let kek = load_kek(dir.path(), &[7; KEY_LENGTH]).unwrap();
+
+        let sealed = seal(Some(&kek), b"a", b"policy".to_vec()).unwrap();
+        assert!(sealed.starts_with(SEALED_MAGIC));
+        assert_eq!(*open(Some(&kek), b"a", sealed.clone()).unwrap(), b"policy");
+        assert!(open(Some(&kek), b"b", sealed.clone()).is_err());
+        assert!(open(None, b"a", sealed.clone()).is_err());
+        assert!(open(Some(&kek), b"a", sealed[..SEALED_MAGIC.len() + 8].to_vec()).is_err());
+
+        // Contents written without a KEK.
+        assert!(open(Some(&kek), b"a", b"policy".to_vec()).is_err());
+        assert_eq!(*open(None, b"a", b"policy".to_vec()).unwrap(), b"policy");
+        assert_eq!(seal(None, b"a", b"policy".to_vec()).unwrap(), b"policy");
+        let migrating = Kek::load(&dir.path().join("kek"), true).unwrap();
+        assert_eq!(
+            *open(Some(&migrating), b"a", b"policy".to_vec()).unwrap(),
+            b"policy"
+        );
+
+        let other = load_kek(dir.path(), &[8; KEY_LENGTH]).unwrap();
+        assert!(open(Some(&other), b"a", sealed).is_err());
+        assert!(load_kek(dir.path(), &[7; 16]).is_err());
+    }
+
+    #[rstest]
+    #[case(0o700, false, true)]
+    #[case(0o600, false, true)]
+    #[case(0o640, false, false)]
+    #[case(0o604, false, false)]
+    #[case(0o644, true, true)]
+    fn test_check_permissions(#[case] mode: u32, #[case] insecure: bool, #[case] ok: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let work_dir = dir.path().join("as");
+        create_dir(&work_dir.join("opa")).unwrap();
+        let file = work_dir.join("opa").join("default.rego");
+        write_file(&file, b"package policy").unwrap();
+        assert!(check_permissions(&work_dir, false).is_ok());
+
+        std::fs::set_permissions(&file, Permissions::from_mode(mode)).unwrap();
+        assert_eq!(check_permissions(&work_dir, insecure).is_ok(), ok);
+    }
+
+    #[test]
+    fn test_write_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("policy.rego");
+        std::fs::write(&file, "old").unwrap();
+        std::fs::set_permissions(&file, Permissions::from_mode(0o644)).unwrap();
+
+        write_file(&file, b"new").unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"new");
+        let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, FILE_MODE);
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! OpenTelemetry export of the Attestation Service tracing spans.
+
+use anyhow::{Context as _, Result};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Install a global tracing subscriber that exports all spans to the OTLP/gRPC
+/// collector at `endpoint`. Log records keep going through the `log` crate.
+pub fn init(endpoint: &str, service_name: &str) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name.to_string(),
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+        .context("install OTLP trace pipeline")?;
+
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber).context("set tracing subscriber")?;
+
+    Ok(())
+}
+
+/// Flush the spans still buffered by the exporter.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct MapInjector<'a>(&'a mut Vec<(String, String)>);
+
+impl Injector for MapInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.push((key.to_string(), value));
+    }
+}
+
+/// The W3C trace context of the current span, as header name/value pairs to
+/// be attached to an outgoing request.
+pub fn current_trace_context() -> Vec<(String, String)> {
+    let mut headers = Vec::new();
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MapInjector(&mut headers))
+    });
+    headers
+}
+
+struct MapExtractor<'a>(&'a [(String, String)]);
+
+impl Extractor for MapExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(k, _)| k.as_str()).collect()
+    }
+}
+
+/// Make the remote span described by the `headers` of an incoming request the
+/// parent of `span`.
+pub fn set_parent_from_headers(span: &tracing::Span, headers: &[(String, String)]) {
+    let parent: Context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&MapExtractor(headers)));
+    span.set_parent(parent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TracerProvider as _;
+
+    #[test]
+    fn test_trace_context_propagation() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = trace::TracerProvider::builder().build().tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+            let headers = [(
+                "Traceparent".to_string(),
+                format!("00-{trace_id}-00f067aa0ba902b7-01"),
+            )];
+            let span = tracing::info_span!("evaluate");
+            set_parent_from_headers(&span, &headers);
+            let _entered = span.enter();
+
+            // The span continues the remote trace, under a span of its own.
+            let context = current_trace_context();
+            let (_, traceparent) = context
+                .iter()
+                .find(|(name, _)| name == "traceparent")
+                .unwrap();
+            assert!(traceparent.starts_with(&format!("00-{trace_id}-")));
+            assert!(!traceparent.contains("00f067aa0ba902b7"));
+        });
+    }
+}
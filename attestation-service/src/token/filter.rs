@@ -0,0 +1,120 @@
+// Copyright (c) 2024 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Selection of the flattened TCB claims copied into the issued tokens.
+//!
+//! The policies are evaluated against every claim of the evidence, but not
+//! every relying party needs, or should learn, every detail of the platform
+//! of the attester, e.g. the full TDX or SNP report.
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// The flattened claims, e.g. `tdx.quote.body.mr_td`, carried by the tokens
+/// in `tcb-status` and `reference-data`. A claim is kept if it matches one of
+/// the `allow` patterns, or if there are none, and matches none of the `deny`
+/// patterns. In a pattern, `*` matches any characters and `?` one character.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ClaimFilter {
+    #[serde(default)]
+    pub allow: Vec<String>,
+
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl ClaimFilter {
+    /// Whether the claim `name` is copied into the tokens.
+    pub fn allows(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|pattern| glob_match(pattern, name)))
+            && !self.deny.iter().any(|pattern| glob_match(pattern, name))
+    }
+
+    /// Drop the claims that aren't copied into the tokens.
+    pub fn retain(&self, claims: &mut Map<String, Value>) {
+        claims.retain(|name, _| self.allows(name));
+    }
+}
+
+/// Whether `name` matches the glob `pattern`.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` in the pattern, and of the name it was
+    // matched at, to backtrack to.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    #[rstest]
+    #[case("tdx.quote.body.mr_td", "tdx.quote.body.mr_td", true)]
+    #[case("tdx.quote.body.*", "tdx.quote.body.mr_td", true)]
+    #[case("*.mr_td", "tdx.quote.body.mr_td", true)]
+    #[case("tdx.*.mr_*", "tdx.quote.body.mr_td", true)]
+    #[case("tdx.quote.body.rtmr?", "tdx.quote.body.rtmr0", true)]
+    #[case("tdx.quote.body.rtmr?", "tdx.quote.body.rtmr10", false)]
+    #[case("tdx.quote.header.*", "tdx.quote.body.mr_td", false)]
+    #[case("*", "snp.measurement", true)]
+    #[case("snp.*", "tdx.quote.body.mr_td", false)]
+    fn test_glob_match(#[case] pattern: &str, #[case] name: &str, #[case] expected: bool) {
+        assert_eq!(glob_match(pattern, name), expected);
+    }
+
+    #[test]
+    fn test_retain() {
+        let filter: ClaimFilter = serde_json::from_value(json!({
+            "allow": ["tdx.quote.body.*", "tdx.ccel.*"],
+            "deny": ["tdx.quote.body.report_data"],
+        }))
+        .unwrap();
+        let mut claims = json!({
+            "tdx.quote.header.version": "4",
+            "tdx.quote.body.mr_td": "aa",
+            "tdx.quote.body.report_data": "bb",
+            "tdx.ccel.kernel": "cc",
+        })
+        .as_object()
+        .unwrap()
+        .clone();
+        filter.retain(&mut claims);
+        assert_eq!(
+            Value::Object(claims),
+            json!({
+                "tdx.quote.body.mr_td": "aa",
+                "tdx.ccel.kernel": "cc",
+            })
+        );
+
+        // Every claim is kept by default.
+        assert!(ClaimFilter::default().allows("tdx.quote.body.report_data"));
+    }
+}
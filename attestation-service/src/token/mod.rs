@@ -9,8 +9,13 @@ use serde_json::Value;
 use simple::COCO_AS_ISSUER_NAME;
 use strum::{Display, EnumString};
 
+use crate::storage::Kek;
+
+pub mod filter;
 mod simple;
 
+use filter::ClaimFilter;
+
 const DEFAULT_TOKEN_TIMEOUT: i64 = 5;
 
 pub trait AttestationTokenBroker {
@@ -21,6 +26,22 @@ pub trait AttestationTokenBroker {
     /// Get the public keys and X.509 formatted certificate chain of the attestation token broker.
     /// Returns the certificate chain in [JWKS format](https://www.rfc-editor.org/rfc/rfc7517#appendix-B).
     fn pubkey_jwks(&self) -> Result<String>;
+
+    /// Sign the `evaluation-reports` of `token`, an unexpired token issued
+    /// by the broker, as a JWS of their own, so that the verdict can be
+    /// forwarded without the claims of the token. The JWS expires with the
+    /// token. Returns the compact serialization of the JWS.
+    fn sign_evaluation_reports(&self, token: &str) -> Result<String>;
+
+    /// The claims of `token` if it is signed by the broker, expired or not,
+    /// e.g. those of the token of a prior attestation.
+    fn verify_signature(&self, token: &str) -> Result<Value>;
+
+    /// Get the [OIDC discovery document](https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata)
+    /// of the attestation token broker, if it issues OIDC ID tokens.
+    fn oidc_discovery(&self) -> Option<Value> {
+        None
+    }
 }
 
 #[derive(Deserialize, Debug, Clone, EnumString, Display)]
@@ -32,12 +53,13 @@ impl AttestationTokenBrokerType {
     pub fn to_token_broker(
         &self,
         config: AttestationTokenConfig,
+        kek: Option<&Kek>,
     ) -> Result<Box<dyn AttestationTokenBroker + Send + Sync>> {
         match self {
-            AttestationTokenBrokerType::Simple => {
-                Ok(Box::new(simple::SimpleAttestationTokenBroker::new(config)?)
-                    as Box<dyn AttestationTokenBroker + Send + Sync>)
-            }
+            AttestationTokenBrokerType::Simple => Ok(Box::new(
+                simple::SimpleAttestationTokenBroker::new(config, kek)?,
+            )
+                as Box<dyn AttestationTokenBroker + Send + Sync>),
         }
     }
 }
@@ -51,6 +73,17 @@ pub struct AttestationTokenConfig {
     pub issuer_name: String,
 
     pub signer: Option<TokenSignerConfig>,
+
+    /// Issue the tokens as OIDC ID tokens, so that cloud IAM systems can
+    /// trust them. `issuer_name` must then be the HTTPS URL the discovery
+    /// document is served below.
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
+
+    /// The flattened TCB claims copied into the tokens. Every claim by
+    /// default.
+    #[serde(default)]
+    pub tcb_claims: ClaimFilter,
 }
 
 fn default_issuer_name() -> String {
@@ -66,12 +99,27 @@ pub struct TokenSignerConfig {
     pub cert_path: Option<String>,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct OidcConfig {
+    /// `aud` of the tokens, e.g. the workload identity pool provider of the
+    /// cloud IAM.
+    pub audience: Vec<String>,
+
+    /// JSON pointer of the claim used as `sub` of the tokens. There is no
+    /// default, as cloud IAM systems map the `sub` to their principals, and
+    /// a claim shared by every workload, such as `/tee`, would grant them
+    /// all the same principal.
+    pub subject_claim: String,
+}
+
 impl Default for AttestationTokenConfig {
     fn default() -> Self {
         Self {
             duration_min: DEFAULT_TOKEN_TIMEOUT,
             issuer_name: COCO_AS_ISSUER_NAME.to_string(),
             signer: None,
+            oidc: None,
+            tcb_claims: ClaimFilter::default(),
         }
     }
 }
@@ -6,7 +6,7 @@ use anyhow::*;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
 use openssl::rsa::Rsa;
-use openssl::sign::Signer;
+use openssl::sign::{Signer, Verifier};
 use openssl::x509::X509;
 use openssl::{
     hash::MessageDigest,
@@ -15,13 +15,24 @@ use openssl::{
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use serde_json::{json, Value};
+use zeroize::Zeroizing;
 
-use crate::token::{AttestationTokenBroker, AttestationTokenConfig};
+use crate::storage::{self, Kek};
+use crate::token::{AttestationTokenBroker, AttestationTokenConfig, OidcConfig};
 
 pub const COCO_AS_ISSUER_NAME: &str = "CoCo-Attestation-Service";
 const RSA_KEY_BITS: u32 = 2048;
 const SIMPLE_TOKEN_ALG: &str = "RS384";
 
+/// Algorithm of the OIDC ID tokens, that every relying party supports.
+const OIDC_TOKEN_ALG: &str = "RS256";
+
+/// `typ` of the signed evaluation reports, telling them apart from tokens.
+const EVALUATION_REPORT_TYP: &str = "evaluation-report+jwt";
+
+/// Additional data of the token signer key encrypted with the KEK.
+const SIGNER_KEY_AAD: &[u8] = b"token-signer-key";
+
 pub struct SimpleAttestationTokenBroker {
     private_key: Rsa<Private>,
     config: AttestationTokenConfig,
@@ -30,7 +41,11 @@ pub struct SimpleAttestationTokenBroker {
 }
 
 impl SimpleAttestationTokenBroker {
-    pub fn new(config: AttestationTokenConfig) -> Result<Self> {
+    pub fn new(config: AttestationTokenConfig, kek: Option<&Kek>) -> Result<Self> {
+        if let Some(oidc) = &config.oidc {
+            check_oidc_config(&config.issuer_name, oidc)?;
+        }
+
         if config.signer.is_none() {
             log::info!("No Token Signer key in config file, create an ephemeral key and without CA pubkey cert");
             return Ok(Self {
@@ -44,6 +59,13 @@ impl SimpleAttestationTokenBroker {
         let signer = config.signer.clone().unwrap();
         let pem_data = std::fs::read(&signer.key_path)
             .map_err(|e| anyhow!("Read Token Signer private key failed: {:?}", e))?;
+        // The key is kept outside of the work dir, and is only encrypted if
+        // the operator chose to.
+        let pem_data = match pem_data.starts_with(storage::SEALED_MAGIC) {
+            true => storage::open(kek, SIGNER_KEY_AAD, pem_data)
+                .context("Decrypt Token Signer private key failed")?,
+            false => Zeroizing::new(pem_data),
+        };
         let private_key = Rsa::private_key_from_pem(&pem_data)?;
 
         let cert_chain = signer
@@ -77,21 +99,87 @@ impl SimpleAttestationTokenBroker {
 }
 
 impl SimpleAttestationTokenBroker {
-    fn rs384_sign(&self, payload: &[u8]) -> Result<Vec<u8>> {
+    fn alg(&self) -> &'static str {
+        match self.config.oidc {
+            Some(_) => OIDC_TOKEN_ALG,
+            None => SIMPLE_TOKEN_ALG,
+        }
+    }
+
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>> {
         let rsa_pkey = PKey::from_rsa(self.private_key.clone())?;
-        let mut signer = Signer::new(MessageDigest::sha384(), &rsa_pkey)?;
+        let mut signer = Signer::new(self.digest(), &rsa_pkey)?;
         signer.update(payload)?;
         let signature = signer.sign_to_vec()?;
 
         Ok(signature)
     }
+
+    fn digest(&self) -> MessageDigest {
+        match self.config.oidc {
+            Some(_) => MessageDigest::sha256(),
+            None => MessageDigest::sha384(),
+        }
+    }
+
+    /// The claims of `token`, if it was issued by the broker and is not
+    /// expired.
+    fn verify(&self, token: &str) -> Result<Value> {
+        let claims = self.verify_signature(token)?;
+        let exp = claims["exp"]
+            .as_i64()
+            .ok_or_else(|| anyhow!("Token has no expiration"))?;
+        if exp <= time::OffsetDateTime::now_utc().unix_timestamp() {
+            bail!("Token expired");
+        }
+        Ok(claims)
+    }
+
+    /// The [RFC 7638](https://www.rfc-editor.org/rfc/rfc7638) thumbprint of
+    /// the public key, identifying it in the JWKS.
+    fn kid(&self) -> String {
+        let thumbprint_input = json!({
+            "e": URL_SAFE_NO_PAD.encode(self.private_key.e().to_vec()),
+            "kty": "RSA",
+            "n": URL_SAFE_NO_PAD.encode(self.private_key.n().to_vec()),
+        });
+        URL_SAFE_NO_PAD.encode(openssl::sha::sha256(
+            thumbprint_input.to_string().as_bytes(),
+        ))
+    }
+}
+
+/// Check that the tokens of `issuer` can be OIDC ID tokens. The discovery
+/// document is served at the root of the host, so the issuer has no path.
+fn check_oidc_config(issuer: &str, oidc: &OidcConfig) -> Result<()> {
+    let host = issuer.strip_prefix("https://").unwrap_or_default();
+    if host.is_empty() || host.contains(['/', '?', '#']) {
+        bail!("OIDC issuer must be an HTTPS URL without path, query or fragment, got {issuer}");
+    }
+    if oidc.subject_claim.is_empty() {
+        bail!("OIDC subject claim must be set");
+    }
+    if oidc.audience.is_empty() {
+        bail!("OIDC audience must not be empty");
+    }
+    Ok(())
+}
+
+/// The `sub` of the token of `claims`, the claim at `pointer`.
+fn oidc_subject(claims: &Value, pointer: &str) -> Result<String> {
+    match claims.pointer(pointer) {
+        Some(Value::String(subject)) if !subject.is_empty() => Ok(subject.clone()),
+        Some(value @ (Value::Number(_) | Value::Bool(_))) => Ok(value.to_string()),
+        _ => bail!("OIDC subject claim {pointer} is not set"),
+    }
 }
 
 impl AttestationTokenBroker for SimpleAttestationTokenBroker {
     fn issue(&self, custom_claims: Value) -> Result<String> {
         let header_value = json!({
             "typ": "JWT",
-            "alg": SIMPLE_TOKEN_ALG,
+            "alg": self.alg(),
+            "kid": self.kid(),
         });
         let header_string = serde_json::to_string(&header_value)?;
         let header_b64 = URL_SAFE_NO_PAD.encode(header_string.as_bytes());
@@ -117,6 +205,18 @@ impl AttestationTokenBroker for SimpleAttestationTokenBroker {
         .ok_or_else(|| anyhow!("Internal Error: generate claims failed"))?
         .clone();
 
+        if let Some(oidc) = &self.config.oidc {
+            claims.insert(
+                "sub".to_string(),
+                oidc_subject(&custom_claims, &oidc.subject_claim)?.into(),
+            );
+            let audience = match oidc.audience.as_slice() {
+                [audience] => Value::from(audience.as_str()),
+                audiences => Value::from(audiences.to_vec()),
+            };
+            claims.insert("aud".to_string(), audience);
+        }
+
         claims.extend(
             custom_claims
                 .as_object()
@@ -129,7 +229,7 @@ impl AttestationTokenBroker for SimpleAttestationTokenBroker {
         let claims_b64 = URL_SAFE_NO_PAD.encode(claims_string.as_bytes());
 
         let signature_payload = format!("{header_b64}.{claims_b64}");
-        let signature = self.rs384_sign(signature_payload.as_bytes())?;
+        let signature = self.sign(signature_payload.as_bytes())?;
         let signature_b64 = URL_SAFE_NO_PAD.encode(signature);
 
         let token = format!("{signature_payload}.{signature_b64}");
@@ -137,13 +237,82 @@ impl AttestationTokenBroker for SimpleAttestationTokenBroker {
         Ok(token)
     }
 
+    fn sign_evaluation_reports(&self, token: &str) -> Result<String> {
+        let claims = self.verify(token)?;
+        let Some(reports) = claims.get("evaluation-reports") else {
+            bail!("Token has no evaluation reports");
+        };
+
+        let header = json!({
+            "typ": EVALUATION_REPORT_TYP,
+            "alg": self.alg(),
+            "kid": self.kid(),
+        });
+        let mut payload = json!({
+            "iss": self.config.issuer_name.clone(),
+            "iat": time::OffsetDateTime::now_utc().unix_timestamp(),
+            "exp": claims["exp"],
+            "tee": claims["tee"],
+            "evaluation-reports": reports,
+        });
+        // The verdicts of the further sources of a composite attestation.
+        if let Some(submods) = claims.get("submods").and_then(Value::as_object) {
+            let submods: serde_json::Map<String, Value> = submods
+                .iter()
+                .map(|(name, submod)| {
+                    let verdict = json!({
+                        "tee": submod["tee"],
+                        "evaluation-reports": submod["evaluation-reports"],
+                    });
+                    (name.clone(), verdict)
+                })
+                .collect();
+            payload["submods"] = submods.into();
+        }
+
+        let signature_payload = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_string(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_string(&payload)?)
+        );
+        let signature = self.sign(signature_payload.as_bytes())?;
+        Ok(format!(
+            "{signature_payload}.{}",
+            URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+
+    fn verify_signature(&self, token: &str) -> Result<Value> {
+        let [header_b64, claims_b64, signature_b64] = token.split('.').collect::<Vec<_>>()[..]
+        else {
+            bail!("Malformed token");
+        };
+        let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header_b64)?)
+            .context("Malformed token header")?;
+        if header["alg"] != self.alg() || header["kid"] != self.kid().as_str() {
+            bail!("Token is not signed by the key of this attestation service");
+        }
+
+        let rsa_pkey = PKey::from_rsa(self.private_key.clone())?;
+        let mut verifier = Verifier::new(self.digest(), &rsa_pkey)?;
+        verifier.update(format!("{header_b64}.{claims_b64}").as_bytes())?;
+        if !verifier.verify(&URL_SAFE_NO_PAD.decode(signature_b64)?)? {
+            bail!("Invalid token signature");
+        }
+
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims_b64)?)
+            .context("Malformed token claims")
+    }
+
     fn pubkey_jwks(&self) -> Result<String> {
         let n = self.private_key.n().to_vec();
         let e = self.private_key.e().to_vec();
 
         let mut jwk = Jwk {
             kty: "RSA".to_string(),
-            alg: SIMPLE_TOKEN_ALG.to_string(),
+            alg: self.alg().to_string(),
+            kid: self.kid(),
+            key_use: "sig".to_string(),
             n: URL_SAFE_NO_PAD.encode(n),
             e: URL_SAFE_NO_PAD.encode(e),
             x5u: None,
@@ -166,12 +335,28 @@ impl AttestationTokenBroker for SimpleAttestationTokenBroker {
 
         Ok(serde_json::to_string(&jwks)?)
     }
+
+    fn oidc_discovery(&self) -> Option<Value> {
+        self.config.oidc.as_ref()?;
+        let issuer = &self.config.issuer_name;
+        Some(json!({
+            "issuer": issuer,
+            "jwks_uri": format!("{issuer}/.well-known/jwks.json"),
+            "response_types_supported": ["id_token"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": [OIDC_TOKEN_ALG],
+            "claims_supported": ["iss", "sub", "aud", "iat", "nbf", "exp", "jti", "tee"],
+        }))
+    }
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
 struct Jwk {
     kty: String,
     alg: String,
+    kid: String,
+    #[serde(rename = "use")]
+    key_use: String,
     n: String,
     e: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -179,3 +364,157 @@ struct Jwk {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub x5c: Option<Vec<String>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn oidc_broker(audience: &[&str]) -> SimpleAttestationTokenBroker {
+        let config = AttestationTokenConfig {
+            issuer_name: "https://kbs.example.com".to_string(),
+            oidc: Some(OidcConfig {
+                audience: audience.iter().map(|it| it.to_string()).collect(),
+                subject_claim: "/tee".to_string(),
+            }),
+            ..Default::default()
+        };
+        SimpleAttestationTokenBroker::new(config, None).unwrap()
+    }
+
+    fn decode(part: &str) -> Value {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+    }
+
+    #[rstest]
+    #[case("https://kbs.example.com", &["kbs"], true)]
+    #[case("https://kbs.example.com/as", &["kbs"], false)]
+    #[case("https://", &["kbs"], false)]
+    #[case("https://kbs.example.com/", &["kbs"], false)]
+    #[case("http://kbs.example.com", &["kbs"], false)]
+    #[case("https://kbs.example.com?a=b", &["kbs"], false)]
+    #[case(COCO_AS_ISSUER_NAME, &["kbs"], false)]
+    #[case("https://kbs.example.com", &[], false)]
+    fn test_check_oidc_config(#[case] issuer: &str, #[case] audience: &[&str], #[case] ok: bool) {
+        let oidc = OidcConfig {
+            audience: audience.iter().map(|it| it.to_string()).collect(),
+            subject_claim: "/tee".to_string(),
+        };
+        assert_eq!(check_oidc_config(issuer, &oidc).is_ok(), ok);
+    }
+
+    #[test]
+    fn test_issue_oidc_token() {
+        let broker = oidc_broker(&["kbs"]);
+        let token = broker.issue(json!({"tee": "sample"})).unwrap();
+        let parts: Vec<_> = token.split('.').collect();
+        let (header, claims) = (decode(parts[0]), decode(parts[1]));
+
+        let jwks: Value = serde_json::from_str(&broker.pubkey_jwks().unwrap()).unwrap();
+        assert_eq!(header["alg"], OIDC_TOKEN_ALG);
+        assert_eq!(header["kid"], jwks["keys"][0]["kid"]);
+        assert_eq!(jwks["keys"][0]["alg"], OIDC_TOKEN_ALG);
+        assert_eq!(claims["iss"], "https://kbs.example.com");
+        assert_eq!(claims["sub"], "sample");
+        assert_eq!(claims["aud"], "kbs");
+
+        let discovery = broker.oidc_discovery().unwrap();
+        assert_eq!(discovery["issuer"], claims["iss"]);
+        assert_eq!(
+            discovery["jwks_uri"],
+            "https://kbs.example.com/.well-known/jwks.json"
+        );
+
+        let broker = oidc_broker(&["kbs", "sts.amazonaws.com"]);
+        let token = broker.issue(json!({"tee": "sample"})).unwrap();
+        let claims = decode(token.split('.').nth(1).unwrap());
+        assert_eq!(claims["aud"], json!(["kbs", "sts.amazonaws.com"]));
+        assert!(broker.issue(json!({"tcb-status": {}})).is_err());
+    }
+
+    #[test]
+    fn test_sign_evaluation_reports() {
+        let broker = SimpleAttestationTokenBroker::new(Default::default(), None).unwrap();
+        let reports = json!([{"policy-id": "default", "policy-hash": "abc"}]);
+        let token = broker
+            .issue(json!({
+                "tee": "tdx",
+                "evaluation-reports": reports,
+                "tcb-status": {"tdx.quote.body.mr_td": "00"},
+                "submods": {"gpu0": {"tee": "sample", "evaluation-reports": [], "tcb-status": {}}},
+            }))
+            .unwrap();
+
+        let report = broker.sign_evaluation_reports(&token).unwrap();
+        let parts: Vec<_> = report.split('.').collect();
+        let (header, payload) = (decode(parts[0]), decode(parts[1]));
+        assert_eq!(header["typ"], EVALUATION_REPORT_TYP);
+        assert_eq!(payload["evaluation-reports"], reports);
+        assert_eq!(
+            payload["exp"],
+            decode(token.split('.').nth(1).unwrap())["exp"]
+        );
+        assert_eq!(payload["submods"]["gpu0"]["tee"], "sample");
+        assert!(payload.get("tcb-status").is_none());
+        assert!(payload["submods"]["gpu0"].get("tcb-status").is_none());
+
+        // The report is signed like the tokens.
+        let rsa_pkey = PKey::from_rsa(broker.private_key.clone()).unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha384(), &rsa_pkey).unwrap();
+        verifier
+            .update(format!("{}.{}", parts[0], parts[1]).as_bytes())
+            .unwrap();
+        assert!(verifier
+            .verify(&URL_SAFE_NO_PAD.decode(parts[2]).unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_sign_evaluation_reports_of_foreign_token() {
+        let broker = SimpleAttestationTokenBroker::new(Default::default(), None).unwrap();
+        let claims = json!({"tee": "sample", "evaluation-reports": []});
+
+        let other = SimpleAttestationTokenBroker::new(Default::default(), None).unwrap();
+        let token = other.issue(claims.clone()).unwrap();
+        assert!(broker.sign_evaluation_reports(&token).is_err());
+
+        let token = broker.issue(claims).unwrap();
+        let parts: Vec<_> = token.split('.').collect();
+        let forged = URL_SAFE_NO_PAD.encode(
+            json!({"tee": "sample", "evaluation-reports": [{"policy-id": "forged"}], "exp": i64::MAX})
+                .to_string(),
+        );
+        let forged = format!("{}.{forged}.{}", parts[0], parts[2]);
+        assert!(broker.sign_evaluation_reports(&forged).is_err());
+        assert!(broker.sign_evaluation_reports("garbage").is_err());
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let config = AttestationTokenConfig {
+            duration_min: -1,
+            ..Default::default()
+        };
+        let broker = SimpleAttestationTokenBroker::new(config, None).unwrap();
+        let token = broker.issue(json!({"tee": "sample"})).unwrap();
+        assert!(broker.verify(&token).is_err());
+        assert_eq!(broker.verify_signature(&token).unwrap()["tee"], "sample");
+
+        let other = SimpleAttestationTokenBroker::new(Default::default(), None).unwrap();
+        assert!(other.verify_signature(&token).is_err());
+        let parts: Vec<_> = token.split('.').collect();
+        let forged = URL_SAFE_NO_PAD.encode(json!({"tee": "snp"}).to_string());
+        let forged = format!("{}.{forged}.{}", parts[0], parts[2]);
+        assert!(broker.verify_signature(&forged).is_err());
+    }
+
+    #[test]
+    fn test_issue_token() {
+        let broker = SimpleAttestationTokenBroker::new(Default::default(), None).unwrap();
+        let token = broker.issue(json!({"tee": "sample"})).unwrap();
+        let parts: Vec<_> = token.split('.').collect();
+        assert_eq!(decode(parts[0])["alg"], SIMPLE_TOKEN_ALG);
+        assert!(decode(parts[1]).get("sub").is_none());
+        assert!(broker.oidc_discovery().is_none());
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The JSON Canonicalization Scheme (JCS, [RFC 8785]) serialization of the
+//! structured runtime and init data, whose digest the evidence binds.
+//!
+//! KBS and the Attestation Service serialize the structured data this way
+//! before hashing it, so that an attester in any language reproduces the
+//! digest with a JCS library:
+//! - no whitespace,
+//! - the members of the objects sorted by the UTF-16 code units of their
+//!   names,
+//! - the strings only escaping `"`, `\` and the control characters,
+//! - the numbers as IEEE 754 doubles, written as ECMAScript does.
+//!
+//! [RFC 8785]: https://www.rfc-editor.org/rfc/rfc8785
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Serialize `value` into its canonical form.
+pub fn to_string(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(value, &mut out);
+    out
+}
+
+/// Serialize `value` into its canonical form, as bytes to hash.
+pub fn to_vec(value: &Value) -> Vec<u8> {
+    to_string(value).into_bytes()
+}
+
+/// Serialize `value`, converted to JSON, into its canonical form.
+pub fn to_vec_from<T: Serialize>(value: &T) -> serde_json::Result<Vec<u8>> {
+    Ok(to_vec(&serde_json::to_value(value)?))
+}
+
+fn write_value(value: &Value, out: &mut String) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => write_number(n.as_f64().unwrap_or(f64::NAN), out),
+        Value::String(s) => write_string(s, out),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(item, out);
+            }
+            out.push(']');
+        }
+        Value::Object(members) => {
+            let mut members: Vec<_> = members.iter().collect();
+            members.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (name, value)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(name, out);
+                out.push(':');
+                write_value(value, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{08}' => out.push_str("\\b"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\u{0c}' => out.push_str("\\f"),
+            '\r' => out.push_str("\\r"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Write `n` as ECMAScript's `Number.prototype.toString()`. JSON has no
+/// NaN or infinities, so they can only come from numbers out of range.
+fn write_number(n: f64, out: &mut String) {
+    if !n.is_finite() {
+        out.push_str("null");
+        return;
+    }
+    if n == 0.0 {
+        out.push('0');
+        return;
+    }
+    if n < 0.0 {
+        out.push('-');
+    }
+
+    // The shortest digits round-tripping to `n`, and the exponent `e` of the
+    // first one, as in `d.ddde<e>`.
+    let scientific = format!("{:e}", n.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let digits = mantissa.replace('.', "");
+    let exponent: i32 = exponent.parse().unwrap_or_default();
+    // The position of the decimal point after the first `point` digits.
+    let point = exponent + 1;
+    let count = digits.len() as i32;
+
+    if count <= point && point <= 21 {
+        out.push_str(&digits);
+        out.push_str(&"0".repeat((point - count) as usize));
+    } else if 0 < point && point <= 21 {
+        let (integer, fraction) = digits.split_at(point as usize);
+        out.push_str(integer);
+        out.push('.');
+        out.push_str(fraction);
+    } else if -6 < point && point <= 0 {
+        out.push_str("0.");
+        out.push_str(&"0".repeat(-point as usize));
+        out.push_str(&digits);
+    } else {
+        let (first, rest) = digits.split_at(1);
+        out.push_str(first);
+        if !rest.is_empty() {
+            out.push('.');
+            out.push_str(rest);
+        }
+        out.push('e');
+        out.push(if exponent < 0 { '-' } else { '+' });
+        out.push_str(&exponent.abs().to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    #[rstest]
+    #[case(json!(0), "0")]
+    #[case(json!(-0.0), "0")]
+    #[case(json!(100), "100")]
+    #[case(json!(4.50), "4.5")]
+    #[case(json!(-1.5), "-1.5")]
+    #[case(json!(2e-3), "0.002")]
+    #[case(json!(1e-7), "1e-7")]
+    #[case(json!(1e-27), "1e-27")]
+    #[case(json!(1e21), "1e+21")]
+    #[case(json!(1e30), "1e+30")]
+    #[case(json!(123456789012345680000.0), "123456789012345680000")]
+    #[case(json!(333333333.33333329), "333333333.3333333")]
+    #[case(json!(9007199254740993u64), "9007199254740992")]
+    #[case(json!(5e-324), "5e-324")]
+    #[case(json!(1.7976931348623157e308), "1.7976931348623157e+308")]
+    fn test_numbers(#[case] value: Value, #[case] expected: &str) {
+        assert_eq!(to_string(&value), expected);
+    }
+
+    #[test]
+    fn test_strings() {
+        let value = json!("\u{20ac}$\u{000F}\u{000a}A'\u{0042}\u{0022}\u{005c}\\\"/");
+        assert_eq!(to_string(&value), "\"€$\\u000f\\nA'B\\\"\\\\\\\\\\\"/\"");
+    }
+
+    #[test]
+    fn test_sorted_members() {
+        // The members are sorted by UTF-16 code units, which puts the
+        // surrogate pair of U+1F600 before U+FB33, unlike UTF-8 bytes.
+        let value = json!({
+            "\u{20ac}": "Euro Sign",
+            "\r": "Carriage Return",
+            "\u{fb33}": "Hebrew Letter Dalet With Dagesh",
+            "1": "One",
+            "\u{1f600}": "Emoji: Grinning Face",
+            "\u{0080}": "Control",
+            "\u{00f6}": "Latin Small Letter O With Diaeresis",
+        });
+        assert_eq!(
+            to_string(&value),
+            "{\"\\r\":\"Carriage Return\",\"1\":\"One\",\"\u{80}\":\"Control\",\
+             \"\u{f6}\":\"Latin Small Letter O With Diaeresis\",\"\u{20ac}\":\"Euro Sign\",\
+             \"\u{1f600}\":\"Emoji: Grinning Face\",\
+             \"\u{fb33}\":\"Hebrew Letter Dalet With Dagesh\"}"
+        );
+    }
+
+    #[test]
+    fn test_nested() {
+        let value = json!({
+            "tee-pubkey": {"kty": "EC", "crv": "P-256", "alg": "ES256"},
+            "nonce": "abc",
+            "list": [true, null, 1.0, {"b": 1, "a": 2}],
+        });
+        assert_eq!(
+            to_string(&value),
+            r#"{"list":[true,null,1,{"a":2,"b":1}],"nonce":"abc","tee-pubkey":{"alg":"ES256","crv":"P-256","kty":"EC"}}"#
+        );
+    }
+}
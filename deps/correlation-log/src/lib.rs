@@ -0,0 +1,97 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Log output of KBS and the Attestation Service.
+//!
+//! The binaries write the log records as text, or as one JSON object per
+//! line for log aggregation. The records written while serving a request
+//! carry its correlation ID, which is passed on to the services the request
+//! calls, KBS to the Attestation Service and the Attestation Service to the
+//! RVPS, so that the logs of one request can be followed across them. KBS
+//! and a built-in Attestation Service share the correlation ID of the
+//! request.
+
+use std::future::Future;
+
+/// Metadata key of the correlation ID of a gRPC request, as the
+/// `X-Request-ID` header of the RESTful APIs.
+pub const REQUEST_ID_KEY: &str = "x-request-id";
+
+tokio::task_local! {
+    static CORRELATION_ID: String;
+}
+
+/// The correlation ID of the request being served, if any.
+pub fn correlation_id() -> Option<String> {
+    CORRELATION_ID.try_with(Clone::clone).ok()
+}
+
+/// Run `f` on behalf of the request with `correlation_id`, if it has one.
+pub async fn with_correlation_id<F: Future>(correlation_id: Option<String>, f: F) -> F::Output {
+    match correlation_id {
+        Some(correlation_id) => CORRELATION_ID.scope(correlation_id, f).await,
+        None => f.await,
+    }
+}
+
+#[cfg(feature = "output")]
+mod output {
+    use env_logger::fmt::Formatter;
+    use log::Record;
+    use serde_json::json;
+    use std::io::Write;
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+    pub enum LogFormat {
+        /// Human readable lines.
+        #[default]
+        Text,
+
+        /// One JSON object per line, with the `timestamp`, `level`,
+        /// `target`, `message` and, while serving a request,
+        /// `correlation_id` of the record.
+        Json,
+    }
+
+    /// Install the global logger writing to stderr in `format`. The records
+    /// are filtered by `RUST_LOG`, `info` by default.
+    pub fn init(format: LogFormat) {
+        let mut builder =
+            env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"));
+        if format == LogFormat::Json {
+            builder.format(write_json);
+        }
+        builder.init();
+    }
+
+    fn write_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+        let mut line = json!({
+            "timestamp": buf.timestamp_micros().to_string(),
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        if let Some(correlation_id) = super::correlation_id() {
+            line["correlation_id"] = correlation_id.into();
+        }
+        writeln!(buf, "{line}")
+    }
+}
+
+#[cfg(feature = "output")]
+pub use output::{init, LogFormat};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_correlation_id() {
+        assert_eq!(correlation_id(), None);
+        let id = with_correlation_id(Some("req-1".into()), async { correlation_id() }).await;
+        assert_eq!(id.as_deref(), Some("req-1"));
+        let id = with_correlation_id(None, async { correlation_id() }).await;
+        assert_eq!(id, None);
+    }
+}
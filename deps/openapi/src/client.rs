@@ -0,0 +1,342 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A typed Rust client of the KBS API, generated from its OpenAPI document.
+//!
+//! The generated code has a type per schema of the document and a method of
+//! `Client` per operation, named after its operation ID. It is included in a
+//! crate defining `Client`, with its `base_url`, its `http` client and its
+//! `send` method, `Result` and `segment`, which encodes a path parameter.
+//! The cookie parameters, i.e. the KBS session ID, are left to the cookie
+//! store of the HTTP client.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::fmt::Write;
+
+use crate::spec::METHODS;
+
+const SCHEMA_PREFIX: &str = "#/components/schemas/";
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern", "false",
+    "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+    "return", "static", "struct", "trait", "true", "type", "unsafe", "use", "where", "while",
+    "abstract", "final", "override", "yield",
+];
+
+/// How the body of a successful response is read.
+enum Output {
+    Unit,
+    Json(String),
+    Text,
+    Bytes,
+}
+
+/// The Rust code of the client of the OpenAPI `document`.
+pub fn generate(document: &Value) -> Result<String> {
+    let mut out = String::from("// Generated from the OpenAPI document of KBS, do not edit.\n");
+
+    if let Some(schemas) = document["components"]["schemas"].as_object() {
+        for (name, schema) in schemas {
+            write_schema(&mut out, name, schema).with_context(|| format!("schema {name}"))?;
+        }
+    }
+
+    out += "\nimpl Client {";
+    if let Some(paths) = document["paths"].as_object() {
+        for (path, item) in paths {
+            for method in METHODS {
+                if let Some(operation) = item.get(method) {
+                    write_operation(&mut out, path, method, operation)
+                        .with_context(|| format!("{} {path}", method.to_uppercase()))?;
+                }
+            }
+        }
+    }
+    out += "}\n";
+
+    Ok(out)
+}
+
+/// `name` in snake_case, e.g. `get_resource` for `getResource`.
+fn snake(name: &str) -> String {
+    let mut snake = String::new();
+    for c in name.chars() {
+        match c {
+            '-' => snake.push('_'),
+            c if c.is_uppercase() => {
+                if !snake.is_empty() {
+                    snake.push('_');
+                }
+                snake.extend(c.to_lowercase());
+            }
+            c => snake.push(c),
+        }
+    }
+    snake
+}
+
+/// The Rust identifier of the property or parameter `name`.
+fn ident(name: &str) -> String {
+    let ident = snake(name);
+    match KEYWORDS.contains(&ident.as_str()) {
+        true => format!("r#{ident}"),
+        false => ident,
+    }
+}
+
+/// The Rust type of the values of `schema`.
+fn rust_type(schema: &Value) -> String {
+    if let Some(reference) = schema["$ref"].as_str() {
+        return match reference.strip_prefix(SCHEMA_PREFIX) {
+            Some(name) => name.to_string(),
+            None => "serde_json::Value".to_string(),
+        };
+    }
+    match schema["type"].as_str() {
+        Some("string") => "String".to_string(),
+        Some("integer") => "i64".to_string(),
+        Some("number") => "f64".to_string(),
+        Some("boolean") => "bool".to_string(),
+        Some("array") => format!("Vec<{}>", rust_type(&schema["items"])),
+        _ => "serde_json::Value".to_string(),
+    }
+}
+
+fn write_doc(out: &mut String, indent: &str, doc: Option<&str>) -> Result<()> {
+    if let Some(doc) = doc {
+        let doc = doc.split_whitespace().collect::<Vec<_>>().join(" ");
+        writeln!(out, "{indent}/// {doc}")?;
+    }
+    Ok(())
+}
+
+fn write_schema(out: &mut String, name: &str, schema: &Value) -> Result<()> {
+    out.push('\n');
+    write_doc(out, "", schema["description"].as_str())?;
+    let Some(properties) = schema["properties"].as_object() else {
+        writeln!(out, "pub type {name} = {};", rust_type(schema))?;
+        return Ok(());
+    };
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+
+    writeln!(
+        out,
+        "#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]"
+    )?;
+    writeln!(out, "pub struct {name} {{")?;
+    for (property, schema) in properties {
+        write_doc(out, "    ", schema["description"].as_str())?;
+        let field = ident(property);
+        if field.trim_start_matches("r#") != property {
+            writeln!(out, "    #[serde(rename = \"{property}\")]")?;
+        }
+        let mut ty = rust_type(schema);
+        if !required.contains(&property.as_str()) || schema["nullable"] == true {
+            writeln!(
+                out,
+                "    #[serde(default, skip_serializing_if = \"Option::is_none\")]"
+            )?;
+            ty = format!("Option<{ty}>");
+        }
+        writeln!(out, "    pub {field}: {ty},")?;
+    }
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+fn write_operation(out: &mut String, path: &str, method: &str, operation: &Value) -> Result<()> {
+    let Some(id) = operation["operationId"].as_str() else {
+        bail!("missing operation ID");
+    };
+    for parameter in operation["parameters"].as_array().into_iter().flatten() {
+        if !matches!(parameter["in"].as_str(), Some("path" | "cookie")) {
+            bail!("unsupported parameter {parameter}");
+        }
+    }
+
+    let mut args = String::new();
+    // The URL, with the path parameters as arguments of `format!`.
+    let mut url = String::from("{}");
+    let mut url_args = String::from("self.base_url");
+    for segment in path.split('/').skip(1) {
+        url.push('/');
+        match segment
+            .strip_prefix('{')
+            .and_then(|segment| segment.strip_suffix('}'))
+        {
+            Some(name) => {
+                let arg = ident(name);
+                write!(args, ", {arg}: &str")?;
+                write!(url_args, ", segment({arg})")?;
+                url += "{}";
+            }
+            None => url += segment,
+        }
+    }
+
+    let body = match &operation["requestBody"]["content"] {
+        Value::Null => "",
+        Value::Object(content) if content.contains_key("application/json") => {
+            let ty = rust_type(&content["application/json"]["schema"]);
+            let ty = match ty.strip_prefix("Vec<").and_then(|ty| ty.strip_suffix('>')) {
+                Some(item) => format!("[{item}]"),
+                None => ty,
+            };
+            write!(args, ", body: &{ty}")?;
+            ".json(body)"
+        }
+        _ => {
+            write!(args, ", body: Vec<u8>")?;
+            ".body(body)"
+        }
+    };
+
+    let success = operation["responses"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .find(|(status, _)| status.starts_with('2'))
+        .map(|(_, response)| response);
+    let content = success
+        .and_then(|response| response["content"].as_object())
+        .and_then(|content| content.iter().next());
+    let output = match content {
+        None => Output::Unit,
+        Some((media_type, content)) => {
+            let schema = &content["schema"];
+            if schema["$ref"].is_string()
+                || matches!(schema["type"].as_str(), Some("object" | "array"))
+            {
+                Output::Json(rust_type(schema))
+            } else if media_type.starts_with("text/") {
+                Output::Text
+            } else {
+                Output::Bytes
+            }
+        }
+    };
+    let ty: &str = match &output {
+        Output::Unit => "()",
+        Output::Json(ty) => ty,
+        Output::Text => "String",
+        Output::Bytes => "Vec<u8>",
+    };
+
+    out.push('\n');
+    if let Some(summary) = operation["summary"].as_str() {
+        write_doc(out, "    ", Some(summary))?;
+        writeln!(out, "    ///")?;
+    }
+    writeln!(out, "    /// `{} {path}`", method.to_uppercase())?;
+    writeln!(
+        out,
+        "    pub async fn {}(&self{args}) -> Result<{ty}> {{",
+        snake(id)
+    )?;
+    writeln!(out, "        let url = format!(\"{url}\", {url_args});")?;
+    writeln!(
+        out,
+        "        let request = self.http.request(reqwest::Method::{}, url){body};",
+        method.to_uppercase()
+    )?;
+    match output {
+        Output::Unit => {
+            writeln!(out, "        self.send(request).await?;")?;
+            writeln!(out, "        Ok(())")?;
+        }
+        Output::Json(_) => writeln!(out, "        Ok(self.send(request).await?.json().await?)")?,
+        Output::Text => writeln!(out, "        Ok(self.send(request).await?.text().await?)")?,
+        Output::Bytes => writeln!(
+            out,
+            "        Ok(self.send(request).await?.bytes().await?.to_vec())"
+        )?,
+    }
+    writeln!(out, "    }}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    #[rstest]
+    #[case("getResource", "get_resource")]
+    #[case(
+        "getResourceForTenantWithoutRepository",
+        "get_resource_for_tenant_without_repository"
+    )]
+    #[case("tee-pubkey", "tee_pubkey")]
+    #[case("policy_id", "policy_id")]
+    fn test_snake(#[case] name: &str, #[case] expected: &str) {
+        assert_eq!(snake(name), expected);
+    }
+
+    #[test]
+    fn test_generate() {
+        let document = json!({
+            "paths": {
+                "/kbs/v0/sessions/{session_id}": {
+                    "delete": {
+                        "operationId": "terminateSession",
+                        "summary": "Terminate a session",
+                        "parameters": [{ "in": "path", "name": "session_id" }],
+                        "responses": { "200": { "description": "Terminated" } },
+                    },
+                },
+                "/kbs/v0/required-policies": {
+                    "post": {
+                        "operationId": "setRequiredPolicies",
+                        "requestBody": { "content": { "application/json": { "schema": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/PolicyBinding" },
+                        } } } },
+                        "responses": {},
+                    },
+                },
+            },
+            "components": { "schemas": { "PolicyBinding": {
+                "required": ["resource"],
+                "properties": {
+                    "resource": { "type": "string" },
+                    "type": { "type": "string", "description": "Binding type" },
+                    "policy-ids": { "type": "array", "items": { "type": "string" } },
+                },
+            } } },
+        });
+        let code = generate(&document).unwrap();
+
+        assert!(code.contains("pub struct PolicyBinding {"));
+        assert!(code.contains("    pub resource: String,\n"));
+        assert!(code.contains("    /// Binding type\n"));
+        assert!(code.contains("    pub r#type: Option<String>,\n"));
+        assert!(code.contains(
+            "    #[serde(rename = \"policy-ids\")]\n    #[serde(default, skip_serializing_if = \"Option::is_none\")]\n    pub policy_ids: Option<Vec<String>>,\n"
+        ));
+        assert!(code.contains(
+            "    pub async fn terminate_session(&self, session_id: &str) -> Result<()> {\n        let url = format!(\"{}/kbs/v0/sessions/{}\", self.base_url, segment(session_id));\n"
+        ));
+        assert!(code.contains(
+            "    pub async fn set_required_policies(&self, body: &[PolicyBinding]) -> Result<()> {"
+        ));
+        assert!(code.contains(".json(body);"));
+    }
+
+    #[test]
+    fn test_unsupported_parameter() {
+        let document = json!({ "paths": { "/kbs/v0/search": { "get": {
+            "operationId": "search",
+            "parameters": [{ "in": "query", "name": "q" }],
+        } } } });
+        assert!(generate(&document).is_err());
+    }
+}
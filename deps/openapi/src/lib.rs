@@ -0,0 +1,25 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The OpenAPI document of the KBS API and a typed Rust client of it,
+//! generated by the build scripts of KBS and of `kbs-api-client`.
+//!
+//! The paths of the document are the [`routes::ROUTES`] of the HTTP server of
+//! KBS built with the given features, so that the document covers every route
+//! it serves, and the operations are the ones described in `kbs/docs/kbs.yaml`.
+
+use anyhow::Result;
+
+pub mod client;
+pub mod routes;
+pub mod spec;
+
+pub use spec::Spec;
+
+/// The OpenAPI document of the routes of a KBS built with the cargo features
+/// for which `features` is true, with the operations of the YAML OpenAPI
+/// `description`.
+pub fn generate(features: impl Fn(&str) -> bool, description: &str) -> Result<Spec> {
+    spec::build(&routes::enabled(features), description)
+}
@@ -0,0 +1,409 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The routes of the KBS HTTP server. KBS registers them in `KbsService`,
+//! whose tests check that every route of [`ROUTES`] is served, and no other
+//! method at its path.
+
+/// Prefix of the paths registered with `kbs_path!`.
+pub const KBS_PREFIX: &str = "/kbs/v0";
+
+/// A handler of the requests with `method` to `path`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Route {
+    /// Full path, as registered in actix, e.g.
+    /// `/kbs/v0/resource/{repository:.+}/{type}/{tag}`.
+    pub path: &'static str,
+
+    /// Lowercase HTTP method, as the keys of an OpenAPI path item.
+    pub method: &'static str,
+
+    /// Name of the handler function, without its module.
+    pub handler: &'static str,
+
+    /// The cargo feature of KBS the route is registered with, if any.
+    pub feature: Option<&'static str>,
+}
+
+impl Route {
+    /// The OpenAPI path template of the route, i.e. its path without the
+    /// regular expressions of its parameters, e.g.
+    /// `/kbs/v0/resource/{repository}/{type}/{tag}`.
+    pub fn template(&self) -> String {
+        self.path
+            .split('/')
+            .map(|segment| match segment.strip_prefix('{') {
+                Some(param) => match param.split_once(':') {
+                    Some((name, _)) => format!("{{{name}}}"),
+                    None => segment.to_string(),
+                },
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// A route of `handler`, registered with `feature`.
+const fn route(
+    path: &'static str,
+    method: &'static str,
+    handler: &'static str,
+    feature: Option<&'static str>,
+) -> Route {
+    Route {
+        path,
+        method,
+        handler,
+        feature,
+    }
+}
+
+const AS: Option<&str> = Some("as");
+const RESOURCE: Option<&str> = Some("resource");
+const POLICY: Option<&str> = Some("policy");
+const SPIFFE: Option<&str> = Some("spiffe");
+
+/// The routes of the KBS HTTP server, in the order they are registered. The
+/// first path of a handler is the one its operation is described at, the
+/// other ones being the paths of the tenants and of the default repository.
+pub const ROUTES: &[Route] = &[
+    route("/kbs/v0/reload", "post", "reload", None),
+    route("/kbs/v0/audit/verify", "get", "verify_audit_log", None),
+    route("/kbs/v0/openapi.json", "get", "openapi", None),
+    route("/healthz", "get", "healthz", None),
+    route("/readyz", "get", "readyz", None),
+    route("/metrics", "get", "metrics", None),
+    route("/kbs/v0/auth", "post", "auth", AS),
+    route("/kbs/v0/tenant/{tenant}/auth", "post", "auth", AS),
+    route("/kbs/v0/attest", "post", "attest", AS),
+    route("/kbs/v0/tenant/{tenant}/attest", "post", "attest", AS),
+    route(
+        "/kbs/v0/attestation-policy",
+        "get",
+        "list_attestation_policies",
+        AS,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/attestation-policy",
+        "get",
+        "list_attestation_policies",
+        AS,
+    ),
+    route(
+        "/kbs/v0/attestation-policy",
+        "post",
+        "attestation_policy",
+        AS,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/attestation-policy",
+        "post",
+        "attestation_policy",
+        AS,
+    ),
+    route(
+        "/kbs/v0/attestation-policy/{policy_id}",
+        "get",
+        "get_attestation_policy",
+        AS,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/attestation-policy/{policy_id}",
+        "get",
+        "get_attestation_policy",
+        AS,
+    ),
+    route(
+        "/kbs/v0/attestation-policy/{policy_id}",
+        "delete",
+        "remove_attestation_policy",
+        AS,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/attestation-policy/{policy_id}",
+        "delete",
+        "remove_attestation_policy",
+        AS,
+    ),
+    route("/kbs/v0/verify", "post", "verify_evidence", AS),
+    route(
+        "/kbs/v0/tenant/{tenant}/verify",
+        "post",
+        "verify_evidence",
+        AS,
+    ),
+    route(
+        "/kbs/v0/reattestation-check",
+        "post",
+        "reattestation_check",
+        AS,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/reattestation-check",
+        "post",
+        "reattestation_check",
+        AS,
+    ),
+    route("/kbs/v0/sessions", "get", "list_sessions", AS),
+    route(
+        "/kbs/v0/tenant/{tenant}/sessions",
+        "get",
+        "list_sessions",
+        AS,
+    ),
+    route(
+        "/kbs/v0/sessions/{session_handle}",
+        "delete",
+        "terminate_session",
+        AS,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/sessions/{session_handle}",
+        "delete",
+        "terminate_session",
+        AS,
+    ),
+    route(
+        "/kbs/v0/policy-captures/{request_id}",
+        "get",
+        "get_policy_capture",
+        AS,
+    ),
+    route("/kbs/v0/drift", "get", "get_drift", AS),
+    route("/kbs/v0/drift/baseline", "post", "set_drift_baseline", AS),
+    route(
+        "/.well-known/openid-configuration",
+        "get",
+        "openid_configuration",
+        AS,
+    ),
+    route("/.well-known/jwks.json", "get", "jwks", AS),
+    route(
+        "/kbs/v0/resource/{repository:.+}/{type}/{tag}",
+        "get",
+        "get_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/resource/{type}/{tag}",
+        "get",
+        "get_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/resource/{repository:.+}/{type}/{tag}",
+        "get",
+        "get_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/resource/{type}/{tag}",
+        "get",
+        "get_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/resource/{repository:.+}/{type}/{tag}",
+        "post",
+        "set_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/resource/{type}/{tag}",
+        "post",
+        "set_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/resource/{repository:.+}/{type}/{tag}",
+        "post",
+        "set_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/resource/{type}/{tag}",
+        "post",
+        "set_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/wrap/{repository:.+}/{type}/{tag}",
+        "post",
+        "wrap_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/wrap/{type}/{tag}",
+        "post",
+        "wrap_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/wrap/{repository:.+}/{type}/{tag}",
+        "post",
+        "wrap_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/wrap/{type}/{tag}",
+        "post",
+        "wrap_resource",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/repository-mirror-check",
+        "post",
+        "repository_mirror_check",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/repository-mirror-check",
+        "post",
+        "repository_mirror_check",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/repository-usage",
+        "get",
+        "repository_usage",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/repository-usage",
+        "get",
+        "repository_usage",
+        RESOURCE,
+    ),
+    route("/kbs/v0/introspect", "post", "introspect", RESOURCE),
+    route(
+        "/kbs/v0/token-revocations",
+        "post",
+        "revoke_token",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/resource-policy",
+        "get",
+        "get_resource_policy",
+        POLICY,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/resource-policy",
+        "get",
+        "get_resource_policy",
+        POLICY,
+    ),
+    route("/kbs/v0/resource-policy", "post", "resource_policy", POLICY),
+    route(
+        "/kbs/v0/tenant/{tenant}/resource-policy",
+        "post",
+        "resource_policy",
+        POLICY,
+    ),
+    route(
+        "/kbs/v0/required-policies",
+        "get",
+        "get_required_policies",
+        POLICY,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/required-policies",
+        "get",
+        "get_required_policies",
+        POLICY,
+    ),
+    route(
+        "/kbs/v0/required-policies",
+        "post",
+        "set_required_policies",
+        POLICY,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/required-policies",
+        "post",
+        "set_required_policies",
+        POLICY,
+    ),
+    route(
+        "/kbs/v0/download-url/{repository:.+}/{type}/{tag}",
+        "post",
+        "download_url",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/download-url/{type}/{tag}",
+        "post",
+        "download_url",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/download-url/{repository:.+}/{type}/{tag}",
+        "post",
+        "download_url",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/tenant/{tenant}/download-url/{type}/{tag}",
+        "post",
+        "download_url",
+        RESOURCE,
+    ),
+    route("/kbs/v0/download/{capability}", "get", "download", RESOURCE),
+    route("/kbs/v0/token-exchange", "post", "token_exchange", RESOURCE),
+    route(
+        "/kbs/v0/tenant/{tenant}/token-exchange",
+        "post",
+        "token_exchange",
+        RESOURCE,
+    ),
+    route(
+        "/kbs/v0/token-exchange/jwks",
+        "get",
+        "token_exchange_jwks",
+        RESOURCE,
+    ),
+    route("/kbs/v0/svid", "post", "svid", SPIFFE),
+    route("/kbs/v0/tenant/{tenant}/svid", "post", "svid", SPIFFE),
+];
+
+/// The routes of [`ROUTES`] registered by a KBS built with the cargo
+/// features for which `enabled` is true.
+pub fn enabled(enabled: impl Fn(&str) -> bool) -> Vec<Route> {
+    ROUTES
+        .iter()
+        .filter(|route| match route.feature {
+            Some(feature) => enabled(feature),
+            None => true,
+        })
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enabled() {
+        let routes = enabled(|feature| feature == "policy");
+        assert!(routes.iter().any(|route| route.handler == "healthz"));
+        assert!(routes
+            .iter()
+            .any(|route| route.handler == "resource_policy"));
+        assert!(!routes.iter().any(|route| route.handler == "get_resource"));
+        assert_eq!(enabled(|_| true), ROUTES);
+    }
+
+    #[test]
+    fn test_template() {
+        let template = |path| route(path, "get", "handler", None).template();
+        assert_eq!(
+            template("/kbs/v0/resource/{repository:.+}/{type}/{tag}"),
+            "/kbs/v0/resource/{repository}/{type}/{tag}"
+        );
+        assert_eq!(template("/healthz"), "/healthz");
+        assert!(ROUTES.iter().all(|route| !route.template().contains(':')));
+    }
+}
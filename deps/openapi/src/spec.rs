@@ -0,0 +1,304 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The OpenAPI document of the routes of the server, with the operations
+//! described in `kbs/docs/kbs.yaml`.
+//!
+//! The description lists the operations once, at their paths relative to
+//! [`KBS_PREFIX`]. The other paths of a handler, i.e. the ones of the tenants
+//! and the ones of the default repository, get a copy of the operation of its
+//! first path with the path parameters adjusted and an operation ID of its
+//! own, e.g. `getResourceForTenant`.
+
+use anyhow::{Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+use crate::routes::{Route, KBS_PREFIX};
+
+pub(crate) const METHODS: [&str; 7] = ["get", "put", "post", "delete", "patch", "head", "options"];
+
+/// The OpenAPI document, and where the routes and their description disagree.
+#[derive(Debug)]
+pub struct Spec {
+    pub document: Value,
+
+    /// Routes without a described operation, as `<METHOD> <path>`. They get
+    /// an operation named after their handler, without a request body.
+    pub undocumented: Vec<String>,
+
+    /// Described operations no route serves, as `<METHOD> <path>`. They are
+    /// left out of the document.
+    pub unrouted: Vec<String>,
+}
+
+/// Build the OpenAPI document of `routes` from the YAML OpenAPI document
+/// `description`.
+pub fn build(routes: &[Route], description: &str) -> Result<Spec> {
+    let description: serde_yaml::Value =
+        serde_yaml::from_str(description).context("parse the API description")?;
+    // The status codes of the responses are integers in YAML, and strings in
+    // JSON.
+    let mut document = serde_json::to_value(description).context("convert the API description")?;
+    let mut described = match document.get_mut("paths").map(Value::take) {
+        Some(Value::Object(paths)) => paths,
+        _ => Map::new(),
+    };
+
+    // The operation of the first path of each handler.
+    let mut bases: HashMap<(&str, &str), (String, Value)> = HashMap::new();
+    let mut undocumented = Vec::new();
+    let mut paths = Map::new();
+    for route in routes {
+        let path = route.template();
+        let key = (route.method, route.handler);
+        let operation = match take_described(&mut described, &path, route.method) {
+            Some(operation) => operation,
+            None => match bases.get(&key) {
+                Some((base_path, operation)) => variant(operation, base_path, &path),
+                None => {
+                    undocumented.push(format!("{} {path}", route.method.to_uppercase()));
+                    stub(route, &path)
+                }
+            },
+        };
+        bases
+            .entry(key)
+            .or_insert_with(|| (path.clone(), operation.clone()));
+
+        let item = paths
+            .entry(path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[route.method] = operation;
+    }
+
+    let unrouted = described
+        .iter()
+        .flat_map(|(path, item)| {
+            METHODS
+                .iter()
+                .filter(move |method| item.get(**method).is_some())
+                .map(move |method| format!("{} {path}", method.to_uppercase()))
+        })
+        .collect();
+
+    // The paths are absolute now.
+    if let Some(servers) = document.get_mut("servers").and_then(Value::as_array_mut) {
+        for server in servers {
+            if let Some(url) = server["url"].as_str().map(str::to_string) {
+                server["url"] = json!(url.strip_suffix(KBS_PREFIX).unwrap_or(&url));
+            }
+        }
+    }
+    document["paths"] = Value::Object(paths);
+
+    Ok(Spec {
+        document,
+        undocumented,
+        unrouted,
+    })
+}
+
+/// Remove the described operation of `method` at the path template `path`
+/// from `described`. The paths outside of [`KBS_PREFIX`] are described at
+/// their full path.
+fn take_described(described: &mut Map<String, Value>, path: &str, method: &str) -> Option<Value> {
+    let path = path.strip_prefix(KBS_PREFIX).unwrap_or(path);
+    described.get_mut(path)?.as_object_mut()?.remove(method)
+}
+
+/// The names of the parameters in the path template `path`.
+fn path_params(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .collect()
+}
+
+fn path_param(name: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" },
+    })
+}
+
+/// `name` in PascalCase, e.g. `Tenant` for `tenant`.
+fn pascal(name: &str) -> String {
+    name.split(['_', '-'])
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// The operation `base` of `base_path`, served at `path` by the same handler.
+fn variant(base: &Value, base_path: &str, path: &str) -> Value {
+    let base_params = path_params(base_path);
+    let params = path_params(path);
+
+    let mut suffix = String::new();
+    for param in params.iter().filter(|param| !base_params.contains(param)) {
+        suffix += &format!("For{}", pascal(param));
+    }
+    for param in base_params.iter().filter(|param| !params.contains(param)) {
+        suffix += &format!("Without{}", pascal(param));
+    }
+
+    let mut operation = base.clone();
+    if let Some(id) = operation["operationId"].as_str().map(str::to_string) {
+        operation["operationId"] = json!(format!("{id}{suffix}"));
+    }
+
+    let mut parameters: Vec<Value> = operation["parameters"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|parameter| {
+            parameter["in"] != "path"
+                || parameter["name"]
+                    .as_str()
+                    .is_some_and(|name| params.contains(&name))
+        })
+        .collect();
+    for param in params.iter().filter(|param| !base_params.contains(param)) {
+        parameters.push(path_param(param));
+    }
+    operation["parameters"] = Value::Array(parameters);
+    operation
+}
+
+/// The operation of an undocumented route at the path template `path`, named
+/// after its handler.
+fn stub(route: &Route, path: &str) -> Value {
+    let pascal = pascal(route.handler);
+    let mut chars = pascal.chars();
+    let id: String = chars
+        .next()
+        .map(|first| first.to_lowercase().chain(chars).collect())
+        .unwrap_or_default();
+    let parameters: Vec<Value> = path_params(path).into_iter().map(path_param).collect();
+
+    json!({
+        "operationId": id,
+        "parameters": parameters,
+        "responses": {
+            "200": { "description": "Success" },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTION: &str = r#"
+openapi: 3.1.0
+servers:
+- url: http://<kbs>/kbs/v0
+paths:
+  /resource/{repository}/{type}/{tag}:
+    get:
+      operationId: getResource
+      parameters:
+        - in: cookie
+          name: kbs-session-id
+        - in: path
+          name: repository
+        - in: path
+          name: type
+        - in: path
+          name: tag
+      responses:
+        200:
+          description: The resource
+  /healthz:
+    get:
+      operationId: healthz
+      responses:
+        200:
+          description: Alive
+  /removed:
+    post:
+      operationId: removed
+"#;
+
+    fn route(path: &'static str, method: &'static str, handler: &'static str) -> Route {
+        Route {
+            path,
+            method,
+            handler,
+            feature: None,
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let routes = [
+            route("/healthz", "get", "healthz"),
+            route("/kbs/v0/reload", "post", "reload"),
+            route(
+                "/kbs/v0/resource/{repository:.+}/{type}/{tag}",
+                "get",
+                "get_resource",
+            ),
+            route("/kbs/v0/resource/{type}/{tag}", "get", "get_resource"),
+            route(
+                "/kbs/v0/tenant/{tenant}/resource/{repository:.+}/{type}/{tag}",
+                "get",
+                "get_resource",
+            ),
+        ];
+        let spec = build(&routes, DESCRIPTION).unwrap();
+        let document = &spec.document;
+
+        assert_eq!(document["servers"][0]["url"], "http://<kbs>");
+        assert_eq!(
+            document["paths"]["/healthz"]["get"]["operationId"],
+            "healthz"
+        );
+        assert_eq!(
+            document["paths"]["/healthz"]["get"]["responses"]["200"]["description"],
+            "Alive"
+        );
+
+        let resource = &document["paths"]["/kbs/v0/resource/{repository}/{type}/{tag}"]["get"];
+        assert_eq!(resource["operationId"], "getResource");
+
+        let default_repository = &document["paths"]["/kbs/v0/resource/{type}/{tag}"]["get"];
+        assert_eq!(
+            default_repository["operationId"],
+            "getResourceWithoutRepository"
+        );
+        let names: Vec<_> = default_repository["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|parameter| parameter["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["kbs-session-id", "type", "tag"]);
+
+        let tenant =
+            &document["paths"]["/kbs/v0/tenant/{tenant}/resource/{repository}/{type}/{tag}"]["get"];
+        assert_eq!(tenant["operationId"], "getResourceForTenant");
+        assert_eq!(tenant["parameters"][4]["name"], "tenant");
+
+        let reload = &document["paths"]["/kbs/v0/reload"]["post"];
+        assert_eq!(reload["operationId"], "reload");
+        assert_eq!(spec.undocumented, vec!["POST /kbs/v0/reload"]);
+        assert_eq!(spec.unrouted, vec!["POST /removed"]);
+    }
+
+    #[test]
+    fn test_pascal() {
+        assert_eq!(pascal("tenant"), "Tenant");
+        assert_eq!(pascal("get_resource_policy"), "GetResourcePolicy");
+        assert_eq!(pascal("request-id"), "RequestId");
+    }
+}
@@ -9,6 +9,8 @@ pub mod sample;
 
 pub mod eventlog;
 
+pub mod samples;
+
 #[cfg(feature = "az-snp-vtpm-verifier")]
 pub mod az_snp_vtpm;
 
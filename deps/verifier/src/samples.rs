@@ -0,0 +1,74 @@
+//! Sample evidence bundled with the verifiers, to check that a verifier and
+//! its collateral configuration work without an attester, e.g. at startup.
+//!
+//! The evidence of a hardware TEE is a genuine quote, so verifying it
+//! exercises the signature checks and the collateral fetch of the verifier.
+//! It binds no report data nor init data, and its TCB may be out of date.
+
+use base64::Engine;
+use kbs_types::Tee;
+use serde_json::json;
+
+/// The TEEs whose verifier is compiled in.
+pub fn compiled_tees() -> Vec<Tee> {
+    let mut tees = vec![Tee::Sample];
+    for (compiled, tee) in [
+        (cfg!(feature = "tdx-verifier"), Tee::Tdx),
+        (cfg!(feature = "sgx-verifier"), Tee::Sgx),
+        (cfg!(feature = "snp-verifier"), Tee::Snp),
+        (cfg!(feature = "az-snp-vtpm-verifier"), Tee::AzSnpVtpm),
+        (cfg!(feature = "az-tdx-vtpm-verifier"), Tee::AzTdxVtpm),
+        (cfg!(feature = "csv-verifier"), Tee::Csv),
+        (cfg!(feature = "cca-verifier"), Tee::Cca),
+        (cfg!(feature = "se-verifier"), Tee::Se),
+        (cfg!(feature = "system-verifier"), Tee::System),
+    ] {
+        if compiled {
+            tees.push(tee);
+        }
+    }
+    tees
+}
+
+/// The sample evidence of `tee`, if one is bundled.
+pub fn sample_evidence(tee: &Tee) -> Option<Vec<u8>> {
+    let evidence = match tee {
+        Tee::Sample => json!({"svn": "1", "report_data": "", "init_data": ""}),
+        #[cfg(feature = "tdx-verifier")]
+        Tee::Tdx => json!({
+            "quote": base64::engine::general_purpose::STANDARD
+                .encode(include_bytes!("../test_data/tdx_quote_4.dat")),
+        }),
+        #[cfg(feature = "sgx-verifier")]
+        Tee::Sgx => json!({
+            "quote": base64::engine::general_purpose::STANDARD
+                .encode(include_bytes!("../test_data/occlum_quote.dat")),
+        }),
+        _ => return None,
+    };
+    Some(evidence.to_string().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{to_verifier, InitDataHash, ReportData};
+
+    #[tokio::test]
+    async fn test_sample_evidence() {
+        assert_eq!(compiled_tees()[0], Tee::Sample);
+
+        let evidence = sample_evidence(&Tee::Sample).unwrap();
+        let claims = to_verifier(&Tee::Sample)
+            .unwrap()
+            .evaluate(
+                &evidence,
+                &ReportData::NotProvided,
+                &InitDataHash::NotProvided,
+            )
+            .await
+            .unwrap();
+        assert_eq!(claims["svn"], "1");
+        assert!(sample_evidence(&Tee::Sev).is_none());
+    }
+}
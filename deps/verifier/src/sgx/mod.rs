@@ -147,7 +147,9 @@ async fn ecdsa_quote_verification(quote: &[u8]) -> Result<()> {
     }
 
     // get collateral
-    let collateral = match tee_qv_get_collateral(quote) {
+    let collateral = match tracing::info_span!("collateral_fetch", tee = "sgx")
+        .in_scope(|| tee_qv_get_collateral(quote))
+    {
         std::result::Result::Ok(c) => {
             debug!("tee_qv_get_collateral successfully returned.");
             Some(c)
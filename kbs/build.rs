@@ -7,9 +7,43 @@
 #[allow(unused_imports)]
 use std::process::Command;
 
+/// Generate the OpenAPI document of the routes of the HTTP server with the
+/// enabled features, with the operations described in `docs/kbs.yaml`.
+fn openapi() -> Result<(), String> {
+    println!("cargo:rerun-if-changed=docs/kbs.yaml");
+
+    let description = std::fs::read_to_string("docs/kbs.yaml").map_err(|e| format!("{e}"))?;
+    let enabled = |feature: &str| {
+        let feature = feature.to_uppercase().replace('-', "_");
+        std::env::var_os(format!("CARGO_FEATURE_{feature}")).is_some()
+    };
+    let spec = kbs_openapi::generate(enabled, &description).map_err(|e| format!("{e:#}"))?;
+
+    let out_dir = std::env::var("OUT_DIR").map_err(|e| format!("{e}"))?;
+    std::fs::write(
+        std::path::Path::new(&out_dir).join("openapi.json"),
+        spec.document.to_string(),
+    )
+    .map_err(|e| format!("{e}"))
+}
+
 fn main() -> Result<(), String> {
+    openapi()?;
+
     #[cfg(feature = "tonic-build")]
     tonic_build::compile_protos("../protos/attestation.proto").map_err(|e| format!("{e}"))?;
 
+    #[cfg(feature = "grpc-api")]
+    tonic_build::compile_protos("../protos/kbs.proto").map_err(|e| format!("{e}"))?;
+
+    #[cfg(feature = "spiffe")]
+    tonic_build::configure()
+        .build_server(false)
+        .compile(
+            &["../protos/spire/svid.proto", "../protos/spire/bundle.proto"],
+            &["../protos"],
+        )
+        .map_err(|e| format!("{e}"))?;
+
     Ok(())
 }
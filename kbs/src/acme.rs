@@ -0,0 +1,450 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! HTTPS certificate management over ACME (RFC 8555), e.g. with Let's Encrypt.
+//!
+//! The ACME account, the certificate chain and its private key are stored in
+//! the ACME work dir. A certificate is ordered on startup if none is stored or
+//! the stored one is about to expire, and renewed in the background.
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use anyhow::{anyhow, bail, Context, Result};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, OrderStatus,
+};
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+
+use crate::tls::TlsCredentials;
+
+pub const DEFAULT_ACME_DIRECTORY_URL: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const DEFAULT_ACME_WORK_DIR: &str = "/opt/confidential-containers/kbs/acme";
+pub const DEFAULT_HTTP01_SOCKET: &str = "0.0.0.0:80";
+pub const DEFAULT_RENEW_BEFORE_DAYS: i64 = 30;
+
+/// How often the stored certificate is checked for renewal.
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How many times the order is polled before giving up.
+const MAX_POLL_ATTEMPTS: u32 = 10;
+const MAX_POLL_DELAY: Duration = Duration::from_secs(10);
+
+const HTTP01_PATH: &str = "/.well-known/acme-challenge/{token}";
+
+/// ACME certificate management configuration.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AcmeConfig {
+    /// Domain names the certificate is issued for.
+    pub domains: Vec<String>,
+
+    /// Contact URLs of the ACME account, e.g. `mailto:admin@example.com`.
+    #[serde(default)]
+    pub contact: Vec<String>,
+
+    /// Whether the operator agrees to the terms of service of the ACME
+    /// server, which it requires to create the account. Must be set
+    /// explicitly, and to `true`.
+    pub terms_of_service_agreed: bool,
+
+    /// Directory URL of the ACME server.
+    #[serde(default = "default_directory_url")]
+    pub directory_url: String,
+
+    /// Directory the ACME account, certificate and private key are kept in.
+    #[serde(default = "default_work_dir")]
+    pub work_dir: PathBuf,
+
+    /// How the domains are validated.
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+
+    /// Renew the certificate this many days before it expires.
+    #[serde(default = "default_renew_before_days")]
+    pub renew_before_days: i64,
+}
+
+fn default_directory_url() -> String {
+    DEFAULT_ACME_DIRECTORY_URL.to_string()
+}
+
+fn default_work_dir() -> PathBuf {
+    PathBuf::from(DEFAULT_ACME_WORK_DIR)
+}
+
+fn default_http01_socket() -> SocketAddr {
+    DEFAULT_HTTP01_SOCKET.parse().expect("valid default socket")
+}
+
+fn default_renew_before_days() -> i64 {
+    DEFAULT_RENEW_BEFORE_DAYS
+}
+
+/// ACME challenge used to prove control over the domains.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum AcmeChallenge {
+    /// Answer HTTP-01 challenges on a plain HTTP `socket`, that must be
+    /// reachable as port 80 of every domain.
+    Http01 {
+        #[serde(default = "default_http01_socket")]
+        socket: SocketAddr,
+    },
+
+    /// Publish DNS-01 challenges as TXT records. The `hook` program is called
+    /// as `hook set <record> <value>` before and `hook unset <record> <value>`
+    /// after the validation.
+    Dns01 { hook: PathBuf },
+}
+
+impl Default for AcmeChallenge {
+    fn default() -> Self {
+        Self::Http01 {
+            socket: default_http01_socket(),
+        }
+    }
+}
+
+/// Key authorizations of the pending HTTP-01 challenges by token.
+type Http01Tokens = Arc<RwLock<HashMap<String, String>>>;
+
+pub(crate) struct Acme {
+    config: AcmeConfig,
+    http01_tokens: Http01Tokens,
+}
+
+impl Acme {
+    /// Prepare the work dir and start the HTTP-01 responder if needed.
+    pub async fn new(config: AcmeConfig) -> Result<Self> {
+        if config.domains.is_empty() {
+            bail!("No domain given for ACME");
+        }
+        if !config.terms_of_service_agreed {
+            bail!("The terms of service of the ACME server must be agreed to");
+        }
+
+        tokio::fs::create_dir_all(&config.work_dir)
+            .await
+            .context("create ACME work dir")?;
+
+        let http01_tokens = Http01Tokens::default();
+        if let AcmeChallenge::Http01 { socket } = &config.challenge {
+            let tokens = web::Data::new(http01_tokens.clone());
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(tokens.clone())
+                    .route(HTTP01_PATH, web::get().to(http01_response))
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(socket)
+            .context("bind ACME HTTP-01 socket")?
+            .run();
+            tokio::spawn(server);
+            info!("Answering ACME HTTP-01 challenges at http://{socket}");
+        }
+
+        Ok(Self {
+            config,
+            http01_tokens,
+        })
+    }
+
+    pub fn certificate_path(&self) -> PathBuf {
+        self.config.work_dir.join("cert.pem")
+    }
+
+    pub fn private_key_path(&self) -> PathBuf {
+        self.config.work_dir.join("key.pem")
+    }
+
+    fn account_path(&self) -> PathBuf {
+        self.config.work_dir.join("account.json")
+    }
+
+    /// Order a new certificate unless the stored one is still valid for
+    /// `renew_before_days`. Returns whether a new certificate was stored.
+    pub async fn ensure_certificate(&self) -> Result<bool> {
+        if !needs_renewal(&self.certificate_path(), self.config.renew_before_days) {
+            return Ok(false);
+        }
+
+        info!("Ordering certificate for {:?}", self.config.domains);
+        self.order().await?;
+        info!("Certificate for {:?} stored", self.config.domains);
+        Ok(true)
+    }
+
+    /// Renew the certificate in the background and reload `credentials`
+    /// once it has been renewed.
+    pub fn renew(self, credentials: Arc<TlsCredentials>) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(RENEW_CHECK_INTERVAL).await;
+                match self.ensure_certificate().await {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        if let Err(e) = credentials.reload() {
+                            error!("Failed to load renewed certificate: {e:?}");
+                        }
+                    }
+                    Err(e) => error!("Failed to renew certificate: {e:?}"),
+                }
+            }
+        });
+    }
+
+    async fn account(&self) -> Result<Account> {
+        let path = self.account_path();
+        if path.exists() {
+            let credentials: AccountCredentials =
+                serde_json::from_slice(&tokio::fs::read(&path).await.context("read ACME account")?)
+                    .context("parse ACME account")?;
+            return Account::from_credentials(credentials)
+                .await
+                .context("restore ACME account");
+        }
+
+        let contact: Vec<&str> = self.config.contact.iter().map(String::as_str).collect();
+        let (account, credentials) = Account::create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: self.config.terms_of_service_agreed,
+                only_return_existing: false,
+            },
+            &self.config.directory_url,
+            None,
+        )
+        .await
+        .context("create ACME account")?;
+        write_private(&path, &serde_json::to_vec(&credentials)?).await?;
+        info!("ACME account created");
+
+        Ok(account)
+    }
+
+    async fn order(&self) -> Result<()> {
+        let account = self.account().await?;
+        let identifiers: Vec<Identifier> = self
+            .config
+            .domains
+            .iter()
+            .map(|domain| Identifier::Dns(domain.clone()))
+            .collect();
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &identifiers,
+            })
+            .await
+            .context("create ACME order")?;
+
+        let mut dns_records = Vec::new();
+        let mut challenge_urls = Vec::new();
+        for authz in order.authorizations().await? {
+            match authz.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => bail!("ACME authorization is {status:?}"),
+            }
+
+            let Identifier::Dns(domain) = &authz.identifier;
+            let challenge_type = match self.config.challenge {
+                AcmeChallenge::Http01 { .. } => ChallengeType::Http01,
+                AcmeChallenge::Dns01 { .. } => ChallengeType::Dns01,
+            };
+            let challenge = authz
+                .challenges
+                .iter()
+                .find(|c| c.r#type == challenge_type)
+                .ok_or_else(|| anyhow!("No {challenge_type:?} challenge for {domain}"))?;
+            let key_authorization = order.key_authorization(challenge);
+
+            match &self.config.challenge {
+                AcmeChallenge::Http01 { .. } => {
+                    self.http01_tokens
+                        .write()
+                        .map_err(|_| anyhow!("HTTP-01 tokens lock poisoned"))?
+                        .insert(
+                            challenge.token.clone(),
+                            key_authorization.as_str().to_string(),
+                        );
+                }
+                AcmeChallenge::Dns01 { hook } => {
+                    let record = format!("_acme-challenge.{}", domain.trim_start_matches("*."));
+                    let value = key_authorization.dns_value();
+                    run_hook(hook, "set", &record, &value).await?;
+                    dns_records.push((record, value));
+                }
+            }
+            challenge_urls.push(challenge.url.clone());
+        }
+
+        let result = async {
+            for url in &challenge_urls {
+                order.set_challenge_ready(url).await?;
+            }
+            wait_for_order(&mut order).await?;
+
+            let mut params = rcgen::CertificateParams::new(self.config.domains.clone());
+            params.distinguished_name = rcgen::DistinguishedName::new();
+            let key = rcgen::Certificate::from_params(params)?;
+            order.finalize(&key.serialize_request_der()?).await?;
+
+            let mut attempts = 0;
+            let chain = loop {
+                if let Some(chain) = order.certificate().await? {
+                    break chain;
+                }
+                attempts += 1;
+                if attempts >= MAX_POLL_ATTEMPTS {
+                    bail!("ACME certificate was not issued in time");
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            };
+
+            write_private(
+                &self.private_key_path(),
+                key.serialize_private_key_pem().as_bytes(),
+            )
+            .await?;
+            write_private(&self.certificate_path(), chain.as_bytes()).await
+        }
+        .await;
+
+        if let AcmeChallenge::Dns01 { hook } = &self.config.challenge {
+            for (record, value) in &dns_records {
+                if let Err(e) = run_hook(hook, "unset", record, value).await {
+                    warn!("Failed to remove {record} TXT record: {e:?}");
+                }
+            }
+        }
+        if let Ok(mut tokens) = self.http01_tokens.write() {
+            tokens.clear();
+        }
+
+        result
+    }
+}
+
+/// GET /.well-known/acme-challenge/{token}
+async fn http01_response(
+    token: web::Path<String>,
+    tokens: web::Data<Http01Tokens>,
+) -> HttpResponse {
+    let key_authorization = tokens
+        .read()
+        .ok()
+        .and_then(|tokens| tokens.get(token.as_str()).cloned());
+    match key_authorization {
+        Some(key_authorization) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(key_authorization),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn wait_for_order(order: &mut instant_acme::Order) -> Result<()> {
+    let mut delay = Duration::from_millis(250);
+    for _ in 0..MAX_POLL_ATTEMPTS {
+        tokio::time::sleep(delay).await;
+        let state = order.refresh().await?;
+        match state.status {
+            OrderStatus::Ready => return Ok(()),
+            OrderStatus::Invalid => bail!("ACME order is invalid: {:?}", state.error),
+            _ => delay = (delay * 2).min(MAX_POLL_DELAY),
+        }
+    }
+
+    bail!("ACME order is not ready in time")
+}
+
+async fn run_hook(hook: &Path, action: &str, record: &str, value: &str) -> Result<()> {
+    let status = tokio::process::Command::new(hook)
+        .args([action, record, value])
+        .status()
+        .await
+        .with_context(|| format!("run DNS-01 hook {}", hook.display()))?;
+    if !status.success() {
+        bail!("DNS-01 hook `{action} {record}` failed with {status}");
+    }
+    Ok(())
+}
+
+/// Replace `path` by a file only readable by KBS.
+async fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .await
+        .with_context(|| format!("create {}", tmp.display()))?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("write {}", path.display()))
+}
+
+/// Whether the certificate at `path` is missing, unreadable or expires within
+/// `renew_before_days`.
+fn needs_renewal(path: &Path, renew_before_days: i64) -> bool {
+    let Ok(pem) = std::fs::read(path) else {
+        return true;
+    };
+    let not_after = match x509_parser::pem::parse_x509_pem(&pem) {
+        Ok((_, pem)) => match pem.parse_x509() {
+            Ok(cert) => cert.validity().not_after.timestamp(),
+            Err(_) => return true,
+        },
+        Err(_) => return true,
+    };
+
+    let renew_at = not_after - renew_before_days * 24 * 60 * 60;
+    OffsetDateTime::now_utc().unix_timestamp() >= renew_at
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(10, true)]
+    #[case(60, false)]
+    fn test_needs_renewal(#[case] valid_days: i64, #[case] expected: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cert.pem");
+        assert!(needs_renewal(&path, DEFAULT_RENEW_BEFORE_DAYS));
+
+        let mut params = rcgen::CertificateParams::new(vec!["kbs.example.com".to_string()]);
+        params.not_after = OffsetDateTime::now_utc() + time::Duration::days(valid_days);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        std::fs::write(&path, cert.serialize_pem().unwrap()).unwrap();
+
+        assert_eq!(needs_renewal(&path, DEFAULT_RENEW_BEFORE_DAYS), expected);
+    }
+
+    #[tokio::test]
+    async fn test_terms_of_service() {
+        let config = |json| serde_json::from_value::<AcmeConfig>(json);
+        assert!(config(serde_json::json!({"domains": ["kbs.example.com"]})).is_err());
+
+        let declined = config(serde_json::json!({
+            "domains": ["kbs.example.com"],
+            "terms_of_service_agreed": false,
+        }))
+        .unwrap();
+        assert!(Acme::new(declined).await.is_err());
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+use zeroize::Zeroizing;
+
+use super::ArchiveStore;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Version of the Blob service REST API, the first to set immutability
+/// policies on upload.
+const BLOB_API_VERSION: &str = "2021-12-02";
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AzureBlobConfig {
+    /// URL of the container the records are put in, e.g.
+    /// `https://<account>.blob.core.windows.net/<container>`.
+    pub container_url: String,
+
+    /// File holding the SAS token authorizing to create blobs in the
+    /// container.
+    pub sas_token_path: PathBuf,
+
+    /// Prefix of the blob names, e.g. `kbs/`.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// Archive of the records as the block blobs of an Azure Blob Storage
+/// container, authorized with a SAS token.
+pub(crate) struct AzureBlobStore {
+    client: reqwest::Client,
+    container_url: String,
+    prefix: String,
+    sas_token: Zeroizing<String>,
+}
+
+impl AzureBlobStore {
+    pub fn new(config: &AzureBlobConfig) -> Result<Self> {
+        let container_url = config.container_url.trim_end_matches('/').to_string();
+        Url::parse(&container_url)
+            .with_context(|| format!("invalid container URL {container_url}"))?;
+        let sas_token = std::fs::read_to_string(&config.sas_token_path)
+            .with_context(|| format!("read SAS token {}", config.sas_token_path.display()))?;
+        let sas_token = sas_token.trim().trim_start_matches('?').to_string();
+        if sas_token.is_empty() {
+            bail!("SAS token {} is empty", config.sas_token_path.display());
+        }
+
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            container_url,
+            prefix: config.prefix.clone(),
+            sas_token: Zeroizing::new(sas_token),
+        })
+    }
+}
+
+#[async_trait]
+impl ArchiveStore for AzureBlobStore {
+    async fn put(
+        &self,
+        name: &str,
+        record: Vec<u8>,
+        retain_until: Option<OffsetDateTime>,
+    ) -> Result<()> {
+        let mut url = Url::parse(&format!("{}/{}{name}", self.container_url, self.prefix))?;
+        url.set_query(Some(&self.sas_token));
+
+        let mut request = self
+            .client
+            .put(url)
+            .header("x-ms-blob-type", "BlockBlob")
+            .header("x-ms-version", BLOB_API_VERSION)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        // Version-level immutability must be enabled on the container to
+        // retain the records.
+        if let Some(retain_until) = retain_until {
+            request = request
+                .header(
+                    "x-ms-immutability-policy-until-date",
+                    http_date(retain_until)?,
+                )
+                .header("x-ms-immutability-policy-mode", "Locked");
+        }
+
+        let response = request.body(record).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            bail!(
+                "Azure Blob Storage returned {status}: {}",
+                response.text().await?
+            );
+        }
+        Ok(())
+    }
+}
+
+/// `time` in the HTTP date format, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: OffsetDateTime) -> Result<String> {
+    let date = time.to_offset(time::UtcOffset::UTC).format(&Rfc2822)?;
+    Ok(date.replace("+0000", "GMT"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Date, Month};
+
+    #[test]
+    fn test_http_date() {
+        let time = Date::from_calendar_date(1994, Month::November, 6)
+            .unwrap()
+            .with_hms(8, 49, 37)
+            .unwrap()
+            .assume_utc();
+        assert_eq!(http_date(time).unwrap(), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}
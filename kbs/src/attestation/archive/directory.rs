@@ -0,0 +1,149 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use time::{Date, Month, OffsetDateTime};
+use tokio::io::AsyncWriteExt;
+
+use super::ArchiveStore;
+
+/// Records are only readable by KBS, as they hold attestation tokens.
+const DIR_MODE: u32 = 0o700;
+const FILE_MODE: u32 = 0o600;
+
+/// Archive of the records as files below a local directory, in a directory
+/// per day.
+pub(crate) struct DirectoryStore {
+    path: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(path: &Path) -> Result<Self> {
+        create_dir(path)
+            .with_context(|| format!("create attestation archive {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+fn create_dir(path: &Path) -> std::io::Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(DIR_MODE)
+        .create(path)
+}
+
+#[async_trait]
+impl ArchiveStore for DirectoryStore {
+    async fn put(
+        &self,
+        name: &str,
+        record: Vec<u8>,
+        // Nothing keeps KBS, or its administrators, from deleting the files.
+        _retain_until: Option<OffsetDateTime>,
+    ) -> Result<()> {
+        let path = self.path.join(name);
+        if let Some(parent) = path.parent() {
+            create_dir(parent).with_context(|| format!("create {}", parent.display()))?;
+        }
+
+        let tmp = path.with_extension("tmp");
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(FILE_MODE)
+            .open(&tmp)
+            .await
+            .with_context(|| format!("create {}", tmp.display()))?;
+        file.write_all(&record).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .with_context(|| format!("write {}", path.display()))
+    }
+
+    async fn expire(&self, before: OffsetDateTime) -> Result<()> {
+        let before = before.date();
+        for (date, path) in day_dirs(&self.path)? {
+            if date < before {
+                tokio::fs::remove_dir_all(&path)
+                    .await
+                    .with_context(|| format!("delete {}", path.display()))?;
+                // Drop the month and year directories left empty. Removing
+                // a directory that isn't empty fails, which is fine.
+                for parent in path.ancestors().skip(1).take(2) {
+                    let _ = tokio::fs::remove_dir(parent).await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The `YYYY/MM/DD` directories below `root`, with their date. Other files
+/// are ignored.
+fn day_dirs(root: &Path) -> Result<Vec<(Date, PathBuf)>> {
+    let mut days = Vec::new();
+    for (year, year_dir) in numbered_dirs(root)? {
+        for (month, month_dir) in numbered_dirs(&year_dir)? {
+            for (day, day_dir) in numbered_dirs(&month_dir)? {
+                let Ok(month) = Month::try_from(month as u8) else {
+                    continue;
+                };
+                if let Ok(date) = Date::from_calendar_date(year as i32, month, day as u8) {
+                    days.push((date, day_dir));
+                }
+            }
+        }
+    }
+    Ok(days)
+}
+
+fn numbered_dirs(path: &Path) -> Result<Vec<(u16, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(path).with_context(|| format!("read {}", path.display()))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(number) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            dirs.push((number, entry.path()));
+        }
+    }
+    Ok(dirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn test_directory_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DirectoryStore::new(&dir.path().join("archive")).unwrap();
+
+        for name in [
+            "2024/01/31/a.json",
+            "2024/02/01/b.json",
+            "2024/02/02/c.json",
+        ] {
+            store.put(name, b"{}".to_vec(), None).await.unwrap();
+        }
+        let path = dir.path().join("archive/2024/02/01/b.json");
+        assert_eq!(std::fs::read(&path).unwrap(), b"{}");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, FILE_MODE);
+
+        let before = Date::from_calendar_date(2024, Month::February, 2).unwrap();
+        store.expire(before.midnight().assume_utc()).await.unwrap();
+        assert!(!dir.path().join("archive/2024/01").exists());
+        assert!(!dir.path().join("archive/2024/02/01").exists());
+        assert!(dir.path().join("archive/2024/02/02/c.json").exists());
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Durable records of the attestations.
+//!
+//! Every attestation transaction, with the evidence, the claims, the policies
+//! and reference values it was checked against and the digest of the issued
+//! token, is archived to a store for later forensic re-verification and
+//! compliance retention. Records are written in the background, so that a
+//! slow or failing store doesn't delay or break the attestation.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use kbs_types::Tee;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::mpsc;
+
+use super::{Attest, PolicyOutcome, Verdict};
+use crate::audit::{AuditEvent, Outcome};
+
+mod azure;
+mod directory;
+mod s3;
+
+pub use azure::AzureBlobConfig;
+use azure::AzureBlobStore;
+use directory::DirectoryStore;
+pub use s3::S3Config;
+use s3::S3Store;
+
+const ARCHIVE_ATTEMPTS: u32 = 3;
+
+/// Records waiting to be archived. Records beyond it are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// How often the records past their retention are deleted, for the stores
+/// that don't expire them on their own.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Attestation archive configuration.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ArchiveConfig {
+    #[serde(flatten)]
+    pub store: ArchiveStoreConfig,
+
+    /// Days the records are retained. Records are kept forever when
+    /// omitted.
+    pub retention_days: Option<u32>,
+}
+
+/// Attestation archive store configuration.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum ArchiveStoreConfig {
+    /// Write the records as files below a local directory.
+    Directory { path: PathBuf },
+
+    /// Put the records as objects of an S3 bucket.
+    S3(S3Config),
+
+    /// Put the records as blobs of an Azure Blob Storage container.
+    AzureBlob(AzureBlobConfig),
+}
+
+impl ArchiveConfig {
+    /// Check that the store can be opened, without archiving or expiring
+    /// any record.
+    pub fn validate(&self) -> Result<()> {
+        self.store.store().map(|_| ())
+    }
+}
+
+impl ArchiveStoreConfig {
+    fn store(&self) -> Result<Arc<dyn ArchiveStore>> {
+        Ok(match self {
+            Self::Directory { path } => Arc::new(DirectoryStore::new(path)?),
+            Self::S3(config) => Arc::new(S3Store::new(config)?),
+            Self::AzureBlob(config) => Arc::new(AzureBlobStore::new(config)?),
+        })
+    }
+}
+
+#[async_trait]
+pub(crate) trait ArchiveStore: Send + Sync {
+    /// Store the serialized record `record` as `name`, where it can't be
+    /// deleted before `retain_until`, if set.
+    async fn put(
+        &self,
+        name: &str,
+        record: Vec<u8>,
+        retain_until: Option<OffsetDateTime>,
+    ) -> Result<()>;
+
+    /// Delete the records archived before `before`. Stores with their own
+    /// lifecycle management leave it to them.
+    async fn expire(&self, _before: OffsetDateTime) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The archived record of one attestation.
+#[derive(Clone, Debug, Serialize)]
+pub struct AttestationRecord {
+    pub id: String,
+    pub timestamp: String,
+
+    /// Correlation ID of the audit records of the attestation.
+    pub correlation_id: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    pub tee: Tee,
+
+    /// The attestation as sent by the attester: the evidence and, in a KBS
+    /// session, its TEE public key. Evidence verified by the admin API isn't
+    /// bound to a session.
+    pub attestation: String,
+
+    /// Nonce of the challenge the evidence answers, in a KBS session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+
+    /// Base64url encoded TLS channel binding the evidence is bound to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_binding: Option<String>,
+
+    /// The attestation policy the evidence was verified with.
+    pub policy_id: String,
+
+    pub outcome: Outcome,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// Policies the evidence was evaluated against.
+    pub policies: Vec<PolicyOutcome>,
+
+    /// Base64 encoded policies the evidence was evaluated against, as
+    /// stored when the record is archived.
+    pub policy_texts: BTreeMap<String, String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claims: Option<Value>,
+
+    /// Reference values the claims were checked against.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference_data: Option<Value>,
+
+    /// Hex encoded SHA-256 digest of the issued token. The token itself
+    /// isn't archived, as it is valid until it expires.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_digest: Option<String>,
+}
+
+impl AttestationRecord {
+    /// Start the record of the verification of `attestation` from `tee`
+    /// with `policy_id`, audited as `event`.
+    pub fn new(event: &AuditEvent, tee: Tee, policy_id: &str, attestation: &str) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: event.timestamp.clone(),
+            correlation_id: event.correlation_id.clone(),
+            tenant: event
+                .details
+                .get("tenant")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            tee,
+            attestation: attestation.to_string(),
+            nonce: None,
+            channel_binding: None,
+            policy_id: policy_id.to_string(),
+            outcome: Outcome::Success,
+            reason: None,
+            policies: Vec::new(),
+            policy_texts: BTreeMap::new(),
+            claims: None,
+            reference_data: None,
+            token_digest: None,
+        }
+    }
+
+    /// Set the challenge `nonce` and the TLS `channel_binding` of the KBS
+    /// session the evidence is bound to.
+    pub fn session(mut self, nonce: &str, channel_binding: Option<&[u8]>) -> Self {
+        self.nonce = Some(nonce.to_string());
+        self.channel_binding = channel_binding.map(|binding| URL_SAFE_NO_PAD.encode(binding));
+        self
+    }
+
+    /// Set the outcome of the verification from its `verdict`.
+    pub fn verdict<E: Display>(mut self, verdict: &std::result::Result<Verdict, E>) -> Self {
+        match verdict {
+            Ok(verdict) => {
+                self.outcome = Outcome::Success;
+                self.policies = verdict.policies.clone();
+                self.reference_data = verdict.claims.get("reference-data").cloned();
+                self.claims = Some(verdict.claims.clone());
+                self.token_digest = Some(verdict.token.as_str())
+                    .filter(|token| !token.is_empty())
+                    .map(|token| hex::encode(Sha256::digest(token)));
+            }
+            Err(e) => {
+                self.outcome = Outcome::Failure;
+                self.reason = Some(e.to_string());
+            }
+        }
+        self
+    }
+
+    /// Name of the record in the store, partitioned by day.
+    fn name(&self) -> String {
+        let date = OffsetDateTime::parse(&self.timestamp, &Rfc3339)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc())
+            .date();
+        format!(
+            "{:04}/{:02}/{:02}/{}.json",
+            date.year(),
+            u8::from(date.month()),
+            date.day(),
+            self.id
+        )
+    }
+}
+
+/// Handle to the attestation archive. Archiving is disabled when no store
+/// is configured.
+#[derive(Clone, Default)]
+pub struct Archive {
+    queue: Option<mpsc::Sender<(AttestationRecord, Arc<dyn Attest>)>>,
+}
+
+impl Archive {
+    pub fn new(config: Option<&ArchiveConfig>) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self::default());
+        };
+
+        let store = config.store.store().context("open attestation archive")?;
+        let retention = config
+            .retention_days
+            .map(|days| time::Duration::days(days.into()));
+        if let Some(retention) = retention {
+            let store = store.clone();
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = store.expire(OffsetDateTime::now_utc() - retention).await {
+                        warn!("Failed to delete expired attestation records: {e:#}");
+                    }
+                    tokio::time::sleep(EXPIRY_INTERVAL).await;
+                }
+            });
+        }
+
+        let (queue, mut records) =
+            mpsc::channel::<(AttestationRecord, Arc<dyn Attest>)>(QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some((record, backend)) = records.recv().await {
+                let retain_until = retention.map(|retention| OffsetDateTime::now_utc() + retention);
+                archive(store.as_ref(), record, backend.as_ref(), retain_until).await;
+            }
+        });
+
+        Ok(Self { queue: Some(queue) })
+    }
+
+    /// Whether the attestations are archived.
+    pub fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Archive `record` in the background, with the texts of its policies
+    /// read from `backend`. Records the store can't keep up with are dropped
+    /// with a warning in the log.
+    pub fn archive(&self, record: AttestationRecord, backend: Arc<dyn Attest>) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        if let Err(e) = queue.try_send((record, backend)) {
+            warn!("Dropping attestation record: {e}");
+        }
+    }
+}
+
+/// Archive `record` to `store`, with the texts of its policies read from
+/// `backend`. Failed writes are retried twice and then dropped with a warning
+/// in the log.
+async fn archive(
+    store: &dyn ArchiveStore,
+    mut record: AttestationRecord,
+    backend: &dyn Attest,
+    retain_until: Option<OffsetDateTime>,
+) {
+    let policy_ids = std::iter::once(&record.policy_id)
+        .chain(record.policies.iter().map(|policy| &policy.policy_id))
+        .cloned()
+        .collect::<Vec<_>>();
+    for policy_id in policy_ids {
+        if record.policy_texts.contains_key(&policy_id) {
+            continue;
+        }
+        match backend.get_policy(&policy_id).await {
+            Ok(policy) => {
+                record.policy_texts.insert(policy_id, policy);
+            }
+            Err(e) => warn!("Failed to read policy {policy_id} to archive: {e:#}"),
+        }
+    }
+
+    let name = record.name();
+    let body = match serde_json::to_vec(&record) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Dropping attestation record {name}: {e}");
+            return;
+        }
+    };
+    for attempt in 1..=ARCHIVE_ATTEMPTS {
+        match store.put(&name, body.clone(), retain_until).await {
+            Ok(()) => return,
+            Err(e) if attempt < ARCHIVE_ATTEMPTS => {
+                warn!("Archiving attestation record {name}, attempt {attempt} failed: {e:#}");
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+            Err(e) => warn!("Dropping attestation record {name}: {e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditEventType;
+    use serde_json::json;
+
+    struct Backend;
+
+    #[async_trait]
+    impl Attest for Backend {
+        async fn verify(
+            &self,
+            _: Tee,
+            _: &str,
+            _: &str,
+            _: &str,
+            _: Option<&str>,
+            _: Option<&[u8]>,
+            _: Option<&str>,
+        ) -> anyhow::Result<Verdict> {
+            anyhow::bail!("unused")
+        }
+
+        async fn get_policy(&self, policy_id: &str) -> anyhow::Result<String> {
+            Ok(format!("{policy_id} text"))
+        }
+    }
+
+    #[test]
+    fn test_archive_config() {
+        let config: ArchiveConfig = serde_json::from_value(json!({
+            "type": "S3",
+            "bucket": "records",
+            "region": "eu-west-1",
+            "retention_days": 365,
+        }))
+        .unwrap();
+        assert_eq!(
+            config,
+            ArchiveConfig {
+                store: ArchiveStoreConfig::S3(S3Config {
+                    bucket: "records".into(),
+                    region: "eu-west-1".into(),
+                    endpoint: None,
+                    prefix: String::new(),
+                }),
+                retention_days: Some(365),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = Archive::new(Some(&ArchiveConfig {
+            store: ArchiveStoreConfig::Directory {
+                path: dir.path().to_path_buf(),
+            },
+            retention_days: None,
+        }))
+        .unwrap();
+        assert!(archive.is_enabled());
+
+        let event = AuditEvent::from_peer(AuditEventType::AttestationVerdict, "c".into(), None);
+        let verdict: std::result::Result<Verdict, String> = Ok(Verdict {
+            token: "token".into(),
+            claims: json!({"reference-data": {"svn": [1]}}),
+            policies: vec![PolicyOutcome {
+                policy_id: "tdx".into(),
+                policy_hash: None,
+                matched: true,
+            }],
+        });
+        let record = AttestationRecord::new(&event, Tee::Tdx, "default", "evidence")
+            .session("nonce", Some(b"binding"))
+            .verdict(&verdict);
+        let path = dir.path().join(record.name());
+        archive.archive(record, Arc::new(Backend));
+
+        for _ in 0..50 {
+            if path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let record: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(record["correlation_id"], "c");
+        assert_eq!(record["outcome"], "success");
+        assert_eq!(record["channel_binding"], "YmluZGluZw");
+        assert_eq!(record["reference_data"], json!({"svn": [1]}));
+        assert_eq!(
+            record["policy_texts"],
+            json!({"default": "default text", "tdx": "tdx text"})
+        );
+        assert_eq!(
+            record["token_digest"],
+            "3c469e9d6c5875d37a43f353d4f88e61fcf812c66eee3457465a40b0da4153e0"
+        );
+        assert!(record.get("token").is_none());
+    }
+}
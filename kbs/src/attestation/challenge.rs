@@ -0,0 +1,316 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Challenges of the `/auth` handshake.
+//!
+//! By default a challenge is a random nonce of the configured length and
+//! encoding, along with the configured extra parameters. TEEs that need a
+//! vendor-specific challenge register their own [`ChallengeProvider`].
+
+use anyhow::*;
+use async_trait::async_trait;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use kbs_types::{Challenge, Tee};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use super::AttestationService;
+
+/// Default number of random bytes in a nonce.
+const DEFAULT_NONCE_LENGTH: usize = 32;
+
+/// Nonces shorter than this are not fresh enough.
+const MIN_NONCE_LENGTH: usize = 16;
+
+/// Extra parameter carrying the KBS time when `server_time` is set.
+const SERVER_TIME_PARAM: &str = "server-time";
+
+fn default_nonce_length() -> usize {
+    DEFAULT_NONCE_LENGTH
+}
+
+/// Generates the challenge of a KBS session.
+#[async_trait]
+pub trait ChallengeProvider: Send + Sync {
+    /// Generate the challenge for an attester of `tee`, or of a TEE to be
+    /// detected from its evidence when `None`, with the TEE specific
+    /// `tee_parameters` of its request.
+    async fn generate_challenge(
+        &self,
+        tee: Option<Tee>,
+        tee_parameters: String,
+    ) -> Result<Challenge>;
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NonceEncoding {
+    /// Standard base64 with padding.
+    #[default]
+    Base64,
+
+    /// URL safe base64 without padding.
+    Base64Url,
+
+    /// Lowercase hexadecimal.
+    Hex,
+}
+
+impl NonceEncoding {
+    fn encode(&self, nonce: &[u8]) -> String {
+        match self {
+            NonceEncoding::Base64 => STANDARD.encode(nonce),
+            NonceEncoding::Base64Url => URL_SAFE_NO_PAD.encode(nonce),
+            NonceEncoding::Hex => nonce.iter().fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ChallengeConfig {
+    /// Number of random bytes in a nonce.
+    #[serde(default = "default_nonce_length")]
+    pub nonce_length: usize,
+
+    /// Encoding of the nonce in the challenge.
+    #[serde(default)]
+    pub nonce_encoding: NonceEncoding,
+
+    /// Add the current KBS time (RFC 3339) to the extra parameters, e.g. for
+    /// attesters that check certificate validity against it.
+    #[serde(default)]
+    pub server_time: bool,
+
+    /// Static extra parameters of every challenge, e.g. hints on the
+    /// required evidence format.
+    #[serde(default)]
+    pub extra_params: Map<String, Value>,
+
+    /// Directory the answered nonces are recorded in, shared by the KBS
+    /// replicas so that evidence answering a nonce is accepted by one of
+    /// them only. The nonces are only kept in memory without it.
+    #[serde(default)]
+    pub nonce_store_dir: Option<PathBuf>,
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        Self {
+            nonce_length: DEFAULT_NONCE_LENGTH,
+            nonce_encoding: NonceEncoding::default(),
+            server_time: false,
+            extra_params: Map::new(),
+            nonce_store_dir: None,
+        }
+    }
+}
+
+/// The default challenge: a random nonce and the configured extra parameters.
+pub struct NonceChallenge {
+    config: ChallengeConfig,
+}
+
+impl NonceChallenge {
+    pub fn new(config: ChallengeConfig) -> Result<Self> {
+        if config.nonce_length < MIN_NONCE_LENGTH {
+            bail!("Nonce length must be at least {MIN_NONCE_LENGTH} bytes");
+        }
+        Ok(Self { config })
+    }
+
+    fn extra_params(&self) -> Result<String> {
+        let mut params = self.config.extra_params.clone();
+        if self.config.server_time {
+            let now = OffsetDateTime::now_utc().format(&Rfc3339)?;
+            params.insert(SERVER_TIME_PARAM.to_string(), Value::String(now));
+        }
+
+        // Keep the extra parameters empty, not `{}`, when there are none.
+        if params.is_empty() {
+            return Ok(String::new());
+        }
+        Ok(serde_json::to_string(&params)?)
+    }
+}
+
+#[async_trait]
+impl ChallengeProvider for NonceChallenge {
+    async fn generate_challenge(
+        &self,
+        _tee: Option<Tee>,
+        _tee_parameters: String,
+    ) -> Result<Challenge> {
+        let mut nonce = vec![0; self.config.nonce_length];
+        thread_rng()
+            .try_fill(&mut nonce[..])
+            .map_err(anyhow::Error::from)?;
+
+        Ok(Challenge {
+            nonce: self.config.nonce_encoding.encode(&nonce),
+            extra_params: self.extra_params()?,
+        })
+    }
+}
+
+/// The challenge of the attestation service backend, for TEEs such as IBM SE
+/// whose challenge is built by their verifier.
+#[async_trait]
+impl ChallengeProvider for AttestationService {
+    async fn generate_challenge(
+        &self,
+        tee: Option<Tee>,
+        tee_parameters: String,
+    ) -> Result<Challenge> {
+        let tee = tee.context("the attestation service challenges named TEEs only")?;
+        AttestationService::generate_challenge(self, tee, tee_parameters).await
+    }
+}
+
+/// The challenge providers of the TEEs.
+pub struct Challenges {
+    default: Arc<dyn ChallengeProvider>,
+    providers: Vec<(Tee, Arc<dyn ChallengeProvider>)>,
+}
+
+impl Challenges {
+    /// Challenges of `default` for every TEE without a registered provider.
+    pub fn new(default: Arc<dyn ChallengeProvider>) -> Self {
+        Self {
+            default,
+            providers: Vec::new(),
+        }
+    }
+
+    /// Generate the challenges of `tee` with `provider`.
+    pub fn register(&mut self, tee: Tee, provider: Arc<dyn ChallengeProvider>) {
+        self.providers.retain(|(registered, _)| *registered != tee);
+        self.providers.push((tee, provider));
+    }
+
+    /// Generate the challenge for an attester of `tee`, with the default
+    /// provider when the TEE is to be detected from the evidence.
+    pub async fn generate_challenge(
+        &self,
+        tee: Option<Tee>,
+        tee_parameters: String,
+    ) -> Result<Challenge> {
+        let provider = self
+            .providers
+            .iter()
+            .find(|(registered, _)| Some(*registered) == tee)
+            .map(|(_, provider)| provider)
+            .unwrap_or(&self.default);
+
+        provider.generate_challenge(tee, tee_parameters).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    struct FixedChallenge;
+
+    #[async_trait]
+    impl ChallengeProvider for FixedChallenge {
+        async fn generate_challenge(
+            &self,
+            _tee: Option<Tee>,
+            tee_parameters: String,
+        ) -> Result<Challenge> {
+            Ok(Challenge {
+                nonce: "fixed".into(),
+                extra_params: tee_parameters,
+            })
+        }
+    }
+
+    #[rstest]
+    #[case(NonceEncoding::Base64, 44)]
+    #[case(NonceEncoding::Base64Url, 43)]
+    #[case(NonceEncoding::Hex, 64)]
+    #[tokio::test]
+    async fn test_nonce_encoding(#[case] nonce_encoding: NonceEncoding, #[case] length: usize) {
+        let provider = NonceChallenge::new(ChallengeConfig {
+            nonce_encoding,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let challenge = provider
+            .generate_challenge(Tee::Sample, String::new())
+            .await
+            .unwrap();
+        assert_eq!(challenge.nonce.len(), length);
+        assert_eq!(challenge.extra_params, "");
+    }
+
+    #[tokio::test]
+    async fn test_extra_params() {
+        let mut extra_params = Map::new();
+        extra_params.insert("evidence-format".into(), "v2".into());
+        let provider = NonceChallenge::new(ChallengeConfig {
+            server_time: true,
+            extra_params,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let challenge = provider
+            .generate_challenge(Tee::Sample, String::new())
+            .await
+            .unwrap();
+        let params: Map<String, Value> = serde_json::from_str(&challenge.extra_params).unwrap();
+        assert_eq!(params["evidence-format"], "v2");
+        assert!(params.contains_key(SERVER_TIME_PARAM));
+    }
+
+    #[test]
+    fn test_nonce_length() {
+        assert!(NonceChallenge::new(ChallengeConfig {
+            nonce_length: 8,
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_registered_provider() {
+        let mut challenges = Challenges::new(Arc::new(
+            NonceChallenge::new(ChallengeConfig::default()).unwrap(),
+        ));
+        challenges.register(Tee::Se, Arc::new(FixedChallenge));
+
+        let challenge = challenges
+            .generate_challenge(Some(Tee::Se), "params".into())
+            .await
+            .unwrap();
+        assert_eq!(challenge.nonce, "fixed");
+        assert_eq!(challenge.extra_params, "params");
+
+        let challenge = challenges
+            .generate_challenge(Some(Tee::Tdx), "params".into())
+            .await
+            .unwrap();
+        assert_ne!(challenge.nonce, "fixed");
+
+        let challenge = challenges
+            .generate_challenge(None, "params".into())
+            .await
+            .unwrap();
+        assert_ne!(challenge.nonce, "fixed");
+    }
+}
@@ -2,14 +2,24 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::attestation::{make_nonce, Attest};
+use super::composite::CompositeEvidence;
+use crate::attestation::{make_nonce, session_runtime_data, Attest, Verdict};
 use anyhow::*;
 use async_trait::async_trait;
-use attestation_service::{config::Config as AsConfig, AttestationService, Data, HashAlgorithm};
+use attestation_service::{
+    config::Config as AsConfig, AttestationService, Data, HashAlgorithm, SubmodEvidence,
+};
 use kbs_types::{Attestation, Challenge, Tee};
-use serde_json::json;
+use serde_json::Value;
+use std::collections::HashMap;
 use tokio::sync::RwLock;
 
+/// The attestation policy evidence is verified with when none is given.
+const DEFAULT_POLICY_ID: &str = "default";
+
+/// CoCo AS running in the KBS process. Verifications only take the read
+/// lock, so they run in parallel. The write lock is taken to change the
+/// policies.
 pub struct BuiltInCoCoAs {
     inner: RwLock<AttestationService>,
 }
@@ -24,25 +34,97 @@ impl Attest for BuiltInCoCoAs {
             .await
     }
 
-    async fn verify(&self, tee: Tee, nonce: &str, attestation: &str) -> Result<String> {
-        let attestation: Attestation = serde_json::from_str(attestation)?;
-
-        // TODO: align with the guest-components/kbs-protocol side.
-        let runtime_data_plaintext = json!({"tee-pubkey": attestation.tee_pubkey, "nonce": nonce});
+    async fn list_policies(&self) -> Result<HashMap<String, String>> {
+        self.inner.read().await.list_policies().await
+    }
 
+    async fn get_policy(&self, policy_id: &str) -> Result<String> {
         self.inner
             .read()
             .await
-            .evaluate(
-                attestation.tee_evidence.into_bytes(),
+            .get_policy(policy_id.to_string())
+            .await
+    }
+
+    async fn remove_policy(&self, policy_id: &str) -> Result<()> {
+        self.inner
+            .write()
+            .await
+            .remove_policy(policy_id.to_string())
+            .await
+    }
+
+    async fn policy_capture(&self, request_id: &str) -> Result<Option<Value>> {
+        let captures = self.inner.read().await.policy_capture(request_id);
+        captures
+            .map(serde_json::to_value)
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    async fn verify(
+        &self,
+        tee: Tee,
+        nonce: &str,
+        attestation: &str,
+        policy_id: &str,
+        rvps_namespace: Option<&str>,
+        channel_binding: Option<&[u8]>,
+        prior_token: Option<&str>,
+    ) -> Result<Verdict> {
+        let attestation: Attestation = serde_json::from_str(attestation)?;
+
+        // TODO: align with the guest-components/kbs-protocol side.
+        let runtime_data_plaintext =
+            session_runtime_data(&attestation.tee_pubkey, nonce, channel_binding);
+
+        let (evidence, submods) = split_evidence(&attestation.tee_evidence);
+        let service = self.inner.read().await;
+        // The AS logs with the correlation ID of the request, which it passes
+        // on to its RVPS.
+        let token = service
+            .evaluate_composite(
+                evidence,
                 tee,
+                submods,
                 Some(Data::Structured(runtime_data_plaintext)),
                 HashAlgorithm::Sha384,
                 None,
                 HashAlgorithm::Sha384,
-                vec!["default".into()],
+                vec![policy_id.into()],
+                rvps_namespace,
+                prior_token,
             )
-            .await
+            .await?;
+
+        Verdict::from_coco_token(token)
+    }
+
+    async fn simple_verify(
+        &self,
+        tee: Tee,
+        evidence: &str,
+        policy_id: Option<&str>,
+        rvps_namespace: Option<&str>,
+    ) -> Result<Verdict> {
+        let (evidence, submods) = split_evidence(evidence);
+        let service = self.inner.read().await;
+        let token = service
+            .evaluate_composite(
+                evidence,
+                tee,
+                submods,
+                None,
+                HashAlgorithm::Sha384,
+                None,
+                HashAlgorithm::Sha384,
+                vec![policy_id.unwrap_or(DEFAULT_POLICY_ID).into()],
+                rvps_namespace,
+                None,
+            )
+            .await?;
+
+        Verdict::from_coco_token(token)
     }
 
     async fn generate_challenge(&self, tee: Tee, tee_parameters: String) -> Result<Challenge> {
@@ -64,6 +146,33 @@ impl Attest for BuiltInCoCoAs {
 
         Ok(challenge)
     }
+
+    async fn health_check(&self) -> Result<()> {
+        self.inner.read().await.health_check().await
+    }
+
+    async fn token_jwks(&self) -> Result<String> {
+        self.inner.read().await.token_jwks()
+    }
+
+    async fn oidc_discovery(&self) -> Result<Option<Value>> {
+        Ok(self.inner.read().await.oidc_discovery())
+    }
+}
+
+/// The evidence of the TEE and of the further sources of `tee_evidence`.
+fn split_evidence(tee_evidence: &str) -> (Vec<u8>, Vec<SubmodEvidence>) {
+    let composite = CompositeEvidence::parse(tee_evidence);
+    let submods = composite
+        .submods
+        .into_iter()
+        .map(|(name, submod)| SubmodEvidence {
+            name,
+            tee: submod.tee,
+            evidence: submod.evidence.into_bytes(),
+        })
+        .collect();
+    (composite.evidence.into_bytes(), submods)
 }
 
 impl BuiltInCoCoAs {
@@ -72,3 +181,117 @@ impl BuiltInCoCoAs {
         Ok(Self { inner })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use serde_json::json;
+
+    async fn builtin(dir: &tempfile::TempDir) -> BuiltInCoCoAs {
+        let mut config = AsConfig {
+            work_dir: dir.path().join("as"),
+            ..Default::default()
+        };
+        config.rvps_config.store_config = json!({ "file_path": dir.path().join("rvps") });
+        BuiltInCoCoAs::new(config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_policy_management() {
+        let dir = tempfile::tempdir().unwrap();
+        let builtin = builtin(&dir).await;
+
+        let policy = URL_SAFE_NO_PAD.encode("package policy\ndefault allow = true");
+        builtin.set_policy("test", &policy).await.unwrap();
+        let policies = builtin.list_policies().await.unwrap();
+        assert!(policies.contains_key("default"));
+        assert!(policies.contains_key("test"));
+        assert_eq!(builtin.get_policy("test").await.unwrap(), policy);
+
+        builtin.remove_policy("test").await.unwrap();
+        assert!(!builtin.list_policies().await.unwrap().contains_key("test"));
+        assert!(builtin.get_policy("test").await.is_err());
+        assert!(builtin.remove_policy("test").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_oidc_discovery() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(builtin(&dir).await.oidc_discovery().await.unwrap(), None);
+
+        let mut config = AsConfig {
+            work_dir: dir.path().join("as"),
+            ..Default::default()
+        };
+        config.rvps_config.store_config = json!({ "file_path": dir.path().join("rvps") });
+        config.attestation_token_config = serde_json::from_value(json!({
+            "duration_min": 5,
+            "issuer_name": "https://kbs.example.com",
+            "oidc": { "audience": ["kbs"], "subject_claim": "/tee" },
+        }))
+        .unwrap();
+        let builtin = BuiltInCoCoAs::new(config).await.unwrap();
+
+        let discovery = builtin.oidc_discovery().await.unwrap().unwrap();
+        assert_eq!(discovery["issuer"], "https://kbs.example.com");
+        let jwks: Value = serde_json::from_str(&builtin.token_jwks().await.unwrap()).unwrap();
+        assert_eq!(jwks["keys"][0]["alg"], "RS256");
+
+        let evidence = json!({ "svn": "1" }).to_string();
+        let verdict = builtin
+            .simple_verify(Tee::Sample, &evidence, None, None)
+            .await
+            .unwrap();
+        assert_eq!(verdict.claims["sub"], "sample");
+        assert_eq!(verdict.claims["aud"], "kbs");
+    }
+
+    #[tokio::test]
+    async fn test_simple_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let builtin = builtin(&dir).await;
+        let evidence = json!({ "svn": "1" }).to_string();
+
+        let verdict = builtin
+            .simple_verify(Tee::Sample, &evidence, None, None)
+            .await
+            .unwrap();
+        assert_eq!(verdict.policies.len(), 1);
+        assert_eq!(verdict.policies[0].policy_id, DEFAULT_POLICY_ID);
+
+        let policy = URL_SAFE_NO_PAD.encode("package policy\ndefault allow = false");
+        builtin.set_policy("deny", &policy).await.unwrap();
+        assert!(builtin
+            .simple_verify(Tee::Sample, &evidence, Some("deny"), None)
+            .await
+            .is_err());
+        assert!(builtin
+            .simple_verify(Tee::Sample, "not evidence", None, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_policy_capture() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = AsConfig {
+            work_dir: dir.path().join("as"),
+            policy_captures: 4,
+            ..Default::default()
+        };
+        config.rvps_config.store_config = json!({ "file_path": dir.path().join("rvps") });
+        let builtin = BuiltInCoCoAs::new(config).await.unwrap();
+
+        let evidence = json!({ "svn": "1" }).to_string();
+        let verification = builtin.simple_verify(Tee::Sample, &evidence, None, None);
+        crate::logging::with_correlation_id(Some("req-1".into()), verification)
+            .await
+            .unwrap();
+
+        let captures = builtin.policy_capture("req-1").await.unwrap().unwrap();
+        assert_eq!(captures[0]["policy_id"], DEFAULT_POLICY_ID);
+        assert_eq!(captures[0]["input"]["sample.svn"], "1");
+        assert!(builtin.policy_capture("req-2").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Composite evidence, i.e. the evidence of a TEE along with the evidence of
+//! further sources such as the devices attached to it. A client sends it as
+//! its `tee-evidence`:
+//!
+//! ```json
+//! {
+//!     "evidence": <evidence of the TEE>,
+//!     "submods": {
+//!         "gpu0": { "tee": "<type of the evidence>", "evidence": <evidence> }
+//!     }
+//! }
+//! ```
+//!
+//! The CoCo AS evaluates the evidence of every source on its own and puts
+//! its claims and verdict in the `submods` claim of the token, so that the
+//! claims of different sources can't collide.
+
+use kbs_types::Tee;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// The evidence of a TEE and of the further sources evaluated with it.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CompositeEvidence {
+    /// Evidence of the TEE.
+    #[serde(deserialize_with = "evidence_text")]
+    pub evidence: String,
+
+    /// Evidence of the further sources by the name of their submodule.
+    pub submods: BTreeMap<String, SubmodEvidence>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SubmodEvidence {
+    pub tee: Tee,
+
+    #[serde(deserialize_with = "evidence_text")]
+    pub evidence: String,
+}
+
+impl CompositeEvidence {
+    /// Split the `tee-evidence` sent by a client. Evidence that isn't
+    /// composite is the evidence of the TEE, without further sources.
+    pub(crate) fn parse(tee_evidence: &str) -> Self {
+        serde_json::from_str(tee_evidence).unwrap_or_else(|_| Self {
+            evidence: tee_evidence.to_string(),
+            submods: BTreeMap::new(),
+        })
+    }
+}
+
+/// Evidence given as a string, or as a JSON value standing for its text.
+fn evidence_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(evidence) => evidence,
+        evidence => evidence.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let evidence = json!({"svn": "1", "report_data": "", "init_data": ""}).to_string();
+        assert_eq!(
+            CompositeEvidence::parse(&evidence),
+            CompositeEvidence {
+                evidence: evidence.clone(),
+                submods: BTreeMap::new(),
+            }
+        );
+
+        let composite = json!({
+            "evidence": {"svn": "1", "report_data": "", "init_data": ""},
+            "submods": {"gpu0": {"tee": "sample", "evidence": "raw"}},
+        });
+        let composite = CompositeEvidence::parse(&composite.to_string());
+        assert_eq!(
+            serde_json::from_str::<Value>(&composite.evidence).unwrap(),
+            serde_json::from_str::<Value>(&evidence).unwrap()
+        );
+        assert_eq!(
+            composite.submods["gpu0"],
+            SubmodEvidence {
+                tee: Tee::Sample,
+                evidence: "raw".into(),
+            }
+        );
+    }
+}
@@ -2,7 +2,8 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::attestation::{make_nonce, Attest};
+use super::composite::CompositeEvidence;
+use crate::attestation::{make_nonce, session_runtime_data, Attest, Verdict};
 use anyhow::*;
 use async_trait::async_trait;
 use base64::{
@@ -10,18 +11,27 @@ use base64::{
     Engine,
 };
 use kbs_types::{Attestation, Challenge, Tee};
-use log::info;
+use log::{debug, info, warn};
 use mobc::{Manager, Pool};
 use rand::{thread_rng, Rng};
 use serde::Deserialize;
-use serde_json::json;
 use std::collections::HashMap;
-use tokio::sync::Mutex;
-use tonic::transport::Channel;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+#[cfg(feature = "opentelemetry")]
+use tonic::metadata::MetadataKey;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Code, Status};
+use tonic_health::pb::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
 
 use self::attestation::{
     attestation_request::RuntimeData, attestation_service_client::AttestationServiceClient,
-    AttestationRequest, ChallengeRequest, SetPolicyRequest,
+    AttestationRequest, ChallengeRequest, SetPolicyRequest, SubmodEvidence,
 };
 
 mod attestation {
@@ -30,115 +40,540 @@ mod attestation {
 
 pub const DEFAULT_AS_ADDR: &str = "http://127.0.0.1:50004";
 pub const DEFAULT_POOL_SIZE: u64 = 100;
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 4;
+pub const DEFAULT_RETRY_BACKOFF_MS: u64 = 250;
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 5;
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10;
+
+/// Upper bound of the delay between two attempts of a request.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
 
 pub const COCO_AS_HASH_ALGORITHM: &str = "sha384";
 
+/// Name the AS gRPC service is registered under in the health service.
+const AS_SERVICE_NAME: &str = "attestation.AttestationService";
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct GrpcConfig {
     as_addr: Option<String>,
+
+    /// Addresses of AS instances to balance the requests across, instead of
+    /// the single `as_addr`.
+    as_addrs: Option<Vec<String>>,
+
+    /// Maximum connections to each AS instance.
     pool_size: Option<u64>,
+
+    /// Attempts of a request before a transient error is returned.
+    retry_attempts: Option<u32>,
+
+    /// Delay before the second attempt of a request in milliseconds, doubled
+    /// for every further attempt.
+    retry_backoff_ms: Option<u64>,
+
+    /// Timeout of connecting to the AS in seconds.
+    connect_timeout: Option<u64>,
+
+    /// Seconds between two health checks of every AS instance.
+    health_check_interval: Option<u64>,
+
+    /// TLS of the connection to an `https` AS address.
+    tls: Option<GrpcTlsConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GrpcTlsConfig {
+    /// PEM bundle of the CA certificates trusted to issue the AS certificate.
+    ca_cert: PathBuf,
+
+    /// PEM certificate chain to authenticate KBS to the AS with.
+    client_cert: Option<PathBuf>,
+
+    /// PEM private key of the `client_cert`.
+    client_key: Option<PathBuf>,
+
+    /// Name the AS certificate is verified against, instead of the host of
+    /// the AS address.
+    domain_name: Option<String>,
+}
+
+impl GrpcTlsConfig {
+    async fn client_tls_config(&self) -> Result<ClientTlsConfig> {
+        let ca_cert = tokio::fs::read(&self.ca_cert)
+            .await
+            .context("read AS CA certificate")?;
+        let mut tls_config = ClientTlsConfig::new().ca_certificate(Certificate::from_pem(ca_cert));
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(client_cert), Some(client_key)) => {
+                let identity = Identity::from_pem(
+                    tokio::fs::read(client_cert)
+                        .await
+                        .context("read AS client certificate")?,
+                    tokio::fs::read(client_key)
+                        .await
+                        .context("read AS client private key")?,
+                );
+                tls_config = tls_config.identity(identity);
+            }
+            (None, None) => {}
+            _ => bail!("AS client certificate and private key must be given together"),
+        }
+
+        if let Some(domain_name) = &self.domain_name {
+            tls_config = tls_config.domain_name(domain_name);
+        }
+        Ok(tls_config)
+    }
 }
 
 impl Default for GrpcConfig {
     fn default() -> Self {
         Self {
             as_addr: Some(DEFAULT_AS_ADDR.to_string()),
+            as_addrs: None,
             pool_size: Some(DEFAULT_POOL_SIZE),
+            retry_attempts: Some(DEFAULT_RETRY_ATTEMPTS),
+            retry_backoff_ms: Some(DEFAULT_RETRY_BACKOFF_MS),
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            health_check_interval: Some(DEFAULT_HEALTH_CHECK_INTERVAL),
+            tls: None,
+        }
+    }
+}
+
+/// Retries of the requests to the AS on transient errors, e.g. while the AS
+/// restarts.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay before the attempt following `attempt`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_RETRY_BACKOFF)
+    }
+
+    /// Run `request` until it succeeds, fails with a permanent error, or
+    /// runs out of attempts.
+    async fn run<T, F, Fut>(&self, name: &str, mut request: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RequestError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                std::result::Result::Ok(response) => return Ok(response),
+                Err(e) if e.is_transient() && attempt < self.attempts => {
+                    let backoff = self.backoff(attempt);
+                    warn!("AS {name} attempt {attempt} failed, retrying in {backoff:?}: {e}");
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(anyhow!(e).context(format!("AS {name} failed"))),
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum RequestError {
+    #[error("connect to AS: {0}")]
+    Connect(#[from] mobc::Error<tonic::transport::Error>),
+
+    #[error(transparent)]
+    Status(#[from] Status),
+}
+
+impl RequestError {
+    /// Whether the request may succeed when sent again.
+    fn is_transient(&self) -> bool {
+        match self {
+            RequestError::Connect(_) => true,
+            RequestError::Status(status) => matches!(
+                status.code(),
+                Code::Unavailable | Code::Aborted | Code::ResourceExhausted
+            ),
         }
     }
 }
 
+/// An AS instance.
+struct Backend {
+    as_addr: String,
+    /// Shared by the concurrent requests, each checking out its own client.
+    pool: Pool<GrpcManager>,
+    health: HealthClient<Channel>,
+
+    /// Whether the last health check or request succeeded.
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    async fn new(
+        as_addr: String,
+        pool_size: u64,
+        connect_timeout: Duration,
+        tls: Option<&GrpcTlsConfig>,
+    ) -> Result<Self> {
+        let https = as_addr.starts_with("https://");
+        let mut endpoint = Channel::from_shared(as_addr.clone())
+            .with_context(|| format!("invalid AS address {as_addr}"))?
+            .connect_timeout(connect_timeout);
+        match tls {
+            Some(tls) if https => {
+                endpoint = endpoint
+                    .tls_config(tls.client_tls_config().await?)
+                    .context("AS TLS config")?;
+            }
+            Some(_) => bail!("AS TLS requires an https AS address, not {as_addr}"),
+            None if https => bail!("An https AS address requires the tls configuration"),
+            None => {}
+        }
+        let health = HealthClient::new(endpoint.connect_lazy());
+        let manager = GrpcManager { endpoint };
+        let pool = Pool::builder().max_open(pool_size).build(manager);
+
+        Ok(Self {
+            as_addr,
+            pool,
+            health,
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        let status = self
+            .health
+            .clone()
+            .check(new_request(HealthCheckRequest {
+                service: AS_SERVICE_NAME.to_string(),
+            }))
+            .await
+            .with_context(|| format!("AS {} health check failed", self.as_addr))?
+            .into_inner()
+            .status();
+        if status != ServingStatus::Serving {
+            bail!("AS {} is {}", self.as_addr, status.as_str_name());
+        }
+        Ok(())
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            match healthy {
+                true => info!("AS {} is healthy again", self.as_addr),
+                false => warn!("AS {} is unhealthy, failing over", self.as_addr),
+            }
+        }
+    }
+
+    /// Send a request with a pooled client. A client that failed is dropped
+    /// from the pool, so that the next request connects again.
+    async fn request<T, F, Fut>(&self, request: &F) -> Result<T, RequestError>
+    where
+        F: Fn(AttestationServiceClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let client = self.pool.get().await?;
+        match request((*client).clone()).await {
+            std::result::Result::Ok(response) => std::result::Result::Ok(response.into_inner()),
+            Err(status) => {
+                drop(client.into_inner());
+                Err(RequestError::Status(status))
+            }
+        }
+    }
+}
+
+/// Clients of one or more AS instances. Requests are spread round-robin
+/// across the healthy instances, and a request that fails on one instance
+/// is retried on the next one.
 pub struct GrpcClientPool {
-    pool: Mutex<Pool<GrpcManager>>,
+    backends: Vec<Arc<Backend>>,
+    next: AtomicUsize,
+    retry: RetryPolicy,
 }
 
 impl GrpcClientPool {
     pub async fn new(config: GrpcConfig) -> Result<Self> {
-        let as_addr = config.as_addr.unwrap_or_else(|| {
-            log::info!("Default remote AS address ({DEFAULT_AS_ADDR}) is used");
-            DEFAULT_AS_ADDR.to_string()
-        });
+        let as_addrs = match (config.as_addr, config.as_addrs) {
+            (Some(_), Some(_)) => bail!("Only one of as_addr and as_addrs can be set"),
+            (None, Some(as_addrs)) if as_addrs.is_empty() => bail!("as_addrs is empty"),
+            (None, Some(as_addrs)) => as_addrs,
+            (Some(as_addr), None) => vec![as_addr],
+            (None, None) => {
+                log::info!("Default remote AS address ({DEFAULT_AS_ADDR}) is used");
+                vec![DEFAULT_AS_ADDR.to_string()]
+            }
+        };
 
         let pool_size = config.pool_size.unwrap_or_else(|| {
             log::info!("Default AS connection pool size ({DEFAULT_POOL_SIZE}) is used");
             DEFAULT_POOL_SIZE
         });
 
-        info!("connect to remote AS [{as_addr}] with pool size {pool_size}");
-        let manager = GrpcManager { as_addr };
-        let pool = Mutex::new(Pool::builder().max_open(pool_size).build(manager));
+        let retry = RetryPolicy {
+            attempts: config
+                .retry_attempts
+                .unwrap_or(DEFAULT_RETRY_ATTEMPTS)
+                .max(1),
+            backoff: Duration::from_millis(
+                config.retry_backoff_ms.unwrap_or(DEFAULT_RETRY_BACKOFF_MS),
+            ),
+        };
+        let connect_timeout =
+            Duration::from_secs(config.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT));
+        let health_check_interval = Duration::from_secs(
+            config
+                .health_check_interval
+                .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL)
+                .max(1),
+        );
+
+        let mut backends = Vec::new();
+        for as_addr in as_addrs {
+            info!("connect to remote AS [{as_addr}] with pool size {pool_size}");
+            let backend =
+                Backend::new(as_addr, pool_size, connect_timeout, config.tls.as_ref()).await?;
+            backends.push(Arc::new(backend));
+        }
+
+        if backends.len() > 1 {
+            let backends = backends.iter().map(Arc::downgrade).collect();
+            tokio::spawn(watch_health(backends, health_check_interval));
+        }
+
+        Ok(Self {
+            backends,
+            next: AtomicUsize::new(0),
+            retry,
+        })
+    }
+
+    /// The next AS instance in turn, preferring the healthy ones. All of them
+    /// are tried when none is healthy.
+    fn select(&self) -> &Backend {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.backends.len();
+        (0..count)
+            .map(|i| &self.backends[(start + i) % count])
+            .find(|backend| backend.healthy.load(Ordering::Relaxed))
+            .unwrap_or(&self.backends[start % count])
+    }
+
+    /// Send a request to an AS instance, retrying it on transient errors
+    /// with the next instance.
+    async fn request<T, F, Fut>(&self, name: &str, request: F) -> Result<T>
+    where
+        F: Fn(AttestationServiceClient<Channel>) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        self.retry
+            .run(name, || async {
+                let backend = self.select();
+                let result = backend.request(&request).await;
+                match &result {
+                    Err(e) if e.is_transient() => backend.set_healthy(false),
+                    _ => backend.set_healthy(true),
+                }
+                result
+            })
+            .await
+    }
+}
+
+/// Check the health of the `backends` every `interval` until they are gone.
+async fn watch_health(backends: Vec<Weak<Backend>>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        for backend in &backends {
+            let Some(backend) = backend.upgrade() else {
+                return;
+            };
+            let result = backend.health_check().await;
+            if let Err(e) = &result {
+                debug!("{e:#}");
+            }
+            backend.set_healthy(result.is_ok());
+        }
+    }
+}
+
+/// The name of `tee` in the requests to the AS.
+fn tee_name(tee: Tee) -> Result<String> {
+    Ok(serde_json::to_string(&tee)
+        .context("CoCo AS client: serialize tee type failed.")?
+        .trim_end_matches('"')
+        .trim_start_matches('"')
+        .to_string())
+}
 
-        Ok(Self { pool })
+/// The evidence of the further sources of `composite` in the requests to
+/// the AS.
+fn submods(composite: CompositeEvidence) -> Result<Vec<SubmodEvidence>> {
+    composite
+        .submods
+        .into_iter()
+        .map(|(name, submod)| {
+            Ok(SubmodEvidence {
+                name,
+                tee: tee_name(submod.tee)?,
+                evidence: URL_SAFE_NO_PAD.encode(submod.evidence),
+            })
+        })
+        .collect()
+}
+
+/// Wrap `message` into a request that carries the trace context of the
+/// current span, so that the spans of the remote AS join the KBS trace, and
+/// the correlation ID of the request being served.
+fn new_request<T>(message: T) -> tonic::Request<T> {
+    #[allow(unused_mut)]
+    let mut request = tonic::Request::new(message);
+
+    #[cfg(feature = "opentelemetry")]
+    for (key, value) in crate::telemetry::current_trace_context() {
+        if let (std::result::Result::Ok(key), std::result::Result::Ok(value)) =
+            (MetadataKey::from_bytes(key.as_bytes()), value.parse())
+        {
+            request.metadata_mut().insert(key, value);
+        }
+    }
+
+    if let Some(value) = crate::logging::correlation_id().and_then(|id| id.parse().ok()) {
+        request
+            .metadata_mut()
+            .insert(crate::logging::REQUEST_ID_KEY, value);
     }
+
+    request
 }
 
 #[async_trait]
 impl Attest for GrpcClientPool {
+    #[tracing::instrument(skip_all, fields(policy_id = policy_id))]
     async fn set_policy(&self, policy_id: &str, policy: &str) -> Result<()> {
-        let req = tonic::Request::new(SetPolicyRequest {
+        let message = SetPolicyRequest {
             policy_id: policy_id.to_string(),
             policy: policy.to_string(),
-        });
-
-        let mut client = { self.pool.lock().await.get().await? };
+        };
 
-        client
-            .set_attestation_policy(req)
-            .await
-            .map_err(|e| anyhow!("Set Policy Failed: {:?}", e))?;
+        // Every AS instance evaluates evidence with its own policies.
+        let request = |mut client: AttestationServiceClient<Channel>| {
+            let req = new_request(message.clone());
+            async move { client.set_attestation_policy(req).await }
+        };
+        for backend in &self.backends {
+            self.retry
+                .run("set policy", || backend.request(&request))
+                .await
+                .with_context(|| format!("set policy of AS {}", backend.as_addr))?;
+        }
 
         Ok(())
     }
 
-    async fn verify(&self, tee: Tee, nonce: &str, attestation: &str) -> Result<String> {
+    #[tracing::instrument(skip_all, fields(tee = ?tee))]
+    async fn verify(
+        &self,
+        tee: Tee,
+        nonce: &str,
+        attestation: &str,
+        policy_id: &str,
+        rvps_namespace: Option<&str>,
+        channel_binding: Option<&[u8]>,
+        prior_token: Option<&str>,
+    ) -> Result<Verdict> {
         let attestation: Attestation = serde_json::from_str(attestation)?;
 
         // TODO: align with the guest-components/kbs-protocol side.
-        let runtime_data_plaintext = json!({"tee-pubkey": attestation.tee_pubkey, "nonce": nonce});
-        let runtime_data_plaintext = serde_json::to_string(&runtime_data_plaintext)
-            .context("CoCo AS client: serialize runtime data failed")?;
-
-        let tee = serde_json::to_string(&tee)
-            .context("CoCo AS client: serialize tee type failed.")?
-            .trim_end_matches('"')
-            .trim_start_matches('"')
-            .to_string();
-        let req = tonic::Request::new(AttestationRequest {
-            tee,
-            evidence: URL_SAFE_NO_PAD.encode(attestation.tee_evidence),
+        let runtime_data_plaintext =
+            session_runtime_data(&attestation.tee_pubkey, nonce, channel_binding);
+        let runtime_data_plaintext = canonical_json::to_string(&runtime_data_plaintext);
+
+        let composite = CompositeEvidence::parse(&attestation.tee_evidence);
+        let message = AttestationRequest {
+            tee: tee_name(tee)?,
+            evidence: URL_SAFE_NO_PAD.encode(&composite.evidence),
             runtime_data_hash_algorithm: COCO_AS_HASH_ALGORITHM.into(),
             init_data_hash_algorithm: COCO_AS_HASH_ALGORITHM.into(),
             runtime_data: Some(RuntimeData::StructuredRuntimeData(runtime_data_plaintext)),
             init_data: None,
-            policy_ids: vec!["default".to_string()],
-        });
+            policy_ids: vec![policy_id.to_string()],
+            submods: submods(composite)?,
+            rvps_namespace: rvps_namespace.unwrap_or_default().to_string(),
+            prior_token: prior_token.unwrap_or_default().to_string(),
+        };
 
-        let mut client = { self.pool.lock().await.get().await? };
+        // Evaluating evidence has no side effects on the AS, so a failed
+        // evaluation can be sent again.
+        let token = self
+            .request("attestation evaluation", |mut client| {
+                let req = new_request(message.clone());
+                async move { client.attestation_evaluate(req).await }
+            })
+            .await?
+            .attestation_token;
+
+        Verdict::from_coco_token(token)
+    }
 
-        let token = client
-            .attestation_evaluate(req)
+    #[tracing::instrument(skip_all, fields(tee = ?tee))]
+    async fn simple_verify(
+        &self,
+        tee: Tee,
+        evidence: &str,
+        policy_id: Option<&str>,
+        rvps_namespace: Option<&str>,
+    ) -> Result<Verdict> {
+        // Without policy IDs, the AS evaluates the evidence with its default
+        // policy.
+        let composite = CompositeEvidence::parse(evidence);
+        let message = AttestationRequest {
+            tee: tee_name(tee)?,
+            evidence: URL_SAFE_NO_PAD.encode(&composite.evidence),
+            runtime_data_hash_algorithm: COCO_AS_HASH_ALGORITHM.into(),
+            init_data_hash_algorithm: COCO_AS_HASH_ALGORITHM.into(),
+            runtime_data: None,
+            init_data: None,
+            policy_ids: policy_id.into_iter().map(str::to_string).collect(),
+            submods: submods(composite)?,
+            rvps_namespace: rvps_namespace.unwrap_or_default().to_string(),
+            prior_token: String::new(),
+        };
+
+        let token = self
+            .request("attestation evaluation", |mut client| {
+                let req = new_request(message.clone());
+                async move { client.attestation_evaluate(req).await }
+            })
             .await?
-            .into_inner()
             .attestation_token;
 
-        Ok(token)
+        Verdict::from_coco_token(token)
     }
 
+    #[tracing::instrument(skip_all, fields(tee = ?tee))]
     async fn generate_challenge(&self, tee: Tee, tee_parameters: String) -> Result<Challenge> {
         let nonce = match tee {
             Tee::Se => {
                 let mut inner = HashMap::new();
                 inner.insert(String::from("tee"), String::from("se"));
                 inner.insert(String::from("tee_params"), tee_parameters);
-                let req = tonic::Request::new(ChallengeRequest { inner });
-
-                let mut client = { self.pool.lock().await.get().await? };
+                let message = ChallengeRequest { inner };
 
-                client
-                    .get_attestation_challenge(req)
-                    .await?
-                    .into_inner()
-                    .attestation_challenge
+                self.request("challenge", |mut client| {
+                    let req = new_request(message.clone());
+                    async move { client.get_attestation_challenge(req).await }
+                })
+                .await?
+                .attestation_challenge
             }
             _ => make_nonce().await?,
         };
@@ -150,10 +585,22 @@ impl Attest for GrpcClientPool {
 
         Ok(challenge)
     }
+
+    /// KBS is ready while any AS instance is.
+    async fn health_check(&self) -> Result<()> {
+        let mut errors = Vec::new();
+        for backend in &self.backends {
+            match backend.health_check().await {
+                std::result::Result::Ok(()) => return Ok(()),
+                Err(e) => errors.push(format!("{e:#}")),
+            }
+        }
+        bail!("{}", errors.join("; "))
+    }
 }
 
 pub struct GrpcManager {
-    as_addr: String,
+    endpoint: Endpoint,
 }
 
 #[async_trait]
@@ -162,11 +609,177 @@ impl Manager for GrpcManager {
     type Error = tonic::transport::Error;
 
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        let connection = AttestationServiceClient::connect(self.as_addr.clone()).await?;
-        std::result::Result::Ok(connection)
+        let channel = self.endpoint.connect().await?;
+        std::result::Result::Ok(AttestationServiceClient::new(channel))
     }
 
     async fn check(&self, conn: Self::Connection) -> Result<Self::Connection, Self::Error> {
         std::result::Result::Ok(conn)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    const RETRY: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        backoff: Duration::from_millis(1),
+    };
+
+    #[test]
+    fn test_backoff() {
+        let retry = RetryPolicy {
+            attempts: 10,
+            backoff: Duration::from_millis(250),
+        };
+        assert_eq!(retry.backoff(1), Duration::from_millis(250));
+        assert_eq!(retry.backoff(3), Duration::from_millis(1000));
+        assert_eq!(retry.backoff(9), MAX_RETRY_BACKOFF);
+    }
+
+    async fn attempts(code: Code, succeed_at: u32) -> (bool, u32) {
+        let attempts = AtomicU32::new(0);
+        let result = RETRY
+            .run("test", || async {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                if attempt == succeed_at {
+                    std::result::Result::Ok(())
+                } else {
+                    Err(RequestError::Status(Status::new(code, "test")))
+                }
+            })
+            .await;
+        (result.is_ok(), attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_tls_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca_cert = dir.path().join("ca.pem");
+        std::fs::write(&ca_cert, "").unwrap();
+        let config = |as_addr: &str, client_cert: Option<PathBuf>, tls: bool| GrpcConfig {
+            as_addr: Some(as_addr.to_string()),
+            tls: tls.then(|| GrpcTlsConfig {
+                ca_cert: ca_cert.clone(),
+                client_cert,
+                client_key: None,
+                domain_name: None,
+            }),
+            ..Default::default()
+        };
+
+        assert!(GrpcClientPool::new(config("https://as:50004", None, true))
+            .await
+            .is_ok());
+        for config in [
+            config("http://as:50004", None, true),
+            config("https://as:50004", None, false),
+            config("https://as:50004", Some(ca_cert.clone()), true),
+        ] {
+            assert!(GrpcClientPool::new(config).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_as_addrs() {
+        let config = |as_addr: Option<&str>, as_addrs: Option<Vec<&str>>| GrpcConfig {
+            as_addr: as_addr.map(String::from),
+            as_addrs: as_addrs.map(|addrs| addrs.into_iter().map(String::from).collect()),
+            ..Default::default()
+        };
+
+        let pool = GrpcClientPool::new(config(None, Some(vec!["http://a:1", "http://b:1"])))
+            .await
+            .unwrap();
+        assert_eq!(pool.backends.len(), 2);
+        let pool = GrpcClientPool::new(config(None, None)).await.unwrap();
+        assert_eq!(pool.backends[0].as_addr, DEFAULT_AS_ADDR);
+
+        for config in [
+            config(Some("http://a:1"), Some(vec!["http://b:1"])),
+            config(None, Some(vec![])),
+        ] {
+            assert!(GrpcClientPool::new(config).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select() {
+        let pool = GrpcClientPool::new(GrpcConfig {
+            as_addr: None,
+            as_addrs: Some(vec![
+                "http://a:1".into(),
+                "http://b:1".into(),
+                "http://c:1".into(),
+            ]),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let select = || pool.select().as_addr.clone();
+
+        // Round-robin across the healthy instances.
+        assert_eq!(
+            [select(), select(), select()],
+            ["http://a:1", "http://b:1", "http://c:1"]
+        );
+
+        // Skip the unhealthy ones.
+        pool.backends[1].set_healthy(false);
+        assert_eq!(
+            [select(), select(), select()],
+            ["http://a:1", "http://c:1", "http://c:1"]
+        );
+
+        // Try all of them when none is healthy.
+        pool.backends[0].set_healthy(false);
+        pool.backends[2].set_healthy(false);
+        assert_eq!(
+            [select(), select(), select()],
+            ["http://a:1", "http://b:1", "http://c:1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let as_addr = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        let (_, health_service) = tonic_health::server::health_reporter();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(health_service)
+                .serve_with_incoming(incoming),
+        );
+        let backend = Backend::new(as_addr, 2, Duration::from_secs(5), None)
+            .await
+            .unwrap();
+
+        // Both requests are in flight at once, each with a client of its own.
+        let barrier = tokio::sync::Barrier::new(2);
+        let request = |_client: AttestationServiceClient<Channel>| async {
+            barrier.wait().await;
+            std::result::Result::<_, Status>::Ok(tonic::Response::new(()))
+        };
+        let requests = futures::future::join(backend.request(&request), backend.request(&request));
+        let (a, b) = tokio::time::timeout(Duration::from_secs(5), requests)
+            .await
+            .unwrap();
+        assert!(a.is_ok() && b.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        // Transient errors are retried.
+        assert_eq!(attempts(Code::Unavailable, 3).await, (true, 3));
+        assert_eq!(attempts(Code::Unavailable, 4).await, (false, 3));
+
+        // Permanent errors are not.
+        assert_eq!(attempts(Code::InvalidArgument, 2).await, (false, 1));
+    }
+}
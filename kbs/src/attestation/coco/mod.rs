@@ -3,3 +3,5 @@ pub(crate) mod grpc;
 
 #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
 pub(crate) mod builtin;
+
+pub(crate) mod composite;
@@ -0,0 +1,177 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Detection of the TEE of evidence, for the attesters requesting the `auto`
+//! TEE instead of naming theirs.
+//!
+//! The evidence of the TEEs is told apart by the members of its JSON object
+//! and, for the quotes and tokens in them, by their magic numbers: the
+//! version and TEE type of the header of the DCAP quotes of Intel TDX and
+//! SGX, and the CBOR tag of the Arm CCA token collection. The TEE of
+//! composite evidence is the one of its `evidence`.
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use kbs_types::Tee;
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+/// The `tee` requesting the detection of the TEE.
+pub const AUTO_TEE: &str = "auto";
+
+/// TEE type of the header of the version 4 and 5 DCAP quotes of Intel TDX.
+const TDX_TEE_TYPE: u32 = 0x81;
+
+/// CBOR tag of the Arm CCA attestation token collection.
+const CCA_TOKEN_TAG: [u8; 3] = [0xd9, 0x01, 0x8f];
+
+/// The TEE requested by an attester: a TEE, or the TEE detected from its
+/// evidence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeeSelector {
+    Auto,
+    Tee(Tee),
+}
+
+impl Serialize for TeeSelector {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Self::Auto => serializer.serialize_str(AUTO_TEE),
+            Self::Tee(tee) => tee.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TeeSelector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        if name == AUTO_TEE {
+            return Ok(Self::Auto);
+        }
+        Tee::deserialize(name.into_deserializer()).map(Self::Tee)
+    }
+}
+
+/// Detect the TEE of `tee_evidence`. IBM SE evidence is not detected, as it
+/// answers a challenge of its own.
+pub fn detect_tee(tee_evidence: &str) -> Result<Tee> {
+    let evidence: Value =
+        serde_json::from_str(tee_evidence).context("evidence is not a JSON object")?;
+    let evidence = match (evidence.get("evidence"), evidence.get("submods")) {
+        (Some(Value::String(evidence)), Some(_)) => {
+            serde_json::from_str(evidence).context("evidence is not a JSON object")?
+        }
+        (Some(evidence), Some(_)) => evidence.clone(),
+        _ => evidence,
+    };
+    let Value::Object(evidence) = evidence else {
+        bail!("evidence is not a JSON object");
+    };
+
+    let has = |member: &str| evidence.contains_key(member);
+    if has("svn") {
+        Ok(Tee::Sample)
+    } else if has("system_report") {
+        Ok(Tee::System)
+    } else if has("tpm_quote") && has("td_quote") {
+        Ok(Tee::AzTdxVtpm)
+    } else if has("quote") && has("report") && has("vcek") {
+        Ok(Tee::AzSnpVtpm)
+    } else if has("attestation_report") && has("serial_number") {
+        Ok(Tee::Csv)
+    } else if has("attestation_report") {
+        Ok(Tee::Snp)
+    } else if has("token") {
+        cca_token(&evidence)
+    } else if let Some(quote) = evidence.get("quote").and_then(Value::as_str) {
+        dcap_quote(quote)
+    } else {
+        bail!("unknown evidence format")
+    }
+}
+
+/// The TEE of the base64 encoded DCAP `quote`.
+fn dcap_quote(quote: &str) -> Result<Tee> {
+    let quote = STANDARD
+        .decode(quote)
+        .context("quote is not base64 encoded")?;
+    let (Some(version), Some(tee_type)) = (quote.get(0..2), quote.get(4..8)) else {
+        bail!("quote is too short");
+    };
+    let version = u16::from_le_bytes([version[0], version[1]]);
+    let tee_type = u32::from_le_bytes([tee_type[0], tee_type[1], tee_type[2], tee_type[3]]);
+    match (version, tee_type) {
+        (3, _) | (4 | 5, 0) => Ok(Tee::Sgx),
+        (4 | 5, TDX_TEE_TYPE) => Ok(Tee::Tdx),
+        _ => bail!("unknown quote version {version} and TEE type {tee_type:#x}"),
+    }
+}
+
+/// The TEE of the `token` of `evidence`, an array of its bytes.
+fn cca_token(evidence: &Map<String, Value>) -> Result<Tee> {
+    let token: Vec<u8> =
+        serde_json::from_value(evidence["token"].clone()).context("token is not a byte array")?;
+    if !token.starts_with(&CCA_TOKEN_TAG) {
+        bail!("token is not a CCA token collection");
+    }
+    Ok(Tee::Cca)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    /// Base64 encoded header of a DCAP quote of `version` and `tee_type`.
+    fn quote(version: u16, tee_type: u32) -> String {
+        let mut header = version.to_le_bytes().to_vec();
+        header.extend(2u16.to_le_bytes());
+        header.extend(tee_type.to_le_bytes());
+        header.extend([0; 40]);
+        STANDARD.encode(header)
+    }
+
+    #[rstest]
+    #[case(json!({"svn": "1", "report_data": ""}), Tee::Sample)]
+    #[case(json!({"cc_eventlog": null, "quote": quote(4, 0x81)}), Tee::Tdx)]
+    #[case(json!({"quote": quote(5, 0x81)}), Tee::Tdx)]
+    #[case(json!({"quote": quote(3, 0)}), Tee::Sgx)]
+    #[case(json!({"quote": quote(4, 0)}), Tee::Sgx)]
+    #[case(json!({"attestation_report": {}, "cert_chain": null}), Tee::Snp)]
+    #[case(json!({"attestation_report": {}, "cert_chain": {}, "serial_number": []}), Tee::Csv)]
+    #[case(json!({"tpm_quote": {}, "hcl_report": [], "td_quote": []}), Tee::AzTdxVtpm)]
+    #[case(json!({"quote": {}, "report": [], "vcek": ""}), Tee::AzSnpVtpm)]
+    #[case(json!({"token": [0xd9, 0x01, 0x8f, 0xa2]}), Tee::Cca)]
+    #[case(json!({"system_report": "", "measurements": ""}), Tee::System)]
+    #[case(json!({
+        "evidence": {"quote": quote(4, 0x81)},
+        "submods": {"gpu0": {"tee": "sample", "evidence": {"svn": "1"}}},
+    }), Tee::Tdx)]
+    #[case(json!({
+        "evidence": json!({"svn": "1"}).to_string(),
+        "submods": {},
+    }), Tee::Sample)]
+    fn test_detect_tee(#[case] evidence: Value, #[case] expected: Tee) {
+        assert_eq!(detect_tee(&evidence.to_string()).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case("raw evidence")]
+    #[case(r#"{"quote": "AwA="}"#)]
+    #[case(r#"{"token": [1, 2, 3]}"#)]
+    #[case(r#"{"measurement": "", "cuid": ""}"#)]
+    fn test_undetected_tee(#[case] evidence: &str) {
+        assert!(detect_tee(evidence).is_err());
+    }
+
+    #[rstest]
+    #[case("\"auto\"", TeeSelector::Auto)]
+    #[case("\"tdx\"", TeeSelector::Tee(Tee::Tdx))]
+    fn test_tee_selector(#[case] json: &str, #[case] selector: TeeSelector) {
+        assert_eq!(serde_json::from_str::<TeeSelector>(json).unwrap(), selector);
+        assert_eq!(serde_json::to_string(&selector).unwrap(), json);
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Drift of the attested platforms from their baselines.
+//!
+//! The claims of every successful attestation are flattened, e.g.
+//! `tcb-status.tdx.quote.body.mr_td`, and the attester is grouped with the
+//! other attesters of its platform by some of them, e.g. its `tee`. The first
+//! configuration of the claims seen on a platform becomes its baseline, and
+//! every distinct configuration seen later is recorded with its differences
+//! from the baseline, e.g. a changed firmware SVN or kernel command line, so
+//! that operators can spot unexpected TCB drift across the fleet. Claims that
+//! change on every attestation, like the nonces and the token timestamps, are
+//! left out. Only the latest configurations of a platform are kept, and they
+//! are stored in the background, out of the attestations.
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::{Mutex, Notify};
+
+use crate::crypto;
+
+fn default_platform_claims() -> Vec<String> {
+    vec!["tee".to_string()]
+}
+
+fn default_max_configurations() -> usize {
+    32
+}
+
+fn default_ignored_claims() -> Vec<String> {
+    [
+        "exp",
+        "iat",
+        "nbf",
+        "jti",
+        "iss",
+        "jwk",
+        "evaluation-reports",
+        "customized_claims",
+        "*.report_data",
+        "*.nonce",
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DriftConfig {
+    /// File the baselines and the configurations seen are stored in.
+    pub path: PathBuf,
+
+    /// Flattened claims whose values name the platform of an attester.
+    #[serde(default = "default_platform_claims")]
+    pub platform_claims: Vec<String>,
+
+    /// Flattened claims left out of the configurations, with the claims
+    /// beneath them. A `*.` prefix matches the claim at any depth.
+    #[serde(default = "default_ignored_claims")]
+    pub ignored_claims: Vec<String>,
+
+    /// Most configurations kept per platform besides its baseline. The
+    /// oldest one is dropped for a new one.
+    #[serde(default = "default_max_configurations")]
+    pub max_configurations: usize,
+}
+
+/// A distinct configuration of the claims of a platform.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Configuration {
+    /// SHA-256 digest of the flattened claims.
+    pub digest: String,
+    pub first_seen: String,
+    pub claims: BTreeMap<String, Value>,
+}
+
+/// The configurations seen on a platform.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+struct Platform {
+    /// Digest of the baseline configuration.
+    baseline: String,
+    configurations: Vec<Configuration>,
+}
+
+impl Platform {
+    fn configuration(&self, digest: &str) -> Option<&Configuration> {
+        self.configurations.iter().find(|c| c.digest == digest)
+    }
+}
+
+/// The difference of a claim from the baseline. A claim missing from the
+/// baseline or from the configuration has no value there.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct ClaimDiff {
+    pub claim: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<Value>,
+}
+
+/// A configuration seen on a platform besides its baseline.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct DriftedConfiguration {
+    pub digest: String,
+    pub first_seen: String,
+    pub diff: Vec<ClaimDiff>,
+}
+
+/// The baseline of a platform and the configurations drifted from it.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct PlatformReport {
+    pub baseline: Configuration,
+    pub drifted: Vec<DriftedConfiguration>,
+}
+
+/// Tracks the configurations of the attested platforms. Nothing is tracked
+/// without a configuration.
+#[derive(Default)]
+pub struct Drift {
+    config: Option<DriftConfig>,
+
+    /// Platforms by name.
+    platforms: Arc<Mutex<BTreeMap<String, Platform>>>,
+
+    /// Held while the platforms are stored, so that the file is written by
+    /// one task at a time.
+    writes: Arc<Mutex<()>>,
+
+    /// Wakes the task storing the platforms once they changed.
+    changed: Arc<Notify>,
+}
+
+impl Drift {
+    pub fn new(config: Option<&DriftConfig>) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self::default());
+        };
+        if config.platform_claims.is_empty() {
+            bail!("Drift detection names the platforms by no claim");
+        }
+
+        let platforms = match std::fs::read(&config.path) {
+            Ok(stored) => serde_json::from_slice(&stored)
+                .with_context(|| format!("parse drift baselines {}", config.path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("read drift baselines {}", config.path.display()))
+            }
+        };
+
+        let drift = Self {
+            config: Some(config.clone()),
+            platforms: Arc::new(Mutex::new(platforms)),
+            writes: Arc::default(),
+            changed: Arc::default(),
+        };
+        let (path, platforms, writes, changed) = (
+            config.path.clone(),
+            drift.platforms.clone(),
+            drift.writes.clone(),
+            drift.changed.clone(),
+        );
+        tokio::spawn(async move {
+            loop {
+                changed.notified().await;
+                if let Err(e) = store(&path, &platforms, &writes).await {
+                    warn!("Failed to store the drift baselines: {e:#}");
+                }
+            }
+        });
+
+        Ok(drift)
+    }
+
+    /// Record the configuration of the attestation `claims`, and return its
+    /// differences from the baseline of its platform. The configuration is
+    /// stored later, and failing to store it is only logged.
+    pub async fn track(&self, claims: &Value) -> Vec<ClaimDiff> {
+        let Some(config) = &self.config else {
+            return Vec::new();
+        };
+        let claims: BTreeMap<String, Value> = flatten(claims)
+            .into_iter()
+            .filter(|(claim, _)| !config.ignored_claims.iter().any(|i| ignores(i, claim)))
+            .collect();
+        let platform_name = config
+            .platform_claims
+            .iter()
+            .map(|claim| match claims.get(claim) {
+                Some(Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+                None => "-".to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let digest = match digest(&claims) {
+            Ok(digest) => digest,
+            Err(e) => {
+                warn!("Failed to track the drift of platform {platform_name}: {e:#}");
+                return Vec::new();
+            }
+        };
+
+        let mut platforms = self.platforms.lock().await;
+        let platform = platforms
+            .entry(platform_name.clone())
+            .or_insert_with(|| Platform {
+                baseline: digest.clone(),
+                configurations: Vec::new(),
+            });
+        let seen = platform.configuration(&digest).is_some();
+        if !seen {
+            platform.configurations.push(Configuration {
+                digest: digest.clone(),
+                first_seen: OffsetDateTime::now_utc()
+                    .format(&Rfc3339)
+                    .unwrap_or_default(),
+                claims: claims.clone(),
+            });
+
+            // The oldest configurations drifted from the baseline make room
+            // for the new one.
+            let baseline = &platform.baseline;
+            let mut drifted = platform
+                .configurations
+                .iter()
+                .filter(|c| &c.digest != baseline)
+                .count();
+            platform.configurations.retain(|c| {
+                let dropped = &c.digest != baseline && drifted > config.max_configurations;
+                drifted -= usize::from(dropped);
+                !dropped
+            });
+        }
+
+        let diff = match platform.configuration(&platform.baseline) {
+            Some(baseline) => diff(&baseline.claims, &claims),
+            None => Vec::new(),
+        };
+        if !seen {
+            if !diff.is_empty() {
+                warn!(
+                    "Platform {platform_name} drifted from its baseline in {} claims",
+                    diff.len()
+                );
+            }
+            self.changed.notify_one();
+        }
+        diff
+    }
+
+    /// The baselines of the platforms, with the configurations drifted from
+    /// them.
+    pub async fn report(&self) -> BTreeMap<String, PlatformReport> {
+        let platforms = self.platforms.lock().await;
+        platforms
+            .iter()
+            .filter_map(|(name, platform)| {
+                let baseline = platform.configuration(&platform.baseline)?;
+                let drifted = platform
+                    .configurations
+                    .iter()
+                    .filter(|c| c.digest != baseline.digest)
+                    .map(|c| DriftedConfiguration {
+                        digest: c.digest.clone(),
+                        first_seen: c.first_seen.clone(),
+                        diff: diff(&baseline.claims, &c.claims),
+                    })
+                    .collect();
+                Some((
+                    name.clone(),
+                    PlatformReport {
+                        baseline: baseline.clone(),
+                        drifted,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// Make the configuration `digest` seen on `platform` its baseline.
+    /// Returns whether the platform has such a configuration.
+    pub async fn accept(&self, platform: &str, digest: &str) -> Result<bool> {
+        let Some(config) = &self.config else {
+            bail!("Drift detection is disabled");
+        };
+        {
+            let mut platforms = self.platforms.lock().await;
+            let Some(tracked) = platforms.get_mut(platform) else {
+                return Ok(false);
+            };
+            if tracked.configuration(digest).is_none() {
+                return Ok(false);
+            }
+            tracked.baseline = digest.to_string();
+        }
+        store(&config.path, &self.platforms, &self.writes).await?;
+        Ok(true)
+    }
+}
+
+/// Whether the ignored claim `ignored` matches the flattened claim `claim`.
+fn ignores(ignored: &str, claim: &str) -> bool {
+    let beneath = |claim: &str, name: &str| claim == name || claim.starts_with(&format!("{name}."));
+    match ignored.strip_prefix("*.") {
+        Some(name) => {
+            beneath(claim, name)
+                || claim
+                    .match_indices('.')
+                    .any(|(dot, _)| beneath(&claim[dot + 1..], name))
+        }
+        None => beneath(claim, ignored),
+    }
+}
+
+/// The claims of the objects of `claims`, named by their path joined with
+/// `.`. Arrays are kept as claims.
+fn flatten(claims: &Value) -> BTreeMap<String, Value> {
+    fn flatten_into(flat: &mut BTreeMap<String, Value>, prefix: &str, object: &Map<String, Value>) {
+        for (name, value) in object {
+            let name = match prefix.is_empty() {
+                true => name.clone(),
+                false => format!("{prefix}.{name}"),
+            };
+            match value {
+                Value::Object(object) => flatten_into(flat, &name, object),
+                value => {
+                    flat.insert(name, value.clone());
+                }
+            }
+        }
+    }
+
+    let mut flat = BTreeMap::new();
+    if let Value::Object(object) = claims {
+        flatten_into(&mut flat, "", object);
+    }
+    flat
+}
+
+fn digest(claims: &BTreeMap<String, Value>) -> Result<String> {
+    Ok(hex::encode(crypto::sha256(
+        serde_json::to_string(claims)?.as_bytes(),
+    )?))
+}
+
+/// The claims of `current` that differ from `baseline`.
+fn diff(baseline: &BTreeMap<String, Value>, current: &BTreeMap<String, Value>) -> Vec<ClaimDiff> {
+    let mut claims: Vec<&String> = baseline.keys().chain(current.keys()).collect();
+    claims.sort();
+    claims.dedup();
+    claims
+        .into_iter()
+        .filter(|claim| baseline.get(*claim) != current.get(*claim))
+        .map(|claim| ClaimDiff {
+            claim: claim.clone(),
+            baseline: baseline.get(claim).cloned(),
+            current: current.get(claim).cloned(),
+        })
+        .collect()
+}
+
+/// Write the current `platforms` to the file at `path`, replacing it
+/// atomically. The platforms are only locked while they are serialized.
+async fn store(
+    path: &Path,
+    platforms: &Mutex<BTreeMap<String, Platform>>,
+    writes: &Mutex<()>,
+) -> Result<()> {
+    let _write = writes.lock().await;
+    let stored = serde_json::to_vec(&*platforms.lock().await)?;
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, stored)
+        .await
+        .with_context(|| format!("write {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    #[rstest]
+    #[case("exp", "exp", true)]
+    #[case("customized_claims", "customized_claims.runtime_data.nonce", true)]
+    #[case("customized", "customized_claims.init_data", false)]
+    #[case("*.report_data", "tcb-status.tdx.quote.body.report_data", true)]
+    #[case("*.report_data", "report_data", true)]
+    #[case("*.report_data", "tcb-status.report_data_hash", false)]
+    fn test_ignores(#[case] ignored: &str, #[case] claim: &str, #[case] expected: bool) {
+        assert_eq!(ignores(ignored, claim), expected);
+    }
+
+    fn claims(svn: u64, cmdline: Option<&str>) -> Value {
+        let mut claims = json!({
+            "tee": "tdx",
+            "iat": svn * 1000,
+            "tcb-status": {
+                "tdx.quote.body.tcb_svn": svn,
+                "tdx.quote.body.report_data": format!("{svn}"),
+            },
+        });
+        if let Some(cmdline) = cmdline {
+            claims["tcb-status"]["tdx.ccel.kernel_parameters"] = cmdline.into();
+        }
+        claims
+    }
+
+    #[tokio::test]
+    async fn test_drift() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DriftConfig {
+            path: dir.path().join("drift.json"),
+            platform_claims: default_platform_claims(),
+            ignored_claims: default_ignored_claims(),
+            max_configurations: 1,
+        };
+        let drift = Drift::new(Some(&config)).unwrap();
+
+        assert!(drift.track(&claims(1, Some("quiet"))).await.is_empty());
+        let diff = drift.track(&claims(2, None)).await;
+        assert_eq!(
+            diff,
+            vec![
+                ClaimDiff {
+                    claim: "tcb-status.tdx.ccel.kernel_parameters".into(),
+                    baseline: Some("quiet".into()),
+                    current: None,
+                },
+                ClaimDiff {
+                    claim: "tcb-status.tdx.quote.body.tcb_svn".into(),
+                    baseline: Some(1.into()),
+                    current: Some(2.into()),
+                },
+            ]
+        );
+
+        // The configurations are kept across restarts.
+        store(&config.path, &drift.platforms, &drift.writes)
+            .await
+            .unwrap();
+        let drift = Drift::new(Some(&config)).unwrap();
+        let report = drift.report().await;
+        let drifted = &report["tdx"].drifted;
+        assert_eq!(drifted.len(), 1);
+        assert_eq!(drifted[0].diff, diff);
+
+        assert!(drift.accept("tdx", &drifted[0].digest).await.unwrap());
+        assert!(drift.track(&claims(2, None)).await.is_empty());
+        assert!(!drift.accept("tdx", "unknown").await.unwrap());
+        assert!(!drift.accept("snp", &drifted[0].digest).await.unwrap());
+
+        // The oldest configuration drifted from the baseline is dropped.
+        let baseline = drifted[0].digest.clone();
+        assert!(!drift.track(&claims(1, Some("quiet"))).await.is_empty());
+        assert!(!drift.track(&claims(3, None)).await.is_empty());
+        let report = drift.report().await;
+        assert_eq!(report["tdx"].baseline.digest, baseline);
+        let drifted = &report["tdx"].drifted;
+        assert_eq!(drifted.len(), 1);
+        assert_eq!(drifted[0].diff[0].current, Some(3.into()));
+
+        assert!(Drift::default().track(&claims(1, None)).await.is_empty());
+    }
+}
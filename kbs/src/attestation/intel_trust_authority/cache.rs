@@ -0,0 +1,153 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Caches of the Intel Trust Authority verification results and JWKS, so
+//! that evidence submitted again and the token signing keys don't cost
+//! another round trip to the SaaS verifier.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::attestation::Verdict;
+
+/// Most verification results cached at a time.
+const MAX_CACHED_RESULTS: usize = 4096;
+
+/// Verdicts of successful verifications, keyed by the digest of the
+/// appraised evidence.
+pub(super) struct ResultCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<[u8; 32], (Verdict, Instant)>>,
+}
+
+impl ResultCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// The digest of an attestation request, which covers the nonce and the
+    /// TEE public key through `runtime_data`, and of the Intel Trust
+    /// Authority policies it is appraised against.
+    pub fn key(quote: &str, runtime_data: &str, policy_ids: &[String]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(quote);
+        hasher.update([0]);
+        hasher.update(runtime_data);
+        for policy_id in policy_ids {
+            hasher.update([0]);
+            hasher.update(policy_id);
+        }
+        hasher.finalize().into()
+    }
+
+    pub fn get(&self, key: &[u8; 32]) -> Option<Verdict> {
+        let entries = self.entries.lock().expect("poisoned result cache");
+        entries
+            .get(key)
+            .filter(|(_, expiry)| *expiry > Instant::now())
+            .map(|(verdict, _)| verdict.clone())
+    }
+
+    /// Cache `verdict` for the configured period, but no longer than
+    /// `lifetime`, the time until its token expires.
+    pub fn insert(&self, key: [u8; 32], verdict: Verdict, lifetime: Duration) {
+        let ttl = self.ttl.min(lifetime);
+        if ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("poisoned result cache");
+        if entries.len() >= MAX_CACHED_RESULTS {
+            entries.retain(|_, (_, expiry)| *expiry > now);
+        }
+        if entries.len() < MAX_CACHED_RESULTS {
+            entries.insert(key, (verdict, now + ttl));
+        }
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().expect("poisoned result cache").clear();
+    }
+}
+
+/// Least time between two fetches of the JWKS, so that tokens with unknown
+/// key IDs don't make KBS hammer the JWKS endpoint.
+pub(super) const MIN_CERTS_REFRESH: Duration = Duration::from_secs(60);
+
+/// Whether a JWKS fetched at `fetched` has to be fetched again, because it
+/// is older than `refresh_interval` or lacks the signing key of a token.
+pub(super) fn certs_stale(
+    fetched: Option<Instant>,
+    refresh_interval: Duration,
+    has_key: bool,
+) -> bool {
+    match fetched {
+        None => true,
+        Some(fetched) => {
+            let age = fetched.elapsed();
+            age >= refresh_interval || (!has_key && age >= MIN_CERTS_REFRESH)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verdict(token: &str) -> Verdict {
+        Verdict {
+            token: token.into(),
+            claims: serde_json::Value::Null,
+            policies: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let cache = ResultCache::new(Duration::from_secs(60));
+        let key = ResultCache::key("quote", "runtime data", &[]);
+        assert_ne!(key, ResultCache::key("quote", "other runtime data", &[]));
+        assert_ne!(key, ResultCache::key("quote", "runtime data", &["a".into()]));
+        assert!(cache.get(&key).is_none());
+
+        cache.insert(key, verdict("token"), Duration::from_secs(3600));
+        assert_eq!(cache.get(&key).unwrap().token, "token");
+
+        // Expired tokens are not cached.
+        cache.clear();
+        assert!(cache.get(&key).is_none());
+
+        let key = ResultCache::key("quote", "other runtime data", &[]);
+        cache.insert(key, verdict("token"), Duration::ZERO);
+        assert!(cache.get(&key).is_none());
+
+        let cache = ResultCache::new(Duration::ZERO);
+        assert!(!cache.enabled());
+        cache.insert(key, verdict("token"), Duration::from_secs(3600));
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn test_certs_stale() {
+        let hour = Duration::from_secs(3600);
+        assert!(certs_stale(None, hour, false));
+        assert!(!certs_stale(Some(Instant::now()), hour, true));
+
+        // An unknown key is fetched again, but not more than once a minute.
+        assert!(!certs_stale(Some(Instant::now()), hour, false));
+        let fetched = Instant::now().checked_sub(MIN_CERTS_REFRESH);
+        assert!(certs_stale(fetched, hour, false));
+        assert!(!certs_stale(fetched, hour, true));
+    }
+}
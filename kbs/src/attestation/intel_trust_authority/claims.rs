@@ -0,0 +1,154 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Translation of the claims of Intel Trust Authority tokens into the
+//! `tee` and flattened `tcb-status` claims of the CoCo AS, so that a resource
+//! policy reads the same claims whichever backend verified the evidence.
+
+use kbs_types::Tee;
+use serde_json::{Map, Value};
+
+/// The `tcb-status` claims of the CoCo AS and the Intel Trust Authority
+/// claims of a TDX quote they are taken from.
+const TDX_CLAIMS: &[(&str, &str)] = &[
+    ("tdx.quote.body.mr_td", "tdx_mrtd"),
+    ("tdx.quote.body.rtmr_0", "tdx_rtmr0"),
+    ("tdx.quote.body.rtmr_1", "tdx_rtmr1"),
+    ("tdx.quote.body.rtmr_2", "tdx_rtmr2"),
+    ("tdx.quote.body.rtmr_3", "tdx_rtmr3"),
+    ("tdx.quote.body.mr_seam", "tdx_mrseam"),
+    ("tdx.quote.body.mrsigner_seam", "tdx_mrsignerseam"),
+    ("tdx.quote.body.seam_attributes", "tdx_seam_attributes"),
+    ("tdx.quote.body.td_attributes", "tdx_td_attributes"),
+    ("tdx.quote.body.xfam", "tdx_xfam"),
+    ("tdx.quote.body.tcb_svn", "tdx_tee_tcb_svn"),
+    ("tdx.quote.body.mr_config_id", "tdx_mrconfigid"),
+    ("tdx.quote.body.mr_owner", "tdx_mrowner"),
+    ("tdx.quote.body.mr_owner_config", "tdx_mrownerconfig"),
+    ("report_data", "tdx_report_data"),
+];
+
+/// The `tcb-status` claims of the CoCo AS and the Intel Trust Authority
+/// claims of an SGX quote they are taken from.
+const SGX_CLAIMS: &[(&str, &str)] = &[
+    ("sgx.body.mr_enclave", "sgx_mrenclave"),
+    ("sgx.body.mr_signer", "sgx_mrsigner"),
+    ("sgx.body.config_id", "sgx_config_id"),
+    ("report_data", "sgx_report_data"),
+];
+
+/// Intel Trust Authority reports these as numbers, the CoCo AS as the
+/// little-endian hex of their 16 bits in the quote.
+const SGX_U16_CLAIMS: &[(&str, &str)] = &[
+    ("sgx.body.isv_prod_id", "sgx_isvprodid"),
+    ("sgx.body.isv_svn", "sgx_isvsvn"),
+];
+
+/// Add the `tee` and `tcb-status` claims of the CoCo AS to the `claims` of
+/// an Intel Trust Authority token for evidence of `tee`. The TEE claims are
+/// either top-level or, in newer tokens, nested under the name of the TEE.
+pub(super) fn add_coco_claims(tee: Tee, claims: &mut Value) {
+    let (name, mapping, u16_mapping) = match tee {
+        Tee::Tdx => ("tdx", TDX_CLAIMS, &[][..]),
+        Tee::Sgx => ("sgx", SGX_CLAIMS, SGX_U16_CLAIMS),
+        _ => return,
+    };
+    let source = match &claims[name] {
+        Value::Object(nested) => nested.clone(),
+        _ => claims.as_object().cloned().unwrap_or_default(),
+    };
+
+    let mut tcb_status = Map::new();
+    for (coco, ita) in mapping {
+        if let Some(value) = source.get(*ita) {
+            tcb_status.insert(coco.to_string(), value.clone());
+        }
+    }
+    for (coco, ita) in u16_mapping {
+        if let Some(value) = source.get(*ita).and_then(Value::as_u64) {
+            let value = hex::encode((value as u16).to_le_bytes());
+            tcb_status.insert(coco.to_string(), value.into());
+        }
+    }
+
+    if let Value::Object(claims) = claims {
+        claims.insert("tee".to_string(), name.into());
+        claims.insert("tcb-status".to_string(), tcb_status.into());
+    }
+}
+
+/// Add the `tee` and `tcb-status` claims of the CoCo AS to the `claims` of
+/// a token presented by the client, if they are of an Intel Trust Authority
+/// token. The TEE is taken from its `attester_type` claim, and the claims of
+/// other tokens are left as they are.
+pub(crate) fn add_coco_claims_of_token(claims: &mut Value) {
+    if claims.get("tcb-status").is_some() {
+        return;
+    }
+    let attester_type = claims["attester_type"]
+        .as_str()
+        .or_else(|| claims["tdx"]["attester_type"].as_str())
+        .or_else(|| claims["sgx"]["attester_type"].as_str());
+    let tee = match attester_type {
+        Some("TDX") => Tee::Tdx,
+        Some("SGX") => Tee::Sgx,
+        _ => return,
+    };
+    add_coco_claims(tee, claims);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    #[rstest]
+    #[case(json!({"tdx_mrtd": "aa", "tdx_rtmr0": "bb", "tdx_report_data": "cc"}))]
+    #[case(json!({"tdx": {"tdx_mrtd": "aa", "tdx_rtmr0": "bb", "tdx_report_data": "cc"}}))]
+    fn test_tdx_claims(#[case] mut claims: Value) {
+        add_coco_claims(Tee::Tdx, &mut claims);
+        assert_eq!(claims["tee"], "tdx");
+        assert_eq!(
+            claims["tcb-status"],
+            json!({
+                "tdx.quote.body.mr_td": "aa",
+                "tdx.quote.body.rtmr_0": "bb",
+                "report_data": "cc",
+            })
+        );
+    }
+
+    #[test]
+    fn test_sgx_claims() {
+        let mut claims = json!({
+            "sgx_mrenclave": "aa",
+            "sgx_mrsigner": "bb",
+            "sgx_isvprodid": 1,
+            "sgx_isvsvn": 258,
+        });
+        add_coco_claims(Tee::Sgx, &mut claims);
+        assert_eq!(claims["tee"], "sgx");
+        assert_eq!(
+            claims["tcb-status"],
+            json!({
+                "sgx.body.mr_enclave": "aa",
+                "sgx.body.mr_signer": "bb",
+                "sgx.body.isv_prod_id": "0100",
+                "sgx.body.isv_svn": "0201",
+            })
+        );
+    }
+
+    #[rstest]
+    #[case(json!({"attester_type": "TDX", "tdx_mrtd": "aa"}), Some("tdx"))]
+    #[case(json!({"tdx": {"attester_type": "TDX", "tdx_mrtd": "aa"}}), Some("tdx"))]
+    #[case(json!({"attester_type": "SGX", "sgx_mrenclave": "aa"}), Some("sgx"))]
+    #[case(json!({"tee": "sample", "tcb-status": {}}), Some("sample"))]
+    #[case(json!({"attester_type": "SEV"}), None)]
+    fn test_token_claims(#[case] mut claims: Value, #[case] tee: Option<&str>) {
+        add_coco_claims_of_token(&mut claims);
+        assert_eq!(claims["tee"].as_str(), tee);
+    }
+}
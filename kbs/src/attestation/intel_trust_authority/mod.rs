@@ -2,7 +2,10 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use super::Attest;
+mod cache;
+mod claims;
+
+use super::{session_runtime_data, Attest, PolicyOutcome, Verdict};
 use anyhow::*;
 use async_trait::async_trait;
 use base64::{engine::general_purpose::STANDARD, Engine};
@@ -10,10 +13,20 @@ use jsonwebtoken::{decode, decode_header, jwk, Algorithm, DecodingKey, Validatio
 use kbs_types::{Attestation, Tee};
 use reqwest::header::{ACCEPT, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
 use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+use cache::{certs_stale, ResultCache};
+pub(crate) use claims::add_coco_claims_of_token;
+
+pub const DEFAULT_CERTS_REFRESH_INTERVAL: u64 = 3600;
+
+/// Appraisal endpoint of SGX and TDX quotes, which tells them apart.
+const DEFAULT_APPRAISAL_PATH: &str = "/appraisal/v1/attest";
 
 #[derive(Deserialize, Debug)]
 struct IntelTrustAuthorityTeeEvidence {
@@ -26,6 +39,8 @@ struct IntelTrustAuthorityTeeEvidence {
 struct AttestReqData {
     quote: String,
     runtime_data: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    policy_ids: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -35,7 +50,31 @@ struct AttestRespData {
 
 #[derive(Deserialize, Debug)]
 struct Claims {
+    policy_ids_matched: Option<Vec<serde_json::Value>>,
     policy_ids_unmatched: Option<Vec<serde_json::Value>>,
+    exp: u64,
+}
+
+impl Claims {
+    /// The outcomes of the `{"id": <id>, ...}` policies of the token.
+    fn policies(&self) -> Vec<PolicyOutcome> {
+        let outcomes = |policies: &Option<Vec<serde_json::Value>>, matched| {
+            policies
+                .iter()
+                .flatten()
+                .filter_map(move |policy| {
+                    Some(PolicyOutcome {
+                        policy_id: policy["id"].as_str()?.to_string(),
+                        policy_hash: policy["hash"].as_str().map(str::to_string),
+                        matched,
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut policies = outcomes(&self.policy_ids_matched, true);
+        policies.extend(outcomes(&self.policy_ids_unmatched, false));
+        policies
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -47,21 +86,81 @@ struct ErrorResponse {
 pub struct IntelTrustAuthorityConfig {
     pub base_url: String,
     pub api_key: String,
-    pub certs_file: String,
+
+    /// JWKS file with the token signing keys.
+    pub certs_file: Option<String>,
+
+    /// URL of the JWKS with the token signing keys, fetched instead of
+    /// reading `certs_file`.
+    pub certs_url: Option<String>,
+
+    /// Seconds a JWKS fetched from `certs_url` is used for.
+    pub certs_refresh_interval: Option<u64>,
+
+    /// Seconds the token of a verification is reused for the same evidence.
+    /// Verifications are not cached by default.
+    pub result_cache_ttl: Option<u64>,
+
+    /// URL of the HTTP(S) proxy Intel Trust Authority is reached through.
+    pub proxy: Option<String>,
+
+    /// PEM bundle of CA certificates trusted on top of the system ones, e.g.
+    /// the CA of a TLS intercepting proxy.
+    pub ca_certs: Option<String>,
+
     pub allow_unmatched_policy: Option<bool>,
+
+    /// Intel Trust Authority policies evidence is appraised against, by the
+    /// ID of the KBS attestation policy it is verified with.
+    #[serde(default)]
+    pub policy_ids: HashMap<String, Vec<String>>,
+
+    /// Path of the appraisal endpoint of SGX quotes.
+    pub sgx_appraisal_path: Option<String>,
+
+    /// Path of the appraisal endpoint of TDX quotes.
+    pub tdx_appraisal_path: Option<String>,
+}
+
+struct Certs {
+    set: jwk::JwkSet,
+
+    /// When the JWKS was fetched from `certs_url`.
+    fetched: Option<Instant>,
 }
 
 pub struct IntelTrustAuthority {
     config: IntelTrustAuthorityConfig,
-    certs: jwk::JwkSet,
+    client: reqwest::Client,
+    certs: RwLock<Certs>,
+    results: ResultCache,
 }
 
 #[async_trait]
 impl Attest for IntelTrustAuthority {
-    async fn verify(&self, tee: Tee, nonce: &str, attestation: &str) -> Result<String> {
+    fn flush_cache(&self) {
+        self.results.clear();
+    }
+
+    #[tracing::instrument(skip_all, fields(tee = ?tee))]
+    async fn verify(
+        &self,
+        tee: Tee,
+        nonce: &str,
+        attestation: &str,
+        policy_id: &str,
+        rvps_namespace: Option<&str>,
+        channel_binding: Option<&[u8]>,
+        _prior_token: Option<&str>,
+    ) -> Result<Verdict> {
         if tee != Tee::Tdx && tee != Tee::Sgx {
             bail!("Intel Trust Authority: TEE {tee:?} is not supported.");
         }
+        // Intel Trust Authority has no reference values of KBS, so it can't
+        // check the attesters of a tenant against the ones of its namespace.
+        if let Some(rvps_namespace) = rvps_namespace.filter(|namespace| !namespace.is_empty()) {
+            bail!("Intel Trust Authority: RVPS namespace {rvps_namespace} is not supported.");
+        }
         // get quote
         let attestation = serde_json::from_str::<Attestation>(attestation)
             .map_err(|e| anyhow!("Deserialize Attestation failed: {:?}", e))?;
@@ -69,26 +168,42 @@ impl Attest for IntelTrustAuthority {
             serde_json::from_str::<IntelTrustAuthorityTeeEvidence>(&attestation.tee_evidence)
                 .map_err(|e| anyhow!("Deserialize supported TEE Evidence failed: {:?}", e))?;
 
-        let runtime_data = json!({
-            "tee-pubkey": attestation.tee_pubkey,
-            "nonce": nonce,
-        })
-        .to_string();
+        let runtime_data = canonical_json::to_string(&session_runtime_data(
+            &attestation.tee_pubkey,
+            nonce,
+            channel_binding,
+        ));
 
         // construct attest request data
         let req_data = AttestReqData {
             quote: evidence.quote,
             runtime_data: STANDARD.encode(runtime_data),
+            policy_ids: self
+                .config
+                .policy_ids
+                .get(policy_id)
+                .cloned()
+                .unwrap_or_default(),
         };
 
+        let cache_key = ResultCache::key(
+            &req_data.quote,
+            &req_data.runtime_data,
+            &req_data.policy_ids,
+        );
+        if let Some(verdict) = self.results.get(&cache_key) {
+            log::debug!("reuse cached attestation result");
+            return Ok(verdict);
+        }
+
         let attest_req_body = serde_json::to_string(&req_data)
             .map_err(|e| anyhow!("Serialize attestation request body failed: {:?}", e))?;
 
         // send attest request
         log::info!("post attestation request ...");
-        let client = reqwest::Client::new();
-        let resp = client
-            .post(format!("{}/appraisal/v1/attest", &self.config.base_url))
+        let resp = self
+            .client
+            .post(format!("{}{}", &self.config.base_url, self.appraisal_path(tee)))
             .header(CONTENT_TYPE, "application/json")
             .header(ACCEPT, "application/json")
             .header("x-api-key", &self.config.api_key)
@@ -122,7 +237,7 @@ impl Attest for IntelTrustAuthority {
         log::debug!("token={}", &resp_data.token);
 
         // find jwk
-        let key = self.certs.find(&kid).ok_or(anyhow!("Find jwk failed"))?;
+        let key = self.jwk(&kid).await?;
         let alg = key
             .common
             .key_algorithm
@@ -132,29 +247,275 @@ impl Attest for IntelTrustAuthority {
         let alg = Algorithm::from_str(alg.as_str())?;
         // verify and decode token
         let dkey = DecodingKey::from_jwk(&key)?;
-        let token = decode::<Claims>(&resp_data.token, &dkey, &Validation::new(alg))
+        let token = decode::<serde_json::Value>(&resp_data.token, &dkey, &Validation::new(alg))
             .map_err(|e| anyhow!("Decode token failed: {:?}", e))?;
+        let claims = serde_json::from_value::<Claims>(token.claims.clone())
+            .map_err(|e| anyhow!("Deserialize token claims failed: {:?}", e))?;
 
         // check unmatched policy
         let allow = self.config.allow_unmatched_policy.unwrap_or(false);
-        if !allow && token.claims.policy_ids_unmatched.is_some() {
+        if !allow && claims.policy_ids_unmatched.is_some() {
             bail!("Evidence doesn't match policy");
         }
 
-        Ok(resp_data.token.clone())
+        let mut token_claims = token.claims;
+        claims::add_coco_claims(tee, &mut token_claims);
+        let verdict = Verdict {
+            token: resp_data.token,
+            claims: token_claims,
+            policies: claims.policies(),
+        };
+
+        if self.results.enabled() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let lifetime = Duration::from_secs(claims.exp.saturating_sub(now));
+            self.results.insert(cache_key, verdict.clone(), lifetime);
+        }
+
+        Ok(verdict)
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        let status = self
+            .client
+            .get(format!("{}/appraisal/v1/nonce", &self.config.base_url))
+            .header(ACCEPT, "application/json")
+            .header("x-api-key", &self.config.api_key)
+            .send()
+            .await
+            .map_err(|e| anyhow!("Intel Trust Authority is unreachable: {:?}", e))?
+            .status();
+        if !status.is_success() {
+            bail!("Intel Trust Authority returned {status}");
+        }
+        Ok(())
     }
 }
 
 impl IntelTrustAuthority {
     pub fn new(config: IntelTrustAuthorityConfig) -> Result<Self> {
-        let file = File::open(&config.certs_file)
-            .map_err(|e| anyhow!("Open certs file failed: {:?}", e))?;
-        let reader = BufReader::new(file);
+        let set = match (&config.certs_file, &config.certs_url) {
+            (Some(certs_file), None) => {
+                let file = File::open(certs_file)
+                    .map_err(|e| anyhow!("Open certs file failed: {:?}", e))?;
+                let reader = BufReader::new(file);
+                serde_json::from_reader(reader)
+                    .map_err(|e| anyhow!("Deserialize certs failed: {:?}", e))?
+            }
+            // Fetched on the first verification.
+            (None, Some(_)) => jwk::JwkSet { keys: Vec::new() },
+            _ => bail!("Exactly one of certs_file and certs_url must be set"),
+        };
+
+        let results = ResultCache::new(Duration::from_secs(
+            config.result_cache_ttl.unwrap_or_default(),
+        ));
 
         Ok(Self {
-            config: config.clone(),
-            certs: serde_json::from_reader(reader)
-                .map_err(|e| anyhow!("Deserialize certs failed: {:?}", e))?,
+            client: http_client(&config)?,
+            config,
+            certs: RwLock::new(Certs { set, fetched: None }),
+            results,
         })
     }
+
+    fn appraisal_path(&self, tee: Tee) -> &str {
+        let path = match tee {
+            Tee::Sgx => &self.config.sgx_appraisal_path,
+            _ => &self.config.tdx_appraisal_path,
+        };
+        path.as_deref().unwrap_or(DEFAULT_APPRAISAL_PATH)
+    }
+
+    /// The token signing key `kid`, fetching the JWKS again when it is
+    /// stale or lacks the key.
+    async fn jwk(&self, kid: &str) -> Result<jwk::Jwk> {
+        let Some(certs_url) = &self.config.certs_url else {
+            let certs = self.certs.read().await;
+            return certs
+                .set
+                .find(kid)
+                .cloned()
+                .ok_or(anyhow!("Find jwk failed"));
+        };
+        let refresh_interval = Duration::from_secs(
+            self.config
+                .certs_refresh_interval
+                .unwrap_or(DEFAULT_CERTS_REFRESH_INTERVAL),
+        );
+
+        {
+            let certs = self.certs.read().await;
+            let key = certs.set.find(kid);
+            if !certs_stale(certs.fetched, refresh_interval, key.is_some()) {
+                return key.cloned().ok_or(anyhow!("Find jwk failed"));
+            }
+        }
+
+        let mut certs = self.certs.write().await;
+        // Another request may have fetched it meanwhile.
+        let has_key = certs.set.find(kid).is_some();
+        if certs_stale(certs.fetched, refresh_interval, has_key) {
+            match self.fetch_certs(certs_url).await {
+                std::result::Result::Ok(set) => {
+                    *certs = Certs {
+                        set,
+                        fetched: Some(Instant::now()),
+                    }
+                }
+                Err(e) if has_key => log::warn!("{e:#}, using the previous JWKS"),
+                Err(e) => return Err(e),
+            }
+        }
+
+        certs
+            .set
+            .find(kid)
+            .cloned()
+            .ok_or(anyhow!("Find jwk failed"))
+    }
+
+    async fn fetch_certs(&self, certs_url: &str) -> Result<jwk::JwkSet> {
+        log::info!("fetch Intel Trust Authority JWKS from {certs_url}");
+        self.client
+            .get(certs_url)
+            .header(ACCEPT, "application/json")
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|e| anyhow!("Fetch certs failed: {:?}", e))?
+            .json::<jwk::JwkSet>()
+            .await
+            .map_err(|e| anyhow!("Deserialize certs failed: {:?}", e))
+    }
+}
+
+fn http_client(config: &IntelTrustAuthorityConfig) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = &config.proxy {
+        let proxy =
+            reqwest::Proxy::all(proxy).map_err(|e| anyhow!("Invalid proxy {proxy}: {:?}", e))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(ca_certs) = &config.ca_certs {
+        let pem = std::fs::read(ca_certs)
+            .map_err(|e| anyhow!("Read CA certificates {ca_certs} failed: {:?}", e))?;
+        let certs = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| anyhow!("Parse CA certificates {ca_certs} failed: {:?}", e))?;
+        if certs.is_empty() {
+            bail!("No CA certificate in {ca_certs}");
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    builder
+        .build()
+        .map_err(|e| anyhow!("Build Intel Trust Authority http client failed: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_policies() {
+        let claims: Claims = serde_json::from_value(json!({
+            "policy_ids_matched": [{"id": "a", "version": "v1"}],
+            "policy_ids_unmatched": [{"id": "b", "hash": "hash"}],
+            "exp": 0,
+        }))
+        .unwrap();
+        assert_eq!(
+            claims.policies(),
+            vec![
+                PolicyOutcome {
+                    policy_id: "a".into(),
+                    policy_hash: None,
+                    matched: true,
+                },
+                PolicyOutcome {
+                    policy_id: "b".into(),
+                    policy_hash: Some("hash".into()),
+                    matched: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_certs_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let certs_file = dir.path().join("certs.json");
+        std::fs::write(&certs_file, r#"{"keys": []}"#).unwrap();
+        let config = |certs_file: Option<&std::path::Path>, certs_url: Option<&str>| {
+            IntelTrustAuthorityConfig {
+                base_url: "https://api.trustauthority.intel.com".into(),
+                api_key: "key".into(),
+                certs_file: certs_file.map(|path| path.to_string_lossy().to_string()),
+                certs_url: certs_url.map(String::from),
+                certs_refresh_interval: None,
+                result_cache_ttl: Some(60),
+                proxy: None,
+                ca_certs: None,
+                allow_unmatched_policy: None,
+                policy_ids: HashMap::new(),
+                sgx_appraisal_path: None,
+                tdx_appraisal_path: None,
+            }
+        };
+
+        let certs_url = "https://portal.trustauthority.intel.com/certs";
+        assert!(IntelTrustAuthority::new(config(Some(&certs_file), None)).is_ok());
+        assert!(IntelTrustAuthority::new(config(None, Some(certs_url))).is_ok());
+        assert!(IntelTrustAuthority::new(config(Some(&certs_file), Some(certs_url))).is_err());
+        assert!(IntelTrustAuthority::new(config(None, None)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_client() {
+        use tokio::io::AsyncReadExt;
+
+        let mut config: IntelTrustAuthorityConfig = serde_json::from_value(json!({
+            "base_url": "https://api.trustauthority.intel.com",
+            "api_key": "key",
+        }))
+        .unwrap();
+        config.ca_certs = Some(
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/../deps/verifier/src/snp/milan_ask_ark_asvk.pem"
+            )
+            .into(),
+        );
+        assert!(http_client(&config).is_ok());
+        config.ca_certs = Some(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml").into());
+        assert!(http_client(&config).is_err());
+        config.ca_certs = Some("/nonexistent/ca.pem".into());
+        assert!(http_client(&config).is_err());
+
+        config.ca_certs = None;
+        config.proxy = Some("not a proxy".into());
+        assert!(http_client(&config).is_err());
+
+        // The requests go through the proxy.
+        let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        config.proxy = Some(format!("http://{}", proxy.local_addr().unwrap()));
+        let client = http_client(&config).unwrap();
+        let request = tokio::spawn(async move {
+            client
+                .get("http://trustauthority.invalid/certs")
+                .send()
+                .await
+        });
+        let (mut stream, _) = proxy.accept().await.unwrap();
+        let mut buffer = vec![0; 1024];
+        let read = stream.read(&mut buffer).await.unwrap();
+        assert!(String::from_utf8_lossy(&buffer[..read])
+            .starts_with("GET http://trustauthority.invalid/certs HTTP/1.1"));
+        drop((stream, proxy));
+        assert!(request.await.unwrap().is_err());
+    }
 }
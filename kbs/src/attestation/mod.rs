@@ -4,28 +4,56 @@
 
 use anyhow::*;
 use async_trait::async_trait;
-#[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
-use attestation_service::config::Config as AsConfig;
-use base64::{engine::general_purpose::STANDARD, Engine};
-#[cfg(feature = "coco-as-grpc")]
-use coco::grpc::*;
-#[cfg(feature = "intel-trust-authority-as")]
-use intel_trust_authority::*;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
 use kbs_types::{Challenge, Tee};
 use rand::{thread_rng, Rng};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[cfg(not(feature = "intel-trust-authority-as"))]
-pub const AS_TOKEN_TEE_PUBKEY_PATH: &str = "/customized_claims/runtime_data/tee-pubkey";
-#[cfg(feature = "intel-trust-authority-as")]
-pub const AS_TOKEN_TEE_PUBKEY_PATH: &str = "/attester_runtime_data/tee-pubkey";
+use crate::audit::AuditEvent;
+use crate::config::KbsConfig;
+
+/// Durable records of the attestations
+pub mod archive;
+use archive::{Archive, AttestationRecord};
+
+/// Challenges of the attestation handshake
+pub mod challenge;
 
 #[cfg(feature = "coco-as")]
 #[allow(missing_docs)]
 pub mod coco;
 
+/// Detection of the TEE of evidence
+pub mod detect;
+
+/// Drift of the attested platforms from their baselines
+pub mod drift;
+use drift::{ClaimDiff, Drift};
+
 #[cfg(feature = "intel-trust-authority-as")]
 pub mod intel_trust_authority;
 
+/// Stream of the attestation verdicts
+pub mod publisher;
+use publisher::{Publisher, VerdictKind};
+
+mod registry;
+pub use registry::{
+    AttestationRoute, BackendFuture, BackendRegistry, BUILTIN_BACKEND, COCO_GRPC_BACKEND,
+    ITA_BACKEND,
+};
+
+mod verdict;
+pub use verdict::{token_claims, PolicyOutcome, Verdict};
+
 /// Number of bytes in a nonce.
 const NONCE_SIZE_BYTES: usize = 32;
 
@@ -40,6 +68,22 @@ pub async fn make_nonce() -> Result<String> {
     Ok(STANDARD.encode(&nonce))
 }
 
+/// The runtime data the evidence of a KBS session is expected to bind: the
+/// TEE public key of the attester, the nonce of the challenge and, when
+/// present, the base64url encoded TLS channel binding of the connection the
+/// evidence is sent over as `tls-exporter`.
+pub fn session_runtime_data(
+    tee_pubkey: &impl Serialize,
+    nonce: &str,
+    channel_binding: Option<&[u8]>,
+) -> Value {
+    let mut runtime_data = json!({"tee-pubkey": tee_pubkey, "nonce": nonce});
+    if let Some(channel_binding) = channel_binding {
+        runtime_data["tls-exporter"] = URL_SAFE_NO_PAD.encode(channel_binding).into();
+    }
+    runtime_data
+}
+
 /// Interface for Attestation Services.
 ///
 /// Attestation Service implementations should implement this interface.
@@ -50,9 +94,54 @@ pub trait Attest: Send + Sync {
         Err(anyhow!("Set Policy API is unimplemented"))
     }
 
-    /// Verify Attestation Evidence
-    /// Return Attestation Results Token
-    async fn verify(&self, tee: Tee, nonce: &str, attestation: &str) -> Result<String>;
+    /// List Attestation Policies as a `policy-id` -> `policy hash` map
+    async fn list_policies(&self) -> Result<HashMap<String, String>> {
+        Err(anyhow!("List Policies API is unimplemented"))
+    }
+
+    /// Get the base64 encoded Attestation Policy `policy_id`
+    async fn get_policy(&self, _policy_id: &str) -> Result<String> {
+        Err(anyhow!("Get Policy API is unimplemented"))
+    }
+
+    /// Remove Attestation Policy
+    async fn remove_policy(&self, _policy_id: &str) -> Result<()> {
+        Err(anyhow!("Remove Policy API is unimplemented"))
+    }
+
+    /// Verify Attestation Evidence with the attestation policy `policy_id`
+    /// and the reference values of the RVPS namespace `rvps_namespace`, or
+    /// of the default one
+    /// The evidence must bind the [`session_runtime_data`] of `nonce` and
+    /// `channel_binding`
+    /// The token `prior_token` of the prior attestation of the attester, if
+    /// any, is given to the policy as context
+    /// Return the Attestation Results Token with its claims and the outcome
+    /// of every evaluated policy
+    async fn verify(
+        &self,
+        tee: Tee,
+        nonce: &str,
+        attestation: &str,
+        policy_id: &str,
+        rvps_namespace: Option<&str>,
+        channel_binding: Option<&[u8]>,
+        prior_token: Option<&str>,
+    ) -> Result<Verdict>;
+
+    /// Verify the TEE evidence `evidence` on its own, without the nonce and
+    /// TEE public key of a KBS session, with the attestation policy
+    /// `policy_id` or the default one of the attestation service, and the
+    /// reference values of the RVPS namespace `rvps_namespace`
+    async fn simple_verify(
+        &self,
+        _tee: Tee,
+        _evidence: &str,
+        _policy_id: Option<&str>,
+        _rvps_namespace: Option<&str>,
+    ) -> Result<Verdict> {
+        Err(anyhow!("Simple Verify API is unimplemented"))
+    }
 
     /// generate the Challenge to pass to attester based on Tee and nonce
     async fn generate_challenge(&self, _tee: Tee, _tee_parameters: String) -> Result<Challenge> {
@@ -63,85 +152,368 @@ pub trait Attest: Send + Sync {
             extra_params: String::new(),
         })
     }
+
+    /// Check that the Attestation Service is able to serve requests
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Drop the cached verdicts, made with attestation policies that have
+    /// changed since
+    fn flush_cache(&self) {}
+
+    /// Get the policy evaluations captured for the request with the
+    /// correlation ID `request_id`, if the backend captures them
+    async fn policy_capture(&self, _request_id: &str) -> Result<Option<Value>> {
+        Ok(None)
+    }
+
+    /// Get the public keys verifying the Attestation Results Tokens, in JWKS
+    /// format
+    async fn token_jwks(&self) -> Result<String> {
+        Err(anyhow!("Token JWKS API is unimplemented"))
+    }
+
+    /// Get the OIDC discovery document of the Attestation Results Token
+    /// issuer, if the tokens are OIDC ID tokens
+    async fn oidc_discovery(&self) -> Result<Option<Value>> {
+        Ok(None)
+    }
 }
 
 /// Attestation Service
-pub enum AttestationService {
-    #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
-    CoCoASBuiltIn(coco::builtin::BuiltInCoCoAs),
+///
+/// Evidence is verified by the backend its TEE is routed to, or by the
+/// default backend. Attestation policies are managed on the default backend.
+pub struct AttestationService {
+    backend: Arc<dyn Attest>,
+
+    /// Backends of the TEEs routed away from `backend`.
+    routes: Vec<(Tee, Arc<dyn Attest>)>,
+
+    /// Whether evidence must be bound to the TLS channel it is sent over.
+    channel_binding: bool,
 
-    #[cfg(feature = "coco-as-grpc")]
-    CoCoASgRPC(GrpcClientPool),
+    /// Whether the clients have to attest again when the attestation
+    /// policies change.
+    reattest_on_policy_change: bool,
 
-    #[cfg(feature = "intel-trust-authority-as")]
-    IntelTA(IntelTrustAuthority),
+    /// Time between the checks of the unexpired tokens, if they are checked
+    /// periodically.
+    reattestation_check_interval: Option<Duration>,
+
+    /// Whether the TEE of the attesters requesting the `auto` TEE is
+    /// detected from their evidence.
+    tee_detection: bool,
+
+    /// Whether the evidence of the sample attester is accepted.
+    insecure_dev_mode: bool,
+
+    /// Archive of the attestation records.
+    archive: Archive,
+
+    /// Stream of the attestation verdicts.
+    publisher: Publisher,
+
+    /// Configurations of the attested platforms.
+    drift: Drift,
 }
 
 impl AttestationService {
-    /// Create and initialize AttestationService.
-    #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
-    pub async fn new(config: AsConfig) -> Result<Self> {
-        let built_in_as = coco::builtin::BuiltInCoCoAs::new(config).await?;
-        Ok(Self::CoCoASBuiltIn(built_in_as))
-    }
-
-    /// Create and initialize AttestationService.
-    #[cfg(feature = "coco-as-grpc")]
-    pub async fn new(config: GrpcConfig) -> Result<Self> {
-        let pool = GrpcClientPool::new(config).await?;
-        Ok(Self::CoCoASgRPC(pool))
-    }
-
-    /// Create and initialize AttestationService.
-    #[cfg(feature = "intel-trust-authority-as")]
-    pub fn new(config: IntelTrustAuthorityConfig) -> Result<Self> {
-        let ta_client = intel_trust_authority::IntelTrustAuthority::new(config)?;
-        Ok(Self::IntelTA(ta_client))
-    }
-
-    pub async fn verify(&self, tee: Tee, nonce: &str, attestation: &str) -> Result<String> {
-        match self {
-            #[cfg(feature = "coco-as-grpc")]
-            AttestationService::CoCoASgRPC(inner) => inner.verify(tee, nonce, attestation).await,
-            #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
-            AttestationService::CoCoASBuiltIn(inner) => inner.verify(tee, nonce, attestation).await,
-            #[cfg(feature = "intel-trust-authority-as")]
-            AttestationService::IntelTA(inner) => inner.verify(tee, nonce, attestation).await,
+    /// Create and initialize the attestation backends `config` picks among
+    /// the ones of `registry`.
+    pub async fn new(config: &KbsConfig, registry: &BackendRegistry) -> Result<Self> {
+        let name = registry.backend_name(config)?;
+        log::info!("Using attestation backend {name}");
+        if config.insecure_dev_mode {
+            log::warn!(
+                "INSECURE DEV MODE: evidence of the sample attester is accepted, \
+                 any client can attest and be released resources without a TEE"
+            );
+        }
+
+        let mut backends = BTreeMap::new();
+        backends.insert(name.to_string(), registry.create(name, config).await?);
+
+        let mut routes: Vec<(Tee, Arc<dyn Attest>)> = Vec::new();
+        for route in &config.attestation_routes {
+            if !backends.contains_key(&route.backend) {
+                let backend = registry.create(&route.backend, config).await?;
+                backends.insert(route.backend.clone(), backend);
+            }
+
+            for tee in &route.tees {
+                if routes.iter().any(|(routed, _)| routed == tee) {
+                    bail!("TEE {tee:?} is routed to more than one attestation backend");
+                }
+                log::info!(
+                    "Routing {tee:?} evidence to attestation backend {}",
+                    route.backend
+                );
+                routes.push((*tee, backends[&route.backend].clone()));
+            }
         }
+
+        Ok(Self {
+            backend: backends[name].clone(),
+            routes,
+            channel_binding: config.tls_channel_binding,
+            reattest_on_policy_change: config.reattest_on_policy_change,
+            reattestation_check_interval: config
+                .reattestation_check_interval
+                .map(Duration::from_secs),
+            tee_detection: config.tee_detection,
+            insecure_dev_mode: config.insecure_dev_mode,
+            archive: Archive::new(config.archive_config.as_ref())?,
+            publisher: Publisher::new(config.publisher_config.as_ref())?,
+            drift: Drift::new(config.drift_config.as_ref())?,
+        })
     }
 
-    pub async fn set_policy(&self, policy_id: &str, policy: &str) -> Result<()> {
-        match self {
-            #[cfg(feature = "coco-as-grpc")]
-            AttestationService::CoCoASgRPC(inner) => inner.set_policy(policy_id, policy).await,
-            #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
-            AttestationService::CoCoASBuiltIn(inner) => inner.set_policy(policy_id, policy).await,
-            #[cfg(feature = "intel-trust-authority-as")]
-            AttestationService::IntelTA(inner) => inner.set_policy(policy_id, policy).await,
+    /// Verify all evidence with `backend`.
+    pub fn from_backend(backend: Arc<dyn Attest>) -> Self {
+        Self {
+            backend,
+            routes: Vec::new(),
+            channel_binding: false,
+            reattest_on_policy_change: false,
+            reattestation_check_interval: None,
+            tee_detection: false,
+            insecure_dev_mode: false,
+            archive: Archive::default(),
+            publisher: Publisher::default(),
+            drift: Drift::default(),
         }
     }
 
-    pub async fn generate_challenge(&self, tee: Tee, tee_parameters: String) -> Result<Challenge> {
-        match self {
-            #[cfg(feature = "coco-as-grpc")]
-            AttestationService::CoCoASgRPC(inner) => {
-                inner.generate_challenge(tee, tee_parameters).await
-            }
-            #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
-            AttestationService::CoCoASBuiltIn(inner) => {
-                inner.generate_challenge(tee, tee_parameters).await
+    /// The backend verifying the evidence of `tee`.
+    fn backend(&self, tee: Tee) -> &Arc<dyn Attest> {
+        self.routes
+            .iter()
+            .find(|(routed, _)| *routed == tee)
+            .map_or(&self.backend, |(_, backend)| backend)
+    }
+
+    /// Whether evidence must be bound to the TLS channel it is sent over.
+    pub fn requires_channel_binding(&self) -> bool {
+        self.channel_binding
+    }
+
+    /// Whether the clients have to attest again when the attestation
+    /// policies change.
+    pub fn reattest_on_policy_change(&self) -> bool {
+        self.reattest_on_policy_change
+    }
+
+    /// Time between the checks of the unexpired tokens with the current
+    /// attestation policies and reference values, if they are checked
+    /// periodically.
+    pub fn reattestation_check_interval(&self) -> Option<Duration> {
+        self.reattestation_check_interval
+    }
+
+    /// Whether the TEE of the attesters requesting the `auto` TEE is
+    /// detected from their evidence.
+    pub fn detects_tee(&self) -> bool {
+        self.tee_detection
+    }
+
+    /// Whether the attestations are archived.
+    pub fn archives(&self) -> bool {
+        self.archive.is_enabled()
+    }
+
+    /// Archive `record` in the background, with the policies of the default
+    /// backend.
+    pub fn archive(&self, record: AttestationRecord) {
+        self.archive.archive(record, self.backend.clone());
+    }
+
+    /// Publish the `verdict` of `kind` from `tee` audited as `event` to the
+    /// attestation result stream, if enabled.
+    pub fn publish<E: Display>(
+        &self,
+        kind: VerdictKind,
+        event: &AuditEvent,
+        tee: Tee,
+        verdict: &Result<Verdict, E>,
+    ) {
+        self.publisher.publish(kind, event, tee, verdict);
+    }
+
+    /// Record the platform configuration of the attestation `claims`, and
+    /// return its differences from the baseline of the platform, if drift
+    /// detection is enabled.
+    pub async fn track_drift(&self, claims: &Value) -> Vec<ClaimDiff> {
+        self.drift.track(claims).await
+    }
+
+    /// The drift of the attested platforms from their baselines.
+    pub fn drift(&self) -> &Drift {
+        &self.drift
+    }
+
+    /// Verify the `attestation` answering `nonce`, received over a TLS
+    /// connection with `channel_binding`. The channel binding is only
+    /// checked, and then required, when enabled in the configuration.
+    /// `prior_token` is the token of the prior attestation of the attester.
+    pub async fn verify(
+        &self,
+        tee: Tee,
+        nonce: &str,
+        attestation: &str,
+        policy_id: &str,
+        rvps_namespace: Option<&str>,
+        channel_binding: Option<&[u8]>,
+        prior_token: Option<&str>,
+    ) -> Result<Verdict> {
+        let parsed: Value = serde_json::from_str(attestation).unwrap_or_default();
+        let tee_evidence = parsed["tee-evidence"].as_str().unwrap_or_default();
+        self.check_sample_evidence(tee, tee_evidence)?;
+        let channel_binding = match (self.channel_binding, channel_binding) {
+            (false, _) => None,
+            (true, Some(channel_binding)) => Some(channel_binding),
+            (true, None) => {
+                bail!("TLS channel binding is required, attest over a TLS 1.3 connection")
             }
-            #[cfg(feature = "intel-trust-authority-as")]
-            AttestationService::IntelTA(inner) => {
-                inner.generate_challenge(tee, tee_parameters).await
+        };
+        self.backend(tee)
+            .verify(
+                tee,
+                nonce,
+                attestation,
+                policy_id,
+                rvps_namespace,
+                channel_binding,
+                prior_token,
+            )
+            .await
+    }
+
+    pub async fn simple_verify(
+        &self,
+        tee: Tee,
+        evidence: &str,
+        policy_id: Option<&str>,
+        rvps_namespace: Option<&str>,
+    ) -> Result<Verdict> {
+        self.check_sample_evidence(tee, evidence)?;
+        self.backend(tee)
+            .simple_verify(tee, evidence, policy_id, rvps_namespace)
+            .await
+    }
+
+    /// Fail if `tee_evidence` of `tee` holds sample evidence, of the TEE or
+    /// of a submodule of composite evidence, unless in insecure dev mode.
+    fn check_sample_evidence(&self, tee: Tee, tee_evidence: &str) -> Result<()> {
+        if !has_sample_evidence(tee, tee_evidence) {
+            return Ok(());
+        }
+        if !self.insecure_dev_mode {
+            bail!("sample evidence is only accepted in insecure dev mode");
+        }
+        log::warn!("INSECURE DEV MODE: attesting a client with sample evidence");
+        Ok(())
+    }
+
+    pub async fn set_policy(&self, policy_id: &str, policy: &str) -> Result<()> {
+        self.backend.set_policy(policy_id, policy).await?;
+        self.flush_caches();
+        Ok(())
+    }
+
+    pub async fn list_policies(&self) -> Result<HashMap<String, String>> {
+        self.backend.list_policies().await
+    }
+
+    pub async fn get_policy(&self, policy_id: &str) -> Result<String> {
+        self.backend.get_policy(policy_id).await
+    }
+
+    pub async fn remove_policy(&self, policy_id: &str) -> Result<()> {
+        self.backend.remove_policy(policy_id).await?;
+        self.flush_caches();
+        Ok(())
+    }
+
+    /// The policy evaluations the backends captured for the request with the
+    /// correlation ID `request_id`.
+    pub async fn policy_capture(&self, request_id: &str) -> Result<Option<Value>> {
+        let routed = self.routes.iter().map(|(_, backend)| backend);
+        for backend in std::iter::once(&self.backend).chain(routed) {
+            if let Some(captures) = backend.policy_capture(request_id).await? {
+                return Ok(Some(captures));
             }
         }
+        Ok(None)
+    }
+
+    /// Drop the verdicts cached by the backends.
+    fn flush_caches(&self) {
+        self.backend.flush_cache();
+        for (_, backend) in &self.routes {
+            backend.flush_cache();
+        }
+    }
+
+    pub async fn generate_challenge(&self, tee: Tee, tee_parameters: String) -> Result<Challenge> {
+        self.backend(tee)
+            .generate_challenge(tee, tee_parameters)
+            .await
+    }
+
+    /// The public keys verifying the tokens of the default backend.
+    pub async fn token_jwks(&self) -> Result<String> {
+        self.backend.token_jwks().await
+    }
+
+    /// The OIDC discovery document of the token issuer of the default
+    /// backend.
+    pub async fn oidc_discovery(&self) -> Result<Option<Value>> {
+        self.backend.oidc_discovery().await
+    }
+
+    pub async fn health_check(&self) -> Result<()> {
+        self.backend.health_check().await?;
+        for (_, backend) in &self.routes {
+            backend.health_check().await?;
+        }
+        Ok(())
     }
 }
 
+/// Whether `tee_evidence` of `tee` is sample evidence, or composite evidence
+/// with a submodule of sample evidence, see `coco::composite`.
+fn has_sample_evidence(tee: Tee, tee_evidence: &str) -> bool {
+    if tee == Tee::Sample {
+        return true;
+    }
+    let Some(evidence) = serde_json::from_str::<Value>(tee_evidence).ok() else {
+        return false;
+    };
+    evidence["submods"].as_object().is_some_and(|submods| {
+        submods.values().any(|submod| {
+            serde_json::from_value::<Tee>(submod["tee"].clone()).ok() == Some(Tee::Sample)
+        })
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn test_session_runtime_data() {
+        assert_eq!(
+            session_runtime_data(&"key", "nonce", None),
+            json!({"tee-pubkey": "key", "nonce": "nonce"})
+        );
+        assert_eq!(
+            session_runtime_data(&"key", "nonce", Some(&[0xfb, 0xff])),
+            json!({"tee-pubkey": "key", "nonce": "nonce", "tls-exporter": "-_8"})
+        );
+    }
 
     #[tokio::test]
     async fn test_make_nonce() {
@@ -179,4 +551,121 @@ mod tests {
             nonces.push(nonce);
         }
     }
+
+    #[tokio::test]
+    async fn test_flush_caches_on_policy_change() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct Backend {
+            flushes: AtomicUsize,
+        }
+
+        #[async_trait]
+        impl Attest for Backend {
+            async fn set_policy(&self, policy_id: &str, _policy: &str) -> Result<()> {
+                match policy_id {
+                    "invalid" => bail!("invalid policy"),
+                    _ => Ok(()),
+                }
+            }
+
+            async fn verify(
+                &self,
+                _: Tee,
+                _: &str,
+                _: &str,
+                _: &str,
+                _: Option<&str>,
+                _: Option<&[u8]>,
+                _: Option<&str>,
+            ) -> Result<Verdict> {
+                bail!("unused")
+            }
+
+            fn flush_cache(&self) {
+                self.flushes.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let backend = Arc::new(Backend::default());
+        let service = AttestationService::from_backend(backend.clone());
+        service.set_policy("default", "policy").await.unwrap();
+        assert_eq!(backend.flushes.load(Ordering::Relaxed), 1);
+
+        // The verdicts are kept when the policy is not changed.
+        service.set_policy("invalid", "policy").await.unwrap_err();
+        service.remove_policy("default").await.unwrap_err();
+        assert_eq!(backend.flushes.load(Ordering::Relaxed), 1);
+    }
+
+    #[rstest]
+    #[case(Tee::Tdx, None, false, true)]
+    #[case(Tee::Sample, None, false, false)]
+    #[case(Tee::Sample, None, true, true)]
+    #[case(Tee::Tdx, Some(Tee::Tdx), false, true)]
+    #[case(Tee::Tdx, Some(Tee::Sample), false, false)]
+    #[case(Tee::Tdx, Some(Tee::Sample), true, true)]
+    #[tokio::test]
+    async fn test_insecure_dev_mode(
+        #[case] tee: Tee,
+        #[case] submod_tee: Option<Tee>,
+        #[case] insecure_dev_mode: bool,
+        #[case] accepted: bool,
+    ) {
+        struct Backend;
+
+        #[async_trait]
+        impl Attest for Backend {
+            async fn verify(
+                &self,
+                _: Tee,
+                _: &str,
+                _: &str,
+                _: &str,
+                _: Option<&str>,
+                _: Option<&[u8]>,
+                _: Option<&str>,
+            ) -> Result<Verdict> {
+                Ok(Verdict {
+                    token: "token".into(),
+                    claims: Value::Null,
+                    policies: Vec::new(),
+                })
+            }
+
+            async fn simple_verify(
+                &self,
+                _: Tee,
+                _: &str,
+                _: Option<&str>,
+                _: Option<&str>,
+            ) -> Result<Verdict> {
+                Ok(Verdict {
+                    token: "token".into(),
+                    claims: Value::Null,
+                    policies: Vec::new(),
+                })
+            }
+        }
+
+        let tee_evidence = match submod_tee {
+            Some(submod_tee) => json!({
+                "evidence": "",
+                "submods": {"gpu0": {"tee": submod_tee, "evidence": ""}},
+            })
+            .to_string(),
+            None => String::new(),
+        };
+        let attestation = json!({"tee-evidence": tee_evidence}).to_string();
+
+        let mut service = AttestationService::from_backend(Arc::new(Backend));
+        service.insecure_dev_mode = insecure_dev_mode;
+        let verdict = service
+            .verify(tee, "", &attestation, "", None, None, None)
+            .await;
+        assert_eq!(verdict.is_ok(), accepted);
+        let verdict = service.simple_verify(tee, &tee_evidence, None, None).await;
+        assert_eq!(verdict.is_ok(), accepted);
+    }
 }
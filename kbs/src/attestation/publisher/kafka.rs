@@ -0,0 +1,127 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use super::Broker;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Media type of the produce requests of JSON records of the REST Proxy API
+/// v2.
+const KAFKA_JSON: &str = "application/vnd.kafka.json.v2+json";
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct KafkaRestConfig {
+    /// URL of the Kafka REST Proxy, e.g. `https://kafka-rest.example.com:8082`.
+    pub url: String,
+
+    /// Topic the records are produced to.
+    pub topic: String,
+
+    /// User of the HTTP basic authentication to the proxy.
+    pub username: Option<String>,
+
+    /// File holding the password of `username`.
+    pub password_path: Option<PathBuf>,
+}
+
+/// Production of the records to a Kafka topic through the REST Proxy API v2.
+pub(crate) struct KafkaRestBroker {
+    client: reqwest::Client,
+    url: Url,
+    credentials: Option<(String, Zeroizing<String>)>,
+}
+
+impl KafkaRestBroker {
+    pub fn new(config: &KafkaRestConfig) -> Result<Self> {
+        let url = format!(
+            "{}/topics/{}",
+            config.url.trim_end_matches('/'),
+            config.topic
+        );
+        let url = Url::parse(&url).with_context(|| format!("invalid Kafka REST URL {url}"))?;
+        let credentials = match (&config.username, &config.password_path) {
+            (Some(username), Some(path)) => {
+                let password = std::fs::read_to_string(path)
+                    .with_context(|| format!("read Kafka REST password {}", path.display()))?;
+                Some((
+                    username.clone(),
+                    Zeroizing::new(password.trim().to_string()),
+                ))
+            }
+            (None, None) => None,
+            _ => bail!("username and password_path of the Kafka REST Proxy must be set together"),
+        };
+
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            url,
+            credentials,
+        })
+    }
+}
+
+#[async_trait]
+impl Broker for KafkaRestBroker {
+    async fn publish(&self, record: &Value) -> Result<()> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, KAFKA_JSON)
+            .header(reqwest::header::ACCEPT, "application/vnd.kafka.v2+json")
+            .body(json!({ "records": [{ "value": record }] }).to_string());
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password.as_str()));
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            bail!(
+                "Kafka REST Proxy returned {status}: {}",
+                response.text().await?
+            );
+        }
+
+        // Records failing to be produced are reported in their offsets.
+        let response: Value = response.json().await?;
+        if let Some(error) = response["offsets"][0]["error"].as_str() {
+            bail!("Kafka REST Proxy failed to produce the record: {error}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials() {
+        let mut config = KafkaRestConfig {
+            url: "https://kafka-rest.example.com:8082/".into(),
+            topic: "kbs-verdicts".into(),
+            username: Some("kbs".into()),
+            password_path: None,
+        };
+        assert!(KafkaRestBroker::new(&config).is_err());
+
+        config.username = None;
+        let broker = KafkaRestBroker::new(&config).unwrap();
+        assert_eq!(
+            broker.url.as_str(),
+            "https://kafka-rest.example.com:8082/topics/kbs-verdicts"
+        );
+    }
+}
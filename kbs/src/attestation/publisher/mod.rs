@@ -0,0 +1,373 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A stream of the attestation verdicts.
+//!
+//! Every attestation of a KBS session, verification of evidence by an admin
+//! and token failing its re-attestation check is published to a message
+//! broker as a compact record with the TEE, the identity claims of the
+//! attester, the policies it was evaluated against and whether it passed,
+//! for fleet-health
+//! dashboards and admission controllers to subscribe to. Records are
+//! published in the background, in order, and dropped when the broker can't
+//! keep up, so that a slow or unreachable broker doesn't delay or break the
+//! attestations.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use kbs_types::Tee;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::{PolicyOutcome, Verdict};
+use crate::audit::{AuditEvent, Outcome};
+
+mod kafka;
+mod nats;
+
+use kafka::KafkaRestBroker;
+pub use kafka::KafkaRestConfig;
+use nats::NatsBroker;
+pub use nats::NatsConfig;
+
+const PUBLISH_ATTEMPTS: u32 = 3;
+
+/// Records waiting to be published, beyond which new ones are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// Attestation result stream configuration.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct PublisherConfig {
+    #[serde(flatten)]
+    pub broker: BrokerConfig,
+
+    /// JSON pointers of the claims identifying the attester, e.g.
+    /// `/customized_claims/runtime_data/workload`, copied into the records.
+    #[serde(default)]
+    pub identity_claims: Vec<String>,
+}
+
+/// Message broker configuration.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum BrokerConfig {
+    /// Publish the records on a subject of a NATS server.
+    Nats(NatsConfig),
+
+    /// Produce the records to a Kafka topic through a Kafka REST Proxy.
+    KafkaRest(KafkaRestConfig),
+}
+
+impl BrokerConfig {
+    fn broker(&self) -> Result<Arc<dyn Broker>> {
+        Ok(match self {
+            Self::Nats(config) => Arc::new(NatsBroker::new(config)?),
+            Self::KafkaRest(config) => Arc::new(KafkaRestBroker::new(config)?),
+        })
+    }
+}
+
+#[async_trait]
+pub(crate) trait Broker: Send + Sync {
+    /// Publish the record `record`.
+    async fn publish(&self, record: &Value) -> Result<()>;
+}
+
+/// What a verdict is the outcome of.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VerdictKind {
+    /// Attestation of a KBS session.
+    Attestation,
+
+    /// Verification of evidence by an admin, outside of a KBS session.
+    Verification,
+
+    /// Verification of the evidence of an unexpired token again, after the
+    /// attestation policy or reference values changed.
+    Reattestation,
+}
+
+/// The published record of the verdict of one attestation.
+#[derive(Clone, Debug, Serialize)]
+pub struct VerdictRecord {
+    /// Unique ID of the record, to spot the ones delivered twice. It isn't
+    /// derived from the KBS session, so that the subscribers can't tell the
+    /// session handle.
+    pub id: String,
+
+    pub timestamp: String,
+
+    pub kind: VerdictKind,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    pub tee: Tee,
+
+    pub outcome: Outcome,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// The configured identity claims the attester has, by JSON pointer.
+    pub identity: BTreeMap<String, Value>,
+
+    /// Policies the evidence was evaluated against.
+    pub policies: Vec<PolicyOutcome>,
+}
+
+impl VerdictRecord {
+    /// The record of the `verdict` of `kind` from `tee` audited as `event`,
+    /// with the claims at `identity_claims`.
+    pub fn new<E: Display>(
+        kind: VerdictKind,
+        event: &AuditEvent,
+        tee: Tee,
+        verdict: &std::result::Result<Verdict, E>,
+        identity_claims: &[String],
+    ) -> Self {
+        let mut record = Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: event.timestamp.clone(),
+            kind,
+            tenant: event
+                .details
+                .get("tenant")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            tee,
+            outcome: Outcome::Success,
+            reason: None,
+            identity: BTreeMap::new(),
+            policies: Vec::new(),
+        };
+        match verdict {
+            Ok(verdict) => {
+                record.policies = verdict.policies.clone();
+                record.identity = identity_claims
+                    .iter()
+                    .filter_map(|pointer| {
+                        let claim = verdict.claims.pointer(pointer)?;
+                        Some((pointer.clone(), claim.clone()))
+                    })
+                    .collect();
+            }
+            Err(e) => {
+                record.outcome = Outcome::Failure;
+                record.reason = Some(e.to_string());
+            }
+        }
+        record
+    }
+}
+
+/// Handle to the attestation result stream. Publishing is disabled when no
+/// broker is configured.
+#[derive(Clone, Default)]
+pub struct Publisher {
+    queue: Option<mpsc::Sender<VerdictRecord>>,
+    identity_claims: Arc<Vec<String>>,
+}
+
+impl Publisher {
+    pub fn new(config: Option<&PublisherConfig>) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self::default());
+        };
+
+        let broker = config
+            .broker
+            .broker()
+            .context("open attestation result stream")?;
+        let (queue, mut records) = mpsc::channel::<VerdictRecord>(QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some(record) = records.recv().await {
+                publish(broker.as_ref(), &record).await;
+            }
+        });
+
+        Ok(Self {
+            queue: Some(queue),
+            identity_claims: Arc::new(config.identity_claims.clone()),
+        })
+    }
+
+    /// Whether the attestation verdicts are published.
+    pub fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Publish the `verdict` of `kind` from `tee` audited as `event` in the
+    /// background. Records the broker can't keep up with are dropped with a
+    /// warning in the log.
+    pub fn publish<E: Display>(
+        &self,
+        kind: VerdictKind,
+        event: &AuditEvent,
+        tee: Tee,
+        verdict: &std::result::Result<Verdict, E>,
+    ) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let record = VerdictRecord::new(kind, event, tee, verdict, &self.identity_claims);
+        if let Err(e) = queue.try_send(record) {
+            warn!("Dropping attestation verdict record: {e}");
+        }
+    }
+}
+
+/// Publish `record` with `broker`. Failed publications are retried twice
+/// and then dropped with a warning in the log.
+async fn publish(broker: &dyn Broker, record: &VerdictRecord) {
+    let id = &record.id;
+    let record = match serde_json::to_value(record) {
+        Ok(record) => record,
+        Err(e) => {
+            warn!("Dropping attestation verdict record {id}: {e}");
+            return;
+        }
+    };
+    for attempt in 1..=PUBLISH_ATTEMPTS {
+        match broker.publish(&record).await {
+            Ok(()) => return,
+            Err(e) if attempt < PUBLISH_ATTEMPTS => {
+                warn!(
+                    "Publishing attestation verdict record {id}, attempt {attempt} failed: {e:#}"
+                );
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+            Err(e) => warn!("Dropping attestation verdict record {id}: {e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditEventType;
+    use rstest::rstest;
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        records: Mutex<Vec<Value>>,
+        failures: Mutex<u32>,
+    }
+
+    #[async_trait]
+    impl Broker for Recorder {
+        async fn publish(&self, record: &Value) -> Result<()> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                anyhow::bail!("unavailable");
+            }
+            self.records.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_publisher_config() {
+        let config: PublisherConfig = serde_json::from_value(json!({
+            "type": "Nats",
+            "address": "nats.example.com:4222",
+            "subject": "kbs.verdicts",
+            "identity_claims": ["/customized_claims/workload"],
+        }))
+        .unwrap();
+        assert_eq!(
+            config,
+            PublisherConfig {
+                broker: BrokerConfig::Nats(NatsConfig {
+                    address: "nats.example.com:4222".into(),
+                    subject: "kbs.verdicts".into(),
+                    token_path: None,
+                    tls: None,
+                }),
+                identity_claims: vec!["/customized_claims/workload".into()],
+            }
+        );
+    }
+
+    #[rstest]
+    #[case(Ok(Verdict {
+        token: "token".into(),
+        claims: json!({"customized_claims": {"workload": "db"}, "tdx": {"svn": 3}}),
+        policies: vec![PolicyOutcome {
+            policy_id: "default".into(),
+            policy_hash: None,
+            matched: true,
+        }],
+    }), json!({
+        "kind": "attestation",
+        "tee": "tdx",
+        "outcome": "success",
+        "identity": {"/customized_claims/workload": "db"},
+        "policies": [{"policy_id": "default", "matched": true}],
+    }))]
+    #[case(Err("evidence expired".to_string()), json!({
+        "kind": "attestation",
+        "tee": "tdx",
+        "outcome": "failure",
+        "reason": "evidence expired",
+        "identity": {},
+        "policies": [],
+    }))]
+    fn test_verdict_record(
+        #[case] verdict: std::result::Result<Verdict, String>,
+        #[case] expected: Value,
+    ) {
+        let event = AuditEvent::from_peer(AuditEventType::AttestationVerdict, "c".into(), None);
+        let claims = [
+            "/customized_claims/workload".to_string(),
+            "/sgx".to_string(),
+        ];
+        let record = VerdictRecord::new(
+            VerdictKind::Attestation,
+            &event,
+            Tee::Tdx,
+            &verdict,
+            &claims,
+        );
+        let mut record = serde_json::to_value(record).unwrap();
+        record.as_object_mut().unwrap().remove("timestamp");
+        assert!(record
+            .as_object_mut()
+            .unwrap()
+            .remove("id")
+            .is_some_and(|id| id != "c"));
+        assert_eq!(record, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_publish_retries() {
+        let event = AuditEvent::from_peer(AuditEventType::AttestationVerdict, "c".into(), None);
+        let verdict: std::result::Result<Verdict, String> = Err("failed".into());
+        let record = VerdictRecord::new(VerdictKind::Attestation, &event, Tee::Tdx, &verdict, &[]);
+
+        let broker = Recorder {
+            failures: Mutex::new(2),
+            ..Default::default()
+        };
+        publish(&broker, &record).await;
+        assert_eq!(broker.records.lock().unwrap().len(), 1);
+
+        let broker = Recorder {
+            failures: Mutex::new(PUBLISH_ATTEMPTS),
+            ..Default::default()
+        };
+        publish(&broker, &record).await;
+        assert!(broker.records.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{bail, Context, Result};
+use async_nats::{Client, ConnectOptions};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use zeroize::Zeroizing;
+
+use super::Broker;
+
+const NATS_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct NatsConfig {
+    /// Address of the NATS server, e.g. `nats://nats.example.com:4222`.
+    pub address: String,
+
+    /// Subject the records are published on, e.g. `kbs.verdicts`.
+    pub subject: String,
+
+    /// File holding the token authenticating to the server.
+    pub token_path: Option<PathBuf>,
+
+    /// TLS of the connection to the server, which is required when set.
+    pub tls: Option<NatsTlsConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct NatsTlsConfig {
+    /// PEM bundle of the CA certificates trusted to issue the server
+    /// certificate, besides the ones of the system.
+    pub ca_cert: Option<PathBuf>,
+
+    /// PEM certificate chain to authenticate KBS to the server with.
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM private key of the `client_cert`.
+    pub client_key: Option<PathBuf>,
+}
+
+/// Publication of the records on a subject of a NATS server with the
+/// `async-nats` client, which reconnects on its own.
+pub(crate) struct NatsBroker {
+    address: String,
+    subject: String,
+    token: Option<Zeroizing<String>>,
+    tls: Option<NatsTlsConfig>,
+
+    /// Client of the server, connected on the first record.
+    client: OnceCell<Client>,
+}
+
+impl NatsBroker {
+    pub fn new(config: &NatsConfig) -> Result<Self> {
+        if config.subject.is_empty() || config.subject.contains(char::is_whitespace) {
+            bail!("invalid NATS subject {:?}", config.subject);
+        }
+        let token = match &config.token_path {
+            Some(path) => {
+                let token = std::fs::read_to_string(path)
+                    .with_context(|| format!("read NATS token {}", path.display()))?;
+                Some(Zeroizing::new(token.trim().to_string()))
+            }
+            None => None,
+        };
+        if let Some(tls) = &config.tls {
+            if tls.client_cert.is_some() != tls.client_key.is_some() {
+                bail!("NATS client_cert and client_key must be set together");
+            }
+        }
+
+        Ok(Self {
+            address: config.address.clone(),
+            subject: config.subject.clone(),
+            token,
+            tls: config.tls.clone(),
+            client: OnceCell::new(),
+        })
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        let mut options = ConnectOptions::new()
+            .name("kbs")
+            .connection_timeout(NATS_TIMEOUT);
+        if let Some(token) = &self.token {
+            options = options.token(token.to_string());
+        }
+        if let Some(tls) = &self.tls {
+            options = options.require_tls(true);
+            if let Some(ca_cert) = &tls.ca_cert {
+                options = options.add_root_certificates(ca_cert.clone());
+            }
+            if let (Some(client_cert), Some(client_key)) = (&tls.client_cert, &tls.client_key) {
+                options = options.add_client_certificate(client_cert.clone(), client_key.clone());
+            }
+        }
+
+        options
+            .connect(self.address.as_str())
+            .await
+            .with_context(|| format!("connect to NATS server {}", self.address))
+    }
+}
+
+#[async_trait]
+impl Broker for NatsBroker {
+    async fn publish(&self, record: &Value) -> Result<()> {
+        let payload = serde_json::to_vec(record)?;
+        let client = self.client.get_or_try_init(|| self.connect()).await?;
+        client
+            .publish(self.subject.clone(), payload.into())
+            .await
+            .context("publish to NATS")?;
+        client.flush().await.context("flush to NATS")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Serve one NATS client, and return the CONNECT options it sent and the
+    /// payload of its first PUB, with the connection kept open.
+    async fn serve(listener: &TcpListener) -> (Value, Vec<u8>, BufReader<TcpStream>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        stream
+            .get_mut()
+            .write_all(b"INFO {\"server_id\":\"test\",\"max_payload\":1048576}\r\n")
+            .await
+            .unwrap();
+
+        let mut connect = Value::Null;
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let line = line.trim_end();
+            if let Some(options) = line.strip_prefix("CONNECT ") {
+                connect = serde_json::from_str(options).unwrap();
+            } else if line == "PING" {
+                stream.get_mut().write_all(b"PONG\r\n").await.unwrap();
+            } else if let Some(args) = line.strip_prefix("PUB ") {
+                let len: usize = args.rsplit(' ').next().unwrap().parse().unwrap();
+                let mut payload = vec![0; len + 2];
+                stream.read_exact(&mut payload).await.unwrap();
+                payload.truncate(len);
+                return (connect, payload, stream);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let token = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(token.path(), "secret\n").unwrap();
+        let broker = NatsBroker::new(&NatsConfig {
+            address: format!("nats://{}", listener.local_addr().unwrap()),
+            subject: "kbs.verdicts".into(),
+            token_path: Some(token.path().to_path_buf()),
+            tls: None,
+        })
+        .unwrap();
+
+        let record = json!({"tee": "tdx", "outcome": "success"});
+        let (published, (connect, payload, _stream)) =
+            tokio::join!(broker.publish(&record), serve(&listener));
+        published.unwrap();
+        assert_eq!(connect["auth_token"], "secret");
+        assert_eq!(serde_json::from_slice::<Value>(&payload).unwrap(), record);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = NatsConfig {
+            address: "localhost:4222".into(),
+            subject: "kbs verdicts".into(),
+            token_path: None,
+            tls: None,
+        };
+        assert!(NatsBroker::new(&config).is_err());
+
+        let config = NatsConfig {
+            subject: "kbs.verdicts".into(),
+            tls: Some(NatsTlsConfig {
+                ca_cert: None,
+                client_cert: Some("client.pem".into()),
+                client_key: None,
+            }),
+            ..config
+        };
+        assert!(NatsBroker::new(&config).is_err());
+    }
+}
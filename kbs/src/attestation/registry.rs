@@ -0,0 +1,265 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Attestation backends chosen at runtime.
+//!
+//! Every backend compiled into KBS registers a factory under its name, and the
+//! `attestation_backend` of the KBS configuration picks one of them. Programs
+//! embedding KBS can register their own [`Attest`] implementations next to
+//! the compiled in ones.
+
+use anyhow::{anyhow, Result};
+use kbs_types::Tee;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use super::Attest;
+use crate::config::KbsConfig;
+
+/// Name of the built-in CoCo AS backend.
+pub const BUILTIN_BACKEND: &str = "builtin";
+
+/// Name of the remote gRPC CoCo AS backend.
+pub const COCO_GRPC_BACKEND: &str = "coco-grpc";
+
+/// Name of the Intel Trust Authority backend.
+pub const ITA_BACKEND: &str = "ita";
+
+/// A backend being created from the KBS configuration.
+pub type BackendFuture = Pin<Box<dyn Future<Output = Result<Arc<dyn Attest>>> + Send>>;
+
+type Factory = Box<dyn Fn(&KbsConfig) -> BackendFuture + Send + Sync>;
+
+/// The attestation backend verifying the evidence of some TEEs.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AttestationRoute {
+    /// TEEs routed to `backend`.
+    pub tees: Vec<Tee>,
+
+    /// Name of the backend.
+    pub backend: String,
+}
+
+/// Factories of the attestation backends, by name.
+pub struct BackendRegistry {
+    factories: BTreeMap<String, Factory>,
+
+    /// The backend used when the configuration doesn't pick one.
+    default: Option<String>,
+}
+
+impl Default for BackendRegistry {
+    /// The registry of the backends compiled into KBS. The default backend
+    /// is the first of the built-in CoCo AS, the gRPC CoCo AS and Intel Trust
+    /// Authority that is compiled in.
+    fn default() -> Self {
+        #[allow(unused_mut)]
+        let mut registry = Self::empty();
+
+        #[cfg(feature = "intel-trust-authority-as")]
+        registry.register(ITA_BACKEND, |config| {
+            let config = config.intel_trust_authority_config.clone();
+            Box::pin(async move {
+                let config = config.ok_or_else(|| {
+                    anyhow!("The {ITA_BACKEND} backend requires the intel_trust_authority_config")
+                })?;
+                let backend = super::intel_trust_authority::IntelTrustAuthority::new(config)?;
+                Ok(Arc::new(backend) as Arc<dyn Attest>)
+            })
+        });
+
+        #[cfg(feature = "coco-as-grpc")]
+        registry.register(COCO_GRPC_BACKEND, |config| {
+            let config = config.grpc_config.clone().unwrap_or_default();
+            Box::pin(async move {
+                let backend = super::coco::grpc::GrpcClientPool::new(config).await?;
+                Ok(Arc::new(backend) as Arc<dyn Attest>)
+            })
+        });
+
+        #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
+        registry.register(BUILTIN_BACKEND, |config| {
+            let config = config.as_config.clone().unwrap_or_default();
+            Box::pin(async move {
+                let backend = super::coco::builtin::BuiltInCoCoAs::new(config).await?;
+                Ok(Arc::new(backend) as Arc<dyn Attest>)
+            })
+        });
+
+        registry
+    }
+}
+
+impl BackendRegistry {
+    /// A registry without any backend.
+    pub fn empty() -> Self {
+        Self {
+            factories: BTreeMap::new(),
+            default: None,
+        }
+    }
+
+    /// Register `factory` to create the backend `name`, replacing the
+    /// backend registered under the same name. The last registered backend
+    /// is the default one.
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&KbsConfig) -> BackendFuture + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_string(), Box::new(factory));
+        self.default = Some(name.to_string());
+    }
+
+    /// Names of the registered backends.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// The backend the configuration picks, or the default one.
+    pub fn backend_name<'a>(&'a self, config: &'a KbsConfig) -> Result<&'a str> {
+        config
+            .attestation_backend
+            .as_deref()
+            .or(self.default.as_deref())
+            .ok_or_else(|| anyhow!("No attestation backend is registered"))
+    }
+
+    /// Create the backend `name` from `config`.
+    pub async fn create(&self, name: &str, config: &KbsConfig) -> Result<Arc<dyn Attest>> {
+        let factory = self.factories.get(name).ok_or_else(|| {
+            anyhow!(
+                "Unknown attestation backend {name}, the registered ones are: {}",
+                self.names().collect::<Vec<_>>().join(", ")
+            )
+        })?;
+        factory(config).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::{AttestationService, Verdict};
+    use kbs_types::Tee;
+
+    struct Backend(&'static str);
+
+    #[async_trait::async_trait]
+    impl Attest for Backend {
+        async fn verify(
+            &self,
+            _: Tee,
+            _: &str,
+            _: &str,
+            _: &str,
+            _: Option<&str>,
+            _: Option<&[u8]>,
+            _: Option<&str>,
+        ) -> Result<Verdict> {
+            Ok(Verdict {
+                token: self.0.to_string(),
+                claims: serde_json::Value::Null,
+                policies: Vec::new(),
+            })
+        }
+    }
+
+    fn config(attestation_backend: Option<&str>) -> KbsConfig {
+        routed_config(attestation_backend, "")
+    }
+
+    fn routed_config(attestation_backend: Option<&str>, routes: &str) -> KbsConfig {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("kbs.toml");
+        let mut config = String::from("insecure_http = true\n");
+        if let Some(attestation_backend) = attestation_backend {
+            config += &format!("attestation_backend = \"{attestation_backend}\"\n");
+        }
+        config += "[attestation_token_config]\nattestation_token_type = \"CoCo\"\n";
+        config += routes;
+        std::fs::write(&config_file, config).unwrap();
+        KbsConfig::try_from(config_file.as_path()).unwrap()
+    }
+
+    fn registry(names: &[&'static str]) -> BackendRegistry {
+        let mut registry = BackendRegistry::empty();
+        for &name in names {
+            registry.register(name, move |_| {
+                Box::pin(async move { Ok(Arc::new(Backend(name)) as Arc<dyn Attest>) })
+            });
+        }
+        registry
+    }
+
+    #[tokio::test]
+    async fn test_registry() {
+        assert!(BackendRegistry::empty()
+            .backend_name(&config(None))
+            .is_err());
+
+        let registry = registry(&["a", "b"]);
+        assert_eq!(registry.names().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(registry.backend_name(&config(None)).unwrap(), "b");
+
+        let config = config(Some("a"));
+        let name = registry.backend_name(&config).unwrap();
+        let backend = registry.create(name, &config).await.unwrap();
+        let verdict = backend
+            .verify(Tee::Sample, "", "", "", None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(verdict.token, "a");
+
+        let e = registry.create("c", &config).await.err().unwrap();
+        assert!(e.to_string().contains("the registered ones are: a, b"));
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let registry = registry(&["a", "b", "c"]);
+        let routes = r#"
+[[attestation_routes]]
+tees = ["tdx", "sgx"]
+backend = "b"
+
+[[attestation_routes]]
+tees = ["snp"]
+backend = "a"
+"#;
+        let service = AttestationService::new(&routed_config(Some("a"), routes), &registry)
+            .await
+            .unwrap();
+        for (tee, backend) in [
+            (Tee::Tdx, "b"),
+            (Tee::Sgx, "b"),
+            (Tee::Snp, "a"),
+            (Tee::Cca, "a"),
+        ] {
+            let verdict = service
+                .verify(tee, "", "", "", None, None, None)
+                .await
+                .unwrap();
+            assert_eq!(verdict.token, backend);
+        }
+
+        let routes = r#"
+[[attestation_routes]]
+tees = ["tdx"]
+backend = "b"
+
+[[attestation_routes]]
+tees = ["tdx"]
+backend = "c"
+"#;
+        let config = routed_config(None, routes);
+        assert!(AttestationService::new(&config, &registry).await.is_err());
+
+        let routes = "[[attestation_routes]]\ntees = [\"tdx\"]\nbackend = \"d\"\n";
+        let config = routed_config(None, routes);
+        assert!(AttestationService::new(&config, &registry).await.is_err());
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Serialize;
+use serde_json::Value;
+
+/// The outcome of the evaluation of evidence against an attestation policy.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PolicyOutcome {
+    pub policy_id: String,
+
+    /// Digest of the policy, when the attestation service reports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy_hash: Option<String>,
+
+    /// Whether the evidence matched the policy.
+    pub matched: bool,
+}
+
+/// The result of a successful verification of evidence.
+#[derive(Clone, Debug)]
+pub struct Verdict {
+    /// Attestation results token issued by the attestation service.
+    pub token: String,
+
+    /// Claims of `token`.
+    pub claims: Value,
+
+    /// Policies the evidence was evaluated against.
+    pub policies: Vec<PolicyOutcome>,
+}
+
+impl Verdict {
+    /// The verdict of a token of the CoCo AS, which fails the verification
+    /// of evidence that doesn't match a policy, and lists the policies the
+    /// evidence matched in its `evaluation-reports` claim.
+    #[cfg(feature = "coco-as")]
+    pub fn from_coco_token(token: String) -> Result<Self> {
+        let claims = token_claims(&token)?;
+        let policies = claims["evaluation-reports"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|report| {
+                Some(PolicyOutcome {
+                    policy_id: report["policy-id"].as_str()?.to_string(),
+                    policy_hash: report["policy-hash"].as_str().map(str::to_string),
+                    matched: true,
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            token,
+            claims,
+            policies,
+        })
+    }
+}
+
+/// The claims of the JWT `token`, read without verifying its signature.
+pub fn token_claims(token: &str) -> Result<Value> {
+    let claims = token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| anyhow!("Illegal token format"))?;
+    let claims = URL_SAFE_NO_PAD
+        .decode(claims)
+        .context("Illegal token base64 claims")?;
+    serde_json::from_slice(&claims).context("Illegal token claims")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(claims: Value) -> String {
+        format!(
+            "{}.{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"ES256"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string()),
+            URL_SAFE_NO_PAD.encode("signature"),
+        )
+    }
+
+    #[test]
+    fn test_token_claims() {
+        let claims = serde_json::json!({"tee": "sample"});
+        assert_eq!(token_claims(&token(claims.clone())).unwrap(), claims);
+        assert!(token_claims("not a token").is_err());
+        assert!(token_claims("a.!.c").is_err());
+    }
+
+    #[cfg(feature = "coco-as")]
+    #[test]
+    fn test_from_coco_token() {
+        let token = token(serde_json::json!({
+            "tee": "sample",
+            "evaluation-reports": [
+                {"policy-id": "default", "policy-hash": "hash"},
+            ],
+        }));
+        let verdict = Verdict::from_coco_token(token.clone()).unwrap();
+        assert_eq!(verdict.token, token);
+        assert_eq!(verdict.claims["tee"], "sample");
+        assert_eq!(
+            verdict.policies,
+            vec![PolicyOutcome {
+                policy_id: "default".into(),
+                policy_hash: Some("hash".into()),
+                matched: true,
+            }]
+        );
+    }
+}
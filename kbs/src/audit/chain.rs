@@ -0,0 +1,342 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hash chain of the audit records.
+//!
+//! Every record carries its sequence number and the SHA-256 hash of the line
+//! before it, so that modifying, inserting or removing a record breaks the
+//! chain. With a signing key, checkpoint records sign the head of the chain,
+//! so that records cut from the end of the log are detected as well, up to
+//! the last checkpoint.
+
+use anyhow::{Context, Result};
+use jwt_simple::prelude::{
+    Claims, Duration, Ed25519KeyPair, Ed25519PublicKey, EdDSAKeyPairLike, EdDSAPublicKeyLike,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::io::AsyncBufReadExt;
+
+use crate::crypto;
+
+/// `prev_hash` of the first record of a chain.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// `event` of the checkpoint records.
+const CHECKPOINT_EVENT: &str = "checkpoint";
+
+/// Default seconds between two checkpoints.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 60;
+
+/// Claims signed by a checkpoint.
+#[derive(Deserialize, Serialize)]
+struct CheckpointClaims {
+    /// Sequence number of the checkpoint.
+    seq: u64,
+
+    /// Hash of the record before the checkpoint.
+    hash: String,
+}
+
+/// A record linked to the chain.
+#[derive(Serialize)]
+struct Linked<'a, T> {
+    seq: u64,
+    prev_hash: &'a str,
+    #[serde(flatten)]
+    record: &'a T,
+}
+
+/// A checkpoint record, signing the head of the chain.
+#[derive(Serialize)]
+pub(crate) struct Checkpoint {
+    timestamp: String,
+    event: &'static str,
+    signature: String,
+}
+
+/// Head of the chain, updated with every record written to the sink.
+pub(crate) struct Chain {
+    /// Sequence number of the next record.
+    seq: u64,
+
+    /// Hash of the last record.
+    head: String,
+
+    /// Records written since the last checkpoint.
+    pub unsigned: u64,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Self {
+            seq: 0,
+            head: GENESIS_HASH.to_string(),
+            unsigned: 0,
+        }
+    }
+}
+
+impl Chain {
+    /// Continue the chain of the log file at `path`. Records written before
+    /// the log was chained are linked as they are.
+    pub fn resume(path: &Path) -> Result<Self> {
+        let Some(line) =
+            last_line(path).with_context(|| format!("read audit log {}", path.display()))?
+        else {
+            return Ok(Self::default());
+        };
+
+        let record: Value = serde_json::from_str(&line).unwrap_or_default();
+        let seq = match (record.get("prev_hash"), record["seq"].as_u64()) {
+            (Some(_), Some(seq)) => seq + 1,
+            _ => 0,
+        };
+        let unsigned = match record["event"] == CHECKPOINT_EVENT {
+            true => 0,
+            false => 1,
+        };
+        Ok(Self {
+            seq,
+            head: hash(&line)?,
+            unsigned,
+        })
+    }
+
+    /// Serialize `record` as the next line of the chain.
+    pub fn link<T: Serialize>(&self, record: &T) -> Result<String> {
+        Ok(serde_json::to_string(&Linked {
+            seq: self.seq,
+            prev_hash: &self.head,
+            record,
+        })?)
+    }
+
+    /// Move the head to `line`, once it is written.
+    pub fn advance(&mut self, line: &str, checkpoint: bool) -> Result<()> {
+        self.head = hash(line)?;
+        self.seq += 1;
+        self.unsigned = match checkpoint {
+            true => 0,
+            false => self.unsigned + 1,
+        };
+        Ok(())
+    }
+
+    /// Sign the head of the chain with `key`.
+    pub fn checkpoint(&self, key: &Ed25519KeyPair) -> Result<Checkpoint> {
+        let claims = CheckpointClaims {
+            seq: self.seq,
+            hash: self.head.clone(),
+        };
+        // Checkpoints are verified long after they are written, so they don't
+        // expire.
+        let mut claims = Claims::with_custom_claims(claims, Duration::from_secs(0));
+        claims.expires_at = None;
+
+        Ok(Checkpoint {
+            timestamp: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            event: CHECKPOINT_EVENT,
+            signature: key.sign(claims)?,
+        })
+    }
+}
+
+/// Load the Ed25519 private key (PEM) signing the checkpoints.
+pub(crate) fn load_signing_key(path: &Path) -> Result<Ed25519KeyPair> {
+    let pem = std::fs::read_to_string(path)
+        .with_context(|| format!("read audit signing key {}", path.display()))?;
+    Ed25519KeyPair::from_pem(&pem).context("parse audit signing key")
+}
+
+fn hash(line: &str) -> Result<String> {
+    Ok(hex::encode(crypto::sha256(line.as_bytes())?))
+}
+
+/// The last line of the file at `path`, if it exists and is not empty. Only
+/// the end of the file is read.
+fn last_line(path: &Path) -> Result<Option<String>> {
+    const BLOCK: u64 = 4096;
+
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut tail = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(BLOCK);
+        let mut block = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+        block.append(&mut tail);
+        tail = block;
+        end = start;
+
+        let content = tail.strip_suffix(b"\n").unwrap_or(&tail);
+        if let Some(newline) = content.iter().rposition(|b| *b == b'\n') {
+            return Ok(Some(String::from_utf8(content[newline + 1..].to_vec())?));
+        }
+    }
+
+    let content = tail.strip_suffix(b"\n").unwrap_or(&tail);
+    match content.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(String::from_utf8(content.to_vec())?)),
+    }
+}
+
+/// Outcome of the verification of an audit log.
+#[derive(Debug, Default, Serialize)]
+pub struct AuditLogReport {
+    /// Chained records verified, checkpoints included.
+    pub records: u64,
+
+    /// Records written before the log was chained.
+    pub unchained_records: u64,
+
+    /// Checkpoints with a valid signature.
+    pub checkpoints: u64,
+
+    /// Time of the last valid checkpoint.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_checkpoint: Option<String>,
+
+    /// Records after the last valid checkpoint. They could have been cut
+    /// from the end of the log without breaking the chain.
+    pub unsigned_records: u64,
+
+    /// The first inconsistency found, which ends the verification.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditLogReport {
+    /// Whether the chain is intact.
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Verify the chain of the audit log at `path`, and the checkpoints against
+/// `key`. Without a key, checkpoints are not verified and count as unsigned
+/// records.
+pub async fn verify_audit_log(
+    path: &Path,
+    key: Option<&Ed25519PublicKey>,
+) -> Result<AuditLogReport> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("open audit log {}", path.display()))?;
+    let mut lines = tokio::io::BufReader::new(file).lines();
+
+    let mut report = AuditLogReport::default();
+    let mut head = GENESIS_HASH.to_string();
+    let mut seq = 0;
+    let mut number = 0;
+    while let Some(line) = lines.next_line().await? {
+        number += 1;
+        if let Err(e) = verify_line(&line, &head, &mut seq, key, &mut report) {
+            report.error = Some(format!("line {number}: {e}"));
+            break;
+        }
+        head = hash(&line)?;
+    }
+
+    Ok(report)
+}
+
+/// Verify that `line` follows the record of hash `head` and sequence number
+/// `seq` - 1, and account for it in `report`.
+fn verify_line(
+    line: &str,
+    head: &str,
+    seq: &mut u64,
+    key: Option<&Ed25519PublicKey>,
+    report: &mut AuditLogReport,
+) -> Result<()> {
+    let record: Value = serde_json::from_str(line).context("not a JSON record")?;
+
+    let Some(prev_hash) = record.get("prev_hash") else {
+        if report.records > 0 {
+            anyhow::bail!("record is not chained");
+        }
+        report.unchained_records += 1;
+        return Ok(());
+    };
+    let record_seq = record["seq"]
+        .as_u64()
+        .context("record has no sequence number")?;
+
+    if report.records > 0 && prev_hash == GENESIS_HASH {
+        // A log file is continued across restarts, so a new chain means the
+        // log before it was replaced.
+        anyhow::bail!("record starts a new chain, the log was truncated");
+    }
+    if prev_hash != head {
+        anyhow::bail!("record does not follow the previous one, the log was modified");
+    }
+    if record_seq != *seq {
+        anyhow::bail!("expected record {}, found record {record_seq}", *seq);
+    }
+    *seq = record_seq + 1;
+    report.records += 1;
+
+    match (record["event"] == CHECKPOINT_EVENT, key) {
+        (true, Some(key)) => {
+            let signature = record["signature"]
+                .as_str()
+                .context("checkpoint has no signature")?;
+            let claims = key
+                .verify_token::<CheckpointClaims>(signature, None)
+                .context("invalid checkpoint signature")?;
+            if claims.custom.seq != record_seq
+                || claims.custom.hash != prev_hash.as_str().unwrap_or_default()
+            {
+                anyhow::bail!("checkpoint signature is for another record");
+            }
+            report.checkpoints += 1;
+            report.last_checkpoint = record["timestamp"].as_str().map(str::to_string);
+            report.unsigned_records = 0;
+        }
+        _ => report.unsigned_records += 1,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("", None)]
+    #[case("\n", None)]
+    #[case("a", Some("a"))]
+    #[case("a\nb\n", Some("b"))]
+    #[case("a\nb", Some("b"))]
+    fn test_last_line(#[case] content: &str, #[case] expected: Option<&str>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        std::fs::write(&path, content).unwrap();
+        assert_eq!(last_line(&path).unwrap().as_deref(), expected);
+    }
+
+    #[test]
+    fn test_last_line_across_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let long = "x".repeat(10000);
+        std::fs::write(&path, format!("{}\n{long}\n", "y".repeat(5000))).unwrap();
+        assert_eq!(last_line(&path).unwrap().unwrap(), long);
+        assert_eq!(last_line(&dir.path().join("missing.log")).unwrap(), None);
+    }
+}
@@ -0,0 +1,691 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Audit log of security relevant KBS operations.
+//!
+//! Audit records are kept apart from the debug log. Every record is a single
+//! JSON object written as one line to the configured sink, chained to the
+//! previous one by its hash.
+
+use crate::tls::ClientIdentity;
+use actix_web::HttpRequest;
+use anyhow::Result;
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+mod chain;
+mod sink;
+mod webhook;
+
+use chain::{load_signing_key, Chain, DEFAULT_CHECKPOINT_INTERVAL};
+pub use chain::{verify_audit_log, AuditLogReport};
+use jwt_simple::prelude::{Ed25519KeyPair, Ed25519PublicKey};
+use sink::{AuditSink, FileSink, HttpSink, SyslogSink};
+pub use webhook::WebhookConfig;
+use webhook::{Webhook, WebhookQueue};
+
+/// Header a client can set to correlate its requests in the audit log.
+pub const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+/// Records waiting to be written to the sink, beyond which recording waits.
+const QUEUE_SIZE: usize = 1024;
+
+/// Audit log configuration.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AuditConfig {
+    #[serde(flatten)]
+    pub sink: AuditSinkConfig,
+
+    /// Ed25519 private key (PEM) signing the checkpoints of the chain.
+    /// Checkpoints are not written when omitted.
+    pub signing_key: Option<PathBuf>,
+
+    /// Seconds between two checkpoints.
+    #[serde(default = "default_checkpoint_interval")]
+    pub checkpoint_interval: u64,
+}
+
+fn default_checkpoint_interval() -> u64 {
+    DEFAULT_CHECKPOINT_INTERVAL
+}
+
+impl AuditConfig {
+    /// Public key of the checkpoint signatures, if checkpoints are written.
+    pub fn verification_key(&self) -> Result<Option<Ed25519PublicKey>> {
+        let key = self
+            .signing_key
+            .as_deref()
+            .map(load_signing_key)
+            .transpose()?;
+        Ok(key.map(|key| key.public_key()))
+    }
+}
+
+/// Audit log sink configuration.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum AuditSinkConfig {
+    /// Append records to a local file.
+    File { path: PathBuf },
+
+    /// Send records to the local syslog daemon.
+    Syslog { socket: Option<PathBuf> },
+
+    /// POST every record to an HTTP collector.
+    Http { url: String },
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventType {
+    AttestationAttempt,
+    AttestationVerdict,
+    PolicyChange,
+    ResourceAccess,
+    AdminAction,
+    #[cfg(feature = "spiffe")]
+    SvidIssuance,
+    /// The evidence of an unexpired token fails the changed attestation
+    /// policies or reference values.
+    #[cfg(feature = "as")]
+    ReattestationRequired,
+    /// A generated resource was replaced on its rotation schedule.
+    #[cfg(feature = "resource")]
+    ResourceRotation,
+    /// A download URL of a resource was minted.
+    #[cfg(feature = "resource")]
+    DownloadUrlIssuance,
+    /// The resources of a newly attested workload were provisioned.
+    #[cfg(feature = "resource")]
+    ResourceProvisioning,
+    /// An attestation results token was exchanged for a narrower token.
+    #[cfg(feature = "resource")]
+    TokenExchange,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ActorKind {
+    /// A TEE going through the attestation protocol.
+    Attester,
+    /// A user of the admin APIs.
+    Admin,
+    /// KBS itself, e.g. a scheduled task.
+    Kbs,
+}
+
+/// The principal that triggered an audited operation.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Actor {
+    pub kind: ActorKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Subject of the TLS client certificate the actor connected with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<String>,
+}
+
+impl Actor {
+    pub fn attester(id: Option<String>) -> Self {
+        Self {
+            kind: ActorKind::Attester,
+            id,
+            address: None,
+            client_certificate: None,
+        }
+    }
+
+    pub fn admin(id: Option<String>) -> Self {
+        Self {
+            kind: ActorKind::Admin,
+            id,
+            address: None,
+            client_certificate: None,
+        }
+    }
+
+    #[cfg(feature = "resource")]
+    pub fn kbs() -> Self {
+        Self {
+            kind: ActorKind::Kbs,
+            id: None,
+            address: None,
+            client_certificate: None,
+        }
+    }
+}
+
+/// A single audit record.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub event: AuditEventType,
+    pub outcome: Outcome,
+    pub correlation_id: String,
+    pub actor: Actor,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub details: Map<String, Value>,
+}
+
+impl AuditEvent {
+    /// Start a record for an operation triggered by `request`. The actor
+    /// defaults to an anonymous attester at the peer address, identified by
+    /// its TLS client certificate if it presented one.
+    pub fn new(event: AuditEventType, request: &HttpRequest) -> Self {
+        let mut record = Self::from_peer(
+            event,
+            correlation_id(request),
+            request
+                .connection_info()
+                .realip_remote_addr()
+                .map(str::to_string),
+        );
+        record.actor.client_certificate = request
+            .conn_data::<ClientIdentity>()
+            .map(|identity| identity.subject.clone());
+        if let Some(tenant) = request.match_info().get(crate::tenant::TENANT_SEGMENT) {
+            record.details.insert("tenant".to_string(), tenant.into());
+        }
+        record
+    }
+
+    /// Start a record for an operation triggered by an anonymous attester at
+    /// `address` outside of the RESTful API.
+    pub fn from_peer(
+        event: AuditEventType,
+        correlation_id: String,
+        address: Option<String>,
+    ) -> Self {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+
+        let mut actor = Actor::attester(None);
+        actor.address = address;
+
+        Self {
+            timestamp,
+            event,
+            outcome: Outcome::Success,
+            correlation_id,
+            actor,
+            details: Map::new(),
+        }
+    }
+
+    pub fn actor(mut self, actor: Actor) -> Self {
+        self.set_actor(actor);
+        self
+    }
+
+    /// Replace the actor, keeping the peer address and client certificate of
+    /// the request.
+    pub fn set_actor(&mut self, actor: Actor) {
+        self.actor = Actor {
+            address: self.actor.address.take(),
+            client_certificate: self.actor.client_certificate.take(),
+            ..actor
+        };
+    }
+
+    pub fn detail(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.set_detail(key, value);
+        self
+    }
+
+    pub fn set_detail(&mut self, key: &str, value: impl Into<Value>) {
+        self.details.insert(key.to_string(), value.into());
+    }
+
+    /// Set the outcome from the result of the audited operation. Errors are
+    /// kept as the `reason` detail.
+    pub fn result<T, E: Display>(mut self, result: &std::result::Result<T, E>) -> Self {
+        match result {
+            Ok(_) => self.outcome = Outcome::Success,
+            Err(e) => {
+                self.outcome = Outcome::Failure;
+                self.details
+                    .insert("reason".to_string(), Value::String(e.to_string()));
+            }
+        }
+        self
+    }
+}
+
+/// Use the handle of the KBS session for attestation flows so that the auth,
+/// attest and resource requests of one attester share an ID, without
+/// disclosing the session cookie. Otherwise fall back to the
+/// client provided request ID, or a fresh one, the one of the log records of
+/// the request if already set.
+pub(crate) fn correlation_id(request: &HttpRequest) -> String {
+    if let Some(correlation_id) = crate::logging::correlation_id() {
+        return correlation_id;
+    }
+
+    #[cfg(feature = "as")]
+    if let Some(cookie) = request.cookie(crate::session::KBS_SESSION_ID) {
+        return crate::session::session_handle(cookie.value());
+    }
+
+    request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// An entry of the queue of the sink writer.
+enum Entry {
+    Line(String),
+
+    /// Notified once the lines queued before are written.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Write the queued lines to `sink` one after another. A line that fails to
+/// be written is missing from the chain, which verification then reports.
+async fn write(sink: Arc<dyn AuditSink>, mut entries: mpsc::Receiver<Entry>) {
+    while let Some(entry) = entries.recv().await {
+        match entry {
+            Entry::Line(line) => {
+                if let Err(e) = sink.write(&line).await {
+                    error!("Failed to write audit record: {e:?}");
+                }
+            }
+            Entry::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Handle to the audit sink and webhooks shared by all HTTP workers.
+/// Auditing is disabled when no sink is configured.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    /// Queue of the task writing to the sink.
+    writer: Option<mpsc::Sender<Entry>>,
+
+    /// Head of the chain. Held while a record is linked and queued, so that
+    /// the records are written in the order they are chained, but not while
+    /// it is written.
+    chain: Arc<Mutex<Chain>>,
+    signing_key: Option<Arc<Ed25519KeyPair>>,
+
+    /// Path of the `File` sink.
+    path: Option<PathBuf>,
+    webhooks: Vec<Arc<WebhookQueue>>,
+}
+
+impl AuditLog {
+    pub async fn new(config: Option<&AuditConfig>, webhooks: &[WebhookConfig]) -> Result<Self> {
+        let webhooks = webhooks
+            .iter()
+            .map(|config| Ok(Arc::new(WebhookQueue::new(Webhook::new(config)?))))
+            .collect::<Result<_>>()?;
+        let Some(config) = config else {
+            return Ok(Self {
+                webhooks,
+                ..Default::default()
+            });
+        };
+
+        let mut chain = Chain::default();
+        let mut path = None;
+        let sink: Arc<dyn AuditSink> = match &config.sink {
+            AuditSinkConfig::File { path: file } => {
+                // The chain of the file is continued across restarts.
+                chain = Chain::resume(file)?;
+                path = Some(file.clone());
+                Arc::new(FileSink::new(file).await?)
+            }
+            AuditSinkConfig::Syslog { socket } => Arc::new(SyslogSink::new(socket.as_deref())?),
+            AuditSinkConfig::Http { url } => Arc::new(HttpSink::new(url)?),
+        };
+        let (writer, entries) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(write(sink, entries));
+        let signing_key = config
+            .signing_key
+            .as_deref()
+            .map(load_signing_key)
+            .transpose()?
+            .map(Arc::new);
+
+        let audit = Self {
+            writer: Some(writer),
+            chain: Arc::new(Mutex::new(chain)),
+            signing_key,
+            path,
+            webhooks,
+        };
+        if audit.signing_key.is_some() {
+            let interval = Duration::from_secs(config.checkpoint_interval.max(1));
+            let audit = audit.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    audit.checkpoint().await;
+                }
+            });
+        }
+
+        Ok(audit)
+    }
+
+    /// Whether the records are written to a sink or delivered to webhooks.
+    #[cfg(feature = "as")]
+    pub fn is_enabled(&self) -> bool {
+        self.writer.is_some() || !self.webhooks.is_empty()
+    }
+
+    /// Path of the audit log, if it is written to a file.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Public key of the checkpoint signatures, if checkpoints are written.
+    pub fn verification_key(&self) -> Option<Ed25519PublicKey> {
+        self.signing_key.as_ref().map(|key| key.public_key())
+    }
+
+    /// Sign the head of the chain, unless no record was written since the
+    /// last checkpoint.
+    pub async fn checkpoint(&self) {
+        let (Some(writer), Some(signing_key)) = (&self.writer, &self.signing_key) else {
+            return;
+        };
+        let Ok(slot) = writer.reserve().await else {
+            return;
+        };
+
+        let mut chain = self.chain.lock().await;
+        if chain.unsigned == 0 {
+            return;
+        }
+        let result = chain.checkpoint(signing_key).and_then(|checkpoint| {
+            let line = chain.link(&checkpoint)?;
+            chain.advance(&line, true)?;
+            Ok(line)
+        });
+        match result {
+            Ok(line) => slot.send(Entry::Line(line)),
+            Err(e) => error!("Failed to write audit checkpoint: {e:?}"),
+        }
+    }
+
+    /// Wait until the records recorded so far are written to the sink.
+    pub async fn flush(&self) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let (done, written) = oneshot::channel();
+        if writer.send(Entry::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    /// Write `event` to the sink and notify the webhooks subscribed to it. A
+    /// failing sink or webhook must not break the request being audited, so
+    /// errors only end up in the debug log.
+    pub async fn record(&self, event: AuditEvent) {
+        for webhook in &self.webhooks {
+            webhook.notify(&event);
+        }
+
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        // The slot is reserved before the chain is locked, so that a full
+        // queue doesn't hold up the other records.
+        let Ok(slot) = writer.reserve().await else {
+            error!("Failed to write audit event: the audit writer stopped");
+            return;
+        };
+
+        let mut chain = self.chain.lock().await;
+        let line = match chain.link(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize audit event: {e}");
+                return;
+            }
+        };
+        if let Err(e) = chain.advance(&line, false) {
+            error!("Failed to chain audit event: {e:?}");
+            return;
+        }
+        slot.send(Entry::Line(line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    fn file_config(path: &Path, signing_key: Option<PathBuf>) -> AuditConfig {
+        AuditConfig {
+            sink: AuditSinkConfig::File {
+                path: path.to_path_buf(),
+            },
+            signing_key,
+            checkpoint_interval: 3600,
+        }
+    }
+
+    #[test]
+    fn test_audit_config() {
+        let parse = |toml: &str| {
+            config::Config::builder()
+                .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+                .build()
+                .unwrap()
+                .try_deserialize::<AuditConfig>()
+        };
+
+        let config = parse("type = \"Http\"\nurl = \"http://127.0.0.1\"").unwrap();
+        assert_eq!(
+            config,
+            AuditConfig {
+                sink: AuditSinkConfig::Http {
+                    url: "http://127.0.0.1".into()
+                },
+                signing_key: None,
+                checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            }
+        );
+
+        let config = parse(
+            "type = \"File\"\npath = \"/var/log/kbs\"\nsigning_key = \"/etc/kbs/audit.key\"\ncheckpoint_interval = 10",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            AuditConfig {
+                checkpoint_interval: 10,
+                ..file_config(Path::new("/var/log/kbs"), Some("/etc/kbs/audit.key".into()))
+            }
+        );
+        assert!(parse("type = \"Other\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_audit_file_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let audit = AuditLog::new(Some(&file_config(&path, None)), &[])
+            .await
+            .unwrap();
+
+        let request = TestRequest::default()
+            .insert_header((REQUEST_ID_HEADER, "req-1"))
+            .to_http_request();
+        audit
+            .record(
+                AuditEvent::new(AuditEventType::PolicyChange, &request)
+                    .actor(Actor::admin(Some("alice".into())))
+                    .detail("policy_id", "default")
+                    .result(&Err::<(), _>("denied")),
+            )
+            .await;
+        audit
+            .record(AuditEvent::new(AuditEventType::ResourceAccess, &request))
+            .await;
+        audit.flush().await;
+
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        let lines: Vec<Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "policy_change");
+        assert_eq!(lines[0]["outcome"], "failure");
+        assert_eq!(lines[0]["correlation_id"], "req-1");
+        assert_eq!(lines[0]["actor"], json!({"kind": "admin", "id": "alice"}));
+        assert_eq!(
+            lines[0]["details"],
+            json!({"policy_id": "default", "reason": "denied"})
+        );
+        assert_eq!(lines[1]["outcome"], "success");
+        assert!(lines[1].get("details").is_none());
+    }
+
+    #[test]
+    fn test_set_actor() {
+        let mut event = AuditEvent::from_peer(
+            AuditEventType::PolicyChange,
+            "req-1".into(),
+            Some("10.0.0.1".into()),
+        );
+        event.actor.client_certificate = Some("CN=admin".into());
+
+        // The peer of the request is kept with the authenticated actor.
+        event.set_actor(Actor::admin(Some("alice".into())));
+        assert_eq!(
+            serde_json::to_value(&event.actor).unwrap(),
+            json!({
+                "kind": "admin",
+                "id": "alice",
+                "address": "10.0.0.1",
+                "client_certificate": "CN=admin",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit_chain() {
+        use jwt_simple::prelude::Ed25519KeyPair;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let key = Ed25519KeyPair::generate();
+        let key_path = dir.path().join("audit.key");
+        std::fs::write(&key_path, key.to_pem()).unwrap();
+        let request = TestRequest::default().to_http_request();
+        let event = || AuditEvent::new(AuditEventType::ResourceAccess, &request);
+
+        // Written before the log was chained.
+        std::fs::write(&path, "{\"event\":\"resource_access\"}\n").unwrap();
+        let audit = AuditLog::new(Some(&file_config(&path, Some(key_path.clone()))), &[])
+            .await
+            .unwrap();
+        audit.record(event()).await;
+        audit.checkpoint().await;
+        // Nothing to sign.
+        audit.checkpoint().await;
+        audit.record(event()).await;
+        audit.flush().await;
+
+        // The chain of the file is continued.
+        let audit = AuditLog::new(Some(&file_config(&path, Some(key_path))), &[])
+            .await
+            .unwrap();
+        audit.record(event()).await;
+        audit.checkpoint().await;
+        audit.flush().await;
+
+        let key = audit.verification_key().unwrap();
+        let report = verify_audit_log(&path, Some(&key)).await.unwrap();
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.unchained_records, 1);
+        assert_eq!(report.records, 5);
+        assert_eq!(report.checkpoints, 2);
+        assert_eq!(report.unsigned_records, 0);
+
+        let report = verify_audit_log(&path, None).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.checkpoints, 0);
+        assert_eq!(report.unsigned_records, 5);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        let verify = |lines: &[&str]| {
+            let path = dir.path().join("tampered.log");
+            std::fs::write(&path, lines.join("\n")).unwrap();
+            let key = key.clone();
+            async move { verify_audit_log(&path, Some(&key)).await.unwrap() }
+        };
+
+        let mut modified = lines.clone();
+        let record = modified[3].replace("resource_access", "admin_action");
+        modified[3] = &record;
+        let report = verify(&modified).await;
+        assert_eq!(
+            report.error.unwrap(),
+            "line 5: record does not follow the previous one, the log was modified"
+        );
+        assert_eq!(report.records, 3);
+
+        let mut removed = lines.clone();
+        removed.remove(3);
+        assert!(verify(&removed).await.error.is_some());
+
+        // Cutting the end of the log leaves unsigned records.
+        let report = verify(&lines[..5]).await;
+        assert!(report.is_ok());
+        assert_eq!(report.unsigned_records, 2);
+
+        // A new chain after the first record, as left by replacing the log.
+        let restarted_path = dir.path().join("restarted.log");
+        let restarted = AuditLog::new(Some(&file_config(&restarted_path, None)), &[])
+            .await
+            .unwrap();
+        restarted.record(event()).await;
+        restarted.flush().await;
+        let restarted = std::fs::read_to_string(&restarted_path).unwrap();
+        let mut appended = lines.clone();
+        appended.push(restarted.trim_end());
+        assert!(verify(&appended)
+            .await
+            .error
+            .unwrap()
+            .contains("starts a new chain"));
+
+        let other = Ed25519KeyPair::generate().public_key();
+        let report = verify_audit_log(&path, Some(&other)).await.unwrap();
+        assert!(report
+            .error
+            .unwrap()
+            .contains("invalid checkpoint signature"));
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use std::path::Path;
+use std::time::Duration;
+use tokio::{fs::File, io::AsyncWriteExt, net::UnixDatagram, sync::Mutex};
+
+const DEFAULT_SYSLOG_SOCKET: &str = "/dev/log";
+
+/// LOG_AUTHPRIV | LOG_INFO
+const SYSLOG_PRIORITY: u8 = 10 << 3 | 6;
+
+/// Time an audit collector has to accept a record.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[async_trait]
+pub(crate) trait AuditSink: Send + Sync {
+    /// Write one serialized audit record.
+    async fn write(&self, record: &str) -> Result<()>;
+}
+
+pub(crate) struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub async fn new(path: &Path) -> Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("open audit log {}", path.display()))?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+#[async_trait]
+impl AuditSink for FileSink {
+    async fn write(&self, record: &str) -> Result<()> {
+        let mut file = self.file.lock().await;
+        file.write_all(format!("{record}\n").as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+pub(crate) struct SyslogSink {
+    socket: UnixDatagram,
+}
+
+impl SyslogSink {
+    pub fn new(path: Option<&Path>) -> Result<Self> {
+        let path = path.unwrap_or(Path::new(DEFAULT_SYSLOG_SOCKET));
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(path)
+            .with_context(|| format!("connect to syslog socket {}", path.display()))?;
+
+        Ok(Self { socket })
+    }
+}
+
+#[async_trait]
+impl AuditSink for SyslogSink {
+    async fn write(&self, record: &str) -> Result<()> {
+        let message = format!("<{SYSLOG_PRIORITY}>kbs[{}]: {record}", std::process::id());
+        self.socket.send(message.as_bytes()).await?;
+        Ok(())
+    }
+}
+
+pub(crate) struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpSink {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?,
+            url: url.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl AuditSink for HttpSink {
+    async fn write(&self, record: &str) -> Result<()> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(record.to_string())
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            bail!("audit collector returned {}", response.status());
+        }
+
+        Ok(())
+    }
+}
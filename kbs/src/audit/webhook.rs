@@ -0,0 +1,312 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Webhook notifications of audit events.
+//!
+//! Every webhook receives the events it subscribed to as a JSON POST, signed
+//! with HMAC-SHA256 under its secret so that the receiver can tell the events
+//! come from KBS.
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use strum::AsRefStr;
+use tokio::sync::mpsc;
+use zeroize::Zeroizing;
+
+use super::{AuditEvent, AuditEventType, Outcome};
+use crate::crypto;
+
+/// Header with the `sha256=<hex>` HMAC of the request body.
+pub const SIGNATURE_HEADER: &str = "X-KBS-Signature";
+
+/// Header with the type of the delivered event.
+pub const EVENT_HEADER: &str = "X-KBS-Event";
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_ATTEMPTS: u32 = 3;
+
+/// Deliveries waiting to be sent to a webhook, beyond which new ones are
+/// dropped.
+const QUEUE_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, AsRefStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum WebhookEvent {
+    AttestationSuccess,
+    AttestationFailure,
+    PolicyChange,
+    ResourceDenied,
+    ReattestationRequired,
+    ResourceRotated,
+    WorkloadProvisioned,
+}
+
+impl WebhookEvent {
+    const ALL: [WebhookEvent; 7] = [
+        WebhookEvent::AttestationSuccess,
+        WebhookEvent::AttestationFailure,
+        WebhookEvent::PolicyChange,
+        WebhookEvent::ResourceDenied,
+        WebhookEvent::ReattestationRequired,
+        WebhookEvent::ResourceRotated,
+        WebhookEvent::WorkloadProvisioned,
+    ];
+
+    /// The webhook event of the audit `event`, if any.
+    fn of(event: &AuditEvent) -> Option<Self> {
+        match (event.event, event.outcome) {
+            (AuditEventType::AttestationVerdict, Outcome::Success) => {
+                Some(Self::AttestationSuccess)
+            }
+            (AuditEventType::AttestationVerdict, Outcome::Failure) => {
+                Some(Self::AttestationFailure)
+            }
+            (AuditEventType::PolicyChange, Outcome::Success) => Some(Self::PolicyChange),
+            (AuditEventType::ResourceAccess, Outcome::Failure) => Some(Self::ResourceDenied),
+            #[cfg(feature = "as")]
+            (AuditEventType::ReattestationRequired, _) => Some(Self::ReattestationRequired),
+            #[cfg(feature = "resource")]
+            (AuditEventType::ResourceRotation, Outcome::Success) => Some(Self::ResourceRotated),
+            #[cfg(feature = "resource")]
+            (AuditEventType::ResourceProvisioning, Outcome::Success) => {
+                Some(Self::WorkloadProvisioned)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn default_events() -> Vec<WebhookEvent> {
+    WebhookEvent::ALL.to_vec()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct WebhookConfig {
+    /// URL the events are POSTed to.
+    pub url: String,
+
+    /// File with the HMAC key the events are signed with.
+    pub secret_path: PathBuf,
+
+    /// Events to deliver. All of them by default.
+    #[serde(default = "default_events")]
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Serialize)]
+struct Delivery<'a> {
+    #[serde(rename = "type")]
+    kind: WebhookEvent,
+    event: &'a AuditEvent,
+}
+
+pub(crate) struct Webhook {
+    client: reqwest::Client,
+    url: String,
+    secret: Zeroizing<Vec<u8>>,
+    events: Vec<WebhookEvent>,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig) -> Result<Self> {
+        let secret =
+            Zeroizing::new(std::fs::read(&config.secret_path).with_context(|| {
+                format!("read webhook secret {}", config.secret_path.display())
+            })?);
+        if secret.is_empty() {
+            bail!("webhook secret {} is empty", config.secret_path.display());
+        }
+
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(DELIVERY_TIMEOUT)
+                .build()?,
+            url: config.url.clone(),
+            secret,
+            events: config.events.clone(),
+        })
+    }
+
+    fn sign(&self, body: &[u8]) -> Result<String> {
+        let mac = crypto::hmac_sha256(&self.secret, body)?;
+        Ok(format!("sha256={}", hex::encode(mac)))
+    }
+
+    /// The body to deliver for `event`, unless the webhook did not
+    /// subscribe to it.
+    pub fn payload(&self, event: &AuditEvent) -> Result<Option<(WebhookEvent, Vec<u8>)>> {
+        let Some(kind) = WebhookEvent::of(event).filter(|kind| self.events.contains(kind)) else {
+            return Ok(None);
+        };
+
+        let body = serde_json::to_vec(&Delivery { kind, event })?;
+        Ok(Some((kind, body)))
+    }
+
+    /// POST `body`, retrying failed deliveries with a growing delay.
+    pub async fn deliver(&self, kind: WebhookEvent, body: Vec<u8>) {
+        let kind = kind.as_ref();
+        let signature = match self.sign(&body) {
+            Ok(signature) => signature,
+            Err(e) => {
+                warn!("Dropping {kind} event for webhook {}: {e:#}", self.url);
+                return;
+            }
+        };
+
+        for attempt in 1..=DELIVERY_ATTEMPTS {
+            let result = self
+                .client
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .header(EVENT_HEADER, kind)
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => return,
+                Err(e) if attempt < DELIVERY_ATTEMPTS => {
+                    warn!(
+                        "Webhook {} delivery attempt {attempt} failed: {e}",
+                        self.url
+                    );
+                    tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                }
+                Err(e) => warn!("Dropping {kind} event for webhook {}: {e}", self.url),
+            }
+        }
+    }
+}
+
+/// Webhook with a background task delivering its events one after another.
+pub(crate) struct WebhookQueue {
+    webhook: Arc<Webhook>,
+    queue: mpsc::Sender<(WebhookEvent, Vec<u8>)>,
+}
+
+impl WebhookQueue {
+    pub fn new(webhook: Webhook) -> Self {
+        let webhook = Arc::new(webhook);
+        let (queue, mut deliveries) = mpsc::channel::<(WebhookEvent, Vec<u8>)>(QUEUE_SIZE);
+        let worker = webhook.clone();
+        tokio::spawn(async move {
+            while let Some((kind, body)) = deliveries.recv().await {
+                worker.deliver(kind, body).await;
+            }
+        });
+
+        Self { webhook, queue }
+    }
+
+    /// Queue `event` for delivery if the webhook subscribed to it. Events
+    /// the webhook can't keep up with are dropped with a warning in the log.
+    pub fn notify(&self, event: &AuditEvent) {
+        match self.webhook.payload(event) {
+            Ok(Some((kind, body))) => {
+                if let Err(e) = self.queue.try_send((kind, body)) {
+                    warn!(
+                        "Dropping {} event for webhook {}: {e}",
+                        kind.as_ref(),
+                        self.webhook.url
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to serialize webhook event: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use rstest::rstest;
+
+    fn webhook(events: Vec<WebhookEvent>) -> Webhook {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_path = dir.path().join("secret");
+        std::fs::write(&secret_path, "key").unwrap();
+        Webhook::new(&WebhookConfig {
+            url: "http://127.0.0.1:1/".into(),
+            secret_path,
+            events,
+        })
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case(AuditEventType::AttestationVerdict, Ok(()), Some(WebhookEvent::AttestationSuccess))]
+    #[case(
+        AuditEventType::AttestationVerdict,
+        Err("rejected"),
+        Some(WebhookEvent::AttestationFailure)
+    )]
+    #[case(AuditEventType::PolicyChange, Ok(()), Some(WebhookEvent::PolicyChange))]
+    #[case(
+        AuditEventType::ResourceAccess,
+        Err("denied"),
+        Some(WebhookEvent::ResourceDenied)
+    )]
+    #[case(AuditEventType::ResourceAccess, Ok(()), None)]
+    #[case(AuditEventType::AttestationAttempt, Ok(()), None)]
+    fn test_webhook_event(
+        #[case] event: AuditEventType,
+        #[case] result: std::result::Result<(), &str>,
+        #[case] expected: Option<WebhookEvent>,
+    ) {
+        let request = TestRequest::default().to_http_request();
+        let event = AuditEvent::new(event, &request).result(&result);
+        assert_eq!(WebhookEvent::of(&event), expected);
+    }
+
+    #[test]
+    fn test_payload() {
+        let request = TestRequest::default().to_http_request();
+        let event = AuditEvent::new(AuditEventType::ResourceAccess, &request)
+            .result(&Err::<(), _>("denied"));
+
+        let webhook = self::webhook(vec![WebhookEvent::AttestationFailure]);
+        assert!(webhook.payload(&event).unwrap().is_none());
+
+        let webhook = self::webhook(default_events());
+        let (kind, body) = webhook.payload(&event).unwrap().unwrap();
+        assert_eq!(kind, WebhookEvent::ResourceDenied);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["type"], "resource_denied");
+        assert_eq!(body["event"]["details"]["reason"], "denied");
+    }
+
+    #[test]
+    fn test_sign() {
+        // HMAC-SHA256 test vector of RFC 4231, test case 2.
+        let mut webhook = webhook(default_events());
+        webhook.secret = Zeroizing::new(b"Jefe".to_vec());
+        assert_eq!(
+            webhook.sign(b"what do ya want for nothing?").unwrap(),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_empty_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_path = dir.path().join("secret");
+        std::fs::write(&secret_path, "").unwrap();
+        assert!(Webhook::new(&WebhookConfig {
+            url: "http://127.0.0.1:1/".into(),
+            secret_path,
+            events: default_events(),
+        })
+        .is_err());
+    }
+}
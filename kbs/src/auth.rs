@@ -5,21 +5,421 @@
 use actix_web::http::header::Header;
 use actix_web::HttpRequest;
 use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
-use anyhow::{Context, Result};
-use jwt_simple::prelude::{
-    Ed25519PublicKey, EdDSAPublicKeyLike, NoCustomClaims, VerificationOptions,
-};
+use anyhow::{anyhow, bail, Context, Result};
+use ipnet::IpNet;
+use jwt_simple::prelude::{Ed25519PublicKey, EdDSAPublicKeyLike, JWTClaims, VerificationOptions};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
 
-pub(crate) fn validate_auth(request: &HttpRequest, public_key: &Ed25519PublicKey) -> Result<()> {
+/// A public key trusted to sign admin tokens.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AdminKeyConfig {
+    /// Name of the key, recorded in the audit log.
+    pub name: String,
+
+    /// Ed25519 public key (PEM format) file path.
+    pub public_key: PathBuf,
+
+    /// RFC 3339 time from which tokens signed by the key are rejected.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+
+    /// Roles granted to the tokens signed by the key without a `roles`
+    /// claim. Every role by default, as before tokens had roles, so that the
+    /// tokens issued then keep working. Empty to reject such tokens.
+    #[serde(default = "default_roles")]
+    pub default_roles: Vec<Role>,
+}
+
+fn default_roles() -> Vec<Role> {
+    Role::ALL.to_vec()
+}
+
+pub(crate) struct AdminKey {
+    pub name: String,
+    key: Ed25519PublicKey,
+    expires_at: Option<OffsetDateTime>,
+    default_roles: Vec<Role>,
+}
+
+impl AdminKey {
+    pub fn new(
+        name: String,
+        key: Ed25519PublicKey,
+        expires_at: Option<OffsetDateTime>,
+        default_roles: Vec<Role>,
+    ) -> Self {
+        Self {
+            name,
+            key,
+            expires_at,
+            default_roles,
+        }
+    }
+
+    pub async fn load(config: &AdminKeyConfig) -> Result<Self> {
+        let pem = tokio::fs::read_to_string(&config.public_key)
+            .await
+            .with_context(|| format!("read admin key {}", config.name))?;
+        let key = Ed25519PublicKey::from_pem(&pem)
+            .with_context(|| format!("parse admin key {}", config.name))?;
+
+        Ok(Self::new(
+            config.name.clone(),
+            key,
+            config.expires_at,
+            config.default_roles.clone(),
+        ))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= OffsetDateTime::now_utc())
+    }
+
+    /// Whether the token signed by the key with `claims` grants
+    /// `permission`, through its roles or the default roles of the key.
+    pub fn grants(&self, claims: &AdminClaims, permission: Permission) -> bool {
+        claims
+            .roles
+            .as_deref()
+            .unwrap_or(&self.default_roles)
+            .iter()
+            .any(|role| role.grants(permission))
+    }
+}
+
+/// Load the keys trusted to sign admin tokens: the `user_public_key` and the
+/// `configs` keys. No key is needed with insecure APIs enabled.
+pub(crate) async fn load_admin_keys(
+    insecure_api: bool,
+    user_public_key: Option<&Path>,
+    configs: &[AdminKeyConfig],
+) -> Result<Vec<AdminKey>> {
+    let mut admin_keys = Vec::new();
+    if insecure_api {
+        return Ok(admin_keys);
+    }
+
+    if let Some(key_path) = user_public_key {
+        let user_public_key_pem = tokio::fs::read_to_string(key_path)
+            .await
+            .context("read user public key")?;
+        let key =
+            Ed25519PublicKey::from_pem(&user_public_key_pem).context("parse user public key")?;
+        admin_keys.push(AdminKey::new(
+            "default".to_string(),
+            key,
+            None,
+            default_roles(),
+        ));
+    }
+    for config in configs {
+        let key = AdminKey::load(config).await?;
+        if key.is_expired() {
+            log::warn!("Admin key {} is expired", key.name);
+        }
+        admin_keys.push(key);
+    }
+    if admin_keys.is_empty() {
+        bail!("no user public key given");
+    }
+
+    Ok(admin_keys)
+}
+
+/// Networks the admin APIs can be called from, as defense in depth when KBS is
+/// exposed publicly for attestation. Any client is allowed when empty.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct AdminAllowlist(Vec<IpNet>);
+
+impl AdminAllowlist {
+    /// Parse `networks` of CIDR ranges, e.g. `10.0.0.0/8`, or single IP
+    /// addresses.
+    pub fn new(networks: &[String]) -> Result<Self> {
+        let networks = networks
+            .iter()
+            .map(|network| {
+                network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                    .with_context(|| format!("invalid admin network {network}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(networks))
+    }
+
+    /// Whether a client at `address` may call the admin APIs. Clients without
+    /// an IP address, i.e. on unix sockets, are only allowed without an
+    /// allowlist.
+    pub fn allows(&self, address: Option<IpAddr>) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+        let Some(address) = address else {
+            return false;
+        };
+
+        let address = address.to_canonical();
+        self.0.iter().any(|network| network.contains(&address))
+    }
+}
+
+/// Roles an admin token grants through its `roles` claim.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Reads and sets the attestation and resource policies.
+    PolicyAdmin,
+
+    /// Writes resources and mints their download URLs.
+    ResourceAdmin,
+
+    /// Reads the policies, can't change anything.
+    Auditor,
+
+    /// Reloads the KBS configuration.
+    ConfigAdmin,
+
+    /// Lists and terminates the attestation sessions, and revokes the
+    /// attestation results tokens.
+    SessionAdmin,
+
+    /// Checks attestation results tokens through the introspection API.
+    RelyingParty,
+}
+
+/// An operation on the admin APIs.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Permission {
+    ReadPolicy,
+    WritePolicy,
+    WriteResource,
+    DelegateResource,
+    ReadResourceUsage,
+    ReloadConfig,
+    ReadAuditLog,
+    ReadSessions,
+    TerminateSession,
+    RevokeToken,
+    IntrospectToken,
+}
+
+impl Role {
+    pub const ALL: [Role; 6] = [
+        Role::PolicyAdmin,
+        Role::ResourceAdmin,
+        Role::Auditor,
+        Role::ConfigAdmin,
+        Role::SessionAdmin,
+        Role::RelyingParty,
+    ];
+
+    pub fn grants(&self, permission: Permission) -> bool {
+        match self {
+            Role::PolicyAdmin => {
+                matches!(permission, Permission::ReadPolicy | Permission::WritePolicy)
+            }
+            Role::ResourceAdmin => {
+                matches!(
+                    permission,
+                    Permission::WriteResource
+                        | Permission::DelegateResource
+                        | Permission::ReadResourceUsage
+                )
+            }
+            Role::Auditor => {
+                matches!(
+                    permission,
+                    Permission::ReadPolicy
+                        | Permission::ReadAuditLog
+                        | Permission::ReadSessions
+                        | Permission::ReadResourceUsage
+                )
+            }
+            Role::ConfigAdmin => permission == Permission::ReloadConfig,
+            Role::SessionAdmin => {
+                matches!(
+                    permission,
+                    Permission::ReadSessions
+                        | Permission::TerminateSession
+                        | Permission::RevokeToken
+                )
+            }
+            Role::RelyingParty => permission == Permission::IntrospectToken,
+        }
+    }
+}
+
+/// Custom claims of an admin token.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct AdminClaims {
+    /// Roles of the token, the default roles of its key when omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<Role>>,
+}
+
+/// Verify the admin token of `request` against the unexpired `keys`. Returns
+/// the key that signed the token along with its claims.
+pub(crate) fn validate_auth<'a>(
+    request: &HttpRequest,
+    keys: &'a [AdminKey],
+) -> Result<(&'a AdminKey, JWTClaims<AdminClaims>)> {
     let bearer = Authorization::<Bearer>::parse(request)
         .context("parse Authorization header failed")?
         .into_scheme();
 
     let token = bearer.token();
 
-    let _claims = public_key
-        .verify_token::<NoCustomClaims>(token, Some(VerificationOptions::default()))
-        .context("token verification failed")?;
+    let mut error = anyhow!("no unexpired admin key");
+    for key in keys.iter().filter(|key| !key.is_expired()) {
+        match key
+            .key
+            .verify_token::<AdminClaims>(token, Some(VerificationOptions::default()))
+        {
+            Ok(claims) => return Ok((key, claims)),
+            Err(e) => error = e,
+        }
+    }
+
+    Err(error).context("token verification failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use jwt_simple::prelude::{Claims, Duration, Ed25519KeyPair, EdDSAKeyPairLike};
+    use rstest::rstest;
+
+    fn request(key_pair: &Ed25519KeyPair) -> HttpRequest {
+        let claims = Claims::with_custom_claims(AdminClaims::default(), Duration::from_mins(5));
+        let token = key_pair.sign(claims).unwrap();
+        TestRequest::default()
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .to_http_request()
+    }
+
+    #[test]
+    fn test_validate_auth() {
+        let old = Ed25519KeyPair::generate();
+        let new = Ed25519KeyPair::generate();
+        let untrusted = Ed25519KeyPair::generate();
+
+        let expired = OffsetDateTime::now_utc() - time::Duration::hours(1);
+        let keys = [
+            AdminKey::new("old".into(), old.public_key(), Some(expired), Vec::new()),
+            AdminKey::new("new".into(), new.public_key(), None, Vec::new()),
+        ];
+
+        let (key, _) = validate_auth(&request(&new), &keys).unwrap();
+        assert_eq!(key.name, "new");
+
+        assert!(validate_auth(&request(&old), &keys).is_err());
+        assert!(validate_auth(&request(&untrusted), &keys).is_err());
+    }
+
+    #[rstest]
+    #[case(&[], None, true)]
+    #[case(&["10.0.0.0/8"], Some("10.1.2.3"), true)]
+    #[case(&["10.0.0.0/8"], Some("192.168.1.1"), false)]
+    #[case(&["192.168.1.1"], Some("192.168.1.1"), true)]
+    #[case(&["192.168.1.1"], Some("::ffff:192.168.1.1"), true)]
+    #[case(&["fd00::/8"], Some("fd00::1"), true)]
+    #[case(&["10.0.0.0/8"], None, false)]
+    fn test_admin_allowlist(
+        #[case] networks: &[&str],
+        #[case] address: Option<&str>,
+        #[case] allowed: bool,
+    ) {
+        let networks: Vec<String> = networks.iter().map(|n| n.to_string()).collect();
+        let allowlist = AdminAllowlist::new(&networks).unwrap();
+        assert_eq!(
+            allowlist.allows(address.map(|a| a.parse().unwrap())),
+            allowed
+        );
+    }
+
+    #[test]
+    fn test_invalid_admin_network() {
+        assert!(AdminAllowlist::new(&["10.0.0.0/33".to_string()]).is_err());
+    }
+
+    #[rstest]
+    #[case(r#"{"roles": ["policy-admin"]}"#, Permission::WritePolicy, true)]
+    #[case(r#"{"roles": ["policy-admin"]}"#, Permission::WriteResource, false)]
+    #[case(r#"{"roles": ["resource-admin"]}"#, Permission::WriteResource, true)]
+    #[case(r#"{"roles": ["resource-admin"]}"#, Permission::ReadPolicy, false)]
+    #[case(r#"{"roles": ["resource-admin"]}"#, Permission::DelegateResource, true)]
+    #[case(r#"{"roles": ["auditor"]}"#, Permission::DelegateResource, false)]
+    #[case(
+        r#"{"roles": ["resource-admin"]}"#,
+        Permission::ReadResourceUsage,
+        true
+    )]
+    #[case(r#"{"roles": ["auditor"]}"#, Permission::ReadResourceUsage, true)]
+    #[case(r#"{"roles": ["auditor"]}"#, Permission::WriteResource, false)]
+    #[case(
+        r#"{"roles": ["session-admin"]}"#,
+        Permission::ReadResourceUsage,
+        false
+    )]
+    #[case(r#"{"roles": ["auditor"]}"#, Permission::ReadPolicy, true)]
+    #[case(r#"{"roles": ["auditor"]}"#, Permission::WritePolicy, false)]
+    #[case(r#"{"roles": ["config-admin"]}"#, Permission::ReloadConfig, true)]
+    #[case(r#"{"roles": ["policy-admin"]}"#, Permission::ReloadConfig, false)]
+    #[case(r#"{"roles": ["auditor"]}"#, Permission::ReadAuditLog, true)]
+    #[case(r#"{"roles": ["config-admin"]}"#, Permission::ReadAuditLog, false)]
+    #[case(r#"{"roles": ["session-admin"]}"#, Permission::TerminateSession, true)]
+    #[case(r#"{"roles": ["auditor"]}"#, Permission::ReadSessions, true)]
+    #[case(r#"{"roles": ["auditor"]}"#, Permission::TerminateSession, false)]
+    #[case(r#"{"roles": ["session-admin"]}"#, Permission::RevokeToken, true)]
+    #[case(r#"{"roles": ["relying-party"]}"#, Permission::RevokeToken, false)]
+    #[case(r#"{"roles": ["relying-party"]}"#, Permission::IntrospectToken, true)]
+    #[case(r#"{"roles": ["relying-party"]}"#, Permission::ReadPolicy, false)]
+    #[case(
+        r#"{"roles": ["auditor", "resource-admin"]}"#,
+        Permission::WriteResource,
+        true
+    )]
+    #[case(r#"{"roles": []}"#, Permission::ReadPolicy, false)]
+    fn test_grants(#[case] claims: &str, #[case] permission: Permission, #[case] expected: bool) {
+        let claims: AdminClaims = serde_json::from_str(claims).unwrap();
+        let key = AdminKey::new(
+            "key".into(),
+            Ed25519KeyPair::generate().public_key(),
+            None,
+            default_roles(),
+        );
+        assert_eq!(key.grants(&claims, permission), expected);
+    }
 
-    Ok(())
+    #[rstest]
+    #[case(None, Permission::WritePolicy, true)]
+    #[case(None, Permission::TerminateSession, true)]
+    #[case(Some(r#"[]"#), Permission::ReadPolicy, false)]
+    #[case(Some(r#"["auditor"]"#), Permission::ReadPolicy, true)]
+    #[case(Some(r#"["auditor"]"#), Permission::WritePolicy, false)]
+    fn test_default_roles(
+        #[case] default_roles: Option<&str>,
+        #[case] permission: Permission,
+        #[case] expected: bool,
+    ) {
+        let mut config = serde_json::json!({"name": "key", "public_key": "key.pem"});
+        if let Some(default_roles) = default_roles {
+            config["default_roles"] = serde_json::from_str(default_roles).unwrap();
+        }
+        let config: AdminKeyConfig = serde_json::from_value(config).unwrap();
+        let key = AdminKey::new(
+            config.name,
+            Ed25519KeyPair::generate().public_key(),
+            None,
+            config.default_roles,
+        );
+        assert_eq!(key.grants(&AdminClaims::default(), permission), expected);
+    }
 }
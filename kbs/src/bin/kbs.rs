@@ -7,11 +7,11 @@
 extern crate anyhow;
 
 use anyhow::{bail, Result};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
 #[cfg(feature = "as")]
-use kbs::attestation::AttestationService;
+use kbs::attestation::{AttestationService, BackendRegistry};
 use kbs::{
     config::{Cli, KbsConfig},
     ApiServer,
@@ -20,18 +20,51 @@ use log::{debug, info, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-
     let cli = Cli::parse();
+    kbs::logging::init(cli.log_format);
+
+    if cli.check_config {
+        let report = kbs::check::check_config_file(
+            Path::new(&cli.config_file),
+            #[cfg(feature = "as")]
+            &BackendRegistry::default(),
+        )
+        .await;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(audit_log) = &cli.verify_audit_log {
+        let kbs_config = KbsConfig::try_from(Path::new(&cli.config_file))?;
+        let key = match &kbs_config.audit_config {
+            Some(audit_config) => audit_config.verification_key()?,
+            None => None,
+        };
+        let report = kbs::verify_audit_log(audit_log, key.as_ref()).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if !report.is_ok() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     info!("Using config file {}", cli.config_file);
     let kbs_config = KbsConfig::try_from(Path::new(&cli.config_file))?;
 
     debug!("Config: {:#?}", kbs_config);
 
-    if !kbs_config.insecure_http
-        && (kbs_config.private_key.is_none() || kbs_config.certificate.is_none())
+    kbs::crypto::init(kbs_config.fips)?;
+
+    #[allow(unused_mut)]
+    let mut has_credentials = kbs_config.private_key.is_some() && kbs_config.certificate.is_some();
+    #[cfg(feature = "acme")]
     {
+        has_credentials |= kbs_config.acme_config.is_some();
+    }
+    if !kbs_config.insecure_http && !has_credentials {
         bail!("Must specify HTTPS private key and certificate when running in secure mode");
     }
 
@@ -40,37 +73,77 @@ async fn main() -> Result<()> {
     }
 
     #[cfg(feature = "as")]
-    let attestation_service = {
-        cfg_if::cfg_if! {
-            if #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))] {
-                AttestationService::new(kbs_config.as_config.unwrap_or_default()).await?
-            } else if #[cfg(feature = "coco-as-grpc")] {
-                AttestationService::new(kbs_config.grpc_config.unwrap_or_default()).await?
-            } else if #[cfg(feature = "intel-trust-authority-as")] {
-                AttestationService::new(kbs_config.intel_trust_authority_config)?
-            } else {
-                compile_error!("Please enable at least one of the following features: `coco-as-builtin`, `coco-as-builtin-no-verifier`, `coco-as-grpc` or `intel-trust-authority-as` to continue.");
-            }
-        }
-    };
+    if kbs_config.insecure_dev_mode {
+        warn!("insecure dev mode is enabled, sample evidence is accepted");
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    if let Some(tracing_config) = &kbs_config.tracing_config {
+        kbs::telemetry::init(tracing_config)?;
+    }
+
+    #[cfg(all(
+        feature = "as",
+        not(any(
+            feature = "coco-as-builtin",
+            feature = "coco-as-builtin-no-verifier",
+            feature = "coco-as-grpc",
+            feature = "intel-trust-authority-as"
+        ))
+    ))]
+    compile_error!("Please enable at least one of the following features: `coco-as-builtin`, `coco-as-builtin-no-verifier`, `coco-as-grpc` or `intel-trust-authority-as` to continue.");
+
+    #[cfg(feature = "as")]
+    let attestation_service =
+        AttestationService::new(&kbs_config, &BackendRegistry::default()).await?;
 
     let api_server = ApiServer::new(
         kbs_config.sockets,
+        kbs_config.unix_sockets,
+        #[cfg(feature = "grpc-api")]
+        kbs_config.grpc_sockets,
         kbs_config.private_key,
         kbs_config.auth_public_key,
         kbs_config.certificate,
         kbs_config.insecure_http,
         #[cfg(feature = "as")]
         attestation_service,
+        #[cfg(feature = "as")]
+        kbs_config.challenge_config.unwrap_or_default(),
         kbs_config.timeout,
+        kbs_config.reattestation_interval,
         kbs_config.insecure_api,
         #[cfg(feature = "resource")]
         kbs_config.repository_config.unwrap_or_default(),
         #[cfg(feature = "resource")]
         kbs_config.attestation_token_config,
+        #[cfg(feature = "resource")]
+        kbs_config.download_url_config,
+        #[cfg(feature = "resource")]
+        kbs_config.token_exchange_config,
         #[cfg(feature = "opa")]
         kbs_config.policy_engine_config.unwrap_or_default(),
+        kbs_config.audit_config,
+        kbs_config.webhooks,
+        kbs_config.tenants,
+        kbs_config.shutdown_timeout,
+        kbs_config.http_server_config.unwrap_or_default(),
+        kbs_config.client_auth_config,
+        kbs_config.cors_config,
+        kbs_config.admin_keys,
+        kbs_config.admin_allowed_networks,
+        Some(PathBuf::from(&cli.config_file)),
+        #[cfg(feature = "acme")]
+        kbs_config.acme_config,
+        #[cfg(feature = "spiffe")]
+        kbs_config.spiffe_config,
+        kbs_config.workload_identity,
     )?;
 
-    api_server.serve().await.map_err(anyhow::Error::from)
+    let res = api_server.serve().await;
+
+    #[cfg(feature = "opentelemetry")]
+    kbs::telemetry::shutdown();
+
+    res
 }
@@ -0,0 +1,394 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Validation of the KBS configuration without serving.
+//!
+//! Every component KBS would start is built from the configuration: the
+//! HTTPS credentials are loaded, the admin keys parsed, the repository and
+//! policy engine opened and the attestation backends created and probed. All
+//! the checks run even if some fail, so a single run reports every problem.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::path::Path;
+
+use crate::audit::AuditLog;
+use crate::auth::{load_admin_keys, AdminAllowlist};
+use crate::config::KbsConfig;
+use crate::tenant::Tenants;
+use crate::tls::TlsCredentials;
+
+#[cfg(feature = "as")]
+use crate::attestation::{challenge::NonceChallenge, AttestationService, BackendRegistry};
+#[cfg(feature = "policy")]
+use crate::policy_engine::PolicyEngine;
+
+/// Outcome of a check.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+
+    /// KBS can start, but likely not as intended.
+    Warning,
+
+    /// KBS fails to start or to serve some requests.
+    Error,
+}
+
+/// Outcome of the check of one part of the configuration.
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    pub check: &'static str,
+    pub status: Status,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Outcomes of all the checks of a configuration.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub checks: Vec<Diagnostic>,
+}
+
+impl Report {
+    /// Whether no check failed. Warnings are allowed.
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|d| d.status != Status::Error)
+    }
+
+    /// Status of the check `check`, if it ran.
+    pub fn status(&self, check: &str) -> Option<Status> {
+        self.checks
+            .iter()
+            .find(|d| d.check == check)
+            .map(|d| d.status)
+    }
+
+    fn record(&mut self, check: &'static str, result: Result<()>) {
+        let (status, message) = match result {
+            Ok(()) => (Status::Ok, None),
+            Err(e) => (Status::Error, Some(format!("{e:#}"))),
+        };
+        self.checks.push(Diagnostic {
+            check,
+            status,
+            message,
+        });
+    }
+
+    fn warn(&mut self, check: &'static str, message: &str) {
+        self.checks.push(Diagnostic {
+            check,
+            status: Status::Warning,
+            message: Some(message.to_string()),
+        });
+    }
+}
+
+/// Check the configuration file `config_file`.
+pub async fn check_config_file(
+    config_file: &Path,
+    #[cfg(feature = "as")] registry: &BackendRegistry,
+) -> Report {
+    match KbsConfig::try_from(config_file) {
+        Ok(config) => {
+            check_config(
+                &config,
+                #[cfg(feature = "as")]
+                registry,
+            )
+            .await
+        }
+        Err(e) => {
+            let mut report = Report::default();
+            report.record("config-file", Err(e));
+            report
+        }
+    }
+}
+
+/// Check `config`, creating its attestation backends from `registry`.
+pub async fn check_config(
+    config: &KbsConfig,
+    #[cfg(feature = "as")] registry: &BackendRegistry,
+) -> Report {
+    let mut report = Report::default();
+    report.record("config-file", Ok(()));
+
+    // Before any other check, so that they run with the FIPS provider.
+    if config.fips {
+        report.record("fips", crate::crypto::init(true));
+    }
+
+    report.record("https", check_https(config));
+    if config.sockets.is_empty() && config.unix_sockets.is_empty() {
+        report.warn(
+            "sockets",
+            "No socket is configured, KBS only serves systemd activated sockets",
+        );
+    }
+    if let Some(cors_config) = &config.cors_config {
+        report.record("cors", cors_config.validate());
+    }
+
+    if config.insecure_api {
+        report.warn("admin-keys", "Insecure APIs are enabled");
+    } else {
+        let admin_keys = load_admin_keys(
+            config.insecure_api,
+            config.auth_public_key.as_deref(),
+            &config.admin_keys,
+        )
+        .await;
+        report.record("admin-keys", admin_keys.map(|_| ()));
+    }
+    report.record(
+        "admin-networks",
+        AdminAllowlist::new(&config.admin_allowed_networks).map(|_| ()),
+    );
+    report.record(
+        "tenants",
+        Tenants::new(&config.tenants, config.insecure_api)
+            .await
+            .map(|_| ()),
+    );
+    report.record(
+        "audit",
+        AuditLog::new(config.audit_config.as_ref(), &config.webhooks)
+            .await
+            .map(|_| ()),
+    );
+
+    #[cfg(feature = "resource")]
+    {
+        report.record("repository", check_repository(config).await);
+        report.record(
+            "attestation-token",
+            crate::token::create_token_verifier(config.attestation_token_config.clone())
+                .map(|_| ()),
+        );
+        if let Some(download_url_config) = &config.download_url_config {
+            report.record(
+                "download-url",
+                crate::resource::download::DownloadUrls::new(download_url_config).map(|_| ()),
+            );
+        }
+        if let Some(token_exchange_config) = &config.token_exchange_config {
+            report.record(
+                "token-exchange",
+                crate::token::exchange::TokenExchanger::new(token_exchange_config).map(|_| ()),
+            );
+        }
+    }
+
+    #[cfg(feature = "policy")]
+    report.record(
+        "resource-policy",
+        PolicyEngine::new(&config.policy_engine_config.clone().unwrap_or_default())
+            .await
+            .map(|_| ())
+            .map_err(anyhow::Error::from),
+    );
+
+    #[cfg(feature = "spiffe")]
+    if let Some(spiffe_config) = &config.spiffe_config {
+        report.record(
+            "spiffe",
+            crate::spiffe::SvidIssuer::new(spiffe_config).map(|_| ()),
+        );
+    }
+
+    if let Some(workload_identity) = &config.workload_identity {
+        report.record(
+            "workload-identity",
+            crate::identity::WorkloadIdentity::new(Some(workload_identity)).map(|_| ()),
+        );
+        #[cfg(feature = "resource")]
+        if let Some(provisioning) = &workload_identity.provisioning {
+            report.record(
+                "provisioning",
+                crate::resource::provision::Provisioner::new(Some(provisioning)).map(|_| ()),
+            );
+        }
+    }
+
+    #[cfg(feature = "as")]
+    {
+        if config.insecure_dev_mode {
+            report.warn(
+                "dev-mode",
+                "Insecure dev mode is enabled, evidence of the sample attester is accepted",
+            );
+        }
+        report.record(
+            "challenge",
+            NonceChallenge::new(config.challenge_config.clone().unwrap_or_default()).map(|_| ()),
+        );
+        if let Some(archive_config) = &config.archive_config {
+            report.record("archive", archive_config.validate());
+        }
+        report.record(
+            "attestation-service",
+            check_attestation_service(config, registry).await,
+        );
+    }
+
+    report
+}
+
+fn check_https(config: &KbsConfig) -> Result<()> {
+    if config.insecure_http {
+        if config.client_auth_config.is_some() {
+            bail!("Client certificate authentication requires HTTPS");
+        }
+        #[cfg(feature = "as")]
+        if config.tls_channel_binding {
+            bail!("TLS channel binding requires HTTPS");
+        }
+        return Ok(());
+    }
+
+    // The certificate is issued when KBS starts.
+    #[cfg(feature = "acme")]
+    if config.acme_config.is_some() {
+        return Ok(());
+    }
+
+    match (&config.certificate, &config.private_key) {
+        (Some(certificate), Some(private_key)) => {
+            TlsCredentials::new(certificate, private_key, config.client_auth_config.clone())?;
+            Ok(())
+        }
+        _ => bail!("Must specify HTTPS private key and certificate when running in secure mode"),
+    }
+}
+
+#[cfg(feature = "resource")]
+async fn check_repository(config: &KbsConfig) -> Result<()> {
+    let repository = config
+        .repository_config
+        .clone()
+        .unwrap_or_default()
+        .initialize()?;
+    let repository = repository.read().await;
+    repository.health_check().await
+}
+
+#[cfg(feature = "as")]
+async fn check_attestation_service(config: &KbsConfig, registry: &BackendRegistry) -> Result<()> {
+    use anyhow::Context;
+
+    // The archive is checked on its own, as it would expire records.
+    let config = KbsConfig {
+        archive_config: None,
+        ..config.clone()
+    };
+    let attestation_service = AttestationService::new(&config, registry).await?;
+    attestation_service
+        .health_check()
+        .await
+        .context("attestation backend health check")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(dir: &Path, extra: &str) -> std::path::PathBuf {
+        let config_file = dir.join("kbs.toml");
+        let config = format!(
+            r#"
+insecure_http = true
+attestation_backend = "a"
+{extra}
+[attestation_token_config]
+attestation_token_type = "CoCo"
+
+[repository_config]
+type = "LocalFs"
+dir_path = "{dir}/repository"
+
+[policy_engine_config]
+policy_path = "{dir}/policy.rego"
+"#,
+            dir = dir.display()
+        );
+        std::fs::write(&config_file, config).unwrap();
+        config_file
+    }
+
+    #[cfg(feature = "as")]
+    fn registry() -> BackendRegistry {
+        use crate::attestation::{Attest, Verdict};
+        use kbs_types::Tee;
+        use std::sync::Arc;
+
+        struct Backend;
+
+        #[async_trait::async_trait]
+        impl Attest for Backend {
+            async fn verify(
+                &self,
+                _: Tee,
+                _: &str,
+                _: &str,
+                _: &str,
+                _: Option<&str>,
+                _: Option<&[u8]>,
+                _: Option<&str>,
+            ) -> Result<Verdict> {
+                bail!("unused")
+            }
+        }
+
+        let mut registry = BackendRegistry::empty();
+        registry.register("a", |_| {
+            Box::pin(async { Ok(Arc::new(Backend) as Arc<dyn Attest>) })
+        });
+        registry
+    }
+
+    async fn check(config_file: &Path) -> Report {
+        check_config_file(
+            config_file,
+            #[cfg(feature = "as")]
+            &registry(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_check_config() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let report = check(&dir.path().join("missing.toml")).await;
+        assert!(!report.is_ok());
+        assert_eq!(report.status("config-file"), Some(Status::Error));
+        assert_eq!(report.checks.len(), 1);
+
+        let key = dir.path().join("auth.pub");
+        std::fs::write(&key, "not a key").unwrap();
+        let config_file = write_config(
+            dir.path(),
+            &format!(
+                "auth_public_key = \"{}\"\nadmin_allowed_networks = [\"10.0.0.0/33\"]\n",
+                key.display()
+            ),
+        );
+        let report = check(&config_file).await;
+        assert!(!report.is_ok());
+        assert_eq!(report.status("https"), Some(Status::Ok));
+        assert_eq!(report.status("admin-keys"), Some(Status::Error));
+        assert_eq!(report.status("admin-networks"), Some(Status::Error));
+        #[cfg(feature = "as")]
+        assert_eq!(report.status("attestation-service"), Some(Status::Ok));
+
+        let config_file = write_config(dir.path(), "insecure_api = true\n");
+        let report = check(&config_file).await;
+        assert!(report.is_ok(), "{report:#?}");
+        assert_eq!(report.status("admin-keys"), Some(Status::Warning));
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Claims of the attestation results tokens of the attestation backends.
+
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// The IDs of the attestation policies the evidence passed, from the
+/// `evaluation-reports` of the tokens of the CoCo AS, which only issues a
+/// token for evidence that passed all of them, or the `policy_ids_matched` of
+/// the tokens of Intel Trust Authority.
+pub(crate) fn passed_policies(claims: &Value) -> BTreeSet<&str> {
+    let coco = claims["evaluation-reports"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|report| report["policy-id"].as_str());
+    let ita = claims["policy_ids_matched"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|policy| policy["id"].as_str());
+    coco.chain(ita).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_passed_policies() {
+        let claims = json!({
+            "evaluation-reports": [{"policy-id": "a"}, {"policy-id": "b"}],
+            "policy_ids_matched": [{"id": "c"}],
+            "policy_ids_unmatched": [{"id": "d"}],
+        });
+        assert_eq!(
+            passed_policies(&claims).into_iter().collect::<Vec<_>>(),
+            ["a", "b", "c"]
+        );
+        assert!(passed_policies(&json!({})).is_empty());
+    }
+}
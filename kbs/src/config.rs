@@ -2,23 +2,48 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+#[cfg(feature = "acme")]
+use crate::acme::AcmeConfig;
+#[cfg(feature = "as")]
+use crate::attestation::archive::ArchiveConfig;
+#[cfg(feature = "as")]
+use crate::attestation::challenge::ChallengeConfig;
 #[cfg(feature = "coco-as-grpc")]
 use crate::attestation::coco::grpc::GrpcConfig;
+#[cfg(feature = "as")]
+use crate::attestation::drift::DriftConfig;
 #[cfg(feature = "intel-trust-authority-as")]
 use crate::attestation::intel_trust_authority::IntelTrustAuthorityConfig;
+#[cfg(feature = "as")]
+use crate::attestation::publisher::PublisherConfig;
+#[cfg(feature = "as")]
+use crate::attestation::AttestationRoute;
+use crate::audit::{AuditConfig, WebhookConfig};
+use crate::auth::AdminKeyConfig;
+use crate::cors::CorsConfig;
+use crate::http::{HttpServerConfig, ReattestationInterval};
+use crate::identity::WorkloadIdentityConfig;
+use crate::logging::LogFormat;
 #[cfg(feature = "policy")]
 use crate::policy_engine::PolicyEngineConfig;
 #[cfg(feature = "resource")]
-use crate::resource::RepositoryConfig;
+use crate::resource::{download::DownloadUrlConfig, RepositoryConfig};
+#[cfg(feature = "spiffe")]
+use crate::spiffe::SpiffeConfig;
+#[cfg(feature = "opentelemetry")]
+use crate::telemetry::TracingConfig;
+use crate::tenant::TenantConfig;
+use crate::tls::ClientAuthConfig;
 #[cfg(feature = "resource")]
-use crate::token::AttestationTokenVerifierConfig;
+use crate::token::{exchange::TokenExchangeConfig, AttestationTokenVerifierConfig};
 use anyhow::anyhow;
 #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
 use attestation_service::config::Config as AsConfig;
 use clap::Parser;
-use config::{Config, File};
+use config::{Config, ConfigError, Environment, File, Unexpected};
 use serde::Deserialize;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 
@@ -26,6 +51,13 @@ const DEFAULT_INSECURE_API: bool = false;
 const DEFAULT_INSECURE_HTTP: bool = false;
 const DEFAULT_SOCKET: &str = "127.0.0.1:8080";
 const DEFAULT_TIMEOUT: i64 = 5;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+
+/// Prefix and separator of the environment variables overriding the fields
+/// of the configuration file, e.g. `KBS__GRPC_CONFIG__AS_ADDR` for
+/// `grpc_config.as_addr`.
+const ENV_PREFIX: &str = "KBS";
+const ENV_SEPARATOR: &str = "__";
 
 /// Contains all configurable KBS properties.
 #[derive(Clone, Debug, Deserialize)]
@@ -38,30 +70,142 @@ pub struct KbsConfig {
     #[cfg(feature = "resource")]
     pub attestation_token_config: AttestationTokenVerifierConfig,
 
+    /// Name of the attestation backend to verify evidence with. The first
+    /// compiled in of `builtin`, `coco-grpc` and `ita` when omitted.
+    #[cfg(feature = "as")]
+    pub attestation_backend: Option<String>,
+
+    /// Backends verifying the evidence of some TEEs instead of
+    /// `attestation_backend`.
+    #[cfg(feature = "as")]
+    #[serde(default)]
+    pub attestation_routes: Vec<AttestationRoute>,
+
+    /// Configuration sections of custom attestation backends, by name.
+    #[cfg(feature = "as")]
+    #[serde(default)]
+    pub attestation_backend_config: HashMap<String, Value>,
+
     /// Configuration for the built-in Attestation Service.
     #[cfg(any(feature = "coco-as-builtin", feature = "coco-as-builtin-no-verifier"))]
     pub as_config: Option<AsConfig>,
 
+    /// Challenges of the attestation handshake. A 32 byte base64 nonce
+    /// without extra parameters when omitted.
+    #[cfg(feature = "as")]
+    pub challenge_config: Option<ChallengeConfig>,
+
+    /// Require the evidence to bind the TLS channel binding of the `/attest`
+    /// request, so that valid evidence can't be relayed over another
+    /// connection. Only TLS 1.3 connections have a channel binding.
+    #[cfg(feature = "as")]
+    #[serde(default)]
+    pub tls_channel_binding: bool,
+
+    /// Require the clients to attest again when an attestation policy is set
+    /// or removed, instead of using their verdicts until they expire.
+    #[cfg(feature = "as")]
+    #[serde(default)]
+    pub reattest_on_policy_change: bool,
+
+    /// Seconds between the checks of the unexpired tokens of every tenant
+    /// with the current attestation policies and reference values, which KBS
+    /// isn't notified of changes of. Only checked on attestation policy
+    /// changes and on request when omitted.
+    #[cfg(feature = "as")]
+    pub reattestation_check_interval: Option<u64>,
+
+    /// Detect the TEE of the attesters requesting the `auto` TEE from the
+    /// format of their evidence, instead of rejecting them.
+    #[cfg(feature = "as")]
+    #[serde(default)]
+    pub tee_detection: bool,
+
+    /// Accept the evidence of the sample attester, which runs outside of any
+    /// TEE and proves nothing, so that applications can be developed against
+    /// KBS on machines without a TEE. Never enable it in production.
+    #[cfg(feature = "as")]
+    #[serde(default)]
+    pub insecure_dev_mode: bool,
+
+    /// Archive of the attestation records, for forensic re-verification
+    /// and compliance retention. Attestations are not archived when omitted.
+    #[cfg(feature = "as")]
+    pub archive_config: Option<ArchiveConfig>,
+
+    /// Stream of the attestation verdicts, published to a message broker for
+    /// dashboards and admission controllers to subscribe to. Verdicts are not
+    /// published when omitted.
+    #[cfg(feature = "as")]
+    pub publisher_config: Option<PublisherConfig>,
+
+    /// Baselines of the attested platforms, which the claims of every
+    /// attestation are compared with to detect TCB drift. Drift is not
+    /// tracked when omitted.
+    #[cfg(feature = "as")]
+    pub drift_config: Option<DriftConfig>,
+
     /// Configuration for remote attestation over gRPC.
     #[cfg(feature = "coco-as-grpc")]
     pub grpc_config: Option<GrpcConfig>,
 
     /// Configuration for Intel Trust Authority attestation.
     #[cfg(feature = "intel-trust-authority-as")]
-    pub intel_trust_authority_config: IntelTrustAuthorityConfig,
+    pub intel_trust_authority_config: Option<IntelTrustAuthorityConfig>,
 
     /// Socket addresses (IP:port) to listen on, e.g. 127.0.0.1:8080.
     pub sockets: Vec<SocketAddr>,
 
+    /// Unix domain socket paths to serve plain HTTP on, e.g. for a co-located
+    /// reverse proxy.
+    #[serde(default)]
+    pub unix_sockets: Vec<PathBuf>,
+
+    /// Socket addresses (IP:port) to serve the KBS protocol over gRPC on.
+    #[cfg(feature = "grpc-api")]
+    #[serde(default)]
+    pub grpc_sockets: Vec<SocketAddr>,
+
     /// HTTPS session timeout in minutes.
     pub timeout: i64,
 
+    /// Minutes after which clients have to attest again to access resources,
+    /// even if their session or token is still valid. Attestation verdicts
+    /// are used until they expire when omitted.
+    pub reattestation_interval: Option<i64>,
+
+    /// Seconds to wait on shutdown for pending attestations, and then for
+    /// in-flight requests, to complete.
+    pub shutdown_timeout: u64,
+
+    /// Request size limits and connection timeouts of the HTTP server. The
+    /// actix defaults are used when omitted.
+    pub http_server_config: Option<HttpServerConfig>,
+
     /// HTTPS private key.
     pub private_key: Option<PathBuf>,
 
     /// HTTPS Certificate.
     pub certificate: Option<PathBuf>,
 
+    /// Obtain and renew the HTTPS certificate over ACME instead of using
+    /// `private_key` and `certificate`.
+    #[cfg(feature = "acme")]
+    pub acme_config: Option<AcmeConfig>,
+
+    /// Require TLS client certificates issued by a trusted CA. Client
+    /// certificates are not requested when omitted.
+    pub client_auth_config: Option<ClientAuthConfig>,
+
+    /// Cross-origin requests allowed from browsers. Cross-origin requests
+    /// are not answered with CORS headers when omitted.
+    pub cors_config: Option<CorsConfig>,
+
+    /// Restrict OpenSSL to its FIPS provider for all the cryptography of
+    /// KBS. Requires KBS built with the `fips` feature.
+    #[serde(default)]
+    pub fips: bool,
+
     /// Insecure HTTP.
     /// WARNING: Using this option makes the HTTP connection insecure.
     pub insecure_http: bool,
@@ -70,6 +214,16 @@ pub struct KbsConfig {
     /// Only JWTs signed with the corresponding private keys are authenticated.
     pub auth_public_key: Option<PathBuf>,
 
+    /// More public keys trusted to sign admin tokens, each with an optional
+    /// expiry. Lets admin keys be rotated and held by different teams.
+    #[serde(default)]
+    pub admin_keys: Vec<AdminKeyConfig>,
+
+    /// CIDR ranges or IP addresses of the clients allowed to call the admin
+    /// APIs. Any client is allowed when empty.
+    #[serde(default)]
+    pub admin_allowed_networks: Vec<String>,
+
     /// Insecure HTTP APIs.
     /// WARNING: Using this option enables KBS insecure APIs such as Resource Registration without
     /// verifying the JWK.
@@ -79,27 +233,108 @@ pub struct KbsConfig {
     /// specific resources.
     #[cfg(feature = "policy")]
     pub policy_engine_config: Option<PolicyEngineConfig>,
+
+    /// Audit log configuration. Auditing is disabled when omitted.
+    pub audit_config: Option<AuditConfig>,
+
+    /// Webhooks notified of attestation verdicts, policy changes and denied
+    /// resource requests.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+
+    /// Tenants with their own resources, policies and admin keys, served
+    /// under `/kbs/v0/tenant/<id>`.
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+
+    /// OpenTelemetry tracing configuration. Spans are not exported when omitted.
+    #[cfg(feature = "opentelemetry")]
+    pub tracing_config: Option<TracingConfig>,
+
+    /// Signing and lifetime of the single-use download URLs of resources.
+    /// Download URLs are not minted when omitted.
+    #[cfg(feature = "resource")]
+    pub download_url_config: Option<DownloadUrlConfig>,
+
+    /// Signing, lifetime and audiences of the tokens attestation results
+    /// tokens are exchanged for. Tokens are not exchanged when omitted.
+    #[cfg(feature = "resource")]
+    pub token_exchange_config: Option<TokenExchangeConfig>,
+
+    /// SPIRE server minting the X509-SVIDs of the attested workloads. SVIDs
+    /// are not issued when omitted.
+    #[cfg(feature = "spiffe")]
+    pub spiffe_config: Option<SpiffeConfig>,
+
+    /// Template of the identity of the attested workloads over their
+    /// attestation claims. Workloads have no identity when omitted.
+    pub workload_identity: Option<WorkloadIdentityConfig>,
 }
 
 impl TryFrom<&Path> for KbsConfig {
     type Error = anyhow::Error;
 
-    /// Load `Config` from a configuration file. Supported formats are all formats supported by the
-    /// `config` crate. See `KbsConfig` for schema information.
+    /// Load `Config` from a configuration file, overridden by the `KBS__*`
+    /// environment variables. Supported formats are all formats supported by the
+    /// `config` crate, e.g. TOML, YAML and JSON. See `KbsConfig` for schema information.
     fn try_from(config_path: &Path) -> Result<Self, Self::Error> {
-        let c = Config::builder()
-            .set_default("insecure_api", DEFAULT_INSECURE_API)?
-            .set_default("insecure_http", DEFAULT_INSECURE_HTTP)?
-            .set_default("sockets", vec![DEFAULT_SOCKET])?
-            .set_default("timeout", DEFAULT_TIMEOUT)?
-            .add_source(File::with_name(config_path.to_str().unwrap()))
-            .build()?;
-
-        c.try_deserialize()
-            .map_err(|e| anyhow!("invalid config: {}", e.to_string()))
+        KbsConfig::load(config_path, std::env::vars().collect())
+    }
+}
+
+impl KbsConfig {
+    /// Load the configuration file at `config_path`, with its fields
+    /// overridden by the `KBS__*` variables of `env`.
+    ///
+    /// Lists are given in the environment as comma separated values. They are
+    /// split once the value of a variable failed to deserialize as a string,
+    /// so that every list of the configuration, nested or not, can be set.
+    fn load(config_path: &Path, env: HashMap<String, String>) -> anyhow::Result<Self> {
+        let mut lists: HashMap<String, Vec<String>> = HashMap::new();
+        loop {
+            let mut builder = Config::builder()
+                .set_default("insecure_api", DEFAULT_INSECURE_API)?
+                .set_default("insecure_http", DEFAULT_INSECURE_HTTP)?
+                .set_default("sockets", vec![DEFAULT_SOCKET])?
+                .set_default("timeout", DEFAULT_TIMEOUT)?
+                .set_default("shutdown_timeout", DEFAULT_SHUTDOWN_TIMEOUT)?
+                .add_source(File::with_name(config_path.to_str().unwrap()))
+                .add_source(
+                    Environment::with_prefix(ENV_PREFIX)
+                        .separator(ENV_SEPARATOR)
+                        .source(Some(env.clone())),
+                );
+            for (key, values) in &lists {
+                builder = builder.set_override(key.as_str(), values.clone())?;
+            }
+
+            match builder.build()?.try_deserialize::<Self>() {
+                Ok(config) => {
+                    ReattestationInterval::validate(config.reattestation_interval)?;
+                    return Ok(config);
+                }
+                Err(ConfigError::Type {
+                    unexpected: Unexpected::Str(value),
+                    key: Some(key),
+                    ..
+                }) if !lists.contains_key(&key) && env.contains_key(&env_variable(&key)) => {
+                    let values = value.split(',').map(|v| v.trim().to_string()).collect();
+                    lists.insert(key, values);
+                }
+                Err(e) => return Err(anyhow!("invalid config: {e}")),
+            }
+        }
     }
 }
 
+/// Name of the environment variable overriding the field `key`.
+fn env_variable(key: &str) -> String {
+    format!(
+        "{ENV_PREFIX}{ENV_SEPARATOR}{}",
+        key.replace('.', ENV_SEPARATOR).to_uppercase()
+    )
+}
+
 /// KBS command-line arguments.
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -108,4 +343,70 @@ pub struct Cli {
     /// supported by the `config` crate.
     #[arg(short, long, env = "KBS_CONFIG_FILE")]
     pub config_file: String,
+
+    /// Validate the config file and exit instead of serving. The outcome of
+    /// every check is printed as JSON, and the exit status is non-zero if any
+    /// of them failed.
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Verify the hash chain of the audit log `FILE` and exit instead of
+    /// serving. The checkpoints are verified with the audit signing key of
+    /// the config file. The outcome is printed as JSON, and the exit status is
+    /// non-zero if the chain is broken.
+    #[arg(long, value_name = "FILE")]
+    pub verify_audit_log: Option<PathBuf>,
+
+    /// Format of the log records written to stderr.
+    #[arg(long, env = "KBS_LOG_FORMAT", value_enum, default_value_t)]
+    pub log_format: LogFormat,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("kbs.yaml");
+        std::fs::write(
+            &config_file,
+            "insecure_http: true\ntimeout: 5\nattestation_token_config:\n  attestation_token_type: CoCo\n",
+        )
+        .unwrap();
+
+        let env = HashMap::from([
+            ("KBS__TIMEOUT".into(), "10".into()),
+            ("KBS__INSECURE_API".into(), "true".into()),
+            ("KBS__SOCKETS".into(), "0.0.0.0:8080, 0.0.0.0:8081".into()),
+            ("KBS__ADMIN_ALLOWED_NETWORKS".into(), "10.0.0.0/8".into()),
+            (
+                "KBS__CORS_CONFIG__ALLOWED_ORIGINS".into(),
+                "https://a.example.com,https://b.example.com".into(),
+            ),
+            (
+                "KBS__HTTP_SERVER_CONFIG__REQUEST_TIMEOUT".into(),
+                "30".into(),
+            ),
+            ("KBS_CONFIG_FILE".into(), "kbs.toml".into()),
+        ]);
+        let config = KbsConfig::load(&config_file, env).unwrap();
+        assert!(config.insecure_http);
+        assert!(config.insecure_api);
+        assert_eq!(config.timeout, 10);
+        assert_eq!(
+            config.sockets,
+            vec![
+                "0.0.0.0:8080".parse::<SocketAddr>().unwrap(),
+                "0.0.0.0:8081".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.http_server_config.unwrap().request_timeout, 30);
+        assert_eq!(config.admin_allowed_networks, ["10.0.0.0/8"]);
+        assert_eq!(
+            config.cors_config.unwrap().allowed_origins,
+            ["https://a.example.com", "https://b.example.com"]
+        );
+    }
 }
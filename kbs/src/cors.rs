@@ -0,0 +1,168 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cross-origin resource sharing (CORS) of the KBS HTTP server, so that web
+//! applications such as management dashboards can call KBS from a browser.
+
+use actix_cors::Cors;
+use actix_web::http::{header::HeaderName, Method, Uri};
+use actix_web::middleware::Condition;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// Origin that allows any origin.
+const ANY_ORIGIN: &str = "*";
+
+fn default_allowed_methods() -> Vec<String> {
+    vec!["GET".to_string(), "POST".to_string()]
+}
+
+fn default_allowed_headers() -> Vec<String> {
+    vec!["Authorization".to_string(), "Content-Type".to_string()]
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests, e.g.
+    /// `https://dashboard.example.com`, or `*` for any origin.
+    pub allowed_origins: Vec<String>,
+
+    /// Methods allowed in cross-origin requests.
+    #[serde(default = "default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+
+    /// Request headers allowed in cross-origin requests.
+    #[serde(default = "default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+
+    /// Seconds browsers may cache the result of a preflight request.
+    #[serde(default)]
+    pub max_age: Option<usize>,
+
+    /// Allow cross-origin requests to carry cookies, e.g. the KBS session
+    /// cookie. Not allowed with any origin.
+    #[serde(default)]
+    pub allow_credentials: bool,
+}
+
+impl CorsConfig {
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|origin| origin == ANY_ORIGIN)
+    }
+
+    /// Check the configuration up front, as the CORS middleware only fails
+    /// on invalid values when the server starts its workers.
+    pub fn validate(&self) -> Result<()> {
+        if self.allows_any_origin() && self.allow_credentials {
+            bail!("CORS credentials can't be allowed for any origin");
+        }
+        for origin in self.allowed_origins.iter().filter(|o| *o != ANY_ORIGIN) {
+            origin
+                .parse::<Uri>()
+                .with_context(|| format!("invalid CORS origin {origin}"))?;
+        }
+        for method in &self.allowed_methods {
+            Method::from_bytes(method.as_bytes())
+                .with_context(|| format!("invalid CORS method {method}"))?;
+        }
+        for header in &self.allowed_headers {
+            HeaderName::try_from(header.as_str())
+                .with_context(|| format!("invalid CORS header {header}"))?;
+        }
+        Ok(())
+    }
+
+    fn cors(&self) -> Cors {
+        let mut cors = Cors::default()
+            .allowed_methods(self.allowed_methods.iter().map(String::as_str))
+            .allowed_headers(self.allowed_headers.iter().map(String::as_str))
+            .max_age(self.max_age);
+
+        if self.allows_any_origin() {
+            cors = cors.allow_any_origin().send_wildcard();
+        } else {
+            for origin in &self.allowed_origins {
+                cors = cors.allowed_origin(origin);
+            }
+        }
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+
+        cors
+    }
+}
+
+/// The CORS middleware of the HTTP server. Cross-origin requests are left
+/// alone when CORS is not configured.
+pub(crate) fn middleware(config: Option<&CorsConfig>) -> Condition<Cors> {
+    match config {
+        Some(config) => Condition::new(true, config.cors()),
+        None => Condition::new(false, Cors::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use rstest::rstest;
+
+    fn config(allowed_origins: &[&str], allow_credentials: bool) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: allowed_origins.iter().map(|o| o.to_string()).collect(),
+            allowed_methods: default_allowed_methods(),
+            allowed_headers: default_allowed_headers(),
+            max_age: None,
+            allow_credentials,
+        }
+    }
+
+    #[rstest]
+    #[case(config(&["https://dashboard.example.com"], true), true)]
+    #[case(config(&["*"], false), true)]
+    #[case(config(&["*"], true), false)]
+    #[case(config(&["not an origin"], false), false)]
+    fn test_validate(#[case] config: CorsConfig, #[case] valid: bool) {
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[actix_web::test]
+    async fn test_preflight() {
+        let config = config(&["https://dashboard.example.com"], false);
+        let app = init_service(
+            App::new()
+                .wrap(middleware(Some(&config)))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let preflight = |origin: &'static str| {
+            TestRequest::default()
+                .method(Method::OPTIONS)
+                .insert_header((header::ORIGIN, origin))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+                .to_request()
+        };
+
+        let response = call_service(&app, preflight("https://dashboard.example.com")).await;
+        assert_eq!(
+            response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://dashboard.example.com"
+        );
+
+        let response = call_service(&app, preflight("https://evil.example.com")).await;
+        assert!(response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cryptographic primitives of KBS: the hash chain of the audit log, the HMAC
+//! of the webhook deliveries and, with the `resource` feature, the wrapping of
+//! resources to TEE keys and their envelope encryption at rest.
+//!
+//! The primitives come from OpenSSL with the `openssl` feature and from the
+//! RustCrypto crates otherwise. Both backends produce the same output, so a
+//! repository encrypted by one can be read by the other.
+//!
+//! With the `fips` feature, [`init`] restricts OpenSSL to its FIPS provider,
+//! for the HTTPS stack and the built-in attestation service as well.
+
+use anyhow::Result;
+use subtle::ConstantTimeEq;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "openssl")] {
+        mod ossl;
+        pub(crate) use ossl::*;
+    } else {
+        mod rustcrypto;
+        pub(crate) use rustcrypto::*;
+    }
+}
+
+/// Length in bytes of an AES-256-GCM key.
+#[cfg(feature = "resource")]
+pub(crate) const AES_256_GCM_KEY_LENGTH: usize = 32;
+
+/// Length in bytes of an AES-256-GCM nonce.
+#[cfg(feature = "resource")]
+pub(crate) const AES_256_GCM_NONCE_LENGTH: usize = 12;
+
+/// NIST curves of the EC keys resources are wrapped to.
+#[cfg(feature = "resource")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EcCurve {
+    P256,
+    P384,
+}
+
+#[cfg(feature = "resource")]
+impl EcCurve {
+    /// Length in bytes of a coordinate of a point of the curve.
+    pub fn coordinate_length(&self) -> usize {
+        match self {
+            EcCurve::P256 => 32,
+            EcCurve::P384 => 48,
+        }
+    }
+}
+
+/// The outcome of an ECDH key agreement with an ephemeral key.
+#[cfg(feature = "resource")]
+pub(crate) struct EphemeralAgreement {
+    /// Big-endian coordinates of the ephemeral public key.
+    pub x: Vec<u8>,
+    pub y: Vec<u8>,
+
+    /// The shared secret `Z`.
+    pub secret: zeroize::Zeroizing<Vec<u8>>,
+}
+
+/// Whether `a` equals `b`, in a time independent of where they differ, to
+/// compare MACs, nonces and session handles.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Set up the crypto backend, in FIPS mode if `fips`. Must be called before
+/// any other use of OpenSSL, which would load its default provider.
+pub fn init(fips: bool) -> Result<()> {
+    if !fips {
+        return Ok(());
+    }
+
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "fips")] {
+            ossl::enable_fips()?;
+            log::info!("FIPS mode enabled, OpenSSL is restricted to its FIPS provider");
+            Ok(())
+        } else {
+            anyhow::bail!("FIPS mode requires KBS built with the `fips` feature")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            hex::encode(sha256(b"abc").unwrap()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // HMAC-SHA256 test vector of RFC 4231, test case 2.
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?").unwrap()),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"handle", b"handle"));
+        assert!(!constant_time_eq(b"handle", b"handlf"));
+        assert!(!constant_time_eq(b"handle", b"hand"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[cfg(feature = "resource")]
+    #[test]
+    fn test_aes_256_gcm() {
+        // Test case 14 of the GCM specification.
+        let key = [0; AES_256_GCM_KEY_LENGTH];
+        let iv = [0; AES_256_GCM_NONCE_LENGTH];
+        let sealed = aes_256_gcm_encrypt(&key, &iv, b"", &[0; 16]).unwrap();
+        assert_eq!(
+            hex::encode(&sealed),
+            "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919"
+        );
+        assert_eq!(
+            *aes_256_gcm_decrypt(&key, &iv, b"", &sealed).unwrap(),
+            [0; 16]
+        );
+
+        let sealed = aes_256_gcm_encrypt(&key, &iv, b"aad", b"secret").unwrap();
+        assert_eq!(
+            *aes_256_gcm_decrypt(&key, &iv, b"aad", &sealed).unwrap(),
+            b"secret"
+        );
+        assert!(aes_256_gcm_decrypt(&key, &iv, b"other", &sealed).is_err());
+        assert!(aes_256_gcm_decrypt(&[1; AES_256_GCM_KEY_LENGTH], &iv, b"aad", &sealed).is_err());
+        assert!(aes_256_gcm_decrypt(&key, &iv, b"aad", &sealed[..8]).is_err());
+    }
+
+    #[cfg(feature = "resource")]
+    #[test]
+    fn test_rsa_pkcs1v15_encrypt() {
+        use openssl::rsa::{Padding, Rsa};
+
+        let key = Rsa::generate(2048).unwrap();
+        let wrapped =
+            rsa_pkcs1v15_encrypt(&key.n().to_vec(), &key.e().to_vec(), b"secret").unwrap();
+        let mut unwrapped = vec![0; key.size() as usize];
+        let length = key
+            .private_decrypt(&wrapped, &mut unwrapped, Padding::PKCS1)
+            .unwrap();
+        assert_eq!(&unwrapped[..length], b"secret");
+    }
+
+    #[cfg(feature = "resource")]
+    #[test]
+    fn test_rsa_oaep_sha256_encrypt() {
+        use openssl::{
+            encrypt::Decrypter,
+            hash::MessageDigest,
+            pkey::PKey,
+            rsa::{Padding, Rsa},
+        };
+
+        let key = Rsa::generate(2048).unwrap();
+        let wrapped =
+            rsa_oaep_sha256_encrypt(&key.n().to_vec(), &key.e().to_vec(), b"secret").unwrap();
+        let key = PKey::from_rsa(key).unwrap();
+        let mut decrypter = Decrypter::new(&key).unwrap();
+        decrypter.set_rsa_padding(Padding::PKCS1_OAEP).unwrap();
+        decrypter.set_rsa_oaep_md(MessageDigest::sha256()).unwrap();
+        decrypter.set_rsa_mgf1_md(MessageDigest::sha256()).unwrap();
+        let mut unwrapped = vec![0; decrypter.decrypt_len(&wrapped).unwrap()];
+        let length = decrypter.decrypt(&wrapped, &mut unwrapped).unwrap();
+        assert_eq!(&unwrapped[..length], b"secret");
+    }
+
+    #[cfg(feature = "resource")]
+    #[rstest::rstest]
+    #[case(EcCurve::P256, openssl::nid::Nid::X9_62_PRIME256V1)]
+    #[case(EcCurve::P384, openssl::nid::Nid::SECP384R1)]
+    fn test_ecdh_ephemeral(#[case] curve: EcCurve, #[case] nid: openssl::nid::Nid) {
+        use openssl::{
+            bn::{BigNum, BigNumContext},
+            derive::Deriver,
+            ec::{EcGroup, EcKey},
+            pkey::PKey,
+        };
+
+        let group = EcGroup::from_curve_name(nid).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        key.public_key()
+            .affine_coordinates(&group, &mut x, &mut y, &mut ctx)
+            .unwrap();
+
+        let agreement = ecdh_ephemeral(curve, &x.to_vec(), &y.to_vec()).unwrap();
+        assert_eq!(agreement.x.len(), curve.coordinate_length());
+        assert_eq!(agreement.y.len(), curve.coordinate_length());
+
+        let epk = EcKey::from_public_key_affine_coordinates(
+            &group,
+            &BigNum::from_slice(&agreement.x).unwrap(),
+            &BigNum::from_slice(&agreement.y).unwrap(),
+        )
+        .unwrap();
+        let key = PKey::from_ec_key(key).unwrap();
+        let epk = PKey::from_ec_key(epk).unwrap();
+        let mut deriver = Deriver::new(&key).unwrap();
+        deriver.set_peer(&epk).unwrap();
+        assert_eq!(deriver.derive_to_vec().unwrap(), *agreement.secret);
+
+        // Not a point of the curve.
+        assert!(ecdh_ephemeral(curve, &x.to_vec(), &x.to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_init() {
+        init(false).unwrap();
+        #[cfg(not(feature = "fips"))]
+        assert!(init(true).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The OpenSSL backend. Only the EVP interfaces are used, as the low-level
+//! ones bypass the OpenSSL providers, the FIPS one included.
+
+use anyhow::Result;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+#[cfg(feature = "resource")]
+use {
+    super::{EcCurve, EphemeralAgreement, AES_256_GCM_NONCE_LENGTH},
+    anyhow::bail,
+    openssl::{
+        bn::{BigNum, BigNumContext},
+        derive::Deriver,
+        ec::{EcGroup, EcKey},
+        encrypt::Encrypter,
+        nid::Nid,
+        rsa::{Padding, Rsa},
+        symm::{decrypt_aead, encrypt_aead, Cipher},
+    },
+    zeroize::Zeroizing,
+};
+
+/// Length in bytes of the AES-256-GCM tag appended to the ciphertext.
+#[cfg(feature = "resource")]
+const AES_256_GCM_TAG_LENGTH: usize = 16;
+
+/// Fill `buf` with random bytes.
+#[cfg(feature = "resource")]
+pub(crate) fn fill_random(buf: &mut [u8]) -> Result<()> {
+    openssl::rand::rand_bytes(buf)?;
+    Ok(())
+}
+
+/// The SHA-256 digest of `data`.
+pub(crate) fn sha256(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(openssl::hash::hash(MessageDigest::sha256(), data)?.to_vec())
+}
+
+/// The HMAC-SHA256 of `data` under `key`.
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Encrypt `plaintext` with AES-256-GCM, authenticating `aad` too. Returns
+/// the ciphertext followed by the tag.
+#[cfg(feature = "resource")]
+pub(crate) fn aes_256_gcm_encrypt(
+    key: &[u8],
+    iv: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    if iv.len() != AES_256_GCM_NONCE_LENGTH {
+        bail!("AES-256-GCM nonce must be {AES_256_GCM_NONCE_LENGTH} bytes");
+    }
+    let mut tag = [0; AES_256_GCM_TAG_LENGTH];
+    let mut sealed = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(iv),
+        aad,
+        plaintext,
+        &mut tag,
+    )?;
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+/// Decrypt the output of [`aes_256_gcm_encrypt`].
+#[cfg(feature = "resource")]
+pub(crate) fn aes_256_gcm_decrypt(
+    key: &[u8],
+    iv: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    if iv.len() != AES_256_GCM_NONCE_LENGTH {
+        bail!("AES-256-GCM nonce must be {AES_256_GCM_NONCE_LENGTH} bytes");
+    }
+    if sealed.len() < AES_256_GCM_TAG_LENGTH {
+        bail!("AES-256-GCM ciphertext is shorter than its tag");
+    }
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - AES_256_GCM_TAG_LENGTH);
+    let plaintext = decrypt_aead(Cipher::aes_256_gcm(), key, Some(iv), aad, ciphertext, tag)?;
+    Ok(Zeroizing::new(plaintext))
+}
+
+/// Encrypt `data` to the RSA public key of modulus `n` and exponent `e`,
+/// both big-endian, with PKCS #1 v1.5 padding.
+#[cfg(feature = "resource")]
+pub(crate) fn rsa_pkcs1v15_encrypt(n: &[u8], e: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = Rsa::from_public_components(BigNum::from_slice(n)?, BigNum::from_slice(e)?)?;
+    let key = PKey::from_rsa(key)?;
+    let mut encrypter = Encrypter::new(&key)?;
+    encrypter.set_rsa_padding(Padding::PKCS1)?;
+    let mut wrapped = vec![0; encrypter.encrypt_len(data)?];
+    let length = encrypter.encrypt(data, &mut wrapped)?;
+    wrapped.truncate(length);
+    Ok(wrapped)
+}
+
+/// Encrypt `data` to the RSA public key of modulus `n` and exponent `e`,
+/// both big-endian, with OAEP padding, SHA-256 and MGF1 with SHA-256.
+#[cfg(feature = "resource")]
+pub(crate) fn rsa_oaep_sha256_encrypt(n: &[u8], e: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = Rsa::from_public_components(BigNum::from_slice(n)?, BigNum::from_slice(e)?)?;
+    let key = PKey::from_rsa(key)?;
+    let mut encrypter = Encrypter::new(&key)?;
+    encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+    encrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+    encrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+    let mut wrapped = vec![0; encrypter.encrypt_len(data)?];
+    let length = encrypter.encrypt(data, &mut wrapped)?;
+    wrapped.truncate(length);
+    Ok(wrapped)
+}
+
+/// Agree on a secret with the EC public key of `curve` at the big-endian
+/// coordinates `x` and `y`, using a fresh ephemeral key.
+#[cfg(feature = "resource")]
+pub(crate) fn ecdh_ephemeral(curve: EcCurve, x: &[u8], y: &[u8]) -> Result<EphemeralAgreement> {
+    let nid = match curve {
+        EcCurve::P256 => Nid::X9_62_PRIME256V1,
+        EcCurve::P384 => Nid::SECP384R1,
+    };
+    let group = EcGroup::from_curve_name(nid)?;
+    let peer = EcKey::from_public_key_affine_coordinates(
+        &group,
+        &BigNum::from_slice(x)?,
+        &BigNum::from_slice(y)?,
+    )?;
+    let peer = PKey::from_ec_key(peer)?;
+
+    let ephemeral = EcKey::generate(&group)?;
+    let mut ctx = BigNumContext::new()?;
+    let (mut epk_x, mut epk_y) = (BigNum::new()?, BigNum::new()?);
+    ephemeral
+        .public_key()
+        .affine_coordinates(&group, &mut epk_x, &mut epk_y, &mut ctx)?;
+    let ephemeral = PKey::from_ec_key(ephemeral)?;
+
+    let mut deriver = Deriver::new(&ephemeral)?;
+    deriver.set_peer(&peer)?;
+    let secret = Zeroizing::new(deriver.derive_to_vec()?);
+
+    let length = curve.coordinate_length() as i32;
+    Ok(EphemeralAgreement {
+        x: epk_x.to_vec_padded(length)?,
+        y: epk_y.to_vec_padded(length)?,
+        secret,
+    })
+}
+
+/// Restrict OpenSSL to its FIPS provider. Loading a provider disables the
+/// fallback to the default one, so that only the FIPS provider, and the base
+/// one for encoders and decoders, serve algorithms from then on.
+#[cfg(feature = "fips")]
+pub(super) fn enable_fips() -> Result<()> {
+    use anyhow::Context;
+    use openssl::provider::Provider;
+
+    let fips = Provider::load(None, "fips").context("load the OpenSSL FIPS provider")?;
+    let base = Provider::load(None, "base").context("load the OpenSSL base provider")?;
+    // The providers stay loaded for the lifetime of KBS.
+    std::mem::forget((fips, base));
+
+    // MD5 is only served by the default provider.
+    if openssl::hash::hash(MessageDigest::md5(), b"").is_ok() {
+        anyhow::bail!(
+            "OpenSSL still serves non FIPS algorithms, it was used before FIPS mode was enabled"
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,146 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The RustCrypto backend.
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "resource")]
+use {
+    super::{EcCurve, EphemeralAgreement, AES_256_GCM_NONCE_LENGTH},
+    aes_gcm::{
+        aead::{Aead, Payload},
+        Aes256Gcm, KeyInit, Nonce,
+    },
+    anyhow::{anyhow, bail},
+    p256::elliptic_curve::{
+        ecdh::EphemeralSecret,
+        sec1::{EncodedPoint, FromEncodedPoint, ModulusSize, ToEncodedPoint},
+        AffinePoint, CurveArithmetic, FieldBytes, FieldBytesSize, PublicKey,
+    },
+    rand::{rngs::OsRng, RngCore},
+    rsa::{BigUint, Oaep, Pkcs1v15Encrypt, RsaPublicKey},
+    zeroize::Zeroizing,
+};
+
+/// Fill `buf` with random bytes.
+#[cfg(feature = "resource")]
+pub(crate) fn fill_random(buf: &mut [u8]) -> Result<()> {
+    OsRng.try_fill_bytes(buf)?;
+    Ok(())
+}
+
+/// The SHA-256 digest of `data`.
+pub(crate) fn sha256(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(Sha256::digest(data).to_vec())
+}
+
+/// The HMAC-SHA256 of `data` under `key`.
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Encrypt `plaintext` with AES-256-GCM, authenticating `aad` too. Returns
+/// the ciphertext followed by the tag.
+#[cfg(feature = "resource")]
+pub(crate) fn aes_256_gcm_encrypt(
+    key: &[u8],
+    iv: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>> {
+    if iv.len() != AES_256_GCM_NONCE_LENGTH {
+        bail!("AES-256-GCM nonce must be {AES_256_GCM_NONCE_LENGTH} bytes");
+    }
+    Aes256Gcm::new_from_slice(key)?
+        .encrypt(
+            Nonce::from_slice(iv),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|e| anyhow!("AES-256-GCM encryption failed: {e}"))
+}
+
+/// Decrypt the output of [`aes_256_gcm_encrypt`].
+#[cfg(feature = "resource")]
+pub(crate) fn aes_256_gcm_decrypt(
+    key: &[u8],
+    iv: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    if iv.len() != AES_256_GCM_NONCE_LENGTH {
+        bail!("AES-256-GCM nonce must be {AES_256_GCM_NONCE_LENGTH} bytes");
+    }
+    Aes256Gcm::new_from_slice(key)?
+        .decrypt(Nonce::from_slice(iv), Payload { msg: sealed, aad })
+        .map(Zeroizing::new)
+        .map_err(|e| anyhow!("AES-256-GCM decryption failed: {e}"))
+}
+
+/// Encrypt `data` to the RSA public key of modulus `n` and exponent `e`,
+/// both big-endian, with PKCS #1 v1.5 padding.
+#[cfg(feature = "resource")]
+pub(crate) fn rsa_pkcs1v15_encrypt(n: &[u8], e: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e))?;
+    Ok(key.encrypt(&mut OsRng, Pkcs1v15Encrypt, data)?)
+}
+
+/// Encrypt `data` to the RSA public key of modulus `n` and exponent `e`,
+/// both big-endian, with OAEP padding, SHA-256 and MGF1 with SHA-256.
+#[cfg(feature = "resource")]
+pub(crate) fn rsa_oaep_sha256_encrypt(n: &[u8], e: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e))?;
+    Ok(key.encrypt(&mut OsRng, Oaep::new::<Sha256>(), data)?)
+}
+
+/// Agree on a secret with the EC public key of `curve` at the big-endian
+/// coordinates `x` and `y`, using a fresh ephemeral key.
+#[cfg(feature = "resource")]
+pub(crate) fn ecdh_ephemeral(curve: EcCurve, x: &[u8], y: &[u8]) -> Result<EphemeralAgreement> {
+    match curve {
+        EcCurve::P256 => ecdh_ephemeral_on::<p256::NistP256>(curve, x, y),
+        EcCurve::P384 => ecdh_ephemeral_on::<p384::NistP384>(curve, x, y),
+    }
+}
+
+#[cfg(feature = "resource")]
+fn ecdh_ephemeral_on<C>(curve: EcCurve, x: &[u8], y: &[u8]) -> Result<EphemeralAgreement>
+where
+    C: CurveArithmetic,
+    AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+    FieldBytesSize<C>: ModulusSize,
+{
+    let length = curve.coordinate_length();
+    if x.len() > length || y.len() > length {
+        bail!("EC public key coordinates are longer than {length} bytes");
+    }
+    let pad = |coordinate: &[u8]| {
+        let mut padded = FieldBytes::<C>::default();
+        padded[length - coordinate.len()..].copy_from_slice(coordinate);
+        padded
+    };
+    let point = EncodedPoint::<C>::from_affine_coordinates(&pad(x), &pad(y), false);
+    let peer = Option::<PublicKey<C>>::from(PublicKey::<C>::from_encoded_point(&point))
+        .ok_or_else(|| anyhow!("EC public key is not a point of the curve"))?;
+
+    let ephemeral = EphemeralSecret::<C>::random(&mut OsRng);
+    let epk = ephemeral.public_key().to_encoded_point(false);
+    let (Some(epk_x), Some(epk_y)) = (epk.x(), epk.y()) else {
+        bail!("ephemeral EC public key is the identity");
+    };
+    let secret = ephemeral.diffie_hellman(&peer);
+
+    Ok(EphemeralAgreement {
+        x: epk_x.to_vec(),
+        y: epk_y.to_vec(),
+        secret: Zeroizing::new(secret.raw_secret_bytes().to_vec()),
+    })
+}
@@ -0,0 +1,397 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The KBS attestation and resource protocol over gRPC, served next to the
+//! RESTful API. See `protos/kbs.proto`.
+
+use crate::attestation::{challenge::Challenges, AttestationService};
+use crate::audit::{Actor, AuditEvent, AuditEventType, AuditLog};
+use crate::http::{
+    attest_session, new_session, read_resource, repository_label, result_label, session_claims,
+    token_claims, Error, ReattestationInterval, RESOURCE_REQUESTS,
+};
+use crate::identity::WorkloadIdentity;
+use crate::logging::{with_correlation_id, REQUEST_ID_KEY};
+#[cfg(feature = "policy")]
+use crate::policy_engine::PolicyEngine;
+use crate::reload::Reloadable;
+use crate::resource::provision::{provision_workload, Provisioner};
+use crate::resource::{Repository, ResourceDesc};
+use crate::session::{session_handle, AuthRequest as AuthRequestBody, SessionMap};
+use crate::tenant::Tenants;
+use crate::tls::{ClientAuthConfig, ClientAuthScope};
+use crate::token::AttestationTokenVerifier;
+use actix_web::web;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use kbs_types::Attestation;
+use log::{error, info};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinHandle;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+
+use api::get_resource_request::Credential;
+use api::key_broker_service_server::{KeyBrokerService, KeyBrokerServiceServer};
+use api::{
+    AttestRequest, AttestResponse, AuthRequest, AuthResponse, GetResourceRequest,
+    GetResourceResponse,
+};
+
+mod api {
+    tonic::include_proto!("kbs");
+}
+
+pub(crate) struct KbsGrpc {
+    pub sessions: web::Data<SessionMap>,
+    pub timeout: web::Data<Reloadable<i64>>,
+    pub attestation_service: Arc<AttestationService>,
+    pub challenges: web::Data<Challenges>,
+    pub repository: web::Data<Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>>,
+    pub token_verifier:
+        web::Data<Reloadable<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>>,
+    pub reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    #[cfg(feature = "policy")]
+    pub policy_engine: web::Data<Reloadable<PolicyEngine>>,
+    /// The gRPC API serves the default tenant only.
+    pub tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    pub audit: web::Data<AuditLog>,
+    pub workload_identity: web::Data<WorkloadIdentity>,
+    pub provisioner: web::Data<Provisioner>,
+}
+
+impl KbsGrpc {
+    /// Serve the gRPC API at `sockets`, over TLS with the certificate chain
+    /// and private key files of `tls`. Unlike the HTTPS credentials, these are
+    /// not reloaded. The servers stop once `shutdown` changes, after the
+    /// requests in flight completed, and the returned tasks then finish.
+    pub async fn serve(
+        self,
+        sockets: &[SocketAddr],
+        tls: Option<(PathBuf, PathBuf)>,
+        client_auth: Option<&ClientAuthConfig>,
+        shutdown: watch::Receiver<()>,
+    ) -> Result<Vec<JoinHandle<()>>> {
+        let tls_config = match tls {
+            Some((certificate, private_key)) => {
+                let identity = Identity::from_pem(
+                    tokio::fs::read(&certificate)
+                        .await
+                        .context("read gRPC certificate")?,
+                    tokio::fs::read(&private_key)
+                        .await
+                        .context("read gRPC private key")?,
+                );
+                let mut tls_config = ServerTlsConfig::new().identity(identity);
+                // The gRPC API has no admin operations, so client
+                // certificates are only required with the `all` scope.
+                if let Some(client_auth) = client_auth {
+                    let ca_bundle = tokio::fs::read(&client_auth.ca_bundle)
+                        .await
+                        .context("read client CA bundle")?;
+                    tls_config = tls_config
+                        .client_ca_root(Certificate::from_pem(ca_bundle))
+                        .client_auth_optional(client_auth.scope != ClientAuthScope::All);
+                }
+                Some(tls_config)
+            }
+            None => None,
+        };
+
+        info!(
+            "Starting gRPC{} server at {sockets:?}",
+            if tls_config.is_some() { " TLS" } else { "" }
+        );
+
+        let service = Arc::new(self);
+        let mut servers = Vec::new();
+        for socket in sockets {
+            let incoming = TcpIncoming::new(*socket, true, None)
+                .map_err(|e| anyhow::anyhow!("bind gRPC socket {socket}: {e}"))?;
+            let mut server = Server::builder();
+            if let Some(tls_config) = &tls_config {
+                server = server
+                    .tls_config(tls_config.clone())
+                    .context("gRPC TLS config")?;
+            }
+            let router = server.add_service(KeyBrokerServiceServer::from_arc(service.clone()));
+            let socket = *socket;
+            let mut shutdown = shutdown.clone();
+            servers.push(tokio::spawn(async move {
+                let stopped = async move {
+                    let _ = shutdown.changed().await;
+                };
+                if let Err(e) = router.serve_with_incoming_shutdown(incoming, stopped).await {
+                    error!("gRPC server at {socket} failed: {e}");
+                }
+            }));
+        }
+
+        Ok(servers)
+    }
+}
+
+#[tonic::async_trait]
+impl KeyBrokerService for KbsGrpc {
+    #[tracing::instrument(skip_all)]
+    async fn auth(&self, request: Request<AuthRequest>) -> Result<Response<AuthResponse>, Status> {
+        info!("gRPC Auth API called.");
+        let request = request.into_inner();
+        let tee = serde_json::from_value(Value::String(request.tee))
+            .map_err(|e| Status::invalid_argument(format!("illegal TEE: {e}")))?;
+
+        let session = new_session(
+            AuthRequestBody {
+                version: request.version,
+                tee,
+                extra_params: request.extra_params,
+            },
+            &self.sessions,
+            self.timeout.get(),
+            &self.challenges,
+            None,
+            self.attestation_service.detects_tee(),
+            Some(request.session_id.as_str()).filter(|id| !id.is_empty()),
+        )
+        .await
+        .map_err(status)?;
+
+        let response = AuthResponse {
+            session_id: session.id().to_string(),
+            nonce: session.challenge().nonce.clone(),
+            extra_params: session.challenge().extra_params.clone(),
+        };
+        self.sessions.insert(session);
+
+        Ok(Response::new(response))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn attest(
+        &self,
+        request: Request<AttestRequest>,
+    ) -> Result<Response<AttestResponse>, Status> {
+        info!("gRPC Attest API called.");
+        let address = peer_address(&request);
+        let request = request.into_inner();
+        let attestation: Attestation = serde_json::from_str(&request.attestation)
+            .map_err(|e| Status::invalid_argument(format!("illegal attestation: {e}")))?;
+
+        let correlation_id = session_handle(&request.session_id);
+        let (token, _, identity) = with_correlation_id(
+            Some(correlation_id.clone()),
+            attest_session(
+                &request.session_id,
+                &attestation,
+                &self.sessions,
+                &self.attestation_service,
+                self.reattestation_interval.get(),
+                &self.tenants.get(),
+                None,
+                &self.audit,
+                &self.workload_identity,
+                // The TLS exporter of tonic connections isn't available.
+                None,
+                |event| AuditEvent::from_peer(event, correlation_id.clone(), address.clone()),
+            ),
+        )
+        .await
+        .map_err(status)?;
+
+        if let Some(identity) = identity {
+            provision_workload(
+                &self.provisioner,
+                &self.repository.get(),
+                None,
+                &identity,
+                &self.audit,
+                AuditEvent::from_peer(
+                    AuditEventType::ResourceProvisioning,
+                    correlation_id,
+                    address,
+                ),
+            )
+            .await;
+        }
+
+        Ok(Response::new(AttestResponse { token }))
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn get_resource(
+        &self,
+        request: Request<GetResourceRequest>,
+    ) -> Result<Response<GetResourceResponse>, Status> {
+        let address = peer_address(&request);
+        let request_id = request
+            .metadata()
+            .get(REQUEST_ID_KEY)
+            .and_then(|id| id.to_str().ok())
+            .map(str::to_string);
+        let request = request.into_inner();
+
+        let repository = match request.repository.as_str() {
+            "" => "default".to_string(),
+            repository => repository.to_string(),
+        };
+        let resource_description = ResourceDesc {
+            repository_name: repository.clone(),
+            resource_type: request.r#type,
+            resource_tag: request.tag,
+        };
+        let path = format!(
+            "{}/{}/{}",
+            resource_description.repository_name,
+            resource_description.resource_type,
+            resource_description.resource_tag
+        );
+
+        let correlation_id = match &request.credential {
+            Some(Credential::SessionId(session_id)) => session_handle(session_id),
+            _ => request_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        };
+
+        let mut identity = None;
+        let result = with_correlation_id(
+            Some(correlation_id.clone()),
+            self.resource_response(request.credential, resource_description, &mut identity),
+        )
+        .await;
+
+        RESOURCE_REQUESTS
+            .with_label_values(&[repository_label(&repository), result_label(&result)])
+            .inc();
+        self.audit
+            .record(
+                AuditEvent::from_peer(AuditEventType::ResourceAccess, correlation_id, address)
+                    .actor(Actor::attester(identity))
+                    .detail("path", path)
+                    .result(&result),
+            )
+            .await;
+
+        result.map(Response::new).map_err(status)
+    }
+}
+
+impl KbsGrpc {
+    /// The resource for the attester with `credential`, whose workload
+    /// identity is set to `identity`.
+    async fn resource_response(
+        &self,
+        credential: Option<Credential>,
+        resource_description: ResourceDesc,
+        identity: &mut Option<String>,
+    ) -> crate::http::Result<GetResourceResponse> {
+        let claims_str = match credential {
+            Some(Credential::SessionId(session_id)) => {
+                session_claims(
+                    &session_id,
+                    &self.sessions,
+                    None,
+                    self.reattestation_interval.get(),
+                )
+                .await?
+            }
+            Some(Credential::Token(token)) => {
+                token_claims(
+                    token,
+                    &self.token_verifier.get(),
+                    &self.tenants.get(),
+                    None,
+                    self.reattestation_interval.get(),
+                )
+                .await?
+            }
+            None => {
+                return Err(Error::InvalidRequest(
+                    "no session ID or token given".to_string(),
+                ))
+            }
+        };
+        let (claims_str, workload_identity) = self
+            .workload_identity
+            .annotate_str(&claims_str)
+            .map_err(|e| Error::AttestationClaimsParseFailed(format!("{e:#}")))?;
+        *identity = workload_identity;
+
+        let jwe = read_resource(
+            claims_str,
+            resource_description,
+            &self.repository.get(),
+            #[cfg(feature = "policy")]
+            &self.policy_engine.get(),
+        )
+        .await?;
+
+        let decode = |field: &str, value: &str| {
+            URL_SAFE_NO_PAD
+                .decode(value)
+                .map_err(|e| Error::JWEFailed(format!("base64 decode {field} failed: {e}")))
+        };
+        Ok(GetResourceResponse {
+            encrypted_key: decode("encrypted_key", &jwe.encrypted_key)?,
+            iv: decode("iv", &jwe.iv)?,
+            ciphertext: decode("ciphertext", &jwe.ciphertext)?,
+            tag: decode("tag", &jwe.tag)?,
+            protected: jwe.protected,
+        })
+    }
+}
+
+fn peer_address<T>(request: &Request<T>) -> Option<String> {
+    request
+        .remote_addr()
+        .map(|address| address.ip().to_string())
+}
+
+/// The gRPC status of a KBS protocol error, following the HTTP status codes
+/// of the RESTful API.
+fn status(e: Error) -> Status {
+    error!("{e}");
+    let message = e.to_string();
+    match e {
+        Error::ReadSecretFailed(_)
+        | Error::UnknownTenant(_)
+        | Error::UnknownSession(_)
+        | Error::UnknownPolicyCapture(_) => Status::not_found(message),
+        Error::ShuttingDown => Status::unavailable(message),
+        Error::InvalidRequest(_) | Error::ProtocolVersion { .. } | Error::ResourceRejected(_) => {
+            Status::invalid_argument(message)
+        }
+        Error::PolicyReject | Error::PermissionDenied(_) | Error::TenantMismatch => {
+            Status::permission_denied(message)
+        }
+        _ => Status::unauthenticated(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use tonic::Code;
+
+    #[rstest]
+    #[case(Error::ReadSecretFailed("test".into()), Code::NotFound)]
+    #[case(Error::ShuttingDown, Code::Unavailable)]
+    #[case(
+        Error::ProtocolVersion { reason: "test".into(), supported: Vec::new() },
+        Code::InvalidArgument
+    )]
+    #[case(Error::PolicyReject, Code::PermissionDenied)]
+    #[case(Error::TenantMismatch, Code::PermissionDenied)]
+    #[case(Error::ExpiredCookie, Code::Unauthenticated)]
+    #[case(Error::NonceReused, Code::Unauthenticated)]
+    #[case(Error::ReattestationRequired, Code::Unauthenticated)]
+    fn test_status(#[case] err: Error, #[case] code: Code) {
+        assert_eq!(status(err).code(), code);
+    }
+}
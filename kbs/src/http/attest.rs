@@ -2,64 +2,129 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{raise_error, session::SessionStatus};
+use crate::attestation::archive::AttestationRecord;
+use crate::attestation::detect::{detect_tee, TeeSelector};
+use crate::attestation::publisher::VerdictKind;
+#[cfg(feature = "resource")]
+use crate::resource::provision::{provision_workload, Provisioner};
+use crate::session::{session_handle, token_expiry, AuthRequest, IssuedToken, SessionStatus};
+use crate::{raise_error, tls::ChannelBinding};
+use actix_web::cookie::{time::OffsetDateTime, Cookie};
 
 use super::*;
 
 use anyhow::anyhow;
-use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
 use kbs_types::Challenge;
-use log::{debug, error, info};
-use semver::{BuildMetadata, Prerelease, Version, VersionReq};
+use log::{debug, error, info, warn};
+use semver::{Version, VersionReq};
 use serde_json::json;
 
-static KBS_MAJOR_VERSION: u64 = 0;
-static KBS_MINOR_VERSION: u64 = 1;
-static KBS_PATCH_VERSION: u64 = 0;
+/// Versions of the KBS protocol KBS speaks, newest first. Attesters of older
+/// versions are served as well.
+const PROTOCOL_VERSIONS: &[&str] = &["0.1.0"];
 
 lazy_static! {
-    static ref VERSION_REQ: VersionReq = {
-        let kbs_version = Version {
-            major: KBS_MAJOR_VERSION,
-            minor: KBS_MINOR_VERSION,
-            patch: KBS_PATCH_VERSION,
-            pre: Prerelease::EMPTY,
-            build: BuildMetadata::EMPTY,
-        };
+    static ref VERSION_REQ: VersionReq =
+        VersionReq::parse(&format!("<={}", PROTOCOL_VERSIONS[0])).unwrap();
+}
+
+fn protocol_version_error(reason: String) -> Error {
+    Error::ProtocolVersion {
+        reason,
+        supported: PROTOCOL_VERSIONS.iter().map(|v| v.to_string()).collect(),
+    }
+}
+
+/// Check that KBS speaks the protocol `version` requested by an attester.
+fn check_protocol_version(version: &str) -> Result<()> {
+    let requested = Version::parse(version)
+        .map_err(|e| protocol_version_error(format!("illegal requested version: {e}")))?;
+    if !VERSION_REQ.matches(&requested) {
+        raise_error!(protocol_version_error(format!(
+            "expected version: {}, requested version: {version}",
+            *VERSION_REQ
+        )));
+    }
+    Ok(())
+}
 
-        VersionReq::parse(&format!("<={kbs_version}")).unwrap()
+/// Start a KBS session for the `request` of an attester. The session still
+/// has to be inserted into the session map. The `auto` TEE is only accepted
+/// with `tee_detection`. The workload identity of `prior_session`, the
+/// session the attester had before, is kept if it is attested, so that the
+/// policy gets the latest token of the identity as the prior attestation.
+pub(crate) async fn new_session(
+    request: AuthRequest,
+    map: &SessionMap,
+    timeout: i64,
+    challenges: &Challenges,
+    tenant: Option<String>,
+    tee_detection: bool,
+    prior_session: Option<&str>,
+) -> Result<SessionStatus> {
+    debug!("Auth Request: {:?}", &request);
+    if map.is_draining() {
+        raise_error!(Error::ShuttingDown);
+    }
+
+    check_protocol_version(&request.version)?;
+
+    let tee = match request.tee {
+        TeeSelector::Tee(tee) => Some(tee),
+        TeeSelector::Auto if tee_detection => None,
+        TeeSelector::Auto => raise_error!(Error::InvalidRequest(
+            "TEE detection is disabled, the TEE must be named".into()
+        )),
     };
+    let challenge = challenges
+        .generate_challenge(tee, request.extra_params.clone())
+        .await
+        .map_err(|e| Error::FailedAuthentication(format!("generate challenge: {e:?}")))?;
+
+    let prior_identity = match prior_session {
+        Some(id) => map.attested_identity(id, tenant.as_deref()).await,
+        None => None,
+    };
+
+    SessionStatus::auth(request, timeout, challenge, tenant, prior_identity)
+        .map_err(|e| Error::FailedAuthentication(format!("Session: {e}")))
 }
 
 /// POST /auth
+#[tracing::instrument(skip_all)]
 pub(crate) async fn auth(
-    request: web::Json<Request>,
+    request: web::Json<AuthRequest>,
+    http_request: HttpRequest,
     map: web::Data<SessionMap>,
-    timeout: web::Data<i64>,
+    timeout: web::Data<Reloadable<i64>>,
+    challenges: web::Data<Challenges>,
     attestation_service: web::Data<Arc<AttestationService>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
 ) -> Result<HttpResponse> {
     info!("Auth API called.");
-    debug!("Auth Request: {:?}", &request);
-    let version = Version::parse(&request.version).unwrap();
-    if !VERSION_REQ.matches(&version) {
-        raise_error!(Error::ProtocolVersion(format!(
-            "expected version: {}, requested version: {}",
-            *VERSION_REQ,
-            request.version.clone()
-        )));
-    }
-
-    let challenge = attestation_service
-        .generate_challenge(request.tee, request.extra_params.clone())
-        .await
-        .map_err(|e| Error::FailedAuthentication(format!("generate challenge: {e:?}")))?;
-
-    let session = SessionStatus::auth(request.0, **timeout, challenge)
-        .map_err(|e| Error::FailedAuthentication(format!("Session: {e}")))?;
+    let tenant = tenants
+        .get()
+        .of_request(&http_request)?
+        .map(|tenant| tenant.id.clone());
+    let session = new_session(
+        request.0,
+        &map,
+        timeout.get(),
+        &challenges,
+        tenant,
+        attestation_service.detects_tee(),
+        http_request
+            .cookie(KBS_SESSION_ID)
+            .as_ref()
+            .map(Cookie::value),
+    )
+    .await?;
 
     let response = HttpResponse::Ok()
         .cookie(session.cookie())
+        .insert_header((PROTOCOL_VERSIONS_HEADER, PROTOCOL_VERSIONS.join(", ")))
         .json(session.challenge());
 
     map.insert(session);
@@ -68,86 +133,498 @@ pub(crate) async fn auth(
 }
 
 /// POST /attest
+#[tracing::instrument(skip_all)]
 pub(crate) async fn attest(
     attestation: web::Json<Attestation>,
     request: HttpRequest,
     map: web::Data<SessionMap>,
     attestation_service: web::Data<Arc<AttestationService>>,
+    reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    audit: web::Data<AuditLog>,
+    workload_identity: web::Data<WorkloadIdentity>,
+    #[cfg(feature = "resource")] repository: web::Data<
+        Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>,
+    >,
+    #[cfg(feature = "resource")] provisioner: web::Data<Provisioner>,
 ) -> Result<HttpResponse> {
     info!("Attest API called.");
+    let channel_binding = request.conn_data::<ChannelBinding>();
     let cookie = request.cookie(KBS_SESSION_ID).ok_or(Error::MissingCookie)?;
 
-    let (tee, nonce) = {
+    let tenants = tenants.get();
+    let tenant = tenants.of_request(&request)?;
+    let (token, cookie, identity) = attest_session(
+        cookie.value(),
+        &attestation,
+        &map,
+        &attestation_service,
+        reattestation_interval.get(),
+        &tenants,
+        tenant.as_ref().map(|tenant| tenant.id.as_str()),
+        &audit,
+        &workload_identity,
+        channel_binding.map(|binding| binding.0.as_slice()),
+        |event| AuditEvent::new(event, &request),
+    )
+    .await?;
+
+    #[cfg(feature = "resource")]
+    if let Some(identity) = identity {
+        let repository = match &tenant {
+            Some(tenant) => tenant.repository.clone(),
+            None => repository.get(),
+        };
+        provision_workload(
+            &provisioner,
+            &repository,
+            tenant.as_ref().map(|tenant| tenant.id.as_str()),
+            &identity,
+            &audit,
+            AuditEvent::new(AuditEventType::ResourceProvisioning, &request),
+        )
+        .await;
+    }
+    #[cfg(not(feature = "resource"))]
+    let _ = identity;
+
+    let body = serde_json::to_string(&json!({
+        "token": token,
+    }))
+    .map_err(|e| Error::TokenIssueFailed(format!("Serialize token failed {e}")))?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .content_type("application/json")
+        .body(body))
+}
+
+/// Verify the `attestation` of the session `session_id` of `tenant` with the
+/// attestation policy of the tenant and issue its attestation token. Returns
+/// the token, the updated session cookie and the identity of the workload if
+/// it was attested anew. The token of an already attested
+/// session is returned again until it is older than `reattestation_interval`.
+/// The audit records of the attempt are started with `audit_event`, and name
+/// the attester by its `workload_identity`, which the claims of the session
+/// are annotated with. `channel_binding` is the TLS channel binding of the
+/// connection the attestation was received over, if any. The prior identity,
+/// the one of the session the attester started this one from, is resolved
+/// before the evidence is evaluated once, with the latest token of that
+/// identity as the prior attestation. The attester must be attested as the
+/// same workload again, and a workload with a prior attestation can't be
+/// attested without starting from its prior session.
+pub(crate) async fn attest_session(
+    session_id: &str,
+    attestation: &Attestation,
+    map: &SessionMap,
+    attestation_service: &AttestationService,
+    reattestation_interval: ReattestationInterval,
+    tenants: &Tenants,
+    tenant: Option<&str>,
+    audit: &AuditLog,
+    workload_identity: &WorkloadIdentity,
+    channel_binding: Option<&[u8]>,
+    audit_event: impl Fn(AuditEventType) -> AuditEvent,
+) -> Result<(String, Cookie<'static>, Option<String>)> {
+    let (tee, nonce, timeout, prior_identity) = {
         let session = map
             .sessions
-            .get_async(cookie.value())
+            .get_async(session_id)
             .await
             .ok_or(Error::InvalidCookie)?;
         let session = session.get();
 
-        debug!("Session ID {}", session.id());
+        debug!("Session {}", session_handle(session.id()));
 
         if session.is_expired() {
             raise_error!(Error::ExpiredCookie);
         }
 
-        if let SessionStatus::Attested { token, .. } = session {
+        if session.tenant() != tenant {
+            raise_error!(Error::TenantMismatch);
+        }
+
+        if let SessionStatus::Attested {
+            token, attested_at, ..
+        } = session
+        {
+            // The challenge of the session is gone, so a new one is needed.
+            reattestation_interval.check(*attested_at)?;
             debug!(
                 "Session {} is already attested. Skip attestation and return the old token",
-                session.id()
+                session_handle(session.id())
             );
-            let body = serde_json::to_string(&json!({
-                "token": token,
-            }))
-            .map_err(|e| Error::TokenIssueFailed(format!("Serialize token failed {e}")))?;
-
-            return Ok(HttpResponse::Ok()
-                .cookie(session.cookie())
-                .content_type("application/json")
-                .body(body));
+            return Ok((token.clone(), session.cookie(), None));
         }
 
-        let attestation_str = serde_json::to_string_pretty(&attestation.0)
+        let attestation_str = serde_json::to_string_pretty(attestation)
             .map_err(|_| Error::AttestationFailed("Failed to serialize Attestation".into()))?;
         debug!("Attestation: {attestation_str}");
 
-        (session.request().tee, session.challenge().nonce.to_string())
+        (
+            session.request().tee,
+            session.challenge().nonce.to_string(),
+            *session.timeout(),
+            session.prior_identity().map(str::to_string),
+        )
     };
 
-    let attestation_str = serde_json::to_string(&attestation)
-        .map_err(|e| Error::AttestationFailed(format!("serialize attestation failed : {e:?}")))?;
-    let token = attestation_service
-        .verify(tee, &nonce, &attestation_str)
+    let tee = match tee {
+        TeeSelector::Tee(tee) => tee,
+        TeeSelector::Auto => detect_tee(&attestation.tee_evidence)
+            .map_err(|e| Error::AttestationFailed(format!("detect the TEE: {e:#}")))?,
+    };
+
+    // A nonce answers a single attestation, so that evidence can't be
+    // replayed, e.g. concurrently on the same session.
+    let consumed = map
+        .consume_nonce(&nonce, timeout)
         .await
-        .map_err(|e| Error::AttestationFailed(format!("{e:?}")))?;
-
-    let claims_b64 = token
-        .split('.')
-        .nth(1)
-        .ok_or_else(|| Error::TokenIssueFailed("Illegal token format".to_string()))?;
-    let claims = String::from_utf8(
-        URL_SAFE_NO_PAD
-            .decode(claims_b64)
-            .map_err(|e| Error::TokenIssueFailed(format!("Illegal token base64 claims: {e}")))?,
-    )
-    .map_err(|e| Error::TokenIssueFailed(format!("Illegal token base64 claims: {e}")))?;
+        .map_err(|e| Error::AttestationFailed(format!("record the nonce: {e:#}")))?;
+    if !consumed {
+        raise_error!(Error::NonceReused);
+    }
+
+    let attestation_str = serde_json::to_string(attestation)
+        .map_err(|e| Error::AttestationFailed(format!("serialize attestation failed : {e:?}")))?;
+
+    let tee_name = serde_json::to_value(tee)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string));
+    audit
+        .record(
+            audit_event(AuditEventType::AttestationAttempt)
+                .actor(Actor::attester(tee_name.clone())),
+        )
+        .await;
 
-    let mut session = map
-        .sessions
-        .get_async(cookie.value())
+    let policy_id = tenants.attestation_policy(tenant)?;
+    let rvps_namespace = tenants.rvps_namespace(tenant)?;
+    let prior_token = match &prior_identity {
+        Some(identity) => map.prior_token(tenant, identity).await,
+        None => None,
+    };
+    let verdict = attestation_service
+        .verify(
+            tee,
+            &nonce,
+            &attestation_str,
+            &policy_id,
+            rvps_namespace.as_deref(),
+            channel_binding,
+            prior_token.as_deref(),
+        )
+        .await;
+    ATTESTATION_REQUESTS
+        .with_label_values(&[
+            tee_name.as_deref().unwrap_or_default(),
+            result_label(&verdict),
+        ])
+        .inc();
+    // The attester is named by its TEE unless it has an identity.
+    let identity = verdict
+        .as_ref()
+        .ok()
+        .and_then(|verdict| workload_identity.of(&verdict.claims));
+    let mut event = audit_event(AuditEventType::AttestationVerdict)
+        .actor(Actor::attester(identity.or(tee_name)))
+        .result(&verdict);
+    if let Ok(verdict) = &verdict {
+        event = event.detail("policies", json!(verdict.policies));
+        let drift = attestation_service.track_drift(&verdict.claims).await;
+        if !drift.is_empty() {
+            event = event.detail("drift", json!(drift));
+        }
+    }
+    if attestation_service.archives() {
+        attestation_service.archive(
+            AttestationRecord::new(&event, tee, &policy_id, &attestation_str)
+                .session(&nonce, channel_binding)
+                .verdict(&verdict),
+        );
+    }
+    attestation_service.publish(VerdictKind::Attestation, &event, tee, &verdict);
+    audit.record(event).await;
+
+    let mut verdict = verdict.map_err(|e| Error::AttestationFailed(format!("{e:?}")))?;
+    let identity = workload_identity.annotate(&mut verdict.claims);
+    if prior_identity.is_some() && identity != prior_identity {
+        raise_error!(Error::AttestationFailed(
+            "the attester isn't the workload of its prior session".into()
+        ));
+    }
+    // The prior attestation can't be left out by leaving out the cookie of
+    // the prior session, which the policy would then not be evaluated with.
+    if let (None, Some(identity)) = (&prior_identity, &identity) {
+        if map.prior_token(tenant, identity).await.is_some() {
+            raise_error!(Error::AttestationFailed(
+                "the workload has a prior attestation, authenticate with its session".into()
+            ));
+        }
+    }
+
+    let cookie = {
+        let mut session = map
+            .sessions
+            .get_async(session_id)
+            .await
+            .ok_or(Error::InvalidCookie)?;
+        let session = session.get_mut();
+        session.attest(tee, verdict.claims.to_string(), verdict.token.clone());
+        session.cookie()
+    };
+
+    // The evidence is kept to verify it again while the token is valid, even
+    // once the session expired.
+    let issued = IssuedToken {
+        session_handle: session_handle(session_id),
+        tenant: tenant.map(str::to_string),
+        tee,
+        evidence: attestation.tee_evidence.clone(),
+        attestation_claims: verdict.claims.to_string(),
+        attested_at: OffsetDateTime::now_utc(),
+        expires_at: token_expiry(&verdict.token).unwrap_or(timeout),
+    };
+    if !map.issue(&verdict.token, issued).await {
+        warn!(
+            "Evidence of session {} isn't kept, it won't be verified again",
+            session_handle(session_id)
+        );
+    }
+
+    if let Some(identity) = &identity {
+        map.record_attestation(tenant, identity, &verdict.token, timeout)
+            .await;
+    }
+
+    Ok((verdict.token, cookie, identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("0.1.0", true)]
+    #[case("0.0.1", true)]
+    #[case("0.2.0", false)]
+    #[case("1.0.0", false)]
+    #[case("v1", false)]
+    fn test_check_protocol_version(#[case] version: &str, #[case] supported: bool) {
+        match check_protocol_version(version) {
+            Ok(()) => assert!(supported),
+            Err(Error::ProtocolVersion {
+                supported: versions,
+                ..
+            }) => {
+                assert!(!supported);
+                assert_eq!(versions, PROTOCOL_VERSIONS);
+            }
+            Err(e) => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[rstest]
+    #[case(false, false)]
+    #[case(true, true)]
+    #[tokio::test]
+    async fn test_auto_tee(#[case] tee_detection: bool, #[case] accepted: bool) {
+        use crate::attestation::challenge::{ChallengeConfig, NonceChallenge};
+
+        let challenges = Challenges::new(Arc::new(
+            NonceChallenge::new(ChallengeConfig::default()).unwrap(),
+        ));
+        let request: AuthRequest = serde_json::from_value(json!({
+            "version": "0.1.0",
+            "tee": "auto",
+            "extra-params": "",
+        }))
+        .unwrap();
+
+        match new_session(
+            request,
+            &SessionMap::new(),
+            5,
+            &challenges,
+            None,
+            tee_detection,
+            None,
+        )
         .await
-        .ok_or(Error::InvalidCookie)?;
-    let session = session.get_mut();
+        {
+            Ok(session) => {
+                assert!(accepted);
+                assert_eq!(session.request().tee, TeeSelector::Auto);
+            }
+            Err(Error::InvalidRequest(_)) => assert!(!accepted),
+            Err(e) => panic!("unexpected error {e}"),
+        }
+    }
 
-    let body = serde_json::to_string(&json!({
-        "token": token,
-    }))
-    .map_err(|e| Error::TokenIssueFailed(format!("Serialize token failed {e}")))?;
+    #[tokio::test]
+    async fn test_draining() {
+        use crate::attestation::challenge::{ChallengeConfig, NonceChallenge};
 
-    session.attest(claims, token);
+        let challenges = Challenges::new(Arc::new(
+            NonceChallenge::new(ChallengeConfig::default()).unwrap(),
+        ));
+        let request = || -> AuthRequest {
+            serde_json::from_value(json!({
+                "version": "0.1.0",
+                "tee": "sample",
+                "extra-params": "",
+            }))
+            .unwrap()
+        };
+        let map = SessionMap::new();
+        assert!(
+            new_session(request(), &map, 5, &challenges, None, false, None)
+                .await
+                .is_ok()
+        );
 
-    Ok(HttpResponse::Ok()
-        .cookie(session.cookie())
-        .content_type("application/json")
-        .body(body))
+        // No session is started once KBS is shutting down.
+        map.drain();
+        assert!(matches!(
+            new_session(request(), &map, 5, &challenges, None, false, None).await,
+            Err(Error::ShuttingDown)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_prior_attestation() {
+        use crate::attestation::{Attest, Verdict};
+        use crate::identity::WorkloadIdentityConfig;
+        use kbs_types::Tee;
+        use std::sync::Mutex;
+        use uuid::Uuid;
+
+        /// Attests the workload named by the evidence, unless it is `denied`,
+        /// and records the prior token of every evaluation.
+        #[derive(Default)]
+        struct Backend {
+            prior_tokens: Mutex<Vec<Option<String>>>,
+        }
+
+        #[async_trait::async_trait]
+        impl Attest for Backend {
+            async fn verify(
+                &self,
+                _: Tee,
+                _: &str,
+                attestation: &str,
+                _: &str,
+                _: Option<&str>,
+                _: Option<&[u8]>,
+                prior_token: Option<&str>,
+            ) -> anyhow::Result<Verdict> {
+                let mut prior_tokens = self.prior_tokens.lock().unwrap();
+                prior_tokens.push(prior_token.map(str::to_string));
+                let attestation: serde_json::Value = serde_json::from_str(attestation)?;
+                let workload = attestation["tee-evidence"].as_str().unwrap_or_default();
+                if workload == "denied" {
+                    anyhow::bail!("the policy denied the evidence");
+                }
+                Ok(Verdict {
+                    token: format!("token-{}", prior_tokens.len()),
+                    claims: json!({ "workload": workload }),
+                    policies: Vec::new(),
+                })
+            }
+        }
+
+        let backend = Arc::new(Backend::default());
+        let attestation_service = AttestationService::from_backend(backend.clone());
+        let map = SessionMap::new();
+        let audit = AuditLog::new(None, &[]).await.unwrap();
+        let workload_identity = WorkloadIdentity::new(Some(
+            &serde_json::from_value::<WorkloadIdentityConfig>(json!({ "template": "{/workload}" }))
+                .unwrap(),
+        ))
+        .unwrap();
+        let attest = |prior_identity: Option<&str>, workload: &str| {
+            let session = SessionStatus::auth(
+                kbs_types::Request {
+                    version: "0.1.0".into(),
+                    tee: Tee::Tdx,
+                    extra_params: String::new(),
+                }
+                .into(),
+                5,
+                Challenge {
+                    nonce: Uuid::new_v4().to_string(),
+                    extra_params: String::new(),
+                },
+                None,
+                prior_identity.map(str::to_string),
+            )
+            .unwrap();
+            let session_id = session.id().to_string();
+            map.insert(session);
+            let attestation: Attestation = serde_json::from_value(json!({
+                "tee-pubkey": {"kty": "RSA", "alg": "RSA1_5", "k-mod": "AQAB", "k-exp": "AQAB"},
+                "tee-evidence": workload,
+            }))
+            .unwrap();
+            let (map, attestation_service, audit, workload_identity) =
+                (&map, &attestation_service, &audit, &workload_identity);
+            async move {
+                attest_session(
+                    &session_id,
+                    &attestation,
+                    map,
+                    attestation_service,
+                    ReattestationInterval::default(),
+                    &Tenants::default(),
+                    None,
+                    audit,
+                    workload_identity,
+                    None,
+                    |event| AuditEvent::from_peer(event, String::new(), None),
+                )
+                .await
+                .map(|(token, _, identity)| (token, identity))
+            }
+        };
+
+        let (token, identity) = attest(None, "w").await.unwrap();
+        assert_eq!(
+            (token.as_str(), identity.as_deref()),
+            ("token-1", Some("w"))
+        );
+
+        // The evidence is evaluated once, with the prior token of the identity
+        // of the prior session, whether the policy allows it or not.
+        let (token, _) = attest(Some("w"), "w").await.unwrap();
+        assert_eq!(token, "token-2");
+        assert!(matches!(
+            attest(Some("w"), "denied").await,
+            Err(Error::AttestationFailed(_))
+        ));
+        // Another workload can't start from the prior session.
+        assert!(matches!(
+            attest(Some("w"), "v").await,
+            Err(Error::AttestationFailed(_))
+        ));
+        // Nor the workload without its prior session.
+        assert!(matches!(
+            attest(None, "w").await,
+            Err(Error::AttestationFailed(_))
+        ));
+        let (token, _) = attest(None, "v").await.unwrap();
+        assert_eq!(token, "token-6");
+
+        assert_eq!(
+            *backend.prior_tokens.lock().unwrap(),
+            [
+                None,
+                Some("token-1".to_string()),
+                Some("token-2".to_string()),
+                Some("token-2".to_string()),
+                None,
+                None,
+            ]
+        );
+    }
 }
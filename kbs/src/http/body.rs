@@ -0,0 +1,165 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use futures::StreamExt;
+use std::path::PathBuf;
+use tempfile::TempPath;
+use tokio::io::AsyncWriteExt;
+use zeroize::Zeroizing;
+
+use super::*;
+
+/// How the uploaded resources are received.
+#[derive(Clone, Debug)]
+pub(crate) struct UploadConfig {
+    /// Maximum size in bytes of an uploaded resource.
+    pub limit: usize,
+
+    /// Size in bytes beyond which an uploaded resource is written to a
+    /// temporary file instead of being kept in memory.
+    pub spill_threshold: usize,
+
+    /// Directory of the temporary files.
+    pub spill_dir: PathBuf,
+}
+
+/// A request body, received in memory up to the spill threshold and into a
+/// temporary file beyond. The memory is wiped and the file removed when the
+/// body is dropped.
+pub(crate) enum SpooledBody {
+    Memory(Zeroizing<Vec<u8>>),
+    File(TempPath),
+}
+
+impl SpooledBody {
+    /// Receive `payload` chunk by chunk, failing as soon as it exceeds the
+    /// upload limit. Past the spill threshold it is received into a
+    /// temporary file if `spill` is set, and into memory otherwise.
+    pub(crate) async fn receive(
+        mut payload: web::Payload,
+        config: &UploadConfig,
+        spill: bool,
+    ) -> Result<Self> {
+        let mut buffer = Zeroizing::new(Vec::new());
+        let mut file = None;
+        let mut size = 0;
+
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| payload_error(e.into()))?;
+            size += chunk.len();
+            if size > config.limit {
+                return Err(Error::PayloadTooLarge(format!(
+                    "resource is larger than {} bytes",
+                    config.limit
+                )));
+            }
+
+            if spill && file.is_none() && size > config.spill_threshold {
+                file = Some(Self::spill(config, &buffer).await?);
+                buffer = Zeroizing::new(Vec::new());
+            }
+            match &mut file {
+                Some((spilled, _)) => spilled.write_all(&chunk).await.map_err(spill_error)?,
+                None => extend(&mut buffer, &chunk),
+            }
+        }
+
+        match file {
+            Some((mut spilled, path)) => {
+                spilled.flush().await.map_err(spill_error)?;
+                Ok(Self::File(path))
+            }
+            None => Ok(Self::Memory(buffer)),
+        }
+    }
+
+    /// Create a temporary file holding the part of the body received so far.
+    async fn spill(config: &UploadConfig, received: &[u8]) -> Result<(tokio::fs::File, TempPath)> {
+        let (file, path) = tempfile::Builder::new()
+            .prefix("kbs-upload-")
+            .tempfile_in(&config.spill_dir)
+            .map_err(spill_error)?
+            .into_parts();
+        let mut file = tokio::fs::File::from_std(file);
+        file.write_all(received).await.map_err(spill_error)?;
+        Ok((file, path))
+    }
+}
+
+/// Append `chunk` to `buffer`. A full buffer is copied to a larger one, so
+/// that the old one is wiped rather than left behind by a reallocation.
+fn extend(buffer: &mut Zeroizing<Vec<u8>>, chunk: &[u8]) {
+    if buffer.capacity() - buffer.len() < chunk.len() {
+        let capacity = (buffer.len() + chunk.len()).max(2 * buffer.capacity());
+        let mut grown = Zeroizing::new(Vec::with_capacity(capacity));
+        grown.extend_from_slice(buffer);
+        *buffer = grown;
+    }
+    buffer.extend_from_slice(chunk);
+}
+
+fn spill_error(e: std::io::Error) -> Error {
+    Error::SetSecretFailed(format!("write uploaded resource to a temporary file: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    async fn receive(
+        request: HttpRequest,
+        payload: web::Payload,
+        config: web::Data<UploadConfig>,
+    ) -> Result<String> {
+        let spill = request.query_string() != "memory";
+        let body = SpooledBody::receive(payload, &config, spill).await?;
+        Ok(match body {
+            SpooledBody::Memory(data) => format!("memory {}", String::from_utf8_lossy(&data)),
+            SpooledBody::File(path) => {
+                format!("file {}", std::fs::read_to_string(&path).unwrap())
+            }
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_spooled_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = UploadConfig {
+            limit: 16,
+            spill_threshold: 4,
+            spill_dir: dir.path().to_path_buf(),
+        };
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/", web::post().to(receive)),
+        )
+        .await;
+        let request = |body: &'static str| TestRequest::post().uri("/").set_payload(body);
+
+        let body = call_and_read_body(&app, request("1234").to_request()).await;
+        assert_eq!(body, "memory 1234");
+
+        let body = call_and_read_body(&app, request("123456789").to_request()).await;
+        assert_eq!(body, "file 123456789");
+        // The temporary file is removed with the body.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let body = call_and_read_body(
+            &app,
+            TestRequest::post()
+                .uri("/?memory")
+                .set_payload("123456789")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(body, "memory 123456789");
+
+        let response = call_service(&app, request("larger than the limit").to_request()).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -4,6 +4,83 @@
 
 use super::*;
 
+/// Authenticate the admin user behind `request`, make it the actor of `event`
+/// and check that its token grants `permission`. When client certificate
+/// authentication is configured the requester must have presented a verified
+/// client certificate. With insecure APIs enabled every such requester is
+/// accepted as an anonymous admin. Requesters outside of the `allowlist` are
+/// always rejected.
+pub(crate) fn authorize_admin(
+    request: &HttpRequest,
+    permission: Permission,
+    event: &mut AuditEvent,
+    admin_keys: &[AdminKey],
+    insecure: bool,
+    client_auth: &Option<ClientAuthScope>,
+    allowlist: &AdminAllowlist,
+) -> Result<()> {
+    let address = request.peer_addr().map(|address| address.ip());
+    if !allowlist.allows(address) {
+        return Err(Error::PermissionDenied(match address {
+            Some(address) => format!("admin APIs are not allowed from {address}"),
+            None => "admin APIs are not allowed without a client IP address".to_string(),
+        }));
+    }
+
+    if client_auth.is_some() && request.conn_data::<ClientIdentity>().is_none() {
+        return Err(Error::FailedAuthentication(
+            "A client certificate is required".to_string(),
+        ));
+    }
+
+    if insecure {
+        event.set_actor(Actor::admin(None));
+        return Ok(());
+    }
+
+    if admin_keys.is_empty() {
+        return Err(Error::UserPublicKeyNotProvided);
+    }
+
+    let (key, claims) = validate_auth(request, admin_keys).map_err(|e| {
+        Error::FailedAuthentication(format!("Requester is not an authorized user: {e:#}"))
+    })?;
+
+    event.set_actor(Actor::admin(claims.subject.clone()));
+    event.set_detail("admin_key", key.name.as_str());
+    if !key.grants(&claims.custom, permission) {
+        return Err(Error::PermissionDenied(format!(
+            "token of {} doesn't grant {permission}",
+            claims.subject.as_deref().unwrap_or("anonymous admin")
+        )));
+    }
+
+    Ok(())
+}
+
+/// The admin keys of the tenant addressed by `request`.
+pub(crate) fn tenant_admin_keys(
+    tenant: &Option<Arc<Tenant>>,
+    admin_keys: &Reloadable<Arc<Vec<AdminKey>>>,
+) -> Arc<Vec<AdminKey>> {
+    match tenant {
+        Some(tenant) => tenant.admin_keys.clone(),
+        None => admin_keys.get(),
+    }
+}
+
+/// The resource policy engine of the tenant addressed by `request`.
+#[cfg(feature = "policy")]
+pub(crate) fn tenant_policy_engine(
+    tenant: &Option<Arc<Tenant>>,
+    policy_engine: &Reloadable<PolicyEngine>,
+) -> PolicyEngine {
+    match tenant {
+        Some(tenant) => tenant.policy_engine.clone(),
+        None => policy_engine.get(),
+    }
+}
+
 #[cfg(feature = "as")]
 #[derive(serde::Deserialize, Debug)]
 pub struct SetPolicyInput {
@@ -11,72 +88,886 @@ pub struct SetPolicyInput {
     policy: String,
 }
 
+/// Require the clients attested with the former attestation policies to
+/// attest again, if configured, and check in the background which tokens of
+/// `tenant` fail the changed policy `policy_id`.
+#[cfg(feature = "as")]
+fn attestation_policy_changed(
+    attestation_service: &web::Data<Arc<AttestationService>>,
+    reattestation_interval: &Reloadable<ReattestationInterval>,
+    sessions: &web::Data<SessionMap>,
+    tenants: Arc<Tenants>,
+    tenant: Option<String>,
+    policy_id: &str,
+    audit: &web::Data<AuditLog>,
+) {
+    if attestation_service.reattest_on_policy_change() {
+        let now = actix_web::cookie::time::OffsetDateTime::now_utc();
+        reattestation_interval.update(|interval| interval.invalidate(now));
+    }
+    check_sessions_later(
+        sessions.clone(),
+        attestation_service.clone(),
+        tenants,
+        tenant,
+        policy_id,
+        audit.clone(),
+    );
+}
+
 #[cfg(feature = "as")]
 /// POST /attestation-policy
+#[tracing::instrument(skip_all)]
 pub(crate) async fn attestation_policy(
     request: HttpRequest,
     input: web::Json<SetPolicyInput>,
-    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
     insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
     attestation_service: web::Data<Arc<AttestationService>>,
+    reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    sessions: web::Data<SessionMap>,
+    audit: web::Data<AuditLog>,
 ) -> Result<HttpResponse> {
-    if !insecure.get_ref() {
-        let user_pub_key = user_pub_key
-            .as_ref()
-            .as_ref()
-            .ok_or(Error::UserPublicKeyNotProvided)?;
+    let mut event = AuditEvent::new(AuditEventType::PolicyChange, &request)
+        .detail("policy", "attestation")
+        .detail("policy_id", input.policy_id.as_str());
 
-        validate_auth(&request, user_pub_key).map_err(|e| {
-            Error::FailedAuthentication(format!("Requester is not an authorized user: {e}"))
-        })?;
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::WritePolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+        tenants.get().check_attestation_policy(
+            tenant.as_ref().map(|tenant| tenant.id.as_str()),
+            &input.policy_id,
+        )?;
+
+        attestation_service
+            .set_policy(&input.policy_id, &input.policy)
+            .await
+            .map_err(|e| Error::PolicyEndpoint(format!("Set policy error {e}")))?;
+        attestation_policy_changed(
+            &attestation_service,
+            &reattestation_interval,
+            &sessions,
+            tenants.get(),
+            tenant.map(|tenant| tenant.id.clone()),
+            &input.policy_id,
+            &audit,
+        );
+        Ok(())
     }
+    .await;
 
-    attestation_service
-        .set_policy(&input.policy_id, &input.policy)
+    audit.record(event.result(&result)).await;
+    result?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(feature = "as")]
+/// GET /attestation-policy
+///
+/// The returned body would look like
+/// ```json
+/// [
+///     {"policy-id": <id-1>, "policy-hash": <hash-1>},
+///     {"policy-id": <id-2>, "policy-hash": <hash-2>},
+///     ...
+/// ]
+/// ```
+/// with the policies the requesting admin manages only.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn list_attestation_policies(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &request)
+        .detail("action", "list-attestation-policies");
+
+    let result = async {
+        let tenants = tenants.get();
+        let tenant = tenants.of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::ReadPolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        let policies = attestation_service
+            .list_policies()
+            .await
+            .map_err(|e| Error::PolicyEndpoint(format!("List policies error {e:#}")))?;
+        let tenant = tenant.as_ref().map(|tenant| tenant.id.as_str());
+        let mut policies: Vec<_> = policies
+            .into_iter()
+            .filter(|(id, _)| tenants.check_attestation_policy(tenant, id).is_ok())
+            .collect();
+        policies.sort();
+        Ok(policies)
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    let policies: Vec<_> = result?
+        .into_iter()
+        .map(|(id, hash)| serde_json::json!({"policy-id": id, "policy-hash": hash}))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(policies))
+}
+
+#[cfg(feature = "as")]
+/// GET /attestation-policy/{policy_id}
+#[tracing::instrument(skip_all)]
+pub(crate) async fn get_attestation_policy(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let policy_id = request.match_info().query("policy_id").to_string();
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &request)
+        .detail("action", "get-attestation-policy")
+        .detail("policy_id", policy_id.as_str());
+
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::ReadPolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+        tenants.get().check_attestation_policy(
+            tenant.as_ref().map(|tenant| tenant.id.as_str()),
+            &policy_id,
+        )?;
+
+        attestation_service
+            .get_policy(&policy_id)
+            .await
+            .map_err(|e| Error::PolicyEndpoint(format!("Get policy error {e:#}")))
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    let policy = result?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "policy_id": policy_id, "policy": policy })))
+}
+
+#[cfg(feature = "as")]
+/// DELETE /attestation-policy/{policy_id}
+#[tracing::instrument(skip_all)]
+pub(crate) async fn remove_attestation_policy(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    sessions: web::Data<SessionMap>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let policy_id = request.match_info().query("policy_id").to_string();
+    let mut event = AuditEvent::new(AuditEventType::PolicyChange, &request)
+        .detail("policy", "attestation")
+        .detail("policy_id", policy_id.as_str())
+        .detail("action", "remove");
+
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::WritePolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+        tenants.get().check_attestation_policy(
+            tenant.as_ref().map(|tenant| tenant.id.as_str()),
+            &policy_id,
+        )?;
+
+        attestation_service
+            .remove_policy(&policy_id)
+            .await
+            .map_err(|e| Error::PolicyEndpoint(format!("Remove policy error {e:#}")))?;
+        attestation_policy_changed(
+            &attestation_service,
+            &reattestation_interval,
+            &sessions,
+            tenants.get(),
+            tenant.map(|tenant| tenant.id.clone()),
+            &policy_id,
+            &audit,
+        );
+        Ok(())
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    result?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(feature = "as")]
+#[derive(serde::Deserialize, Debug)]
+pub struct VerifyEvidenceInput {
+    tee: kbs_types::Tee,
+    evidence: String,
+    policy_id: Option<String>,
+}
+
+#[cfg(feature = "as")]
+/// POST /verify
+#[tracing::instrument(skip_all)]
+pub(crate) async fn verify_evidence(
+    request: HttpRequest,
+    input: web::Json<VerifyEvidenceInput>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event =
+        AuditEvent::new(AuditEventType::AdminAction, &request).detail("action", "verify-evidence");
+    // Set once the evidence is verified, so that it is archived.
+    let mut verified_policy_id = None;
+
+    let result = async {
+        let tenants = tenants.get();
+        let tenant = tenants.of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::ReadPolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        let tenant = tenant.as_ref().map(|tenant| tenant.id.as_str());
+        let policy_id = match &input.policy_id {
+            Some(policy_id) => {
+                tenants.check_attestation_policy(tenant, policy_id)?;
+                policy_id.clone()
+            }
+            None => tenants.attestation_policy(tenant)?,
+        };
+        event.set_detail("policy_id", policy_id.as_str());
+
+        let rvps_namespace = tenants.rvps_namespace(tenant)?;
+        let verdict = attestation_service
+            .simple_verify(
+                input.tee,
+                &input.evidence,
+                Some(&policy_id),
+                rvps_namespace.as_deref(),
+            )
+            .await
+            .map_err(|e| Error::AttestationFailed(format!("{e:#}")));
+        verified_policy_id = Some(policy_id);
+        verdict
+    }
+    .await;
+
+    if let Ok(verdict) = &result {
+        event = event.detail("policies", serde_json::json!(verdict.policies));
+    }
+    let event = event.result(&result);
+    if let Some(policy_id) = verified_policy_id.filter(|_| attestation_service.archives()) {
+        attestation_service.archive(
+            crate::attestation::archive::AttestationRecord::new(
+                &event,
+                input.tee,
+                &policy_id,
+                &input.evidence,
+            )
+            .verdict(&result),
+        );
+    }
+    attestation_service.publish(
+        crate::attestation::publisher::VerdictKind::Verification,
+        &event,
+        input.tee,
+        &result,
+    );
+    audit.record(event).await;
+    let verdict = result?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "policies": verdict.policies,
+    })))
+}
+
+#[cfg(feature = "as")]
+/// POST /reattestation-check
+///
+/// Verify the evidence of the unexpired tokens again with the current
+/// attestation policy and reference values. The returned body lists the
+/// tokens whose evidence would now fail attestation:
+/// ```json
+/// {
+///     "checked": 2,
+///     "stale": [
+///         {
+///             "session_handle": "...",
+///             "token_digest": "...",
+///             "tee": "tdx",
+///             "attested_at": "2024-05-01T12:00:00Z",
+///             "expires_at": "2024-05-01T12:05:00Z",
+///             "claims": { ... },
+///             "reason": "..."
+///         }
+///     ]
+/// }
+/// ```
+#[tracing::instrument(skip_all)]
+pub(crate) async fn reattestation_check(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    map: web::Data<SessionMap>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &request)
+        .detail("action", "reattestation-check");
+
+    let result = async {
+        let tenants = tenants.get();
+        let tenant = tenants.of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::ReadPolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        check_sessions(
+            &map,
+            &attestation_service,
+            &tenants,
+            tenant.as_ref().map(|tenant| tenant.id.as_str()),
+            &audit,
+        )
         .await
-        .map_err(|e| Error::PolicyEndpoint(format!("Set policy error {e}")))?;
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+#[cfg(feature = "as")]
+/// GET /sessions
+///
+/// List the unexpired sessions of the tenant:
+/// ```json
+/// [
+///     {
+///         "handle": "...",
+///         "tee": "tdx",
+///         "attested": true,
+///         "created_at": "2024-05-01T12:00:00Z",
+///         "expires_at": "2024-05-01T12:05:00Z",
+///         "attested_at": "2024-05-01T12:00:01Z",
+///         "policies": ["default"]
+///     }
+/// ]
+/// ```
+#[tracing::instrument(skip_all)]
+pub(crate) async fn list_sessions(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    map: web::Data<SessionMap>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event =
+        AuditEvent::new(AuditEventType::AdminAction, &request).detail("action", "list-sessions");
+
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::ReadSessions,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        Ok(map
+            .list(tenant.as_ref().map(|tenant| tenant.id.as_str()))
+            .await)
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+#[cfg(feature = "as")]
+/// DELETE /sessions/{session_handle}
+///
+/// Terminate the session with the handle `session_handle`, as listed by
+/// `GET /sessions`, so that its cookie is no longer accepted and its client
+/// has to attest again. The tokens issued to the session stay valid until
+/// they expire.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn terminate_session(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    map: web::Data<SessionMap>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let handle = request
+        .match_info()
+        .get("session_handle")
+        .unwrap_or_default();
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &request)
+        .detail("action", "terminate-session")
+        .detail("session", handle);
+
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::TerminateSession,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        if !map
+            .terminate(handle, tenant.as_ref().map(|tenant| tenant.id.as_str()))
+            .await
+        {
+            return Err(Error::UnknownSession(handle.to_string()));
+        }
+        Ok(())
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    result?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(feature = "as")]
+/// GET /policy-captures/{request_id}
+///
+/// Get the attestation policy evaluations the attestation service captured
+/// for the request with the correlation ID `request_id`, i.e. the handle of
+/// the KBS session of an attestation. Only the admins of the whole KBS may read them, as
+/// they hold the claims of the evidence.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn get_policy_capture(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let request_id = request.match_info().get("request_id").unwrap_or_default();
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &request)
+        .detail("action", "get-policy-capture")
+        .detail("request_id", request_id);
+
+    let result = async {
+        authorize_admin(
+            &request,
+            Permission::ReadPolicy,
+            &mut event,
+            &admin_keys.get(),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        attestation_service
+            .policy_capture(request_id)
+            .await
+            .map_err(|e| Error::PolicyEndpoint(format!("Get policy capture error {e:#}")))?
+            .ok_or_else(|| Error::UnknownPolicyCapture(request_id.to_string()))
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+#[cfg(feature = "as")]
+/// GET /drift
+///
+/// Get the baseline of every attested platform, with the configurations of
+/// its claims seen since then and their differences from the baseline:
+/// ```json
+/// {
+///     "tdx": {
+///         "baseline": {
+///             "digest": "...",
+///             "first_seen": "2024-05-01T12:00:00Z",
+///             "claims": { "tee": "tdx", ... }
+///         },
+///         "drifted": [
+///             {
+///                 "digest": "...",
+///                 "first_seen": "2024-06-01T12:00:00Z",
+///                 "diff": [
+///                     {
+///                         "claim": "tcb-status.tdx.quote.body.tcb_svn",
+///                         "baseline": "...",
+///                         "current": "..."
+///                     }
+///                 ]
+///             }
+///         ]
+///     }
+/// }
+/// ```
+/// Only the admins of the whole KBS may read it, as the platforms are shared
+/// by the tenants.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn get_drift(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event =
+        AuditEvent::new(AuditEventType::AdminAction, &request).detail("action", "get-drift");
+
+    let result = async {
+        authorize_admin(
+            &request,
+            Permission::ReadPolicy,
+            &mut event,
+            &admin_keys.get(),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        Ok(attestation_service.drift().report().await)
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+#[cfg(feature = "as")]
+/// Body of a drift baseline change.
+#[derive(serde::Deserialize)]
+pub(crate) struct DriftBaselineRequest {
+    platform: String,
+
+    /// Digest of the configuration of the platform to make its baseline.
+    configuration: String,
+}
+
+#[cfg(feature = "as")]
+/// POST /drift/baseline
+///
+/// Make a configuration seen on a platform its baseline, e.g. after an
+/// expected firmware update, so that the attestations are compared with it.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn set_drift_baseline(
+    request: HttpRequest,
+    body: web::Json<DriftBaselineRequest>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::PolicyChange, &request)
+        .detail("policy", "drift-baseline")
+        .detail("platform", body.platform.as_str())
+        .detail("configuration", body.configuration.as_str());
+
+    let result = async {
+        authorize_admin(
+            &request,
+            Permission::WritePolicy,
+            &mut event,
+            &admin_keys.get(),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        let accepted = attestation_service
+            .drift()
+            .accept(&body.platform, &body.configuration)
+            .await
+            .map_err(|e| Error::PolicyEndpoint(format!("Set drift baseline error {e:#}")))?;
+        if !accepted {
+            return Err(Error::UnknownPlatformConfiguration(format!(
+                "{} of platform {}",
+                body.configuration, body.platform
+            )));
+        }
+        Ok(())
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    result?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
 #[cfg(feature = "policy")]
 /// POST /resource-policy
+#[tracing::instrument(skip_all)]
 pub(crate) async fn resource_policy(
     request: HttpRequest,
     input: web::Json<serde_json::Value>,
-    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
     insecure: web::Data<bool>,
-    policy_engine: web::Data<PolicyEngine>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    policy_engine: web::Data<Reloadable<PolicyEngine>>,
+    audit: web::Data<AuditLog>,
 ) -> Result<HttpResponse> {
-    if !insecure.get_ref() {
-        let user_pub_key = user_pub_key
-            .as_ref()
-            .as_ref()
-            .ok_or(Error::UserPublicKeyNotProvided)?;
+    let mut event =
+        AuditEvent::new(AuditEventType::PolicyChange, &request).detail("policy", "resource");
 
-        validate_auth(&request, user_pub_key).map_err(|e| {
-            Error::FailedAuthentication(format!("Requester is not an authorized user: {e}"))
-        })?;
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::WritePolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        tenant_policy_engine(&tenant, &policy_engine)
+            .engine
+            .lock()
+            .await
+            .set_policy(
+                input.into_inner()["policy"]
+                    .as_str()
+                    .ok_or(Error::PolicyEndpoint(
+                        "Get policy from request failed".to_string(),
+                    ))?
+                    .to_string(),
+            )
+            .await
+            .map_err(|e| Error::PolicyEndpoint(format!("Set policy error {e}")))
     }
+    .await;
 
-    policy_engine
-        .0
-        .lock()
-        .await
-        .set_policy(
-            input.into_inner()["policy"]
-                .as_str()
-                .ok_or(Error::PolicyEndpoint(
-                    "Get policy from request failed".to_string(),
-                ))?
-                .to_string(),
-        )
-        .await
-        .map_err(|e| Error::PolicyEndpoint(format!("Set policy error {e}")))?;
+    audit.record(event.result(&result)).await;
+    result?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
+#[cfg(feature = "policy")]
+/// GET /resource-policy
+#[tracing::instrument(skip_all)]
+pub(crate) async fn get_resource_policy(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    policy_engine: web::Data<Reloadable<PolicyEngine>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &request)
+        .detail("action", "get-resource-policy");
+
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::ReadPolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        tenant_policy_engine(&tenant, &policy_engine)
+            .engine
+            .lock()
+            .await
+            .get_policy()
+            .await
+            .map_err(|e| Error::PolicyEndpoint(format!("Get policy error {e}")))
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    let policy = result?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "policy": policy })))
+}
+
+/// Warning of the responses replacing the required policies.
+#[cfg(feature = "policy")]
+const REQUIRED_POLICIES_WARNING: &str = "Bindings set through the API are kept in memory only, \
+    the configured ones take their place again on reload or restart";
+
+#[cfg(feature = "policy")]
+/// POST /required-policies
+#[tracing::instrument(skip_all)]
+pub(crate) async fn set_required_policies(
+    request: HttpRequest,
+    input: web::Json<Vec<PolicyBinding>>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    policy_engine: web::Data<Reloadable<PolicyEngine>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::PolicyChange, &request)
+        .detail("policy", "required-policies");
+
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::WritePolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        let required_policies = RequiredPolicies::new(input.into_inner())
+            .map_err(|e| Error::PolicyEndpoint(format!("Set required policies error {e}")))?;
+        *tenant_policy_engine(&tenant, &policy_engine)
+            .required_policies
+            .write()
+            .await = required_policies;
+        Ok(())
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    result?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "warning": REQUIRED_POLICIES_WARNING })))
+}
+
+#[cfg(feature = "policy")]
+/// GET /required-policies
+#[tracing::instrument(skip_all)]
+pub(crate) async fn get_required_policies(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    policy_engine: web::Data<Reloadable<PolicyEngine>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &request)
+        .detail("action", "get-required-policies");
+
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::ReadPolicy,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        let required_policies = tenant_policy_engine(&tenant, &policy_engine)
+            .required_policies
+            .read()
+            .await
+            .clone();
+        Ok(required_policies)
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+
+    Ok(HttpResponse::Ok().json(result?))
+}
+
 #[cfg(feature = "resource")]
 /// POST /resource/{repository}/{type}/{tag}
 /// POST /resource/{type}/{tag}
@@ -88,44 +979,320 @@ pub(crate) async fn resource_policy(
 /// any JWT signed with the user's private key will be authenticated.
 /// JWT generation and user identification is unimplemented for now, and thus this
 /// endpoint is insecure and is only meant for testing purposes.
+#[tracing::instrument(skip_all)]
 pub(crate) async fn set_resource(
     request: HttpRequest,
-    data: web::Bytes,
-    user_pub_key: web::Data<Option<Ed25519PublicKey>>,
+    payload: web::Payload,
+    upload_config: web::Data<UploadConfig>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
     insecure: web::Data<bool>,
-    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    repository: web::Data<Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>>,
+    audit: web::Data<AuditLog>,
 ) -> Result<HttpResponse> {
-    if !insecure.get_ref() {
-        let user_pub_key = user_pub_key
-            .as_ref()
-            .as_ref()
-            .ok_or(Error::UserPublicKeyNotProvided)?;
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &request)
+        .detail("action", "set-resource")
+        .detail("path", request.path());
 
-        validate_auth(&request, user_pub_key).map_err(|e| {
-            Error::FailedAuthentication(format!("Requester is not an authorized user: {e}"))
-        })?;
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::WriteResource,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+        let repository = match &tenant {
+            Some(tenant) => tenant.repository.clone(),
+            None => repository.get(),
+        };
+        let spill = repository.read().await.spills_uploads().await;
+        let body = SpooledBody::receive(payload, &upload_config, spill).await?;
+
+        let resource_description = ResourceDesc {
+            repository_name: request
+                .match_info()
+                .get("repository")
+                .unwrap_or("default")
+                .to_string(),
+            resource_type: request
+                .match_info()
+                .get("type")
+                .ok_or_else(|| Error::InvalidRequest(String::from("no `type` in url")))?
+                .to_string(),
+            resource_tag: request
+                .match_info()
+                .get("tag")
+                .ok_or_else(|| Error::InvalidRequest(String::from("no `tag` in url")))?
+                .to_string(),
+        };
+
+        match body {
+            SpooledBody::Memory(data) => {
+                set_secret_resource(&repository, resource_description, &data).await
+            }
+            SpooledBody::File(path) => {
+                set_secret_resource_file(&repository, resource_description, &path).await
+            }
+        }
+        .map_err(|e| {
+            if let Some(rejected) = e.downcast_ref::<WriteRejected>() {
+                Error::ResourceRejected(rejected.to_string())
+            } else if let Some(exceeded) = e.downcast_ref::<QuotaExceeded>() {
+                Error::QuotaExceeded(exceeded.to_string())
+            } else {
+                Error::SetSecretFailed(format!("{e}"))
+            }
+        })
     }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    result?;
 
-    let resource_description = ResourceDesc {
-        repository_name: request
-            .match_info()
-            .get("repository")
-            .unwrap_or("default")
-            .to_string(),
-        resource_type: request
-            .match_info()
-            .get("type")
-            .ok_or_else(|| Error::InvalidRequest(String::from("no `type` in url")))?
-            .to_string(),
-        resource_tag: request
-            .match_info()
-            .get("tag")
-            .ok_or_else(|| Error::InvalidRequest(String::from("no `tag` in url")))?
-            .to_string(),
-    };
-
-    set_secret_resource(&repository, resource_description, data.as_ref())
-        .await
-        .map_err(|e| Error::SetSecretFailed(format!("{e}")))?;
     Ok(HttpResponse::Ok().content_type("application/json").body(""))
 }
+
+#[cfg(feature = "resource")]
+/// GET /repository-usage
+///
+/// The resources and bytes stored in every repository of the tenant, with
+/// their quotas:
+/// ```json
+/// {
+///     "default": {
+///         "resources": 12,
+///         "bytes": 18432,
+///         "max_resources": 100,
+///         "max_bytes": 1048576
+///     }
+/// }
+/// ```
+#[tracing::instrument(skip_all)]
+pub(crate) async fn repository_usage(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    repository: web::Data<Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event =
+        AuditEvent::new(AuditEventType::AdminAction, &request).detail("action", "repository-usage");
+
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::ReadResourceUsage,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        let repository = match &tenant {
+            Some(tenant) => tenant.repository.clone(),
+            None => repository.get(),
+        };
+        let usage = repository
+            .read()
+            .await
+            .usage()
+            .await
+            .map_err(|e| Error::RepositoryUsageFailed(format!("{e:#}")))?;
+        Ok(usage)
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+#[cfg(feature = "resource")]
+#[derive(serde::Deserialize, Debug)]
+pub struct MirrorCheckInput {
+    #[serde(default)]
+    repair: bool,
+}
+
+#[cfg(feature = "resource")]
+/// POST /repository-mirror-check
+///
+/// Compare the resources of the mirrored repository of the tenant with its
+/// secondary repository, copying the missing and different ones again with
+/// `repair`:
+/// ```json
+/// {
+///     "checked": 12,
+///     "missing": ["default/key/1"],
+///     "different": [],
+///     "extra": [],
+///     "repaired": 1,
+///     "errors": []
+/// }
+/// ```
+#[tracing::instrument(skip_all)]
+pub(crate) async fn repository_mirror_check(
+    request: HttpRequest,
+    input: web::Json<MirrorCheckInput>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    repository: web::Data<Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &request)
+        .detail("action", "repository-mirror-check")
+        .detail("repair", input.repair);
+
+    let result = async {
+        let tenant = tenants.get().of_request(&request)?;
+        authorize_admin(
+            &request,
+            Permission::WriteResource,
+            &mut event,
+            &tenant_admin_keys(&tenant, &admin_keys),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        let repository = match &tenant {
+            Some(tenant) => tenant.repository.clone(),
+            None => repository.get(),
+        };
+        let report = repository
+            .read()
+            .await
+            .check_mirror(input.repair)
+            .await
+            .map_err(|e| Error::MirrorCheckFailed(format!("{e:#}")))?;
+        Ok(report)
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+/// POST /reload
+///
+/// Reload the KBS configuration file, as on SIGHUP.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn reload(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    reloader: web::Data<Reloader>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event =
+        AuditEvent::new(AuditEventType::AdminAction, &request).detail("action", "reload-config");
+
+    let result = async {
+        authorize_admin(
+            &request,
+            Permission::ReloadConfig,
+            &mut event,
+            &admin_keys.get(),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        reloader
+            .reload()
+            .await
+            .map_err(|e| Error::ConfigReloadFailed(format!("{e:#}")))
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    result?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// GET /audit/verify
+///
+/// Verify the hash chain and the checkpoints of the audit log file.
+pub(crate) async fn verify_audit_log(
+    request: HttpRequest,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let mut event =
+        AuditEvent::new(AuditEventType::AdminAction, &request).detail("action", "verify-audit-log");
+
+    let result = async {
+        authorize_admin(
+            &request,
+            Permission::ReadAuditLog,
+            &mut event,
+            &admin_keys.get(),
+            **insecure,
+            &client_auth,
+            &allowlist,
+        )?;
+
+        let path = audit.path().ok_or_else(|| {
+            Error::AuditLogVerifyFailed("the audit log is not written to a file".to_string())
+        })?;
+        crate::audit::verify_audit_log(path, audit.verification_key().as_ref())
+            .await
+            .map_err(|e| Error::AuditLogVerifyFailed(format!("{e:#}")))
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_client_certificate_required() {
+        let request = TestRequest::default().to_http_request();
+        let allowlist = AdminAllowlist::default();
+        let authorize = |client_auth| {
+            let mut event = AuditEvent::new(AuditEventType::PolicyChange, &request);
+            authorize_admin(
+                &request,
+                Permission::WritePolicy,
+                &mut event,
+                &[],
+                true,
+                &client_auth,
+                &allowlist,
+            )
+        };
+
+        assert!(authorize(None).is_ok());
+        // Insecure APIs don't spare the client certificate.
+        for scope in [ClientAuthScope::All, ClientAuthScope::Admin] {
+            assert!(matches!(
+                authorize(Some(scope)),
+                Err(Error::FailedAuthentication(_))
+            ));
+        }
+    }
+}
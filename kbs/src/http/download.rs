@@ -0,0 +1,238 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::resource::download::DownloadUrls;
+
+use super::*;
+
+/// Body of a download URL request.
+#[derive(Deserialize)]
+pub(crate) struct DownloadUrlRequest {
+    /// Lifetime of the URL in seconds, the longest allowed by default.
+    ttl: Option<u64>,
+}
+
+/// POST /download-url/{repository}/{type}/{tag}
+/// POST /download-url/{type}/{tag}
+///
+/// Mint a single-use URL to download the resource, for an attested client
+/// the resource policy allows it to, or for an admin.
+#[tracing::instrument(skip_all)]
+#[cfg_attr(not(feature = "policy"), allow(unused_variables))]
+pub(crate) async fn download_url(
+    request: HttpRequest,
+    body: web::Json<DownloadUrlRequest>,
+    download_urls: web::Data<DownloadUrls>,
+    #[cfg(feature = "as")] map: web::Data<SessionMap>,
+    token_verifier: web::Data<Reloadable<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>>,
+    reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    #[cfg(feature = "policy")] policy_engine: web::Data<Reloadable<PolicyEngine>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    audit: web::Data<AuditLog>,
+    workload_identity: web::Data<WorkloadIdentity>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::DownloadUrlIssuance, &request)
+        .detail("path", request.path());
+
+    let result = async {
+        let tenants = tenants.get();
+        let tenant = tenants.of_request(&request)?;
+        let tenant_id = tenant.as_ref().map(|tenant| tenant.id.as_str());
+        let resource_description = request_resource(&request)?;
+        if !resource_description.is_valid() {
+            raise_error!(Error::InvalidRequest("Invalid resource path".to_string()));
+        }
+
+        match request_claims(
+            &request,
+            #[cfg(feature = "as")]
+            &map,
+            &token_verifier.get(),
+            reattestation_interval.get(),
+            &tenants,
+            tenant_id,
+            &workload_identity,
+        )
+        .await
+        {
+            Ok((claims, identity)) => {
+                event.set_actor(Actor::attester(identity));
+                #[cfg(feature = "policy")]
+                check_resource_policy(
+                    claims,
+                    &resource_description,
+                    &tenant_policy_engine(&tenant, &policy_engine),
+                )
+                .await?;
+            }
+            // Admins mint the URLs without attesting.
+            Err(e) => authorize_admin(
+                &request,
+                Permission::DelegateResource,
+                &mut event,
+                &tenant_admin_keys(&tenant, &admin_keys),
+                **insecure,
+                &client_auth,
+                &allowlist,
+            )
+            .map_err(|_| e)?,
+        }
+
+        let (capability, expires_at) = download_urls
+            .mint(tenant_id, &resource_description, body.ttl)
+            .map_err(|e| Error::InvalidRequest(format!("{e:#}")))?;
+        Ok(HttpResponse::Ok().json(json!({
+            "url": format!("{}/download/{capability}", crate::KBS_PREFIX),
+            "expires_at": expires_at,
+        })))
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+
+    result
+}
+
+/// GET /download/{capability}
+///
+/// The plaintext resource of a download URL, once before the URL expires.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn download(
+    request: HttpRequest,
+    download_urls: web::Data<DownloadUrls>,
+    repository: web::Data<Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    // The URL is a credential, so the path isn't recorded.
+    let mut event = AuditEvent::new(AuditEventType::ResourceAccess, &request);
+    let mut repository_name = None;
+
+    let result = async {
+        let capability = download_urls
+            .verify(request.match_info().get("capability").unwrap_or_default())
+            .map_err(|e| Error::InvalidDownloadUrl(format!("{e:#}")))?;
+        let resource_description = capability.resource();
+        repository_name = Some(resource_description.repository_name.clone());
+        event.set_detail(
+            "resource",
+            format!(
+                "{}/{}/{}",
+                resource_description.repository_name,
+                resource_description.resource_type,
+                resource_description.resource_tag
+            ),
+        );
+        event
+            .details
+            .insert("download_url".to_string(), capability.jti.clone().into());
+
+        let repository = match &capability.tenant {
+            Some(tenant) => tenants.get().get(tenant)?.repository.clone(),
+            None => repository.get(),
+        };
+        let repository = repository.read().await;
+        // Read first, so that a failed read leaves the URL to be retried.
+        let resource = repository
+            .read_secret_resource(resource_description)
+            .await
+            .map_err(|e| Error::ReadSecretFailed(format!("{e:?}")))?;
+        // Marked in the repository, so that the KBS instances sharing it
+        // redeem the URL once.
+        let redeemed = repository
+            .redeem_download_url(&capability.jti, capability.exp)
+            .await
+            .map_err(|e| Error::ReadSecretFailed(format!("{e:?}")))?;
+        if !redeemed {
+            raise_error!(Error::InvalidDownloadUrl(
+                "the download URL was already used".into()
+            ));
+        }
+        Ok(HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(resource.to_vec()))
+    }
+    .await;
+
+    RESOURCE_REQUESTS
+        .with_label_values(&[
+            repository_label(repository_name.as_deref().unwrap_or("default")),
+            result_label(&result),
+        ])
+        .inc();
+
+    audit.record(event.result(&result)).await;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::download::DownloadUrlConfig;
+    use crate::resource::RepositoryConfig;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn test_download() {
+        let download_urls = DownloadUrls::new(&DownloadUrlConfig {
+            key_path: None,
+            max_ttl: 60,
+        })
+        .unwrap();
+        let resource = ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "key".into(),
+            resource_tag: "1".into(),
+        };
+        let (capability, _) = download_urls.mint(None, &resource, None).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let repository: RepositoryConfig = serde_json::from_value(json!({
+            "type": "LocalFs",
+            "dir_path": dir.path(),
+        }))
+        .unwrap();
+        let repository = repository.initialize().unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(download_urls))
+                .app_data(web::Data::new(Reloadable::new(repository.clone())))
+                .app_data(web::Data::new(Reloadable::new(Arc::new(
+                    Tenants::new(&[], false).await.unwrap(),
+                ))))
+                .app_data(web::Data::new(AuditLog::new(None, &[]).await.unwrap()))
+                .route("/download/{capability}", web::get().to(download)),
+        )
+        .await;
+
+        // A failed read doesn't use up the URL.
+        let uri = format!("/download/{capability}");
+        let response = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(response.status(), 404);
+
+        repository
+            .write()
+            .await
+            .write_secret_resource(resource, b"secret")
+            .await
+            .unwrap();
+        let response = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert!(response.status().is_success());
+        assert_eq!(read_body(response).await, "secret");
+
+        // The URL is used up.
+        let response = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(response.status(), 403);
+    }
+}
@@ -20,6 +20,9 @@ use thiserror::Error;
 
 const ERROR_TYPE_PREFIX: &str = "https://github.com/confidential-containers/kbs/errors";
 
+/// Header with the comma separated KBS protocol versions KBS supports.
+pub const PROTOCOL_VERSIONS_HEADER: &str = "X-KBS-Protocol-Versions";
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[allow(dead_code)]
@@ -28,9 +31,15 @@ pub enum Error {
     #[error("Attestation failed: {0}")]
     AttestationFailed(String),
 
+    #[error("Verify audit log failed: {0}")]
+    AuditLogVerifyFailed(String),
+
     #[error("Received illegal attestation claims: {0}")]
     AttestationClaimsParseFailed(String),
 
+    #[error("Reload KBS configuration failed: {0}")]
+    ConfigReloadFailed(String),
+
     #[error("The cookie is expired")]
     ExpiredCookie,
 
@@ -43,12 +52,27 @@ pub enum Error {
     #[error("The request is invalid: {0}")]
     InvalidRequest(String),
 
+    #[error("The download URL is invalid: {0}")]
+    InvalidDownloadUrl(String),
+
     #[error("Json Web Encryption failed: {0}")]
     JWEFailed(String),
 
     #[error("The cookie is missing")]
     MissingCookie,
 
+    #[error("The challenge nonce was already answered, request a new challenge")]
+    NonceReused,
+
+    #[error("The request body is too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("OIDC discovery failed: {0}")]
+    OidcDiscoveryFailed(String),
+
     #[error("Policy error: {0}")]
     PolicyEndpoint(String),
 
@@ -58,18 +82,51 @@ pub enum Error {
     #[error("Resource not permitted.")]
     PolicyReject,
 
-    #[error("KBS Client Protocol Version Mismatch: {0}")]
-    ProtocolVersion(String),
+    #[error(
+        "KBS Client Protocol Version Mismatch: {reason}, supported versions: {}",
+        .supported.join(", ")
+    )]
+    ProtocolVersion {
+        reason: String,
+        supported: Vec<String>,
+    },
+
+    #[error("Repository mirror check failed: {0}")]
+    MirrorCheckFailed(String),
 
     #[error("Public key get failed: {0}")]
     PublicKeyGetFailed(String),
 
+    #[error("{0}")]
+    QuotaExceeded(String),
+
+    #[error("Repository usage failed: {0}")]
+    RepositoryUsageFailed(String),
+
+    #[error("Re-attestation required: the attestation verdict is too old")]
+    ReattestationRequired,
+
     #[error("Read secret failed: {0}")]
     ReadSecretFailed(String),
 
+    #[error("{0}")]
+    ResourceRejected(String),
+
     #[error("Set secret failed: {0}")]
     SetSecretFailed(String),
 
+    #[error("SVID issue failed: {0}")]
+    SvidIssueFailed(String),
+
+    #[error("The credentials of the request belong to another tenant")]
+    TenantMismatch,
+
+    #[error("KBS is shutting down")]
+    ShuttingDown,
+
+    #[error("Token exchange failed: {0}")]
+    TokenExchangeFailed(String),
+
     #[error("Attestation token issue failed: {0}")]
     TokenIssueFailed(String),
 
@@ -79,6 +136,18 @@ pub enum Error {
     #[error("The cookie is unauthenticated")]
     UnAuthenticatedCookie,
 
+    #[error("No policy evaluations captured for request {0}")]
+    UnknownPolicyCapture(String),
+
+    #[error("Unknown platform configuration: {0}")]
+    UnknownPlatformConfiguration(String),
+
+    #[error("Unknown session: {0}")]
+    UnknownSession(String),
+
+    #[error("Unknown tenant: {0}")]
+    UnknownTenant(String),
+
     #[error("User public key not provided when launching the KBS")]
     UserPublicKeyNotProvided,
 }
@@ -112,13 +181,38 @@ impl ResponseError for Error {
         // All the fields inside the ErrorInfo are printable characters, so this
         // error cannot happen.
         // A test covering all the possible error types are given to ensure this.
-        let body = serde_json::to_string(&info).expect("serialize error response failed");
+        let mut body = serde_json::to_value(&info).expect("serialize error response failed");
+        // The acceptable versions let attesters tell which protocol to speak.
+        if let Error::ProtocolVersion { supported, .. } = self {
+            body["supported-versions"] = supported.clone().into();
+        }
+        let body = body.to_string();
 
         // Due to the definition of KBS attestation protocol, we set the http code.
         let mut res = match self {
             Error::ReadSecretFailed(_) => HttpResponse::NotFound(),
+            Error::ConfigReloadFailed(_)
+            | Error::AuditLogVerifyFailed(_)
+            | Error::MirrorCheckFailed(_)
+            | Error::RepositoryUsageFailed(_) => HttpResponse::InternalServerError(),
+            Error::UnknownTenant(_)
+            | Error::UnknownSession(_)
+            | Error::UnknownPolicyCapture(_)
+            | Error::UnknownPlatformConfiguration(_)
+            | Error::OidcDiscoveryFailed(_) => HttpResponse::NotFound(),
+            Error::PermissionDenied(_)
+            | Error::TenantMismatch
+            | Error::InvalidDownloadUrl(_)
+            | Error::TokenExchangeFailed(_) => HttpResponse::Forbidden(),
+            Error::PayloadTooLarge(_) => HttpResponse::PayloadTooLarge(),
+            Error::ResourceRejected(_) => HttpResponse::BadRequest(),
+            Error::QuotaExceeded(_) => HttpResponse::InsufficientStorage(),
+            Error::ShuttingDown => HttpResponse::ServiceUnavailable(),
             _ => HttpResponse::Unauthorized(),
         };
+        if let Error::ProtocolVersion { supported, .. } = self {
+            res.insert_header((PROTOCOL_VERSIONS_HEADER, supported.join(", ")));
+        }
 
         error!("{self}");
 
@@ -134,24 +228,66 @@ mod tests {
 
     #[rstest]
     #[case(Error::AttestationFailed("test".into()))]
+    #[case(Error::AuditLogVerifyFailed("test".into()))]
+    #[case(Error::ConfigReloadFailed("test".into()))]
     #[case(Error::ExpiredCookie)]
     #[case(Error::FailedAuthentication("test".into()))]
     #[case(Error::InvalidCookie)]
     #[case(Error::ExpiredCookie)]
     #[case(Error::MissingCookie)]
     #[case(Error::InvalidRequest("test".into()))]
+    #[case(Error::InvalidDownloadUrl("test".into()))]
     #[case(Error::JWEFailed("test".into()))]
+    #[case(Error::MirrorCheckFailed("test".into()))]
+    #[case(Error::OidcDiscoveryFailed("test".into()))]
+    #[case(Error::PayloadTooLarge("test".into()))]
+    #[case(Error::PermissionDenied("test".into()))]
     #[case(Error::PolicyEndpoint("test".into()))]
     #[case(Error::PolicyReject)]
-    #[case(Error::ProtocolVersion("test".into()))]
+    #[case(Error::ProtocolVersion { reason: "test".into(), supported: Vec::new() })]
     #[case(Error::PublicKeyGetFailed("test".into()))]
+    #[case(Error::QuotaExceeded("test".into()))]
+    #[case(Error::ReattestationRequired)]
+    #[case(Error::RepositoryUsageFailed("test".into()))]
     #[case(Error::ReadSecretFailed("test".into()))]
+    #[case(Error::ResourceRejected("test".into()))]
     #[case(Error::SetSecretFailed("test".into()))]
+    #[case(Error::ShuttingDown)]
+    #[case(Error::SvidIssueFailed("test".into()))]
+    #[case(Error::TenantMismatch)]
+    #[case(Error::TokenExchangeFailed("test".into()))]
     #[case(Error::TokenIssueFailed("test".into()))]
     #[case(Error::TokenParseFailed("test".into()))]
     #[case(Error::UnAuthenticatedCookie)]
+    #[case(Error::UnknownPolicyCapture("test".into()))]
+    #[case(Error::UnknownPlatformConfiguration("test".into()))]
+    #[case(Error::UnknownSession("test".into()))]
+    #[case(Error::UnknownTenant("test".into()))]
     #[case(Error::UserPublicKeyNotProvided)]
     fn into_error_response(#[case] err: Error) {
         let _ = actix_web::ResponseError::error_response(&err);
     }
+
+    #[actix_web::test]
+    async fn test_protocol_version_response() {
+        let err = Error::ProtocolVersion {
+            reason: "expected version: <=0.1.0, requested version: 1.0.0".into(),
+            supported: vec!["0.1.0".into()],
+        };
+        let response = actix_web::ResponseError::error_response(&err);
+        assert_eq!(
+            response
+                .headers()
+                .get(super::PROTOCOL_VERSIONS_HEADER)
+                .unwrap(),
+            "0.1.0"
+        );
+
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["type"].as_str().unwrap().ends_with("/ProtocolVersion"));
+        assert_eq!(body["supported-versions"], serde_json::json!(["0.1.0"]));
+    }
 }
@@ -0,0 +1,215 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+
+use crate::token::exchange::TokenExchanger;
+
+use super::*;
+
+/// `grant_type` of a token exchange.
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+
+/// Type of the subject and issued tokens, both JWTs.
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+/// Form of a token exchange request, as defined by RFC 8693.
+#[derive(Debug, Deserialize)]
+pub(crate) struct TokenExchangeRequest {
+    grant_type: String,
+
+    /// The attestation results token to exchange.
+    subject_token: String,
+    subject_token_type: String,
+
+    /// The service the token is for.
+    audience: Option<String>,
+
+    /// Space-separated names of the claims of the attestation results token
+    /// to carry over.
+    scope: Option<String>,
+
+    requested_token_type: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenExchangeResponse {
+    access_token: String,
+    issued_token_type: &'static str,
+
+    /// The issued token is not an OAuth access token.
+    token_type: &'static str,
+    expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+
+/// POST /token-exchange
+///
+/// Exchange an attestation results token for a token restricted to one
+/// audience and a subset of its claims, to pass to a third-party service.
+/// The claims are annotated with the workload identity of the attester,
+/// which is the subject of the issued token.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn token_exchange(
+    request: HttpRequest,
+    form: web::Form<TokenExchangeRequest>,
+    token_exchanger: web::Data<TokenExchanger>,
+    token_verifier: web::Data<Reloadable<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>>,
+    reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    workload_identity: web::Data<WorkloadIdentity>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let form = form.into_inner();
+    let mut event = AuditEvent::new(AuditEventType::TokenExchange, &request);
+
+    let result = async {
+        if form.grant_type != TOKEN_EXCHANGE_GRANT_TYPE {
+            raise_error!(Error::InvalidRequest(format!(
+                "unsupported grant type {}",
+                form.grant_type
+            )));
+        }
+        if form.subject_token_type != JWT_TOKEN_TYPE {
+            raise_error!(Error::InvalidRequest(format!(
+                "unsupported subject token type {}",
+                form.subject_token_type
+            )));
+        }
+        if let Some(requested) = &form.requested_token_type {
+            if requested != JWT_TOKEN_TYPE {
+                raise_error!(Error::InvalidRequest(format!(
+                    "unsupported requested token type {requested}"
+                )));
+            }
+        }
+
+        let tenants = tenants.get();
+        let tenant = tenants.of_request(&request)?;
+        let tenant_id = tenant.as_ref().map(|tenant| tenant.id.as_str());
+        let claims = token_claims(
+            form.subject_token,
+            &token_verifier.get(),
+            &tenants,
+            tenant_id,
+            reattestation_interval.get(),
+        )
+        .await?;
+        let mut claims: serde_json::Value = serde_json::from_str(&claims)
+            .map_err(|e| Error::AttestationClaimsParseFailed(e.to_string()))?;
+        // The identity is the one of the template, never a claim of the token.
+        let identity = workload_identity.annotate(&mut claims);
+        event.set_actor(Actor::attester(identity));
+
+        let audience = form.audience.unwrap_or_default();
+        let scope: Vec<String> = form
+            .scope
+            .as_deref()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        event
+            .details
+            .insert("audience".to_string(), audience.clone().into());
+        event
+            .details
+            .insert("scope".to_string(), scope.clone().into());
+
+        let exchanged = token_exchanger
+            .exchange(&claims, &audience, &scope)
+            .map_err(|e| Error::TokenExchangeFailed(format!("{e:#}")))?;
+        Ok(HttpResponse::Ok().json(TokenExchangeResponse {
+            access_token: exchanged.token,
+            issued_token_type: JWT_TOKEN_TYPE,
+            token_type: "N_A",
+            expires_in: exchanged.expires_in,
+            scope: (!exchanged.scope.is_empty()).then(|| exchanged.scope.join(" ")),
+        }))
+    }
+    .await;
+
+    audit.record(event.result(&result)).await;
+
+    result
+}
+
+/// GET /token-exchange/jwks
+///
+/// The public key verifying the exchanged tokens.
+pub(crate) async fn token_exchange_jwks(
+    token_exchanger: web::Data<TokenExchanger>,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(token_exchanger.jwks()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::exchange::TokenExchangeConfig;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        "grant_type=client_credentials&subject_token=t&subject_token_type=\
+            urn:ietf:params:oauth:token-type:jwt&audience=storage"
+    )]
+    #[case(
+        "grant_type=urn:ietf:params:oauth:grant-type:token-exchange&subject_token=t\
+            &subject_token_type=urn:ietf:params:oauth:token-type:id_token&audience=storage"
+    )]
+    #[case(
+        "grant_type=urn:ietf:params:oauth:grant-type:token-exchange&subject_token=forged\
+            &subject_token_type=urn:ietf:params:oauth:token-type:jwt&audience=storage"
+    )]
+    #[actix_web::test]
+    async fn test_token_exchange_rejected(#[case] form: &'static str) {
+        let token_exchanger = TokenExchanger::new(&TokenExchangeConfig {
+            signing_key_path: None,
+            issuer: "kbs".into(),
+            max_ttl: 60,
+            audiences: vec!["storage".into()],
+        })
+        .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(token_exchanger))
+                .app_data(web::Data::new(Reloadable::new(
+                    crate::token::create_token_verifier(Default::default()).unwrap(),
+                )))
+                .app_data(web::Data::new(Reloadable::new(
+                    ReattestationInterval::default(),
+                )))
+                .app_data(web::Data::new(Reloadable::new(Arc::new(
+                    Tenants::new(&[], false).await.unwrap(),
+                ))))
+                .app_data(web::Data::new(WorkloadIdentity::new(None).unwrap()))
+                .app_data(web::Data::new(AuditLog::new(None, &[]).await.unwrap()))
+                .route("/token-exchange", web::post().to(token_exchange))
+                .route("/token-exchange/jwks", web::get().to(token_exchange_jwks)),
+        )
+        .await;
+
+        let response = call_service(
+            &app,
+            TestRequest::post()
+                .uri("/token-exchange")
+                .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+                .set_payload(form)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 401);
+
+        let response = call_service(
+            &app,
+            TestRequest::get().uri("/token-exchange/jwks").to_request(),
+        )
+        .await;
+        assert!(response.status().is_success());
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Result;
+use serde_json::{Map, Value};
+use std::future::Future;
+use std::time::Duration;
+
+use super::*;
+
+/// Upper bound for a single dependency check, so that a hanging backend makes
+/// the probe fail instead of time out.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn check(checks: &mut Map<String, Value>, name: &str, f: impl Future<Output = Result<()>>) {
+    let status = match tokio::time::timeout(CHECK_TIMEOUT, f).await {
+        Ok(Ok(())) => "ok".to_string(),
+        Ok(Err(e)) => format!("{e:#}"),
+        Err(_) => "timed out".to_string(),
+    };
+    checks.insert(name.to_string(), Value::String(status));
+}
+
+/// GET /healthz
+///
+/// The process is able to handle HTTP requests.
+pub(crate) async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+/// GET /readyz
+///
+/// Every backend KBS depends on answers. The body maps each checked backend to
+/// `ok` or the reason it is not ready.
+pub(crate) async fn readyz(
+    #[cfg(feature = "as")] attestation_service: web::Data<Arc<AttestationService>>,
+    #[cfg(feature = "resource")] repository: web::Data<
+        Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>,
+    >,
+) -> HttpResponse {
+    let mut checks = Map::new();
+
+    #[cfg(feature = "as")]
+    check(
+        &mut checks,
+        "attestation-service",
+        attestation_service.health_check(),
+    )
+    .await;
+
+    #[cfg(feature = "resource")]
+    check(&mut checks, "repository", async {
+        repository.get().read().await.health_check().await
+    })
+    .await;
+
+    let ready = checks.values().all(|status| status == "ok");
+    let mut response = match ready {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+    response.json(checks)
+}
@@ -0,0 +1,373 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::raise_error;
+use crate::tls::ClientAuthScope;
+use crate::token::expiration;
+use crate::token::revocation::RevokedTokens;
+
+use super::*;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct IntrospectionRequest {
+    /// The attestation results token to check.
+    token: String,
+
+    /// Resource path `<repository>/<type>/<tag>` to evaluate the resource
+    /// policy for with the claims of the token.
+    resource: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TokenStatus {
+    /// KBS accepts the token.
+    Valid,
+
+    /// The token is past its expiration time.
+    Expired,
+
+    /// The token is valid, but its attestation is older than the
+    /// re-attestation interval, so KBS would not release resources for it.
+    Stale,
+
+    /// The token was revoked by an admin before it expired.
+    Revoked,
+
+    /// The token is malformed or not signed by a trusted issuer.
+    Invalid,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PolicyResult {
+    resource: String,
+    allowed: bool,
+}
+
+/// The verdict on a token, with its claims once it passed verification.
+/// Why a token failed verification isn't returned.
+#[derive(Debug, Serialize)]
+pub(crate) struct IntrospectionResponse {
+    /// Whether KBS would release resources for the token.
+    active: bool,
+    status: TokenStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    claims: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy: Option<PolicyResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RevocationRequest {
+    /// The attestation results token to revoke.
+    token: String,
+}
+
+/// POST /introspect
+///
+/// Check an attestation results token for a relying party that can't verify
+/// it itself, return its claims, and optionally evaluate the resource policy
+/// with them. The relying party authenticates with an admin token granting
+/// the `relying-party` role.
+pub(crate) async fn introspect(
+    http_request: HttpRequest,
+    request: web::Json<IntrospectionRequest>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    audit: web::Data<AuditLog>,
+    token_verifier: web::Data<Reloadable<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>>,
+    reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    revoked_tokens: web::Data<RevokedTokens>,
+    #[cfg(feature = "policy")] policy_engine: web::Data<Reloadable<PolicyEngine>>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &http_request)
+        .detail("action", "introspect-token");
+    let result = authorize_admin(
+        &http_request,
+        Permission::IntrospectToken,
+        &mut event,
+        &admin_keys.get(),
+        **insecure,
+        &client_auth,
+        &allowlist,
+    );
+    audit.record(event.result(&result)).await;
+    result?;
+
+    let request = request.into_inner();
+
+    let claims = match token_verifier
+        .get()
+        .read()
+        .await
+        .verify(request.token.clone())
+        .await
+    {
+        Ok(claims) => claims,
+        Err(e) => {
+            debug!("Introspected token failed verification: {e:#}");
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            let status = match expiration(&request.token) {
+                Some(exp) if exp < now => TokenStatus::Expired,
+                _ if revoked_tokens.is_revoked(&request.token).await => TokenStatus::Revoked,
+                _ => TokenStatus::Invalid,
+            };
+            return Ok(HttpResponse::Ok().json(IntrospectionResponse {
+                active: false,
+                status,
+                claims: None,
+                policy: None,
+            }));
+        }
+    };
+
+    let status = match reattestation_interval.get().check_claims(&claims) {
+        Ok(()) => TokenStatus::Valid,
+        Err(Error::ReattestationRequired) => TokenStatus::Stale,
+        Err(e) => raise_error!(e),
+    };
+
+    let policy = match request.resource {
+        #[cfg(feature = "policy")]
+        Some(resource) => {
+            let allowed = policy_engine
+                .get()
+                .engine
+                .lock()
+                .await
+                .evaluate(resource.clone(), claims.clone())
+                .await
+                .map_err(|e| Error::PolicyEngineFailed(e.to_string()))?;
+            Some(PolicyResult { resource, allowed })
+        }
+        #[cfg(not(feature = "policy"))]
+        Some(_) => raise_error!(Error::InvalidRequest(
+            "KBS is built without a resource policy engine".to_string()
+        )),
+        None => None,
+    };
+
+    let claims = serde_json::from_str(&claims)
+        .map_err(|e| Error::AttestationClaimsParseFailed(e.to_string()))?;
+    Ok(HttpResponse::Ok().json(IntrospectionResponse {
+        active: status == TokenStatus::Valid,
+        status,
+        claims: Some(claims),
+        policy,
+    }))
+}
+
+/// POST /token-revocations
+///
+/// Revoke an attestation results token before it expires, so that KBS
+/// rejects it from then on and ends the sessions it was issued to.
+pub(crate) async fn revoke_token(
+    http_request: HttpRequest,
+    request: web::Json<RevocationRequest>,
+    admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    insecure: web::Data<bool>,
+    client_auth: web::Data<Option<ClientAuthScope>>,
+    allowlist: web::Data<AdminAllowlist>,
+    audit: web::Data<AuditLog>,
+    revoked_tokens: web::Data<RevokedTokens>,
+    #[cfg(feature = "as")] map: web::Data<SessionMap>,
+) -> Result<HttpResponse> {
+    let mut event = AuditEvent::new(AuditEventType::AdminAction, &http_request)
+        .detail("action", "revoke-token");
+    let result = authorize_admin(
+        &http_request,
+        Permission::RevokeToken,
+        &mut event,
+        &admin_keys.get(),
+        **insecure,
+        &client_auth,
+        &allowlist,
+    );
+    audit.record(event.result(&result)).await;
+    result?;
+
+    revoked_tokens.revoke(&request.token).await;
+    #[cfg(feature = "as")]
+    map.terminate_token(&request.token).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    fn token(claims: &str) -> String {
+        format!(
+            "{}.{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"RS384"}"#),
+            URL_SAFE_NO_PAD.encode(claims),
+            URL_SAFE_NO_PAD.encode("signature"),
+        )
+    }
+
+    /// A CoCo token with `claims`, signed by the key of its `jwk` claim.
+    fn signed_token(mut claims: Value) -> String {
+        use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, sign::Signer};
+
+        let rsa = Rsa::generate(2048).unwrap();
+        claims["jwk"] = serde_json::json!({
+            "kty": "RSA",
+            "alg": "RS384",
+            "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+            "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+        });
+        let payload = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"RS384"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string()),
+        );
+        let key = PKey::from_rsa(rsa).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha384(), &key).unwrap();
+        let signature = signer.sign_oneshot_to_vec(payload.as_bytes()).unwrap();
+        format!("{payload}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    #[actix_web::test]
+    async fn test_introspect() {
+        use crate::auth::{AdminClaims, Role};
+        use actix_web::http::StatusCode;
+        use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+        use actix_web::App;
+        use jwt_simple::prelude::{Claims, Duration, Ed25519KeyPair, EdDSAKeyPairLike};
+
+        let key_pair = Ed25519KeyPair::generate();
+        let admin_key = AdminKey::new(
+            "relying-party".into(),
+            key_pair.public_key(),
+            None,
+            Vec::new(),
+        );
+        let revoked_tokens = Arc::new(RevokedTokens::default());
+
+        #[allow(unused_mut)]
+        let mut app = App::new()
+            .app_data(web::Data::new(Reloadable::new(Arc::new(vec![admin_key]))))
+            .app_data(web::Data::new(false))
+            .app_data(web::Data::new(None::<ClientAuthScope>))
+            .app_data(web::Data::new(AdminAllowlist::new(&[]).unwrap()))
+            .app_data(web::Data::new(AuditLog::new(None, &[]).await.unwrap()))
+            .app_data(web::Data::new(Reloadable::new(
+                crate::token::revocation::check_revocations(
+                    crate::token::create_token_verifier(Default::default()).unwrap(),
+                    revoked_tokens.clone(),
+                ),
+            )))
+            .app_data(web::Data::from(revoked_tokens))
+            .app_data(web::Data::new(Reloadable::new(
+                ReattestationInterval::default(),
+            )))
+            .route("/introspect", web::post().to(introspect))
+            .route("/token-revocations", web::post().to(revoke_token));
+        #[cfg(feature = "as")]
+        {
+            app = app.app_data(web::Data::new(SessionMap::new()));
+        }
+        #[cfg(feature = "policy")]
+        {
+            let dir = tempfile::tempdir().unwrap();
+            let policy_engine = PolicyEngine::new(&crate::policy_engine::PolicyEngineConfig {
+                policy_path: Some(dir.path().join("policy.rego")),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            app = app.app_data(web::Data::new(Reloadable::new(policy_engine)));
+        }
+        let app = init_service(app).await;
+
+        let admin_token = |roles: Vec<Role>| {
+            let claims = Claims::with_custom_claims(
+                AdminClaims { roles: Some(roles) },
+                Duration::from_mins(5),
+            );
+            key_pair.sign(claims).unwrap()
+        };
+        let post = |uri: &str, admin_token: Option<String>, token: String| {
+            let mut request = TestRequest::post()
+                .uri(uri)
+                .set_json(serde_json::json!({ "token": token }));
+            if let Some(admin_token) = admin_token {
+                request = request.insert_header(("Authorization", format!("Bearer {admin_token}")));
+            }
+            request.to_request()
+        };
+
+        let response = call_service(&app, post("/introspect", None, "forged".into())).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let auditor = admin_token(vec![Role::Auditor]);
+        let response =
+            call_service(&app, post("/introspect", Some(auditor), "forged".into())).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let relying_party = admin_token(vec![Role::RelyingParty]);
+        let response: Value = call_and_read_body_json(
+            &app,
+            post(
+                "/introspect",
+                Some(relying_party.clone()),
+                token(r#"{"exp": 42}"#),
+            ),
+        )
+        .await;
+        assert_eq!(response["active"], false);
+        assert_eq!(response["status"], "expired");
+
+        let response: Value = call_and_read_body_json(
+            &app,
+            post("/introspect", Some(relying_party.clone()), "forged".into()),
+        )
+        .await;
+        assert_eq!(response["status"], "invalid");
+        assert!(response.get("reason").is_none());
+        assert!(response.get("claims").is_none());
+
+        // The claims of a valid token are returned, until it is revoked.
+        let exp = time::OffsetDateTime::now_utc().unix_timestamp() + 300;
+        let valid = signed_token(serde_json::json!({ "exp": exp, "tee": "sample" }));
+        let response: Value = call_and_read_body_json(
+            &app,
+            post("/introspect", Some(relying_party.clone()), valid.clone()),
+        )
+        .await;
+        assert_eq!(response["active"], true);
+        assert_eq!(response["status"], "valid");
+        assert_eq!(response["claims"]["tee"], "sample");
+
+        let response = call_service(
+            &app,
+            post(
+                "/token-revocations",
+                Some(relying_party.clone()),
+                valid.clone(),
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let session_admin = admin_token(vec![Role::SessionAdmin]);
+        let response = call_service(
+            &app,
+            post("/token-revocations", Some(session_admin), valid.clone()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response: Value =
+            call_and_read_body_json(&app, post("/introspect", Some(relying_party), valid)).await;
+        assert_eq!(response["active"], false);
+        assert_eq!(response["status"], "revoked");
+        assert!(response.get("claims").is_none());
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use log::error;
+use prometheus::{register_int_counter_vec, Encoder, IntCounterVec, TextEncoder};
+#[cfg(feature = "as")]
+use prometheus::{register_int_gauge, IntGauge};
+#[cfg(feature = "resource")]
+use prometheus::{register_int_gauge_vec, IntGaugeVec};
+
+use super::*;
+
+#[cfg(feature = "as")]
+lazy_static! {
+    /// Attestation requests by TEE type and result (`success` or `failure`).
+    pub(crate) static ref ATTESTATION_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "kbs_attestation_requests_total",
+        "Attestation requests by TEE type and result",
+        &["tee", "result"]
+    )
+    .unwrap();
+
+    /// Sessions currently held by KBS, refreshed on every scrape.
+    pub(crate) static ref SESSIONS: IntGauge =
+        register_int_gauge!("kbs_sessions", "Number of KBS sessions").unwrap();
+}
+
+#[cfg(feature = "resource")]
+lazy_static! {
+    /// Resource requests by repository (`default` or `other`, see
+    /// [`repository_label`]) and result (`success` or `failure`).
+    pub(crate) static ref RESOURCE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "kbs_resource_requests_total",
+        "Resource requests by repository and result",
+        &["repository", "result"]
+    )
+    .unwrap();
+
+    /// Resources stored by tenant and repository, refreshed on every scrape.
+    /// The tenant is empty for the default one.
+    pub(crate) static ref REPOSITORY_RESOURCES: IntGaugeVec = register_int_gauge_vec!(
+        "kbs_repository_resources",
+        "Resources stored by tenant and repository",
+        &["tenant", "repository"]
+    )
+    .unwrap();
+
+    /// Bytes stored by tenant and repository, refreshed on every scrape.
+    pub(crate) static ref REPOSITORY_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        "kbs_repository_bytes",
+        "Bytes stored by tenant and repository",
+        &["tenant", "repository"]
+    )
+    .unwrap();
+}
+
+/// Refresh the usage gauges of `repository` of `tenant`. Repositories whose
+/// usage isn't accounted are left out.
+#[cfg(feature = "resource")]
+async fn record_usage(repository: &Arc<RwLock<dyn Repository + Send + Sync>>, tenant: &str) {
+    let Ok(usage) = repository.read().await.usage().await else {
+        return;
+    };
+    for (name, usage) in usage {
+        REPOSITORY_RESOURCES
+            .with_label_values(&[tenant, &name])
+            .set(usage.resources as i64);
+        REPOSITORY_BYTES
+            .with_label_values(&[tenant, &name])
+            .set(usage.bytes as i64);
+    }
+}
+
+/// Label value for the repository `name` of a resource request. A request
+/// names any repository it wants, so the repositories other than the default
+/// one share the `other` label, bounding the number of series.
+#[cfg(feature = "resource")]
+pub(crate) fn repository_label(name: &str) -> &'static str {
+    match name {
+        "default" => "default",
+        _ => "other",
+    }
+}
+
+/// Label value for the result of an operation.
+pub(crate) fn result_label<T, E>(result: &std::result::Result<T, E>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    }
+}
+
+/// GET /metrics
+///
+/// Metrics of a built-in Attestation Service share the default registry and
+/// are exported here as well.
+pub(crate) async fn metrics(
+    #[cfg(feature = "as")] map: web::Data<SessionMap>,
+    #[cfg(feature = "resource")] repository: web::Data<
+        Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>,
+    >,
+    #[cfg(feature = "resource")] tenants: web::Data<Reloadable<Arc<Tenants>>>,
+) -> HttpResponse {
+    #[cfg(feature = "as")]
+    SESSIONS.set(map.sessions.len() as i64);
+
+    #[cfg(feature = "resource")]
+    {
+        // Repositories removed by a reload aren't reported anymore.
+        REPOSITORY_RESOURCES.reset();
+        REPOSITORY_BYTES.reset();
+        record_usage(&repository.get(), "").await;
+        for tenant in tenants.get().iter() {
+            record_usage(&tenant.repository, &tenant.id).await;
+        }
+    }
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        error!("Failed to encode metrics: {e}");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "resource")]
+    use crate::resource::RepositoryConfig;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(result_label::<(), ()>(&Ok(())), "success");
+        assert_eq!(result_label::<(), ()>(&Err(())), "failure");
+        #[cfg(feature = "resource")]
+        {
+            assert_eq!(repository_label("default"), "default");
+            assert_eq!(repository_label("tenant-a/team-x"), "other");
+        }
+    }
+
+    #[cfg(feature = "resource")]
+    #[tokio::test]
+    async fn test_record_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository: RepositoryConfig = serde_json::from_value(serde_json::json!({
+            "type": "LocalFs",
+            "dir_path": dir.path(),
+        }))
+        .unwrap();
+        let repository = repository.initialize().unwrap();
+        let resource = ResourceDesc {
+            repository_name: "a/b".into(),
+            resource_type: "key".into(),
+            resource_tag: "1".into(),
+        };
+        repository
+            .write()
+            .await
+            .write_secret_resource(resource, b"secret")
+            .await
+            .unwrap();
+
+        record_usage(&repository, "test-record-usage").await;
+        for name in ["a", "a/b"] {
+            let labels = ["test-record-usage", name];
+            assert_eq!(REPOSITORY_RESOURCES.with_label_values(&labels).get(), 1);
+            assert_eq!(REPOSITORY_BYTES.with_label_values(&labels).get(), 6);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        #[cfg(feature = "resource")]
+        let dir = tempfile::tempdir().unwrap();
+        #[cfg(feature = "resource")]
+        let repository: RepositoryConfig = serde_json::from_value(serde_json::json!({
+            "type": "LocalFs",
+            "dir_path": dir.path(),
+        }))
+        .unwrap();
+
+        let app = App::new();
+        #[cfg(feature = "as")]
+        let app = app.app_data(web::Data::new(SessionMap::new()));
+        #[cfg(feature = "resource")]
+        let app = app
+            .app_data(web::Data::new(Reloadable::new(
+                repository.initialize().unwrap(),
+            )))
+            .app_data(web::Data::new(Reloadable::new(Arc::new(
+                Tenants::new(&[], false).await.unwrap(),
+            ))));
+        let app = init_service(app.route("/metrics", web::get().to(metrics))).await;
+
+        #[cfg(feature = "resource")]
+        RESOURCE_REQUESTS
+            .with_label_values(&["default", "success"])
+            .inc();
+        let response = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(response.status().is_success());
+        let body = read_body(response).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        #[cfg(feature = "as")]
+        assert!(body.contains("kbs_sessions"));
+        #[cfg(feature = "resource")]
+        assert!(body.contains("kbs_resource_requests_total"));
+    }
+}
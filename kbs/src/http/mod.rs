@@ -3,32 +3,63 @@
 // SPDX-License-Identifier: Apache-2.0
 
 #[cfg(feature = "as")]
-use crate::attestation::{AttestationService, AS_TOKEN_TEE_PUBKEY_PATH};
-use crate::auth::validate_auth;
+use crate::attestation::{challenge::Challenges, AttestationService};
+use crate::audit::{Actor, AuditEvent, AuditEventType, AuditLog};
+use crate::auth::{validate_auth, AdminAllowlist, AdminKey, Permission};
+use crate::identity::WorkloadIdentity;
 #[cfg(feature = "policy")]
-use crate::policy_engine::PolicyEngine;
+use crate::policy_engine::{PolicyBinding, PolicyEngine, RequiredPolicies};
+use crate::reload::{Reloadable, Reloader};
 #[cfg(feature = "resource")]
-use crate::resource::{set_secret_resource, Repository, ResourceDesc};
+use crate::resource::{
+    hooks::WriteRejected, quota::QuotaExceeded, set_secret_resource, set_secret_resource_file,
+    Repository, ResourceDesc,
+};
 #[cfg(feature = "as")]
 use crate::session::{SessionMap, KBS_SESSION_ID};
+use crate::tenant::{Tenant, Tenants};
+use crate::tls::{ClientAuthScope, ClientIdentity};
 #[cfg(feature = "resource")]
 use crate::token::AttestationTokenVerifier;
 use actix_web::Responder;
 use actix_web::{body::BoxBody, web, HttpRequest, HttpResponse};
-use jwt_simple::prelude::Ed25519PublicKey;
-use kbs_types::{Attestation, Challenge, ErrorInformation, Request};
+use kbs_types::{Attestation, Challenge, ErrorInformation};
 use std::sync::Arc;
 use tokio::sync::{Mutex, RwLock};
 
 #[cfg(feature = "as")]
 mod attest;
 
+#[cfg(feature = "resource")]
+mod body;
 mod config;
+#[cfg(feature = "resource")]
+mod download;
 mod error;
+#[cfg(feature = "resource")]
+mod exchange;
+mod health;
+mod metrics;
+#[cfg(feature = "as")]
+mod oidc;
+mod openapi;
+mod reattestation;
+#[cfg(feature = "as")]
+mod reverify;
+mod server;
+
+#[cfg(feature = "resource")]
+mod introspect;
 
 #[cfg(feature = "resource")]
 mod resource;
 
+#[cfg(feature = "spiffe")]
+mod svid;
+
+#[cfg(feature = "resource")]
+mod wrap;
+
 #[cfg(feature = "as")]
 /// RESTful APIs that related to attestation
 pub use attest::*;
@@ -36,8 +67,55 @@ pub use attest::*;
 /// RESTful APIs that configure KBS and AS, require user authentication
 pub use self::config::*;
 
+#[cfg(feature = "as")]
+/// OIDC discovery of the attestation token issuer
+pub(crate) use oidc::*;
+
+#[cfg(feature = "as")]
+/// Verification of the unexpired tokens again after policy changes
+pub(crate) use reverify::*;
+
 #[cfg(feature = "resource")]
 /// RESTful APIs that to get secret resources, need attestation verification
 pub use resource::*;
 
+#[cfg(feature = "resource")]
+/// RESTful API that checks attestation results tokens for relying parties
+pub(crate) use introspect::*;
+
+#[cfg(feature = "spiffe")]
+/// RESTful API that exchanges attestation results for SPIFFE SVIDs
+pub(crate) use svid::*;
+
+#[cfg(feature = "resource")]
+/// RESTful APIs that mint and serve single-use download URLs of resources
+pub(crate) use download::*;
+
+#[cfg(feature = "resource")]
+/// RESTful APIs that exchange attestation results tokens for narrower tokens
+pub(crate) use exchange::*;
+
+#[cfg(feature = "resource")]
+/// RESTful API that wraps resources to keys chosen by attested clients
+pub(crate) use wrap::*;
+
 pub use error::*;
+
+/// Liveness and readiness probes
+pub use health::*;
+
+/// Prometheus metrics of KBS
+pub use self::metrics::*;
+
+/// OpenAPI document of the KBS API
+pub(crate) use openapi::*;
+
+/// Request size limits and timeouts of the HTTP server
+pub use server::*;
+
+#[cfg(feature = "resource")]
+/// Uploaded resources, spilled to disk when large
+pub(crate) use body::*;
+
+/// Maximum age of the attestation verdicts used to access resources
+pub(crate) use reattestation::*;
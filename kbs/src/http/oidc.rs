@@ -0,0 +1,37 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+
+/// GET /.well-known/openid-configuration
+///
+/// The OIDC discovery document of the attestation token issuer, so that cloud
+/// IAM systems can trust the attestation tokens as OIDC ID tokens.
+pub(crate) async fn openid_configuration(
+    attestation_service: web::Data<Arc<AttestationService>>,
+) -> Result<HttpResponse> {
+    let discovery = attestation_service
+        .oidc_discovery()
+        .await
+        .map_err(|e| Error::OidcDiscoveryFailed(format!("{e:#}")))?
+        .ok_or_else(|| {
+            Error::OidcDiscoveryFailed("attestation tokens are not OIDC ID tokens".to_string())
+        })?;
+    Ok(HttpResponse::Ok().json(discovery))
+}
+
+/// GET /.well-known/jwks.json
+///
+/// The public keys verifying the attestation tokens.
+pub(crate) async fn jwks(
+    attestation_service: web::Data<Arc<AttestationService>>,
+) -> Result<HttpResponse> {
+    let jwks = attestation_service
+        .token_jwks()
+        .await
+        .map_err(|e| Error::OidcDiscoveryFailed(format!("{e:#}")))?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(jwks))
+}
@@ -0,0 +1,156 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+
+/// The OpenAPI document of the routes of the server, generated at build time
+/// with the operations described in `docs/kbs.yaml`. The routes of the
+/// features KBS is built without aren't in it, the ones of the features it
+/// isn't configured with are.
+const OPENAPI: &str = include_str!(concat!(env!("OUT_DIR"), "/openapi.json"));
+
+/// GET /openapi.json
+///
+/// The OpenAPI document of the KBS API.
+pub(crate) async fn openapi() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(OPENAPI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{register_routes, OptionalRoutes};
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+    use kbs_openapi::routes::{self, Route};
+    use serde_json::Value;
+
+    fn enabled(feature: &str) -> bool {
+        match feature {
+            "as" => cfg!(feature = "as"),
+            "resource" => cfg!(feature = "resource"),
+            "policy" => cfg!(feature = "policy"),
+            "spiffe" => cfg!(feature = "spiffe"),
+            _ => false,
+        }
+    }
+
+    /// `path` with its parameters set.
+    fn uri(path: &str) -> String {
+        path.split('/')
+            .map(|segment| match segment.starts_with('{') {
+                true => "x",
+                false => segment,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Every route of `kbs-openapi` is served, and no other method at its
+    /// path, so that the OpenAPI document is the one of the server.
+    #[actix_web::test]
+    async fn test_routes() {
+        let optional = OptionalRoutes {
+            #[cfg(feature = "resource")]
+            download_url: true,
+            #[cfg(feature = "resource")]
+            token_exchange: true,
+            #[cfg(feature = "spiffe")]
+            svid: true,
+        };
+        // The handlers fail without the state of the server, with another
+        // status than the unrouted requests.
+        let app = init_service(
+            App::new()
+                .configure(|config| register_routes(config, optional))
+                .default_service(web::to(|| async { HttpResponse::ImATeapot().finish() })),
+        )
+        .await;
+
+        let routes = routes::enabled(enabled);
+        for route in &routes {
+            let routed = |method: &str| {
+                routes
+                    .iter()
+                    .any(|other: &Route| other.path == route.path && other.method == method)
+            };
+            for method in ["get", "post", "put", "delete", "patch"] {
+                let request = TestRequest::default()
+                    .method(Method::from_bytes(method.to_uppercase().as_bytes()).unwrap())
+                    .uri(&uri(route.path))
+                    .to_request();
+                let status = call_service(&app, request).await.status();
+                match routed(method) {
+                    true => assert!(
+                        status != StatusCode::IM_A_TEAPOT
+                            && status != StatusCode::METHOD_NOT_ALLOWED,
+                        "{method} {} isn't routed",
+                        route.path
+                    ),
+                    false => assert!(
+                        status == StatusCode::IM_A_TEAPOT
+                            || status == StatusCode::METHOD_NOT_ALLOWED,
+                        "{method} {} is routed",
+                        route.path
+                    ),
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_openapi() {
+        let app = init_service(App::new().route("/openapi.json", web::get().to(openapi))).await;
+        let request = TestRequest::get().uri("/openapi.json").to_request();
+        let document: Value = call_and_read_body_json(&app, request).await;
+
+        let paths = &document["paths"];
+        assert_eq!(
+            paths["/kbs/v0/openapi.json"]["get"]["operationId"],
+            "getOpenApi"
+        );
+        #[cfg(feature = "as")]
+        assert_eq!(
+            paths["/kbs/v0/attestation-policy"]["post"]["operationId"],
+            "setAttestationPolicy"
+        );
+        #[cfg(feature = "as")]
+        assert_eq!(
+            paths["/kbs/v0/tenant/{tenant}/attestation-policy"]["post"]["operationId"],
+            "setAttestationPolicyForTenant"
+        );
+        #[cfg(feature = "resource")]
+        assert_eq!(
+            paths["/kbs/v0/resource/{repository}/{type}/{tag}"]["get"]["operationId"],
+            "getResource"
+        );
+        #[cfg(feature = "resource")]
+        assert_eq!(
+            paths["/kbs/v0/resource/{type}/{tag}"]["get"]["operationId"],
+            "getResourceWithoutRepository"
+        );
+        #[cfg(not(feature = "spiffe"))]
+        assert!(paths.get("/kbs/v0/svid").is_none());
+    }
+
+    /// Every route is described in `docs/kbs.yaml`, and every operation
+    /// described there is served.
+    #[test]
+    fn test_description() {
+        let spec = kbs_openapi::generate(|_| true, include_str!("../../docs/kbs.yaml")).unwrap();
+        assert!(
+            spec.undocumented.is_empty(),
+            "undocumented routes: {:?}",
+            spec.undocumented
+        );
+        assert!(
+            spec.unrouted.is_empty(),
+            "unrouted operations: {:?}",
+            spec.unrouted
+        );
+    }
+}
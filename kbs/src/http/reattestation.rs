@@ -0,0 +1,158 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use actix_web::cookie::time::{Duration, OffsetDateTime};
+
+use crate::raise_error;
+
+use super::*;
+
+/// How long an attestation verdict can be used to access resources. Clients
+/// have to attest again once it is older, even if their session or token has
+/// not expired yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ReattestationInterval {
+    interval: Option<Duration>,
+
+    /// Verdicts of the attestations before are stale, e.g. since the
+    /// attestation policies changed.
+    not_before: Option<OffsetDateTime>,
+}
+
+impl ReattestationInterval {
+    /// Re-attestation after `minutes`, or never when `None`.
+    pub fn new(minutes: Option<i64>) -> Self {
+        Self {
+            interval: minutes.map(Duration::minutes),
+            not_before: None,
+        }
+    }
+
+    /// Check that clients are given a positive number of `minutes` before
+    /// they have to attest again.
+    pub fn validate(minutes: Option<i64>) -> anyhow::Result<()> {
+        if let Some(minutes) = minutes.filter(|minutes| *minutes <= 0) {
+            anyhow::bail!("reattestation_interval must be positive, not {minutes}");
+        }
+        Ok(())
+    }
+
+    /// Re-attestation after `minutes`, keeping the verdicts made stale.
+    pub fn reconfigure(self, minutes: Option<i64>) -> Self {
+        Self {
+            not_before: self.not_before,
+            ..Self::new(minutes)
+        }
+    }
+
+    /// Require the clients attested before `at` to attest again. Tokens
+    /// carry their issue time in seconds, so the ones issued in the second
+    /// of `at` are stale as well.
+    #[cfg(feature = "as")]
+    pub fn invalidate(self, at: OffsetDateTime) -> Self {
+        Self {
+            not_before: Some(at),
+            ..self
+        }
+    }
+
+    /// Check the verdict of an attestation at `attested_at`.
+    pub fn check(&self, attested_at: OffsetDateTime) -> Result<()> {
+        if self
+            .not_before
+            .is_some_and(|not_before| attested_at < not_before)
+        {
+            raise_error!(Error::ReattestationRequired);
+        }
+
+        let Some(interval) = self.interval else {
+            return Ok(());
+        };
+
+        if attested_at + interval < OffsetDateTime::now_utc() {
+            raise_error!(Error::ReattestationRequired);
+        }
+        Ok(())
+    }
+
+    /// Check the verdict of the attestation token with `claims`, issued at
+    /// their `iat` claim.
+    #[cfg(feature = "resource")]
+    pub fn check_claims(&self, claims: &str) -> Result<()> {
+        if self.interval.is_none() && self.not_before.is_none() {
+            return Ok(());
+        }
+
+        let claims: serde_json::Value = serde_json::from_str(claims)
+            .map_err(|e| Error::AttestationClaimsParseFailed(e.to_string()))?;
+        let attested_at = claims["iat"]
+            .as_i64()
+            .and_then(|iat| OffsetDateTime::from_unix_timestamp(iat).ok())
+            .ok_or_else(|| {
+                Error::TokenParseFailed("no issue time in the attestation token".to_string())
+            })?;
+
+        self.check(attested_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(None, 120, true)]
+    #[case(Some(60), 30, true)]
+    #[case(Some(60), 90, false)]
+    fn test_check(#[case] interval: Option<i64>, #[case] age: i64, #[case] fresh: bool) {
+        let attested_at = OffsetDateTime::now_utc() - Duration::minutes(age);
+        assert_eq!(
+            ReattestationInterval::new(interval)
+                .check(attested_at)
+                .is_ok(),
+            fresh
+        );
+    }
+
+    #[rstest]
+    #[case(None, true)]
+    #[case(Some(1), true)]
+    #[case(Some(0), false)]
+    #[case(Some(-5), false)]
+    fn test_validate(#[case] interval: Option<i64>, #[case] valid: bool) {
+        assert_eq!(ReattestationInterval::validate(interval).is_ok(), valid);
+    }
+
+    #[cfg(feature = "as")]
+    #[test]
+    fn test_invalidate() {
+        let changed_at = OffsetDateTime::now_utc() - Duration::minutes(10);
+        let interval = ReattestationInterval::new(Some(60)).invalidate(changed_at);
+        assert!(interval.check(changed_at - Duration::minutes(1)).is_err());
+        assert!(interval.check(changed_at + Duration::minutes(1)).is_ok());
+
+        // Stale verdicts stay stale when the configuration is reloaded.
+        let interval = interval.reconfigure(None);
+        assert!(interval.check(changed_at - Duration::minutes(1)).is_err());
+        assert!(interval.check(OffsetDateTime::now_utc()).is_ok());
+    }
+
+    #[cfg(feature = "resource")]
+    #[test]
+    fn test_check_claims() {
+        let interval = ReattestationInterval::new(Some(60));
+        let iat = |age: i64| (OffsetDateTime::now_utc() - Duration::minutes(age)).unix_timestamp();
+
+        assert!(interval
+            .check_claims(&format!(r#"{{"iat": {}}}"#, iat(30)))
+            .is_ok());
+        assert!(matches!(
+            interval.check_claims(&format!(r#"{{"iat": {}}}"#, iat(90))),
+            Err(Error::ReattestationRequired)
+        ));
+        assert!(interval.check_claims("{}").is_err());
+        assert!(ReattestationInterval::default().check_claims("{}").is_ok());
+    }
+}
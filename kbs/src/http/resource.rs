@@ -4,64 +4,136 @@
 
 use actix_web::{http::header::Header, web::Bytes};
 use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
-use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
 use anyhow::{anyhow, bail};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
 use kbs_types::{Response, TeePubKey};
 use log::{debug, error, info};
-use rand::{rngs::OsRng, Rng};
-use rsa::{BigUint, Pkcs1v15Encrypt, RsaPublicKey};
 use serde::Deserialize;
 use serde_json::{json, Deserializer, Value};
+use zeroize::Zeroizing;
 
+use crate::crypto::{self, AES_256_GCM_KEY_LENGTH, AES_256_GCM_NONCE_LENGTH};
 use crate::raise_error;
 
 use super::*;
 
-#[cfg(feature = "as")]
-const TOKEN_TEE_PUBKEY_PATH: &str = AS_TOKEN_TEE_PUBKEY_PATH;
-#[cfg(not(feature = "as"))]
-const TOKEN_TEE_PUBKEY_PATH: &str = "/customized_claims/runtime_data/tee-pubkey";
+/// Where the tokens of the CoCo AS and of Intel Trust Authority carry the
+/// TEE public key.
+pub(crate) const TOKEN_TEE_PUBKEY_PATHS: &[&str] = &[
+    "/customized_claims/runtime_data/tee-pubkey",
+    "/attester_runtime_data/tee-pubkey",
+];
 
-#[allow(unused_assignments)]
 /// GET /resource/{repository}/{type}/{tag}
 /// GET /resource/{type}/{tag}
+#[tracing::instrument(skip_all)]
 pub(crate) async fn get_resource(
     request: HttpRequest,
-    repository: web::Data<Arc<RwLock<dyn Repository + Send + Sync>>>,
+    repository: web::Data<Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>>,
     #[cfg(feature = "as")] map: web::Data<SessionMap>,
-    token_verifier: web::Data<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>,
-    #[cfg(feature = "policy")] policy_engine: web::Data<PolicyEngine>,
+    token_verifier: web::Data<Reloadable<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>>,
+    reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    #[cfg(feature = "policy")] policy_engine: web::Data<Reloadable<PolicyEngine>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    audit: web::Data<AuditLog>,
+    workload_identity: web::Data<WorkloadIdentity>,
 ) -> Result<HttpResponse> {
-    #[allow(unused_mut)]
-    let mut claims_option = None;
-    #[cfg(feature = "as")]
-    {
-        claims_option = get_attest_claims_from_session(&request, map).await.ok();
-    }
-    let claims_str = if let Some(c) = claims_option {
-        debug!("Get pkey from session.");
-        c
-    } else {
-        debug!("Get pkey from auth header");
-        get_attest_claims_from_header(&request, token_verifier).await?
+    let mut identity = None;
+    let result = resource_response(
+        &request,
+        repository.get(),
+        #[cfg(feature = "as")]
+        map,
+        token_verifier.get(),
+        reattestation_interval.get(),
+        #[cfg(feature = "policy")]
+        policy_engine.get(),
+        &tenants.get(),
+        &workload_identity,
+        &mut identity,
+    )
+    .await;
+
+    RESOURCE_REQUESTS
+        .with_label_values(&[
+            repository_label(request.match_info().get("repository").unwrap_or("default")),
+            result_label(&result),
+        ])
+        .inc();
+
+    audit
+        .record(
+            AuditEvent::new(AuditEventType::ResourceAccess, &request)
+                .actor(Actor::attester(identity))
+                .detail("path", request.path())
+                .result(&result),
+        )
+        .await;
+
+    result
+}
+
+/// The resource requested by `request`, encrypted for its attester, whose
+/// workload identity is set to `identity`.
+async fn resource_response(
+    request: &HttpRequest,
+    repository: Arc<RwLock<dyn Repository + Send + Sync>>,
+    #[cfg(feature = "as")] map: web::Data<SessionMap>,
+    token_verifier: Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>,
+    reattestation_interval: ReattestationInterval,
+    #[cfg(feature = "policy")] policy_engine: PolicyEngine,
+    tenants: &Tenants,
+    workload_identity: &WorkloadIdentity,
+    identity: &mut Option<String>,
+) -> Result<HttpResponse> {
+    let tenant = tenants.of_request(request)?;
+    let tenant_id = tenant.as_ref().map(|tenant| tenant.id.as_str());
+
+    let (claims_str, workload) = request_claims(
+        request,
+        #[cfg(feature = "as")]
+        &map,
+        &token_verifier,
+        reattestation_interval,
+        tenants,
+        tenant_id,
+        workload_identity,
+    )
+    .await?;
+    *identity = workload;
+
+    let repository = match &tenant {
+        Some(tenant) => tenant.repository.clone(),
+        None => repository,
+    };
+    #[cfg(feature = "policy")]
+    let policy_engine = match &tenant {
+        Some(tenant) => tenant.policy_engine.clone(),
+        None => policy_engine,
     };
-    let claims: Value = serde_json::from_str(&claims_str).map_err(|e| {
-        Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
-    })?;
 
-    let pkey_value =
-        claims
-            .pointer(TOKEN_TEE_PUBKEY_PATH)
-            .ok_or(Error::AttestationClaimsParseFailed(String::from(
-                "Failed to find `tee-pubkey` in the attestation claims",
-            )))?;
-    let pubkey = TeePubKey::deserialize(pkey_value).map_err(|e| {
-        Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
-    })?;
+    let resource_description = request_resource(request)?;
 
-    let resource_description = ResourceDesc {
+    let jwe = read_resource(
+        claims_str,
+        resource_description,
+        &repository,
+        #[cfg(feature = "policy")]
+        &policy_engine,
+    )
+    .await?;
+
+    let res = serde_json::to_string(&jwe).map_err(|e| Error::JWEFailed(e.to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(res))
+}
+
+/// The resource addressed by the path of `request`.
+pub(crate) fn request_resource(request: &HttpRequest) -> Result<ResourceDesc> {
+    Ok(ResourceDesc {
         repository_name: request
             .match_info()
             .get("repository")
@@ -77,8 +149,104 @@ pub(crate) async fn get_resource(
             .get("tag")
             .ok_or_else(|| Error::InvalidRequest(String::from("no `tag` in url")))?
             .to_string(),
+    })
+}
+
+/// The attestation claims of the attester of `request`, from its session
+/// cookie or else from the attestation results token of its Authorization
+/// header. The claims are annotated with the workload identity of the
+/// attester, which is returned with them.
+#[allow(unused_assignments)]
+pub(crate) async fn request_claims(
+    request: &HttpRequest,
+    #[cfg(feature = "as")] map: &SessionMap,
+    token_verifier: &Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>,
+    reattestation_interval: ReattestationInterval,
+    tenants: &Tenants,
+    tenant_id: Option<&str>,
+    workload_identity: &WorkloadIdentity,
+) -> Result<(String, Option<String>)> {
+    #[allow(unused_mut)]
+    let mut claims_option = None;
+    #[cfg(feature = "as")]
+    {
+        claims_option =
+            match get_attest_claims_from_session(request, map, tenant_id, reattestation_interval)
+                .await
+            {
+                Ok(claims) => Some(claims),
+                Err(e @ (Error::ReattestationRequired | Error::TenantMismatch)) => raise_error!(e),
+                Err(_) => None,
+            };
+    }
+    let claims = if let Some(c) = claims_option {
+        debug!("Get pkey from session.");
+        c
+    } else {
+        debug!("Get pkey from auth header");
+        get_attest_claims_from_header(
+            request,
+            token_verifier,
+            tenants,
+            tenant_id,
+            reattestation_interval,
+        )
+        .await?
     };
+    workload_identity
+        .annotate_str(&claims)
+        .map_err(|e| Error::AttestationClaimsParseFailed(format!("{e:#}")))
+}
 
+/// Read the resource described by `resource_description` for the attester
+/// with the attestation claims `claims_str`, encrypted to its TEE public key.
+pub(crate) async fn read_resource(
+    claims_str: String,
+    resource_description: ResourceDesc,
+    repository: &Arc<RwLock<dyn Repository + Send + Sync>>,
+    #[cfg(feature = "policy")] policy_engine: &PolicyEngine,
+) -> Result<Response> {
+    let pubkey = tee_pubkey(&claims_str)?;
+    let resource_byte = read_permitted_resource(
+        claims_str,
+        resource_description,
+        repository,
+        #[cfg(feature = "policy")]
+        policy_engine,
+    )
+    .await?;
+
+    jwe(pubkey, &resource_byte)
+}
+
+/// The TEE public key of the attester with the attestation claims
+/// `claims_str`.
+pub(crate) fn tee_pubkey(claims_str: &str) -> Result<TeePubKey> {
+    let claims: Value = serde_json::from_str(claims_str).map_err(|e| {
+        Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
+    })?;
+
+    let pkey_value = TOKEN_TEE_PUBKEY_PATHS
+        .iter()
+        .find_map(|path| claims.pointer(path))
+        .ok_or(Error::AttestationClaimsParseFailed(String::from(
+            "Failed to find `tee-pubkey` in the attestation claims",
+        )))?;
+    TeePubKey::deserialize(pkey_value).map_err(|e| {
+        Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
+    })
+}
+
+/// Read the resource described by `resource_description`, if the resource
+/// policy allows it to the attester with the attestation claims
+/// `claims_str`.
+#[cfg_attr(not(feature = "policy"), allow(unused_variables))]
+pub(crate) async fn read_permitted_resource(
+    claims_str: String,
+    resource_description: ResourceDesc,
+    repository: &Arc<RwLock<dyn Repository + Send + Sync>>,
+    #[cfg(feature = "policy")] policy_engine: &PolicyEngine,
+) -> Result<Zeroizing<Vec<u8>>> {
     if !resource_description.is_valid() {
         return Err(Error::InvalidRequest("Invalid resource path".to_string()));
     }
@@ -91,104 +259,182 @@ pub(crate) async fn get_resource(
     );
 
     #[cfg(feature = "policy")]
-    {
-        let resource_path = format!(
-            "{}/{}/{}",
-            resource_description.repository_name,
-            resource_description.resource_type,
-            resource_description.resource_tag
-        );
-        let resource_allowed = policy_engine
-            .0
-            .lock()
-            .await
-            .evaluate(resource_path, claims_str)
-            .await
-            .map_err(|e| Error::PolicyEngineFailed(e.to_string()))?;
-
-        if !resource_allowed {
-            raise_error!(Error::PolicyReject);
-        }
-
-        info!("Resource access request passes policy check.");
-    }
+    check_resource_policy(claims_str, &resource_description, policy_engine).await?;
 
-    let resource_byte = repository
+    repository
         .read()
         .await
         .read_secret_resource(resource_description)
         .await
-        .map_err(|e| Error::ReadSecretFailed(format!("{e:?}")))?;
+        .map_err(|e| Error::ReadSecretFailed(format!("{e:?}")))
+}
 
-    let jwe = jwe(pubkey, resource_byte)?;
+/// Check that the resource policy allows the resource described by
+/// `resource_description` to the attester with the attestation claims
+/// `claims_str`.
+#[cfg(feature = "policy")]
+pub(crate) async fn check_resource_policy(
+    claims_str: String,
+    resource_description: &ResourceDesc,
+    policy_engine: &PolicyEngine,
+) -> Result<()> {
+    let resource_path = format!(
+        "{}/{}/{}",
+        resource_description.repository_name,
+        resource_description.resource_type,
+        resource_description.resource_tag
+    );
+    let claims: Value = serde_json::from_str(&claims_str).map_err(|e| {
+        Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
+    })?;
+    let missing = policy_engine
+        .required_policies
+        .read()
+        .await
+        .missing(&resource_path, &claims);
+    if !missing.is_empty() {
+        info!("Resource {resource_path} requires the attestation policies {missing:?}");
+        raise_error!(Error::PolicyReject);
+    }
 
-    let res = serde_json::to_string(&jwe).map_err(|e| Error::JWEFailed(e.to_string()))?;
+    let resource_allowed = policy_engine
+        .engine
+        .lock()
+        .await
+        .evaluate(resource_path, claims_str)
+        .await
+        .map_err(|e| Error::PolicyEngineFailed(e.to_string()))?;
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/json")
-        .body(res))
+    if !resource_allowed {
+        raise_error!(Error::PolicyReject);
+    }
+
+    info!("Resource access request passes policy check.");
+    Ok(())
 }
 
 #[cfg(feature = "as")]
 async fn get_attest_claims_from_session(
     request: &HttpRequest,
-    map: web::Data<SessionMap>,
+    map: &SessionMap,
+    tenant: Option<&str>,
+    reattestation_interval: ReattestationInterval,
 ) -> Result<String> {
     // check cookie
-
-    use crate::session::SessionStatus;
     let cookie = request
         .cookie(KBS_SESSION_ID)
         .ok_or(Error::UnAuthenticatedCookie)?;
 
+    session_claims(cookie.value(), map, tenant, reattestation_interval).await
+}
+
+/// The attestation claims of the session `session_id`, attested for `tenant`,
+/// unless it was attested longer than `reattestation_interval` ago.
+#[cfg(feature = "as")]
+pub(crate) async fn session_claims(
+    session_id: &str,
+    map: &SessionMap,
+    tenant: Option<&str>,
+    reattestation_interval: ReattestationInterval,
+) -> Result<String> {
+    use crate::session::{session_handle, SessionStatus};
+
+    let handle = session_handle(session_id);
     let session = map
         .sessions
-        .get_async(cookie.value())
+        .get_async(session_id)
         .await
         .ok_or(Error::UnAuthenticatedCookie)?;
 
     let session = session.get();
 
-    info!("Cookie {} request to get resource", session.id());
+    info!("Session {handle} requests a resource");
 
     if session.is_expired() {
-        error!("Expired KBS cookie {}", cookie.value());
+        error!("Expired KBS session {handle}");
         raise_error!(Error::ExpiredCookie);
     }
 
     let SessionStatus::Attested {
-        attestation_claims, ..
+        attestation_claims,
+        attested_at,
+        ..
     } = session
     else {
         raise_error!(Error::UnAuthenticatedCookie);
     };
 
+    if session.tenant() != tenant {
+        info!("KBS session {handle} belongs to another tenant");
+        raise_error!(Error::TenantMismatch);
+    }
+
+    if let Err(e) = reattestation_interval.check(*attested_at) {
+        info!("KBS session {handle} requires re-attestation");
+        return Err(e);
+    }
+
     Ok(attestation_claims.to_owned())
 }
 
 async fn get_attest_claims_from_header(
     request: &HttpRequest,
-    token_verifier: web::Data<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>,
+    token_verifier: &Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>,
+    tenants: &Tenants,
+    tenant: Option<&str>,
+    reattestation_interval: ReattestationInterval,
 ) -> Result<String> {
     let bearer = Authorization::<Bearer>::parse(request)
         .map_err(|e| Error::InvalidRequest(format!("parse Authorization header failed: {e}")))?
         .into_scheme();
 
-    let token = bearer.token().to_string();
+    token_claims(
+        bearer.token().to_string(),
+        token_verifier,
+        tenants,
+        tenant,
+        reattestation_interval,
+    )
+    .await
+}
 
+/// The attestation claims of the attestation results `token`, issued for
+/// `tenant`, unless it was issued longer than `reattestation_interval` ago.
+pub(crate) async fn token_claims(
+    token: String,
+    token_verifier: &Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>,
+    tenants: &Tenants,
+    tenant: Option<&str>,
+    reattestation_interval: ReattestationInterval,
+) -> Result<String> {
     let claims = token_verifier
         .read()
         .await
         .verify(token)
         .await
         .map_err(|e| Error::TokenParseFailed(format!("verify token failed: {e}")))?;
+    reattestation_interval.check_claims(&claims)?;
+    tenants.check_token_claims(tenant, &claims)?;
+
+    // Resource policies read the claims of the CoCo AS, whichever backend
+    // issued the token.
+    #[cfg(feature = "intel-trust-authority-as")]
+    let claims = {
+        let mut claims: Value = serde_json::from_str(&claims).map_err(|e| {
+            Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
+        })?;
+        crate::attestation::intel_trust_authority::add_coco_claims_of_token(&mut claims);
+        claims.to_string()
+    };
     Ok(claims)
 }
 
 const RSA_ALGORITHM: &str = "RSA1_5";
 const AES_GCM_256_ALGORITHM: &str = "A256GCM";
 
-pub(crate) fn jwe(tee_pub_key: TeePubKey, payload_data: Vec<u8>) -> Result<Response> {
+/// Encrypt `payload_data` to `tee_pub_key`. The content encryption key is
+/// wiped from memory once wrapped.
+pub(crate) fn jwe(tee_pub_key: TeePubKey, payload_data: &[u8]) -> Result<Response> {
     if tee_pub_key.alg != *RSA_ALGORITHM {
         raise_error!(Error::JWEFailed(format!(
             "algorithm is not {RSA_ALGORITHM} but {}",
@@ -196,33 +442,21 @@ pub(crate) fn jwe(tee_pub_key: TeePubKey, payload_data: Vec<u8>) -> Result<Respo
         )));
     }
 
-    let mut rng = rand::thread_rng();
-
-    let aes_sym_key = Aes256Gcm::generate_key(&mut OsRng);
-    let cipher = Aes256Gcm::new(&aes_sym_key);
-    let iv = rng.gen::<[u8; 12]>();
-    let nonce = Nonce::from_slice(&iv);
-    let encrypted_payload_data = cipher
-        .encrypt(nonce, payload_data.as_slice())
+    let mut aes_sym_key = Zeroizing::new([0; AES_256_GCM_KEY_LENGTH]);
+    let mut iv = [0; AES_256_GCM_NONCE_LENGTH];
+    crypto::fill_random(&mut *aes_sym_key)
+        .and_then(|_| crypto::fill_random(&mut iv))
+        .map_err(|e| Error::JWEFailed(format!("generate content encryption key failed: {e}")))?;
+    let encrypted_payload_data = crypto::aes_256_gcm_encrypt(&*aes_sym_key, &iv, b"", payload_data)
         .map_err(|e| Error::JWEFailed(format!("AES encrypt Resource payload failed: {e:?}")))?;
 
     let k_mod = URL_SAFE_NO_PAD
         .decode(&tee_pub_key.k_mod)
         .map_err(|e| Error::JWEFailed(format!("base64 decode k_mod failed: {e:?}")))?;
-    let n = BigUint::from_bytes_be(&k_mod);
     let k_exp = URL_SAFE_NO_PAD
         .decode(&tee_pub_key.k_exp)
         .map_err(|e| Error::JWEFailed(format!("base64 decode k_exp failed: {e:?}")))?;
-    let e = BigUint::from_bytes_be(&k_exp);
-
-    let rsa_pub_key = RsaPublicKey::new(n, e).map_err(|e| {
-        Error::JWEFailed(format!(
-            "Building RSA key from modulus and exponent failed: {e:?}"
-        ))
-    })?;
-    let sym_key: &[u8] = aes_sym_key.as_slice();
-    let wrapped_sym_key = rsa_pub_key
-        .encrypt(&mut rng, Pkcs1v15Encrypt, sym_key)
+    let wrapped_sym_key = crypto::rsa_pkcs1v15_encrypt(&k_mod, &k_exp, &*aes_sym_key)
         .map_err(|e| Error::JWEFailed(format!("RSA encrypt sym key failed: {e:?}")))?;
 
     let protected_header = json!(
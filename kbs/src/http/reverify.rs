@@ -0,0 +1,313 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verification of the evidence of the unexpired tokens again, once the
+//! attestation policies or reference values changed, to find the workloads
+//! that would now fail attestation before they have to attest again.
+
+use actix_web::cookie::time::format_description::well_known::Rfc3339;
+use futures::stream::{self, StreamExt};
+use kbs_types::Tee;
+use log::info;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::attestation::publisher::VerdictKind;
+use crate::identity::identity_of;
+use crate::session::IssuedToken;
+
+use super::*;
+
+/// Number of attestations verified again at the same time.
+const REVERIFY_CONCURRENCY: usize = 8;
+
+/// An attestation of an unexpired token whose evidence fails attestation
+/// now.
+#[derive(Debug, Serialize)]
+pub(crate) struct StaleSession {
+    /// Handle of the session the token was issued in.
+    pub session_handle: String,
+
+    /// Hex encoded SHA-256 digest of the token.
+    pub token_digest: String,
+    pub tee: Tee,
+    pub attested_at: String,
+
+    /// Time the token expires.
+    pub expires_at: String,
+
+    /// Claims of the token.
+    pub claims: Value,
+
+    /// Why the evidence fails attestation.
+    pub reason: String,
+}
+
+/// Outcome of the verification of the unexpired tokens of a tenant.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct ReattestationCheck {
+    /// Number of tokens whose evidence is verified again.
+    pub checked: usize,
+    pub stale: Vec<StaleSession>,
+}
+
+/// Verify the evidence of the unexpired tokens issued to `tenant` again,
+/// with its current attestation policy, and record a `reattestation_required`
+/// audit event for every token that fails.
+pub(crate) async fn check_sessions(
+    map: &SessionMap,
+    attestation_service: &AttestationService,
+    tenants: &Tenants,
+    tenant: Option<&str>,
+    audit: &AuditLog,
+) -> Result<ReattestationCheck> {
+    let policy_id = tenants.attestation_policy(tenant)?;
+    let rvps_namespace = tenants.rvps_namespace(tenant)?;
+    let issued = map.issued(tenant).await;
+    let checked = issued.len();
+
+    let verify = |(token_digest, issued): (String, IssuedToken)| {
+        let (policy_id, rvps_namespace) = (&policy_id, &rvps_namespace);
+        async move {
+            let verdict = attestation_service
+                .simple_verify(
+                    issued.tee,
+                    &issued.evidence,
+                    Some(policy_id),
+                    rvps_namespace.as_deref(),
+                )
+                .await;
+            (token_digest, issued, verdict)
+        }
+    };
+    let results: Vec<_> = stream::iter(issued)
+        .map(verify)
+        .buffer_unordered(REVERIFY_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut stale = Vec::new();
+    for (token_digest, issued, verdict) in results {
+        let Err(e) = &verdict else {
+            continue;
+        };
+        let reason = format!("{e:#}");
+
+        let tee_name = serde_json::to_value(issued.tee)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string));
+        let claims: Value = serde_json::from_str(&issued.attestation_claims).unwrap_or_default();
+        let identity = identity_of(&claims);
+        let mut event = AuditEvent::from_peer(
+            AuditEventType::ReattestationRequired,
+            issued.session_handle.clone(),
+            None,
+        )
+        .actor(Actor::attester(identity.or(tee_name)))
+        .detail("policy_id", policy_id.as_str())
+        .detail("token_digest", token_digest.as_str())
+        .detail("reason", reason.as_str());
+        if let Some(tenant) = tenant {
+            event = event.detail("tenant", tenant);
+        }
+        attestation_service.publish(VerdictKind::Reattestation, &event, issued.tee, &verdict);
+        audit.record(event).await;
+
+        stale.push(StaleSession {
+            session_handle: issued.session_handle,
+            token_digest,
+            tee: issued.tee,
+            attested_at: issued.attested_at.format(&Rfc3339).unwrap_or_default(),
+            expires_at: issued.expires_at.format(&Rfc3339).unwrap_or_default(),
+            claims,
+            reason,
+        });
+    }
+
+    Ok(ReattestationCheck { checked, stale })
+}
+
+/// Check the unexpired tokens of `tenant` in the background, once the
+/// attestation policy `policy_id` changed, unless nobody is notified of the
+/// outcome. The tokens are only affected by the policy they are verified
+/// with.
+pub(crate) fn check_sessions_later(
+    map: web::Data<SessionMap>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    tenants: Arc<Tenants>,
+    tenant: Option<String>,
+    policy_id: &str,
+    audit: web::Data<AuditLog>,
+) {
+    let affected = tenants
+        .attestation_policy(tenant.as_deref())
+        .is_ok_and(|attestation_policy| attestation_policy == policy_id);
+    if !audit.is_enabled() || !affected {
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        match check_sessions(
+            &map,
+            &attestation_service,
+            &tenants,
+            tenant.as_deref(),
+            &audit,
+        )
+        .await
+        {
+            Ok(check) => info!(
+                "{} of {} unexpired tokens fail the changed attestation policy",
+                check.stale.len(),
+                check.checked
+            ),
+            Err(e) => log::warn!("Failed to check the unexpired tokens: {e}"),
+        }
+    });
+}
+
+/// Check the unexpired tokens of every tenant every `interval`, as changes
+/// of the reference values in the RVPS aren't notified to KBS.
+pub(crate) fn check_sessions_periodically(
+    map: web::Data<SessionMap>,
+    attestation_service: web::Data<Arc<AttestationService>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    audit: web::Data<AuditLog>,
+    interval: std::time::Duration,
+) {
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let tenants = tenants.get();
+            let ids = std::iter::once(None).chain(tenants.iter().map(|t| Some(t.id.as_str())));
+            for tenant in ids {
+                match check_sessions(&map, &attestation_service, &tenants, tenant, &audit).await {
+                    Ok(check) if !check.stale.is_empty() => info!(
+                        "{} of {} unexpired tokens of tenant {} fail attestation now",
+                        check.stale.len(),
+                        check.checked,
+                        tenant.unwrap_or("default")
+                    ),
+                    Ok(_) => {}
+                    Err(e) => log::warn!("Failed to check the unexpired tokens: {e}"),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::{Attest, Verdict};
+    use crate::audit::{AuditConfig, AuditSinkConfig};
+    use crate::session::{session_handle, token_digest};
+    use actix_web::cookie::time::{Duration, OffsetDateTime};
+
+    struct Backend;
+
+    #[async_trait::async_trait]
+    impl Attest for Backend {
+        async fn verify(
+            &self,
+            _: Tee,
+            _: &str,
+            _: &str,
+            _: &str,
+            _: Option<&str>,
+            _: Option<&[u8]>,
+            _: Option<&str>,
+        ) -> anyhow::Result<Verdict> {
+            anyhow::bail!("unused")
+        }
+
+        async fn simple_verify(
+            &self,
+            _: Tee,
+            evidence: &str,
+            _: Option<&str>,
+            _: Option<&str>,
+        ) -> anyhow::Result<Verdict> {
+            match evidence {
+                "fresh" => Ok(Verdict {
+                    token: String::new(),
+                    claims: Value::Null,
+                    policies: Vec::new(),
+                }),
+                _ => anyhow::bail!("svn is revoked"),
+            }
+        }
+    }
+
+    fn issued(evidence: &str, tenant: Option<&str>, expires_in: Duration) -> IssuedToken {
+        let now = OffsetDateTime::now_utc();
+        IssuedToken {
+            session_handle: session_handle(&uuid::Uuid::new_v4().to_string()),
+            tenant: tenant.map(str::to_string),
+            tee: Tee::Tdx,
+            evidence: evidence.into(),
+            attestation_claims: r#"{"svn": 1}"#.into(),
+            attested_at: now,
+            expires_at: now + expires_in,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_path = dir.path().join("audit.log");
+        let audit = AuditLog::new(
+            Some(&AuditConfig {
+                sink: AuditSinkConfig::File {
+                    path: audit_path.clone(),
+                },
+                signing_key: None,
+                checkpoint_interval: 3600,
+            }),
+            &[],
+        )
+        .await
+        .unwrap();
+
+        let map = SessionMap::new();
+        let stale = issued("stale", None, Duration::hours(1));
+        let stale_handle = stale.session_handle.clone();
+        assert!(map.issue("stale", stale).await);
+        assert!(
+            map.issue("fresh", issued("fresh", None, Duration::hours(1)))
+                .await
+        );
+        assert!(
+            map.issue("tenant", issued("stale", Some("a"), Duration::hours(1)))
+                .await
+        );
+        // The evidence of expired tokens isn't verified again.
+        assert!(
+            map.issue("expired", issued("stale", None, -Duration::hours(1)))
+                .await
+        );
+
+        let check = check_sessions(
+            &map,
+            &AttestationService::from_backend(Arc::new(Backend)),
+            &Tenants::new(&[], false).await.unwrap(),
+            None,
+            &audit,
+        )
+        .await
+        .unwrap();
+        assert_eq!(check.checked, 2);
+        assert_eq!(check.stale.len(), 1);
+        assert_eq!(check.stale[0].session_handle, stale_handle);
+        assert_eq!(check.stale[0].token_digest, token_digest("stale"));
+        assert_eq!(check.stale[0].claims["svn"], 1);
+        assert_eq!(check.stale[0].reason, "svn is revoked");
+
+        audit.flush().await;
+        let records = std::fs::read_to_string(&audit_path).unwrap();
+        let record: Value = serde_json::from_str(records.lines().next().unwrap()).unwrap();
+        assert_eq!(record["event"], "reattestation_required");
+        assert_eq!(record["correlation_id"], stale_handle);
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use actix_web::error::JsonPayloadError;
+#[cfg(feature = "resource")]
+use actix_web::http::StatusCode;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::*;
+
+/// Request body size limit of the JSON payloads, as the actix default.
+const DEFAULT_JSON_PAYLOAD_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Request body size limit of the uploaded resources, as the actix default.
+const DEFAULT_RESOURCE_PAYLOAD_LIMIT: usize = 256 * 1024;
+
+/// Size beyond which an uploaded resource is written to a temporary file.
+const DEFAULT_SPILL_THRESHOLD: usize = 1024 * 1024;
+
+const DEFAULT_REQUEST_TIMEOUT: u64 = 5;
+const DEFAULT_KEEP_ALIVE: u64 = 5;
+const DEFAULT_DISCONNECT_TIMEOUT: u64 = 1;
+
+fn default_json_payload_limit() -> usize {
+    DEFAULT_JSON_PAYLOAD_LIMIT
+}
+
+fn default_resource_payload_limit() -> usize {
+    DEFAULT_RESOURCE_PAYLOAD_LIMIT
+}
+
+fn default_spill_threshold() -> usize {
+    DEFAULT_SPILL_THRESHOLD
+}
+
+fn default_request_timeout() -> u64 {
+    DEFAULT_REQUEST_TIMEOUT
+}
+
+fn default_keep_alive() -> u64 {
+    DEFAULT_KEEP_ALIVE
+}
+
+fn default_disconnect_timeout() -> u64 {
+    DEFAULT_DISCONNECT_TIMEOUT
+}
+
+/// Request size limits and connection timeouts of the HTTP server.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct HttpServerConfig {
+    /// Maximum size in bytes of the JSON request bodies, such as the
+    /// attestation evidence and the policies.
+    #[serde(default = "default_json_payload_limit")]
+    pub json_payload_limit: usize,
+
+    /// Maximum size in bytes of an uploaded resource.
+    #[serde(default = "default_resource_payload_limit")]
+    pub resource_payload_limit: usize,
+
+    /// Size in bytes beyond which an uploaded resource is received into a
+    /// temporary file instead of memory.
+    #[serde(default = "default_spill_threshold")]
+    pub spill_threshold: usize,
+
+    /// Directory of the temporary files of the uploaded resources, the
+    /// system temporary directory if omitted.
+    #[serde(default)]
+    pub spill_dir: Option<PathBuf>,
+
+    /// Seconds a client has to send the request head after connecting.
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+
+    /// Seconds an idle connection is kept open for the next request.
+    /// 0 disables keep-alive.
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: u64,
+
+    /// Seconds a client has to close the connection once the response is
+    /// written.
+    #[serde(default = "default_disconnect_timeout")]
+    pub disconnect_timeout: u64,
+}
+
+impl Default for HttpServerConfig {
+    fn default() -> Self {
+        Self {
+            json_payload_limit: DEFAULT_JSON_PAYLOAD_LIMIT,
+            resource_payload_limit: DEFAULT_RESOURCE_PAYLOAD_LIMIT,
+            spill_threshold: DEFAULT_SPILL_THRESHOLD,
+            spill_dir: None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            disconnect_timeout: DEFAULT_DISCONNECT_TIMEOUT,
+        }
+    }
+}
+
+impl HttpServerConfig {
+    pub fn json_config(&self) -> web::JsonConfig {
+        web::JsonConfig::default()
+            .limit(self.json_payload_limit)
+            .error_handler(|e, _| match e {
+                JsonPayloadError::OverflowKnownLength { .. }
+                | JsonPayloadError::Overflow { .. } => Error::PayloadTooLarge(e.to_string()).into(),
+                e => e.into(),
+            })
+    }
+
+    pub fn payload_config(&self) -> web::PayloadConfig {
+        web::PayloadConfig::new(self.resource_payload_limit)
+    }
+
+    #[cfg(feature = "resource")]
+    pub(crate) fn upload_config(&self) -> UploadConfig {
+        UploadConfig {
+            limit: self.resource_payload_limit,
+            spill_threshold: self.spill_threshold,
+            spill_dir: self.spill_dir.clone().unwrap_or_else(std::env::temp_dir),
+        }
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout)
+    }
+
+    pub fn keep_alive(&self) -> Option<Duration> {
+        match self.keep_alive {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+
+    pub fn disconnect_timeout(&self) -> Duration {
+        Duration::from_secs(self.disconnect_timeout)
+    }
+}
+
+/// Map the error of reading a request body to the KBS error, 413 when the
+/// body exceeds its limit.
+#[cfg(feature = "resource")]
+pub(crate) fn payload_error(e: actix_web::Error) -> Error {
+    if e.as_response_error().status_code() == StatusCode::PAYLOAD_TOO_LARGE {
+        Error::PayloadTooLarge(e.to_string())
+    } else {
+        Error::InvalidRequest(format!("read request body failed: {e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn test_json_payload_limit() {
+        let config = HttpServerConfig {
+            json_payload_limit: 16,
+            ..Default::default()
+        };
+        let app = init_service(App::new().app_data(config.json_config()).route(
+            "/",
+            web::post().to(|_: web::Json<serde_json::Value>| async { HttpResponse::Ok().finish() }),
+        ))
+        .await;
+
+        let request = |body: &str| {
+            TestRequest::post()
+                .uri("/")
+                .insert_header(("Content-Type", "application/json"))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+
+        let response = call_service(&app, request(r#"{"a": 1}"#)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = call_service(&app, request(r#"{"a": "larger than the limit"}"#)).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
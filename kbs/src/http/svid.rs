@@ -0,0 +1,69 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::Deserialize;
+
+use crate::spiffe::SvidIssuer;
+
+use super::*;
+
+/// Body of an SVID request.
+#[derive(Deserialize)]
+pub(crate) struct SvidRequest {
+    /// PEM CSR with the SPIFFE ID of the workload as its only URI SAN.
+    csr: String,
+}
+
+/// POST /svid
+/// POST /tenant/{tenant}/svid
+#[tracing::instrument(skip_all)]
+pub(crate) async fn svid(
+    request: HttpRequest,
+    body: web::Json<SvidRequest>,
+    issuer: web::Data<SvidIssuer>,
+    map: web::Data<SessionMap>,
+    token_verifier: web::Data<Reloadable<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>>,
+    reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    audit: web::Data<AuditLog>,
+    workload_identity: web::Data<WorkloadIdentity>,
+) -> Result<HttpResponse> {
+    let tenants = tenants.get();
+    let (mut spiffe_id, mut identity) = (None, None);
+    let result = async {
+        let tenant = tenants.of_request(&request)?;
+        let (claims, workload) = request_claims(
+            &request,
+            &map,
+            &token_verifier.get(),
+            reattestation_interval.get(),
+            &tenants,
+            tenant.as_ref().map(|tenant| tenant.id.as_str()),
+            &workload_identity,
+        )
+        .await?;
+        identity = workload;
+        let claims = serde_json::from_str(&claims).map_err(|e| {
+            Error::AttestationClaimsParseFailed(format!("illegal attestation claims: {e}"))
+        })?;
+
+        spiffe_id = issuer.spiffe_id(&claims).ok();
+        let svid = issuer
+            .mint_x509_svid(&claims, &body.csr)
+            .await
+            .map_err(|e| Error::SvidIssueFailed(format!("{e:#}")))?;
+        Ok(HttpResponse::Ok().json(svid))
+    }
+    .await;
+
+    let mut event = AuditEvent::new(AuditEventType::SvidIssuance, &request)
+        .actor(Actor::attester(identity))
+        .result(&result);
+    if let Some(spiffe_id) = spiffe_id {
+        event = event.detail("spiffe_id", spiffe_id);
+    }
+    audit.record(event).await;
+
+    result
+}
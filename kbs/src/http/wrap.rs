@@ -0,0 +1,471 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Resources wrapped to a key of the client's choice.
+//!
+//! Besides the TEE public key of its attestation, an attested client may ask
+//! for a resource wrapped to another public key, e.g. of an HSM or a service
+//! it hands the resource on to, or to a key encryption key stored in KBS. The
+//! resource is wrapped into a JWE in the flattened JSON serialization of
+//! [RFC 7516](https://www.rfc-editor.org/rfc/rfc7516), so that any JOSE
+//! library can unwrap it.
+//!
+//! That JWE is itself returned encrypted to the TEE public key, like any
+//! resource: a client replaying a stolen attestation token can't have the
+//! resource wrapped to a key of its own.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use kbs_types::Response;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use zeroize::Zeroizing;
+
+use crate::crypto::{self, EcCurve, AES_256_GCM_KEY_LENGTH, AES_256_GCM_NONCE_LENGTH};
+use crate::raise_error;
+
+use super::*;
+
+/// Content encryption of the wrapped resources.
+const CONTENT_ENCRYPTION: &str = "A256GCM";
+
+/// Length in bytes of the AES-256-GCM tag.
+const AES_256_GCM_TAG_LENGTH: usize = 16;
+
+/// Public JWK of an RSA key.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RsaJwk {
+    n: String,
+    e: String,
+}
+
+/// Public JWK of an EC key.
+#[derive(Debug, Deserialize)]
+pub(crate) struct EcJwk {
+    crv: String,
+    x: String,
+    y: String,
+}
+
+/// Body of a wrap request: the key to wrap the resource to, and how.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "alg")]
+pub(crate) enum WrapRequest {
+    /// Encrypt the content encryption key to an RSA key.
+    #[serde(rename = "RSA-OAEP-256")]
+    RsaOaep256 { jwk: RsaJwk },
+
+    /// Derive the content encryption key from an ECDH agreement with an EC
+    /// key of P-256 or P-384.
+    #[serde(rename = "ECDH-ES")]
+    EcdhEs { jwk: EcJwk },
+
+    /// Encrypt the content encryption key with the 32 bytes of the resource
+    /// at `kek`, `<repository>/<type>/<tag>`, which the resource policy must
+    /// allow to the client too.
+    #[serde(rename = "A256GCMKW")]
+    A256Gcmkw { kek: String },
+}
+
+impl WrapRequest {
+    fn alg(&self) -> &'static str {
+        match self {
+            WrapRequest::RsaOaep256 { .. } => "RSA-OAEP-256",
+            WrapRequest::EcdhEs { .. } => "ECDH-ES",
+            WrapRequest::A256Gcmkw { .. } => "A256GCMKW",
+        }
+    }
+}
+
+fn decode(name: &str, value: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|e| Error::InvalidRequest(format!("base64 decode `{name}` failed: {e}")))
+}
+
+/// The content encryption key and the JOSE header of a JWE to the key of
+/// `request`, and its encrypted key. `kek` is the key encryption key of an
+/// `A256GCMKW` request.
+fn wrap_key(
+    request: &WrapRequest,
+    kek: Option<&[u8]>,
+) -> Result<(Zeroizing<Vec<u8>>, Value, Vec<u8>)> {
+    let jwe_error = |e: anyhow::Error| Error::JWEFailed(format!("{e:#}"));
+    let mut header = json!({
+        "alg": request.alg(),
+        "enc": CONTENT_ENCRYPTION,
+    });
+
+    let random_cek = || {
+        let mut cek = Zeroizing::new(vec![0; AES_256_GCM_KEY_LENGTH]);
+        crypto::fill_random(&mut cek).map_err(jwe_error)?;
+        Ok::<_, Error>(cek)
+    };
+
+    match request {
+        WrapRequest::RsaOaep256 { jwk } => {
+            let cek = random_cek()?;
+            let encrypted_key =
+                crypto::rsa_oaep_sha256_encrypt(&decode("n", &jwk.n)?, &decode("e", &jwk.e)?, &cek)
+                    .map_err(jwe_error)?;
+            Ok((cek, header, encrypted_key))
+        }
+        WrapRequest::EcdhEs { jwk } => {
+            let curve = match jwk.crv.as_str() {
+                "P-256" => EcCurve::P256,
+                "P-384" => EcCurve::P384,
+                crv => raise_error!(Error::InvalidRequest(format!(
+                    "unsupported curve {crv}, supported curves: P-256, P-384"
+                ))),
+            };
+            let agreement =
+                crypto::ecdh_ephemeral(curve, &decode("x", &jwk.x)?, &decode("y", &jwk.y)?)
+                    .map_err(jwe_error)?;
+            header["epk"] = json!({
+                "kty": "EC",
+                "crv": jwk.crv,
+                "x": URL_SAFE_NO_PAD.encode(&agreement.x),
+                "y": URL_SAFE_NO_PAD.encode(&agreement.y),
+            });
+            let cek = concat_kdf(
+                &agreement.secret,
+                CONTENT_ENCRYPTION,
+                b"",
+                b"",
+                AES_256_GCM_KEY_LENGTH,
+            )
+            .map_err(jwe_error)?;
+            Ok((cek, header, Vec::new()))
+        }
+        WrapRequest::A256Gcmkw { .. } => {
+            let kek = kek.unwrap_or_default();
+            if kek.len() != AES_256_GCM_KEY_LENGTH {
+                raise_error!(Error::InvalidRequest(format!(
+                    "the key encryption key must be {AES_256_GCM_KEY_LENGTH} bytes"
+                )));
+            }
+            let cek = random_cek()?;
+            let mut iv = [0; AES_256_GCM_NONCE_LENGTH];
+            crypto::fill_random(&mut iv).map_err(jwe_error)?;
+            let mut sealed = crypto::aes_256_gcm_encrypt(kek, &iv, b"", &cek).map_err(jwe_error)?;
+            let tag = sealed.split_off(sealed.len() - AES_256_GCM_TAG_LENGTH);
+            header["iv"] = URL_SAFE_NO_PAD.encode(iv).into();
+            header["tag"] = URL_SAFE_NO_PAD.encode(tag).into();
+            Ok((cek, header, sealed))
+        }
+    }
+}
+
+/// The Concat KDF of RFC 7518, section 4.6.2, deriving a key of `length`
+/// bytes, at most 32, for `enc` from the shared secret `z` and the party
+/// information `apu` and `apv`.
+fn concat_kdf(
+    z: &[u8],
+    enc: &str,
+    apu: &[u8],
+    apv: &[u8],
+    length: usize,
+) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    if length > 32 {
+        anyhow::bail!("Concat KDF keys of more than a single SHA-256 round are not supported");
+    }
+    let mut input = Zeroizing::new(Vec::new());
+    input.extend_from_slice(&1u32.to_be_bytes());
+    input.extend_from_slice(z);
+    for info in [enc.as_bytes(), apu, apv] {
+        input.extend_from_slice(&(info.len() as u32).to_be_bytes());
+        input.extend_from_slice(info);
+    }
+    input.extend_from_slice(&((length * 8) as u32).to_be_bytes());
+    let mut key = Zeroizing::new(crypto::sha256(&input)?);
+    key.truncate(length);
+    Ok(key)
+}
+
+/// Wrap `resource` into a JWE to the key of `request`.
+fn wrap(request: &WrapRequest, kek: Option<&[u8]>, resource: &[u8]) -> Result<Response> {
+    let jwe_error = |e: anyhow::Error| Error::JWEFailed(format!("{e:#}"));
+    let (cek, header, encrypted_key) = wrap_key(request, kek)?;
+
+    let protected = URL_SAFE_NO_PAD.encode(header.to_string());
+    let mut iv = [0; AES_256_GCM_NONCE_LENGTH];
+    crypto::fill_random(&mut iv).map_err(jwe_error)?;
+    let mut ciphertext = crypto::aes_256_gcm_encrypt(&cek, &iv, protected.as_bytes(), resource)
+        .map_err(jwe_error)?;
+    let tag = ciphertext.split_off(ciphertext.len() - AES_256_GCM_TAG_LENGTH);
+
+    Ok(Response {
+        protected,
+        encrypted_key: URL_SAFE_NO_PAD.encode(encrypted_key),
+        iv: URL_SAFE_NO_PAD.encode(iv),
+        ciphertext: URL_SAFE_NO_PAD.encode(ciphertext),
+        tag: URL_SAFE_NO_PAD.encode(tag),
+    })
+}
+
+/// The resource at the path `<repository>/<type>/<tag>`.
+fn resource_at(path: &str) -> Result<ResourceDesc> {
+    ResourceDesc::from_path(path).ok_or_else(|| {
+        Error::InvalidRequest(format!(
+            "resource path {path} is not <repository>/<type>/<tag>"
+        ))
+    })
+}
+
+/// POST /wrap/{repository}/{type}/{tag}
+/// POST /wrap/{type}/{tag}
+#[tracing::instrument(skip_all)]
+pub(crate) async fn wrap_resource(
+    request: HttpRequest,
+    body: web::Json<WrapRequest>,
+    repository: web::Data<Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>>,
+    #[cfg(feature = "as")] map: web::Data<SessionMap>,
+    token_verifier: web::Data<Reloadable<Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>>>,
+    reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    #[cfg(feature = "policy")] policy_engine: web::Data<Reloadable<PolicyEngine>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    audit: web::Data<AuditLog>,
+    workload_identity: web::Data<WorkloadIdentity>,
+) -> Result<HttpResponse> {
+    let tenants = tenants.get();
+    let mut identity = None;
+    let result = async {
+        let tenant = tenants.of_request(&request)?;
+        let (claims, workload) = request_claims(
+            &request,
+            #[cfg(feature = "as")]
+            &map,
+            &token_verifier.get(),
+            reattestation_interval.get(),
+            &tenants,
+            tenant.as_ref().map(|tenant| tenant.id.as_str()),
+            &workload_identity,
+        )
+        .await?;
+        identity = workload;
+
+        let repository = match &tenant {
+            Some(tenant) => tenant.repository.clone(),
+            None => repository.get(),
+        };
+        #[cfg(feature = "policy")]
+        let policy_engine = match &tenant {
+            Some(tenant) => tenant.policy_engine.clone(),
+            None => policy_engine.get(),
+        };
+        let read = |resource_description| {
+            read_permitted_resource(
+                claims.clone(),
+                resource_description,
+                &repository,
+                #[cfg(feature = "policy")]
+                &policy_engine,
+            )
+        };
+
+        let pubkey = tee_pubkey(&claims)?;
+        let kek = match &*body {
+            WrapRequest::A256Gcmkw { kek } => Some(read(resource_at(kek)?).await?),
+            _ => None,
+        };
+        let resource = read(request_resource(&request)?).await?;
+        let wrapped = wrap(&body, kek.as_deref().map(|kek| &kek[..]), &resource)?;
+        let wrapped = serde_json::to_vec(&wrapped).map_err(|e| Error::JWEFailed(e.to_string()))?;
+        Ok(HttpResponse::Ok().json(jwe(pubkey, &wrapped)?))
+    }
+    .await;
+
+    RESOURCE_REQUESTS
+        .with_label_values(&[
+            repository_label(request.match_info().get("repository").unwrap_or("default")),
+            result_label(&result),
+        ])
+        .inc();
+
+    audit
+        .record(
+            AuditEvent::new(AuditEventType::ResourceAccess, &request)
+                .actor(Actor::attester(identity))
+                .detail("path", request.path())
+                .detail("alg", body.alg())
+                .result(&result),
+        )
+        .await;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        bn::{BigNum, BigNumContext},
+        derive::Deriver,
+        ec::{EcGroup, EcKey},
+        encrypt::Decrypter,
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        rsa::{Padding, Rsa},
+    };
+
+    /// Decrypt the content of `jwe` with `cek`.
+    fn open(jwe: &Response, cek: &[u8]) -> Vec<u8> {
+        let mut sealed = URL_SAFE_NO_PAD.decode(&jwe.ciphertext).unwrap();
+        sealed.extend(URL_SAFE_NO_PAD.decode(&jwe.tag).unwrap());
+        crypto::aes_256_gcm_decrypt(
+            cek,
+            &URL_SAFE_NO_PAD.decode(&jwe.iv).unwrap(),
+            jwe.protected.as_bytes(),
+            &sealed,
+        )
+        .unwrap()
+        .to_vec()
+    }
+
+    fn header(jwe: &Response) -> Value {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(&jwe.protected).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_concat_kdf() {
+        // ECDH-ES example of RFC 7518, appendix C.
+        let z = [
+            158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49,
+            110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196,
+        ];
+        let key = concat_kdf(&z, "A128GCM", b"Alice", b"Bob", 16).unwrap();
+        assert_eq!(URL_SAFE_NO_PAD.encode(&*key), "VqqN6vgjbSBcIijNcacQGg");
+        assert!(concat_kdf(&z, "A128GCM", b"", b"", 64).is_err());
+    }
+
+    #[test]
+    fn test_wrap_rsa_oaep_256() {
+        let key = Rsa::generate(2048).unwrap();
+        let request = WrapRequest::RsaOaep256 {
+            jwk: RsaJwk {
+                n: URL_SAFE_NO_PAD.encode(key.n().to_vec()),
+                e: URL_SAFE_NO_PAD.encode(key.e().to_vec()),
+            },
+        };
+        let jwe = wrap(&request, None, b"secret").unwrap();
+        assert_eq!(
+            header(&jwe),
+            json!({"alg": "RSA-OAEP-256", "enc": "A256GCM"})
+        );
+
+        let key = PKey::from_rsa(key).unwrap();
+        let mut decrypter = Decrypter::new(&key).unwrap();
+        decrypter.set_rsa_padding(Padding::PKCS1_OAEP).unwrap();
+        decrypter.set_rsa_oaep_md(MessageDigest::sha256()).unwrap();
+        decrypter.set_rsa_mgf1_md(MessageDigest::sha256()).unwrap();
+        let encrypted_key = URL_SAFE_NO_PAD.decode(&jwe.encrypted_key).unwrap();
+        let mut cek = vec![0; decrypter.decrypt_len(&encrypted_key).unwrap()];
+        let length = decrypter.decrypt(&encrypted_key, &mut cek).unwrap();
+        assert_eq!(open(&jwe, &cek[..length]), b"secret");
+    }
+
+    #[test]
+    fn test_wrap_ecdh_es() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        key.public_key()
+            .affine_coordinates(&group, &mut x, &mut y, &mut ctx)
+            .unwrap();
+        let request = WrapRequest::EcdhEs {
+            jwk: EcJwk {
+                crv: "P-256".into(),
+                x: URL_SAFE_NO_PAD.encode(x.to_vec_padded(32).unwrap()),
+                y: URL_SAFE_NO_PAD.encode(y.to_vec_padded(32).unwrap()),
+            },
+        };
+        let jwe = wrap(&request, None, b"secret").unwrap();
+        assert!(jwe.encrypted_key.is_empty());
+
+        let epk = &header(&jwe)["epk"];
+        assert_eq!(epk["crv"], "P-256");
+        let coordinate = |name: &str| {
+            let coordinate = URL_SAFE_NO_PAD.decode(epk[name].as_str().unwrap());
+            BigNum::from_slice(&coordinate.unwrap()).unwrap()
+        };
+        let epk =
+            EcKey::from_public_key_affine_coordinates(&group, &coordinate("x"), &coordinate("y"))
+                .unwrap();
+        let key = PKey::from_ec_key(key).unwrap();
+        let epk = PKey::from_ec_key(epk).unwrap();
+        let mut deriver = Deriver::new(&key).unwrap();
+        deriver.set_peer(&epk).unwrap();
+        let cek = concat_kdf(
+            &deriver.derive_to_vec().unwrap(),
+            CONTENT_ENCRYPTION,
+            b"",
+            b"",
+            AES_256_GCM_KEY_LENGTH,
+        )
+        .unwrap();
+        assert_eq!(open(&jwe, &cek), b"secret");
+
+        let request = WrapRequest::EcdhEs {
+            jwk: EcJwk {
+                crv: "P-521".into(),
+                x: String::new(),
+                y: String::new(),
+            },
+        };
+        assert!(matches!(
+            wrap(&request, None, b"secret"),
+            Err(Error::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_wrap_a256gcmkw() {
+        let request = WrapRequest::A256Gcmkw {
+            kek: "default/kek/a".into(),
+        };
+        let kek = [7; AES_256_GCM_KEY_LENGTH];
+        let jwe = wrap(&request, Some(&kek), b"secret").unwrap();
+
+        let header = header(&jwe);
+        assert_eq!(header["alg"], "A256GCMKW");
+        let mut sealed = URL_SAFE_NO_PAD.decode(&jwe.encrypted_key).unwrap();
+        sealed.extend(
+            URL_SAFE_NO_PAD
+                .decode(header["tag"].as_str().unwrap())
+                .unwrap(),
+        );
+        let iv = URL_SAFE_NO_PAD
+            .decode(header["iv"].as_str().unwrap())
+            .unwrap();
+        let cek = crypto::aes_256_gcm_decrypt(&kek, &iv, b"", &sealed).unwrap();
+        assert_eq!(open(&jwe, &cek), b"secret");
+
+        assert!(wrap(&request, Some(&kek[..16]), b"secret").is_err());
+    }
+
+    #[test]
+    fn test_keys_are_wiped() {
+        // The content encryption key and the key agreed by ECDH are wiped
+        // from memory when they are dropped.
+        fn wiped<T: zeroize::Zeroize>(_: &Zeroizing<T>) {}
+
+        let request = WrapRequest::A256Gcmkw {
+            kek: "default/kek/a".into(),
+        };
+        let (cek, _, _) = wrap_key(&request, Some(&[7; AES_256_GCM_KEY_LENGTH])).unwrap();
+        wiped(&cek);
+        wiped(&concat_kdf(&[1; 32], CONTENT_ENCRYPTION, b"", b"", 32).unwrap());
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request: WrapRequest =
+            serde_json::from_str(r#"{"alg": "A256GCMKW", "kek": "default/kek/a"}"#).unwrap();
+        assert_eq!(request.alg(), "A256GCMKW");
+        assert!(serde_json::from_str::<WrapRequest>(r#"{"alg": "RSA1_5", "jwk": {}}"#).is_err());
+        assert!(resource_at("default/kek").is_err());
+        assert_eq!(resource_at("default/kek/a").unwrap().resource_tag, "a");
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Canonical identity of the attested workloads.
+//!
+//! The identity of a workload is rendered from its attestation claims with a
+//! template, e.g. `{/tee}/{/tdx/quote/body/mr_td}`, and added to the claims as
+//! `workload_identity`. The audit records, the session listing and the
+//! resource policy all name a workload by it.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+#[cfg(feature = "resource")]
+use crate::resource::provision::ProvisioningConfig;
+
+/// Member of the attestation claims holding the identity of the workload.
+pub(crate) const WORKLOAD_IDENTITY_CLAIM: &str = "workload_identity";
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct WorkloadIdentityConfig {
+    /// Identity of the attested workloads. Every `{pointer}` is replaced by
+    /// the attestation claim at the JSON pointer, e.g.
+    /// `{/tee}/{/tdx/quote/body/mr_td}`.
+    pub template: String,
+
+    /// Resources created for every workload on its first attestation.
+    /// Nothing is provisioned when omitted.
+    #[cfg(feature = "resource")]
+    #[serde(default)]
+    pub provisioning: Option<ProvisioningConfig>,
+}
+
+/// Part of an identity template.
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+
+    /// JSON pointer of a claim.
+    Claim(String),
+}
+
+/// Renders the identity of the workloads from their attestation claims.
+/// Workloads have no identity without a template.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WorkloadIdentity {
+    template: Vec<Part>,
+}
+
+impl WorkloadIdentity {
+    pub fn new(config: Option<&WorkloadIdentityConfig>) -> Result<Self> {
+        let template = match config {
+            Some(config) => parse_template(&config.template)?,
+            None => Vec::new(),
+        };
+        Ok(Self { template })
+    }
+
+    /// The identity of the workload with the attestation claims `claims`,
+    /// unless a claim of the template is missing or isn't a string, number
+    /// or boolean.
+    pub fn of(&self, claims: &Value) -> Option<String> {
+        if self.template.is_empty() {
+            return None;
+        }
+        let mut identity = String::new();
+        for part in &self.template {
+            match part {
+                Part::Literal(literal) => identity.push_str(literal),
+                Part::Claim(pointer) => match claims.pointer(pointer)? {
+                    Value::String(s) => identity.push_str(s),
+                    value @ (Value::Number(_) | Value::Bool(_)) => {
+                        identity.push_str(&value.to_string())
+                    }
+                    _ => return None,
+                },
+            }
+        }
+        Some(identity)
+    }
+
+    /// Add the identity of the workload to its attestation claims `claims`,
+    /// and return it. An identity the claims carry already is replaced, or
+    /// removed when the workload has none.
+    pub fn annotate(&self, claims: &mut Value) -> Option<String> {
+        let identity = self.of(claims);
+        if let Value::Object(claims) = claims {
+            match &identity {
+                Some(identity) => {
+                    claims.insert(
+                        WORKLOAD_IDENTITY_CLAIM.to_string(),
+                        identity.as_str().into(),
+                    );
+                }
+                None => {
+                    claims.remove(WORKLOAD_IDENTITY_CLAIM);
+                }
+            }
+        }
+        identity
+    }
+
+    /// The attestation claims `claims_str` with the identity of the
+    /// workload, and the identity.
+    pub fn annotate_str(&self, claims_str: &str) -> Result<(String, Option<String>)> {
+        let mut claims: Value =
+            serde_json::from_str(claims_str).context("illegal attestation claims")?;
+        let identity = self.annotate(&mut claims);
+        Ok((claims.to_string(), identity))
+    }
+}
+
+/// The identity of the workload with the annotated attestation claims
+/// `claims`, if it has one.
+pub(crate) fn identity_of(claims: &Value) -> Option<String> {
+    claims
+        .get(WORKLOAD_IDENTITY_CLAIM)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+/// Split `template` into literals and claims.
+fn parse_template(template: &str) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("unclosed `{{` in identity template `{template}`"))?;
+        let pointer = &rest[start + 1..start + end];
+        if !pointer.starts_with('/') {
+            bail!("`{pointer}` of identity template `{template}` is not a JSON pointer");
+        }
+        if start > 0 {
+            parts.push(Part::Literal(rest[..start].to_string()));
+        }
+        parts.push(Part::Claim(pointer.to_string()));
+        rest = &rest[start + end + 1..];
+    }
+    if rest.contains('}') {
+        bail!("unopened `}}` in identity template `{template}`");
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest.to_string()));
+    }
+    if !parts.iter().any(|part| matches!(part, Part::Claim(_))) {
+        bail!("identity template `{template}` uses no claim");
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn identity(template: &str) -> WorkloadIdentity {
+        WorkloadIdentity::new(Some(&WorkloadIdentityConfig {
+            template: template.to_string(),
+            ..Default::default()
+        }))
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case("{/tee}", true)]
+    #[case("{/tee}/{/tdx/quote/body/mr_td}", true)]
+    #[case("image@{/init_data/image_digest}", true)]
+    #[case("", false)]
+    #[case("workload", false)]
+    #[case("{tee}", false)]
+    #[case("{/tee", false)]
+    #[case("tee}", false)]
+    fn test_parse_template(#[case] template: &str, #[case] ok: bool) {
+        assert_eq!(parse_template(template).is_ok(), ok, "{template}");
+    }
+
+    #[test]
+    fn test_identity() {
+        let identity = identity("{/tee}/{/tdx/quote/body/mr_td}-svn{/svn}");
+        let claims = json!({"tee": "tdx", "tdx": {"quote": {"body": {"mr_td": "abcd"}}}, "svn": 2});
+        assert_eq!(identity.of(&claims).unwrap(), "tdx/abcd-svn2");
+        assert!(identity.of(&json!({"tee": "tdx", "svn": 2})).is_none());
+        assert!(identity
+            .of(&json!({"tee": ["tdx"], "tdx": {"quote": {"body": {"mr_td": "abcd"}}}, "svn": 2}))
+            .is_none());
+
+        assert!(WorkloadIdentity::new(None).unwrap().of(&claims).is_none());
+    }
+
+    #[test]
+    fn test_annotate() {
+        let identity = identity("{/tee}");
+        let (annotated, snp) = identity.annotate_str(r#"{"tee": "snp"}"#).unwrap();
+        let annotated: Value = serde_json::from_str(&annotated).unwrap();
+        assert_eq!(identity_of(&annotated), snp);
+        assert_eq!(snp.unwrap(), "snp");
+
+        // The claims can't choose their own identity.
+        let mut claims = json!({"workload_identity": "forged"});
+        assert!(identity.annotate(&mut claims).is_none());
+        assert!(identity_of(&claims).is_none());
+    }
+}
@@ -18,26 +18,53 @@ extern crate rand;
 extern crate uuid;
 
 use actix_web::{middleware, web, App, HttpServer};
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Context, Result};
 #[cfg(feature = "as")]
-use attestation::AttestationService;
-use jwt_simple::prelude::Ed25519PublicKey;
+use attestation::{
+    challenge::{ChallengeConfig, Challenges, NonceChallenge},
+    AttestationService,
+};
+use audit::{AuditConfig, AuditLog, WebhookConfig};
+use auth::{load_admin_keys, AdminAllowlist, AdminKeyConfig};
+use cors::CorsConfig;
+use http::HttpServerConfig;
+use identity::{WorkloadIdentity, WorkloadIdentityConfig};
+use reload::{Reloadable, Reloader};
 #[cfg(feature = "resource")]
-use resource::RepositoryConfig;
-#[cfg(feature = "as")]
+use resource::{
+    download::{DownloadUrlConfig, DownloadUrls},
+    provision::Provisioner,
+    RepositoryConfig,
+};
 use std::sync::Arc;
 use std::{net::SocketAddr, path::PathBuf};
+use tenant::{TenantConfig, Tenants};
 #[cfg(feature = "resource")]
-use token::AttestationTokenVerifierConfig;
-
-#[cfg(feature = "rustls")]
-use rustls::ServerConfig;
+use token::{
+    exchange::{TokenExchangeConfig, TokenExchanger},
+    revocation::{check_revocations, RevokedTokens},
+    AttestationTokenVerifierConfig,
+};
 
-#[cfg(feature = "openssl")]
-use openssl::ssl::SslAcceptorBuilder;
+#[cfg(feature = "acme")]
+use acme::{Acme, AcmeConfig};
+#[cfg(feature = "spiffe")]
+use spiffe::{SpiffeConfig, SvidIssuer};
+use tls::{ClientAuthConfig, ClientAuthScope, TlsCredentials};
 
 #[cfg(feature = "as")]
-use crate::session::SessionMap;
+use crate::session::{DirNonceStore, MemoryNonceStore, NonceStore, SessionMap};
+#[cfg(feature = "as")]
+use actix_web::dev::ServerHandle;
+#[cfg(feature = "as")]
+use kbs_types::Tee;
+#[cfg(feature = "as")]
+use std::time::Duration;
+#[cfg(feature = "as")]
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    time::Instant,
+};
 
 #[cfg(feature = "policy")]
 use crate::policy_engine::{PolicyEngine, PolicyEngineConfig};
@@ -46,13 +73,35 @@ use crate::policy_engine::{PolicyEngine, PolicyEngineConfig};
 /// Attestation Service
 pub mod attestation;
 
+pub mod check;
+
 #[allow(unused_imports)]
 /// KBS config
 pub mod config;
 
+#[cfg(feature = "acme")]
+/// HTTPS certificate management over ACME
+pub mod acme;
+
+mod audit;
+pub use audit::{verify_audit_log, AuditLogReport};
 mod auth;
+#[cfg(any(feature = "as", feature = "policy"))]
+mod claims;
+mod cors;
+/// Cryptographic primitives, from RustCrypto or OpenSSL
+pub mod crypto;
+#[cfg(feature = "grpc-api")]
+mod grpc;
 #[allow(unused_imports)]
 mod http;
+mod identity;
+mod listener;
+/// Log output, plain or as JSON
+pub mod logging;
+#[cfg(any(feature = "resource", feature = "policy"))]
+mod namespace;
+mod reload;
 
 #[cfg(feature = "resource")]
 mod resource;
@@ -60,15 +109,29 @@ mod resource;
 #[cfg(feature = "as")]
 mod session;
 
+#[cfg(feature = "spiffe")]
+mod spiffe;
+
+mod tenant;
+
 #[cfg(feature = "resource")]
 mod token;
 
+mod tls;
+
 #[cfg(feature = "policy")]
 /// Resource Policy Engine
 pub mod policy_engine;
 
+#[cfg(feature = "opentelemetry")]
+/// OpenTelemetry trace export
+pub mod telemetry;
+
 static KBS_PREFIX: &str = "/kbs/v0";
 
+#[cfg(feature = "as")]
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 macro_rules! kbs_path {
     ($path:expr) => {
         format!("{}/{}", KBS_PREFIX, $path)
@@ -79,6 +142,9 @@ macro_rules! kbs_path {
 /// The KBS API server
 pub struct ApiServer {
     sockets: Vec<SocketAddr>,
+    unix_sockets: Vec<PathBuf>,
+    #[cfg(feature = "grpc-api")]
+    grpc_sockets: Vec<SocketAddr>,
     private_key: Option<PathBuf>,
     /// This user public key is used to verify the jwt.
     /// The jwt is carried with the POST request for
@@ -89,37 +155,111 @@ pub struct ApiServer {
 
     #[cfg(feature = "as")]
     attestation_service: Arc<AttestationService>,
+    #[cfg(feature = "as")]
+    challenge_config: ChallengeConfig,
 
     http_timeout: i64,
+    reattestation_interval: Option<i64>,
     insecure_api: bool,
     #[cfg(feature = "resource")]
     repository_config: RepositoryConfig,
     #[cfg(feature = "resource")]
     attestation_token_config: AttestationTokenVerifierConfig,
+    #[cfg(feature = "resource")]
+    download_url_config: Option<DownloadUrlConfig>,
+    #[cfg(feature = "resource")]
+    token_exchange_config: Option<TokenExchangeConfig>,
     #[cfg(feature = "policy")]
     policy_engine_config: PolicyEngineConfig,
+    audit_config: Option<AuditConfig>,
+    webhooks: Vec<WebhookConfig>,
+    /// Tenants served besides the default one.
+    tenants: Vec<TenantConfig>,
+    shutdown_timeout: u64,
+    http_server_config: HttpServerConfig,
+    client_auth_config: Option<ClientAuthConfig>,
+    cors_config: Option<CorsConfig>,
+    /// Additional public keys trusted to sign admin tokens.
+    admin_keys: Vec<AdminKeyConfig>,
+    /// Networks allowed to call the admin APIs.
+    admin_allowlist: AdminAllowlist,
+    /// Configuration file reloaded on SIGHUP and `POST /reload`.
+    config_file: Option<PathBuf>,
+    #[cfg(feature = "acme")]
+    acme_config: Option<AcmeConfig>,
+    #[cfg(feature = "spiffe")]
+    spiffe_config: Option<SpiffeConfig>,
+    /// Identity of the attested workloads, from their attestation claims.
+    workload_identity: WorkloadIdentity,
+    /// Provisions the resources of the workloads on their first attestation.
+    #[cfg(feature = "resource")]
+    provisioner: web::Data<Provisioner>,
 }
 
 impl ApiServer {
     /// Create a new KBS HTTP server
     pub fn new(
         sockets: Vec<SocketAddr>,
+        unix_sockets: Vec<PathBuf>,
+        #[cfg(feature = "grpc-api")] grpc_sockets: Vec<SocketAddr>,
         private_key: Option<PathBuf>,
         user_public_key: Option<PathBuf>,
         certificate: Option<PathBuf>,
         insecure: bool,
 
         #[cfg(feature = "as")] attestation_service: AttestationService,
+        #[cfg(feature = "as")] challenge_config: ChallengeConfig,
 
         http_timeout: i64,
+        reattestation_interval: Option<i64>,
         insecure_api: bool,
         #[cfg(feature = "resource")] repository_config: RepositoryConfig,
         #[cfg(feature = "resource")] attestation_token_config: AttestationTokenVerifierConfig,
+        #[cfg(feature = "resource")] download_url_config: Option<DownloadUrlConfig>,
+        #[cfg(feature = "resource")] token_exchange_config: Option<TokenExchangeConfig>,
         #[cfg(feature = "policy")] policy_engine_config: PolicyEngineConfig,
+        audit_config: Option<AuditConfig>,
+        webhooks: Vec<WebhookConfig>,
+        tenants: Vec<TenantConfig>,
+        shutdown_timeout: u64,
+        http_server_config: HttpServerConfig,
+        client_auth_config: Option<ClientAuthConfig>,
+        cors_config: Option<CorsConfig>,
+        admin_keys: Vec<AdminKeyConfig>,
+        admin_allowed_networks: Vec<String>,
+        config_file: Option<PathBuf>,
+        #[cfg(feature = "acme")] acme_config: Option<AcmeConfig>,
+        #[cfg(feature = "spiffe")] spiffe_config: Option<SpiffeConfig>,
+        workload_identity_config: Option<WorkloadIdentityConfig>,
     ) -> Result<Self> {
-        if !insecure && (private_key.is_none() || certificate.is_none()) {
+        #[allow(unused_mut)]
+        let mut has_credentials = private_key.is_some() && certificate.is_some();
+        #[cfg(feature = "acme")]
+        {
+            has_credentials |= acme_config.is_some();
+        }
+        if !insecure && !has_credentials {
             bail!("Missing HTTPS credentials");
         }
+        if insecure && client_auth_config.is_some() {
+            bail!("Client certificate authentication requires HTTPS");
+        }
+        #[cfg(feature = "as")]
+        if insecure && attestation_service.requires_channel_binding() {
+            bail!("TLS channel binding requires HTTPS");
+        }
+        if let Some(cors_config) = &cors_config {
+            cors_config.validate()?;
+        }
+        http::ReattestationInterval::validate(reattestation_interval)?;
+        let admin_allowlist = AdminAllowlist::new(&admin_allowed_networks)?;
+        let workload_identity = WorkloadIdentity::new(workload_identity_config.as_ref())?;
+        #[cfg(feature = "resource")]
+        let provisioner = web::Data::new(Provisioner::new(
+            workload_identity_config
+                .as_ref()
+                .and_then(|config| config.provisioning.as_ref()),
+        )?);
 
         cfg_if::cfg_if! {
             if #[cfg(not(any(feature = "as", feature = "resource")))] {
@@ -129,6 +269,9 @@ impl ApiServer {
 
         Ok(ApiServer {
             sockets,
+            unix_sockets,
+            #[cfg(feature = "grpc-api")]
+            grpc_sockets,
             private_key,
             user_public_key,
             certificate,
@@ -136,193 +279,896 @@ impl ApiServer {
 
             #[cfg(feature = "as")]
             attestation_service: Arc::new(attestation_service),
+            #[cfg(feature = "as")]
+            challenge_config,
 
             http_timeout,
+            reattestation_interval,
             insecure_api,
             #[cfg(feature = "resource")]
             repository_config,
             #[cfg(feature = "resource")]
             attestation_token_config,
+            #[cfg(feature = "resource")]
+            download_url_config,
+            #[cfg(feature = "resource")]
+            token_exchange_config,
             #[cfg(feature = "policy")]
             policy_engine_config,
+            audit_config,
+            webhooks,
+            tenants,
+            shutdown_timeout,
+            http_server_config,
+            client_auth_config,
+            cors_config,
+            admin_keys,
+            admin_allowlist,
+            config_file,
+            #[cfg(feature = "acme")]
+            acme_config,
+            #[cfg(feature = "spiffe")]
+            spiffe_config,
+            workload_identity,
+            #[cfg(feature = "resource")]
+            provisioner,
         })
     }
 
-    #[cfg(feature = "rustls")]
-    fn tls_config(&self) -> Result<ServerConfig> {
-        use rustls::{Certificate, PrivateKey};
-        use rustls_pemfile::{certs, read_one, Item};
-        use std::fs::File;
-        use std::io::BufReader;
-
-        let cert_file = &mut BufReader::new(File::open(
-            self.certificate
-                .clone()
-                .ok_or_else(|| anyhow!("Missing certificate"))?,
-        )?);
-
-        let key_file = &mut BufReader::new(File::open(
-            self.private_key
-                .clone()
-                .ok_or_else(|| anyhow!("Missing private key"))?,
-        )?);
-
-        let cert_chain = certs(cert_file)?
-            .iter()
-            .map(|c| Certificate(c.clone()))
-            .collect();
-
-        let key = match read_one(key_file)? {
-            Some(Item::RSAKey(key)) | Some(Item::PKCS8Key(key)) | Some(Item::ECKey(key)) => {
-                Ok(PrivateKey(key))
-            }
-            None | Some(_) => Err(anyhow!("Invalid private key file")),
-        }?;
-
-        ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(cert_chain, key)
-            .map_err(anyhow::Error::from)
-    }
-
-    #[cfg(feature = "openssl")]
-    fn tls_config(&self) -> Result<SslAcceptorBuilder> {
-        use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
-
-        let cert_file = self
-            .certificate
-            .clone()
-            .ok_or_else(|| anyhow!("Missing certificate"))?;
-
-        let key_file = self
-            .private_key
-            .clone()
-            .ok_or_else(|| anyhow!("Missing private key"))?;
-
-        let mut builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
-        builder.set_private_key_file(key_file, SslFiletype::PEM)?;
-        builder.set_certificate_chain_file(cert_file)?;
-
-        Ok(builder)
-    }
-
-    /// Start the HTTP server and serve API requests.
-    pub async fn serve(&self) -> Result<()> {
-        log::info!(
-            "Starting HTTP{} server at {:?}",
-            if !self.insecure { "S" } else { "" },
-            self.sockets
-        );
-
+    /// Initialize the state of the KBS API and start its background tasks,
+    /// the session pruning, the configuration reload, the token checks and
+    /// the resource rotation. The returned [`KbsService`] mounts the API in
+    /// any actix application, [`ApiServer::serve`] only adds the listeners
+    /// and the middleware around it.
+    pub async fn service(&self) -> Result<KbsService> {
         #[cfg(feature = "as")]
-        let (attestation_service, sessions) = {
+        let (attestation_service, challenges, sessions) = {
             let attestation_service = web::Data::new(self.attestation_service.clone());
-            let sessions = web::Data::new(SessionMap::new());
+
+            let mut challenges = Challenges::new(Arc::new(NonceChallenge::new(
+                self.challenge_config.clone(),
+            )?));
+            // The IBM SE challenge is built by the SE verifier.
+            challenges.register(Tee::Se, self.attestation_service.clone());
+            let challenges = web::Data::new(challenges);
+
+            let nonce_store: Arc<dyn NonceStore> = match &self.challenge_config.nonce_store_dir {
+                Some(dir) => Arc::new(DirNonceStore::new(dir)?),
+                None => Arc::new(MemoryNonceStore::default()),
+            };
+            let sessions = web::Data::new(SessionMap::with_nonce_store(nonce_store));
             let sessions_clone = sessions.clone();
 
             tokio::spawn(async move {
                 loop {
                     tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-                    sessions_clone
-                        .sessions
-                        .retain_async(|_, v| !v.is_expired())
-                        .await;
+                    sessions_clone.prune().await;
                 }
             });
-            (attestation_service, sessions)
+            (attestation_service, challenges, sessions)
         };
 
-        let http_timeout = self.http_timeout;
-
         #[cfg(feature = "resource")]
         let repository = self.repository_config.initialize()?;
 
         #[cfg(feature = "resource")]
-        let token_verifier =
-            crate::token::create_token_verifier(self.attestation_token_config.clone())?;
+        let (token_verifier, revoked_tokens) = {
+            let revoked_tokens = Arc::new(RevokedTokens::default());
+            let token_verifier = check_revocations(
+                crate::token::create_token_verifier(self.attestation_token_config.clone())?,
+                revoked_tokens.clone(),
+            );
+            let revoked_tokens_clone = revoked_tokens.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                    revoked_tokens_clone.prune().await;
+                }
+            });
+            (token_verifier, revoked_tokens)
+        };
+
+        #[cfg(feature = "resource")]
+        let download_urls = self
+            .download_url_config
+            .as_ref()
+            .map(DownloadUrls::new)
+            .transpose()?
+            .map(web::Data::new);
+
+        #[cfg(feature = "resource")]
+        let token_exchanger = self
+            .token_exchange_config
+            .as_ref()
+            .map(TokenExchanger::new)
+            .transpose()?
+            .map(web::Data::new);
 
         #[cfg(feature = "policy")]
         let policy_engine = PolicyEngine::new(&self.policy_engine_config).await?;
 
-        let user_public_key = match self.insecure_api {
-            true => None,
-            false => match &self.user_public_key {
-                Some(key_path) => {
-                    let user_public_key_pem = tokio::fs::read_to_string(key_path)
-                        .await
-                        .context("read user public key")?;
-                    let key = Ed25519PublicKey::from_pem(&user_public_key_pem)
-                        .context("parse user public key")?;
-                    Some(key)
-                }
-                None => bail!("no user public key given"),
-            },
-        };
+        #[cfg(feature = "spiffe")]
+        let svid_issuer = self
+            .spiffe_config
+            .as_ref()
+            .map(SvidIssuer::new)
+            .transpose()?
+            .map(web::Data::new);
+
+        let admin_keys = load_admin_keys(
+            self.insecure_api,
+            self.user_public_key.as_deref(),
+            &self.admin_keys,
+        )
+        .await?;
+
+        let tenants = Tenants::new(&self.tenants, self.insecure_api).await?;
+
+        // The handlers share the reloadable parts of the configuration with
+        // the reloader.
+        let reloader = web::Data::new(Reloader {
+            config_file: self.config_file.clone(),
+            insecure_api: self.insecure_api,
+            timeout: web::Data::new(Reloadable::new(self.http_timeout)),
+            reattestation_interval: web::Data::new(Reloadable::new(
+                http::ReattestationInterval::new(self.reattestation_interval),
+            )),
+            admin_keys: web::Data::new(Reloadable::new(Arc::new(admin_keys))),
+            #[cfg(feature = "resource")]
+            repository: web::Data::new(Reloadable::new(repository)),
+            #[cfg(feature = "resource")]
+            token_verifier: web::Data::new(Reloadable::new(token_verifier)),
+            #[cfg(feature = "resource")]
+            revoked_tokens,
+            #[cfg(feature = "policy")]
+            policy_engine: web::Data::new(Reloadable::new(policy_engine)),
+            tenants: web::Data::new(Reloadable::new(Arc::new(tenants))),
+        });
+        Reloader::watch(&reloader)?;
 
-        let insecure_api = self.insecure_api;
+        let audit =
+            web::Data::new(AuditLog::new(self.audit_config.as_ref(), &self.webhooks).await?);
+
+        #[cfg(feature = "as")]
+        if let Some(interval) = self.attestation_service.reattestation_check_interval() {
+            http::check_sessions_periodically(
+                sessions.clone(),
+                attestation_service.clone(),
+                reloader.tenants.clone(),
+                audit.clone(),
+                interval,
+            );
+        }
+
+        #[cfg(feature = "resource")]
+        let rotation = Arc::new(resource::rotation::spawn(
+            reloader.repository.clone(),
+            reloader.tenants.clone(),
+            audit.clone(),
+        ));
 
+        Ok(KbsService {
+            reloader,
+            audit,
+            workload_identity: web::Data::new(self.workload_identity.clone()),
+            insecure_api: self.insecure_api,
+            client_auth: self.client_auth_config.as_ref().map(|c| c.scope),
+            admin_allowlist: self.admin_allowlist.clone(),
+            json_config: self.http_server_config.json_config(),
+            payload_config: self.http_server_config.payload_config(),
+            #[cfg(feature = "as")]
+            attestation_service,
+            #[cfg(feature = "as")]
+            challenges,
+            #[cfg(feature = "as")]
+            sessions,
+            #[cfg(feature = "resource")]
+            upload_config: web::Data::new(self.http_server_config.upload_config()),
+            #[cfg(feature = "resource")]
+            provisioner: self.provisioner.clone(),
+            #[cfg(feature = "resource")]
+            rotation,
+            #[cfg(feature = "resource")]
+            download_urls,
+            #[cfg(feature = "resource")]
+            token_exchanger,
+            #[cfg(feature = "spiffe")]
+            svid_issuer,
+        })
+    }
+
+    /// Start the HTTP server and serve API requests.
+    pub async fn serve(&self) -> Result<()> {
+        // Sockets passed by systemd socket activation replace the configured
+        // TCP sockets.
+        let (tcp_listeners, unix_listeners) = listener::systemd_listeners()?;
+        if self.sockets.is_empty()
+            && self.unix_sockets.is_empty()
+            && tcp_listeners.is_empty()
+            && unix_listeners.is_empty()
+        {
+            bail!("No socket to listen on");
+        }
+        if tcp_listeners.is_empty() {
+            log::info!(
+                "Starting HTTP{} server at {:?}",
+                if !self.insecure { "S" } else { "" },
+                self.sockets
+            );
+        } else {
+            log::info!(
+                "Starting HTTP{} server at {} systemd TCP socket(s)",
+                if !self.insecure { "S" } else { "" },
+                tcp_listeners.len()
+            );
+        }
+        if !self.unix_sockets.is_empty() || !unix_listeners.is_empty() {
+            log::info!(
+                "Starting HTTP server at {:?} and {} systemd unix socket(s)",
+                self.unix_sockets,
+                unix_listeners.len()
+            );
+        }
+
+        let service = self.service().await?;
+
+        #[cfg(feature = "grpc-api")]
+        let grpc = grpc::KbsGrpc {
+            sessions: service.sessions.clone(),
+            timeout: service.reloader.timeout.clone(),
+            attestation_service: self.attestation_service.clone(),
+            challenges: service.challenges.clone(),
+            repository: service.reloader.repository.clone(),
+            token_verifier: service.reloader.token_verifier.clone(),
+            reattestation_interval: service.reloader.reattestation_interval.clone(),
+            #[cfg(feature = "policy")]
+            policy_engine: service.reloader.policy_engine.clone(),
+            tenants: service.reloader.tenants.clone(),
+            audit: service.audit.clone(),
+            workload_identity: service.workload_identity.clone(),
+            provisioner: self.provisioner.clone(),
+        };
+        #[cfg(feature = "grpc-api")]
+        let mut grpc_tls = None;
+
+        let cors_config = self.cors_config.clone();
+        let app_service = service.clone();
         let http_server = HttpServer::new(move || {
-            #[allow(unused_mut)]
-            let mut server_app = App::new()
+            App::new()
+                .wrap(cors::middleware(cors_config.as_ref()))
                 .wrap(middleware::Logger::default())
-                .app_data(web::Data::new(http_timeout))
-                .app_data(web::Data::new(user_public_key.clone()))
-                .app_data(web::Data::new(insecure_api));
-
-            cfg_if::cfg_if! {
-                if #[cfg(feature = "as")] {
-                    server_app = server_app.app_data(web::Data::clone(&sessions))
-                    .app_data(web::Data::clone(&attestation_service)).service(web::resource(kbs_path!("auth")).route(web::post().to(http::auth)))
-                    .service(web::resource(kbs_path!("attest")).route(web::post().to(http::attest)))
-                    .service(
-                        web::resource(kbs_path!("attestation-policy"))
-                            .route(web::post().to(http::attestation_policy)),
-                    );
-            }}
-            cfg_if::cfg_if! {
-                if #[cfg(feature = "resource")] {
-                    server_app = server_app.app_data(web::Data::new(repository.clone()))
-                    .app_data(web::Data::new(token_verifier.clone()))
-                    .service(
-                        web::resource([
-                            kbs_path!("resource/{repository}/{type}/{tag}"),
-                            kbs_path!("resource/{type}/{tag}"),
-                        ])
-                        .route(web::get().to(http::get_resource))
-                        .route(web::post().to(http::set_resource)),
-                    );
+                .wrap_fn(|request, service| {
+                    use actix_web::dev::Service;
+                    let correlation_id = audit::correlation_id(request.request());
+                    logging::with_correlation_id(Some(correlation_id), service.call(request))
+                })
+                .configure(|config| app_service.configure(config))
+        })
+        .shutdown_timeout(self.shutdown_timeout)
+        .client_request_timeout(self.http_server_config.request_timeout())
+        .client_disconnect_timeout(self.http_server_config.disconnect_timeout())
+        .keep_alive(self.http_server_config.keep_alive());
+
+        // Sessions are drained on shutdown before the server stops, so the
+        // signals are handled by KBS instead of actix.
+        #[cfg(feature = "as")]
+        let (http_server, sigterm) = (
+            http_server.disable_signals(),
+            signal(SignalKind::terminate()).context("install SIGTERM handler")?,
+        );
+
+        let mut http_server = if !self.insecure {
+            #[cfg(feature = "acme")]
+            let acme = match &self.acme_config {
+                Some(config) => {
+                    let acme = Acme::new(config.clone()).await?;
+                    acme.ensure_certificate().await?;
+                    Some(acme)
                 }
+                None => None,
+            };
+
+            #[allow(unused_mut)]
+            let mut certificate_and_key = (self.certificate.clone(), self.private_key.clone());
+            #[cfg(feature = "acme")]
+            if let Some(acme) = &acme {
+                certificate_and_key =
+                    (Some(acme.certificate_path()), Some(acme.private_key_path()));
             }
-            cfg_if::cfg_if! {
-                if #[cfg(feature = "policy")] {
-                    server_app = server_app.app_data(web::Data::new(policy_engine.clone()))
-                    .service(
-                        web::resource(kbs_path!("resource-policy")).route(web::post().to(http::resource_policy)),
-                    );
-                }
+
+            #[cfg(feature = "grpc-api")]
+            if let (Some(certificate), Some(private_key)) = &certificate_and_key {
+                grpc_tls = Some((certificate.clone(), private_key.clone()));
             }
-            server_app
-        });
 
-        if !self.insecure {
-            let tls_server = {
+            let credentials = match certificate_and_key {
+                (Some(certificate), Some(private_key)) => TlsCredentials::new(
+                    &certificate,
+                    &private_key,
+                    self.client_auth_config.clone(),
+                )?,
+                (None, _) => bail!("Missing certificate"),
+                (_, None) => bail!("Missing private key"),
+            };
+            credentials.watch()?;
+
+            #[cfg(feature = "acme")]
+            if let Some(acme) = acme {
+                acme.renew(credentials.clone());
+            }
+
+            let mut http_server = http_server.on_connect(|connection, extensions| {
+                tls::client_identity(connection, extensions);
+                tls::channel_binding(connection, extensions);
+            });
+            if tcp_listeners.is_empty() && !self.sockets.is_empty() {
                 cfg_if::cfg_if! {
                     if #[cfg(feature = "openssl")] {
-                        http_server.bind_openssl(&self.sockets[..], self.tls_config()?)?
+                        http_server = http_server.bind_openssl(&self.sockets[..], credentials.acceptor()?)?;
                     } else {
-                        http_server.bind_rustls(&self.sockets[..], self.tls_config()?)?
+                        http_server = http_server.bind_rustls(&self.sockets[..], credentials.server_config()?)?;
                     }
                 }
-            };
-
-            tls_server.run().await.map_err(anyhow::Error::from)
+            }
+            for listener in tcp_listeners {
+                cfg_if::cfg_if! {
+                    if #[cfg(feature = "openssl")] {
+                        http_server = http_server.listen_openssl(listener, credentials.acceptor()?)?;
+                    } else {
+                        http_server = http_server.listen_rustls(listener, credentials.server_config()?)?;
+                    }
+                }
+            }
+            http_server
         } else {
+            let mut http_server = http_server;
+            if tcp_listeners.is_empty() && !self.sockets.is_empty() {
+                http_server = http_server.bind(&self.sockets[..])?;
+            }
+            for listener in tcp_listeners {
+                http_server = http_server.listen(listener)?;
+            }
             http_server
-                .bind(&self.sockets[..])?
-                .run()
-                .await
-                .map_err(anyhow::Error::from)
+        };
+
+        // Unix domain sockets are served over plain HTTP, TLS is left to the
+        // co-located reverse proxy.
+        for path in &self.unix_sockets {
+            listener::remove_stale_socket(path)?;
+            http_server = http_server
+                .bind_uds(path)
+                .with_context(|| format!("bind {}", path.display()))?;
+        }
+        for listener in unix_listeners {
+            http_server = http_server.listen_uds(listener)?;
+        }
+        let server = http_server.run();
+
+        // The gRPC servers stop with the HTTP server, once its sessions are
+        // drained.
+        #[cfg(feature = "grpc-api")]
+        let (grpc_shutdown, grpc_servers) = {
+            let (grpc_shutdown, stopped) = tokio::sync::watch::channel(());
+            let grpc_servers = if self.grpc_sockets.is_empty() {
+                Vec::new()
+            } else {
+                grpc.serve(
+                    &self.grpc_sockets,
+                    grpc_tls,
+                    self.client_auth_config.as_ref(),
+                    stopped,
+                )
+                .await?
+            };
+            (grpc_shutdown, grpc_servers)
+        };
+
+        #[cfg(feature = "as")]
+        tokio::spawn(drain_on_signal(
+            sigterm,
+            server.handle(),
+            service.sessions.clone(),
+            Duration::from_secs(self.shutdown_timeout),
+        ));
+
+        let result = server.await.map_err(anyhow::Error::from);
+        #[cfg(feature = "grpc-api")]
+        {
+            let _ = grpc_shutdown.send(());
+            futures::future::join_all(grpc_servers).await;
+        }
+        // Sign the last records on shutdown.
+        service.shutdown().await;
+        result
+    }
+}
+
+/// The KBS API as an actix service, to mount in an existing actix
+/// application alongside its own endpoints and middleware:
+///
+/// ```ignore
+/// let service = api_server.service().await?;
+/// HttpServer::new(move || {
+///     let service = service.clone();
+///     App::new()
+///         .configure(|config| service.configure(config))
+///         .route("/status", web::get().to(status))
+/// })
+/// ```
+///
+/// The embedding application serves the connections, so the client
+/// certificate authentication and the TLS channel binding of
+/// [`ApiServer::serve`] aren't available, and neither are its CORS and
+/// logging middleware.
+#[derive(Clone)]
+pub struct KbsService {
+    reloader: web::Data<Reloader>,
+    audit: web::Data<AuditLog>,
+    workload_identity: web::Data<WorkloadIdentity>,
+    insecure_api: bool,
+    client_auth: Option<ClientAuthScope>,
+    admin_allowlist: AdminAllowlist,
+    json_config: web::JsonConfig,
+    payload_config: web::PayloadConfig,
+    #[cfg(feature = "as")]
+    attestation_service: web::Data<Arc<AttestationService>>,
+    #[cfg(feature = "as")]
+    challenges: web::Data<Challenges>,
+    #[cfg(feature = "as")]
+    sessions: web::Data<SessionMap>,
+    #[cfg(feature = "resource")]
+    upload_config: web::Data<http::UploadConfig>,
+    #[cfg(feature = "resource")]
+    provisioner: web::Data<Provisioner>,
+    #[cfg(feature = "resource")]
+    rotation: Arc<resource::rotation::Scheduler>,
+    #[cfg(feature = "resource")]
+    download_urls: Option<web::Data<DownloadUrls>>,
+    #[cfg(feature = "resource")]
+    token_exchanger: Option<web::Data<TokenExchanger>>,
+    #[cfg(feature = "spiffe")]
+    svid_issuer: Option<web::Data<SvidIssuer>>,
+}
+
+impl KbsService {
+    /// Register the shared state and the routes of the KBS API, under
+    /// `/kbs/v0` like the health probes and the metrics are at the root.
+    pub fn configure(&self, config: &mut web::ServiceConfig) {
+        config
+            .app_data(self.json_config.clone())
+            .app_data(self.payload_config.clone())
+            .app_data(web::Data::clone(&self.reloader.timeout))
+            .app_data(web::Data::clone(&self.reloader.reattestation_interval))
+            .app_data(web::Data::clone(&self.reloader.admin_keys))
+            .app_data(web::Data::clone(&self.reloader.tenants))
+            .app_data(web::Data::new(self.insecure_api))
+            .app_data(web::Data::new(self.client_auth))
+            .app_data(web::Data::new(self.admin_allowlist.clone()))
+            .app_data(web::Data::clone(&self.audit))
+            .app_data(web::Data::clone(&self.workload_identity))
+            .app_data(web::Data::clone(&self.reloader));
+        #[cfg(feature = "as")]
+        config
+            .app_data(web::Data::clone(&self.sessions))
+            .app_data(web::Data::clone(&self.attestation_service))
+            .app_data(web::Data::clone(&self.challenges));
+        #[cfg(feature = "resource")]
+        config
+            .app_data(web::Data::clone(&self.reloader.repository))
+            .app_data(web::Data::clone(&self.reloader.token_verifier))
+            .app_data(web::Data::from(self.reloader.revoked_tokens.clone()))
+            .app_data(web::Data::clone(&self.upload_config))
+            .app_data(web::Data::clone(&self.provisioner));
+        #[cfg(feature = "policy")]
+        config.app_data(web::Data::clone(&self.reloader.policy_engine));
+        #[cfg(feature = "resource")]
+        if let Some(download_urls) = &self.download_urls {
+            config.app_data(web::Data::clone(download_urls));
+        }
+        #[cfg(feature = "resource")]
+        if let Some(token_exchanger) = &self.token_exchanger {
+            config.app_data(web::Data::clone(token_exchanger));
+        }
+        #[cfg(feature = "spiffe")]
+        if let Some(svid_issuer) = &self.svid_issuer {
+            config.app_data(web::Data::clone(svid_issuer));
+        }
+
+        register_routes(
+            config,
+            OptionalRoutes {
+                #[cfg(feature = "resource")]
+                download_url: self.download_urls.is_some(),
+                #[cfg(feature = "resource")]
+                token_exchange: self.token_exchanger.is_some(),
+                #[cfg(feature = "spiffe")]
+                svid: self.svid_issuer.is_some(),
+            },
+        );
+    }
+
+    /// Stop accepting new attestation sessions, e.g. while the embedding
+    /// application drains before a shutdown.
+    #[cfg(feature = "as")]
+    pub fn drain(&self) {
+        self.sessions.drain();
+    }
+
+    /// Stop the scheduled resource rotations, then sign the last records of
+    /// the audit log and wait until they are written, once the embedding
+    /// application has stopped serving requests.
+    pub async fn shutdown(&self) {
+        #[cfg(feature = "resource")]
+        self.rotation.stop().await;
+        self.audit.checkpoint().await;
+        self.audit.flush().await;
+    }
+}
+
+/// The routes registered only if their feature is configured.
+#[derive(Clone, Copy)]
+pub(crate) struct OptionalRoutes {
+    #[cfg(feature = "resource")]
+    pub download_url: bool,
+    #[cfg(feature = "resource")]
+    pub token_exchange: bool,
+    #[cfg(feature = "spiffe")]
+    pub svid: bool,
+}
+
+/// Register the routes of the KBS API, i.e. the `ROUTES` of `kbs-openapi`
+/// the OpenAPI document is generated from.
+#[cfg_attr(
+    not(any(feature = "resource", feature = "spiffe")),
+    allow(unused_variables)
+)]
+pub(crate) fn register_routes(config: &mut web::ServiceConfig, optional: OptionalRoutes) {
+    config
+        .service(web::resource(kbs_path!("reload")).route(web::post().to(http::reload)))
+        .service(
+            web::resource(kbs_path!("audit/verify")).route(web::get().to(http::verify_audit_log)),
+        )
+        .service(web::resource(kbs_path!("openapi.json")).route(web::get().to(http::openapi)))
+        .service(web::resource("/healthz").route(web::get().to(http::healthz)))
+        .service(web::resource("/readyz").route(web::get().to(http::readyz)))
+        .service(web::resource("/metrics").route(web::get().to(http::metrics)));
+
+    #[cfg(feature = "as")]
+    config
+        .service(
+            web::resource([kbs_path!("auth"), kbs_path!("tenant/{tenant}/auth")])
+                .route(web::post().to(http::auth)),
+        )
+        .service(
+            web::resource([kbs_path!("attest"), kbs_path!("tenant/{tenant}/attest")])
+                .route(web::post().to(http::attest)),
+        )
+        .service(
+            web::resource([
+                kbs_path!("attestation-policy"),
+                kbs_path!("tenant/{tenant}/attestation-policy"),
+            ])
+            .route(web::get().to(http::list_attestation_policies))
+            .route(web::post().to(http::attestation_policy)),
+        )
+        .service(
+            web::resource([
+                kbs_path!("attestation-policy/{policy_id}"),
+                kbs_path!("tenant/{tenant}/attestation-policy/{policy_id}"),
+            ])
+            .route(web::get().to(http::get_attestation_policy))
+            .route(web::delete().to(http::remove_attestation_policy)),
+        )
+        .service(
+            web::resource([kbs_path!("verify"), kbs_path!("tenant/{tenant}/verify")])
+                .route(web::post().to(http::verify_evidence)),
+        )
+        .service(
+            web::resource([
+                kbs_path!("reattestation-check"),
+                kbs_path!("tenant/{tenant}/reattestation-check"),
+            ])
+            .route(web::post().to(http::reattestation_check)),
+        )
+        .service(
+            web::resource([kbs_path!("sessions"), kbs_path!("tenant/{tenant}/sessions")])
+                .route(web::get().to(http::list_sessions)),
+        )
+        .service(
+            web::resource([
+                kbs_path!("sessions/{session_handle}"),
+                kbs_path!("tenant/{tenant}/sessions/{session_handle}"),
+            ])
+            .route(web::delete().to(http::terminate_session)),
+        )
+        .service(
+            web::resource(kbs_path!("policy-captures/{request_id}"))
+                .route(web::get().to(http::get_policy_capture)),
+        )
+        .service(web::resource(kbs_path!("drift")).route(web::get().to(http::get_drift)))
+        .service(
+            web::resource(kbs_path!("drift/baseline"))
+                .route(web::post().to(http::set_drift_baseline)),
+        )
+        .service(
+            web::resource("/.well-known/openid-configuration")
+                .route(web::get().to(http::openid_configuration)),
+        )
+        .service(web::resource("/.well-known/jwks.json").route(web::get().to(http::jwks)));
+
+    #[cfg(feature = "resource")]
+    config
+        .service(
+            web::resource([
+                kbs_path!("resource/{repository:.+}/{type}/{tag}"),
+                kbs_path!("resource/{type}/{tag}"),
+                kbs_path!("tenant/{tenant}/resource/{repository:.+}/{type}/{tag}"),
+                kbs_path!("tenant/{tenant}/resource/{type}/{tag}"),
+            ])
+            .route(web::get().to(http::get_resource))
+            .route(web::post().to(http::set_resource)),
+        )
+        .service(
+            web::resource([
+                kbs_path!("wrap/{repository:.+}/{type}/{tag}"),
+                kbs_path!("wrap/{type}/{tag}"),
+                kbs_path!("tenant/{tenant}/wrap/{repository:.+}/{type}/{tag}"),
+                kbs_path!("tenant/{tenant}/wrap/{type}/{tag}"),
+            ])
+            .route(web::post().to(http::wrap_resource)),
+        )
+        .service(
+            web::resource([
+                kbs_path!("repository-mirror-check"),
+                kbs_path!("tenant/{tenant}/repository-mirror-check"),
+            ])
+            .route(web::post().to(http::repository_mirror_check)),
+        )
+        .service(
+            web::resource([
+                kbs_path!("repository-usage"),
+                kbs_path!("tenant/{tenant}/repository-usage"),
+            ])
+            .route(web::get().to(http::repository_usage)),
+        )
+        .service(web::resource(kbs_path!("introspect")).route(web::post().to(http::introspect)))
+        .service(
+            web::resource(kbs_path!("token-revocations")).route(web::post().to(http::revoke_token)),
+        );
+
+    #[cfg(feature = "policy")]
+    config
+        .service(
+            web::resource([
+                kbs_path!("resource-policy"),
+                kbs_path!("tenant/{tenant}/resource-policy"),
+            ])
+            .route(web::get().to(http::get_resource_policy))
+            .route(web::post().to(http::resource_policy)),
+        )
+        .service(
+            web::resource([
+                kbs_path!("required-policies"),
+                kbs_path!("tenant/{tenant}/required-policies"),
+            ])
+            .route(web::get().to(http::get_required_policies))
+            .route(web::post().to(http::set_required_policies)),
+        );
+
+    #[cfg(feature = "resource")]
+    if optional.download_url {
+        config
+            .service(
+                web::resource([
+                    kbs_path!("download-url/{repository:.+}/{type}/{tag}"),
+                    kbs_path!("download-url/{type}/{tag}"),
+                    kbs_path!("tenant/{tenant}/download-url/{repository:.+}/{type}/{tag}"),
+                    kbs_path!("tenant/{tenant}/download-url/{type}/{tag}"),
+                ])
+                .route(web::post().to(http::download_url)),
+            )
+            .service(
+                web::resource(kbs_path!("download/{capability}"))
+                    .route(web::get().to(http::download)),
+            );
+    }
+    #[cfg(feature = "resource")]
+    if optional.token_exchange {
+        config
+            .service(
+                web::resource([
+                    kbs_path!("token-exchange"),
+                    kbs_path!("tenant/{tenant}/token-exchange"),
+                ])
+                .route(web::post().to(http::token_exchange)),
+            )
+            .service(
+                web::resource(kbs_path!("token-exchange/jwks"))
+                    .route(web::get().to(http::token_exchange_jwks)),
+            );
+    }
+    #[cfg(feature = "spiffe")]
+    if optional.svid {
+        config.service(
+            web::resource([kbs_path!("svid"), kbs_path!("tenant/{tenant}/svid")])
+                .route(web::post().to(http::svid)),
+        );
+    }
+}
+
+/// Wait for SIGTERM or SIGINT, then refuse new sessions and give the pending
+/// attestation handshakes up to `timeout` to complete before stopping the
+/// server. Requests still in flight then get another `timeout` to finish.
+#[cfg(feature = "as")]
+async fn drain_on_signal(
+    mut sigterm: Signal,
+    server: ServerHandle,
+    sessions: web::Data<SessionMap>,
+    timeout: Duration,
+) {
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+
+    log::info!(
+        "Shutting down, waiting up to {}s for pending attestations",
+        timeout.as_secs()
+    );
+    sessions.drain();
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let pending = sessions.pending_handshakes().await;
+        if pending == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            log::warn!("Shutdown deadline reached with {pending} pending attestations");
+            break;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    server.stop(true).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::HttpResponse;
+    use jwt_simple::prelude::Ed25519KeyPair;
+
+    #[cfg(feature = "as")]
+    struct Unattested;
+
+    #[cfg(feature = "as")]
+    #[async_trait::async_trait]
+    impl attestation::Attest for Unattested {
+        async fn verify(
+            &self,
+            _: Tee,
+            _: &str,
+            _: &str,
+            _: &str,
+            _: Option<&str>,
+            _: Option<&[u8]>,
+            _: Option<&str>,
+        ) -> Result<attestation::Verdict> {
+            bail!("unused")
+        }
+    }
+
+    /// Every route of `kbs-openapi` of the configured features responds once
+    /// [`KbsService`] is mounted in an application, with the state its
+    /// handler extracts.
+    #[actix_web::test]
+    async fn test_service() {
+        let dir = tempfile::tempdir().unwrap();
+        let user_public_key = dir.path().join("admin.pub");
+        std::fs::write(
+            &user_public_key,
+            Ed25519KeyPair::generate().public_key().to_pem(),
+        )
+        .unwrap();
+
+        let server = ApiServer::new(
+            Vec::new(),
+            Vec::new(),
+            #[cfg(feature = "grpc-api")]
+            Vec::new(),
+            None,
+            Some(user_public_key),
+            None,
+            true,
+            #[cfg(feature = "as")]
+            AttestationService::from_backend(Arc::new(Unattested)),
+            #[cfg(feature = "as")]
+            ChallengeConfig::default(),
+            5,
+            None,
+            false,
+            #[cfg(feature = "resource")]
+            serde_json::from_value(serde_json::json!({
+                "type": "LocalFs",
+                "dir_path": dir.path().join("repository"),
+            }))
+            .unwrap(),
+            #[cfg(feature = "resource")]
+            AttestationTokenVerifierConfig::default(),
+            #[cfg(feature = "resource")]
+            Some(serde_json::from_value(serde_json::json!({})).unwrap()),
+            #[cfg(feature = "resource")]
+            Some(TokenExchangeConfig {
+                signing_key_path: None,
+                issuer: "kbs".into(),
+                max_ttl: 60,
+                audiences: vec!["storage".into()],
+            }),
+            #[cfg(feature = "policy")]
+            PolicyEngineConfig {
+                policy_path: Some(dir.path().join("policy.rego")),
+                ..Default::default()
+            },
+            None,
+            Vec::new(),
+            Vec::new(),
+            5,
+            HttpServerConfig::default(),
+            None,
+            None,
+            Vec::new(),
+            Vec::new(),
+            None,
+            #[cfg(feature = "acme")]
+            None,
+            #[cfg(feature = "spiffe")]
+            None,
+            None,
+        )
+        .unwrap();
+        let service = server.service().await.unwrap();
+        let app = init_service(
+            App::new()
+                .configure(|config| service.configure(config))
+                .default_service(web::to(|| async { HttpResponse::ImATeapot().finish() })),
+        )
+        .await;
+
+        // SVIDs aren't configured.
+        let routes = kbs_openapi::routes::enabled(|feature| match feature {
+            "as" => cfg!(feature = "as"),
+            "resource" => cfg!(feature = "resource"),
+            "policy" => cfg!(feature = "policy"),
+            _ => false,
+        });
+        for route in routes {
+            let uri = route
+                .path
+                .split('/')
+                .map(|segment| match segment.starts_with('{') {
+                    true => "x",
+                    false => segment,
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            let method = Method::from_bytes(route.method.to_uppercase().as_bytes()).unwrap();
+            let response = call_service(
+                &app,
+                TestRequest::default().method(method).uri(&uri).to_request(),
+            )
+            .await;
+            // Unrouted requests are answered by the default service, and the
+            // handlers missing their state with an internal error.
+            assert_ne!(response.status(), StatusCode::IM_A_TEAPOT, "{uri}");
+            assert_ne!(
+                response.status(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "{uri}"
+            );
         }
+        service.shutdown().await;
     }
 }
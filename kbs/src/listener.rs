@@ -0,0 +1,95 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Listening sockets of the KBS HTTP server besides the configured TCP
+//! sockets: unix domain sockets and sockets passed by systemd socket
+//! activation.
+
+use anyhow::{Context, Result};
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+/// First file descriptor passed by systemd, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Take the TCP and unix domain sockets systemd passed to KBS through
+/// `LISTEN_FDS`. Returns no sockets when KBS is not socket activated.
+pub(crate) fn systemd_listeners() -> Result<(Vec<TcpListener>, Vec<UnixListener>)> {
+    let mut tcp = Vec::new();
+    let mut unix = Vec::new();
+
+    let pid = match std::env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(_) => return Ok((tcp, unix)),
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok((tcp, unix));
+    }
+
+    let fds = std::env::var("LISTEN_FDS")
+        .context("LISTEN_FDS not set")?
+        .parse::<RawFd>()
+        .context("invalid LISTEN_FDS")?;
+
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds {
+        // SAFETY: systemd passes the listening sockets as the file
+        // descriptors following stderr and nothing else owns them.
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        if listener.local_addr().is_ok() {
+            tcp.push(listener);
+        } else {
+            // Not an IP socket, so a unix domain socket. The descriptor is
+            // moved over to the new listener.
+            unix.push(unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) });
+        }
+    }
+
+    Ok((tcp, unix))
+}
+
+/// Remove a socket file left behind by a previous KBS instance, so that
+/// `path` can be bound again.
+pub(crate) fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("remove stale socket {}", path.display())),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("kbs.sock");
+        drop(UnixListener::bind(&socket).unwrap());
+        assert!(UnixListener::bind(&socket).is_err());
+
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+        UnixListener::bind(&socket).unwrap();
+
+        // Only sockets are removed.
+        let file = dir.path().join("kbs.conf");
+        std::fs::write(&file, "").unwrap();
+        remove_stale_socket(&file).unwrap();
+        assert!(file.exists());
+        remove_stale_socket(&dir.path().join("missing.sock")).unwrap();
+    }
+
+    #[test]
+    fn test_not_socket_activated() {
+        // The sockets of a test runner started by systemd aren't passed on.
+        let (tcp, unix) = systemd_listeners().unwrap();
+        assert!(tcp.is_empty());
+        assert!(unix.is_empty());
+    }
+}
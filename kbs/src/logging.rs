@@ -0,0 +1,20 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Log output of KBS.
+//!
+//! Log records are written as text, or as one JSON object per line for log
+//! aggregation. The records written while serving a request carry the
+//! correlation ID of its audit records, which is passed on to the
+//! Attestation Service as well, so that the logs of one request can be
+//! followed across the services. The implementation is shared with the
+//! Attestation Service, so that a built-in one logs with the correlation ID
+//! of the KBS request.
+
+pub use correlation_log::{init, LogFormat};
+
+pub(crate) use correlation_log::{correlation_id, with_correlation_id};
+
+#[cfg(any(feature = "grpc-api", feature = "coco-as-grpc"))]
+pub(crate) use correlation_log::REQUEST_ID_KEY;
@@ -0,0 +1,144 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Nested namespaces of the resources.
+//!
+//! A resource path is `<namespace>/<type>/<tag>`, where the namespace, i.e.
+//! the `<repository>` of the resource, has one or more segments, e.g.
+//! `tenant-a/team-x/key/db-password`. The rules applying to resources, like
+//! the required policies, the generators and the write hooks, select them
+//! with patterns:
+//! - `<namespace>/<type>/<tag>` selects the resources at that path, a
+//!   segment of `*` matching any segment. A namespace of `*` alone, as in
+//!   `*/*/*`, matches any namespace, nested or not, as it did before the
+//!   namespaces were nested.
+//! - `<namespace>/`, ending with a `/`, selects every resource of the
+//!   namespace and of the namespaces beneath it, so that a rule on
+//!   `tenant-a/` is inherited by `tenant-a/team-x/key/db-password`.
+
+use std::iter;
+
+/// Whether `namespace` is a namespace of resources, i.e. has no empty
+/// segment nor segment starting with a `.`, which are reserved for the
+/// repositories, e.g. `.download-urls`.
+pub(crate) fn is_valid_namespace(namespace: &str) -> bool {
+    namespace
+        .split('/')
+        .all(|segment| !segment.is_empty() && !segment.starts_with('.'))
+}
+
+/// The namespace, type and tag of the resource at `resource_path`.
+pub(crate) fn split_path(resource_path: &str) -> Option<(&str, &str, &str)> {
+    let mut segments = resource_path.rsplitn(3, '/');
+    let tag = segments.next()?;
+    let resource_type = segments.next()?;
+    let namespace = segments.next()?;
+    Some((namespace, resource_type, tag))
+}
+
+/// `namespace` and the namespaces enclosing it, outermost first, e.g. `a`
+/// and `a/b` for `a/b`.
+pub(crate) fn ancestors(namespace: &str) -> Vec<&str> {
+    namespace
+        .match_indices('/')
+        .map(|(end, _)| &namespace[..end])
+        .chain(iter::once(namespace))
+        .collect()
+}
+
+/// Whether `pattern` is a pattern of resource paths.
+pub(crate) fn is_valid_pattern(pattern: &str) -> bool {
+    match pattern.strip_suffix('/') {
+        Some(namespace) => namespace.split('/').all(|segment| !segment.is_empty()),
+        None => {
+            pattern.split('/').count() >= 3 && pattern.split('/').all(|segment| !segment.is_empty())
+        }
+    }
+}
+
+/// Whether `pattern` selects the resource at `resource_path`.
+pub(crate) fn matches(pattern: &str, resource_path: &str) -> bool {
+    let segment_matches = |(pattern, segment): (&str, &str)| pattern == "*" || pattern == segment;
+    match pattern.strip_suffix('/') {
+        Some(pattern) => {
+            let Some((namespace, _, _)) = split_path(resource_path) else {
+                return false;
+            };
+            pattern.split('/').count() <= namespace.split('/').count()
+                && pattern
+                    .split('/')
+                    .zip(namespace.split('/'))
+                    .all(segment_matches)
+        }
+        None if pattern.split('/').count() == 3 && pattern.starts_with("*/") => {
+            let Some((_, resource_type, tag)) = split_path(resource_path) else {
+                return false;
+            };
+            pattern
+                .split('/')
+                .skip(1)
+                .zip([resource_type, tag])
+                .all(segment_matches)
+        }
+        None => {
+            pattern.split('/').count() == resource_path.split('/').count()
+                && pattern
+                    .split('/')
+                    .zip(resource_path.split('/'))
+                    .all(segment_matches)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("default/key/*", "default/key/a", true)]
+    #[case("default/key/*", "default/cert/a", false)]
+    #[case("default/key/*", "default/team/key/a", false)]
+    #[case("*/key/a", "tenant/key/a", true)]
+    #[case("*/key/a", "tenant/team/key/a", true)]
+    #[case("*/*/*", "prod/x/key/1", true)]
+    #[case("*/key/*", "a/b/cert/c", false)]
+    #[case("*/b/key/*", "a/b/key/c", true)]
+    #[case("*/b/key/*", "a/x/b/key/c", false)]
+    #[case("a/b/key/*", "a/b/key/c", true)]
+    #[case("a/", "a/key/c", true)]
+    #[case("a/", "a/b/c/key/d", true)]
+    #[case("a/", "ab/key/c", false)]
+    #[case("a/b/", "a/key/c", false)]
+    #[case("*/b/", "a/b/key/c", true)]
+    fn test_matches(#[case] pattern: &str, #[case] resource_path: &str, #[case] expected: bool) {
+        assert_eq!(matches(pattern, resource_path), expected);
+    }
+
+    #[rstest]
+    #[case("default/key/*", true)]
+    #[case("a/b/c/key/*", true)]
+    #[case("a/", true)]
+    #[case("a/b/", true)]
+    #[case("default/key", false)]
+    #[case("a//key/b", false)]
+    #[case("/", false)]
+    #[case("a//", false)]
+    fn test_is_valid_pattern(#[case] pattern: &str, #[case] expected: bool) {
+        assert_eq!(is_valid_pattern(pattern), expected);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        assert_eq!(split_path("a/b/key/1"), Some(("a/b", "key", "1")));
+        assert_eq!(split_path("key/1"), None);
+        assert_eq!(ancestors("a/b/c"), ["a", "a/b", "a/b/c"]);
+        assert_eq!(ancestors("default"), ["default"]);
+        assert!(is_valid_namespace("a/b"));
+        assert!(!is_valid_namespace("a/../b"));
+        assert!(!is_valid_namespace("a/"));
+        assert!(!is_valid_namespace(".download-urls"));
+        assert!(!is_valid_namespace("a/.b"));
+    }
+}
@@ -8,10 +8,13 @@ use serde::Deserialize;
 use std::path::PathBuf;
 use std::sync::Arc;
 use thiserror::Error;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 
 #[cfg(feature = "opa")]
 mod opa;
+mod required;
+
+pub use required::{PolicyBinding, RequiredPolicies};
 
 const DEFAULT_POLICY_PATH: &str = "/opa/confidential-containers/kbs/policy.rego";
 
@@ -37,6 +40,9 @@ pub enum ResourcePolicyError {
 
     #[error("Failed to load resource policy")]
     PolicyLoadError,
+
+    #[error("Invalid policy binding: {0}")]
+    BindingError(String),
 }
 
 /// Resource policy engine interface
@@ -59,6 +65,9 @@ pub(crate) trait PolicyEngineInterface: Send + Sync {
 
     /// Set policy (Base64 encode)
     async fn set_policy(&mut self, policy: String) -> Result<(), ResourcePolicyError>;
+
+    /// Get the current policy (Base64 encode)
+    async fn get_policy(&self) -> Result<String, ResourcePolicyError>;
 }
 
 /// Policy engine configuration.
@@ -67,24 +76,36 @@ pub struct PolicyEngineConfig {
     /// Path to a file containing a policy for evaluating whether the TCB status has access to
     /// specific resources.
     pub policy_path: Option<PathBuf>,
+
+    /// Attestation policies the evidence must have passed for the release of
+    /// specific resources.
+    #[serde(default)]
+    pub required_policies: Vec<PolicyBinding>,
 }
 
 impl Default for PolicyEngineConfig {
     fn default() -> Self {
         Self {
             policy_path: Some(PathBuf::from(DEFAULT_POLICY_PATH)),
+            required_policies: Vec::new(),
         }
     }
 }
 
-/// Policy Engine
+/// Policy Engine, with the attestation policies resources require.
 #[derive(Clone)]
-pub(crate) struct PolicyEngine(pub Arc<Mutex<dyn PolicyEngineInterface>>);
+pub(crate) struct PolicyEngine {
+    /// Engine of the resource policy.
+    pub engine: Arc<Mutex<dyn PolicyEngineInterface>>,
+
+    /// Attestation policies the release of resources requires.
+    pub required_policies: Arc<RwLock<RequiredPolicies>>,
+}
 
 impl PolicyEngine {
     /// Create and initialize PolicyEngine
     pub async fn new(config: &PolicyEngineConfig) -> Result<Self, ResourcePolicyError> {
-        let policy_engine: Arc<Mutex<dyn PolicyEngineInterface>> = {
+        let engine: Arc<Mutex<dyn PolicyEngineInterface>> = {
             cfg_if::cfg_if! {
                 if #[cfg(feature = "opa")] {
                     Arc::new(Mutex::new(opa::Opa::new(config.policy_path.clone().unwrap_or(PathBuf::from(DEFAULT_POLICY_PATH)))?))
@@ -93,6 +114,11 @@ impl PolicyEngine {
                 }
             }
         };
-        Ok(Self(policy_engine))
+        let required_policies = RequiredPolicies::new(config.required_policies.clone())
+            .map_err(|e| ResourcePolicyError::BindingError(e.to_string()))?;
+        Ok(Self {
+            engine,
+            required_policies: Arc::new(RwLock::new(required_policies)),
+        })
     }
 }
@@ -14,8 +14,16 @@
 # ```
 #
 # The <PATH> variable is a KBS resource path,
-# which is required to be a string in three segment path format:<TOP>/<MIDDLE>/<TAIL>,
-# for example: "my'repo/License/key".
+# which is required to be a string in path format:<NAMESPACE>/<TYPE>/<TAG>,
+# for example: "my'repo/License/key". The namespace may be nested, for
+# example: "tenant-a/team-x/License/key".
+#
+# The namespace of the resource and the namespaces enclosing it are given as:
+# ```
+# {
+# 	  "resource-namespaces": ["tenant-a", "tenant-a/team-x"]
+# }
+# ```
 #
 # The format of Attestation Claims Input is defined by the attestation service,
 # and its format may look like the following:
@@ -2,6 +2,7 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::namespace;
 use crate::policy_engine::{PolicyEngineInterface, ResourcePolicyError};
 use async_trait::async_trait;
 use base64::Engine;
@@ -49,6 +50,19 @@ impl PolicyEngineInterface for Opa {
             .add_data(resource_path_object)
             .map_err(|_| ResourcePolicyError::DataLoadError)?;
 
+        // Add the namespaces the resource is nested in as data, for the rules
+        // on a namespace to apply to everything beneath it
+        let namespaces = namespace::split_path(&resource_path)
+            .map(|(resource_namespace, _, _)| namespace::ancestors(resource_namespace))
+            .unwrap_or_default();
+        let namespaces_object = regorus::Value::from_json_str(
+            &serde_json::json!({ "resource-namespaces": namespaces }).to_string(),
+        )
+        .map_err(|_| ResourcePolicyError::ResourcePathError)?;
+        engine
+            .add_data(namespaces_object)
+            .map_err(|_| ResourcePolicyError::DataLoadError)?;
+
         // Add TCB claims as input
         engine
             .set_input_json(&input_claims)
@@ -65,6 +79,12 @@ impl PolicyEngineInterface for Opa {
 
         Ok(())
     }
+
+    async fn get_policy(&self) -> Result<String, ResourcePolicyError> {
+        let policy_bytes = tokio::fs::read(&self.policy_path).await?;
+
+        Ok(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(policy_bytes))
+    }
 }
 
 #[cfg(test)]
@@ -140,6 +160,21 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn test_get_policy() {
+        let tmp_file = NamedTempFile::new().unwrap();
+        let mut opa = Opa::new(tmp_file.path().to_path_buf()).unwrap();
+
+        set_policy_from_file(&mut opa, "test/data/policy_1.rego")
+            .await
+            .unwrap();
+
+        let policy = URL_SAFE_NO_PAD
+            .decode(opa.get_policy().await.unwrap())
+            .unwrap();
+        assert_eq!(policy, std::fs::read("test/data/policy_1.rego").unwrap());
+    }
+
     #[rstest]
     #[case("test/data/policy_1.rego", "my_repo/Alice/key", "Alice", 1, Ok(true))]
     #[case("test/data/policy_4.rego", "my_repo/Alice/key", "Alice", 1, Ok(true))]
@@ -173,6 +208,10 @@ mod tests {
     #[case("test/data/policy_5.rego", "myrepo/secret/secret3", "n", 3, Ok(false))]
     #[case("test/data/policy_5.rego", "a/b/secret2", "n", 3, Ok(false))]
     #[case("test/data/policy_5.rego", "abc", "n", 3, Ok(false))]
+    #[case("test/data/policy_6.rego", "acme/key/1", "Alice", 1, Ok(true))]
+    #[case("test/data/policy_6.rego", "acme/dev/key/1", "Alice", 1, Ok(true))]
+    #[case("test/data/policy_6.rego", "acme/dev/key/1", "Bob", 1, Ok(false))]
+    #[case("test/data/policy_6.rego", "other/dev/key/1", "Alice", 1, Ok(false))]
     #[tokio::test]
     async fn test_evaluate(
         #[case] policy_path: &str,
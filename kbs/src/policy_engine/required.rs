@@ -0,0 +1,139 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Attestation policies resources require.
+//!
+//! A resource bound to attestation policies is only released to attesters
+//! whose attestation results token shows that their evidence passed every
+//! one of them, whatever the resource policy decides.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+use crate::claims::passed_policies;
+use crate::namespace;
+
+/// Require the attestation policies `policy_ids` for the resources matching
+/// `resource`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PolicyBinding {
+    /// Resource path `<repository>/<type>/<tag>`, where a segment of `*`
+    /// matches any segment, or namespace `<repository>/` whose resources and
+    /// nested namespaces all require the policies.
+    pub resource: String,
+
+    /// IDs of the attestation policies the evidence must have passed.
+    pub policy_ids: Vec<String>,
+}
+
+impl PolicyBinding {
+    fn validate(&self) -> Result<()> {
+        if !namespace::is_valid_pattern(&self.resource) {
+            bail!(
+                "Bound resource path {} is not <repository>/<type>/<tag> or <repository>/",
+                self.resource
+            );
+        }
+        if self.policy_ids.is_empty() {
+            bail!("Resource {} is bound to no policies", self.resource);
+        }
+        Ok(())
+    }
+
+    fn matches(&self, resource_path: &str) -> bool {
+        namespace::matches(&self.resource, resource_path)
+    }
+}
+
+/// The policy bindings of a resource policy engine.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RequiredPolicies(Vec<PolicyBinding>);
+
+impl RequiredPolicies {
+    pub fn new(bindings: Vec<PolicyBinding>) -> Result<Self> {
+        for binding in &bindings {
+            binding.validate()?;
+        }
+        Ok(Self(bindings))
+    }
+
+    /// The IDs of the policies required for `resource_path` that the
+    /// attester with the attestation claims `claims` didn't pass.
+    pub fn missing(&self, resource_path: &str, claims: &Value) -> BTreeSet<String> {
+        let passed = passed_policies(claims);
+        self.0
+            .iter()
+            .filter(|binding| binding.matches(resource_path))
+            .flat_map(|binding| &binding.policy_ids)
+            .filter(|policy_id| !passed.contains(policy_id.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn required() -> RequiredPolicies {
+        RequiredPolicies::new(vec![
+            PolicyBinding {
+                resource: "default/key/*".into(),
+                policy_ids: vec!["tdx".into()],
+            },
+            PolicyBinding {
+                resource: "default/key/master".into(),
+                policy_ids: vec!["tdx".into(), "prod".into()],
+            },
+            PolicyBinding {
+                resource: "tenant-a/".into(),
+                policy_ids: vec!["tenant-a".into()],
+            },
+        ])
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case("default/key/1", json!({}), &["tdx"])]
+    #[case("default/key/1", json!({"evaluation-reports": [{"policy-id": "tdx"}]}), &[])]
+    #[case("default/key/master", json!({"evaluation-reports": [{"policy-id": "tdx"}]}), &["prod"])]
+    #[case(
+        "default/key/master",
+        json!({"evaluation-reports": [{"policy-id": "tdx"}, {"policy-id": "prod"}]}),
+        &[]
+    )]
+    #[case(
+        "default/key/master",
+        json!({"policy_ids_matched": [{"id": "tdx"}], "policy_ids_unmatched": [{"id": "prod"}]}),
+        &["prod"]
+    )]
+    #[case("default/cert/1", json!({}), &[])]
+    #[case("tenant-a/key/1", json!({}), &["tenant-a"])]
+    #[case("tenant-a/team-x/db/password", json!({}), &["tenant-a"])]
+    #[case(
+        "tenant-a/team-x/db/password",
+        json!({"evaluation-reports": [{"policy-id": "tenant-a"}]}),
+        &[]
+    )]
+    #[case("tenant-b/key/1", json!({}), &[])]
+    fn test_missing(#[case] path: &str, #[case] claims: Value, #[case] expected: &[&str]) {
+        let missing: Vec<String> = required().missing(path, &claims).into_iter().collect();
+        assert_eq!(missing, expected);
+    }
+
+    #[rstest]
+    #[case("default/key", vec!["tdx".into()])]
+    #[case("default/key/*", vec![])]
+    fn test_invalid_binding(#[case] resource: &str, #[case] policy_ids: Vec<String>) {
+        let binding = PolicyBinding {
+            resource: resource.into(),
+            policy_ids,
+        };
+        assert!(RequiredPolicies::new(vec![binding]).is_err());
+    }
+}
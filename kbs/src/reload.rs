@@ -0,0 +1,250 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reloading the KBS configuration without a restart.
+//!
+//! On SIGHUP or `POST /kbs/v0/reload`, KBS reads its configuration file again
+//! and replaces the resource repository, the attestation token verifier, the
+//! resource policy engine, the admin keys, the tenants and the session
+//! timeouts. The new configuration is loaded completely before anything is
+//! replaced, so an invalid one leaves the running configuration in place.
+//! Sessions survive a reload, and requests in flight finish with the
+//! components they started with. Other settings take effect on restart.
+
+use actix_web::web;
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::auth::{load_admin_keys, AdminKey};
+use crate::config::KbsConfig;
+use crate::http::ReattestationInterval;
+#[cfg(feature = "policy")]
+use crate::policy_engine::PolicyEngine;
+use crate::tenant::Tenants;
+#[cfg(feature = "resource")]
+use crate::{
+    resource::Repository,
+    token::{
+        revocation::{check_revocations, RevokedTokens},
+        AttestationTokenVerifier,
+    },
+};
+
+/// A value shared by the request handlers that is replaced on reload.
+pub(crate) struct Reloadable<T>(RwLock<T>);
+
+impl<T: Clone> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self(RwLock::new(value))
+    }
+
+    /// The current value. Handlers keep using the value they got even if it
+    /// is replaced meanwhile.
+    pub fn get(&self) -> T {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = value;
+    }
+
+    /// Replace the value with `f` of the current one.
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        let mut value = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        *value = f(value.clone());
+    }
+}
+
+/// The reloadable parts of the KBS configuration.
+pub(crate) struct Reloader {
+    /// Configuration file KBS was started from. Reloading fails without one.
+    pub config_file: Option<PathBuf>,
+
+    /// Insecure APIs can't be turned on or off by a reload.
+    pub insecure_api: bool,
+
+    pub timeout: web::Data<Reloadable<i64>>,
+    pub reattestation_interval: web::Data<Reloadable<ReattestationInterval>>,
+    pub admin_keys: web::Data<Reloadable<Arc<Vec<AdminKey>>>>,
+    #[cfg(feature = "resource")]
+    pub repository: web::Data<Reloadable<Arc<tokio::sync::RwLock<dyn Repository + Send + Sync>>>>,
+    #[cfg(feature = "resource")]
+    pub token_verifier:
+        web::Data<Reloadable<Arc<tokio::sync::RwLock<dyn AttestationTokenVerifier + Send + Sync>>>>,
+    /// The revoked tokens outlive the token verifiers checking them.
+    #[cfg(feature = "resource")]
+    pub revoked_tokens: Arc<RevokedTokens>,
+    #[cfg(feature = "policy")]
+    pub policy_engine: web::Data<Reloadable<PolicyEngine>>,
+    pub tenants: web::Data<Reloadable<Arc<Tenants>>>,
+}
+
+impl Reloader {
+    /// Load the configuration file again and replace the reloadable parts.
+    pub async fn reload(&self) -> Result<()> {
+        let Some(config_file) = &self.config_file else {
+            bail!("KBS was not started from a configuration file");
+        };
+        let config = KbsConfig::try_from(config_file.as_path())?;
+
+        let admin_keys = load_admin_keys(
+            self.insecure_api,
+            config.auth_public_key.as_deref(),
+            &config.admin_keys,
+        )
+        .await?;
+        #[cfg(feature = "resource")]
+        let repository = config
+            .repository_config
+            .unwrap_or_default()
+            .initialize()
+            .context("initialize repository")?;
+        #[cfg(feature = "resource")]
+        let token_verifier = crate::token::create_token_verifier(config.attestation_token_config)
+            .context("create attestation token verifier")?;
+        #[cfg(feature = "resource")]
+        let token_verifier = check_revocations(token_verifier, self.revoked_tokens.clone());
+        #[cfg(feature = "policy")]
+        let policy_engine = PolicyEngine::new(&config.policy_engine_config.unwrap_or_default())
+            .await
+            .context("initialize policy engine")?;
+        let tenants = Tenants::new(&config.tenants, self.insecure_api)
+            .await
+            .context("initialize tenants")?;
+
+        self.timeout.set(config.timeout);
+        self.reattestation_interval
+            .update(|interval| interval.reconfigure(config.reattestation_interval));
+        self.admin_keys.set(Arc::new(admin_keys));
+        #[cfg(feature = "resource")]
+        {
+            self.repository.set(repository);
+            self.token_verifier.set(token_verifier);
+        }
+        #[cfg(feature = "policy")]
+        self.policy_engine.set(policy_engine);
+        self.tenants.set(Arc::new(tenants));
+
+        info!("KBS configuration reloaded from {}", config_file.display());
+        Ok(())
+    }
+
+    /// Reload the configuration of `reloader` on SIGHUP.
+    pub fn watch(reloader: &web::Data<Self>) -> Result<()> {
+        if reloader.config_file.is_none() {
+            return Ok(());
+        }
+
+        let mut sighup = signal(SignalKind::hangup()).context("install SIGHUP handler")?;
+        let reloader = reloader.clone();
+
+        tokio::spawn(async move {
+            while sighup.recv().await.is_some() {
+                if let Err(e) = reloader.reload().await {
+                    error!("Failed to reload KBS configuration: {e:#}");
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn write_config(config_file: &Path, dir: &Path, timeout: &str) {
+        let config = format!(
+            r#"
+            insecure_api = true
+            timeout = {timeout}
+
+            [repository_config]
+            type = "LocalFs"
+            dir_path = "{repository}"
+
+            [attestation_token_config]
+            attestation_token_type = "CoCo"
+
+            [policy_engine_config]
+            policy_path = "{policy}"
+            "#,
+            repository = dir.join("repository").display(),
+            policy = dir.join("policy.rego").display(),
+        );
+        std::fs::write(config_file, config).unwrap();
+    }
+
+    async fn reloader(config_file: &Path, dir: &Path) -> Reloader {
+        Reloader {
+            config_file: Some(config_file.to_path_buf()),
+            insecure_api: true,
+            timeout: web::Data::new(Reloadable::new(5)),
+            reattestation_interval: web::Data::new(Reloadable::new(
+                ReattestationInterval::default(),
+            )),
+            admin_keys: web::Data::new(Reloadable::new(Arc::new(Vec::new()))),
+            #[cfg(feature = "resource")]
+            repository: web::Data::new(Reloadable::new(
+                serde_json::from_value::<crate::resource::RepositoryConfig>(serde_json::json!({
+                    "type": "LocalFs",
+                    "dir_path": dir.join("repository"),
+                }))
+                .unwrap()
+                .initialize()
+                .unwrap(),
+            )),
+            #[cfg(feature = "resource")]
+            token_verifier: web::Data::new(Reloadable::new(
+                crate::token::create_token_verifier(Default::default()).unwrap(),
+            )),
+            #[cfg(feature = "resource")]
+            revoked_tokens: Arc::default(),
+            #[cfg(feature = "policy")]
+            policy_engine: web::Data::new(Reloadable::new(
+                PolicyEngine::new(&crate::policy_engine::PolicyEngineConfig {
+                    policy_path: Some(dir.join("policy.rego")),
+                    ..Default::default()
+                })
+                .await
+                .unwrap(),
+            )),
+            tenants: web::Data::new(Reloadable::new(Arc::new(Tenants::default()))),
+        }
+    }
+
+    #[test]
+    fn test_reloadable() {
+        let value = Reloadable::new(Arc::new(1));
+        let current = value.get();
+
+        value.set(Arc::new(2));
+        assert_eq!(*current, 1);
+        assert_eq!(*value.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("kbs.toml");
+        let reloader = reloader(&config_file, dir.path()).await;
+
+        write_config(&config_file, dir.path(), "10");
+        reloader.reload().await.unwrap();
+        assert_eq!(reloader.timeout.get(), 10);
+
+        // An invalid configuration is not applied.
+        write_config(&config_file, dir.path(), "\"soon\"");
+        assert!(reloader.reload().await.is_err());
+        assert_eq!(reloader.timeout.get(), 10);
+    }
+}
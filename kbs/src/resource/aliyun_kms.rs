@@ -7,6 +7,7 @@ use anyhow::{Context, Result};
 use kms::{plugins::aliyun::AliyunKmsClient, Annotations, Getter};
 use log::info;
 use serde::Deserialize;
+use zeroize::Zeroizing;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AliyunKmsBackendConfig {
@@ -16,13 +17,28 @@ pub struct AliyunKmsBackendConfig {
     cert_pem: String,
 }
 
+impl AliyunKmsBackendConfig {
+    pub fn client(&self) -> Result<AliyunKmsClient> {
+        AliyunKmsClient::new(
+            &self.client_key,
+            &self.kms_instance_id,
+            &self.password,
+            &self.cert_pem,
+        )
+        .context("create aliyun KMS client")
+    }
+}
+
 pub struct AliyunKmsBackend {
     client: AliyunKmsClient,
 }
 
 #[async_trait::async_trait]
 impl Repository for AliyunKmsBackend {
-    async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+    async fn read_secret_resource(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Zeroizing<Vec<u8>>> {
         info!(
             "Use aliyun KMS backend. Ignore {}/{}",
             resource_desc.repository_name, resource_desc.resource_type
@@ -33,7 +49,7 @@ impl Repository for AliyunKmsBackend {
             .get_secret(&name, &Annotations::default())
             .await
             .context("failed to get resource from aliyun KMS")?;
-        Ok(resource_bytes)
+        Ok(Zeroizing::new(resource_bytes))
     }
 
     async fn write_secret_resource(
@@ -47,13 +63,7 @@ impl Repository for AliyunKmsBackend {
 
 impl AliyunKmsBackend {
     pub fn new(repo_desc: &AliyunKmsBackendConfig) -> Result<Self> {
-        let client = AliyunKmsClient::new(
-            &repo_desc.client_key,
-            &repo_desc.kms_instance_id,
-            &repo_desc.password,
-            &repo_desc.cert_pem,
-        )
-        .context("create aliyun KMS backend")?;
+        let client = repo_desc.client().context("create aliyun KMS backend")?;
         Ok(Self { client })
     }
 }
@@ -0,0 +1,245 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Short-lived, single-use download URLs of resources.
+//!
+//! An attested client, or an admin, has KBS mint a URL to fetch one resource
+//! once before it expires, and hands it to another component, which fetches
+//! the resource without attesting or holding the attestation token of the
+//! client. The URL carries a capability: the resource, the tenant, the
+//! expiry and a unique ID, signed with HMAC-SHA256 under a key of KBS.
+//!
+//! The capabilities redeemed are marked in the repository of their resource
+//! until they expire, so that a URL is redeemed once across the KBS
+//! instances sharing the signing key and the repository behind a load
+//! balancer.
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use time::OffsetDateTime;
+use zeroize::Zeroizing;
+
+use super::ResourceDesc;
+use crate::crypto;
+
+/// Length in bytes of the generated signing keys.
+const KEY_LENGTH: usize = 32;
+
+/// Default longest lifetime of the URLs, in seconds.
+const DEFAULT_MAX_TTL: u64 = 300;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DownloadUrlConfig {
+    /// File holding the key the URLs are signed with, to be shared by the
+    /// KBS instances behind a load balancer. A random key is generated at
+    /// start when omitted, so the URLs minted before a restart are invalid.
+    pub key_path: Option<PathBuf>,
+
+    /// Longest lifetime of the URLs, in seconds. URLs are minted with it
+    /// unless a shorter one is requested.
+    #[serde(default = "default_max_ttl")]
+    pub max_ttl: u64,
+}
+
+fn default_max_ttl() -> u64 {
+    DEFAULT_MAX_TTL
+}
+
+/// The grant of a download URL to read a resource once.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct Capability {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    pub repository: String,
+
+    #[serde(rename = "type")]
+    pub resource_type: String,
+
+    pub tag: String,
+
+    /// Expiration in seconds since the Unix epoch.
+    pub exp: i64,
+
+    /// Unique ID of the capability.
+    pub jti: String,
+}
+
+impl Capability {
+    pub fn resource(&self) -> ResourceDesc {
+        ResourceDesc {
+            repository_name: self.repository.clone(),
+            resource_type: self.resource_type.clone(),
+            resource_tag: self.tag.clone(),
+        }
+    }
+}
+
+/// Mints and verifies the signed capabilities of the download URLs.
+pub(crate) struct DownloadUrls {
+    key: Zeroizing<Vec<u8>>,
+    max_ttl: u64,
+}
+
+impl DownloadUrls {
+    pub fn new(config: &DownloadUrlConfig) -> Result<Self> {
+        if config.max_ttl == 0 {
+            bail!("max_ttl of the download URLs must be positive");
+        }
+        let key = match &config.key_path {
+            Some(path) => {
+                let key = Zeroizing::new(std::fs::read(path).with_context(|| {
+                    format!("read download URL signing key {}", path.display())
+                })?);
+                if key.len() < KEY_LENGTH {
+                    bail!("download URL signing key must be at least {KEY_LENGTH} bytes long");
+                }
+                key
+            }
+            None => {
+                let mut key = Zeroizing::new(vec![0; KEY_LENGTH]);
+                crypto::fill_random(&mut key)?;
+                key
+            }
+        };
+
+        Ok(Self {
+            key,
+            max_ttl: config.max_ttl,
+        })
+    }
+
+    /// Mint the capability to read `resource` of `tenant` once in the next
+    /// `ttl` seconds, `max_ttl` by default. Returns the encoded capability
+    /// and its expiration in seconds since the Unix epoch.
+    pub fn mint(
+        &self,
+        tenant: Option<&str>,
+        resource: &ResourceDesc,
+        ttl: Option<u64>,
+    ) -> Result<(String, i64)> {
+        let ttl = ttl.unwrap_or(self.max_ttl);
+        if ttl == 0 || ttl > self.max_ttl {
+            bail!("ttl must be between 1 and {} seconds", self.max_ttl);
+        }
+
+        let capability = Capability {
+            tenant: tenant.map(str::to_string),
+            repository: resource.repository_name.clone(),
+            resource_type: resource.resource_type.clone(),
+            tag: resource.resource_tag.clone(),
+            exp: OffsetDateTime::now_utc().unix_timestamp() + ttl as i64,
+            jti: uuid::Uuid::new_v4().to_string(),
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&capability)?);
+        let signature = URL_SAFE_NO_PAD.encode(crypto::hmac_sha256(&self.key, payload.as_bytes())?);
+        Ok((format!("{payload}.{signature}"), capability.exp))
+    }
+
+    /// Verify that the encoded capability `encoded` is signed by KBS and
+    /// unexpired. It is redeemed with
+    /// [`Repository::redeem_download_url`](super::Repository::redeem_download_url).
+    pub fn verify(&self, encoded: &str) -> Result<Capability> {
+        let Some((payload, signature)) = encoded.split_once('.') else {
+            bail!("malformed capability");
+        };
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .context("malformed capability signature")?;
+        let expected = crypto::hmac_sha256(&self.key, payload.as_bytes())?;
+        if !crypto::constant_time_eq(&signature, &expected) {
+            bail!("invalid capability signature");
+        }
+
+        let capability: Capability = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .context("malformed capability")?;
+        if capability.exp <= OffsetDateTime::now_utc().unix_timestamp() {
+            bail!("the download URL expired");
+        }
+        Ok(capability)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn download_urls() -> DownloadUrls {
+        DownloadUrls::new(&DownloadUrlConfig {
+            key_path: None,
+            max_ttl: 60,
+        })
+        .unwrap()
+    }
+
+    fn resource() -> ResourceDesc {
+        ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "key".into(),
+            resource_tag: "1".into(),
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let urls = download_urls();
+        let (capability, exp) = urls.mint(Some("team-a"), &resource(), Some(30)).unwrap();
+        assert!(exp > OffsetDateTime::now_utc().unix_timestamp());
+
+        let verified = urls.verify(&capability).unwrap();
+        assert_eq!(verified.tenant.as_deref(), Some("team-a"));
+        assert_eq!(verified.resource().resource_tag, "1");
+        assert_eq!(verified.exp, exp);
+    }
+
+    #[rstest]
+    #[case(Some(0))]
+    #[case(Some(61))]
+    fn test_invalid_ttl(#[case] ttl: Option<u64>) {
+        assert!(download_urls().mint(None, &resource(), ttl).is_err());
+    }
+
+    #[test]
+    fn test_forged_capability() {
+        let urls = download_urls();
+        let (capability, _) = urls.mint(None, &resource(), None).unwrap();
+
+        // Signed with another key.
+        assert!(download_urls().verify(&capability).is_err());
+
+        // Payload changed.
+        let (payload, signature) = capability.split_once('.').unwrap();
+        let mut forged: Capability =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+        forged.tag = "2".into();
+        let forged = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        assert!(urls.verify(&format!("{forged}.{signature}")).is_err());
+
+        assert!(urls.verify("garbage").is_err());
+    }
+
+    #[test]
+    fn test_expired_capability() {
+        let urls = download_urls();
+        let capability = Capability {
+            tenant: None,
+            repository: "default".into(),
+            resource_type: "key".into(),
+            tag: "1".into(),
+            exp: OffsetDateTime::now_utc().unix_timestamp() - 1,
+            jti: "jti".into(),
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&capability).unwrap());
+        let signature =
+            URL_SAFE_NO_PAD.encode(crypto::hmac_sha256(&urls.key, payload.as_bytes()).unwrap());
+        assert!(urls.verify(&format!("{payload}.{signature}")).is_err());
+    }
+}
@@ -0,0 +1,443 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Envelope encryption of resources at rest.
+//!
+//! Every resource is encrypted with its own random AES-256-GCM data key, bound
+//! to the resource path. The data key is stored next to the ciphertext,
+//! encrypted (wrapped) by the key encryption key (KEK), which never touches
+//! the repository directory.
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+use super::ResourceDesc;
+use crate::crypto::{
+    self, AES_256_GCM_KEY_LENGTH as KEY_LENGTH, AES_256_GCM_NONCE_LENGTH as NONCE_LENGTH,
+};
+
+/// Prefix of an encrypted resource file. Files without it were written before
+/// encryption was enabled, or not by KBS.
+const ENVELOPE_MAGIC: &[u8] = b"KBSENC1\n";
+
+/// Maximum clients of the KMS holding the KEK.
+#[cfg(feature = "aliyun")]
+pub const DEFAULT_KMS_POOL_SIZE: u64 = 16;
+
+/// Seconds to wait for a free KMS client before a request fails.
+#[cfg(feature = "aliyun")]
+pub const DEFAULT_KMS_POOL_TIMEOUT: u64 = 30;
+
+/// Key encryption key configuration.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum KekConfig {
+    /// A file holding the 32 bytes of an AES-256 key.
+    File { path: PathBuf },
+
+    /// A key of Aliyun KMS, identified by `key_id`.
+    #[cfg(feature = "aliyun")]
+    Aliyun {
+        key_id: String,
+        #[serde(flatten)]
+        client: super::aliyun_kms::AliyunKmsBackendConfig,
+
+        /// Maximum clients of the KMS, shared by the concurrent requests.
+        pool_size: Option<u64>,
+
+        /// Seconds to wait for a free client of the KMS.
+        pool_timeout: Option<u64>,
+    },
+}
+
+/// Wraps and unwraps the data keys of the resources.
+#[async_trait]
+trait KeyEncryptionKey: Send + Sync {
+    /// Encrypt `data_key`. Returns the wrapped key and the annotations needed
+    /// to unwrap it.
+    async fn wrap(&self, data_key: &[u8]) -> Result<(Vec<u8>, HashMap<String, String>)>;
+
+    async fn unwrap(
+        &self,
+        wrapped_key: &[u8],
+        annotations: &HashMap<String, String>,
+    ) -> Result<Zeroizing<Vec<u8>>>;
+}
+
+/// A local AES-256-GCM key.
+struct FileKek(Zeroizing<Vec<u8>>);
+
+#[async_trait]
+impl KeyEncryptionKey for FileKek {
+    async fn wrap(&self, data_key: &[u8]) -> Result<(Vec<u8>, HashMap<String, String>)> {
+        let mut nonce = [0; NONCE_LENGTH];
+        crypto::fill_random(&mut nonce)?;
+        let mut wrapped_key = nonce.to_vec();
+        wrapped_key.extend(
+            crypto::aes_256_gcm_encrypt(&self.0, &nonce, b"", data_key).context("wrap data key")?,
+        );
+        Ok((wrapped_key, HashMap::new()))
+    }
+
+    async fn unwrap(
+        &self,
+        wrapped_key: &[u8],
+        _annotations: &HashMap<String, String>,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        if wrapped_key.len() < NONCE_LENGTH {
+            bail!("wrapped data key is too short");
+        }
+        let (nonce, wrapped_key) = wrapped_key.split_at(NONCE_LENGTH);
+        crypto::aes_256_gcm_decrypt(&self.0, nonce, b"", wrapped_key)
+            .map_err(|_| anyhow!("unwrap data key failed, is the KEK the one it was wrapped with?"))
+    }
+}
+
+#[cfg(feature = "aliyun")]
+struct AliyunKmsManager(super::aliyun_kms::AliyunKmsBackendConfig);
+
+#[cfg(feature = "aliyun")]
+#[async_trait]
+impl mobc::Manager for AliyunKmsManager {
+    type Connection = kms::plugins::aliyun::AliyunKmsClient;
+    type Error = anyhow::Error;
+
+    async fn connect(&self) -> Result<Self::Connection> {
+        self.0.client()
+    }
+
+    async fn check(&self, conn: Self::Connection) -> Result<Self::Connection> {
+        Ok(conn)
+    }
+}
+
+/// A pool of at most `pool_size` clients of `manager`, a request waiting at
+/// most `pool_timeout` seconds for a free one.
+#[cfg(feature = "aliyun")]
+fn kms_pool<M: mobc::Manager>(
+    manager: M,
+    pool_size: Option<u64>,
+    pool_timeout: Option<u64>,
+) -> mobc::Pool<M> {
+    mobc::Pool::builder()
+        .max_open(pool_size.unwrap_or(DEFAULT_KMS_POOL_SIZE))
+        .get_timeout(Some(std::time::Duration::from_secs(
+            pool_timeout.unwrap_or(DEFAULT_KMS_POOL_TIMEOUT),
+        )))
+        .build(manager)
+}
+
+#[cfg(feature = "aliyun")]
+struct AliyunKek {
+    key_id: String,
+    pool: mobc::Pool<AliyunKmsManager>,
+}
+
+#[cfg(feature = "aliyun")]
+impl AliyunKek {
+    async fn client(&self) -> Result<mobc::Connection<AliyunKmsManager>> {
+        self.pool
+            .get()
+            .await
+            .map_err(|e| anyhow!("get aliyun KMS client: {e}"))
+    }
+}
+
+#[cfg(feature = "aliyun")]
+#[async_trait]
+impl KeyEncryptionKey for AliyunKek {
+    async fn wrap(&self, data_key: &[u8]) -> Result<(Vec<u8>, HashMap<String, String>)> {
+        use kms::Encrypter;
+
+        self.client()
+            .await?
+            .encrypt(data_key, &self.key_id)
+            .await
+            .context("wrap data key with aliyun KMS")
+    }
+
+    async fn unwrap(
+        &self,
+        wrapped_key: &[u8],
+        annotations: &HashMap<String, String>,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        use kms::Decrypter;
+
+        self.client()
+            .await?
+            .decrypt(wrapped_key, &self.key_id, annotations)
+            .await
+            .map(Zeroizing::new)
+            .context("unwrap data key with aliyun KMS")
+    }
+}
+
+/// An encrypted resource, as stored after [`ENVELOPE_MAGIC`].
+#[derive(Deserialize, Serialize)]
+struct Envelope {
+    wrapped_key: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    annotations: HashMap<String, String>,
+    iv: String,
+    ciphertext: String,
+}
+
+/// Encrypts resources before they are written and decrypts them after they
+/// are read.
+pub struct EnvelopeEncryption {
+    kek: Box<dyn KeyEncryptionKey>,
+
+    /// Whether resources written before encryption was enabled are opened.
+    plaintext_migration: bool,
+}
+
+impl EnvelopeEncryption {
+    /// Encryption with the KEK of `config`. Only with `plaintext_migration`
+    /// are the resources stored in plaintext opened.
+    pub fn new(config: &KekConfig, plaintext_migration: bool) -> Result<Self> {
+        let kek: Box<dyn KeyEncryptionKey> = match config {
+            KekConfig::File { path } => {
+                let key = Zeroizing::new(
+                    std::fs::read(path).with_context(|| format!("read KEK {}", path.display()))?,
+                );
+                if key.len() != KEY_LENGTH {
+                    bail!("KEK {} must be {KEY_LENGTH} bytes", path.display());
+                }
+                Box::new(FileKek(key))
+            }
+            #[cfg(feature = "aliyun")]
+            KekConfig::Aliyun {
+                key_id,
+                client,
+                pool_size,
+                pool_timeout,
+            } => {
+                // Fail early on a bad client configuration, rather than on
+                // the first request.
+                client.client()?;
+                Box::new(AliyunKek {
+                    key_id: key_id.clone(),
+                    pool: kms_pool(AliyunKmsManager(client.clone()), *pool_size, *pool_timeout),
+                })
+            }
+        };
+        Ok(Self {
+            kek,
+            plaintext_migration,
+        })
+    }
+
+    pub async fn seal(&self, resource_desc: &ResourceDesc, data: &[u8]) -> Result<Vec<u8>> {
+        let mut data_key = Zeroizing::new([0; KEY_LENGTH]);
+        let mut iv = [0; NONCE_LENGTH];
+        crypto::fill_random(&mut *data_key)?;
+        crypto::fill_random(&mut iv)?;
+        let ciphertext =
+            crypto::aes_256_gcm_encrypt(&*data_key, &iv, aad(resource_desc).as_bytes(), data)
+                .context("encrypt resource")?;
+        let (wrapped_key, annotations) = self.kek.wrap(&*data_key).await?;
+
+        let envelope = Envelope {
+            wrapped_key: STANDARD.encode(wrapped_key),
+            annotations,
+            iv: STANDARD.encode(iv),
+            ciphertext: STANDARD.encode(ciphertext),
+        };
+        let mut sealed = ENVELOPE_MAGIC.to_vec();
+        serde_json::to_writer(&mut sealed, &envelope)?;
+        Ok(sealed)
+    }
+
+    /// Decrypt the `sealed` resource. Resources written before encryption was
+    /// enabled are returned as they are during a plaintext migration, and
+    /// rejected otherwise.
+    pub async fn open(
+        &self,
+        resource_desc: &ResourceDesc,
+        sealed: Vec<u8>,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        let Some(envelope) = sealed.strip_prefix(ENVELOPE_MAGIC) else {
+            if !self.plaintext_migration {
+                bail!(
+                    "Resource {} is not encrypted, enable plaintext_migration to serve it",
+                    aad(resource_desc)
+                );
+            }
+            log::warn!(
+                "Resource {} is not encrypted, register it again to encrypt it",
+                aad(resource_desc)
+            );
+            return Ok(Zeroizing::new(sealed));
+        };
+        let envelope: Envelope =
+            serde_json::from_slice(envelope).context("parse encrypted resource")?;
+
+        let data_key = self
+            .kek
+            .unwrap(
+                &STANDARD.decode(envelope.wrapped_key)?,
+                &envelope.annotations,
+            )
+            .await?;
+        let iv = STANDARD.decode(envelope.iv)?;
+        if iv.len() != NONCE_LENGTH {
+            bail!("illegal IV of encrypted resource");
+        }
+        crypto::aes_256_gcm_decrypt(
+            &data_key,
+            &iv,
+            aad(resource_desc).as_bytes(),
+            &STANDARD.decode(envelope.ciphertext)?,
+        )
+        .map_err(|_| anyhow!("decrypt resource {} failed", aad(resource_desc)))
+    }
+}
+
+/// The resource path the ciphertext is bound to, so that encrypted resources
+/// can't be swapped.
+fn aad(resource_desc: &ResourceDesc) -> String {
+    format!(
+        "{}/{}/{}",
+        resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resource(tag: &str) -> ResourceDesc {
+        ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "key".into(),
+            resource_tag: tag.into(),
+        }
+    }
+
+    fn encryption(dir: &std::path::Path, key: &[u8]) -> EnvelopeEncryption {
+        let path = dir.join("kek");
+        std::fs::write(&path, key).unwrap();
+        EnvelopeEncryption::new(&KekConfig::File { path }, false).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_seal_and_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let encryption = encryption(dir.path(), &[1; KEY_LENGTH]);
+
+        let sealed = encryption.seal(&resource("a"), b"secret").await.unwrap();
+        assert!(sealed.starts_with(ENVELOPE_MAGIC));
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(
+            *encryption
+                .open(&resource("a"), sealed.clone())
+                .await
+                .unwrap(),
+            b"secret"
+        );
+
+        // Bound to the resource path.
+        assert!(encryption
+            .open(&resource("b"), sealed.clone())
+            .await
+            .is_err());
+
+        // Bound to the KEK.
+        let other = tempfile::tempdir().unwrap();
+        let other = self::encryption(other.path(), &[2; KEY_LENGTH]);
+        assert!(other.open(&resource("a"), sealed).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_open_plaintext() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut encryption = encryption(dir.path(), &[1; KEY_LENGTH]);
+        assert!(encryption
+            .open(&resource("a"), b"plain".to_vec())
+            .await
+            .is_err());
+
+        encryption.plaintext_migration = true;
+        assert_eq!(
+            *encryption
+                .open(&resource("a"), b"plain".to_vec())
+                .await
+                .unwrap(),
+            b"plain"
+        );
+    }
+
+    #[test]
+    fn test_kek_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kek");
+        std::fs::write(&path, [1; 16]).unwrap();
+        assert!(EnvelopeEncryption::new(&KekConfig::File { path }, false).is_err());
+    }
+
+    #[cfg(feature = "aliyun")]
+    #[test]
+    fn test_aliyun_config() {
+        let config: KekConfig = serde_json::from_value(serde_json::json!({
+            "type": "Aliyun",
+            "key_id": "key-1",
+            "client_key": "{}",
+            "kms_instance_id": "kst-1",
+            "password": "password",
+            "cert_pem": "",
+            "pool_size": 4,
+        }))
+        .unwrap();
+        let KekConfig::Aliyun {
+            pool_size,
+            pool_timeout,
+            ..
+        } = config
+        else {
+            panic!("not an Aliyun KEK");
+        };
+        assert_eq!((pool_size, pool_timeout), (Some(4), None));
+    }
+
+    #[cfg(feature = "aliyun")]
+    #[tokio::test]
+    async fn test_kms_pool() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+
+        struct Counter(Arc<AtomicU32>);
+
+        #[async_trait]
+        impl mobc::Manager for Counter {
+            type Connection = u32;
+            type Error = anyhow::Error;
+
+            async fn connect(&self) -> Result<u32> {
+                Ok(self.0.fetch_add(1, Ordering::SeqCst))
+            }
+
+            async fn check(&self, conn: u32) -> Result<u32> {
+                Ok(conn)
+            }
+        }
+
+        let connects = Arc::new(AtomicU32::new(0));
+        let pool = kms_pool(Counter(connects.clone()), Some(2), Some(1));
+
+        // Concurrent requests get clients of their own, up to the pool size.
+        let (a, b) = (pool.get().await.unwrap(), pool.get().await.unwrap());
+        assert_ne!(*a, *b);
+        assert!(pool.get().await.is_err());
+
+        // A free client is reused.
+        drop(a);
+        pool.get().await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,594 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! On-demand generation of secrets.
+//!
+//! A repository with generation rules generates a missing resource whose path
+//! matches a rule on its first request, persists it, and serves the stored one
+//! from then on. Only requests that passed the resource policy get here.
+//!
+//! A rule of a single resource may also rotate it: once the resource is older
+//! than the rotation interval, it is replaced with a newly generated one, and
+//! the replaced versions are kept as `<tag>.1` (the previous one), `<tag>.2`,
+//! and so on.
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+use zeroize::Zeroizing;
+
+use super::{Repository, ResourceDesc};
+use crate::namespace;
+
+fn default_length() -> usize {
+    32
+}
+
+fn default_bits() -> u32 {
+    3072
+}
+
+fn default_keep() -> usize {
+    1
+}
+
+/// The kind of secret to generate.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum SecretKind {
+    /// `length` random bytes, e.g. a symmetric key.
+    Random {
+        #[serde(default = "default_length")]
+        length: usize,
+    },
+
+    /// An RSA private key of `bits`, PKCS#8 PEM.
+    Rsa {
+        #[serde(default = "default_bits")]
+        bits: u32,
+    },
+
+    /// An EC private key of `curve`, PKCS#8 PEM.
+    Ec {
+        #[serde(default)]
+        curve: EcCurve,
+    },
+
+    /// An Ed25519 private key, PKCS#8 PEM.
+    Ed25519,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum EcCurve {
+    #[default]
+    #[serde(rename = "P-256")]
+    P256,
+
+    #[serde(rename = "P-384")]
+    P384,
+}
+
+impl SecretKind {
+    /// Check the secret generated as the resource at `path`.
+    pub(super) fn validate(&self, path: &str) -> Result<()> {
+        match self {
+            SecretKind::Random { length: 0 } => {
+                bail!("Generated resource {path} can't be empty")
+            }
+            SecretKind::Rsa { bits } if *bits < 2048 => {
+                bail!("RSA keys of {path} must be at least 2048 bits")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn generate(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let key = match self {
+            SecretKind::Random { length } => {
+                let mut secret = Zeroizing::new(vec![0; *length]);
+                OsRng.fill_bytes(&mut secret);
+                return Ok(secret);
+            }
+            SecretKind::Rsa { bits } => PKey::from_rsa(Rsa::generate(*bits)?)?,
+            SecretKind::Ec { curve } => {
+                let nid = match curve {
+                    EcCurve::P256 => Nid::X9_62_PRIME256V1,
+                    EcCurve::P384 => Nid::SECP384R1,
+                };
+                let group = EcGroup::from_curve_name(nid)?;
+                PKey::from_ec_key(EcKey::generate(&group)?)?
+            }
+            SecretKind::Ed25519 => PKey::generate_ed25519()?,
+        };
+        Ok(Zeroizing::new(key.private_key_to_pem_pkcs8()?))
+    }
+}
+
+/// Generate the resources matching `path` as `kind`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct GeneratorConfig {
+    /// Resource path `<repository>/<type>/<tag>`, where a segment of `*`
+    /// matches any segment, or namespace `<repository>/` of the resources
+    /// of the namespace and of the ones nested in it.
+    pub path: String,
+
+    #[serde(flatten)]
+    pub kind: SecretKind,
+
+    /// Rotation of the resource. Generated resources are never replaced
+    /// when omitted.
+    #[serde(default)]
+    pub rotation: Option<RotationConfig>,
+}
+
+/// Replace a generated resource on a cadence.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RotationConfig {
+    /// Seconds after which the resource is replaced.
+    pub interval: u64,
+
+    /// Number of replaced versions kept retrievable.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+impl GeneratorConfig {
+    fn validate(&self) -> Result<()> {
+        if !namespace::is_valid_pattern(&self.path) {
+            bail!(
+                "Generated resource path {} is not <repository>/<type>/<tag> or <repository>/",
+                self.path
+            );
+        }
+        self.kind.validate(&self.path)?;
+        if let Some(rotation) = &self.rotation {
+            if self.path.split('/').any(|segment| segment == "*") {
+                bail!("Rotated resource path {} can't have a `*`", self.path);
+            }
+            if self.path.ends_with('/') {
+                bail!("Rotated resource path {} can't be a namespace", self.path);
+            }
+            if rotation.interval == 0 {
+                bail!("Rotation interval of {} can't be 0", self.path);
+            }
+        }
+        Ok(())
+    }
+
+    /// The resource of a rule that rotates it.
+    fn rotated_resource(&self) -> Option<ResourceDesc> {
+        self.rotation.as_ref()?;
+        ResourceDesc::from_path(&self.path)
+    }
+
+    /// Whether `resource_desc` is a replaced version of the resource the
+    /// rule rotates.
+    fn is_version(&self, resource_desc: &ResourceDesc) -> bool {
+        self.rotated_resource()
+            .is_some_and(|rotated| is_version(resource_desc, &rotated))
+    }
+
+    fn matches(&self, resource_desc: &ResourceDesc) -> bool {
+        let resource_path = format!(
+            "{}/{}/{}",
+            resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+        );
+        namespace::matches(&self.path, &resource_path)
+    }
+}
+
+/// A repository that generates the missing resources matching its rules.
+pub struct Generating {
+    inner: RwLock<Box<dyn Repository + Send + Sync>>,
+    generators: Vec<GeneratorConfig>,
+}
+
+impl Generating {
+    pub fn new(
+        inner: Box<dyn Repository + Send + Sync>,
+        generators: Vec<GeneratorConfig>,
+    ) -> Result<Self> {
+        for generator in &generators {
+            generator.validate()?;
+        }
+        Ok(Self {
+            inner: RwLock::new(inner),
+            generators,
+        })
+    }
+}
+
+/// Whether `resource_desc` is a replaced version of the `rotated` resource.
+pub(crate) fn is_version(resource_desc: &ResourceDesc, rotated: &ResourceDesc) -> bool {
+    rotated.repository_name == resource_desc.repository_name
+        && rotated.resource_type == resource_desc.resource_type
+        && resource_desc
+            .resource_tag
+            .strip_prefix(&rotated.resource_tag)
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .is_some_and(|n| n.parse::<usize>().is_ok())
+}
+
+/// The `n`th replaced version of `resource_desc`.
+fn version(resource_desc: &ResourceDesc, n: usize) -> ResourceDesc {
+    ResourceDesc {
+        resource_tag: format!("{}.{n}", resource_desc.resource_tag),
+        ..resource_desc.clone()
+    }
+}
+
+/// Replace the resource with a new `kind` of secret, shifting the replaced
+/// versions and keeping `keep` of them.
+async fn rotate(
+    inner: &mut Box<dyn Repository + Send + Sync>,
+    resource_desc: &ResourceDesc,
+    kind: &SecretKind,
+    keep: usize,
+) -> Result<()> {
+    let resource = kind.generate().context("generate resource")?;
+    if keep > 0 {
+        for n in (1..keep).rev() {
+            match inner.read_secret_resource(version(resource_desc, n)).await {
+                Ok(replaced) => {
+                    inner
+                        .write_secret_resource(version(resource_desc, n + 1), &replaced)
+                        .await?
+                }
+                Err(e) if is_not_found(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let current = inner.read_secret_resource(resource_desc.clone()).await?;
+        inner
+            .write_secret_resource(version(resource_desc, 1), &current)
+            .await?;
+    }
+    inner
+        .write_secret_resource(resource_desc.clone(), &resource)
+        .await
+        .context("persist rotated resource")
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+#[async_trait::async_trait]
+impl Repository for Generating {
+    async fn read_secret_resource(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        let e = match self
+            .inner
+            .read()
+            .await
+            .read_secret_resource(resource_desc.clone())
+            .await
+        {
+            Ok(resource) => return Ok(resource),
+            Err(e) => e,
+        };
+        // Versions that were never replaced are not generated.
+        if self
+            .generators
+            .iter()
+            .any(|generator| generator.is_version(&resource_desc))
+        {
+            return Err(e);
+        }
+        let Some(generator) = self
+            .generators
+            .iter()
+            .find(|generator| generator.matches(&resource_desc))
+        else {
+            return Err(e);
+        };
+        if !is_not_found(&e) {
+            return Err(e);
+        }
+
+        // Another request may have generated it meanwhile.
+        let mut inner = self.inner.write().await;
+        match inner.read_secret_resource(resource_desc.clone()).await {
+            Err(e) if is_not_found(&e) => {}
+            result => return result,
+        }
+
+        let resource = generator.kind.generate().context("generate resource")?;
+        info!(
+            "Generated resource {}/{}/{}",
+            resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+        );
+        inner
+            .write_secret_resource(resource_desc, &resource)
+            .await
+            .context("persist generated resource")?;
+        Ok(resource)
+    }
+
+    async fn write_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        self.inner
+            .get_mut()
+            .write_secret_resource(resource_desc, data)
+            .await
+    }
+
+    async fn write_secret_resource_file(
+        &mut self,
+        resource_desc: ResourceDesc,
+        path: &Path,
+    ) -> Result<()> {
+        self.inner
+            .get_mut()
+            .write_secret_resource_file(resource_desc, path)
+            .await
+    }
+
+    async fn spills_uploads(&self) -> bool {
+        self.inner.read().await.spills_uploads().await
+    }
+
+    async fn resource_modified(&self, resource_desc: ResourceDesc) -> Result<SystemTime> {
+        self.inner
+            .read()
+            .await
+            .resource_modified(resource_desc)
+            .await
+    }
+
+    async fn rotate_due_resources(&self) -> Vec<(ResourceDesc, Result<()>)> {
+        let mut rotated = Vec::new();
+        for generator in &self.generators {
+            let (Some(resource_desc), Some(rotation)) =
+                (generator.rotated_resource(), &generator.rotation)
+            else {
+                continue;
+            };
+
+            let mut inner = self.inner.write().await;
+            // The cadence starts once the resource was generated.
+            let modified = match inner.resource_modified(resource_desc.clone()).await {
+                Ok(modified) => modified,
+                Err(e) if is_not_found(&e) => continue,
+                Err(e) => {
+                    rotated.push((resource_desc, Err(e)));
+                    continue;
+                }
+            };
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+            if age < Duration::from_secs(rotation.interval) {
+                continue;
+            }
+
+            let result = rotate(&mut inner, &resource_desc, &generator.kind, rotation.keep).await;
+            if result.is_ok() {
+                info!(
+                    "Rotated resource {}/{}/{}",
+                    resource_desc.repository_name,
+                    resource_desc.resource_type,
+                    resource_desc.resource_tag
+                );
+            }
+            rotated.push((resource_desc, result));
+        }
+        rotated
+    }
+
+    async fn list_resources(&self) -> Result<Vec<ResourceDesc>> {
+        self.inner.read().await.list_resources().await
+    }
+
+    async fn stored_sizes(&self) -> Result<Vec<(ResourceDesc, u64)>> {
+        self.inner.read().await.stored_sizes().await
+    }
+
+    async fn redeem_download_url(&self, id: &str, exp: i64) -> Result<bool> {
+        self.inner.read().await.redeem_download_url(id, exp).await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.inner.read().await.health_check().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::local_fs::{LocalFs, LocalFsRepoDesc};
+    use rstest::rstest;
+
+    fn resource(path: &str) -> ResourceDesc {
+        ResourceDesc::from_path(path).unwrap()
+    }
+
+    fn generating(dir: &std::path::Path, generators: Vec<GeneratorConfig>) -> Generating {
+        let local_fs = LocalFs::new(&LocalFsRepoDesc {
+            dir_path: Some(dir.to_string_lossy().to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        Generating::new(Box::new(local_fs), generators).unwrap()
+    }
+
+    #[rstest]
+    #[case("default/key/*", "default/key/a", true)]
+    #[case("*/key/a", "tenant/key/a", true)]
+    #[case("default/key/*", "default/cert/a", false)]
+    #[case("default/key/a", "default/key/b", false)]
+    #[case("tenant-a/", "tenant-a/team-x/key/a", true)]
+    fn test_matches(#[case] path: &str, #[case] resource_path: &str, #[case] matches: bool) {
+        let generator = GeneratorConfig {
+            path: path.into(),
+            kind: SecretKind::Ed25519,
+            rotation: None,
+        };
+        assert_eq!(generator.matches(&resource(resource_path)), matches);
+    }
+
+    #[rstest]
+    #[case(r#"{"path": "default/key/*", "type": "Random"}"#, SecretKind::Random { length: 32 })]
+    #[case(r#"{"path": "default/key/*", "type": "Rsa", "bits": 2048}"#, SecretKind::Rsa { bits: 2048 })]
+    #[case(r#"{"path": "default/key/*", "type": "Ec", "curve": "P-384"}"#, SecretKind::Ec { curve: EcCurve::P384 })]
+    #[case(r#"{"path": "default/key/*", "type": "Ed25519"}"#, SecretKind::Ed25519)]
+    fn test_parse_config(#[case] config: &str, #[case] kind: SecretKind) {
+        let config: GeneratorConfig = serde_json::from_str(config).unwrap();
+        assert_eq!(config.kind, kind);
+    }
+
+    #[rstest]
+    #[case("default/key", SecretKind::Ed25519, None)]
+    #[case("default/key/*", SecretKind::Random { length: 0 }, None)]
+    #[case("default/key/*", SecretKind::Rsa { bits: 1024 }, None)]
+    #[case("default/key/*", SecretKind::Ed25519, Some(RotationConfig { interval: 60, keep: 1 }))]
+    #[case("default/key/a", SecretKind::Ed25519, Some(RotationConfig { interval: 0, keep: 1 }))]
+    #[case("default/", SecretKind::Ed25519, Some(RotationConfig { interval: 60, keep: 1 }))]
+    fn test_invalid_config(
+        #[case] path: &str,
+        #[case] kind: SecretKind,
+        #[case] rotation: Option<RotationConfig>,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let local_fs = LocalFs::new(&LocalFsRepoDesc {
+            dir_path: Some(dir.path().to_string_lossy().to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let generators = vec![GeneratorConfig {
+            path: path.into(),
+            kind,
+            rotation,
+        }];
+        assert!(Generating::new(Box::new(local_fs), generators).is_err());
+    }
+
+    #[rstest]
+    #[case(SecretKind::Rsa { bits: 2048 })]
+    #[case(SecretKind::Ec { curve: EcCurve::P256 })]
+    #[case(SecretKind::Ed25519)]
+    fn test_generate_keypair(#[case] kind: SecretKind) {
+        let key = kind.generate().unwrap();
+        assert!(PKey::private_key_from_pem(&key).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_generate_on_demand() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = generating(
+            dir.path(),
+            vec![GeneratorConfig {
+                path: "default/key/*".into(),
+                kind: SecretKind::Random { length: 16 },
+                rotation: None,
+            }],
+        );
+
+        let generated = repository
+            .read_secret_resource(resource("default/key/a"))
+            .await
+            .unwrap();
+        assert_eq!(generated.len(), 16);
+        assert_eq!(
+            std::fs::read(dir.path().join("default/key/a")).unwrap(),
+            *generated
+        );
+
+        // Served as persisted from then on.
+        assert_eq!(
+            repository
+                .read_secret_resource(resource("default/key/a"))
+                .await
+                .unwrap(),
+            generated
+        );
+        assert_ne!(
+            repository
+                .read_secret_resource(resource("default/key/b"))
+                .await
+                .unwrap(),
+            generated
+        );
+
+        // Not generated without a matching rule.
+        assert!(repository
+            .read_secret_resource(resource("default/cert/a"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = generating(
+            dir.path(),
+            vec![
+                GeneratorConfig {
+                    path: "default/key/*".into(),
+                    kind: SecretKind::Random { length: 16 },
+                    rotation: None,
+                },
+                GeneratorConfig {
+                    path: "default/key/dek".into(),
+                    kind: SecretKind::Random { length: 16 },
+                    rotation: Some(RotationConfig {
+                        interval: 3600,
+                        keep: 2,
+                    }),
+                },
+            ],
+        );
+
+        // Not rotated before it was generated, nor before it is due.
+        assert!(repository.rotate_due_resources().await.is_empty());
+        let first = repository
+            .read_secret_resource(resource("default/key/dek"))
+            .await
+            .unwrap();
+        assert!(repository.rotate_due_resources().await.is_empty());
+        // Versions are not generated.
+        assert!(repository
+            .read_secret_resource(resource("default/key/dek.1"))
+            .await
+            .is_err());
+
+        let read = |path: &'static str| {
+            let repository = &repository;
+            async move { repository.read_secret_resource(resource(path)).await }
+        };
+        let mut versions = vec![first];
+        for _ in 0..3 {
+            let mut inner = repository.inner.write().await;
+            rotate(
+                &mut inner,
+                &resource("default/key/dek"),
+                &SecretKind::Random { length: 16 },
+                2,
+            )
+            .await
+            .unwrap();
+            drop(inner);
+            versions.push(read("default/key/dek").await.unwrap());
+        }
+
+        assert_eq!(read("default/key/dek.1").await.unwrap(), versions[2]);
+        assert_eq!(read("default/key/dek.2").await.unwrap(), versions[1]);
+        assert!(read("default/key/dek.3").await.is_err());
+        assert_ne!(versions[3], versions[2]);
+    }
+}
@@ -2,33 +2,83 @@
 // Licensed under the Apache License, Version 2.0, see LICENSE for details.
 // SPDX-License-Identifier: Apache-2.0
 
+use super::envelope::{EnvelopeEncryption, KekConfig};
+use super::generator::GeneratorConfig;
+use super::hooks::WriteHookConfig;
+use super::quota::{QuotaConfig, TotalQuotaConfig};
 use super::{Repository, ResourceDesc};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::Deserialize;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use zeroize::Zeroizing;
 
 pub const DEFAULT_REPO_DIR_PATH: &str = "/opt/confidential-containers/kbs/repository";
 
+/// Directory of the repository marking the redeemed download URLs. It holds
+/// no resource, which are two directories deep or more.
+const DOWNLOAD_URLS_DIR: &str = ".download-urls";
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct LocalFsRepoDesc {
     pub dir_path: Option<String>,
+
+    /// Key encryption key of the resources. Resources are stored in plaintext
+    /// when omitted.
+    #[serde(default)]
+    pub kek: Option<KekConfig>,
+
+    /// Serve the resources written before the `kek` was set in plaintext,
+    /// while they are registered again to be encrypted. They are rejected
+    /// otherwise, so that plaintext files put in the repository directory
+    /// can't replace the encrypted resources.
+    #[serde(default)]
+    pub plaintext_migration: bool,
+
+    /// Resources generated on their first request.
+    #[serde(default)]
+    pub generate: Vec<GeneratorConfig>,
+
+    /// Hooks checking the resources written by the admins.
+    #[serde(default)]
+    pub write_hooks: Vec<WriteHookConfig>,
+
+    /// Quotas of the repositories, checked on the writes of the admins.
+    #[serde(default)]
+    pub quotas: Vec<QuotaConfig>,
+
+    /// Quota of all the resources of the repositories.
+    #[serde(default)]
+    pub total_quota: Option<TotalQuotaConfig>,
 }
 
 impl Default for LocalFsRepoDesc {
     fn default() -> Self {
         Self {
             dir_path: Some(DEFAULT_REPO_DIR_PATH.to_string()),
+            kek: None,
+            plaintext_migration: false,
+            generate: Vec::new(),
+            write_hooks: Vec::new(),
+            quotas: Vec::new(),
+            total_quota: None,
         }
     }
 }
 
 pub struct LocalFs {
     pub repo_dir_path: String,
+    encryption: Option<EnvelopeEncryption>,
 }
 
 #[async_trait::async_trait]
 impl Repository for LocalFs {
-    async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>> {
+    async fn read_secret_resource(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Zeroizing<Vec<u8>>> {
         let mut resource_path = PathBuf::from(&self.repo_dir_path);
 
         let ref_resource_path = format!(
@@ -40,7 +90,10 @@ impl Repository for LocalFs {
         let resource_byte = tokio::fs::read(&resource_path)
             .await
             .context("read resource from local fs")?;
-        Ok(resource_byte)
+        match &self.encryption {
+            Some(encryption) => encryption.open(&resource_desc, resource_byte).await,
+            None => Ok(Zeroizing::new(resource_byte)),
+        }
     }
 
     async fn write_secret_resource(
@@ -48,6 +101,190 @@ impl Repository for LocalFs {
         resource_desc: ResourceDesc,
         data: &[u8],
     ) -> Result<()> {
+        let sealed;
+        let data = match &self.encryption {
+            Some(encryption) => {
+                sealed = encryption.seal(&resource_desc, data).await?;
+                &sealed[..]
+            }
+            None => data,
+        };
+
+        let resource_path = self.create_resource_path(resource_desc).await?;
+        tokio::fs::write(resource_path, data)
+            .await
+            .context("write local fs")
+    }
+
+    async fn write_secret_resource_file(
+        &mut self,
+        resource_desc: ResourceDesc,
+        path: &Path,
+    ) -> Result<()> {
+        // Resources are sealed in memory.
+        if self.encryption.is_some() {
+            let data = Zeroizing::new(
+                tokio::fs::read(path)
+                    .await
+                    .context("read uploaded resource")?,
+            );
+            return self.write_secret_resource(resource_desc, &data).await;
+        }
+
+        let resource_path = self.create_resource_path(resource_desc).await?;
+        tokio::fs::copy(path, resource_path)
+            .await
+            .context("write local fs")?;
+        Ok(())
+    }
+
+    async fn spills_uploads(&self) -> bool {
+        self.encryption.is_none()
+    }
+
+    async fn resource_modified(&self, resource_desc: ResourceDesc) -> Result<SystemTime> {
+        let resource_path = PathBuf::from(&self.repo_dir_path).join(format!(
+            "{}/{}/{}",
+            resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+        ));
+        let metadata = tokio::fs::metadata(&resource_path)
+            .await
+            .context("stat resource in local fs")?;
+        Ok(metadata.modified()?)
+    }
+
+    /// The files two directories deep or more are resources, whose
+    /// repository is the namespace of all their directories but the last.
+    async fn list_resources(&self) -> Result<Vec<ResourceDesc>> {
+        let mut resources = Vec::new();
+        // Directories to list, by the segments of their path, the last one
+        // first.
+        let mut pending = vec![Vec::<String>::new()];
+        while let Some(segments) = pending.pop() {
+            let dir = segments
+                .iter()
+                .fold(PathBuf::from(&self.repo_dir_path), |dir, segment| {
+                    dir.join(segment)
+                });
+            let (mut dirs, mut files) = (Vec::new(), Vec::new());
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .context("list resources in local fs")?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    dirs.push(name);
+                } else if file_type.is_file() {
+                    files.push(name);
+                }
+            }
+
+            if let [namespace @ .., resource_type] = &segments[..] {
+                if !namespace.is_empty() {
+                    files.sort();
+                    resources.extend(files.into_iter().map(|resource_tag| ResourceDesc {
+                        repository_name: namespace.join("/"),
+                        resource_type: resource_type.clone(),
+                        resource_tag,
+                    }));
+                }
+            }
+
+            dirs.sort();
+            pending.extend(dirs.into_iter().rev().map(|name| {
+                let mut segments = segments.clone();
+                segments.push(name);
+                segments
+            }));
+        }
+        Ok(resources)
+    }
+
+    async fn stored_sizes(&self) -> Result<Vec<(ResourceDesc, u64)>> {
+        let mut sizes = Vec::new();
+        for resource_desc in self.list_resources().await? {
+            let resource_path = PathBuf::from(&self.repo_dir_path).join(format!(
+                "{}/{}/{}",
+                resource_desc.repository_name,
+                resource_desc.resource_type,
+                resource_desc.resource_tag
+            ));
+            let metadata = tokio::fs::metadata(&resource_path)
+                .await
+                .context("stat resource in local fs")?;
+            sizes.push((resource_desc, metadata.len()));
+        }
+        Ok(sizes)
+    }
+
+    /// A redeemed download URL is marked by a file named after it, holding
+    /// its expiration, created exclusively so that the KBS instances sharing
+    /// the directory redeem it once. The marks of the expired URLs are
+    /// removed.
+    async fn redeem_download_url(&self, id: &str, exp: i64) -> Result<bool> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            bail!("Illegal download URL ID {id}");
+        }
+        let dir = PathBuf::from(&self.repo_dir_path).join(DOWNLOAD_URLS_DIR);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .context("create download URL marks dir")?;
+        if let Err(e) = remove_expired_marks(&dir).await {
+            log::warn!("Failed to remove the marks of the expired download URLs: {e:#}");
+        }
+
+        let mark = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dir.join(id))
+            .await;
+        match mark {
+            Ok(mut mark) => {
+                mark.write_all(exp.to_string().as_bytes())
+                    .await
+                    .context("write download URL mark")?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e).context("create download URL mark"),
+        }
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        let metadata = tokio::fs::metadata(&self.repo_dir_path)
+            .await
+            .context("stat repository dir")?;
+        if !metadata.is_dir() {
+            bail!("{} is not a directory", self.repo_dir_path);
+        }
+        Ok(())
+    }
+}
+
+/// Remove the marks in `dir` of the download URLs that expired. A mark
+/// without an expiration yet is being created, and one that is gone was
+/// removed by another KBS instance.
+async fn remove_expired_marks(dir: &Path) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(exp) = tokio::fs::read_to_string(entry.path()).await else {
+            continue;
+        };
+        if exp.parse::<i64>().is_ok_and(|exp| exp <= now) {
+            match tokio::fs::remove_file(entry.path()).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+impl LocalFs {
+    /// Path of the resource file, creating its parent directories.
+    async fn create_resource_path(&self, resource_desc: ResourceDesc) -> Result<PathBuf> {
         let mut resource_path = PathBuf::from(&self.repo_dir_path);
         resource_path.push(resource_desc.repository_name);
         resource_path.push(resource_desc.resource_type);
@@ -59,20 +296,20 @@ impl Repository for LocalFs {
         }
 
         resource_path.push(resource_desc.resource_tag);
-
-        tokio::fs::write(resource_path, data)
-            .await
-            .context("write local fs")
+        Ok(resource_path)
     }
-}
 
-impl LocalFs {
     pub fn new(repo_desc: &LocalFsRepoDesc) -> Result<Self> {
         Ok(Self {
             repo_dir_path: repo_desc
                 .dir_path
                 .clone()
                 .unwrap_or(DEFAULT_REPO_DIR_PATH.to_string()),
+            encryption: repo_desc
+                .kek
+                .as_ref()
+                .map(|kek| EnvelopeEncryption::new(kek, repo_desc.plaintext_migration))
+                .transpose()?,
         })
     }
 }
@@ -80,6 +317,7 @@ impl LocalFs {
 #[cfg(test)]
 mod tests {
     use crate::resource::{
+        envelope::KekConfig,
         local_fs::{LocalFs, LocalFsRepoDesc},
         Repository, ResourceDesc,
     };
@@ -91,6 +329,12 @@ mod tests {
         let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
         let repo_desc = LocalFsRepoDesc {
             dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
+            kek: None,
+            plaintext_migration: false,
+            generate: Vec::new(),
+            write_hooks: Vec::new(),
+            quotas: Vec::new(),
+            total_quota: None,
         };
 
         let mut local_fs = LocalFs::new(&repo_desc).expect("create local fs failed");
@@ -111,4 +355,179 @@ mod tests {
 
         assert_eq!(&data[..], TEST_DATA);
     }
+
+    #[tokio::test]
+    async fn write_and_read_encrypted_resource() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let kek_path = tmp_dir.path().join("kek");
+        std::fs::write(&kek_path, [7; 32]).expect("write KEK failed");
+        let repo_dir = tmp_dir.path().join("repository");
+        let repo_desc = LocalFsRepoDesc {
+            dir_path: Some(repo_dir.to_string_lossy().to_string()),
+            kek: Some(KekConfig::File { path: kek_path }),
+            plaintext_migration: false,
+            generate: Vec::new(),
+            write_hooks: Vec::new(),
+            quotas: Vec::new(),
+            total_quota: None,
+        };
+
+        let mut local_fs = LocalFs::new(&repo_desc).expect("create local fs failed");
+        let resource_desc = ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "test".into(),
+            resource_tag: "test".into(),
+        };
+
+        local_fs
+            .write_secret_resource(resource_desc.clone(), TEST_DATA)
+            .await
+            .expect("write secret resource failed");
+        let stored = std::fs::read(repo_dir.join("default/test/test")).expect("read file failed");
+        assert!(!stored.windows(TEST_DATA.len()).any(|w| w == TEST_DATA));
+
+        // The decrypted resource is wiped from memory when it is dropped.
+        let data: zeroize::Zeroizing<Vec<u8>> = local_fs
+            .read_secret_resource(resource_desc)
+            .await
+            .expect("read secret resource failed");
+        assert_eq!(&data[..], TEST_DATA);
+    }
+
+    #[tokio::test]
+    async fn write_resource_file() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let upload = tmp_dir.path().join("upload");
+        std::fs::write(&upload, TEST_DATA).expect("write upload failed");
+        let repo_desc = LocalFsRepoDesc {
+            dir_path: Some(
+                tmp_dir
+                    .path()
+                    .join("repository")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            kek: None,
+            plaintext_migration: false,
+            generate: Vec::new(),
+            write_hooks: Vec::new(),
+            quotas: Vec::new(),
+            total_quota: None,
+        };
+
+        let mut local_fs = LocalFs::new(&repo_desc).expect("create local fs failed");
+        let resource_desc = ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "test".into(),
+            resource_tag: "test".into(),
+        };
+
+        local_fs
+            .write_secret_resource_file(resource_desc.clone(), &upload)
+            .await
+            .expect("write secret resource file failed");
+        let data = local_fs
+            .read_secret_resource(resource_desc)
+            .await
+            .expect("read secret resource failed");
+        assert_eq!(&data[..], TEST_DATA);
+    }
+
+    #[tokio::test]
+    async fn list_resources() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let repo_desc = LocalFsRepoDesc {
+            dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
+            kek: None,
+            plaintext_migration: false,
+            generate: Vec::new(),
+            write_hooks: Vec::new(),
+            quotas: Vec::new(),
+            total_quota: None,
+        };
+        let mut local_fs = LocalFs::new(&repo_desc).expect("create local fs failed");
+        for (repository_name, resource_type) in [
+            ("default", "key"),
+            ("other", "cert"),
+            ("tenant-a/team-x", "key"),
+        ] {
+            let resource_desc = ResourceDesc {
+                repository_name: repository_name.into(),
+                resource_type: resource_type.into(),
+                resource_tag: "1".into(),
+            };
+            local_fs
+                .write_secret_resource(resource_desc, TEST_DATA)
+                .await
+                .expect("write secret resource failed");
+        }
+
+        let resources = local_fs
+            .list_resources()
+            .await
+            .expect("list resources failed");
+        let paths: Vec<_> = resources
+            .iter()
+            .map(|r| {
+                format!(
+                    "{}/{}/{}",
+                    r.repository_name, r.resource_type, r.resource_tag
+                )
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            ["default/key/1", "other/cert/1", "tenant-a/team-x/key/1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn redeem_download_url() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let repo_desc = LocalFsRepoDesc {
+            dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
+            kek: None,
+            plaintext_migration: false,
+            generate: Vec::new(),
+            write_hooks: Vec::new(),
+            quotas: Vec::new(),
+            total_quota: None,
+        };
+        // Two KBS instances sharing the directory.
+        let local_fs = LocalFs::new(&repo_desc).expect("create local fs failed");
+        let other = LocalFs::new(&repo_desc).expect("create local fs failed");
+
+        let exp = i64::MAX;
+        assert!(local_fs.redeem_download_url("url-1", exp).await.unwrap());
+        assert!(!other.redeem_download_url("url-1", exp).await.unwrap());
+        assert!(local_fs.redeem_download_url("../url-1", exp).await.is_err());
+
+        // The mark of an expired URL is removed.
+        assert!(local_fs.redeem_download_url("url-2", 0).await.unwrap());
+        assert!(other.redeem_download_url("url-3", exp).await.unwrap());
+        let marks = tmp_dir.path().join(".download-urls");
+        assert!(!marks.join("url-2").exists());
+        assert!(marks.join("url-1").exists());
+
+        // The marks are no resources.
+        assert!(local_fs.list_resources().await.unwrap().is_empty());
+    }
+    #[tokio::test]
+    async fn health_check() {
+        let tmp_dir = tempfile::tempdir().expect("create temp dir failed");
+        let repo_desc = LocalFsRepoDesc {
+            dir_path: Some(tmp_dir.path().to_string_lossy().to_string()),
+            kek: None,
+            plaintext_migration: false,
+            generate: Vec::new(),
+            write_hooks: Vec::new(),
+            quotas: Vec::new(),
+            total_quota: None,
+        };
+        let local_fs = LocalFs::new(&repo_desc).expect("create local fs failed");
+        assert!(local_fs.health_check().await.is_ok());
+
+        tmp_dir.close().expect("remove temp dir failed");
+        assert!(local_fs.health_check().await.is_err());
+    }
 }
@@ -0,0 +1,435 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mirroring of a repository to a secondary one, for disaster recovery.
+//!
+//! Resources are read from and written to the primary repository. Once a
+//! write to the primary succeeded, the resource is queued to be copied to the
+//! secondary in the background, read again from the primary so that the
+//! secondary gets what the primary stored. A copy that fails, or a write
+//! that doesn't fit in the queue, leaves the secondary behind until the
+//! consistency check repairs it.
+
+use anyhow::{bail, Context, Result};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, RwLock};
+use zeroize::Zeroizing;
+
+use super::generator::is_version;
+use super::quota::Usage;
+use super::{Repository, RepositoryConfig, ResourceDesc};
+
+/// Attempts to copy a resource to the secondary repository.
+const COPY_ATTEMPTS: u32 = 3;
+
+/// Delay before the second attempt of a copy, doubled for every next one.
+const COPY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+fn default_queue_size() -> usize {
+    1024
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MirroredRepoDesc {
+    /// Repository the resources are read from and written to.
+    pub primary: Box<RepositoryConfig>,
+
+    /// Repository the writes to `primary` are copied to.
+    pub secondary: Box<RepositoryConfig>,
+
+    /// Number of written resources waiting to be copied to `secondary`.
+    /// Resources written while the queue is full are not mirrored.
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+/// Outcome of the comparison of the primary and secondary repositories.
+#[derive(Debug, Default, Serialize)]
+pub struct MirrorReport {
+    /// Number of resources of the primary repository checked.
+    pub checked: usize,
+
+    /// Resources of the primary repository missing from the secondary.
+    pub missing: Vec<String>,
+
+    /// Resources whose copy in the secondary repository differs.
+    pub different: Vec<String>,
+
+    /// Resources of the secondary repository missing from the primary. They
+    /// are left in place.
+    pub extra: Vec<String>,
+
+    /// Number of missing and different resources copied again.
+    pub repaired: usize,
+
+    /// Resources that could not be compared or repaired, with the reason.
+    pub errors: Vec<String>,
+}
+
+type SharedRepository = Arc<RwLock<dyn Repository + Send + Sync>>;
+
+pub struct Mirrored {
+    primary: SharedRepository,
+    secondary: SharedRepository,
+    queue: mpsc::Sender<ResourceDesc>,
+}
+
+impl Mirrored {
+    /// Mirror the repositories of `desc`. The copies run in a task of the
+    /// current Tokio runtime, until the repository is dropped.
+    pub fn new(desc: &MirroredRepoDesc) -> Result<Self> {
+        if desc.queue_size == 0 {
+            bail!("queue_size of a mirrored repository must be positive");
+        }
+        let primary = desc
+            .primary
+            .initialize()
+            .context("initialize primary repository")?;
+        let secondary = desc
+            .secondary
+            .initialize()
+            .context("initialize secondary repository")?;
+
+        let (queue, mut queued) = mpsc::channel::<ResourceDesc>(desc.queue_size);
+        let (source, target) = (primary.clone(), secondary.clone());
+        tokio::spawn(async move {
+            while let Some(resource_desc) = queued.recv().await {
+                copy_with_retries(&source, &target, resource_desc).await;
+            }
+        });
+
+        Ok(Self {
+            primary,
+            secondary,
+            queue,
+        })
+    }
+
+    /// Queue the copy of `resource_desc` to the secondary repository.
+    fn mirror(&self, resource_desc: ResourceDesc) {
+        if let Err(e) = self.queue.try_send(resource_desc) {
+            warn!(
+                "Mirror queue is full, resource {} is not mirrored until repaired",
+                path(&e.into_inner())
+            );
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Repository for Mirrored {
+    async fn read_secret_resource(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        self.primary
+            .read()
+            .await
+            .read_secret_resource(resource_desc)
+            .await
+    }
+
+    async fn write_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        self.primary
+            .write()
+            .await
+            .write_secret_resource(resource_desc.clone(), data)
+            .await?;
+        self.mirror(resource_desc);
+        Ok(())
+    }
+
+    async fn write_secret_resource_file(
+        &mut self,
+        resource_desc: ResourceDesc,
+        path: &Path,
+    ) -> Result<()> {
+        self.primary
+            .write()
+            .await
+            .write_secret_resource_file(resource_desc.clone(), path)
+            .await?;
+        self.mirror(resource_desc);
+        Ok(())
+    }
+
+    async fn spills_uploads(&self) -> bool {
+        self.primary.read().await.spills_uploads().await
+    }
+
+    async fn resource_modified(&self, resource_desc: ResourceDesc) -> Result<SystemTime> {
+        self.primary
+            .read()
+            .await
+            .resource_modified(resource_desc)
+            .await
+    }
+
+    /// The rotated resources are mirrored with their replaced versions, so
+    /// that the secondary repository still serves them after a failover.
+    async fn rotate_due_resources(&self) -> Vec<(ResourceDesc, Result<()>)> {
+        let primary = self.primary.read().await;
+        let rotated = primary.rotate_due_resources().await;
+        if rotated.iter().all(|(_, result)| result.is_err()) {
+            return rotated;
+        }
+
+        let resources = match primary.list_resources().await {
+            Ok(resources) => resources,
+            Err(e) => {
+                warn!("Failed to list the replaced versions of the rotated resources: {e:#}");
+                Vec::new()
+            }
+        };
+        for (resource_desc, result) in &rotated {
+            if result.is_err() {
+                continue;
+            }
+            self.mirror(resource_desc.clone());
+            for version in resources
+                .iter()
+                .filter(|listed| is_version(listed, resource_desc))
+            {
+                self.mirror(version.clone());
+            }
+        }
+        rotated
+    }
+
+    async fn list_resources(&self) -> Result<Vec<ResourceDesc>> {
+        self.primary.read().await.list_resources().await
+    }
+
+    async fn stored_sizes(&self) -> Result<Vec<(ResourceDesc, u64)>> {
+        self.primary.read().await.stored_sizes().await
+    }
+
+    async fn usage(&self) -> Result<BTreeMap<String, Usage>> {
+        self.primary.read().await.usage().await
+    }
+
+    async fn check_mirror(&self, repair: bool) -> Result<MirrorReport> {
+        let resources = self
+            .primary
+            .read()
+            .await
+            .list_resources()
+            .await
+            .context("list the resources of the primary repository")?;
+        let mut report = MirrorReport {
+            checked: resources.len(),
+            ..Default::default()
+        };
+
+        let mut listed = HashSet::new();
+        for resource_desc in resources {
+            let resource_path = path(&resource_desc);
+            listed.insert(resource_path.clone());
+
+            let data = match self
+                .primary
+                .read()
+                .await
+                .read_secret_resource(resource_desc.clone())
+                .await
+            {
+                Ok(data) => data,
+                Err(e) => {
+                    report.errors.push(format!("{resource_path}: {e:#}"));
+                    continue;
+                }
+            };
+            match self
+                .secondary
+                .read()
+                .await
+                .read_secret_resource(resource_desc.clone())
+                .await
+            {
+                Ok(mirrored) if mirrored == data => continue,
+                Ok(_) => report.different.push(resource_path.clone()),
+                Err(_) => report.missing.push(resource_path.clone()),
+            }
+
+            if repair {
+                match self
+                    .secondary
+                    .write()
+                    .await
+                    .write_secret_resource(resource_desc, &data)
+                    .await
+                {
+                    Ok(()) => report.repaired += 1,
+                    Err(e) => report.errors.push(format!("{resource_path}: {e:#}")),
+                }
+            }
+        }
+
+        // The secondary may not be able to list its resources.
+        if let Ok(mirrored) = self.secondary.read().await.list_resources().await {
+            report.extra = mirrored
+                .iter()
+                .map(path)
+                .filter(|resource_path| !listed.contains(resource_path))
+                .collect();
+        }
+
+        Ok(report)
+    }
+
+    async fn redeem_download_url(&self, id: &str, exp: i64) -> Result<bool> {
+        self.primary.read().await.redeem_download_url(id, exp).await
+    }
+
+    /// The secondary repository only has to be reachable to repair it.
+    async fn health_check(&self) -> Result<()> {
+        if let Err(e) = self.secondary.read().await.health_check().await {
+            warn!("Secondary repository is unhealthy: {e:#}");
+        }
+        self.primary.read().await.health_check().await
+    }
+}
+
+/// Copy `resource_desc` from `primary` to `secondary`, a few times if needed.
+async fn copy_with_retries(
+    primary: &SharedRepository,
+    secondary: &SharedRepository,
+    resource_desc: ResourceDesc,
+) {
+    let mut delay = COPY_RETRY_DELAY;
+    for attempt in 1..=COPY_ATTEMPTS {
+        match copy(primary, secondary, resource_desc.clone()).await {
+            Ok(()) => return,
+            Err(e) if attempt == COPY_ATTEMPTS => error!(
+                "Failed to mirror resource {}, it is not mirrored until repaired: {e:#}",
+                path(&resource_desc)
+            ),
+            Err(e) => {
+                warn!("Failed to mirror resource {}: {e:#}", path(&resource_desc));
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+}
+
+async fn copy(
+    primary: &SharedRepository,
+    secondary: &SharedRepository,
+    resource_desc: ResourceDesc,
+) -> Result<()> {
+    let data = primary
+        .read()
+        .await
+        .read_secret_resource(resource_desc.clone())
+        .await
+        .context("read from primary repository")?;
+    secondary
+        .write()
+        .await
+        .write_secret_resource(resource_desc, &data)
+        .await
+        .context("write to secondary repository")
+}
+
+fn path(resource_desc: &ResourceDesc) -> String {
+    format!(
+        "{}/{}/{}",
+        resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mirrored(primary: &Path, secondary: &Path) -> Mirrored {
+        let desc: MirroredRepoDesc = serde_json::from_value(json!({
+            "primary": {"type": "LocalFs", "dir_path": primary},
+            "secondary": {"type": "LocalFs", "dir_path": secondary},
+        }))
+        .unwrap();
+        Mirrored::new(&desc).unwrap()
+    }
+
+    fn resource(tag: &str) -> ResourceDesc {
+        ResourceDesc {
+            repository_name: "default".into(),
+            resource_type: "key".into(),
+            resource_tag: tag.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mirror_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (primary, secondary) = (dir.path().join("primary"), dir.path().join("secondary"));
+        let mut repository = mirrored(&primary, &secondary);
+
+        repository
+            .write_secret_resource(resource("1"), b"secret")
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(primary.join("default/key/1")).unwrap(),
+            b"secret"
+        );
+
+        // The copy runs in the background.
+        for _ in 0..50 {
+            if secondary.join("default/key/1").exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            std::fs::read(secondary.join("default/key/1")).unwrap(),
+            b"secret"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_mirror() {
+        let dir = tempfile::tempdir().unwrap();
+        let (primary, secondary) = (dir.path().join("primary"), dir.path().join("secondary"));
+        let repository = mirrored(&primary, &secondary);
+        for (dir, tag, data) in [
+            (&primary, "same", "a"),
+            (&secondary, "same", "a"),
+            (&primary, "different", "b"),
+            (&secondary, "different", "c"),
+            (&primary, "missing", "d"),
+            (&secondary, "extra", "e"),
+        ] {
+            std::fs::create_dir_all(dir.join("default/key")).unwrap();
+            std::fs::write(dir.join("default/key").join(tag), data).unwrap();
+        }
+
+        let report = repository.check_mirror(false).await.unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.missing, ["default/key/missing"]);
+        assert_eq!(report.different, ["default/key/different"]);
+        assert_eq!(report.extra, ["default/key/extra"]);
+        assert_eq!(report.repaired, 0);
+
+        let report = repository.check_mirror(true).await.unwrap();
+        assert_eq!(report.repaired, 2);
+        let report = repository.check_mirror(false).await.unwrap();
+        assert!(report.missing.is_empty() && report.different.is_empty());
+        assert_eq!(
+            std::fs::read(secondary.join("default/key/different")).unwrap(),
+            b"b"
+        );
+    }
+}
@@ -4,21 +4,39 @@
 
 use anyhow::*;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::sync::RwLock;
+use zeroize::Zeroizing;
 
+use crate::namespace;
+
+pub(crate) mod download;
+mod envelope;
+mod generator;
+pub(crate) mod hooks;
 mod local_fs;
+pub(crate) mod mirror;
+pub(crate) mod provision;
+pub(crate) mod quota;
+pub(crate) mod rotation;
 
 #[cfg(feature = "aliyun")]
 mod aliyun_kms;
 
+use mirror::MirrorReport;
+use quota::Usage;
+
 /// Interface of a `Repository`.
 #[async_trait::async_trait]
 pub trait Repository {
     /// Read secret resource from repository.
-    async fn read_secret_resource(&self, resource_desc: ResourceDesc) -> Result<Vec<u8>>;
+    /// The resource is wiped from memory when dropped.
+    async fn read_secret_resource(&self, resource_desc: ResourceDesc)
+        -> Result<Zeroizing<Vec<u8>>>;
 
     /// Write secret resource into repository
     async fn write_secret_resource(
@@ -26,8 +44,80 @@ pub trait Repository {
         resource_desc: ResourceDesc,
         data: &[u8],
     ) -> Result<()>;
+
+    /// Write the secret resource held in the file `path` into repository.
+    /// The file is read into memory unless the repository can store it as
+    /// it is.
+    async fn write_secret_resource_file(
+        &mut self,
+        resource_desc: ResourceDesc,
+        path: &Path,
+    ) -> Result<()> {
+        let data = Zeroizing::new(
+            tokio::fs::read(path)
+                .await
+                .context("read uploaded resource")?,
+        );
+        self.write_secret_resource(resource_desc, &data).await
+    }
+
+    /// Whether large uploads may be received into a plaintext temporary
+    /// file, because the repository stores them in plaintext anyway.
+    async fn spills_uploads(&self) -> bool {
+        false
+    }
+
+    /// When the resource was last written.
+    async fn resource_modified(&self, _resource_desc: ResourceDesc) -> Result<SystemTime> {
+        bail!("The repository does not track when resources were written")
+    }
+
+    /// Replace the resources that are due for rotation with newly generated
+    /// ones, keeping the replaced versions. Returns the resources it tried
+    /// to rotate, with the outcome.
+    async fn rotate_due_resources(&self) -> Vec<(ResourceDesc, Result<()>)> {
+        Vec::new()
+    }
+
+    /// All the resources of the repository.
+    async fn list_resources(&self) -> Result<Vec<ResourceDesc>> {
+        bail!("The repository does not list its resources")
+    }
+
+    /// All the resources of the repository, with the bytes they are stored
+    /// in.
+    async fn stored_sizes(&self) -> Result<Vec<(ResourceDesc, u64)>> {
+        bail!("The repository does not account the size of its resources")
+    }
+
+    /// The resources stored in every repository of the resource paths, by
+    /// name, with their quotas.
+    async fn usage(&self) -> Result<BTreeMap<String, Usage>> {
+        Ok(quota::usage(&self.stored_sizes().await?))
+    }
+
+    /// Compare a mirrored repository with its secondary, copying the missing
+    /// and different resources again with `repair`.
+    async fn check_mirror(&self, _repair: bool) -> Result<MirrorReport> {
+        bail!("The repository is not mirrored")
+    }
+
+    /// Record that the download URL `id`, valid until `exp` in seconds
+    /// since the Unix epoch, is redeemed, unless it was already, atomically
+    /// for the KBS instances sharing the repository. Returns whether it
+    /// wasn't redeemed yet.
+    async fn redeem_download_url(&self, _id: &str, _exp: i64) -> Result<bool> {
+        bail!("The repository does not record the redeemed download URLs")
+    }
+
+    /// Check that the repository is able to serve resources.
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
+/// A resource at `<repository>/<type>/<tag>`, where the repository is a
+/// namespace of one or more segments.
 #[derive(Debug, Clone)]
 pub struct ResourceDesc {
     pub repository_name: String,
@@ -36,9 +126,18 @@ pub struct ResourceDesc {
 }
 
 impl ResourceDesc {
+    /// The resource at the path `<repository>/<type>/<tag>`.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let (repository_name, resource_type, resource_tag) = namespace::split_path(path)?;
+        Some(Self {
+            repository_name: repository_name.to_string(),
+            resource_type: resource_type.to_string(),
+            resource_tag: resource_tag.to_string(),
+        })
+    }
+
     pub fn is_valid(&self) -> bool {
-        if &self.repository_name == "."
-            || &self.repository_name == ".."
+        if !namespace::is_valid_namespace(&self.repository_name)
             || &self.resource_type == "."
             || &self.resource_type == ".."
         {
@@ -55,9 +154,22 @@ pub enum RepositoryConfig {
 
     #[cfg(feature = "aliyun")]
     Aliyun(aliyun_kms::AliyunKmsBackendConfig),
+
+    /// A repository whose writes are copied to a secondary one.
+    Mirrored(mirror::MirroredRepoDesc),
 }
 
 impl RepositoryConfig {
+    /// Whether the repository is kept at the default location of its type.
+    pub fn is_default_location(&self) -> bool {
+        match self {
+            Self::LocalFs(desc) => desc.dir_path.is_none(),
+            #[cfg(feature = "aliyun")]
+            Self::Aliyun(_) => false,
+            Self::Mirrored(desc) => desc.primary.is_default_location(),
+        }
+    }
+
     pub fn initialize(&self) -> Result<Arc<RwLock<dyn Repository + Send + Sync>>> {
         match self {
             Self::LocalFs(desc) => {
@@ -75,14 +187,36 @@ impl RepositoryConfig {
                     fs::create_dir_all(format!("{}/default", &dir_path))?;
                 }
 
-                Ok(Arc::new(RwLock::new(local_fs::LocalFs::new(desc)?))
-                    as Arc<RwLock<dyn Repository + Send + Sync>>)
+                let mut repository: Box<dyn Repository + Send + Sync> =
+                    Box::new(local_fs::LocalFs::new(desc)?);
+                if !desc.generate.is_empty() {
+                    repository = Box::new(generator::Generating::new(
+                        repository,
+                        desc.generate.clone(),
+                    )?);
+                }
+                // The quotas and hooks wrap the generator, so that generated
+                // resources don't go through them. The quotas check what the
+                // hooks let through.
+                if !desc.quotas.is_empty() || desc.total_quota.is_some() {
+                    repository = Box::new(quota::Quota::new(
+                        repository,
+                        &desc.quotas,
+                        desc.total_quota.as_ref(),
+                    )?);
+                }
+                let validating = hooks::Validating::new(repository, &desc.write_hooks)?;
+                Ok(Arc::new(RwLock::new(validating)) as Arc<RwLock<dyn Repository + Send + Sync>>)
             }
             #[cfg(feature = "aliyun")]
             Self::Aliyun(config) => {
                 let client = aliyun_kms::AliyunKmsBackend::new(config)?;
                 Ok(Arc::new(RwLock::new(client)) as Arc<RwLock<dyn Repository + Send + Sync>>)
             }
+            Self::Mirrored(desc) => {
+                let mirrored = mirror::Mirrored::new(desc)?;
+                Ok(Arc::new(RwLock::new(mirrored)) as Arc<RwLock<dyn Repository + Send + Sync>>)
+            }
         }
     }
 }
@@ -104,3 +238,15 @@ pub(crate) async fn set_secret_resource(
         .write_secret_resource(resource_desc, data)
         .await
 }
+
+pub(crate) async fn set_secret_resource_file(
+    repository: &Arc<RwLock<dyn Repository + Send + Sync>>,
+    resource_desc: ResourceDesc,
+    path: &Path,
+) -> Result<()> {
+    repository
+        .write()
+        .await
+        .write_secret_resource_file(resource_desc, path)
+        .await
+}
@@ -0,0 +1,350 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Provisioning of the resources of newly attested workloads.
+//!
+//! On the first successful attestation of a workload identity, the configured
+//! resources are created in the namespace of the identity, e.g.
+//! `workloads/<identity>`, generated or copied from a template resource, so
+//! that onboarding a workload needs no admin. Resources the namespace holds
+//! already are left as they are: a workload keeps its resources across
+//! restarts of KBS, and an admin can register some of them beforehand.
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use zeroize::Zeroizing;
+
+use super::generator::SecretKind;
+use super::{Repository, ResourceDesc};
+use crate::audit::{Actor, AuditEvent, AuditLog};
+use crate::namespace;
+
+/// Placeholder of the identity in the namespace of a workload.
+const IDENTITY_PLACEHOLDER: &str = "{identity}";
+
+fn default_namespace() -> String {
+    "workloads/{identity}".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ProvisioningConfig {
+    /// Namespace of the resources of a workload, `{identity}` being replaced
+    /// by its identity.
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+
+    /// Resources created in the namespace of every workload.
+    pub resources: Vec<ProvisionedResourceConfig>,
+}
+
+/// A resource created in the namespace of every workload, either generated
+/// or copied from a template resource.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ProvisionedResourceConfig {
+    /// `<type>/<tag>` of the resource in the namespace.
+    pub path: String,
+
+    /// Secret generated for every workload.
+    #[serde(default)]
+    pub generate: Option<SecretKind>,
+
+    /// Resource path `<repository>/<type>/<tag>` of the resource copied for
+    /// every workload.
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+impl ProvisioningConfig {
+    fn validate(&self) -> Result<()> {
+        if !self.namespace.contains(IDENTITY_PLACEHOLDER) {
+            bail!(
+                "Provisioning namespace {} doesn't use {IDENTITY_PLACEHOLDER}",
+                self.namespace
+            );
+        }
+        if !namespace::is_valid_namespace(&self.namespace.replace(IDENTITY_PLACEHOLDER, "id")) {
+            bail!("Provisioning namespace {} is invalid", self.namespace);
+        }
+        if self.resources.is_empty() {
+            bail!("Provisioning creates no resource");
+        }
+        for resource in &self.resources {
+            let mut segments = resource.path.split('/');
+            if segments.clone().count() != 2 || segments.any(str::is_empty) {
+                bail!(
+                    "Provisioned resource path {} is not <type>/<tag>",
+                    resource.path
+                );
+            }
+            match (&resource.generate, &resource.template) {
+                (Some(kind), None) => kind.validate(&resource.path)?,
+                (None, Some(template)) => {
+                    if ResourceDesc::from_path(template).is_none() {
+                        bail!(
+                            "Template {template} of {} is not <repository>/<type>/<tag>",
+                            resource.path
+                        );
+                    }
+                }
+                _ => bail!(
+                    "Provisioned resource {} must set one of generate and template",
+                    resource.path
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Provisions the resources of the workloads on their first attestation.
+/// Nothing is provisioned without a configuration.
+#[derive(Default)]
+pub(crate) struct Provisioner {
+    config: Option<ProvisioningConfig>,
+
+    /// Workloads provisioned since KBS started, by tenant and identity.
+    provisioned: scc::HashSet<(Option<String>, String)>,
+}
+
+impl Provisioner {
+    pub fn new(config: Option<&ProvisioningConfig>) -> Result<Self> {
+        if let Some(config) = config {
+            config.validate()?;
+        }
+        Ok(Self {
+            config: config.cloned(),
+            provisioned: scc::HashSet::new(),
+        })
+    }
+
+    /// Create the missing resources of the workload `identity` of `tenant`
+    /// in `repository`, unless it was provisioned since KBS started. Returns
+    /// the resources created.
+    pub async fn provision(
+        &self,
+        repository: &Arc<RwLock<dyn Repository + Send + Sync>>,
+        tenant: Option<&str>,
+        identity: &str,
+    ) -> Result<Vec<ResourceDesc>> {
+        let Some(config) = &self.config else {
+            return Ok(Vec::new());
+        };
+        let key = (tenant.map(str::to_string), identity.to_string());
+        if self.provisioned.insert_async(key.clone()).await.is_err() {
+            return Ok(Vec::new());
+        }
+
+        let result = provision(config, repository, identity).await;
+        if result.is_err() {
+            // Try again on the next attestation.
+            self.provisioned.remove_async(&key).await;
+        }
+        result
+    }
+}
+
+async fn provision(
+    config: &ProvisioningConfig,
+    repository: &Arc<RwLock<dyn Repository + Send + Sync>>,
+    identity: &str,
+) -> Result<Vec<ResourceDesc>> {
+    let namespace = config.namespace.replace(IDENTITY_PLACEHOLDER, identity);
+    if !namespace::is_valid_namespace(&namespace) {
+        bail!("Workload identity {identity} doesn't make a valid namespace");
+    }
+
+    let mut created = Vec::new();
+    for resource in &config.resources {
+        let (resource_type, resource_tag) = resource
+            .path
+            .split_once('/')
+            .context("invalid provisioned resource path")?;
+        let resource_desc = ResourceDesc {
+            repository_name: namespace.clone(),
+            resource_type: resource_type.to_string(),
+            resource_tag: resource_tag.to_string(),
+        };
+        if repository
+            .read()
+            .await
+            .read_secret_resource(resource_desc.clone())
+            .await
+            .is_ok()
+        {
+            continue;
+        }
+
+        let data: Zeroizing<Vec<u8>> = match (&resource.generate, &resource.template) {
+            (Some(kind), _) => kind.generate()?,
+            (None, Some(template)) => {
+                let template_desc =
+                    ResourceDesc::from_path(template).context("invalid template path")?;
+                repository
+                    .read()
+                    .await
+                    .read_secret_resource(template_desc)
+                    .await
+                    .with_context(|| format!("read template {template}"))?
+            }
+            (None, None) => bail!("Provisioned resource {} has no source", resource.path),
+        };
+        repository
+            .write()
+            .await
+            .write_secret_resource(resource_desc.clone(), &data)
+            .await
+            .with_context(|| format!("write {namespace}/{}", resource.path))?;
+        created.push(resource_desc);
+    }
+
+    if !created.is_empty() {
+        info!(
+            "Provisioned {} resources of workload {identity} in {namespace}",
+            created.len()
+        );
+    }
+    Ok(created)
+}
+
+/// Provision the resources of the workload `identity` of `tenant`, recording
+/// it in the audit log as `audit_event` if anything was created or failed.
+/// A failure is only logged, the attestation of the workload still succeeds.
+pub(crate) async fn provision_workload(
+    provisioner: &Provisioner,
+    repository: &Arc<RwLock<dyn Repository + Send + Sync>>,
+    tenant: Option<&str>,
+    identity: &str,
+    audit: &AuditLog,
+    audit_event: AuditEvent,
+) {
+    let result = provisioner.provision(repository, tenant, identity).await;
+    let resources = match &result {
+        Ok(created) if created.is_empty() => return,
+        Ok(created) => created.iter().map(path).collect(),
+        Err(e) => {
+            warn!("Failed to provision the resources of workload {identity}: {e:#}");
+            Vec::new()
+        }
+    };
+
+    let mut event = audit_event
+        .actor(Actor::attester(Some(identity.to_string())))
+        .detail("resources", Value::from(resources))
+        .result(&result.map_err(|e| format!("{e:#}")));
+    if let Some(tenant) = tenant {
+        event = event.detail("tenant", tenant);
+    }
+    audit.record(event).await;
+}
+
+fn path(resource_desc: &ResourceDesc) -> String {
+    format!(
+        "{}/{}/{}",
+        resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::local_fs::{LocalFs, LocalFsRepoDesc};
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn config(value: Value) -> ProvisioningConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[rstest]
+    #[case("w/{identity}", json!([{"path": "key/db", "generate": {"type": "Random"}}]), true)]
+    #[case("w/{identity}", json!([{"path": "cert/ca", "template": "templates/cert/ca"}]), true)]
+    #[case("fleet/{identity}/", json!([{"path": "key/db", "generate": {"type": "Random"}}]), false)]
+    #[case("fleet", json!([{"path": "key/db", "generate": {"type": "Random"}}]), false)]
+    #[case("w/{identity}", json!([]), false)]
+    #[case("w/{identity}", json!([{"path": "db", "generate": {"type": "Random"}}]), false)]
+    #[case("w/{identity}", json!([{"path": "key/db"}]), false)]
+    #[case("w/{identity}", json!([{"path": "cert/ca", "template": "ca"}]), false)]
+    #[case(
+        "w/{identity}",
+        json!([{"path": "key/db", "generate": {"type": "Random"}, "template": "a/b/c"}]),
+        false
+    )]
+    fn test_validate(#[case] namespace: &str, #[case] resources: Value, #[case] valid: bool) {
+        let config = config(json!({"namespace": namespace, "resources": resources}));
+        assert_eq!(Provisioner::new(Some(&config)).is_ok(), valid);
+    }
+
+    #[tokio::test]
+    async fn test_provision() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository: Arc<RwLock<dyn Repository + Send + Sync>> = Arc::new(RwLock::new(
+            LocalFs::new(&LocalFsRepoDesc {
+                dir_path: Some(dir.path().to_string_lossy().to_string()),
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        std::fs::create_dir_all(dir.path().join("templates/cert")).unwrap();
+        std::fs::write(dir.path().join("templates/cert/ca"), "ca").unwrap();
+        std::fs::create_dir_all(dir.path().join("workloads/tdx/b/key")).unwrap();
+        std::fs::write(dir.path().join("workloads/tdx/b/key/db"), "registered").unwrap();
+
+        let provisioner = Provisioner::new(Some(&config(json!({
+            "resources": [
+                {"path": "key/db", "generate": {"type": "Random", "length": 16}},
+                {"path": "cert/ca", "template": "templates/cert/ca"},
+            ],
+        }))))
+        .unwrap();
+
+        let created = provisioner
+            .provision(&repository, None, "tdx/a")
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 2);
+        assert_eq!(
+            std::fs::read(dir.path().join("workloads/tdx/a/key/db"))
+                .unwrap()
+                .len(),
+            16
+        );
+        assert_eq!(
+            std::fs::read(dir.path().join("workloads/tdx/a/cert/ca")).unwrap(),
+            b"ca"
+        );
+
+        // A workload is provisioned once.
+        std::fs::remove_file(dir.path().join("workloads/tdx/a/cert/ca")).unwrap();
+        assert!(provisioner
+            .provision(&repository, None, "tdx/a")
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Registered resources are kept.
+        let created = provisioner
+            .provision(&repository, None, "tdx/b")
+            .await
+            .unwrap();
+        assert_eq!(created.len(), 1);
+        assert_eq!(
+            std::fs::read(dir.path().join("workloads/tdx/b/key/db")).unwrap(),
+            b"registered"
+        );
+
+        assert!(provisioner
+            .provision(&repository, None, "../a")
+            .await
+            .is_err());
+        assert!(Provisioner::default()
+            .provision(&repository, None, "tdx/c")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,473 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Quotas of the resources stored in the repositories of a backend.
+//!
+//! The resources and bytes stored under every `<repository>` of the
+//! resource paths are accounted from what the backend stores, so that the
+//! resources generated, rotated or copied in count as well. A repository
+//! accounts the resources of the repositories nested in it, e.g. `a` those of
+//! `a/b`, and its quota limits them all, so that a quota can't be escaped by
+//! writing to a new nested repository. The total quota limits every resource
+//! of the backend, so that the repositories of new names can't exhaust the
+//! storage either. Only the writes of the admins are checked against the
+//! quotas: a write that would take a repository over its quota is refused,
+//! while replacing a resource only accounts the difference in size.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use super::{Repository, ResourceDesc};
+use crate::namespace::{ancestors, is_valid_namespace};
+
+fn any_repository() -> String {
+    "*".to_string()
+}
+
+/// A write refused by a quota.
+#[derive(Debug, Error)]
+#[error("Quota of {scope} exceeded: {reason}")]
+pub struct QuotaExceeded {
+    /// What the quota limits, e.g. `repository a/b`.
+    pub scope: String,
+    pub reason: String,
+}
+
+/// The quota of the repositories named `repository`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct QuotaConfig {
+    /// Name of the repository, or `*` for every top-level repository, each
+    /// with a quota of its own. The quota limits the repositories nested in
+    /// the repository as well.
+    #[serde(default = "any_repository")]
+    pub repository: String,
+
+    /// Most resources stored in the repository.
+    #[serde(default)]
+    pub max_resources: Option<u64>,
+
+    /// Most bytes stored in the repository.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+/// The quota of all the resources of a backend.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TotalQuotaConfig {
+    /// Most resources stored in the backend.
+    #[serde(default)]
+    pub max_resources: Option<u64>,
+
+    /// Most bytes stored in the backend.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+/// The resources stored in a repository, with its quota.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Usage {
+    pub resources: u64,
+    pub bytes: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_resources: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+/// The usage of every repository of the stored `sizes`, by name, with the
+/// resources of the repositories nested in it.
+pub fn usage(sizes: &[(ResourceDesc, u64)]) -> BTreeMap<String, Usage> {
+    let mut usage = BTreeMap::<String, Usage>::new();
+    for (resource_desc, size) in sizes {
+        for repository in ancestors(&resource_desc.repository_name) {
+            let repository = usage.entry(repository.to_string()).or_default();
+            repository.resources += 1;
+            repository.bytes += size;
+        }
+    }
+    usage
+}
+
+/// Fail with `QuotaExceeded` of `scope` if `resources` and `bytes` are over
+/// `max_resources` or `max_bytes`.
+fn check_limits(
+    scope: impl Fn() -> String,
+    (resources, bytes): (u64, u64),
+    max_resources: Option<u64>,
+    max_bytes: Option<u64>,
+) -> Result<()> {
+    let exceeded = |reason: String| QuotaExceeded {
+        scope: scope(),
+        reason,
+    };
+    if let Some(max_resources) = max_resources {
+        if resources > max_resources {
+            bail!(exceeded(format!(
+                "{resources} resources, at most {max_resources} allowed"
+            )));
+        }
+    }
+    if let Some(max_bytes) = max_bytes {
+        if bytes > max_bytes {
+            bail!(exceeded(format!(
+                "{bytes} bytes, at most {max_bytes} allowed"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// A repository refusing the admin writes over the quotas.
+pub struct Quota {
+    inner: Box<dyn Repository + Send + Sync>,
+    quotas: Vec<QuotaConfig>,
+    total: Option<TotalQuotaConfig>,
+}
+
+impl Quota {
+    pub fn new(
+        inner: Box<dyn Repository + Send + Sync>,
+        quotas: &[QuotaConfig],
+        total: Option<&TotalQuotaConfig>,
+    ) -> Result<Self> {
+        for quota in quotas {
+            if quota.repository != "*" && !is_valid_namespace(&quota.repository) {
+                bail!("invalid quota repository `{}`", quota.repository);
+            }
+            if quota.max_resources.is_none() && quota.max_bytes.is_none() {
+                bail!(
+                    "quota of repository `{}` sets neither max_resources nor max_bytes",
+                    quota.repository
+                );
+            }
+        }
+        if let Some(total) = total {
+            if total.max_resources.is_none() && total.max_bytes.is_none() {
+                bail!("total quota sets neither max_resources nor max_bytes");
+            }
+        }
+
+        Ok(Self {
+            inner,
+            quotas: quotas.to_vec(),
+            total: total.cloned(),
+        })
+    }
+
+    /// The quota of `repository`: the first one naming it, or else the first
+    /// one of every top-level repository if it is one.
+    fn quota_of(&self, repository: &str) -> Option<&QuotaConfig> {
+        self.quotas
+            .iter()
+            .find(|quota| quota.repository == repository)
+            .or_else(|| {
+                self.quotas
+                    .iter()
+                    .filter(|_| !repository.contains('/'))
+                    .find(|quota| quota.repository == "*")
+            })
+    }
+
+    /// Check that writing `size` bytes as `resource_desc` keeps the backend,
+    /// and every repository enclosing the one of the resource, within their
+    /// quotas.
+    async fn check(&self, resource_desc: &ResourceDesc, size: u64) -> Result<()> {
+        let repositories = ancestors(&resource_desc.repository_name);
+        let quotas: Vec<_> = repositories
+            .iter()
+            .filter_map(|repository| Some((*repository, self.quota_of(repository)?)))
+            .collect();
+        if quotas.is_empty() && self.total.is_none() {
+            return Ok(());
+        }
+
+        // The resource being replaced is not accounted.
+        let mut stored = self.inner.stored_sizes().await?;
+        stored.retain(|(stored, _)| {
+            stored.repository_name != resource_desc.repository_name
+                || stored.resource_type != resource_desc.resource_type
+                || stored.resource_tag != resource_desc.resource_tag
+        });
+        let usage = usage(&stored);
+
+        if let Some(total) = &self.total {
+            let (resources, bytes) = stored
+                .iter()
+                .fold((1, size), |(resources, bytes), (_, stored_size)| {
+                    (resources + 1, bytes + stored_size)
+                });
+            check_limits(
+                || "the repositories".to_string(),
+                (resources, bytes),
+                total.max_resources,
+                total.max_bytes,
+            )?;
+        }
+        for (repository, quota) in quotas {
+            let usage = usage.get(repository).cloned().unwrap_or_default();
+            check_limits(
+                || format!("repository {repository}"),
+                (usage.resources + 1, usage.bytes + size),
+                quota.max_resources,
+                quota.max_bytes,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Repository for Quota {
+    async fn read_secret_resource(
+        &self,
+        resource_desc: ResourceDesc,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        self.inner.read_secret_resource(resource_desc).await
+    }
+
+    async fn write_secret_resource(
+        &mut self,
+        resource_desc: ResourceDesc,
+        data: &[u8],
+    ) -> Result<()> {
+        self.check(&resource_desc, data.len() as u64).await?;
+        self.inner.write_secret_resource(resource_desc, data).await
+    }
+
+    async fn write_secret_resource_file(
+        &mut self,
+        resource_desc: ResourceDesc,
+        path: &Path,
+    ) -> Result<()> {
+        let size = tokio::fs::metadata(path).await?.len();
+        self.check(&resource_desc, size).await?;
+        self.inner
+            .write_secret_resource_file(resource_desc, path)
+            .await
+    }
+
+    async fn spills_uploads(&self) -> bool {
+        self.inner.spills_uploads().await
+    }
+
+    async fn resource_modified(&self, resource_desc: ResourceDesc) -> Result<SystemTime> {
+        self.inner.resource_modified(resource_desc).await
+    }
+
+    async fn rotate_due_resources(&self) -> Vec<(ResourceDesc, Result<()>)> {
+        self.inner.rotate_due_resources().await
+    }
+
+    async fn list_resources(&self) -> Result<Vec<ResourceDesc>> {
+        self.inner.list_resources().await
+    }
+
+    async fn stored_sizes(&self) -> Result<Vec<(ResourceDesc, u64)>> {
+        self.inner.stored_sizes().await
+    }
+
+    /// The usage of the repositories with a quota, even without resources.
+    async fn usage(&self) -> Result<BTreeMap<String, Usage>> {
+        let mut usage = usage(&self.inner.stored_sizes().await?);
+        for quota in &self.quotas {
+            if quota.repository != "*" {
+                usage.entry(quota.repository.clone()).or_default();
+            }
+        }
+        for (repository, usage) in usage.iter_mut() {
+            if let Some(quota) = self.quota_of(repository) {
+                usage.max_resources = quota.max_resources;
+                usage.max_bytes = quota.max_bytes;
+            }
+        }
+        Ok(usage)
+    }
+
+    async fn redeem_download_url(&self, id: &str, exp: i64) -> Result<bool> {
+        self.inner.redeem_download_url(id, exp).await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.inner.health_check().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::local_fs::{LocalFs, LocalFsRepoDesc};
+    use rstest::rstest;
+
+    fn resource(path: &str) -> ResourceDesc {
+        ResourceDesc::from_path(path).unwrap()
+    }
+
+    fn quota(dir: &Path, quotas: &str, total: Option<&str>) -> Quota {
+        let local_fs = LocalFs::new(&LocalFsRepoDesc {
+            dir_path: Some(dir.to_string_lossy().to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let quotas: Vec<QuotaConfig> = serde_json::from_str(quotas).unwrap();
+        let total: Option<TotalQuotaConfig> =
+            total.map(|total| serde_json::from_str(total).unwrap());
+        Quota::new(Box::new(local_fs), &quotas, total.as_ref()).unwrap()
+    }
+
+    #[rstest]
+    #[case(r#"[{"max_resources": 2}]"#, "default/key/c", false)]
+    #[case(r#"[{"max_resources": 2}]"#, "default/key/a", true)]
+    #[case(r#"[{"max_resources": 2}]"#, "other/key/c", true)]
+    #[case(r#"[{"max_bytes": 10}]"#, "default/key/c", true)]
+    #[case(r#"[{"max_bytes": 9}]"#, "default/key/c", false)]
+    #[case(r#"[{"max_bytes": 7}]"#, "default/key/b", true)]
+    #[case(
+        r#"[{"repository": "default", "max_bytes": 100}, {"max_resources": 2}]"#,
+        "default/key/c",
+        true
+    )]
+    #[case(
+        r#"[{"repository": "other", "max_resources": 1}]"#,
+        "default/key/c",
+        true
+    )]
+    #[case(r#"[{"max_resources": 2}]"#, "default/team/key/c", false)]
+    #[case(
+        r#"[{"repository": "default", "max_resources": 2}]"#,
+        "default/team/key/c",
+        false
+    )]
+    #[case(
+        r#"[{"repository": "default/team", "max_resources": 1}, {"max_resources": 3}]"#,
+        "default/team/key/c",
+        true
+    )]
+    #[case(
+        r#"[{"repository": "default/team", "max_bytes": 1}]"#,
+        "default/team/key/c",
+        false
+    )]
+    #[tokio::test]
+    async fn test_quota(#[case] quotas: &str, #[case] path: &str, #[case] accepted: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut repository = quota(dir.path(), quotas, None);
+        for (path, data) in [("default/key/a", "1234"), ("default/key/b", "1234")] {
+            repository
+                .inner
+                .write_secret_resource(resource(path), data.as_bytes())
+                .await
+                .unwrap();
+        }
+
+        let result = repository
+            .write_secret_resource(resource(path), b"12")
+            .await;
+        assert_eq!(result.is_ok(), accepted);
+        if let Err(e) = result {
+            assert!(e.downcast_ref::<QuotaExceeded>().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut repository = quota(
+            dir.path(),
+            r#"[{"repository": "empty", "max_resources": 1}, {"max_bytes": 100}]"#,
+            None,
+        );
+        repository
+            .write_secret_resource(resource("default/key/a"), b"1234")
+            .await
+            .unwrap();
+        repository
+            .write_secret_resource(resource("default/cert/b"), b"12")
+            .await
+            .unwrap();
+        repository
+            .write_secret_resource(resource("default/team/key/c"), b"1")
+            .await
+            .unwrap();
+
+        let usage = repository.usage().await.unwrap();
+        assert_eq!(
+            usage["default"],
+            Usage {
+                resources: 3,
+                bytes: 7,
+                max_resources: None,
+                max_bytes: Some(100),
+            }
+        );
+        assert_eq!(
+            usage["default/team"],
+            Usage {
+                resources: 1,
+                bytes: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            usage["empty"],
+            Usage {
+                max_resources: Some(1),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_total_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut repository = quota(
+            dir.path(),
+            r#"[{"max_resources": 2}]"#,
+            Some(r#"{"max_resources": 3}"#),
+        );
+        for path in ["a/key/1", "b/key/1", "c/key/1"] {
+            repository
+                .write_secret_resource(resource(path), b"1")
+                .await
+                .unwrap();
+        }
+
+        // The repositories of new names are limited by the total quota.
+        let result = repository
+            .write_secret_resource(resource("d/key/1"), b"1")
+            .await;
+        assert!(result
+            .unwrap_err()
+            .downcast_ref::<QuotaExceeded>()
+            .is_some());
+        repository
+            .write_secret_resource(resource("a/key/1"), b"2")
+            .await
+            .unwrap();
+    }
+
+    #[rstest]
+    #[case(r#"[{"repository": "default"}]"#, None)]
+    #[case(r#"[{"repository": "a//b", "max_bytes": 1}]"#, None)]
+    #[case(r#"[{"repository": "a/../b", "max_bytes": 1}]"#, None)]
+    #[case("[]", Some("{}"))]
+    fn test_invalid_quota(#[case] quotas: &str, #[case] total: Option<&str>) {
+        let dir = tempfile::tempdir().unwrap();
+        let local_fs = LocalFs::new(&LocalFsRepoDesc {
+            dir_path: Some(dir.path().to_string_lossy().to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let quotas: Vec<QuotaConfig> = serde_json::from_str(quotas).unwrap();
+        let total: Option<TotalQuotaConfig> =
+            total.map(|total| serde_json::from_str(total).unwrap());
+        assert!(Quota::new(Box::new(local_fs), &quotas, total.as_ref()).is_err());
+    }
+}
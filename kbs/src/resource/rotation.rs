@@ -0,0 +1,101 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Scheduler of the rotation of generated resources.
+//!
+//! The repositories of the default tenant and of every other tenant are asked
+//! every minute to rotate the resources that are due. Every rotation is
+//! recorded in the audit log and notified to the subscribed webhooks.
+
+use actix_web::web;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex, RwLock};
+use tokio::task::JoinHandle;
+
+use super::{Repository, ResourceDesc};
+use crate::audit::{Actor, AuditEvent, AuditEventType, AuditLog};
+use crate::reload::Reloadable;
+use crate::tenant::Tenants;
+
+/// Time between two checks for due rotations.
+const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The running scheduler, checking for due rotations until stopped.
+pub(crate) struct Scheduler {
+    stop: watch::Sender<()>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    /// Stop checking for due rotations, waiting for the rotations in
+    /// progress to complete so that they are recorded in the audit log.
+    pub(crate) async fn stop(&self) {
+        let _ = self.stop.send(());
+        if let Some(task) = self.task.lock().await.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+/// Check the current repositories for due rotations until the returned
+/// scheduler is stopped. Repositories replaced by a reload are picked up on
+/// the next check.
+pub(crate) fn spawn(
+    repository: web::Data<Reloadable<Arc<RwLock<dyn Repository + Send + Sync>>>>,
+    tenants: web::Data<Reloadable<Arc<Tenants>>>,
+    audit: web::Data<AuditLog>,
+) -> Scheduler {
+    let (stop, mut stopped) = watch::channel(());
+    let task = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(ROTATION_CHECK_INTERVAL) => {},
+                _ = stopped.changed() => break,
+            }
+
+            rotate_due_resources(&repository.get(), None, &audit).await;
+            for tenant in tenants.get().iter() {
+                rotate_due_resources(&tenant.repository, Some(&tenant.id), &audit).await;
+            }
+        }
+    });
+    Scheduler {
+        stop,
+        task: Mutex::new(Some(task)),
+    }
+}
+
+async fn rotate_due_resources(
+    repository: &Arc<RwLock<dyn Repository + Send + Sync>>,
+    tenant: Option<&str>,
+    audit: &AuditLog,
+) {
+    let rotated = repository.read().await.rotate_due_resources().await;
+    for (resource_desc, result) in rotated {
+        if let Err(e) = &result {
+            log::warn!("Failed to rotate resource {}: {e:#}", path(&resource_desc));
+        }
+
+        let mut event = AuditEvent::from_peer(
+            AuditEventType::ResourceRotation,
+            uuid::Uuid::new_v4().to_string(),
+            None,
+        )
+        .actor(Actor::kbs())
+        .detail("resource", path(&resource_desc))
+        .result(&result);
+        if let Some(tenant) = tenant {
+            event = event.detail("tenant", tenant);
+        }
+        audit.record(event).await;
+    }
+}
+
+fn path(resource_desc: &ResourceDesc) -> String {
+    format!(
+        "{}/{}/{}",
+        resource_desc.repository_name, resource_desc.resource_type, resource_desc.resource_tag
+    )
+}
@@ -6,27 +6,107 @@ use actix_web::cookie::{
     time::{Duration, OffsetDateTime},
     Cookie,
 };
-use anyhow::Result;
-use kbs_types::{Challenge, Request};
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use kbs_types::{Challenge, Request, Tee};
 use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
+use crate::attestation::detect::TeeSelector;
+use crate::claims::passed_policies;
+use crate::crypto;
+use crate::identity::identity_of;
+
 pub(crate) static KBS_SESSION_ID: &str = "kbs-session-id";
 
+/// Evidence of the issued tokens kept in total to verify it again. The
+/// evidence of the attestations beyond it isn't kept.
+const MAX_EVIDENCE_BYTES: usize = 64 << 20;
+
+lazy_static! {
+    /// Key of the session handles, random to every KBS process as the
+    /// sessions are.
+    static ref SESSION_HANDLE_KEY: [u8; 32] = rand::random();
+}
+
+/// Opaque handle of the session `id`. The logs and the audit records name a
+/// session by its handle, as whoever knows the ID itself, i.e. the value of
+/// the session cookie, can use the session.
+pub(crate) fn session_handle(id: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(*SESSION_HANDLE_KEY)
+        .chain_update(id)
+        .finalize();
+    hex::encode(&digest[..16])
+}
+
+/// Hex encoded SHA-256 digest of the attestation results `token`.
+pub(crate) fn token_digest(token: &str) -> String {
+    hex::encode(Sha256::digest(token))
+}
+
+/// Expiry of the attestation results `token`, from its `exp` claim if it is
+/// a JWT.
+pub(crate) fn token_expiry(token: &str) -> Option<OffsetDateTime> {
+    let payload = URL_SAFE_NO_PAD.decode(token.split('.').nth(1)?).ok()?;
+    let claims: Value = serde_json::from_slice(&payload).ok()?;
+    OffsetDateTime::from_unix_timestamp(claims.get("exp")?.as_i64()?).ok()
+}
+
+/// The request of an attester starting a session, i.e. a [`Request`] whose
+/// `tee` may be `auto` for the TEE to be detected from the evidence.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct AuthRequest {
+    pub version: String,
+    pub tee: TeeSelector,
+    #[serde(rename = "extra-params")]
+    pub extra_params: String,
+}
+
+impl From<Request> for AuthRequest {
+    fn from(request: Request) -> Self {
+        Self {
+            version: request.version,
+            tee: TeeSelector::Tee(request.tee),
+            extra_params: request.extra_params,
+        }
+    }
+}
+
 /// Finite State Machine model for RCAR handshake
 pub(crate) enum SessionStatus {
     Authed {
-        request: Request,
+        request: AuthRequest,
         challenge: Challenge,
         id: String,
+        created_at: OffsetDateTime,
         timeout: OffsetDateTime,
+        tenant: Option<String>,
+
+        /// Workload identity of the attested session the attester started
+        /// this one from. The latest token of the identity is given to the
+        /// attestation policy as the one of its prior attestation, and the
+        /// attester must be attested as the same workload.
+        prior_identity: Option<String>,
     },
 
     Attested {
         attestation_claims: String,
         token: String,
+        tee: Tee,
         id: String,
+        created_at: OffsetDateTime,
         timeout: OffsetDateTime,
+        attested_at: OffsetDateTime,
+        tenant: Option<String>,
     },
 }
 
@@ -50,16 +130,29 @@ macro_rules! impl_member {
 }
 
 impl SessionStatus {
-    pub fn auth(request: Request, timeout: i64, challenge: Challenge) -> Result<Self> {
+    /// Start the session of an attester of `tenant`, or of the default tenant
+    /// when `None`. `prior_identity` is the workload identity the attester was
+    /// attested as before, if any.
+    pub fn auth(
+        request: AuthRequest,
+        timeout: i64,
+        challenge: Challenge,
+        tenant: Option<String>,
+        prior_identity: Option<String>,
+    ) -> Result<Self> {
         let id = Uuid::new_v4().as_simple().to_string();
 
-        let timeout = OffsetDateTime::now_utc() + Duration::minutes(timeout);
+        let created_at = OffsetDateTime::now_utc();
+        let timeout = created_at + Duration::minutes(timeout);
 
         Ok(Self::Authed {
             request,
             challenge,
             id,
+            created_at,
             timeout,
+            tenant,
+            prior_identity,
         })
     }
 
@@ -76,23 +169,52 @@ impl SessionStatus {
         }
     }
 
-    impl_member!(request, Request, Authed);
+    impl_member!(request, AuthRequest, Authed);
     impl_member!(challenge, Challenge, Authed);
     impl_member!(id, str);
+    impl_member!(created_at, OffsetDateTime);
     impl_member!(timeout, OffsetDateTime);
 
+    pub fn tenant(&self) -> Option<&str> {
+        match self {
+            SessionStatus::Authed { tenant, .. } => tenant.as_deref(),
+            SessionStatus::Attested { tenant, .. } => tenant.as_deref(),
+        }
+    }
+
+    /// Workload identity the attester of a session waiting for its
+    /// attestation was attested as before.
+    pub fn prior_identity(&self) -> Option<&str> {
+        match self {
+            SessionStatus::Authed { prior_identity, .. } => prior_identity.as_deref(),
+            SessionStatus::Attested { .. } => None,
+        }
+    }
+
     pub fn is_expired(&self) -> bool {
         return *self.timeout() < OffsetDateTime::now_utc();
     }
 
-    pub fn attest(&mut self, attestation_claims: String, token: String) {
+    /// Mark the session attested with the evidence of `tee`, whose
+    /// verification issued `token` with `attestation_claims`.
+    pub fn attest(&mut self, tee: Tee, attestation_claims: String, token: String) {
         match self {
-            SessionStatus::Authed { id, timeout, .. } => {
+            SessionStatus::Authed {
+                id,
+                created_at,
+                timeout,
+                tenant,
+                ..
+            } => {
                 *self = SessionStatus::Attested {
                     attestation_claims,
                     token,
+                    tee,
                     id: id.clone(),
+                    created_at: *created_at,
                     timeout: *timeout,
+                    attested_at: OffsetDateTime::now_utc(),
+                    tenant: tenant.take(),
                 };
             }
             SessionStatus::Attested { .. } => {
@@ -102,18 +224,660 @@ impl SessionStatus {
     }
 }
 
+/// An attestation whose evidence is kept until its token expires, to verify
+/// it again when the attestation policies or reference values change.
+#[cfg(feature = "as")]
+#[derive(Clone)]
+pub(crate) struct IssuedToken {
+    /// Handle of the session the token was issued in.
+    pub session_handle: String,
+    pub tenant: Option<String>,
+    pub tee: Tee,
+    pub evidence: String,
+    pub attestation_claims: String,
+    pub attested_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+}
+
+/// Summary of a session, for the admins.
+#[derive(Debug, Serialize)]
+pub(crate) struct SessionSummary {
+    /// Opaque handle of the session, as in the logs and the audit records.
+    /// The session ID itself is only known to the client, as it grants the
+    /// use of the session.
+    pub handle: String,
+
+    /// TEE of the session, `auto` until an attester requesting its detection
+    /// is attested.
+    pub tee: TeeSelector,
+    pub attested: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub attested_at: Option<OffsetDateTime>,
+
+    /// IDs of the attestation policies the evidence of an attested session
+    /// passed.
+    pub policies: Vec<String>,
+
+    /// Workload identity of the attester of an attested session, if it has
+    /// one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workload_identity: Option<String>,
+}
+
+impl SessionSummary {
+    fn of(session: &SessionStatus) -> Self {
+        let (tee, attested_at, policies, workload_identity) = match session {
+            SessionStatus::Authed { request, .. } => (request.tee, None, Vec::new(), None),
+            SessionStatus::Attested {
+                tee,
+                attested_at,
+                attestation_claims,
+                ..
+            } => {
+                let claims: Value = serde_json::from_str(attestation_claims).unwrap_or_default();
+                let policies = passed_policies(&claims)
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect();
+                (
+                    TeeSelector::Tee(*tee),
+                    Some(*attested_at),
+                    policies,
+                    identity_of(&claims),
+                )
+            }
+        };
+        Self {
+            handle: session_handle(session.id()),
+            tee,
+            attested: attested_at.is_some(),
+            created_at: *session.created_at(),
+            expires_at: *session.timeout(),
+            attested_at,
+            policies,
+            workload_identity,
+        }
+    }
+}
+
+/// Records the nonces of the challenges already answered, until their
+/// session expires.
+#[async_trait::async_trait]
+pub(crate) trait NonceStore: Send + Sync {
+    /// Mark `nonce` as answered until `expires`, unless it already was,
+    /// atomically for the KBS instances sharing the store. Returns whether
+    /// it wasn't answered yet.
+    async fn consume(&self, nonce: &str, expires: OffsetDateTime) -> Result<bool>;
+
+    /// Remove the nonces whose session expired.
+    async fn prune(&self) -> Result<()>;
+}
+
+/// The nonces answered on this KBS instance.
+#[derive(Default)]
+pub(crate) struct MemoryNonceStore {
+    nonces: scc::HashMap<String, OffsetDateTime>,
+}
+
+#[async_trait::async_trait]
+impl NonceStore for MemoryNonceStore {
+    async fn consume(&self, nonce: &str, expires: OffsetDateTime) -> Result<bool> {
+        Ok(self
+            .nonces
+            .insert_async(nonce.to_string(), expires)
+            .await
+            .is_ok())
+    }
+
+    async fn prune(&self) -> Result<()> {
+        let now = OffsetDateTime::now_utc();
+        self.nonces.retain_async(|_, expires| *expires > now).await;
+        Ok(())
+    }
+}
+
+/// The nonces answered on the KBS instances sharing a directory. A nonce is
+/// marked by a file named after its digest, holding the Unix timestamp its
+/// session expires at, and created exclusively so that only one instance
+/// accepts it.
+pub(crate) struct DirNonceStore {
+    dir: PathBuf,
+}
+
+impl DirNonceStore {
+    pub fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("create nonce store dir {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl NonceStore for DirNonceStore {
+    async fn consume(&self, nonce: &str, expires: OffsetDateTime) -> Result<bool> {
+        let mark = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.dir.join(hex::encode(Sha256::digest(nonce))))
+            .await;
+        match mark {
+            Ok(mut mark) => {
+                mark.write_all(expires.unix_timestamp().to_string().as_bytes())
+                    .await
+                    .context("write nonce mark")?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e).context("create nonce mark"),
+        }
+    }
+
+    /// A mark without an expiration yet is being created, and one that is
+    /// gone was removed by another KBS instance.
+    async fn prune(&self) -> Result<()> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(expires) = tokio::fs::read_to_string(entry.path()).await else {
+                continue;
+            };
+            if expires.parse::<i64>().is_ok_and(|expires| expires <= now) {
+                match tokio::fs::remove_file(entry.path()).await {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 pub(crate) struct SessionMap {
     pub sessions: scc::HashMap<String, SessionStatus>,
+
+    /// Nonces of the challenges already answered, shared with the other KBS
+    /// instances if their store is.
+    consumed_nonces: Arc<dyn NonceStore>,
+
+    /// Attestations of the unexpired tokens, by digest of their token.
+    issued: scc::HashMap<String, IssuedToken>,
+
+    /// Size of the evidence of `issued`.
+    evidence_bytes: AtomicUsize,
+
+    /// Token of the latest attestation of every workload identity, by tenant
+    /// and identity, until the session it was issued in expires.
+    prior_tokens: scc::HashMap<(Option<String>, String), (String, OffsetDateTime)>,
+    draining: AtomicBool,
 }
 
 impl SessionMap {
     pub fn new() -> Self {
+        Self::with_nonce_store(Arc::new(MemoryNonceStore::default()))
+    }
+
+    /// Sessions recording the answered nonces in `consumed_nonces`.
+    pub fn with_nonce_store(consumed_nonces: Arc<dyn NonceStore>) -> Self {
         SessionMap {
             sessions: scc::HashMap::new(),
+            consumed_nonces,
+            issued: scc::HashMap::new(),
+            evidence_bytes: AtomicUsize::new(0),
+            prior_tokens: scc::HashMap::new(),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Stop accepting new sessions, as KBS is shutting down.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Number of unexpired sessions that still wait for their attestation.
+    pub async fn pending_handshakes(&self) -> usize {
+        let mut pending = 0;
+        self.sessions
+            .scan_async(|_, v| {
+                if matches!(v, SessionStatus::Authed { .. }) && !v.is_expired() {
+                    pending += 1;
+                }
+            })
+            .await;
+        pending
+    }
+
+    /// Keep the attestation `issued` of `token` until the token expires.
+    /// Returns false if its evidence is over the size of the evidence kept.
+    #[cfg(feature = "as")]
+    pub async fn issue(&self, token: &str, issued: IssuedToken) -> bool {
+        let size = issued.evidence.len();
+        if self.evidence_bytes.fetch_add(size, Ordering::SeqCst) + size > MAX_EVIDENCE_BYTES {
+            self.evidence_bytes.fetch_sub(size, Ordering::SeqCst);
+            return false;
         }
+        if self
+            .issued
+            .insert_async(token_digest(token), issued)
+            .await
+            .is_err()
+        {
+            self.evidence_bytes.fetch_sub(size, Ordering::SeqCst);
+        }
+        true
+    }
+
+    /// The attestations of the unexpired tokens issued to `tenant`, or to
+    /// the default tenant when `None`, with the digests of the tokens.
+    #[cfg(feature = "as")]
+    pub async fn issued(&self, tenant: Option<&str>) -> Vec<(String, IssuedToken)> {
+        let now = OffsetDateTime::now_utc();
+        let mut issued = Vec::new();
+        self.issued
+            .scan_async(|digest, v| {
+                if v.tenant.as_deref() == tenant && v.expires_at > now {
+                    issued.push((digest.clone(), v.clone()));
+                }
+            })
+            .await;
+        issued
+    }
+
+    /// Workload identity of the session `id` of `tenant`, if it is attested,
+    /// unexpired and its attester has one.
+    pub async fn attested_identity(&self, id: &str, tenant: Option<&str>) -> Option<String> {
+        self.sessions
+            .read_async(id, |_, v| match v {
+                SessionStatus::Attested {
+                    attestation_claims, ..
+                } if v.tenant() == tenant && !v.is_expired() => {
+                    identity_of(&serde_json::from_str(attestation_claims).ok()?)
+                }
+                _ => None,
+            })
+            .await
+            .flatten()
+    }
+
+    /// Keep `token` as the one of the latest attestation of the workload
+    /// `identity` of `tenant`, until `expires`.
+    pub async fn record_attestation(
+        &self,
+        tenant: Option<&str>,
+        identity: &str,
+        token: &str,
+        expires: OffsetDateTime,
+    ) {
+        self.prior_tokens
+            .upsert_async(
+                (tenant.map(str::to_string), identity.to_string()),
+                (token.to_string(), expires),
+            )
+            .await;
+    }
+
+    /// Token of the latest unexpired attestation of the workload `identity`
+    /// of `tenant`.
+    pub async fn prior_token(&self, tenant: Option<&str>, identity: &str) -> Option<String> {
+        let now = OffsetDateTime::now_utc();
+        self.prior_tokens
+            .read_async(
+                &(tenant.map(str::to_string), identity.to_string()),
+                |_, (token, expires)| (*expires > now).then(|| token.clone()),
+            )
+            .await
+            .flatten()
+    }
+
+    /// The unexpired sessions of `tenant`, or of the default tenant when
+    /// `None`, attested or not.
+    pub async fn list(&self, tenant: Option<&str>) -> Vec<SessionSummary> {
+        let mut sessions = Vec::new();
+        self.sessions
+            .scan_async(|_, v| {
+                if v.tenant() == tenant && !v.is_expired() {
+                    sessions.push(SessionSummary::of(v));
+                }
+            })
+            .await;
+        sessions
+    }
+
+    /// End the session of `tenant` with the handle `handle`, so that its
+    /// cookie is no longer accepted. Returns false if there is no such
+    /// session. The tokens issued to the session stay valid until they expire.
+    pub async fn terminate(&self, handle: &str, tenant: Option<&str>) -> bool {
+        let mut id = None;
+        self.sessions
+            .scan_async(|k, v| {
+                if v.tenant() == tenant
+                    && crypto::constant_time_eq(session_handle(k).as_bytes(), handle.as_bytes())
+                {
+                    id = Some(k.clone());
+                }
+            })
+            .await;
+        let Some(id) = id else {
+            return false;
+        };
+        self.sessions
+            .remove_if_async(&id, |session| session.tenant() == tenant)
+            .await
+            .is_some()
+    }
+
+    /// End the sessions attested with the revoked `token`, and stop giving
+    /// it to the attestation policy as a prior token. Returns the number of
+    /// sessions ended.
+    pub async fn terminate_token(&self, token: &str) -> usize {
+        let mut terminated = 0;
+        self.sessions
+            .retain_async(|_, session| match session {
+                SessionStatus::Attested { token: t, .. } if t == token => {
+                    terminated += 1;
+                    false
+                }
+                _ => true,
+            })
+            .await;
+        self.prior_tokens
+            .retain_async(|_, (prior, _)| prior != token)
+            .await;
+        terminated
     }
 
     pub fn insert(&self, session: SessionStatus) {
         let _ = self.sessions.insert(session.id().to_string(), session);
     }
+
+    /// Mark `nonce` as answered by an attestation until `expires`, on every
+    /// KBS instance sharing the nonce store. Returns false if it already
+    /// was, i.e. the evidence is replayed.
+    pub async fn consume_nonce(&self, nonce: &str, expires: OffsetDateTime) -> Result<bool> {
+        self.consumed_nonces.consume(nonce, expires).await
+    }
+
+    /// Remove the expired sessions, consumed nonces and tokens.
+    pub async fn prune(&self) {
+        self.sessions.retain_async(|_, v| !v.is_expired()).await;
+        if let Err(e) = self.consumed_nonces.prune().await {
+            warn!("Failed to remove the expired nonces: {e:#}");
+        }
+        let now = OffsetDateTime::now_utc();
+        self.issued
+            .retain_async(|_, v| {
+                let unexpired = v.expires_at > now;
+                if !unexpired {
+                    self.evidence_bytes
+                        .fetch_sub(v.evidence.len(), Ordering::SeqCst);
+                }
+                unexpired
+            })
+            .await;
+        self.prior_tokens
+            .retain_async(|_, (_, expires)| *expires > now)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_consume_nonce() {
+        let map = SessionMap::new();
+        let now = OffsetDateTime::now_utc();
+        let (later, earlier) = (now + Duration::minutes(5), now - Duration::minutes(5));
+
+        assert!(map.consume_nonce("a", later).await.unwrap());
+        assert!(!map.consume_nonce("a", later).await.unwrap());
+        assert!(map.consume_nonce("b", earlier).await.unwrap());
+
+        map.prune().await;
+        assert!(!map.consume_nonce("a", later).await.unwrap());
+        assert!(map.consume_nonce("b", later).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_shared_nonce_store() {
+        let dir = tempfile::tempdir().unwrap();
+        // Two KBS replicas sharing the directory.
+        let store = || Arc::new(DirNonceStore::new(dir.path()).unwrap());
+        let (map, other) = (
+            SessionMap::with_nonce_store(store()),
+            SessionMap::with_nonce_store(store()),
+        );
+        let now = OffsetDateTime::now_utc();
+        let (later, earlier) = (now + Duration::minutes(5), now - Duration::minutes(5));
+
+        let nonce = "YWJj/ZGVm+==";
+        assert!(map.consume_nonce(nonce, later).await.unwrap());
+        assert!(!other.consume_nonce(nonce, later).await.unwrap());
+        assert!(other.consume_nonce("b", earlier).await.unwrap());
+
+        // The expired nonces are removed by any replica.
+        map.prune().await;
+        assert!(!other.consume_nonce(nonce, later).await.unwrap());
+        assert!(map.consume_nonce("b", later).await.unwrap());
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        let payload = URL_SAFE_NO_PAD.encode(r#"{"exp": 1714564800}"#);
+        assert_eq!(
+            token_expiry(&format!("header.{payload}.signature")),
+            OffsetDateTime::from_unix_timestamp(1714564800).ok()
+        );
+        assert!(token_expiry("opaque").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_issue() {
+        let now = OffsetDateTime::now_utc();
+        let issued = |evidence: String, expires_at| IssuedToken {
+            session_handle: session_handle("id"),
+            tenant: None,
+            tee: Tee::Sample,
+            evidence,
+            attestation_claims: "{}".into(),
+            attested_at: now,
+            expires_at,
+        };
+        let map = SessionMap::new();
+        assert!(
+            map.issue("a", issued("a".into(), now + Duration::minutes(5)))
+                .await
+        );
+        assert!(
+            map.issue("b", issued("b".into(), now - Duration::minutes(5)))
+                .await
+        );
+        // Evidence over the size of the evidence kept is dropped.
+        let large = "e".repeat(MAX_EVIDENCE_BYTES);
+        assert!(
+            !map.issue("c", issued(large, now + Duration::minutes(5)))
+                .await
+        );
+
+        let tokens = map.issued(None).await;
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].0, token_digest("a"));
+        assert!(map.issued(Some("a")).await.is_empty());
+
+        map.prune().await;
+        assert_eq!(map.evidence_bytes.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_and_terminate() {
+        let session = |tenant: Option<&str>| {
+            SessionStatus::auth(
+                Request {
+                    version: "0.1.0".into(),
+                    tee: Tee::Sample,
+                    extra_params: String::new(),
+                }
+                .into(),
+                5,
+                Challenge {
+                    nonce: "nonce".into(),
+                    extra_params: String::new(),
+                },
+                tenant.map(str::to_string),
+                None,
+            )
+            .unwrap()
+        };
+        let map = SessionMap::new();
+        let mut attested = session(None);
+        attested.attest(
+            Tee::Sample,
+            r#"{"evaluation-reports": [{"policy-id": "default"}], "workload_identity": "sample/1"}"#
+                .into(),
+            "token".into(),
+        );
+        let attested_handle = session_handle(attested.id());
+        map.insert(attested);
+        map.insert(session(None));
+        let other = session(Some("a"));
+        let other_handle = session_handle(other.id());
+        map.insert(other);
+
+        let sessions = map.list(None).await;
+        assert_eq!(sessions.len(), 2);
+        let summary = sessions
+            .iter()
+            .find(|s| s.handle == attested_handle)
+            .unwrap();
+        assert!(summary.attested);
+        assert_eq!(summary.policies, ["default"]);
+        assert_eq!(summary.workload_identity.as_deref(), Some("sample/1"));
+
+        // Sessions of another tenant can't be terminated.
+        assert!(!map.terminate(&other_handle, None).await);
+        assert!(map.terminate(&attested_handle, None).await);
+        assert!(!map.terminate(&attested_handle, None).await);
+        assert_eq!(map.list(None).await.len(), 1);
+        assert_eq!(map.list(Some("a")).await.len(), 1);
+
+        let mut revoked = session(Some("a"));
+        revoked.attest(Tee::Sample, "{}".into(), "revoked".into());
+        map.insert(revoked);
+        assert_eq!(map.terminate_token("revoked").await, 1);
+        assert_eq!(map.terminate_token("revoked").await, 0);
+        assert_eq!(map.list(Some("a")).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let session = || {
+            SessionStatus::auth(
+                Request {
+                    version: "0.1.0".into(),
+                    tee: Tee::Sample,
+                    extra_params: String::new(),
+                }
+                .into(),
+                5,
+                Challenge {
+                    nonce: "nonce".into(),
+                    extra_params: String::new(),
+                },
+                None,
+                None,
+            )
+            .unwrap()
+        };
+        let map = SessionMap::new();
+        assert!(!map.is_draining());
+        assert_eq!(map.pending_handshakes().await, 0);
+
+        // Only the sessions still waiting for their attestation are pending.
+        let mut attested = session();
+        attested.attest(Tee::Sample, "{}".into(), "token".into());
+        map.insert(attested);
+        map.insert(session());
+        map.insert(session());
+        assert_eq!(map.pending_handshakes().await, 2);
+
+        map.drain();
+        assert!(map.is_draining());
+        assert_eq!(map.pending_handshakes().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_prior_attestation() {
+        let session = || {
+            SessionStatus::auth(
+                Request {
+                    version: "0.1.0".into(),
+                    tee: Tee::Sample,
+                    extra_params: String::new(),
+                }
+                .into(),
+                5,
+                Challenge {
+                    nonce: "nonce".into(),
+                    extra_params: String::new(),
+                },
+                None,
+                None,
+            )
+            .unwrap()
+        };
+        let map = SessionMap::new();
+        let pending = session();
+        let pending_id = pending.id().to_string();
+        map.insert(pending);
+        let mut attested = session();
+        attested.attest(
+            Tee::Sample,
+            r#"{"workload_identity": "sample/1"}"#.into(),
+            "token".into(),
+        );
+        let attested_id = attested.id().to_string();
+        map.insert(attested);
+        let mut anonymous = session();
+        anonymous.attest(Tee::Sample, "{}".into(), "token".into());
+        let anonymous_id = anonymous.id().to_string();
+        map.insert(anonymous);
+
+        assert_eq!(
+            map.attested_identity(&attested_id, None).await.as_deref(),
+            Some("sample/1")
+        );
+        assert!(map
+            .attested_identity(&attested_id, Some("a"))
+            .await
+            .is_none());
+        assert!(map.attested_identity(&anonymous_id, None).await.is_none());
+        assert!(map.attested_identity(&pending_id, None).await.is_none());
+        assert!(map.attested_identity("unknown", None).await.is_none());
+
+        let expires = OffsetDateTime::now_utc() + Duration::minutes(5);
+        map.record_attestation(None, "sample/1", "token-1", expires)
+            .await;
+        map.record_attestation(None, "sample/1", "token-2", expires)
+            .await;
+        map.record_attestation(None, "sample/2", "token-3", OffsetDateTime::now_utc())
+            .await;
+        assert_eq!(
+            map.prior_token(None, "sample/1").await.as_deref(),
+            Some("token-2")
+        );
+        assert!(map.prior_token(Some("a"), "sample/1").await.is_none());
+        assert!(map.prior_token(None, "sample/2").await.is_none());
+        map.prune().await;
+        assert!(map.prior_token(None, "sample/1").await.is_some());
+    }
 }
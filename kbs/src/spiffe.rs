@@ -0,0 +1,461 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exchange of attestation results for SPIFFE X509-SVIDs.
+//!
+//! An attested workload sends a CSR for the SPIFFE ID that KBS derives from
+//! its attestation claims, and KBS has a SPIRE server mint the X509-SVID over
+//! the local socket of the server API. The workload gets the SVID and the
+//! bundle of the trust domain, and can join the mTLS mesh of the trust domain
+//! without a SPIRE agent.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use kbs_types::TeePubKey;
+use openssl::x509::X509Req;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint, Uri};
+use x509_parser::prelude::{FromDer, GeneralName, ParsedExtension, X509CertificationRequest};
+
+use crate::http::TOKEN_TEE_PUBKEY_PATHS;
+
+use spire::api::server::bundle::v1::{bundle_client::BundleClient, GetBundleRequest};
+use spire::api::server::svid::v1::{svid_client::SvidClient, MintX509svidRequest};
+
+#[allow(clippy::all)]
+mod spire {
+    pub mod api {
+        pub mod types {
+            tonic::include_proto!("spire.api.types");
+        }
+        pub mod server {
+            pub mod bundle {
+                pub mod v1 {
+                    tonic::include_proto!("spire.api.server.bundle.v1");
+                }
+            }
+            pub mod svid {
+                pub mod v1 {
+                    tonic::include_proto!("spire.api.server.svid.v1");
+                }
+            }
+        }
+    }
+}
+
+/// Default socket of the SPIRE server API.
+const DEFAULT_SOCKET_PATH: &str = "/tmp/spire-server/private/api.sock";
+
+const SPIFFE_SCHEME: &str = "spiffe://";
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SpiffeConfig {
+    /// Unix socket of the SPIRE server API. Minting SVIDs requires a local
+    /// or an admin caller.
+    #[serde(default = "default_socket_path")]
+    pub socket_path: PathBuf,
+
+    /// SPIFFE ID of the attested workloads. Every `{pointer}` is replaced by
+    /// the attestation claim at the JSON pointer, e.g.
+    /// `spiffe://example.org/kbs/{/tee}`.
+    pub id_template: String,
+
+    /// Requested lifetime of the SVIDs in seconds. The SPIRE server default
+    /// is used when omitted.
+    pub ttl: Option<u32>,
+}
+
+fn default_socket_path() -> PathBuf {
+    PathBuf::from(DEFAULT_SOCKET_PATH)
+}
+
+/// Part of a SPIFFE ID template.
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+
+    /// JSON pointer of a claim.
+    Claim(String),
+}
+
+/// An X509-SVID and the bundle to verify the other SVIDs of its trust domain.
+#[derive(Debug, Serialize)]
+pub(crate) struct X509Svid {
+    pub spiffe_id: String,
+
+    /// PEM certificate chain of the SVID, the leaf first.
+    pub x509_svid: String,
+
+    /// PEM X.509 authorities of the trust domain.
+    pub bundle: String,
+
+    /// Expiration of the SVID in seconds since the Unix epoch.
+    pub expires_at: i64,
+}
+
+/// Mints the SVIDs of the attested workloads with a SPIRE server.
+pub(crate) struct SvidIssuer {
+    template: Vec<Part>,
+    ttl: i32,
+    svid_client: SvidClient<Channel>,
+    bundle_client: BundleClient<Channel>,
+}
+
+impl SvidIssuer {
+    /// Create the issuer of `config`. The SPIRE server is connected on the
+    /// first request.
+    pub fn new(config: &SpiffeConfig) -> Result<Self> {
+        let template = parse_template(&config.id_template)?;
+        let ttl = config
+            .ttl
+            .unwrap_or_default()
+            .try_into()
+            .context("SVID ttl is too large")?;
+
+        let socket_path = config.socket_path.clone();
+        // The URI is ignored, the requests go to the socket.
+        let channel = Endpoint::from_static("http://[::]:50051").connect_with_connector_lazy(
+            tower::service_fn(move |_: Uri| UnixStream::connect(socket_path.clone())),
+        );
+
+        Ok(Self {
+            template,
+            ttl,
+            svid_client: SvidClient::new(channel.clone()),
+            bundle_client: BundleClient::new(channel),
+        })
+    }
+
+    /// The SPIFFE ID of the workload with the attestation claims `claims`.
+    pub fn spiffe_id(&self, claims: &Value) -> Result<String> {
+        let mut id = String::new();
+        for part in &self.template {
+            match part {
+                Part::Literal(literal) => id.push_str(literal),
+                Part::Claim(pointer) => {
+                    let value = match claims.pointer(pointer) {
+                        Some(Value::String(s)) => s.clone(),
+                        Some(value @ (Value::Number(_) | Value::Bool(_))) => value.to_string(),
+                        _ => bail!("no claim `{pointer}` for the SPIFFE ID"),
+                    };
+                    // A claim can't add a segment to the path.
+                    if matches!(value.as_str(), "" | "." | "..") || !value.chars().all(is_path_char)
+                    {
+                        bail!("claim `{pointer}` can't be used in a SPIFFE ID: `{value}`");
+                    }
+                    id.push_str(&value);
+                }
+            }
+        }
+        Ok(id)
+    }
+
+    /// Mint the X509-SVID of the workload with the attestation claims
+    /// `claims`, for the PEM encoded `csr`. The only URI SAN of the CSR must
+    /// be the SPIFFE ID of the workload, and its key the attested TEE public
+    /// key, so that only the attested workload holds the key of the SVID.
+    pub async fn mint_x509_svid(&self, claims: &Value, csr: &str) -> Result<X509Svid> {
+        let spiffe_id = self.spiffe_id(claims)?;
+        let csr = csr_der(csr)?;
+        let requested = csr_spiffe_id(&csr)?;
+        if requested != spiffe_id {
+            bail!("the CSR is for {requested}, the SPIFFE ID of the workload is {spiffe_id}");
+        }
+        check_csr_key(&csr, claims)?;
+
+        let response = self
+            .svid_client
+            .clone()
+            .mint_x509svid(MintX509svidRequest { csr, ttl: self.ttl })
+            .await
+            .map_err(|status| anyhow!("mint X509-SVID: {}", status.message()))?
+            .into_inner();
+        let svid = response
+            .svid
+            .context("SPIRE server returned no X509-SVID")?;
+
+        let bundle = self
+            .bundle_client
+            .clone()
+            .get_bundle(GetBundleRequest {})
+            .await
+            .map_err(|status| anyhow!("get bundle: {}", status.message()))?
+            .into_inner();
+
+        Ok(X509Svid {
+            spiffe_id,
+            x509_svid: svid.cert_chain.iter().map(|der| pem(der)).collect(),
+            bundle: bundle
+                .x509_authorities
+                .iter()
+                .map(|authority| pem(&authority.asn1))
+                .collect(),
+            expires_at: svid.expires_at,
+        })
+    }
+}
+
+fn is_path_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')
+}
+
+/// Split `template` into literals and claims, checking that it is a SPIFFE
+/// ID with a path.
+fn parse_template(template: &str) -> Result<Vec<Part>> {
+    let Some(id) = template.strip_prefix(SPIFFE_SCHEME) else {
+        bail!("SPIFFE ID template `{template}` must start with {SPIFFE_SCHEME}");
+    };
+    let Some((trust_domain, _)) = id.split_once('/') else {
+        bail!("SPIFFE ID template `{template}` has no path");
+    };
+    if trust_domain.is_empty()
+        || !trust_domain
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '-' | '_'))
+    {
+        bail!("SPIFFE ID template `{template}` has an invalid trust domain");
+    }
+
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("unclosed `{{` in SPIFFE ID template `{template}`"))?;
+        let pointer = &rest[start + 1..start + end];
+        if !pointer.starts_with('/') {
+            bail!("`{pointer}` of SPIFFE ID template `{template}` is not a JSON pointer");
+        }
+        if start > 0 {
+            parts.push(Part::Literal(rest[..start].to_string()));
+        }
+        parts.push(Part::Claim(pointer.to_string()));
+        rest = &rest[start + end + 1..];
+    }
+    if rest.contains('}') {
+        bail!("unopened `}}` in SPIFFE ID template `{template}`");
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest.to_string()));
+    }
+
+    // The claims only replace path characters.
+    let sample: String = parts
+        .iter()
+        .map(|part| match part {
+            Part::Literal(literal) => literal.as_str(),
+            Part::Claim(_) => "x",
+        })
+        .collect();
+    let path = &sample[SPIFFE_SCHEME.len() + trust_domain.len()..];
+    if path.split('/').skip(1).any(|segment| {
+        segment.is_empty()
+            || segment == "."
+            || segment == ".."
+            || !segment.chars().all(is_path_char)
+    }) {
+        bail!("SPIFFE ID template `{template}` has an invalid path");
+    }
+    Ok(parts)
+}
+
+/// The DER of the PEM encoded CSR `csr`.
+fn csr_der(csr: &str) -> Result<Vec<u8>> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(csr.as_bytes())
+        .map_err(|e| anyhow!("parse CSR PEM: {e}"))?;
+    Ok(pem.contents)
+}
+
+/// The SPIFFE ID of the DER encoded CSR `csr`, its only URI SAN.
+fn csr_spiffe_id(csr: &[u8]) -> Result<String> {
+    let (_, csr) =
+        X509CertificationRequest::from_der(csr).map_err(|e| anyhow!("parse CSR: {e}"))?;
+    let mut uris = csr
+        .requested_extensions()
+        .into_iter()
+        .flatten()
+        .filter_map(|extension| match extension {
+            ParsedExtension::SubjectAlternativeName(san) => Some(&san.general_names),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|name| match name {
+            GeneralName::URI(uri) => Some(*uri),
+            _ => None,
+        });
+    match (uris.next(), uris.next()) {
+        (Some(uri), None) => Ok(uri.to_string()),
+        _ => bail!("the CSR must have exactly one URI SAN"),
+    }
+}
+
+/// Check that the key of the DER encoded CSR `csr` is the TEE public key
+/// attested in `claims`.
+fn check_csr_key(csr: &[u8], claims: &Value) -> Result<()> {
+    let tee_pubkey = TOKEN_TEE_PUBKEY_PATHS
+        .iter()
+        .find_map(|path| claims.pointer(path))
+        .context("no tee-pubkey in the attestation claims")?;
+    let tee_pubkey = TeePubKey::deserialize(tee_pubkey).context("parse tee-pubkey")?;
+    let modulus = URL_SAFE_NO_PAD
+        .decode(&tee_pubkey.k_mod)
+        .context("decode tee-pubkey modulus")?;
+    let exponent = URL_SAFE_NO_PAD
+        .decode(&tee_pubkey.k_exp)
+        .context("decode tee-pubkey exponent")?;
+
+    let key = X509Req::from_der(csr)
+        .context("parse CSR")?
+        .public_key()
+        .context("read CSR key")?
+        .rsa()
+        .context("the CSR key must be the RSA tee-pubkey")?;
+    let unsigned = |bytes: &[u8]| -> Vec<u8> {
+        bytes
+            .iter()
+            .copied()
+            .skip_while(|byte| *byte == 0)
+            .collect()
+    };
+    if unsigned(&modulus) != key.n().to_vec() || unsigned(&exponent) != key.e().to_vec() {
+        bail!("the CSR key is not the attested tee-pubkey");
+    }
+    Ok(())
+}
+
+fn pem(der: &[u8]) -> String {
+    let base64 = STANDARD.encode(der);
+    let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str("-----END CERTIFICATE-----\n");
+    pem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn issuer(template: &str) -> SvidIssuer {
+        SvidIssuer::new(&SpiffeConfig {
+            socket_path: default_socket_path(),
+            id_template: template.to_string(),
+            ttl: None,
+        })
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case("spiffe://example.org/kbs", true)]
+    #[case("spiffe://example.org/kbs/{/tee}/{/tcb-status/svn}", true)]
+    #[case("spiffe://example.org/kbs-{/tee}", true)]
+    #[case("https://example.org/kbs", false)]
+    #[case("spiffe://example.org", false)]
+    #[case("spiffe://example.org/", false)]
+    #[case("spiffe://Example.org/kbs", false)]
+    #[case("spiffe://{/tee}/kbs", false)]
+    #[case("spiffe://example.org/kbs//{/tee}", false)]
+    #[case("spiffe://example.org/kbs/{tee}", false)]
+    #[case("spiffe://example.org/kbs/{/tee", false)]
+    #[case("spiffe://example.org/kbs/tee}", false)]
+    #[case("spiffe://example.org/kbs/a b", false)]
+    fn test_parse_template(#[case] template: &str, #[case] ok: bool) {
+        assert_eq!(parse_template(template).is_ok(), ok, "{template}");
+    }
+
+    #[tokio::test]
+    async fn test_spiffe_id() {
+        let issuer = issuer("spiffe://example.org/kbs/{/tee}/svn-{/tcb-status/svn}");
+        let claims = |tee: &str| json!({"tee": tee, "tcb-status": {"svn": 2}});
+        assert_eq!(
+            issuer.spiffe_id(&claims("tdx")).unwrap(),
+            "spiffe://example.org/kbs/tdx/svn-2"
+        );
+        assert!(issuer.spiffe_id(&claims("tdx/admin")).is_err());
+        assert!(issuer.spiffe_id(&claims("..")).is_err());
+        assert!(issuer.spiffe_id(&claims("")).is_err());
+        assert!(issuer.spiffe_id(&json!({"tee": "tdx"})).is_err());
+    }
+
+    #[test]
+    fn test_csr_spiffe_id() {
+        use openssl::{
+            ec::{EcGroup, EcKey},
+            hash::MessageDigest,
+            nid::Nid,
+            pkey::PKey,
+            stack::Stack,
+            x509::{extension::SubjectAlternativeName, X509Req},
+        };
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let csr = |uris: &[&str], dns: Option<&str>| {
+            let mut builder = X509Req::builder().unwrap();
+            builder.set_pubkey(&key).unwrap();
+            let mut san = SubjectAlternativeName::new();
+            for uri in uris {
+                san.uri(uri);
+            }
+            if let Some(dns) = dns {
+                san.dns(dns);
+            }
+            let mut extensions = Stack::new().unwrap();
+            extensions
+                .push(san.build(&builder.x509v3_context(None)).unwrap())
+                .unwrap();
+            builder.add_extensions(&extensions).unwrap();
+            builder.sign(&key, MessageDigest::sha256()).unwrap();
+            String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
+        };
+        let id = "spiffe://example.org/kbs/tdx";
+
+        let der = csr_der(&csr(&[id], Some("kbs"))).unwrap();
+        assert_eq!(csr_spiffe_id(&der).unwrap(), id);
+
+        let der = csr_der(&csr(&[], Some("kbs"))).unwrap();
+        assert!(csr_spiffe_id(&der).is_err());
+        let der = csr_der(&csr(&[id, "spiffe://example.org/other"], None)).unwrap();
+        assert!(csr_spiffe_id(&der).is_err());
+
+        assert!(csr_der("not a CSR").is_err());
+        assert!(csr_spiffe_id(b"not a CSR").is_err());
+    }
+
+    #[test]
+    fn test_check_csr_key() {
+        use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa};
+
+        let rsa = Rsa::generate(2048).unwrap();
+        let claims = json!({
+            "customized_claims": {"runtime_data": {"tee-pubkey": {
+                "kty": "RSA",
+                "alg": "RSA1_5",
+                "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+                "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+            }}},
+        });
+        let csr = |key: &PKey<openssl::pkey::Private>| {
+            let mut builder = X509Req::builder().unwrap();
+            builder.set_pubkey(key).unwrap();
+            builder.sign(key, MessageDigest::sha256()).unwrap();
+            builder.build().to_der().unwrap()
+        };
+
+        let attested = PKey::from_rsa(rsa).unwrap();
+        assert!(check_csr_key(&csr(&attested), &claims).is_ok());
+
+        let other = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        assert!(check_csr_key(&csr(&other), &claims).is_err());
+        assert!(check_csr_key(&csr(&attested), &json!({})).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! OpenTelemetry export of the KBS tracing spans.
+
+use anyhow::{Context, Result};
+#[cfg(feature = "coco-as-grpc")]
+use opentelemetry::propagation::Injector;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use serde::Deserialize;
+#[cfg(feature = "coco-as-grpc")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+const DEFAULT_OTLP_ENDPOINT: &str = "http://127.0.0.1:4317";
+const DEFAULT_SERVICE_NAME: &str = "kbs";
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TracingConfig {
+    /// OTLP/gRPC endpoint of the trace collector.
+    #[serde(default = "default_otlp_endpoint")]
+    pub otlp_endpoint: String,
+
+    /// Service name the spans are reported under.
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_otlp_endpoint() -> String {
+    DEFAULT_OTLP_ENDPOINT.to_string()
+}
+
+fn default_service_name() -> String {
+    DEFAULT_SERVICE_NAME.to_string()
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: default_otlp_endpoint(),
+            service_name: default_service_name(),
+        }
+    }
+}
+
+/// Install a global tracing subscriber that exports all spans over OTLP.
+/// Log records keep going through the `log` crate.
+pub fn init(config: &TracingConfig) -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&config.otlp_endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                config.service_name.clone(),
+            )])),
+        )
+        .install_batch(runtime::Tokio)
+        .context("install OTLP trace pipeline")?;
+
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber).context("set tracing subscriber")?;
+
+    Ok(())
+}
+
+/// Flush the spans still buffered by the exporter.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+#[cfg(feature = "coco-as-grpc")]
+struct MapInjector<'a>(&'a mut Vec<(String, String)>);
+
+#[cfg(feature = "coco-as-grpc")]
+impl Injector for MapInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.push((key.to_string(), value));
+    }
+}
+
+/// The W3C trace context of the current span, as header name/value pairs to
+/// be attached to an outgoing request.
+#[cfg(feature = "coco-as-grpc")]
+pub(crate) fn current_trace_context() -> Vec<(String, String)> {
+    let mut headers = Vec::new();
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MapInjector(&mut headers))
+    });
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracing_config() {
+        let config: TracingConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, TracingConfig::default());
+        assert_eq!(config.otlp_endpoint, DEFAULT_OTLP_ENDPOINT);
+        assert_eq!(config.service_name, DEFAULT_SERVICE_NAME);
+
+        let config: TracingConfig = serde_json::from_str(r#"{"service_name": "kbs-eu"}"#).unwrap();
+        assert_eq!(config.otlp_endpoint, DEFAULT_OTLP_ENDPOINT);
+        assert_eq!(config.service_name, "kbs-eu");
+    }
+
+    #[cfg(feature = "coco-as-grpc")]
+    #[test]
+    fn test_current_trace_context() {
+        use opentelemetry::trace::TracerProvider as _;
+
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = trace::TracerProvider::builder().build().tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            // Outside of any span, there is no trace to propagate.
+            assert!(current_trace_context()
+                .iter()
+                .all(|(name, _)| name != "traceparent"));
+
+            let span = tracing::info_span!("attest");
+            let _entered = span.enter();
+            let context = current_trace_context();
+            let (_, traceparent) = context
+                .iter()
+                .find(|(name, _)| name == "traceparent")
+                .unwrap();
+            assert!(traceparent.starts_with("00-"));
+        });
+    }
+}
@@ -0,0 +1,549 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tenants of KBS.
+//!
+//! A tenant has its own resource repository, resource policy, admin keys and
+//! attestation policy, and is addressed by the `/kbs/v0/tenant/<id>` prefix of
+//! the KBS API. Attesters get the resources of the tenant they attested for
+//! only, and the admin keys of a tenant manage that tenant only. Requests
+//! without the prefix go to the default tenant of the top level configuration.
+
+use actix_web::HttpRequest;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::auth::{load_admin_keys, AdminKey, AdminKeyConfig};
+use crate::http::Error;
+#[cfg(feature = "policy")]
+use crate::policy_engine::{PolicyEngine, PolicyEngineConfig};
+#[cfg(feature = "resource")]
+use crate::resource::{Repository, RepositoryConfig};
+
+/// Attestation policy of the default tenant.
+pub(crate) const DEFAULT_ATTESTATION_POLICY: &str = "default";
+
+/// Path segment of the API of a tenant.
+pub(crate) const TENANT_SEGMENT: &str = "tenant";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TenantConfig {
+    /// Tenant identifier in the API paths.
+    pub id: String,
+
+    /// ID of the attestation policy the attesters of the tenant are verified
+    /// with. Defaults to the tenant ID.
+    pub attestation_policy: Option<String>,
+
+    /// Namespace of the RVPS the reference values of the attesters of the
+    /// tenant are looked up in. Defaults to the namespace shared with the
+    /// default tenant.
+    pub rvps_namespace: Option<String>,
+
+    /// Public keys trusted to sign the admin tokens of the tenant.
+    #[serde(default)]
+    pub admin_keys: Vec<AdminKeyConfig>,
+
+    #[cfg(feature = "resource")]
+    pub repository_config: RepositoryConfig,
+
+    #[cfg(feature = "policy")]
+    pub policy_engine_config: PolicyEngineConfig,
+}
+
+impl TenantConfig {
+    fn attestation_policy(&self) -> &str {
+        self.attestation_policy.as_deref().unwrap_or(&self.id)
+    }
+}
+
+pub(crate) struct Tenant {
+    pub id: String,
+    pub attestation_policy: String,
+    pub rvps_namespace: Option<String>,
+    pub admin_keys: Arc<Vec<AdminKey>>,
+    #[cfg(feature = "resource")]
+    pub repository: Arc<tokio::sync::RwLock<dyn Repository + Send + Sync>>,
+    #[cfg(feature = "policy")]
+    pub policy_engine: PolicyEngine,
+}
+
+impl Tenant {
+    async fn new(config: &TenantConfig, insecure_api: bool) -> Result<Self> {
+        #[cfg(feature = "resource")]
+        if config.repository_config.is_default_location() {
+            bail!(
+                "Tenant {} must not share the default resource repository",
+                config.id
+            );
+        }
+        #[cfg(feature = "policy")]
+        if config.policy_engine_config.policy_path.is_none() {
+            bail!(
+                "Tenant {} must set the path of its resource policy",
+                config.id
+            );
+        }
+
+        Ok(Self {
+            id: config.id.clone(),
+            attestation_policy: config.attestation_policy().to_string(),
+            rvps_namespace: config.rvps_namespace.clone(),
+            admin_keys: Arc::new(load_admin_keys(insecure_api, None, &config.admin_keys).await?),
+            #[cfg(feature = "resource")]
+            repository: config.repository_config.initialize()?,
+            #[cfg(feature = "policy")]
+            policy_engine: PolicyEngine::new(&config.policy_engine_config).await?,
+        })
+    }
+}
+
+/// The configured tenants, besides the default one.
+#[derive(Default)]
+pub(crate) struct Tenants(HashMap<String, Arc<Tenant>>);
+
+impl Tenants {
+    pub async fn new(configs: &[TenantConfig], insecure_api: bool) -> Result<Self> {
+        let mut tenants = HashMap::new();
+        let mut attestation_policies = HashSet::from([DEFAULT_ATTESTATION_POLICY]);
+        for config in configs {
+            if config.id.is_empty()
+                || !config
+                    .id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                bail!("Illegal tenant ID {:?}", config.id);
+            }
+            if tenants.contains_key(&config.id) {
+                bail!("Duplicated tenant {}", config.id);
+            }
+            // The attestation policy tells which tenant a token belongs to.
+            if !attestation_policies.insert(config.attestation_policy()) {
+                bail!(
+                    "Tenant {} must have an attestation policy of its own",
+                    config.id
+                );
+            }
+
+            let tenant = Tenant::new(config, insecure_api)
+                .await
+                .with_context(|| format!("initialize tenant {}", config.id))?;
+            tenants.insert(config.id.clone(), Arc::new(tenant));
+        }
+
+        Ok(Self(tenants))
+    }
+
+    pub fn get(&self, id: &str) -> Result<Arc<Tenant>, Error> {
+        self.0
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::UnknownTenant(id.to_string()))
+    }
+
+    /// The configured tenants.
+    #[cfg(feature = "resource")]
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        self.0.values()
+    }
+
+    /// The tenant addressed by the path of `request`, or `None` for the
+    /// default tenant.
+    pub fn of_request(&self, request: &HttpRequest) -> Result<Option<Arc<Tenant>>, Error> {
+        request
+            .match_info()
+            .get(TENANT_SEGMENT)
+            .map(|id| self.get(id))
+            .transpose()
+    }
+
+    /// The attestation policy of the attesters of `tenant`.
+    #[cfg(feature = "as")]
+    pub fn attestation_policy(&self, tenant: Option<&str>) -> Result<String, Error> {
+        match tenant {
+            Some(tenant) => Ok(self.get(tenant)?.attestation_policy.clone()),
+            None => Ok(DEFAULT_ATTESTATION_POLICY.to_string()),
+        }
+    }
+
+    /// The RVPS namespace of the reference values of the attesters of
+    /// `tenant`, or `None` for the default namespace.
+    #[cfg(feature = "as")]
+    pub fn rvps_namespace(&self, tenant: Option<&str>) -> Result<Option<String>, Error> {
+        match tenant {
+            Some(tenant) => Ok(self.get(tenant)?.rvps_namespace.clone()),
+            None => Ok(None),
+        }
+    }
+
+    /// The tenant whose attesters are verified with the attestation policy
+    /// `policy_id`, or `None` for the default tenant.
+    fn policy_owner(&self, policy_id: &str) -> Option<&str> {
+        self.0
+            .values()
+            .find(|tenant| tenant.attestation_policy == policy_id)
+            .map(|tenant| tenant.id.as_str())
+    }
+
+    /// Check that the admins of `tenant` may set the attestation policy
+    /// `policy_id`. The admins of a tenant manage its own policy only, and
+    /// the default admins manage the policies of no other tenant.
+    #[cfg(feature = "as")]
+    pub fn check_attestation_policy(
+        &self,
+        tenant: Option<&str>,
+        policy_id: &str,
+    ) -> Result<(), Error> {
+        let allowed = match tenant {
+            Some(tenant) => self.get(tenant)?.attestation_policy == policy_id,
+            None => self.policy_owner(policy_id).is_none(),
+        };
+        if !allowed {
+            return Err(Error::PermissionDenied(format!(
+                "attestation policy {policy_id} belongs to another tenant"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that the attestation results token with `claims` was issued for
+    /// `tenant`. A token belongs to the tenant whose attestation policy it
+    /// was evaluated with, and to the default tenant if that policy belongs
+    /// to no tenant. Tokens that don't name the policies they were evaluated
+    /// with, such as those of Intel Trust Authority, are rejected.
+    #[cfg(feature = "resource")]
+    pub fn check_token_claims(&self, tenant: Option<&str>, claims: &str) -> Result<(), Error> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        let claims: serde_json::Value = serde_json::from_str(claims)
+            .map_err(|e| Error::AttestationClaimsParseFailed(e.to_string()))?;
+        let policies: Vec<&str> = claims["evaluation-reports"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|report| report["policy-id"].as_str())
+            .collect();
+        if policies.is_empty() {
+            return Err(Error::PermissionDenied(
+                "attestation token without evaluation-reports can't be bound to a tenant"
+                    .to_string(),
+            ));
+        }
+        let owner = policies
+            .iter()
+            .find_map(|policy_id| self.policy_owner(policy_id));
+
+        if owner != tenant {
+            return Err(Error::TenantMismatch);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    /// Tenants `a` and `b` with their own repositories and policies in `dir`.
+    async fn tenants(dir: &std::path::Path) -> Tenants {
+        let configs: Vec<TenantConfig> = ["a", "b"]
+            .iter()
+            .map(|id| {
+                serde_json::from_value(serde_json::json!({
+                    "id": id,
+                    "repository_config": {
+                        "type": "LocalFs",
+                        "dir_path": dir.join(id).join("repository"),
+                    },
+                    "policy_engine_config": {
+                        "policy_path": dir.join(id).join("policy.rego"),
+                    },
+                }))
+                .unwrap()
+            })
+            .collect();
+        for id in ["a", "b"] {
+            std::fs::create_dir_all(dir.join(id)).unwrap();
+        }
+        Tenants::new(&configs, true).await.unwrap()
+    }
+
+    /// Tenants `a` and `b` in `dir`, where `a` trusts the admin `key_pair`
+    /// and `b` an admin of its own.
+    #[cfg(feature = "resource")]
+    async fn tenants_with_admin(
+        dir: &std::path::Path,
+        key_pair: &jwt_simple::prelude::Ed25519KeyPair,
+    ) -> Tenants {
+        let public_keys = [
+            key_pair.public_key(),
+            jwt_simple::prelude::Ed25519KeyPair::generate().public_key(),
+        ];
+        let configs: Vec<TenantConfig> = ["a", "b"]
+            .iter()
+            .zip(public_keys)
+            .map(|(id, public_key)| {
+                let path = dir.join(format!("{id}.pem"));
+                std::fs::write(&path, public_key.to_pem()).unwrap();
+                serde_json::from_value(serde_json::json!({
+                    "id": id,
+                    "admin_keys": [{ "name": format!("admin-{id}"), "public_key": path }],
+                    "repository_config": {
+                        "type": "LocalFs",
+                        "dir_path": dir.join(id).join("repository"),
+                    },
+                    "policy_engine_config": {
+                        "policy_path": dir.join(id).join("policy.rego"),
+                    },
+                }))
+                .unwrap()
+            })
+            .collect();
+        for id in ["a", "b"] {
+            std::fs::create_dir_all(dir.join(id)).unwrap();
+        }
+        Tenants::new(&configs, false).await.unwrap()
+    }
+
+    fn claims(policies: &[&str]) -> String {
+        let reports: Vec<_> = policies
+            .iter()
+            .map(|policy| serde_json::json!({ "policy-id": policy }))
+            .collect();
+        serde_json::json!({ "evaluation-reports": reports }).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_of_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let tenants = tenants(dir.path()).await;
+
+        let request = TestRequest::default().to_http_request();
+        assert!(tenants.of_request(&request).unwrap().is_none());
+
+        let request = TestRequest::default()
+            .param(TENANT_SEGMENT, "a")
+            .to_http_request();
+        assert_eq!(tenants.of_request(&request).unwrap().unwrap().id, "a");
+
+        let request = TestRequest::default()
+            .param(TENANT_SEGMENT, "c")
+            .to_http_request();
+        assert!(matches!(
+            tenants.of_request(&request),
+            Err(Error::UnknownTenant(_))
+        ));
+    }
+
+    #[cfg(feature = "resource")]
+    #[tokio::test]
+    async fn test_check_token_claims() {
+        let dir = tempfile::tempdir().unwrap();
+        let tenants = tenants(dir.path()).await;
+
+        assert!(tenants
+            .check_token_claims(Some("a"), &claims(&["a"]))
+            .is_ok());
+        assert!(tenants
+            .check_token_claims(None, &claims(&["default"]))
+            .is_ok());
+
+        for (tenant, policies) in [
+            (Some("a"), &["b"][..]),
+            (Some("a"), &["default"][..]),
+            (None, &["a"][..]),
+        ] {
+            assert!(matches!(
+                tenants.check_token_claims(tenant, &claims(policies)),
+                Err(Error::TenantMismatch)
+            ));
+        }
+        for tenant in [Some("a"), None] {
+            assert!(matches!(
+                tenants.check_token_claims(tenant, &claims(&[])),
+                Err(Error::PermissionDenied(_))
+            ));
+        }
+
+        // Without tenants, every token belongs to the default tenant.
+        assert!(Tenants::default()
+            .check_token_claims(None, &claims(&["a"]))
+            .is_ok());
+    }
+
+    #[cfg(feature = "as")]
+    #[tokio::test]
+    async fn test_check_attestation_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let tenants = tenants(dir.path()).await;
+
+        assert!(tenants.check_attestation_policy(Some("a"), "a").is_ok());
+        assert!(tenants.check_attestation_policy(None, "default").is_ok());
+        assert!(tenants.check_attestation_policy(None, "other").is_ok());
+
+        for (tenant, policy_id) in [(Some("a"), "b"), (Some("a"), "default"), (None, "a")] {
+            assert!(matches!(
+                tenants.check_attestation_policy(tenant, policy_id),
+                Err(Error::PermissionDenied(_))
+            ));
+        }
+    }
+
+    #[cfg(all(feature = "as", feature = "resource"))]
+    #[tokio::test]
+    async fn test_session_isolation() {
+        use crate::http::{session_claims, ReattestationInterval};
+        use crate::session::{SessionMap, SessionStatus};
+        use kbs_types::{Challenge, Request, Tee};
+
+        let map = SessionMap::new();
+        let mut session = SessionStatus::auth(
+            Request {
+                version: "0.1.0".into(),
+                tee: Tee::Sample,
+                extra_params: String::new(),
+            }
+            .into(),
+            5,
+            Challenge {
+                nonce: "nonce".into(),
+                extra_params: String::new(),
+            },
+            Some("a".into()),
+            None,
+        )
+        .unwrap();
+        session.attest(Tee::Sample, claims(&["a"]), "token".into());
+        let id = session.id().to_string();
+        map.insert(session);
+
+        let interval = ReattestationInterval::default();
+        assert!(session_claims(&id, &map, Some("a"), interval).await.is_ok());
+        for tenant in [Some("b"), None] {
+            assert!(matches!(
+                session_claims(&id, &map, tenant, interval).await,
+                Err(Error::TenantMismatch)
+            ));
+        }
+    }
+
+    #[cfg(feature = "resource")]
+    #[actix_web::test]
+    async fn test_admin_isolation() {
+        use crate::audit::AuditLog;
+        use crate::auth::{AdminAllowlist, AdminClaims, Role};
+        use crate::reload::Reloadable;
+        use crate::tls::ClientAuthScope;
+        use actix_web::http::StatusCode;
+        use actix_web::test::{call_service, init_service};
+        use actix_web::{web, App};
+        use jwt_simple::prelude::{Claims, Duration, Ed25519KeyPair, EdDSAKeyPairLike};
+
+        let dir = tempfile::tempdir().unwrap();
+        let key_pair = Ed25519KeyPair::generate();
+        let tenants = tenants_with_admin(dir.path(), &key_pair).await;
+        let repository = serde_json::from_value::<RepositoryConfig>(serde_json::json!({
+            "type": "LocalFs",
+            "dir_path": dir.path().join("repository"),
+        }))
+        .unwrap()
+        .initialize()
+        .unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Reloadable::new(Arc::new(
+                    Vec::<AdminKey>::new(),
+                ))))
+                .app_data(web::Data::new(Reloadable::new(Arc::new(tenants))))
+                .app_data(web::Data::new(false))
+                .app_data(web::Data::new(None::<ClientAuthScope>))
+                .app_data(web::Data::new(AdminAllowlist::new(&[]).unwrap()))
+                .app_data(web::Data::new(Reloadable::new(repository)))
+                .app_data(web::Data::new(
+                    crate::http::HttpServerConfig::default().upload_config(),
+                ))
+                .app_data(web::Data::new(AuditLog::new(None, &[]).await.unwrap()))
+                .service(
+                    web::resource([
+                        "/resource/{repository}/{type}/{tag}",
+                        "/tenant/{tenant}/resource/{repository}/{type}/{tag}",
+                    ])
+                    .route(web::post().to(crate::http::set_resource)),
+                ),
+        )
+        .await;
+
+        let claims = Claims::with_custom_claims(
+            AdminClaims {
+                roles: Some(vec![Role::ResourceAdmin]),
+            },
+            Duration::from_mins(5),
+        );
+        let token = key_pair.sign(claims).unwrap();
+        let set_resource = |path: &str| {
+            actix_web::test::TestRequest::post()
+                .uri(path)
+                .insert_header(("Authorization", format!("Bearer {token}")))
+                .set_payload("secret")
+                .to_request()
+        };
+
+        let response = call_service(&app, set_resource("/tenant/a/resource/default/key/1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            std::fs::read(dir.path().join("a/repository/default/key/1")).unwrap(),
+            b"secret"
+        );
+
+        for path in [
+            "/tenant/b/resource/default/key/1",
+            "/resource/default/key/1",
+        ] {
+            let response = call_service(&app, set_resource(path)).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = call_service(&app, set_resource("/tenant/c/resource/default/key/1")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        assert!(!dir.path().join("b/repository/default/key/1").exists());
+        assert!(!dir.path().join("repository/default/key/1").exists());
+    }
+
+    #[cfg(feature = "resource")]
+    #[tokio::test]
+    async fn test_invalid_tenants() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |id: &str, attestation_policy: Option<&str>, dir_path: bool| {
+            let mut config = serde_json::json!({
+                "id": id,
+                "attestation_policy": attestation_policy,
+                "repository_config": { "type": "LocalFs" },
+                "policy_engine_config": {
+                    "policy_path": dir.path().join(format!("{id}.rego")),
+                },
+            });
+            if dir_path {
+                config["repository_config"]["dir_path"] = serde_json::json!(dir.path().join(id));
+            }
+            serde_json::from_value::<TenantConfig>(config).unwrap()
+        };
+
+        for configs in [
+            vec![config("a/b", None, true)],
+            vec![config("a", None, true), config("a", None, true)],
+            vec![config("a", Some("default"), true)],
+            vec![config("a", None, true), config("b", Some("a"), true)],
+            vec![config("a", None, false)],
+        ] {
+            assert!(Tenants::new(&configs, true).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,460 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! HTTPS credentials of the KBS.
+//!
+//! The certificate chain and private key are reloaded when their files change
+//! or when KBS receives SIGHUP. New TLS handshakes pick up the reloaded
+//! credentials, established connections are not affected.
+//!
+//! Clients can be required to present a certificate issued by a configured CA,
+//! either on every connection or only to use the admin APIs. The subject of
+//! the verified client certificate is handed to the request handlers as a
+//! [`ClientIdentity`].
+//!
+//! The TLS exporter of TLS 1.3 connections is handed to the request handlers
+//! as a [`ChannelBinding`], so that attestation evidence can be bound to the
+//! connection it is sent over.
+
+use actix_web::dev::Extensions;
+use anyhow::{anyhow, Context, Result};
+use log::{error, info};
+use serde::Deserialize;
+use std::any::Any;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+#[cfg(feature = "openssl")]
+use openssl::ssl::{
+    SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod, SslVerifyMode,
+};
+
+#[cfg(feature = "rustls")]
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+/// How often the credential files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// TLS client certificate authentication.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ClientAuthConfig {
+    /// PEM bundle of the CA certificates client certificates must chain to.
+    pub ca_bundle: PathBuf,
+
+    /// Routes that require a client certificate.
+    #[serde(default)]
+    pub scope: ClientAuthScope,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthScope {
+    /// Every connection must present a client certificate.
+    All,
+
+    /// Only the admin APIs require a client certificate. Other clients may
+    /// connect without one.
+    #[default]
+    Admin,
+}
+
+/// The verified certificate a client presented during the TLS handshake.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity {
+    /// Subject distinguished name of the client certificate.
+    pub subject: String,
+}
+
+/// Length in bytes of a [`ChannelBinding`].
+pub const CHANNEL_BINDING_LENGTH: usize = 32;
+
+/// Exporter label of the `tls-exporter` channel binding (RFC 9266).
+const CHANNEL_BINDING_LABEL: &str = "EXPORTER-Channel-Binding";
+
+/// The `tls-exporter` channel binding (RFC 9266) of a TLS 1.3 connection:
+/// keying material exported with the `EXPORTER-Channel-Binding` label and no
+/// context, which both ends of the connection, and only them, can compute.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelBinding(pub Vec<u8>);
+
+pub(crate) struct TlsCredentials {
+    certificate: PathBuf,
+    private_key: PathBuf,
+    client_auth: Option<ClientAuthConfig>,
+
+    #[cfg(feature = "rustls")]
+    current: RwLock<Arc<CertifiedKey>>,
+
+    #[cfg(feature = "openssl")]
+    current: RwLock<SslContext>,
+}
+
+impl TlsCredentials {
+    pub fn new(
+        certificate: &Path,
+        private_key: &Path,
+        client_auth: Option<ClientAuthConfig>,
+    ) -> Result<Arc<Self>> {
+        let current = load(certificate, private_key, client_auth.as_ref())?;
+        Ok(Arc::new(Self {
+            certificate: certificate.to_path_buf(),
+            private_key: private_key.to_path_buf(),
+            client_auth,
+            current: RwLock::new(current),
+        }))
+    }
+
+    /// Reload the credentials from their files. The current credentials are
+    /// kept if the files can't be loaded.
+    pub fn reload(&self) -> Result<()> {
+        let credentials = load(
+            &self.certificate,
+            &self.private_key,
+            self.client_auth.as_ref(),
+        )?;
+        *self
+            .current
+            .write()
+            .map_err(|_| anyhow!("TLS credentials lock poisoned"))? = credentials;
+        Ok(())
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn server_config(self: &Arc<Self>) -> Result<ServerConfig> {
+        use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match &self.client_auth {
+            None => builder.with_no_client_auth(),
+            Some(client_auth) => {
+                let roots = load_ca_bundle(&client_auth.ca_bundle)?;
+                builder.with_client_cert_verifier(match client_auth.scope {
+                    ClientAuthScope::All => AllowAnyAuthenticatedClient::new(roots),
+                    ClientAuthScope::Admin => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+                })
+            }
+        };
+
+        Ok(builder.with_cert_resolver(self.clone()))
+    }
+
+    #[cfg(feature = "openssl")]
+    pub fn acceptor(self: &Arc<Self>) -> Result<SslAcceptorBuilder> {
+        let mut builder = acceptor_builder(
+            &self.certificate,
+            &self.private_key,
+            self.client_auth.as_ref(),
+        )?;
+        let credentials = self.clone();
+        builder.set_servername_callback(move |ssl, _| {
+            let context = credentials
+                .current
+                .read()
+                .map_err(|_| openssl::ssl::SniError::ALERT_FATAL)?;
+            ssl.set_ssl_context(&context)
+                .map_err(|_| openssl::ssl::SniError::ALERT_FATAL)
+        });
+
+        Ok(builder)
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let certificate = std::fs::metadata(&self.certificate).ok()?.modified().ok()?;
+        let private_key = std::fs::metadata(&self.private_key).ok()?.modified().ok()?;
+        Some((certificate, private_key))
+    }
+
+    /// Reload the credentials whenever their files change or on SIGHUP.
+    pub fn watch(self: &Arc<Self>) -> Result<()> {
+        let mut sighup = signal(SignalKind::hangup()).context("install SIGHUP handler")?;
+        let credentials = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            let mut modified = credentials.modified();
+            loop {
+                tokio::select! {
+                    _ = sighup.recv() => {},
+                    _ = interval.tick() => {
+                        if credentials.modified() == modified {
+                            continue;
+                        }
+                    },
+                }
+
+                modified = credentials.modified();
+                match credentials.reload() {
+                    Ok(()) => info!("HTTPS certificate reloaded"),
+                    Err(e) => error!("Failed to reload HTTPS certificate: {e:?}"),
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Attach the [`ClientIdentity`] of a TLS connection to its requests. Meant to
+/// be installed with `HttpServer::on_connect`.
+pub(crate) fn client_identity(connection: &dyn Any, extensions: &mut Extensions) {
+    if let Some(subject) = peer_subject(connection) {
+        extensions.insert(ClientIdentity { subject });
+    }
+}
+
+/// Attach the [`ChannelBinding`] of a TLS 1.3 connection to its requests.
+/// Earlier TLS versions have no binding, as their exporter isn't unique to
+/// the connection without the extended master secret. Meant to be installed
+/// with `HttpServer::on_connect`.
+pub(crate) fn channel_binding(connection: &dyn Any, extensions: &mut Extensions) {
+    if let Some(binding) = export_channel_binding(connection) {
+        extensions.insert(ChannelBinding(binding));
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn export_channel_binding(connection: &dyn Any) -> Option<Vec<u8>> {
+    use actix_tls::accept::rustls::TlsStream;
+    use actix_web::rt::net::TcpStream;
+    use rustls::ProtocolVersion;
+
+    let stream = connection.downcast_ref::<TlsStream<TcpStream>>()?;
+    let connection = stream.get_ref().1;
+    if connection.protocol_version() != Some(ProtocolVersion::TLSv1_3) {
+        return None;
+    }
+    let mut binding = vec![0; CHANNEL_BINDING_LENGTH];
+    connection
+        .export_keying_material(&mut binding, CHANNEL_BINDING_LABEL.as_bytes(), None)
+        .ok()?;
+    Some(binding)
+}
+
+#[cfg(feature = "openssl")]
+fn export_channel_binding(connection: &dyn Any) -> Option<Vec<u8>> {
+    use actix_tls::accept::openssl::TlsStream;
+    use actix_web::rt::net::TcpStream;
+    use openssl::ssl::SslVersion;
+
+    let stream = connection.downcast_ref::<TlsStream<TcpStream>>()?;
+    if stream.ssl().version2() != Some(SslVersion::TLS1_3) {
+        return None;
+    }
+    let mut binding = vec![0; CHANNEL_BINDING_LENGTH];
+    stream
+        .ssl()
+        .export_keying_material(&mut binding, CHANNEL_BINDING_LABEL, None)
+        .ok()?;
+    Some(binding)
+}
+
+#[cfg(feature = "rustls")]
+fn peer_subject(connection: &dyn Any) -> Option<String> {
+    use actix_tls::accept::rustls::TlsStream;
+    use actix_web::rt::net::TcpStream;
+    use x509_parser::prelude::{FromDer, X509Certificate};
+
+    let stream = connection.downcast_ref::<TlsStream<TcpStream>>()?;
+    let certificate = stream.get_ref().1.peer_certificates()?.first()?;
+    let (_, certificate) = X509Certificate::from_der(&certificate.0).ok()?;
+    Some(certificate.subject().to_string())
+}
+
+#[cfg(feature = "openssl")]
+fn peer_subject(connection: &dyn Any) -> Option<String> {
+    use actix_tls::accept::openssl::TlsStream;
+    use actix_web::rt::net::TcpStream;
+
+    let stream = connection.downcast_ref::<TlsStream<TcpStream>>()?;
+    let certificate = stream.ssl().peer_certificate()?;
+    let subject = certificate
+        .subject_name()
+        .entries()
+        .filter_map(|entry| {
+            let key = entry.object().nid().short_name().ok()?;
+            let value = entry.data().as_utf8().ok()?;
+            Some(format!("{key}={value}"))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(subject)
+}
+
+#[cfg(feature = "rustls")]
+impl ResolvesServerCert for TlsCredentials {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|key| key.clone())
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn load_ca_bundle(ca_bundle: &Path) -> Result<rustls::RootCertStore> {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let file = &mut BufReader::new(File::open(ca_bundle).context("open client CA bundle")?);
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&rustls_pemfile::certs(file)?);
+    if added == 0 {
+        anyhow::bail!("No CA certificate found in {}", ca_bundle.display());
+    }
+
+    Ok(roots)
+}
+
+#[cfg(feature = "rustls")]
+fn load(
+    certificate: &Path,
+    private_key: &Path,
+    _client_auth: Option<&ClientAuthConfig>,
+) -> Result<Arc<CertifiedKey>> {
+    use rustls::{Certificate, PrivateKey};
+    use rustls_pemfile::{certs, read_one, Item};
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let cert_file = &mut BufReader::new(File::open(certificate)?);
+    let key_file = &mut BufReader::new(File::open(private_key)?);
+
+    let cert_chain = certs(cert_file)?
+        .iter()
+        .map(|c| Certificate(c.clone()))
+        .collect();
+
+    let key = match read_one(key_file)? {
+        Some(Item::RSAKey(key)) | Some(Item::PKCS8Key(key)) | Some(Item::ECKey(key)) => {
+            Ok(PrivateKey(key))
+        }
+        None | Some(_) => Err(anyhow!("Invalid private key file")),
+    }?;
+
+    let signing_key = rustls::sign::any_supported_type(&key).context("unsupported private key")?;
+
+    Ok(Arc::new(CertifiedKey::new(cert_chain, signing_key)))
+}
+
+#[cfg(feature = "openssl")]
+fn acceptor_builder(
+    certificate: &Path,
+    private_key: &Path,
+    client_auth: Option<&ClientAuthConfig>,
+) -> Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
+    builder.set_private_key_file(private_key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(certificate)?;
+    builder.check_private_key()?;
+
+    if let Some(client_auth) = client_auth {
+        builder
+            .set_ca_file(&client_auth.ca_bundle)
+            .context("load client CA bundle")?;
+        builder.set_verify(match client_auth.scope {
+            ClientAuthScope::All => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+            ClientAuthScope::Admin => SslVerifyMode::PEER,
+        });
+    }
+
+    Ok(builder)
+}
+
+#[cfg(feature = "openssl")]
+fn load(
+    certificate: &Path,
+    private_key: &Path,
+    client_auth: Option<&ClientAuthConfig>,
+) -> Result<SslContext> {
+    Ok(acceptor_builder(certificate, private_key, client_auth)?
+        .build()
+        .into_context())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_auth_config() {
+        let config: ClientAuthConfig =
+            serde_json::from_str(r#"{"ca_bundle": "/etc/kbs/clients.pem"}"#).unwrap();
+        assert_eq!(config.scope, ClientAuthScope::Admin);
+
+        let config: ClientAuthConfig =
+            serde_json::from_str(r#"{"ca_bundle": "/etc/kbs/clients.pem", "scope": "all"}"#)
+                .unwrap();
+        assert_eq!(config.scope, ClientAuthScope::All);
+    }
+
+    /// Write a new self-signed certificate and its private key, returning the
+    /// DER of the certificate. OpenSSL comes with the `resource` feature.
+    #[cfg(all(feature = "resource", any(feature = "rustls", feature = "openssl")))]
+    fn self_signed(certificate: &Path, private_key: &Path) -> Vec<u8> {
+        use openssl::asn1::Asn1Time;
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::hash::MessageDigest;
+        use openssl::nid::Nid;
+        use openssl::pkey::PKey;
+        use openssl::x509::{X509NameBuilder, X509};
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "kbs.example.com").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let x509 = builder.build();
+
+        std::fs::write(certificate, x509.to_pem().unwrap()).unwrap();
+        std::fs::write(private_key, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        x509.to_der().unwrap()
+    }
+
+    #[cfg(all(feature = "resource", feature = "rustls"))]
+    fn current_certificate(credentials: &TlsCredentials) -> Vec<u8> {
+        credentials.current.read().unwrap().cert[0].0.clone()
+    }
+
+    #[cfg(all(feature = "resource", feature = "openssl"))]
+    fn current_certificate(credentials: &TlsCredentials) -> Vec<u8> {
+        let context = credentials.current.read().unwrap();
+        context.certificate().unwrap().to_der().unwrap()
+    }
+
+    #[cfg(all(feature = "resource", any(feature = "rustls", feature = "openssl")))]
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let certificate = dir.path().join("cert.pem");
+        let private_key = dir.path().join("key.pem");
+        let first = self_signed(&certificate, &private_key);
+        let credentials = TlsCredentials::new(&certificate, &private_key, None).unwrap();
+        assert_eq!(current_certificate(&credentials), first);
+
+        // Credentials that can't be loaded leave the current ones in place.
+        std::fs::write(&private_key, "not a key").unwrap();
+        assert!(credentials.reload().is_err());
+        assert_eq!(current_certificate(&credentials), first);
+
+        let second = self_signed(&certificate, &private_key);
+        credentials.reload().unwrap();
+        assert_eq!(current_certificate(&credentials), second);
+    }
+}
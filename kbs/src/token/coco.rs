@@ -18,6 +18,7 @@ use serde_json::Value;
 
 pub struct CoCoAttestationTokenVerifier {
     trusted_certs: Option<X509Store>,
+    clock_skew_tolerance: i64,
 }
 
 impl CoCoAttestationTokenVerifier {
@@ -36,7 +37,10 @@ impl CoCoAttestationTokenVerifier {
             None => None,
         };
 
-        Ok(Self { trusted_certs })
+        Ok(Self {
+            trusted_certs,
+            clock_skew_tolerance: config.clock_skew_tolerance.try_into()?,
+        })
     }
 }
 
@@ -56,17 +60,7 @@ impl AttestationTokenVerifier for CoCoAttestationTokenVerifier {
         let claims_value = serde_json::from_slice::<Value>(&claims)?;
 
         let now = time::OffsetDateTime::now_utc().unix_timestamp();
-        let Some(exp) = claims_value["exp"].as_i64() else {
-            bail!("token expiration unset");
-        };
-        if exp < now {
-            bail!("token expired");
-        }
-        if let Some(nbf) = claims_value["nbf"].as_i64() {
-            if nbf > now {
-                bail!("before validity");
-            }
-        }
+        check_validity(&claims_value, now, self.clock_skew_tolerance)?;
 
         let jwk_value = claims_value["jwk"].as_object().ok_or_else(|| anyhow!("CoCo Attestation Token Claims must contain public key (JWK format) to verify signature"))?;
         let jwk = serde_json::to_string(&jwk_value)?;
@@ -75,20 +69,21 @@ impl AttestationTokenVerifier for CoCoAttestationTokenVerifier {
             .as_bytes()
             .to_vec();
 
-        match header_value["alg"].as_str() {
-            Some("RS384") => {
-                if rsa_jwk.alg != *"RS384" {
-                    bail!("Unmatched RSA JWK alg");
-                }
-                rs384_verify(&payload, &signature, &rsa_jwk)?;
-            }
+        // RS256 is the algorithm of the tokens issued as OIDC ID tokens.
+        let digest = match header_value["alg"].as_str() {
+            Some("RS256") => MessageDigest::sha256(),
+            Some("RS384") => MessageDigest::sha384(),
             None => {
                 bail!("Miss `alg` in JWT header")
             }
             _ => {
                 bail!("Unsupported JWT algrithm")
             }
+        };
+        if header_value["alg"] != rsa_jwk.alg {
+            bail!("Unmatched RSA JWK alg");
         }
+        rsa_verify(digest, &payload, &signature, &rsa_jwk)?;
 
         let Some(trusted_store) = &self.trusted_certs else {
             log::warn!("No Trusted Certificate in Config, skip verification of JWK cert of Attestation Token");
@@ -136,6 +131,28 @@ impl AttestationTokenVerifier for CoCoAttestationTokenVerifier {
     }
 }
 
+/// Check the validity period of the token of claims `claims` at `now`,
+/// tolerating clocks that differ by `leeway` seconds.
+fn check_validity(claims: &Value, now: i64, leeway: i64) -> Result<()> {
+    let Some(exp) = claims["exp"].as_i64() else {
+        bail!("token expiration unset");
+    };
+    if exp < now - leeway {
+        bail!("token expired");
+    }
+    if let Some(nbf) = claims["nbf"].as_i64() {
+        if nbf > now + leeway {
+            bail!("before validity");
+        }
+    }
+    if let Some(iat) = claims["iat"].as_i64() {
+        if iat > now + leeway {
+            bail!("token issued in the future");
+        }
+    }
+    Ok(())
+}
+
 #[allow(dead_code)]
 #[derive(serde::Deserialize, Clone, Debug)]
 struct RsaJWK {
@@ -147,17 +164,17 @@ struct RsaJWK {
     x5c: Option<Vec<String>>,
 }
 
-fn rs384_verify(payload: &[u8], signature: &[u8], jwk: &RsaJWK) -> Result<()> {
+fn rsa_verify(digest: MessageDigest, payload: &[u8], signature: &[u8], jwk: &RsaJWK) -> Result<()> {
     let n = openssl::bn::BigNum::from_slice(&URL_SAFE_NO_PAD.decode(&jwk.n)?)?;
     let e = openssl::bn::BigNum::from_slice(&URL_SAFE_NO_PAD.decode(&jwk.e)?)?;
     let rsa_public_key = Rsa::from_public_components(n, e)?;
     let rsa_pkey = PKey::from_rsa(rsa_public_key)?;
 
-    let mut verifier = Verifier::new(MessageDigest::sha384(), &rsa_pkey)?;
+    let mut verifier = Verifier::new(digest, &rsa_pkey)?;
     verifier.update(payload)?;
 
     if !verifier.verify(signature)? {
-        bail!("RS384 verify failed")
+        bail!("{} verify failed", jwk.alg)
     }
 
     Ok(())
@@ -198,6 +215,27 @@ fn parse_pem_cert_chain(pem_cert_chain: String, chain: &mut Vec<X509>) -> Result
 #[allow(unused_imports)]
 mod test {
     use super::*;
+    #[test]
+    fn test_check_validity() {
+        for (claims, leeway, error) in [
+            (r#"{"exp": 1000}"#, 0, None),
+            (r#"{}"#, 0, Some("token expiration unset")),
+            (r#"{"exp": 990}"#, 0, Some("token expired")),
+            (r#"{"exp": 990}"#, 10, None),
+            (r#"{"exp": 1100, "nbf": 1005}"#, 0, Some("before validity")),
+            (r#"{"exp": 1100, "nbf": 1005}"#, 10, None),
+            (
+                r#"{"exp": 1100, "iat": 1005}"#,
+                0,
+                Some("token issued in the future"),
+            ),
+            (r#"{"exp": 1100, "iat": 1005, "nbf": 1005}"#, 5, None),
+        ] {
+            let claims: Value = serde_json::from_str(claims).unwrap();
+            let res = check_validity(&claims, 1000, leeway);
+            assert_eq!(res.err().map(|e| e.to_string()).as_deref(), error);
+        }
+    }
 
     #[test]
     fn test_parse_pem_cert_chain() {
@@ -0,0 +1,287 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exchange of attestation results tokens for narrower tokens, after RFC 8693.
+//!
+//! The holder of an attestation results token has KBS issue a token for one
+//! audience, carrying only the claims it asks for and expiring sooner, and
+//! passes that token to a third-party service instead of its attestation
+//! results token. The exchanged tokens are signed by KBS with an Ed25519 key,
+//! whose public key is served as a JWKS for the services to verify them.
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jwt_simple::prelude::{Claims, Duration, Ed25519KeyPair, EdDSAKeyPairLike};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::path::PathBuf;
+use time::OffsetDateTime;
+
+use crate::crypto;
+use crate::identity::identity_of;
+
+/// Default longest lifetime of the exchanged tokens, in seconds.
+const DEFAULT_MAX_TTL: u64 = 300;
+
+/// Default issuer of the exchanged tokens.
+const DEFAULT_ISSUER: &str = "kbs";
+
+/// Claims set by KBS on every exchanged token, which can't be requested.
+const REGISTERED_CLAIMS: [&str; 8] = ["iss", "sub", "aud", "exp", "nbf", "iat", "jti", "scope"];
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct TokenExchangeConfig {
+    /// Ed25519 private key (PEM) the exchanged tokens are signed with, to be
+    /// shared by the KBS instances behind a load balancer. A random key is
+    /// generated at start when omitted, so the tokens exchanged before a
+    /// restart can't be verified anymore.
+    pub signing_key_path: Option<PathBuf>,
+
+    /// `iss` claim of the exchanged tokens.
+    #[serde(default = "default_issuer")]
+    pub issuer: String,
+
+    /// Longest lifetime of the exchanged tokens, in seconds. A token never
+    /// outlives the attestation results token it was exchanged for.
+    #[serde(default = "default_max_ttl")]
+    pub max_ttl: u64,
+
+    /// Audiences tokens may be exchanged for, at least one. The issuer of the
+    /// exchanged tokens can't be one of them.
+    pub audiences: Vec<String>,
+}
+
+fn default_issuer() -> String {
+    DEFAULT_ISSUER.to_string()
+}
+
+fn default_max_ttl() -> u64 {
+    DEFAULT_MAX_TTL
+}
+
+/// A token issued by an exchange.
+#[derive(Debug)]
+pub(crate) struct ExchangedToken {
+    pub token: String,
+
+    /// Seconds until the token expires.
+    pub expires_in: i64,
+
+    /// Claims of the attestation results token the token carries.
+    pub scope: Vec<String>,
+}
+
+/// Issues the narrower tokens of the attestation results tokens.
+pub(crate) struct TokenExchanger {
+    key_pair: Ed25519KeyPair,
+    issuer: String,
+    max_ttl: u64,
+    audiences: Vec<String>,
+}
+
+impl TokenExchanger {
+    pub fn new(config: &TokenExchangeConfig) -> Result<Self> {
+        if config.max_ttl == 0 {
+            bail!("max_ttl of the exchanged tokens must be positive");
+        }
+        if config.audiences.is_empty() {
+            bail!("no audiences the tokens may be exchanged for");
+        }
+        if config.audiences.contains(&config.issuer) {
+            bail!(
+                "tokens can't be exchanged for their issuer {}",
+                config.issuer
+            );
+        }
+        let key_pair = match &config.signing_key_path {
+            Some(path) => {
+                let pem = std::fs::read_to_string(path).with_context(|| {
+                    format!("read token exchange signing key {}", path.display())
+                })?;
+                Ed25519KeyPair::from_pem(&pem).context("parse token exchange signing key")?
+            }
+            None => Ed25519KeyPair::generate(),
+        };
+        let key_id = thumbprint(&key_pair)?;
+
+        Ok(Self {
+            key_pair: key_pair.with_key_id(&key_id),
+            issuer: config.issuer.clone(),
+            max_ttl: config.max_ttl,
+            audiences: config.audiences.clone(),
+        })
+    }
+
+    /// Issue a token for `audience` with the claims named by `scope` of the
+    /// verified attestation results token claims `claims`. The workload
+    /// identity of the claims, if any, is the subject of the token.
+    pub fn exchange(
+        &self,
+        claims: &Value,
+        audience: &str,
+        scope: &[String],
+    ) -> Result<ExchangedToken> {
+        if audience.is_empty() {
+            bail!("no audience requested");
+        }
+        if !self.audiences.iter().any(|a| a == audience) {
+            bail!("tokens can't be exchanged for audience {audience}");
+        }
+
+        let mut custom = Map::new();
+        for name in scope {
+            if REGISTERED_CLAIMS.contains(&name.as_str()) {
+                bail!("claim {name} can't be requested");
+            }
+            let value = claims
+                .get(name)
+                .with_context(|| format!("the attestation results token has no claim {name}"))?;
+            custom.insert(name.clone(), value.clone());
+        }
+        if !scope.is_empty() {
+            custom.insert("scope".to_string(), scope.join(" ").into());
+        }
+
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let subject_expires_in = claims["exp"]
+            .as_i64()
+            .context("the attestation results token has no expiration")?
+            - now;
+        let expires_in = subject_expires_in.min(self.max_ttl as i64);
+        if expires_in <= 0 {
+            bail!("the attestation results token is expired");
+        }
+
+        let mut token_claims =
+            Claims::with_custom_claims(custom, Duration::from_secs(expires_in as u64))
+                .with_issuer(&self.issuer)
+                .with_audience(audience)
+                .with_jwt_id(uuid::Uuid::new_v4().to_string());
+        if let Some(identity) = identity_of(claims) {
+            token_claims = token_claims.with_subject(identity);
+        }
+
+        Ok(ExchangedToken {
+            token: self.key_pair.sign(token_claims)?,
+            expires_in,
+            scope: scope.to_vec(),
+        })
+    }
+
+    /// The public key verifying the exchanged tokens, as a JWKS.
+    pub fn jwks(&self) -> Value {
+        json!({
+            "keys": [{
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "use": "sig",
+                "kid": self.key_pair.key_id(),
+                "x": URL_SAFE_NO_PAD.encode(self.key_pair.public_key().to_bytes()),
+            }],
+        })
+    }
+}
+
+/// The RFC 7638 thumbprint of the public key of `key_pair`.
+fn thumbprint(key_pair: &Ed25519KeyPair) -> Result<String> {
+    let x = URL_SAFE_NO_PAD.encode(key_pair.public_key().to_bytes());
+    let jwk = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{x}"}}"#);
+    Ok(URL_SAFE_NO_PAD.encode(crypto::sha256(jwk.as_bytes())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jwt_simple::prelude::{Ed25519PublicKey, EdDSAPublicKeyLike, VerificationOptions};
+    use rstest::rstest;
+    use std::collections::HashSet;
+
+    fn exchanger(audiences: &[&str]) -> TokenExchanger {
+        TokenExchanger::new(&TokenExchangeConfig {
+            signing_key_path: None,
+            issuer: default_issuer(),
+            max_ttl: 60,
+            audiences: audiences.iter().map(|a| a.to_string()).collect(),
+        })
+        .unwrap()
+    }
+
+    fn claims(exp: i64) -> Value {
+        json!({
+            "exp": OffsetDateTime::now_utc().unix_timestamp() + exp,
+            "tee": "tdx",
+            "tcb-status": {"tdx.quote.body.mr_td": "abcd"},
+            "workload_identity": "tdx/abcd",
+        })
+    }
+
+    #[test]
+    fn test_exchange() {
+        let exchanger = exchanger(&["storage"]);
+        let scope = vec!["tee".to_string()];
+        let exchanged = exchanger
+            .exchange(&claims(3600), "storage", &scope)
+            .unwrap();
+        assert_eq!(exchanged.expires_in, 60);
+
+        let jwks = exchanger.jwks();
+        let x = URL_SAFE_NO_PAD
+            .decode(jwks["keys"][0]["x"].as_str().unwrap())
+            .unwrap();
+        let verified = Ed25519PublicKey::from_bytes(&x)
+            .unwrap()
+            .verify_token::<Map<String, Value>>(
+                &exchanged.token,
+                Some(VerificationOptions {
+                    allowed_audiences: Some(HashSet::from(["storage".to_string()])),
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+        assert_eq!(verified.subject.as_deref(), Some("tdx/abcd"));
+        assert_eq!(verified.issuer.as_deref(), Some(DEFAULT_ISSUER));
+        assert_eq!(verified.custom["tee"], "tdx");
+        assert_eq!(verified.custom["scope"], "tee");
+        assert!(!verified.custom.contains_key("tcb-status"));
+
+        // A token never outlives the attestation results token.
+        let exchanged = exchanger.exchange(&claims(10), "storage", &[]).unwrap();
+        assert!(exchanged.expires_in <= 10);
+    }
+
+    #[rstest]
+    #[case(3600, "storage", "tee", true)]
+    #[case(3600, "other", "tee", false)]
+    #[case(3600, "", "tee", false)]
+    #[case(3600, "storage", "missing", false)]
+    #[case(3600, "storage", "exp", false)]
+    #[case(3600, "storage", "scope", false)]
+    #[case(3600, "kbs", "tee", false)]
+    #[case(-10, "storage", "tee", false)]
+    fn test_exchange_rejected(
+        #[case] exp: i64,
+        #[case] audience: &str,
+        #[case] claim: &str,
+        #[case] ok: bool,
+    ) {
+        let exchanger = exchanger(&["storage"]);
+        let result = exchanger.exchange(&claims(exp), audience, &[claim.to_string()]);
+        assert_eq!(result.is_ok(), ok);
+    }
+
+    #[rstest]
+    #[case(&[])]
+    #[case(&["storage", "kbs"])]
+    fn test_audiences_rejected(#[case] audiences: &[&str]) {
+        assert!(TokenExchanger::new(&TokenExchangeConfig {
+            signing_key_path: None,
+            issuer: default_issuer(),
+            max_ttl: 60,
+            audiences: audiences.iter().map(|a| a.to_string()).collect(),
+        })
+        .is_err());
+    }
+}
@@ -4,13 +4,17 @@
 
 use anyhow::*;
 use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use serde::Deserialize;
+use serde_json::Value;
 use std::fmt;
 use std::sync::Arc;
 use strum::EnumString;
 use tokio::sync::RwLock;
 
 mod coco;
+pub(crate) mod exchange;
+pub(crate) mod revocation;
 
 #[async_trait]
 pub trait AttestationTokenVerifier {
@@ -30,6 +34,11 @@ pub struct AttestationTokenVerifierConfig {
 
     // Trusted Certificates file (PEM format) path to verify Attestation Token Signature.
     pub trusted_certs_paths: Option<Vec<String>>,
+
+    /// Seconds the clocks of KBS and of the token issuer may differ by when
+    /// checking the `exp`, `nbf` and `iat` claims of a token.
+    #[serde(default)]
+    pub clock_skew_tolerance: u64,
 }
 
 impl Default for AttestationTokenVerifierConfig {
@@ -37,6 +46,7 @@ impl Default for AttestationTokenVerifierConfig {
         Self {
             attestation_token_type: AttestationTokenVerifierType::CoCo,
             trusted_certs_paths: None,
+            clock_skew_tolerance: 0,
         }
     }
 }
@@ -52,8 +62,35 @@ pub fn create_token_verifier(
     }
 }
 
+/// The expiration time of `token`, read without verifying it.
+pub(crate) fn expiration(token: &str) -> Option<i64> {
+    let claims = token.split('.').nth(1)?;
+    let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+    claims["exp"].as_i64()
+}
+
 impl fmt::Display for AttestationTokenVerifierType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiration() {
+        let token = |claims: &str| {
+            format!(
+                "{}.{}.{}",
+                URL_SAFE_NO_PAD.encode(r#"{"alg":"RS384"}"#),
+                URL_SAFE_NO_PAD.encode(claims),
+                URL_SAFE_NO_PAD.encode("signature"),
+            )
+        };
+        assert_eq!(expiration(&token(r#"{"exp": 42}"#)), Some(42));
+        assert_eq!(expiration(&token("{}")), None);
+        assert_eq!(expiration("not a token"), None);
+    }
+}
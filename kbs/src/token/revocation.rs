@@ -0,0 +1,124 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Revocation of attestation results tokens before they expire.
+//!
+//! An admin revokes a token, e.g. of a compromised workload, and KBS rejects
+//! it from then on like a token failing verification. The revoked tokens are
+//! kept by digest until they expire, by every KBS process on its own.
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+
+use super::{expiration, AttestationTokenVerifier};
+
+/// The revoked attestation results tokens, by hex encoded SHA-256 digest,
+/// with their expiration times.
+#[derive(Default)]
+pub(crate) struct RevokedTokens {
+    tokens: scc::HashMap<String, i64>,
+}
+
+impl RevokedTokens {
+    /// Revoke `token` until it expires, or for good if it has no `exp` claim.
+    /// Returns false if it already was revoked.
+    pub async fn revoke(&self, token: &str) -> bool {
+        let expires = expiration(token).unwrap_or(i64::MAX);
+        self.tokens
+            .insert_async(hex::encode(Sha256::digest(token)), expires)
+            .await
+            .is_ok()
+    }
+
+    pub async fn is_revoked(&self, token: &str) -> bool {
+        self.tokens
+            .contains_async(&hex::encode(Sha256::digest(token)))
+            .await
+    }
+
+    /// Forget the revoked tokens that expired, which are rejected anyway.
+    pub async fn prune(&self) {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        self.tokens.retain_async(|_, expires| *expires >= now).await;
+    }
+}
+
+/// A token verifier rejecting the revoked tokens before `verifier` verifies
+/// them.
+struct RevocationCheck {
+    verifier: Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>,
+    revoked: Arc<RevokedTokens>,
+}
+
+#[async_trait]
+impl AttestationTokenVerifier for RevocationCheck {
+    async fn verify(&self, token: String) -> Result<String> {
+        if self.revoked.is_revoked(&token).await {
+            bail!("the token is revoked");
+        }
+        self.verifier.read().await.verify(token).await
+    }
+}
+
+/// `verifier`, rejecting the tokens of `revoked` as well.
+pub(crate) fn check_revocations(
+    verifier: Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>>,
+    revoked: Arc<RevokedTokens>,
+) -> Arc<RwLock<dyn AttestationTokenVerifier + Send + Sync>> {
+    Arc::new(RwLock::new(RevocationCheck { verifier, revoked }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    fn token(claims: &str) -> String {
+        format!(
+            "{}.{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"EdDSA"}"#),
+            URL_SAFE_NO_PAD.encode(claims),
+            URL_SAFE_NO_PAD.encode("signature"),
+        )
+    }
+
+    struct AcceptAll;
+
+    #[async_trait]
+    impl AttestationTokenVerifier for AcceptAll {
+        async fn verify(&self, _token: String) -> Result<String> {
+            Ok("{}".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoked_tokens() {
+        let revoked = Arc::new(RevokedTokens::default());
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let (expired, unexpired) = (
+            token(&format!(r#"{{"exp": {}}}"#, now - 60)),
+            token(&format!(r#"{{"exp": {}}}"#, now + 60)),
+        );
+        let verifier = check_revocations(Arc::new(RwLock::new(AcceptAll)), revoked.clone());
+        let verifier = verifier.read().await;
+
+        assert!(verifier.verify(unexpired.clone()).await.is_ok());
+        assert!(revoked.revoke(&unexpired).await);
+        assert!(!revoked.revoke(&unexpired).await);
+        assert!(revoked.revoke(&expired).await);
+        assert!(revoked.revoke("opaque").await);
+        assert!(revoked.is_revoked(&unexpired).await);
+        assert!(!revoked.is_revoked(&token("{}")).await);
+        assert!(verifier.verify(unexpired).await.is_err());
+
+        revoked.prune().await;
+        assert!(revoked.is_revoked(&unexpired).await);
+        assert!(revoked.is_revoked("opaque").await);
+        assert!(!revoked.is_revoked(&expired).await);
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Conversion of common provenance formats into RVPS messages carrying
+//! `sample` provenances.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use clap::ValueEnum;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Version of the generated messages.
+const MESSAGE_VERSION: &str = "0.1.0";
+
+/// DSSE payload type of in-toto statements.
+const IN_TOTO_PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+
+/// Default digest algorithm taken from SLSA subjects.
+pub const DEFAULT_SLSA_ALG: &str = "sha256";
+
+/// Formats of the provenances to convert.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    /// An in-toto statement of SLSA provenance, bare or in a DSSE envelope.
+    /// Every subject becomes a reference value of its digest.
+    Slsa,
+
+    /// A JSON object mapping the names of the reference values to a digest
+    /// or a list of digests.
+    Simple,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope {
+    payload_type: String,
+    payload: String,
+}
+
+#[derive(Deserialize)]
+struct Statement {
+    subject: Vec<Subject>,
+}
+
+#[derive(Deserialize)]
+struct Subject {
+    name: String,
+    digest: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Digests {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Reference values of the subjects of a SLSA provenance, using their digest
+/// of algorithm `alg`.
+fn from_slsa(provenance: &str, alg: &str) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut document: Value = serde_json::from_str(provenance).context("parse SLSA provenance")?;
+    if document.get("payloadType").is_some() {
+        let envelope: Envelope = serde_json::from_value(document).context("parse DSSE envelope")?;
+        if envelope.payload_type != IN_TOTO_PAYLOAD_TYPE {
+            bail!("Unsupported DSSE payload type {}", envelope.payload_type);
+        }
+        let payload = base64::engine::general_purpose::STANDARD
+            .decode(envelope.payload)
+            .context("base64 decode DSSE payload")?;
+        document = serde_json::from_slice(&payload).context("parse in-toto statement")?;
+    }
+
+    let statement: Statement =
+        serde_json::from_value(document).context("parse in-toto statement")?;
+    let mut rvs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for subject in statement.subject {
+        let digest = subject
+            .digest
+            .get(alg)
+            .ok_or_else(|| anyhow!("Subject {} has no {alg} digest", subject.name))?;
+        rvs.entry(subject.name).or_default().push(digest.clone());
+    }
+    Ok(rvs)
+}
+
+/// Reference values of a simple JSON provenance.
+fn from_simple(provenance: &str) -> Result<BTreeMap<String, Vec<String>>> {
+    let rvs: BTreeMap<String, Digests> =
+        serde_json::from_str(provenance).context("parse simple provenance")?;
+    Ok(rvs
+        .into_iter()
+        .map(|(name, digests)| match digests {
+            Digests::One(digest) => (name, vec![digest]),
+            Digests::Many(digests) => (name, digests),
+        })
+        .collect())
+}
+
+/// Convert `provenance` of the given format into an RVPS message. `alg` is
+/// the digest algorithm taken from SLSA subjects.
+pub fn to_message(provenance: &str, format: Format, alg: &str) -> Result<String> {
+    let rvs = match format {
+        Format::Slsa => from_slsa(provenance, alg)?,
+        Format::Simple => from_simple(provenance)?,
+    };
+    if rvs.is_empty() {
+        bail!("No reference value in the provenance");
+    }
+
+    let payload = base64::engine::general_purpose::STANDARD.encode(serde_json::to_vec(&rvs)?);
+    let message = json!({
+        "version": MESSAGE_VERSION,
+        "type": "sample",
+        "payload": payload,
+    });
+    Ok(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(message: &str) -> Value {
+        let message: Value = serde_json::from_str(message).unwrap();
+        assert_eq!(message["version"], MESSAGE_VERSION);
+        assert_eq!(message["type"], "sample");
+        let payload = base64::engine::general_purpose::STANDARD
+            .decode(message["payload"].as_str().unwrap())
+            .unwrap();
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    #[test]
+    fn test_slsa() {
+        let statement = json!({
+            "_type": "https://in-toto.io/Statement/v0.1",
+            "predicateType": "https://slsa.dev/provenance/v0.2",
+            "subject": [
+                {"name": "kernel", "digest": {"sha256": "aa", "sha384": "bb"}},
+                {"name": "initrd", "digest": {"sha256": "cc"}},
+                {"name": "kernel", "digest": {"sha256": "dd"}}
+            ],
+            "predicate": {}
+        })
+        .to_string();
+        let expected = json!({"initrd": ["cc"], "kernel": ["aa", "dd"]});
+
+        let message = to_message(&statement, Format::Slsa, DEFAULT_SLSA_ALG).unwrap();
+        assert_eq!(payload(&message), expected);
+
+        let envelope = json!({
+            "payloadType": IN_TOTO_PAYLOAD_TYPE,
+            "payload": base64::engine::general_purpose::STANDARD.encode(&statement),
+            "signatures": []
+        })
+        .to_string();
+        let message = to_message(&envelope, Format::Slsa, DEFAULT_SLSA_ALG).unwrap();
+        assert_eq!(payload(&message), expected);
+
+        // initrd has no sha384 digest
+        assert!(to_message(&statement, Format::Slsa, "sha384").is_err());
+    }
+
+    #[test]
+    fn test_simple() {
+        let provenance = r#"{"kernel": ["aa", "bb"], "initrd": "cc"}"#;
+        let message = to_message(provenance, Format::Simple, DEFAULT_SLSA_ALG).unwrap();
+        assert_eq!(
+            payload(&message),
+            json!({"initrd": ["cc"], "kernel": ["aa", "bb"]})
+        );
+
+        assert!(to_message("{}", Format::Simple, DEFAULT_SLSA_ALG).is_err());
+        assert!(to_message(r#"{"kernel": 1}"#, Format::Simple, DEFAULT_SLSA_ALG).is_err());
+    }
+}
@@ -2,8 +2,16 @@
 
 use anyhow::*;
 use clap::{Args, Parser};
-use log::info;
+use core::result::Result::Ok;
+use log::{error, info};
+use reference_value_provider_service::{config::DEFAULT_STORAGE_TYPE, snapshot, Config, Core};
 use shadow_rs::shadow;
+use std::path::{Path, PathBuf};
+use tonic::transport::Channel;
+
+mod convert;
+
+use convert::{Format, DEFAULT_SLSA_ALG};
 
 pub mod rvps_api {
     tonic::include_proto!("reference");
@@ -11,7 +19,7 @@ pub mod rvps_api {
 
 use crate::rvps_api::{
     reference_value_provider_service_client::ReferenceValueProviderServiceClient,
-    ReferenceValueQueryRequest, ReferenceValueRegisterRequest,
+    ReferenceValueDeleteRequest, ReferenceValueQueryRequest, ReferenceValueRegisterRequest,
 };
 
 shadow!(build);
@@ -19,22 +27,79 @@ shadow!(build);
 /// Default address of RVPS
 const DEFAULT_ADDR: &str = "http://127.0.0.1:50003";
 
-async fn register(addr: &str, provenance_path: &str) -> Result<()> {
-    let message = std::fs::read_to_string(provenance_path).context("read provenance")?;
-    let mut client = ReferenceValueProviderServiceClient::connect(addr.to_string()).await?;
-    let req = tonic::Request::new(ReferenceValueRegisterRequest { message });
+/// The provenance files of `paths`, where each directory stands for the
+/// `.json` files inside it.
+fn provenance_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(path).with_context(|| format!("read {}", path.display()))? {
+            let entry = entry?.path();
+            if entry.is_file() && entry.extension().is_some_and(|ext| ext == "json") {
+                entries.push(entry);
+            }
+        }
+        entries.sort();
+        files.extend(entries);
+    }
+    Ok(files)
+}
+
+/// Read the provenance file `path` as an RVPS message, converting it from
+/// `format` if given.
+fn read_message(path: &Path, format: Option<Format>, alg: &str) -> Result<String> {
+    let provenance = std::fs::read_to_string(path).context("read provenance")?;
+    match format {
+        Some(format) => convert::to_message(&provenance, format, alg),
+        None => Ok(provenance),
+    }
+}
 
+async fn register_file(
+    client: &mut ReferenceValueProviderServiceClient<Channel>,
+    path: &Path,
+    args: &RegisterArgs,
+) -> Result<()> {
+    let message = read_message(path, args.format, &args.alg)?;
+    let req = tonic::Request::new(ReferenceValueRegisterRequest {
+        message,
+        namespace: args.namespace.clone().unwrap_or_default(),
+    });
     client.register_reference_value(req).await?;
+    Ok(())
+}
+
+async fn register(args: RegisterArgs) -> Result<()> {
+    let files = provenance_files(&args.path)?;
+    let mut client = ReferenceValueProviderServiceClient::connect(args.addr.clone()).await?;
 
-    info!("Register provenance succeeded.");
+    let mut failed = 0;
+    for file in &files {
+        match register_file(&mut client, file, &args).await {
+            Ok(()) => info!("Register provenance {} succeeded.", file.display()),
+            Err(e) => {
+                error!("Register provenance {} failed: {e:#}", file.display());
+                failed += 1;
+            }
+        }
+    }
 
+    if failed != 0 {
+        bail!("{failed} of {} provenances failed to register", files.len());
+    }
     Ok(())
 }
 
-async fn query(addr: &str, name: &str) -> Result<()> {
-    let mut client = ReferenceValueProviderServiceClient::connect(addr.to_string()).await?;
+async fn query(args: QueryArgs) -> Result<()> {
+    let mut client = ReferenceValueProviderServiceClient::connect(args.addr).await?;
     let req = tonic::Request::new(ReferenceValueQueryRequest {
-        name: name.to_string(),
+        name: args.name,
+        namespace: args.namespace.unwrap_or_default(),
     });
 
     let rvs = client
@@ -46,6 +111,65 @@ async fn query(addr: &str, name: &str) -> Result<()> {
     Ok(())
 }
 
+async fn delete(args: DeleteArgs) -> Result<()> {
+    let mut client = ReferenceValueProviderServiceClient::connect(args.addr).await?;
+    for name in &args.name {
+        let req = tonic::Request::new(ReferenceValueDeleteRequest {
+            name: name.clone(),
+            namespace: args.namespace.clone().unwrap_or_default(),
+        });
+        let deleted = client
+            .delete_reference_value(req)
+            .await?
+            .into_inner()
+            .deleted;
+        match deleted {
+            true => info!("Delete reference value of {name} succeeded."),
+            false => info!("No reference value of {name} to delete."),
+        }
+    }
+    Ok(())
+}
+
+fn convert(args: ConvertArgs) -> Result<()> {
+    let message = read_message(&args.input, Some(args.format), &args.alg)?;
+    match args.output {
+        Some(output) => std::fs::write(&output, message)
+            .with_context(|| format!("write {}", output.display()))?,
+        None => println!("{message}"),
+    }
+    Ok(())
+}
+
+/// The RVPS core of the local store of `args`.
+fn local_core(args: &StoreArgs) -> Result<Core> {
+    let store_config = serde_json::from_str(&args.store_config).context("parse store config")?;
+    Core::new(Config {
+        store_type: args.store_type.clone(),
+        store_config,
+    })
+}
+
+async fn export(args: ExportArgs) -> Result<()> {
+    let key = snapshot::load_signing_key(&args.key)?;
+    let snapshot = local_core(&args.store)?.export_snapshot(&key).await?;
+    std::fs::write(&args.output, snapshot)
+        .with_context(|| format!("write {}", args.output.display()))?;
+    info!("Export snapshot {} succeeded.", args.output.display());
+    Ok(())
+}
+
+async fn import(args: ImportArgs) -> Result<()> {
+    let public_key = snapshot::load_public_key(&args.public_key)?;
+    let snapshot = std::fs::read(&args.snapshot)
+        .with_context(|| format!("read {}", args.snapshot.display()))?;
+    let count = local_core(&args.store)?
+        .import_snapshot(&snapshot, &public_key)
+        .await?;
+    info!("Import {count} reference values succeeded.");
+    Ok(())
+}
+
 /// RVPS command-line arguments.
 #[derive(Parser)]
 #[command(name = "rvps-tool")]
@@ -57,6 +181,18 @@ enum Cli {
 
     /// Query reference values
     Query(QueryArgs),
+
+    /// Delete reference values
+    Delete(DeleteArgs),
+
+    /// Convert a provenance into an RVPS message
+    Convert(ConvertArgs),
+
+    /// Export the reference values of a local store as a signed snapshot
+    Export(ExportArgs),
+
+    /// Import the reference values of a signed snapshot into a local store
+    Import(ImportArgs),
 }
 
 #[derive(Args)]
@@ -66,9 +202,24 @@ struct RegisterArgs {
     #[arg(short, long, default_value = DEFAULT_ADDR)]
     addr: String,
 
-    /// The path to the provenance json file
+    /// The path to a provenance json file, or to a directory of them. Can be
+    /// given multiple times
+    #[arg(short, long, required = true)]
+    path: Vec<PathBuf>,
+
+    /// Convert the provenances from this format instead of reading them as
+    /// RVPS messages
     #[arg(short, long)]
-    path: String,
+    format: Option<Format>,
+
+    /// The digest algorithm taken from SLSA subjects
+    #[arg(long, default_value = DEFAULT_SLSA_ALG)]
+    alg: String,
+
+    /// The namespace to register the reference values in, instead of the
+    /// default one
+    #[arg(long)]
+    namespace: Option<String>,
 }
 
 #[derive(Args)]
@@ -81,6 +232,89 @@ struct QueryArgs {
     /// The name to query reference value
     #[arg(short, long)]
     name: String,
+
+    /// The namespace of the reference value, instead of the default one
+    #[arg(long)]
+    namespace: Option<String>,
+}
+
+#[derive(Args)]
+#[command(author, version, about, long_about = None)]
+struct DeleteArgs {
+    /// The address of target RVPS
+    #[arg(short, long, default_value = DEFAULT_ADDR)]
+    addr: String,
+
+    /// The name of the reference value to delete. Can be given multiple times
+    #[arg(short, long, required = true)]
+    name: Vec<String>,
+
+    /// The namespace of the reference values, instead of the default one
+    #[arg(long)]
+    namespace: Option<String>,
+}
+
+#[derive(Args)]
+#[command(author, version, about, long_about = None)]
+struct ConvertArgs {
+    /// The format of the provenance
+    #[arg(short, long)]
+    format: Format,
+
+    /// The path to the provenance
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// The path to write the RVPS message to, instead of the standard output
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// The digest algorithm taken from SLSA subjects
+    #[arg(long, default_value = DEFAULT_SLSA_ALG)]
+    alg: String,
+}
+
+/// A store read or written directly rather than through an RVPS, which must
+/// not have it open meanwhile.
+#[derive(Args)]
+struct StoreArgs {
+    /// The type of the store
+    #[arg(long, default_value = DEFAULT_STORAGE_TYPE)]
+    store_type: String,
+
+    /// The JSON configuration of the store
+    #[arg(long, default_value = "{}")]
+    store_config: String,
+}
+
+#[derive(Args)]
+#[command(author, version, about, long_about = None)]
+struct ExportArgs {
+    #[command(flatten)]
+    store: StoreArgs,
+
+    /// The path to the Ed25519 private key (PEM) signing the snapshot
+    #[arg(short, long)]
+    key: PathBuf,
+
+    /// The path to write the snapshot to
+    #[arg(short, long)]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+#[command(author, version, about, long_about = None)]
+struct ImportArgs {
+    #[command(flatten)]
+    store: StoreArgs,
+
+    /// The path to the Ed25519 public key (PEM) verifying the snapshot
+    #[arg(short, long)]
+    public_key: PathBuf,
+
+    /// The path to the snapshot
+    #[arg(short, long)]
+    snapshot: PathBuf,
 }
 
 #[tokio::main]
@@ -99,7 +333,11 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli {
-        Cli::Register(para) => register(&para.addr, &para.path).await,
-        Cli::Query(para) => query(&para.addr, &para.name).await,
+        Cli::Register(para) => register(para).await,
+        Cli::Query(para) => query(para).await,
+        Cli::Delete(para) => delete(para).await,
+        Cli::Convert(para) => convert(para),
+        Cli::Export(para) => export(para).await,
+        Cli::Import(para) => import(para).await,
     }
 }
@@ -3,7 +3,7 @@ use log::{debug, info};
 use reference_value_provider_service::{Config, Core};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::RwLock;
 use tonic::transport::Server;
 use tonic::{Request, Response, Status};
 
@@ -11,18 +11,23 @@ use crate::rvps_api::reference_value_provider_service_server::{
     ReferenceValueProviderService, ReferenceValueProviderServiceServer,
 };
 use crate::rvps_api::{
-    ReferenceValueQueryRequest, ReferenceValueQueryResponse, ReferenceValueRegisterRequest,
-    ReferenceValueRegisterResponse,
+    ReferenceValueDeleteRequest, ReferenceValueDeleteResponse, ReferenceValueQueryRequest,
+    ReferenceValueQueryResponse, ReferenceValueRegisterRequest, ReferenceValueRegisterResponse,
 };
 
 pub mod config;
 
+/// The namespace of a request, `None` for the default one.
+fn namespace(namespace: &str) -> Option<&str> {
+    (!namespace.is_empty()).then_some(namespace)
+}
+
 pub struct RVPSServer {
-    rvps: Arc<Mutex<Core>>,
+    rvps: Arc<RwLock<Core>>,
 }
 
 impl RVPSServer {
-    pub fn new(rvps: Arc<Mutex<Core>>) -> Self {
+    pub fn new(rvps: Arc<RwLock<Core>>) -> Self {
         Self { rvps }
     }
 }
@@ -35,13 +40,16 @@ impl ReferenceValueProviderService for RVPSServer {
     ) -> Result<Response<ReferenceValueQueryResponse>, Status> {
         let request = request.into_inner();
 
-        info!("query {}", request.name);
+        info!(
+            "query {} in namespace {:?}",
+            request.name, request.namespace
+        );
 
         let rvs = self
             .rvps
-            .lock()
+            .read()
             .await
-            .get_digests(&request.name)
+            .get_digests(&request.name, namespace(&request.namespace))
             .await
             .map_err(|e| Status::aborted(format!("Query reference value: {e}")))?
             .map(|rvs| rvs.hash_values)
@@ -65,25 +73,101 @@ impl ReferenceValueProviderService for RVPSServer {
         debug!("registry reference value: {}", request.message);
 
         self.rvps
-            .lock()
+            .write()
             .await
-            .verify_and_extract(&request.message)
+            .verify_and_extract(&request.message, namespace(&request.namespace))
             .await
             .map_err(|e| Status::aborted(format!("Register reference value: {e}")))?;
 
         let res = ReferenceValueRegisterResponse {};
         Ok(Response::new(res))
     }
+
+    async fn delete_reference_value(
+        &self,
+        request: Request<ReferenceValueDeleteRequest>,
+    ) -> Result<Response<ReferenceValueDeleteResponse>, Status> {
+        let request = request.into_inner();
+
+        info!(
+            "delete {} in namespace {:?}",
+            request.name, request.namespace
+        );
+
+        let deleted = self
+            .rvps
+            .write()
+            .await
+            .delete_reference_value(&request.name, namespace(&request.namespace))
+            .await
+            .map_err(|e| Status::aborted(format!("Delete reference value: {e}")))?;
+
+        let res = ReferenceValueDeleteResponse { deleted };
+        Ok(Response::new(res))
+    }
 }
 
 pub async fn start(socket: SocketAddr, config: Config) -> Result<()> {
     let service = Core::new(config)?;
-    let inner = Arc::new(Mutex::new(service));
+    let inner = Arc::new(RwLock::new(service));
     let rvps_server = RVPSServer::new(inner.clone());
 
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_serving::<ReferenceValueProviderServiceServer<RVPSServer>>()
+        .await;
+
     Server::builder()
+        .add_service(health_service)
         .add_service(ReferenceValueProviderServiceServer::new(rvps_server))
         .serve(socket)
         .await
         .context("gRPC error")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let core = Arc::new(RwLock::new(
+            Core::new(Config {
+                store_type: "LocalFs".into(),
+                store_config: json!({"file_path": dir.path()}),
+            })
+            .unwrap(),
+        ));
+        let server = RVPSServer::new(core.clone());
+
+        let payload = json!({"kernel": ["aa"]}).to_string();
+        let message = json!({
+            "version": "0.1.0",
+            "type": "sample",
+            "payload": STANDARD.encode(payload),
+        });
+        server
+            .register_reference_value(Request::new(ReferenceValueRegisterRequest {
+                message: message.to_string(),
+                namespace: String::new(),
+            }))
+            .await
+            .unwrap();
+
+        // A query doesn't wait for the other readers of the store.
+        let _reader = core.read().await;
+        let query = server.query_reference_value(Request::new(ReferenceValueQueryRequest {
+            name: "kernel".into(),
+            namespace: String::new(),
+        }));
+        let response = tokio::time::timeout(Duration::from_secs(5), query)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.into_inner().reference_value_results, r#"["aa"]"#);
+    }
+}
@@ -7,6 +7,7 @@ pub mod config;
 pub mod extractors;
 pub mod pre_processor;
 pub mod reference_value;
+pub mod snapshot;
 pub mod store;
 
 pub use config::Config;
@@ -5,10 +5,12 @@
 
 use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
+use jwt_simple::prelude::{Ed25519KeyPair, Ed25519PublicKey};
 use log::{info, warn};
+use std::collections::HashSet;
 use std::time::SystemTime;
 
-use crate::{store::StoreType, Config};
+use crate::{snapshot, store::StoreType, Config, ReferenceValue};
 
 use super::{
     extractors::{Extractors, ExtractorsImpl},
@@ -16,6 +18,40 @@ use super::{
     Message, Store, TrustedDigest, MESSAGE_VERSION,
 };
 
+/// Separator of the namespace and the name in the keys of the reference
+/// values of a namespace.
+const NAMESPACE_SEPARATOR: &str = "::";
+
+/// Key of the record of the last snapshot imported into a store, whose hash
+/// value is its export time. It is no key of a reference value, which never
+/// starts with the separator.
+const SNAPSHOT_RECORD: &str = "::snapshot";
+
+/// Algorithm of the hash value of the snapshot record.
+const SNAPSHOT_RECORD_ALG: &str = "exported_at";
+
+/// The key the reference value `name` of `namespace` is stored under. The
+/// reference values of the default namespace are stored under their names,
+/// the ones of another namespace under `<namespace>::<name>`, so that the
+/// namespaces of a store never share reference values. Names containing the
+/// separator are rejected, so that no reference value of the default
+/// namespace is stored under the key of another namespace.
+pub fn store_key(namespace: Option<&str>, name: &str) -> Result<String> {
+    if name.contains(NAMESPACE_SEPARATOR) {
+        bail!("Illegal reference value name {name}");
+    }
+    let Some(namespace) = namespace.filter(|namespace| !namespace.is_empty()) else {
+        return Ok(name.to_string());
+    };
+    if !namespace
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        bail!("Illegal reference value namespace {namespace}");
+    }
+    Ok(format!("{namespace}{NAMESPACE_SEPARATOR}{name}"))
+}
+
 /// The core of the RVPS, s.t. componants except communication componants.
 pub struct Core {
     pre_processor: PreProcessor,
@@ -46,7 +82,13 @@ impl Core {
         self
     }
 
-    pub async fn verify_and_extract(&mut self, message: &str) -> Result<()> {
+    /// Verify the provenance of `message` and store the reference values it
+    /// carries in `namespace`, or in the default namespace if `None`.
+    pub async fn verify_and_extract(
+        &mut self,
+        message: &str,
+        namespace: Option<&str>,
+    ) -> Result<()> {
         let mut message: Message = serde_json::from_str(message).context("parse message")?;
 
         // Judge the version field
@@ -62,7 +104,13 @@ impl Core {
 
         let rv = self.extractors.process(message)?;
         for v in rv.iter() {
-            let old = self.store.set(v.name().to_string(), v.clone()).await?;
+            // The key is the name of the stored reference value too, so that
+            // snapshots keep the namespaces.
+            let key = store_key(namespace, v.name())?;
+            let old = self
+                .store
+                .set(key.clone(), v.clone().set_name(&key))
+                .await?;
             if let Some(old) = old {
                 info!("Old Reference value of {} is replaced.", old.name());
             }
@@ -71,8 +119,18 @@ impl Core {
         Ok(())
     }
 
-    pub async fn get_digests(&self, name: &str) -> Result<Option<TrustedDigest>> {
-        let rv = self.store.get(name).await?;
+    /// Get the digests of the reference value `name` of `namespace`, or of
+    /// the default namespace if `None`.
+    pub async fn get_digests(
+        &self,
+        name: &str,
+        namespace: Option<&str>,
+    ) -> Result<Option<TrustedDigest>> {
+        if name.contains(NAMESPACE_SEPARATOR) {
+            // No reference value is stored under such a name.
+            return Ok(None);
+        }
+        let rv = self.store.get(&store_key(namespace, name)?).await?;
         match rv {
             None => Ok(None),
             Some(rv) => {
@@ -95,4 +153,206 @@ impl Core {
             }
         }
     }
+
+    /// Delete the reference value of the given component name of
+    /// `namespace`, or of the default namespace if `None`. Return whether it
+    /// existed.
+    pub async fn delete_reference_value(
+        &mut self,
+        name: &str,
+        namespace: Option<&str>,
+    ) -> Result<bool> {
+        let key = store_key(namespace, name)?;
+        let deleted = self.store.delete(&key).await?;
+        if deleted.is_some() {
+            info!("Reference value of {} is deleted.", key);
+        }
+        Ok(deleted.is_some())
+    }
+
+    /// Export all the reference values as a snapshot signed with `key`.
+    pub async fn export_snapshot(&self, key: &Ed25519KeyPair) -> Result<Vec<u8>> {
+        let mut rvs = self.store.list().await?;
+        rvs.retain(|rv| rv.name != SNAPSHOT_RECORD);
+        rvs.sort_by(|a, b| a.name.cmp(&b.name));
+        snapshot::export(&rvs, key)
+    }
+
+    /// Replace the reference values of the store with the ones of
+    /// `snapshot`, once its signature is verified with `public_key`. A
+    /// snapshot exported before the last one imported is rejected, so that
+    /// withdrawn reference values can't be brought back. Return the number
+    /// of reference values.
+    pub async fn import_snapshot(
+        &mut self,
+        snapshot: &[u8],
+        public_key: &Ed25519PublicKey,
+    ) -> Result<usize> {
+        let snapshot = snapshot::import(snapshot, public_key)?;
+        if let Some(imported_at) = self.snapshot_imported_at().await? {
+            if snapshot.exported_at < imported_at {
+                bail!(
+                    "Snapshot exported at {} is older than the one imported, exported at {}",
+                    snapshot.exported_at,
+                    imported_at
+                );
+            }
+        }
+
+        let names: HashSet<&str> = snapshot.rvs.iter().map(|rv| rv.name.as_str()).collect();
+        for rv in self.store.list().await? {
+            if rv.name != SNAPSHOT_RECORD && !names.contains(rv.name.as_str()) {
+                self.store.delete(&rv.name).await?;
+            }
+        }
+        for rv in &snapshot.rvs {
+            self.store.set(rv.name.clone(), rv.clone()).await?;
+        }
+        let record = ReferenceValue::new()?
+            .set_name(SNAPSHOT_RECORD)
+            .add_hash_value(
+                SNAPSHOT_RECORD_ALG.to_string(),
+                snapshot.exported_at.to_string(),
+            );
+        self.store.set(SNAPSHOT_RECORD.to_string(), record).await?;
+
+        info!("{} reference values are imported.", snapshot.rvs.len());
+        Ok(snapshot.rvs.len())
+    }
+
+    /// Export time of the last snapshot imported into the store, if any.
+    async fn snapshot_imported_at(&self) -> Result<Option<u64>> {
+        let Some(record) = self.store.get(SNAPSHOT_RECORD).await? else {
+            return Ok(None);
+        };
+        let imported_at = record
+            .hash_values()
+            .iter()
+            .find(|pair| pair.alg() == SNAPSHOT_RECORD_ALG)
+            .and_then(|pair| pair.value().parse().ok())
+            .context("Illegal record of the last imported snapshot")?;
+        Ok(Some(imported_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use rstest::rstest;
+    use serde_json::json;
+
+    #[rstest]
+    #[case(None, "kernel", Some("kernel"))]
+    #[case(Some(""), "kernel", Some("kernel"))]
+    #[case(Some("tenant-a"), "kernel", Some("tenant-a::kernel"))]
+    #[case(Some("tenant/a"), "kernel", None)]
+    #[case(Some("tenant:a"), "kernel", None)]
+    #[case(None, "tenant-a::kernel", None)]
+    #[case(Some("tenant-b"), "tenant-a::kernel", None)]
+    fn test_store_key(
+        #[case] namespace: Option<&str>,
+        #[case] name: &str,
+        #[case] expected: Option<&str>,
+    ) {
+        assert_eq!(store_key(namespace, name).ok().as_deref(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_namespaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut core = Core::new(Config {
+            store_type: "LocalFs".into(),
+            store_config: json!({"file_path": dir.path()}),
+        })
+        .unwrap();
+
+        let message = |digest: &str| {
+            let payload = json!({"kernel": [digest]}).to_string();
+            json!({
+                "version": MESSAGE_VERSION,
+                "type": "sample",
+                "payload": STANDARD.encode(payload),
+            })
+            .to_string()
+        };
+        core.verify_and_extract(&message("aa"), None).await.unwrap();
+        core.verify_and_extract(&message("bb"), Some("tenant-a"))
+            .await
+            .unwrap();
+
+        let digests = |digests: Option<TrustedDigest>| digests.unwrap().hash_values;
+        assert_eq!(
+            digests(core.get_digests("kernel", None).await.unwrap()),
+            ["aa"]
+        );
+        assert_eq!(
+            digests(core.get_digests("kernel", Some("tenant-a")).await.unwrap()),
+            ["bb"]
+        );
+        assert!(core
+            .get_digests("kernel", Some("tenant-b"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(core
+            .get_digests("tenant-a::kernel", None)
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(core
+            .delete_reference_value("kernel", Some("tenant-a"))
+            .await
+            .unwrap());
+        assert!(core.get_digests("kernel", None).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_import_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut core = Core::new(Config {
+            store_type: "LocalFs".into(),
+            store_config: json!({"file_path": dir.path()}),
+        })
+        .unwrap();
+        let rv = |name: &str| {
+            ReferenceValue::new()
+                .unwrap()
+                .set_name(name)
+                .add_hash_value("sha256".into(), "aa".into())
+        };
+        core.store.set("initrd".into(), rv("initrd")).await.unwrap();
+
+        let key = Ed25519KeyPair::generate();
+        let snapshot = snapshot::export(&[rv("kernel")], &key).unwrap();
+        assert_eq!(
+            core.import_snapshot(&snapshot, &key.public_key())
+                .await
+                .unwrap(),
+            1
+        );
+        // The reference values missing from the snapshot are removed, and
+        // the record of the snapshot isn't exported.
+        assert!(core.get_digests("initrd", None).await.unwrap().is_none());
+        assert!(core.get_digests("kernel", None).await.unwrap().is_some());
+        let exported = core.export_snapshot(&key).await.unwrap();
+        assert_eq!(
+            snapshot::import(&exported, &key.public_key()).unwrap().rvs,
+            [rv("kernel")]
+        );
+
+        // Snapshots older than the imported one are rejected.
+        let imported_at = core.snapshot_imported_at().await.unwrap().unwrap();
+        let record = rv(SNAPSHOT_RECORD)
+            .add_hash_value(SNAPSHOT_RECORD_ALG.into(), (imported_at + 60).to_string());
+        core.store
+            .set(SNAPSHOT_RECORD.into(), record)
+            .await
+            .unwrap();
+        assert!(core
+            .import_snapshot(&snapshot, &key.public_key())
+            .await
+            .is_err());
+    }
 }
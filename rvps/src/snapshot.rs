@@ -0,0 +1,192 @@
+// Copyright (c) 2024 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Snapshots of the reference values of a store, to carry them to an RVPS
+//! without access to the provenances, e.g. of an air-gapped AS.
+//!
+//! A snapshot is a gzipped tarball of two files:
+//! - `reference-values.json`: the JSON array of all the reference values.
+//! - `signature`: a JWT signed with Ed25519, whose claims carry the SHA-256
+//!   digest of `reference-values.json` and the number of reference values,
+//!   and whose `iat` is the time the snapshot was exported.
+
+use anyhow::{bail, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use jwt_simple::prelude::{
+    Claims, Duration, Ed25519KeyPair, Ed25519PublicKey, EdDSAKeyPairLike, EdDSAPublicKeyLike,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+
+use crate::ReferenceValue;
+
+/// Name of the reference values in a snapshot.
+const REFERENCE_VALUES_ENTRY: &str = "reference-values.json";
+
+/// Name of the signature in a snapshot.
+const SIGNATURE_ENTRY: &str = "signature";
+
+/// Largest file of a snapshot read.
+const MAX_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Claims signed by a snapshot.
+#[derive(Deserialize, Serialize)]
+struct SnapshotClaims {
+    /// Hex SHA-256 digest of the reference values.
+    sha256: String,
+
+    /// Number of reference values.
+    count: usize,
+}
+
+/// The reference values of a snapshot.
+pub struct Contents {
+    pub rvs: Vec<ReferenceValue>,
+
+    /// Seconds since the Unix epoch the snapshot was exported at.
+    pub exported_at: u64,
+}
+
+/// Pack `rvs` into a snapshot signed with `key`.
+pub fn export(rvs: &[ReferenceValue], key: &Ed25519KeyPair) -> Result<Vec<u8>> {
+    let content = serde_json::to_vec_pretty(rvs)?;
+    let claims = SnapshotClaims {
+        sha256: hex::encode(Sha256::digest(&content)),
+        count: rvs.len(),
+    };
+    // Snapshots are imported long after they are exported, so they don't
+    // expire.
+    let mut claims = Claims::with_custom_claims(claims, Duration::from_secs(0));
+    claims.expires_at = None;
+    let signature = key.sign(claims).context("sign snapshot")?;
+
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (name, data) in [
+        (REFERENCE_VALUES_ENTRY, &content[..]),
+        (SIGNATURE_ENTRY, signature.as_bytes()),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, name, data)?;
+    }
+    Ok(archive.into_inner()?.finish()?)
+}
+
+/// Unpack the reference values of `snapshot`, once its signature is verified
+/// with `public_key`.
+pub fn import(snapshot: &[u8], public_key: &Ed25519PublicKey) -> Result<Contents> {
+    let mut content = None;
+    let mut signature = None;
+    let mut archive = tar::Archive::new(GzDecoder::new(snapshot));
+    for entry in archive.entries().context("read snapshot")? {
+        let entry = entry.context("read snapshot")?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        let slot = match name.as_str() {
+            REFERENCE_VALUES_ENTRY => &mut content,
+            SIGNATURE_ENTRY => &mut signature,
+            _ => bail!("Unexpected file {name} in snapshot"),
+        };
+        if entry.size() > MAX_ENTRY_SIZE {
+            bail!("File {name} of snapshot is too large");
+        }
+        let mut data = Vec::new();
+        entry.take(MAX_ENTRY_SIZE).read_to_end(&mut data)?;
+        *slot = Some(data);
+    }
+    let content = content.context("No reference values in snapshot")?;
+    let signature = signature.context("No signature in snapshot")?;
+
+    let claims = public_key
+        .verify_token::<SnapshotClaims>(&String::from_utf8(signature)?, None)
+        .context("verify snapshot signature")?;
+    let exported_at = claims
+        .issued_at
+        .context("No export time in snapshot signature")?
+        .as_secs();
+    let claims = claims.custom;
+    if claims.sha256 != hex::encode(Sha256::digest(&content)) {
+        bail!("Snapshot reference values don't match the signature");
+    }
+
+    let rvs: Vec<ReferenceValue> =
+        serde_json::from_slice(&content).context("parse snapshot reference values")?;
+    if rvs.len() != claims.count {
+        bail!("Snapshot reference values don't match the signature");
+    }
+    Ok(Contents { rvs, exported_at })
+}
+
+/// Load the Ed25519 private key (PEM) signing snapshots.
+pub fn load_signing_key(path: &Path) -> Result<Ed25519KeyPair> {
+    let pem = std::fs::read_to_string(path)
+        .with_context(|| format!("read snapshot signing key {}", path.display()))?;
+    Ed25519KeyPair::from_pem(&pem).context("parse snapshot signing key")
+}
+
+/// Load the Ed25519 public key (PEM) verifying snapshots.
+pub fn load_public_key(path: &Path) -> Result<Ed25519PublicKey> {
+    let pem = std::fs::read_to_string(path)
+        .with_context(|| format!("read snapshot public key {}", path.display()))?;
+    Ed25519PublicKey::from_pem(&pem).context("parse snapshot public key")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rvs() -> Vec<ReferenceValue> {
+        ["kernel", "initrd"]
+            .iter()
+            .map(|name| {
+                ReferenceValue::new()
+                    .unwrap()
+                    .set_name(name)
+                    .add_hash_value("sha256".into(), "aa".into())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_export_import() {
+        let key = Ed25519KeyPair::generate();
+        let snapshot = export(&rvs(), &key).unwrap();
+        assert_eq!(import(&snapshot, &key.public_key()).unwrap().rvs, rvs());
+
+        // Snapshots of another signer are rejected.
+        let other = Ed25519KeyPair::generate();
+        assert!(import(&snapshot, &other.public_key()).is_err());
+        assert!(import(b"not a snapshot", &key.public_key()).is_err());
+    }
+
+    #[test]
+    fn test_tampered_snapshot() {
+        let key = Ed25519KeyPair::generate();
+        let snapshot = export(&rvs(), &key).unwrap();
+
+        // Replace the reference values, keeping the signature.
+        let mut archive = tar::Archive::new(GzDecoder::new(&snapshot[..]));
+        let mut tampered = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            if entry.path().unwrap().to_str() == Some(REFERENCE_VALUES_ENTRY) {
+                data = serde_json::to_vec(&rvs()[..1]).unwrap();
+            }
+            let mut header = entry.header().clone();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            tampered
+                .append_data(&mut header, entry.path().unwrap(), &data[..])
+                .unwrap();
+        }
+        let tampered = tampered.into_inner().unwrap().finish().unwrap();
+        assert!(import(&tampered, &key.public_key()).is_err());
+    }
+}
@@ -72,6 +72,30 @@ impl Store for LocalFs {
             None => Ok(None),
         }
     }
+
+    async fn delete(&self, name: &str) -> Result<Option<ReferenceValue>> {
+        let res = match self.engine.remove(name).context("remove from sled")? {
+            Some(v) => {
+                let v = serde_json::from_slice(&v)?;
+                Ok(Some(v))
+            }
+            None => Ok(None),
+        };
+
+        self.engine.flush()?;
+        res
+    }
+
+    async fn list(&self) -> Result<Vec<ReferenceValue>> {
+        self.engine
+            .iter()
+            .values()
+            .map(|v| {
+                let v = v.context("read from sled")?;
+                Ok(serde_json::from_slice(&v)?)
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -156,6 +180,39 @@ mod tests {
         }
     }
 
+    /// This test will test the `delete` interface
+    /// for [`LocalFs`].
+    #[tokio::test]
+    #[serial]
+    async fn delete() {
+        let temp_dir = tempfile::tempdir().expect("create tempdir failed");
+        let dir_str = temp_dir.path().to_string_lossy().to_string();
+        {
+            let store = LocalFs::new(json!({
+                "file_path": dir_str
+            }))
+            .expect("create local fs store failed.");
+            let rv = ReferenceValue::new().expect("create ReferenceValue failed.");
+            store
+                .set(KEY.to_owned(), rv.clone())
+                .await
+                .expect("set rv failed.");
+
+            let deleted = store
+                .delete(KEY)
+                .await
+                .expect("delete rv failed.")
+                .expect("delete None from LocalFs Store");
+            assert_eq!(deleted, rv);
+            assert!(store.get(KEY).await.expect("get rv failed.").is_none());
+            assert!(store
+                .delete(KEY)
+                .await
+                .expect("delete rv failed.")
+                .is_none());
+        }
+    }
+
     /// This test will simulate a restart operation
     /// for [`LocalFs`].
     #[tokio::test]
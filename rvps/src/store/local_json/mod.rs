@@ -71,4 +71,25 @@ impl Store for LocalJson {
         let rv = rvs.into_iter().find(|rv| rv.name == name);
         Ok(rv)
     }
+
+    async fn delete(&self, name: &str) -> Result<Option<ReferenceValue>> {
+        let _guard = self.lock.write().await;
+        let file = tokio::fs::read(&self.file_path).await?;
+        let mut rvs: Vec<ReferenceValue> = serde_json::from_slice(&file)?;
+        let Some(index) = rvs.iter().position(|rv| rv.name == name) else {
+            return Ok(None);
+        };
+        let rv = rvs.remove(index);
+
+        let contents = serde_json::to_vec(&rvs)?;
+        tokio::fs::write(&self.file_path, contents).await?;
+        Ok(Some(rv))
+    }
+
+    async fn list(&self) -> Result<Vec<ReferenceValue>> {
+        let _guard = self.lock.read().await;
+        let file = tokio::fs::read(&self.file_path).await?;
+        let rvs = serde_json::from_slice(&file)?;
+        Ok(rvs)
+    }
 }
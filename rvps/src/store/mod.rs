@@ -13,16 +13,19 @@ use strum::EnumString;
 
 use self::local_fs::LocalFs;
 use self::local_json::LocalJson;
+use self::snapshot::Snapshot;
 
 use super::ReferenceValue;
 
 pub mod local_fs;
 pub mod local_json;
+pub mod snapshot;
 
 #[derive(Deserialize, Debug, Clone, EnumString)]
 pub enum StoreType {
     LocalFs,
     LocalJson,
+    Snapshot,
 }
 
 impl StoreType {
@@ -34,6 +37,9 @@ impl StoreType {
             StoreType::LocalJson => {
                 Ok(Box::new(LocalJson::new(config)?) as Box<dyn Store + Send + Sync>)
             }
+            StoreType::Snapshot => {
+                Ok(Box::new(Snapshot::new(config)?) as Box<dyn Store + Send + Sync>)
+            }
         }
     }
 }
@@ -51,4 +57,11 @@ pub trait Store {
 
     // Retrieve a reference value
     async fn get(&self, name: &str) -> Result<Option<ReferenceValue>>;
+
+    /// Delete the reference value of the given `name`. Return the deleted
+    /// `Some<ReferenceValue>` if it existed, otherwise return `None`
+    async fn delete(&self, name: &str) -> Result<Option<ReferenceValue>>;
+
+    /// Retrieve all the reference values
+    async fn list(&self) -> Result<Vec<ReferenceValue>>;
 }
@@ -0,0 +1,106 @@
+// Copyright (c) 2024 Alibaba Cloud
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! This Store serves the reference values of a signed snapshot, read-only
+
+use anyhow::*;
+use async_trait::async_trait;
+use log::info;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::{snapshot, ReferenceValue};
+
+use super::Store;
+
+#[derive(Deserialize)]
+struct Config {
+    /// Path of the snapshot.
+    path: PathBuf,
+
+    /// Path of the Ed25519 public key (PEM) the snapshot is signed with.
+    public_key: PathBuf,
+}
+
+/// `Snapshot` implements [`Store`] trait with the reference values of a
+/// snapshot, loaded once and never modified.
+pub struct Snapshot {
+    rvs: HashMap<String, ReferenceValue>,
+}
+
+impl Snapshot {
+    /// Create a new [`Snapshot`] with given config
+    pub fn new(config: Value) -> Result<Self> {
+        let config: Config = serde_json::from_value(config)?;
+        let public_key = snapshot::load_public_key(&config.public_key)?;
+        let content = std::fs::read(&config.path)
+            .with_context(|| format!("read snapshot {}", config.path.display()))?;
+        let rvs: HashMap<_, _> = snapshot::import(&content, &public_key)?
+            .rvs
+            .into_iter()
+            .map(|rv| (rv.name.clone(), rv))
+            .collect();
+        info!(
+            "Loaded {} reference values from snapshot {}",
+            rvs.len(),
+            config.path.display()
+        );
+        Ok(Self { rvs })
+    }
+}
+
+#[async_trait]
+impl Store for Snapshot {
+    async fn set(&self, _name: String, _rv: ReferenceValue) -> Result<Option<ReferenceValue>> {
+        bail!("The reference values of a snapshot are read-only")
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<ReferenceValue>> {
+        Ok(self.rvs.get(name).cloned())
+    }
+
+    async fn delete(&self, _name: &str) -> Result<Option<ReferenceValue>> {
+        bail!("The reference values of a snapshot are read-only")
+    }
+
+    async fn list(&self) -> Result<Vec<ReferenceValue>> {
+        Ok(self.rvs.values().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jwt_simple::prelude::Ed25519KeyPair;
+    use serde_json::json;
+
+    use crate::{snapshot, ReferenceValue, Store};
+
+    use super::Snapshot;
+
+    #[tokio::test]
+    async fn read_only() {
+        let dir = tempfile::tempdir().expect("create tempdir failed");
+        let key = Ed25519KeyPair::generate();
+        let rv = ReferenceValue::new()
+            .expect("create ReferenceValue failed.")
+            .set_name("kernel");
+        let path = dir.path().join("snapshot.tar.gz");
+        let public_key = dir.path().join("public.pem");
+        std::fs::write(&path, snapshot::export(&[rv.clone()], &key).unwrap()).unwrap();
+        std::fs::write(&public_key, key.public_key().to_pem()).unwrap();
+
+        let store = Snapshot::new(json!({
+            "path": path,
+            "public_key": public_key,
+        }))
+        .expect("create snapshot store failed.");
+        assert_eq!(store.get("kernel").await.unwrap(), Some(rv.clone()));
+        assert!(store.get("initrd").await.unwrap().is_none());
+        assert!(store.set("kernel".into(), rv).await.is_err());
+        assert!(store.delete("kernel").await.is_err());
+    }
+}
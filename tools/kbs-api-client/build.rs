@@ -0,0 +1,22 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+fn main() -> Result<()> {
+    let description = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../kbs/docs/kbs.yaml");
+    println!("cargo:rerun-if-changed={}", description.display());
+
+    // The client covers the routes of every feature of KBS.
+    let spec = kbs_openapi::generate(
+        |_| true,
+        &std::fs::read_to_string(&description).context("read the KBS API description")?,
+    )?;
+    let client = kbs_openapi::client::generate(&spec.document)?;
+
+    let out_dir = std::env::var("OUT_DIR")?;
+    std::fs::write(Path::new(&out_dir).join("client.rs"), client)?;
+    Ok(())
+}
@@ -0,0 +1,105 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Typed client of the KBS HTTP API.
+//!
+//! The methods of [`Client`] and the types of their requests and responses
+//! are generated at build time from the OpenAPI document KBS serves at
+//! `/kbs/v0/openapi.json`, one method per operation, e.g.
+//! [`Client::set_attestation_policy`] for `POST /kbs/v0/attestation-policy`
+//! and [`Client::set_attestation_policy_for_tenant`] for the one of a tenant.
+
+use reqwest::StatusCode;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("KBS request failed")]
+    Request(#[from] reqwest::Error),
+
+    #[error("KBS answered {status}: {detail}")]
+    Status { status: StatusCode, detail: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Client of a KBS.
+#[derive(Clone, Debug)]
+pub struct Client {
+    base_url: String,
+    http: reqwest::Client,
+    token: Option<String>,
+}
+
+impl Client {
+    /// A client of the KBS at `base_url`, e.g. `https://kbs.example.com:8080`,
+    /// keeping the `kbs-session-id` cookie of its attestation session.
+    pub fn new(base_url: &str) -> Result<Self> {
+        let http = reqwest::Client::builder().cookie_store(true).build()?;
+        Ok(Self::with_http_client(base_url, http))
+    }
+
+    /// A client of the KBS at `base_url` sending its requests with `http`,
+    /// e.g. to trust the certificate of a KBS with a private CA.
+    pub fn with_http_client(base_url: &str, http: reqwest::Client) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http,
+            token: None,
+        }
+    }
+
+    /// Send the bearer `token` with every request, i.e. the admin token for
+    /// the admin APIs, or an attestation token for the resource APIs.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        // The errors of KBS are problem details with a `detail` member.
+        let body = response.text().await?;
+        let detail = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|error| error["detail"].as_str().map(str::to_string))
+            .unwrap_or(body);
+        Err(Error::Status { status, detail })
+    }
+}
+
+/// `value` percent-encoded as a segment of a path.
+fn segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+include!(concat!(env!("OUT_DIR"), "/client.rs"));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment() {
+        assert_eq!(segment("default"), "default");
+        assert_eq!(segment("cosign-public-key"), "cosign-public-key");
+        assert_eq!(segment("a b/c"), "a%20b%2Fc");
+    }
+}
@@ -12,7 +12,9 @@ use kbs_protocol::evidence_provider::NativeEvidenceProvider;
 use kbs_protocol::token_provider::TestTokenProvider;
 use kbs_protocol::KbsClientBuilder;
 use kbs_protocol::KbsClientCapabilities;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+pub mod provision;
 
 const KBS_URL_PREFIX: &str = "kbs/v0";
 
@@ -42,6 +44,83 @@ pub async fn attestation(
     Ok(token.content)
 }
 
+/// Decode the header and the claims of the attestation results token `token`,
+/// without verifying it.
+pub fn decode_token(token: &str) -> Result<(serde_json::Value, serde_json::Value)> {
+    let mut parts = token.split('.');
+    let mut decode = |name| -> Result<serde_json::Value> {
+        let part = parts
+            .next()
+            .ok_or_else(|| anyhow!("Illegal token format, missing the {name}"))?;
+        let part = URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|e| anyhow!("Illegal token {name} base64: {e}"))?;
+        serde_json::from_slice(&part).map_err(|e| anyhow!("Illegal token {name}: {e}"))
+    };
+    Ok((decode("header")?, decode("claims")?))
+}
+
+#[derive(Serialize)]
+struct IntrospectionInput {
+    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resource: Option<String>,
+}
+
+/// The resource policy result of a token introspection.
+#[derive(Debug, Deserialize)]
+pub struct IntrospectionPolicy {
+    pub resource: String,
+    pub allowed: bool,
+}
+
+/// The verdict of KBS on an attestation results token.
+#[derive(Debug, Deserialize)]
+pub struct Introspection {
+    /// Whether KBS would release resources for the token.
+    pub active: bool,
+
+    /// `valid`, `expired`, `stale`, `revoked` or `invalid`.
+    pub status: String,
+
+    /// The claims of a `valid` or `stale` token.
+    pub claims: Option<serde_json::Value>,
+
+    /// Why an `expired` or `invalid` token was rejected.
+    pub reason: Option<String>,
+
+    pub policy: Option<IntrospectionPolicy>,
+}
+
+/// Check attestation results token with KBS
+/// Input parameters:
+/// - url: KBS server root URL.
+/// - token: Attestation Results Token.
+/// - [resource]: Resource path, to evaluate the resource policy for with the claims of `token`.
+/// - kbs_root_certs_pem: Custom HTTPS root certificate of KBS server. It can be left blank.
+pub async fn introspect_token(
+    url: &str,
+    token: String,
+    resource: Option<String>,
+    kbs_root_certs_pem: Vec<String>,
+) -> Result<Introspection> {
+    let http_client = build_http_client(kbs_root_certs_pem)?;
+
+    let introspect_url = format!("{}/{KBS_URL_PREFIX}/introspect", url);
+    let res = http_client
+        .post(introspect_url)
+        .json(&IntrospectionInput { token, resource })
+        .send()
+        .await?;
+
+    match res.status() {
+        reqwest::StatusCode::OK => Ok(res.json().await?),
+        _ => {
+            bail!("Request Failed, Response: {:?}", res.text().await?)
+        }
+    }
+}
+
 /// Get secret resources with attestation results token
 /// Input parameters:
 /// - url: KBS server root URL.
@@ -126,9 +205,7 @@ pub async fn set_attestation_policy(
     policy_id: Option<String>,
     kbs_root_certs_pem: Vec<String>,
 ) -> Result<()> {
-    let auth_private_key = Ed25519KeyPair::from_pem(&auth_key)?;
-    let claims = Claims::create(Duration::from_hours(2));
-    let token = auth_private_key.sign(claims)?;
+    let token = admin_token(&auth_key, "policy-admin")?;
 
     let http_client = build_http_client(kbs_root_certs_pem)?;
 
@@ -155,6 +232,182 @@ pub async fn set_attestation_policy(
     }
 }
 
+/// An attestation policy of KBS with its digest.
+#[derive(Debug, Deserialize)]
+pub struct PolicyDigest {
+    #[serde(rename = "policy-id")]
+    pub policy_id: String,
+
+    /// Base64url encoded SHA-384 digest of the policy.
+    #[serde(rename = "policy-hash")]
+    pub policy_hash: String,
+}
+
+/// List attestation policies
+/// Input parameters:
+/// - url: KBS server root URL.
+/// - auth_key: KBS owner's authenticate private key (PEM string).
+/// - kbs_root_certs_pem: Custom HTTPS root certificate of KBS server. It can be left blank.
+pub async fn list_attestation_policies(
+    url: &str,
+    auth_key: String,
+    kbs_root_certs_pem: Vec<String>,
+) -> Result<Vec<PolicyDigest>> {
+    let token = admin_token(&auth_key, "auditor")?;
+
+    let http_client = build_http_client(kbs_root_certs_pem)?;
+
+    let policy_url = format!("{}/{KBS_URL_PREFIX}/attestation-policy", url);
+    let res = http_client
+        .get(policy_url)
+        .bearer_auth(token)
+        .send()
+        .await?;
+
+    match res.status() {
+        reqwest::StatusCode::OK => Ok(res.json().await?),
+        _ => {
+            bail!("Request Failed, Response: {:?}", res.text().await?)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GetPolicyOutput {
+    policy: String,
+}
+
+/// Get attestation policy
+/// Input parameters:
+/// - url: KBS server root URL.
+/// - auth_key: KBS owner's authenticate private key (PEM string).
+/// - policy_id: Policy ID.
+/// - kbs_root_certs_pem: Custom HTTPS root certificate of KBS server. It can be left blank.
+pub async fn get_attestation_policy(
+    url: &str,
+    auth_key: String,
+    policy_id: &str,
+    kbs_root_certs_pem: Vec<String>,
+) -> Result<Vec<u8>> {
+    let token = admin_token(&auth_key, "auditor")?;
+
+    let http_client = build_http_client(kbs_root_certs_pem)?;
+
+    let policy_url = format!("{}/{KBS_URL_PREFIX}/attestation-policy/{policy_id}", url);
+    let res = http_client
+        .get(policy_url)
+        .bearer_auth(token)
+        .send()
+        .await?;
+
+    match res.status() {
+        reqwest::StatusCode::OK => {
+            let output: GetPolicyOutput = res.json().await?;
+            Ok(URL_SAFE_NO_PAD.decode(output.policy)?)
+        }
+        _ => {
+            bail!("Request Failed, Response: {:?}", res.text().await?)
+        }
+    }
+}
+
+/// Remove attestation policy
+/// Input parameters:
+/// - url: KBS server root URL.
+/// - auth_key: KBS owner's authenticate private key (PEM string).
+/// - policy_id: Policy ID.
+/// - kbs_root_certs_pem: Custom HTTPS root certificate of KBS server. It can be left blank.
+pub async fn remove_attestation_policy(
+    url: &str,
+    auth_key: String,
+    policy_id: &str,
+    kbs_root_certs_pem: Vec<String>,
+) -> Result<()> {
+    let token = admin_token(&auth_key, "policy-admin")?;
+
+    let http_client = build_http_client(kbs_root_certs_pem)?;
+
+    let policy_url = format!("{}/{KBS_URL_PREFIX}/attestation-policy/{policy_id}", url);
+    let res = http_client
+        .delete(policy_url)
+        .bearer_auth(token)
+        .send()
+        .await?;
+
+    match res.status() {
+        reqwest::StatusCode::OK => Ok(()),
+        _ => {
+            bail!("Request Failed, Response: {:?}", res.text().await?)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VerifyEvidenceInput {
+    tee: String,
+    evidence: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    policy_id: Option<String>,
+}
+
+/// The outcome of the evaluation of evidence against an attestation policy.
+#[derive(Debug, Deserialize)]
+pub struct PolicyOutcome {
+    pub policy_id: String,
+
+    /// Hex encoded SHA-384 digest of the policy.
+    pub policy_hash: Option<String>,
+
+    pub matched: bool,
+}
+
+/// The result of a successful verification of evidence.
+#[derive(Debug, Deserialize)]
+pub struct VerifyEvidenceOutput {
+    pub policies: Vec<PolicyOutcome>,
+}
+
+/// Verify evidence against an attestation policy, without attesting
+/// Input parameters:
+/// - url: KBS server root URL.
+/// - auth_key: KBS owner's authenticate private key (PEM string).
+/// - tee: TEE type of the evidence, e.g. "tdx".
+/// - evidence: TEE evidence, like the `tee-evidence` of an attestation.
+/// - [policy_id]: Policy ID. Default value is the attestation policy of KBS.
+/// - kbs_root_certs_pem: Custom HTTPS root certificate of KBS server. It can be left blank.
+pub async fn verify_evidence(
+    url: &str,
+    auth_key: String,
+    tee: String,
+    evidence: String,
+    policy_id: Option<String>,
+    kbs_root_certs_pem: Vec<String>,
+) -> Result<VerifyEvidenceOutput> {
+    let token = admin_token(&auth_key, "auditor")?;
+
+    let http_client = build_http_client(kbs_root_certs_pem)?;
+
+    let verify_url = format!("{}/{KBS_URL_PREFIX}/verify", url);
+    let input = VerifyEvidenceInput {
+        tee,
+        evidence,
+        policy_id,
+    };
+    let res = http_client
+        .post(verify_url)
+        .bearer_auth(token)
+        .json(&input)
+        .send()
+        .await?;
+
+    match res.status() {
+        reqwest::StatusCode::OK => Ok(res.json().await?),
+        _ => {
+            bail!("Request Failed, Response: {:?}", res.text().await?)
+        }
+    }
+}
+
 #[derive(Clone, Serialize)]
 struct ResourcePolicyData {
     pub policy: String,
@@ -172,9 +425,7 @@ pub async fn set_resource_policy(
     policy_bytes: Vec<u8>,
     kbs_root_certs_pem: Vec<String>,
 ) -> Result<()> {
-    let auth_private_key = Ed25519KeyPair::from_pem(&auth_key)?;
-    let claims = Claims::create(Duration::from_hours(2));
-    let token = auth_private_key.sign(claims)?;
+    let token = admin_token(&auth_key, "policy-admin")?;
 
     let http_client = build_http_client(kbs_root_certs_pem)?;
 
@@ -211,9 +462,7 @@ pub async fn set_resource(
     path: &str,
     kbs_root_certs_pem: Vec<String>,
 ) -> Result<()> {
-    let auth_private_key = Ed25519KeyPair::from_pem(&auth_key)?;
-    let claims = Claims::create(Duration::from_hours(2));
-    let token = auth_private_key.sign(claims)?;
+    let token = admin_token(&auth_key, "resource-admin")?;
 
     let http_client = build_http_client(kbs_root_certs_pem)?;
 
@@ -233,6 +482,24 @@ pub async fn set_resource(
     }
 }
 
+/// Custom claims of a KBS admin token.
+#[derive(Serialize, Deserialize)]
+struct AdminClaims {
+    roles: Vec<String>,
+}
+
+/// Sign an admin token granting `role` with the KBS owner's private key.
+fn admin_token(auth_key: &str, role: &str) -> Result<String> {
+    let auth_private_key = Ed25519KeyPair::from_pem(auth_key)?;
+    let claims = Claims::with_custom_claims(
+        AdminClaims {
+            roles: vec![role.to_string()],
+        },
+        Duration::from_hours(2),
+    );
+    auth_private_key.sign(claims)
+}
+
 fn build_http_client(kbs_root_certs_pem: Vec<String>) -> Result<reqwest::Client> {
     let mut client_builder =
         reqwest::Client::builder().user_agent(format!("kbs-client/{}", env!("CARGO_PKG_VERSION")));
@@ -246,3 +513,183 @@ fn build_http_client(kbs_root_certs_pem: Vec<String>) -> Result<reqwest::Client>
         .build()
         .map_err(|e| anyhow!("Build KBS http client failed: {:?}", e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// Answer a single request with `status` and the JSON `body`. Returns the
+    /// URL of the server and the request it received.
+    async fn serve_once(status: &'static str, body: Value) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request);
+                let complete = text.split_once("\r\n\r\n").is_some_and(|(head, body)| {
+                    let length = head.lines().find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().unwrap())
+                    });
+                    body.len() >= length.unwrap_or(0)
+                });
+                if complete || read == 0 {
+                    break;
+                }
+            }
+
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 {status}\r\ncontent-type: application/json\r\n\
+                 content-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, request)
+    }
+
+    fn auth_key() -> String {
+        Ed25519KeyPair::generate().to_pem()
+    }
+
+    /// The roles of the admin token of `request`.
+    fn roles(request: &str) -> Value {
+        let token = request
+            .lines()
+            .find_map(|line| line.strip_prefix("authorization: Bearer "))
+            .unwrap();
+        let claims = token.split('.').nth(1).unwrap();
+        let claims: Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        claims["roles"].clone()
+    }
+
+    #[test]
+    fn test_decode_token() {
+        let token = format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"EdDSA"}"#),
+            URL_SAFE_NO_PAD.encode(r#"{"evaluation-reports":[]}"#),
+        );
+        let (header, claims) = decode_token(&token).unwrap();
+        assert_eq!(header, json!({"alg": "EdDSA"}));
+        assert_eq!(claims, json!({"evaluation-reports": []}));
+
+        let header = URL_SAFE_NO_PAD.encode("{}");
+        assert!(decode_token(&header).is_err());
+        assert!(decode_token(&format!("{header}.!!!")).is_err());
+        assert!(decode_token(&format!("{header}.{}", URL_SAFE_NO_PAD.encode("["))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_introspect_token() {
+        let (url, request) = serve_once(
+            "200 OK",
+            json!({
+                "active": false,
+                "status": "invalid",
+                "reason": "bad signature",
+                "policy": {"resource": "default/key/1", "allowed": false},
+            }),
+        )
+        .await;
+        let introspection =
+            introspect_token(&url, "token".into(), Some("default/key/1".into()), vec![])
+                .await
+                .unwrap();
+        assert!(!introspection.active);
+        assert_eq!(introspection.status, "invalid");
+        assert_eq!(introspection.reason.as_deref(), Some("bad signature"));
+        assert!(!introspection.policy.unwrap().allowed);
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /kbs/v0/introspect HTTP/1.1"));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(body).unwrap(),
+            json!({"token": "token", "resource": "default/key/1"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attestation_policies() {
+        let (url, request) = serve_once(
+            "200 OK",
+            json!([{"policy-id": "default", "policy-hash": "aGFzaA"}]),
+        )
+        .await;
+        let policies = list_attestation_policies(&url, auth_key(), vec![])
+            .await
+            .unwrap();
+        assert_eq!(policies.len(), 1);
+        assert_eq!(policies[0].policy_id, "default");
+        assert_eq!(policies[0].policy_hash, "aGFzaA");
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET /kbs/v0/attestation-policy HTTP/1.1"));
+        assert_eq!(roles(&request), json!(["auditor"]));
+
+        let (url, request) = serve_once(
+            "200 OK",
+            json!({"policy": URL_SAFE_NO_PAD.encode("package policy")}),
+        )
+        .await;
+        let policy = get_attestation_policy(&url, auth_key(), "default", vec![])
+            .await
+            .unwrap();
+        assert_eq!(policy, b"package policy");
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET /kbs/v0/attestation-policy/default HTTP/1.1"));
+        assert_eq!(roles(&request), json!(["auditor"]));
+
+        let (url, request) = serve_once("200 OK", json!(null)).await;
+        remove_attestation_policy(&url, auth_key(), "default", vec![])
+            .await
+            .unwrap();
+        let request = request.await.unwrap();
+        assert!(request.starts_with("DELETE /kbs/v0/attestation-policy/default HTTP/1.1"));
+        assert_eq!(roles(&request), json!(["policy-admin"]));
+
+        let (url, _) = serve_once("404 Not Found", json!({"detail": "no policy"})).await;
+        assert!(get_attestation_policy(&url, auth_key(), "missing", vec![])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_evidence() {
+        let (url, request) = serve_once(
+            "200 OK",
+            json!({
+                "token": "token",
+                "policies": [{"policy_id": "default", "policy_hash": null, "matched": true}],
+            }),
+        )
+        .await;
+        let output = verify_evidence(&url, auth_key(), "sample".into(), "{}".into(), None, vec![])
+            .await
+            .unwrap();
+        assert_eq!(output.token, "token");
+        assert!(output.policies[0].matched);
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /kbs/v0/verify HTTP/1.1"));
+        assert_eq!(roles(&request), json!(["auditor"]));
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(body).unwrap(),
+            json!({"tee": "sample", "evidence": "{}"})
+        );
+    }
+}
@@ -4,10 +4,12 @@
 
 //! A simple KBS client for test.
 
-use anyhow::{bail, Result};
-use base64::engine::general_purpose::STANDARD;
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
 use base64::Engine;
 use clap::{Args, Parser, Subcommand};
+use kbs_client::provision;
+use sha2::{Digest, Sha384};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -32,6 +34,10 @@ enum Commands {
     #[clap(arg_required_else_help = true)]
     Config(Config),
 
+    /// Manage the attestation policies of KBS
+    #[clap(arg_required_else_help = true)]
+    Policy(Policy),
+
     /// Get confidential resource
     #[clap(arg_required_else_help = true)]
     GetResource {
@@ -56,6 +62,27 @@ enum Commands {
         attestation_token: Option<PathBuf>,
     },
 
+    /// Attest, or take an existing attestation results token, and print the
+    /// claims of the token along with the verdict of KBS on it
+    Inspect {
+        /// Custom TEE private Key (RSA) file path (PEM format)
+        /// The public part of this key will be included in the token obtained by attestation.
+        ///
+        /// If not set this argument,
+        /// KBS client will generate a new TEE Key pair internally.
+        #[clap(long, value_parser, conflicts_with = "token_file")]
+        tee_key_file: Option<PathBuf>,
+
+        /// Attestation Token file path, to inspect instead of attesting
+        #[clap(long, value_parser)]
+        token_file: Option<PathBuf>,
+
+        /// KBS Resource path, e.g my_repo/resource_type/123abc
+        /// to evaluate the resource policy for with the claims of the token
+        #[clap(long, value_parser)]
+        resource: Option<String>,
+    },
+
     /// Attestation and get attestation results token
     Attest {
         /// Custom TEE private Key (RSA) file path (PEM format)
@@ -80,6 +107,72 @@ struct Config {
     auth_private_key: PathBuf,
 }
 
+#[derive(Args)]
+struct Policy {
+    #[clap(subcommand)]
+    command: PolicyCommands,
+
+    /// PEM file path of private key used to sign the admin tokens (JWT) of the requests.
+    /// This client tool only support ED22519 key now.
+    #[clap(long, value_parser)]
+    auth_private_key: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum PolicyCommands {
+    /// Set an attestation policy
+    Set {
+        /// Policy ID
+        #[clap(long, value_parser, default_value = "default")]
+        id: String,
+
+        /// Rego policy file path
+        #[clap(long, value_parser)]
+        policy_file: PathBuf,
+    },
+
+    /// Get an attestation policy
+    Get {
+        /// Policy ID
+        #[clap(long, value_parser, default_value = "default")]
+        id: String,
+
+        /// File to write the Rego policy to, instead of the standard output
+        #[clap(long, value_parser)]
+        output: Option<PathBuf>,
+    },
+
+    /// List the attestation policies with their digest
+    List,
+
+    /// Remove an attestation policy
+    Remove {
+        /// Policy ID
+        #[clap(long, value_parser)]
+        id: String,
+    },
+
+    /// Verify TEE evidence against an attestation policy, without attesting
+    Test {
+        /// Policy ID. The attestation policy of KBS when not set.
+        #[clap(long, value_parser)]
+        id: Option<String>,
+
+        /// TEE type of the evidence, e.g "tdx"
+        #[clap(long, value_parser)]
+        tee: String,
+
+        /// TEE evidence file path
+        #[clap(long, value_parser)]
+        evidence_file: PathBuf,
+    },
+}
+
+/// Format a SHA-384 `digest` like the evaluation reports of attestation tokens.
+fn format_digest(digest: &[u8]) -> String {
+    format!("sha384:{}", hex::encode(digest))
+}
+
 #[derive(Subcommand)]
 enum ConfigCommands {
     /// Set attestation verification policy
@@ -104,19 +197,40 @@ enum ConfigCommands {
         policy_file: PathBuf,
     },
 
-    /// Set confidential resource
+    /// Set confidential resources
     SetResource {
         /// KBS Resource path, e.g my_repo/resource_type/123abc
         /// Document: https://github.com/confidential-containers/attestation-agent/blob/main/docs/KBS_URI.md
-        #[clap(long, value_parser)]
-        path: String,
+        #[clap(long, value_parser, required_unless_present_any = ["dir", "manifest"], requires = "resource_file")]
+        path: Option<String>,
 
         /// Resource file path
-        #[clap(long, value_parser)]
-        resource_file: PathBuf,
+        #[clap(long, value_parser, requires = "path")]
+        resource_file: Option<PathBuf>,
+
+        /// Directory whose `<repository>/<type>/<tag>` files are set as the
+        /// resources of the same path
+        #[clap(long, value_parser, conflicts_with_all = ["path", "manifest"])]
+        dir: Option<PathBuf>,
+
+        /// JSON manifest listing the resources to set
+        #[clap(long, value_parser, conflicts_with = "path")]
+        manifest: Option<PathBuf>,
+
+        /// Value of a `{{NAME}}` placeholder of the manifest, as NAME=VALUE
+        #[clap(long = "var", value_parser = parse_var, requires = "manifest")]
+        vars: Vec<(String, String)>,
     },
 }
 
+/// Parse a NAME=VALUE template variable.
+fn parse_var(var: &str) -> Result<(String, String)> {
+    let (name, value) = var
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected NAME=VALUE, got {var}"))?;
+    Ok((name.to_string(), value.to_string()))
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
@@ -137,6 +251,52 @@ async fn main() -> Result<()> {
             let token = kbs_client::attestation(&cli.url, tee_key, kbs_cert.clone()).await?;
             println!("{token}");
         }
+        Commands::Inspect {
+            tee_key_file,
+            token_file,
+            resource,
+        } => {
+            let token = match token_file {
+                Some(f) => std::fs::read_to_string(f)?.trim().to_string(),
+                None => {
+                    let tee_key = match tee_key_file {
+                        Some(f) => Some(std::fs::read_to_string(f)?),
+                        None => None,
+                    };
+                    kbs_client::attestation(&cli.url, tee_key, kbs_cert.clone()).await?
+                }
+            };
+            println!("Token:\n{token}\n");
+
+            let (header, claims) = kbs_client::decode_token(&token)?;
+            println!("Header:\n{}\n", serde_json::to_string_pretty(&header)?);
+            println!("Claims:\n{}\n", serde_json::to_string_pretty(&claims)?);
+            if let Some(reports) = claims["evaluation-reports"].as_array() {
+                println!("Evaluation reports:");
+                for report in reports {
+                    println!(
+                        "  {} sha384:{}",
+                        report["policy-id"].as_str().unwrap_or_default(),
+                        report["policy-hash"].as_str().unwrap_or_default()
+                    );
+                }
+                println!();
+            }
+
+            let introspection =
+                kbs_client::introspect_token(&cli.url, token, resource, kbs_cert).await?;
+            match introspection.reason {
+                Some(reason) => println!("KBS verdict: {} ({reason})", introspection.status),
+                None => println!("KBS verdict: {}", introspection.status),
+            }
+            if let Some(policy) = introspection.policy {
+                let verdict = if policy.allowed { "allowed" } else { "denied" };
+                println!("Resource policy for {}: {verdict}", policy.resource);
+            }
+            if !introspection.active {
+                bail!("KBS would not release resources for the token");
+            }
+        }
         Commands::GetResource {
             path,
             tee_key_file,
@@ -175,6 +335,79 @@ async fn main() -> Result<()> {
                 println!("{}", STANDARD.encode(resource_bytes));
             }
         }
+        Commands::Policy(policy) => {
+            let auth_key = std::fs::read_to_string(policy.auth_private_key)?;
+            match policy.command {
+                PolicyCommands::Set { id, policy_file } => {
+                    let policy_bytes = std::fs::read(policy_file)?;
+                    let digest = Sha384::digest(&policy_bytes);
+                    kbs_client::set_attestation_policy(
+                        &cli.url,
+                        auth_key,
+                        policy_bytes,
+                        None,
+                        Some(id.clone()),
+                        kbs_cert,
+                    )
+                    .await?;
+                    println!("Set attestation policy {id} {}", format_digest(&digest));
+                }
+                PolicyCommands::Get { id, output } => {
+                    let policy_bytes =
+                        kbs_client::get_attestation_policy(&cli.url, auth_key, &id, kbs_cert)
+                            .await?;
+                    match output {
+                        Some(output) => std::fs::write(output, policy_bytes)?,
+                        None => print!("{}", String::from_utf8_lossy(&policy_bytes)),
+                    }
+                }
+                PolicyCommands::List => {
+                    let mut policies =
+                        kbs_client::list_attestation_policies(&cli.url, auth_key, kbs_cert).await?;
+                    policies.sort_by(|a, b| a.policy_id.cmp(&b.policy_id));
+                    let width = policies
+                        .iter()
+                        .map(|policy| policy.policy_id.len())
+                        .max()
+                        .unwrap_or_default();
+                    for policy in policies {
+                        let digest = URL_SAFE_NO_PAD
+                            .decode(&policy.policy_hash)
+                            .map(|digest| format_digest(&digest))
+                            .unwrap_or(policy.policy_hash);
+                        println!("{:width$}  {digest}", policy.policy_id);
+                    }
+                }
+                PolicyCommands::Remove { id } => {
+                    kbs_client::remove_attestation_policy(&cli.url, auth_key, &id, kbs_cert)
+                        .await?;
+                    println!("Removed attestation policy {id}");
+                }
+                PolicyCommands::Test {
+                    id,
+                    tee,
+                    evidence_file,
+                } => {
+                    let evidence = std::fs::read_to_string(evidence_file)?;
+                    let output = kbs_client::verify_evidence(
+                        &cli.url, auth_key, tee, evidence, id, kbs_cert,
+                    )
+                    .await?;
+                    for policy in output.policies {
+                        let digest = policy
+                            .policy_hash
+                            .map(|hash| format!(" sha384:{hash}"))
+                            .unwrap_or_default();
+                        let outcome = if policy.matched {
+                            "matched"
+                        } else {
+                            "not matched"
+                        };
+                        println!("{}{digest}: {outcome}", policy.policy_id);
+                    }
+                }
+            }
+        }
         Commands::Config(config) => {
             let auth_key = std::fs::read_to_string(config.auth_private_key)?;
             match config.command {
@@ -213,9 +446,43 @@ async fn main() -> Result<()> {
                     );
                 }
                 ConfigCommands::SetResource {
-                    path,
+                    path: None,
+                    dir,
+                    manifest,
+                    vars,
+                    ..
+                } => {
+                    let resources = match (dir, manifest) {
+                        (Some(dir), _) => provision::resources_from_dir(&dir)?,
+                        (None, Some(manifest)) => provision::resources_from_manifest(
+                            &manifest,
+                            &vars.into_iter().collect(),
+                        )?,
+                        (None, None) => {
+                            bail!("One of `--path`, `--dir` or `--manifest` is required")
+                        }
+                    };
+                    for resource in &resources {
+                        kbs_client::set_resource(
+                            &cli.url,
+                            auth_key.clone(),
+                            resource.data.clone(),
+                            &resource.path,
+                            kbs_cert.clone(),
+                        )
+                        .await
+                        .with_context(|| format!("Set resource {}", resource.path))?;
+                        println!("Set resource {}", resource.path);
+                    }
+                    println!("Set {} resources success", resources.len());
+                }
+                ConfigCommands::SetResource {
+                    path: Some(path),
                     resource_file,
+                    ..
                 } => {
+                    let resource_file =
+                        resource_file.ok_or_else(|| anyhow!("`--resource-file` is required"))?;
                     let resource_bytes = std::fs::read(resource_file)?;
                     kbs_client::set_resource(
                         &cli.url,
@@ -236,3 +503,73 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_policy_commands() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from([
+            "kbs-client",
+            "policy",
+            "--auth-private-key",
+            "key.pem",
+            "test",
+            "--tee",
+            "tdx",
+            "--evidence-file",
+            "evidence.json",
+        ])
+        .unwrap();
+        let Commands::Policy(policy) = cli.command else {
+            panic!("not a policy command");
+        };
+        assert!(matches!(
+            policy.command,
+            PolicyCommands::Test { id: None, tee, .. } if tee == "tdx"
+        ));
+
+        // The policies are removed by ID only.
+        assert!(Cli::try_parse_from([
+            "kbs-client",
+            "policy",
+            "--auth-private-key",
+            "key.pem",
+            "remove",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_inspect_command() {
+        let cli = Cli::try_parse_from(["kbs-client", "inspect", "--token-file", "token"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Commands::Inspect {
+                token_file: Some(_),
+                tee_key_file: None,
+                resource: None
+            }
+        ));
+
+        // A token is either attested with the TEE key or read from a file.
+        assert!(Cli::try_parse_from([
+            "kbs-client",
+            "inspect",
+            "--token-file",
+            "token",
+            "--tee-key-file",
+            "key.pem",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_format_digest() {
+        assert_eq!(format_digest(&[0x01, 0xab]), "sha384:01ab");
+    }
+}
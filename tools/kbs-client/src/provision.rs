@@ -0,0 +1,262 @@
+// Copyright (c) 2024 by Alibaba.
+// Licensed under the Apache License, Version 2.0, see LICENSE for details.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bulk provisioning of KBS resources.
+//!
+//! Resources are collected from a directory tree laid out as
+//! `<repository>/<type>/<tag>` files, or from a manifest listing them. All of
+//! them are read and checked before any is uploaded.
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A resource to upload to KBS.
+#[derive(Debug, PartialEq)]
+pub struct Resource {
+    /// Resource path, `<repository>/<type>/<tag>`.
+    pub path: String,
+
+    pub data: Vec<u8>,
+}
+
+/// Check that `path` is a `<repository>/<type>/<tag>` resource path.
+fn check_path(path: &str) -> Result<()> {
+    let segments: Vec<&str> = path.split('/').collect();
+    if segments.len() != 3
+        || segments
+            .iter()
+            .any(|segment| segment.is_empty() || *segment == "." || *segment == "..")
+    {
+        bail!("Illegal resource path {path}, expected <repository>/<type>/<tag>");
+    }
+    Ok(())
+}
+
+/// Collect the files of the directory tree `dir` as the resources of their
+/// path relative to `dir`. Hidden files and directories are skipped.
+pub fn resources_from_dir(dir: &Path) -> Result<Vec<Resource>> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    files.sort();
+
+    files
+        .into_iter()
+        .map(|file| {
+            let relative = file.strip_prefix(dir)?;
+            let path = relative
+                .iter()
+                .map(|segment| {
+                    segment
+                        .to_str()
+                        .ok_or_else(|| anyhow!("Non UTF-8 file name {}", file.display()))
+                })
+                .collect::<Result<Vec<_>>>()?
+                .join("/");
+            check_path(&path).with_context(|| format!("File {}", file.display()))?;
+            let data = std::fs::read(&file).with_context(|| format!("Read {}", file.display()))?;
+            Ok(Resource { path, data })
+        })
+        .collect()
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Read directory {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = entry.path();
+        if std::fs::metadata(&path)?.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// A resource provisioning manifest.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// Default values of the template variables.
+    #[serde(default)]
+    vars: HashMap<String, String>,
+
+    resources: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestEntry {
+    path: String,
+
+    /// File holding the resource, relative to the manifest.
+    file: Option<String>,
+
+    /// The resource itself.
+    value: Option<String>,
+}
+
+/// Replace the `{{name}}` placeholders of `template` with the value of the
+/// variable `name`.
+fn render(template: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| anyhow!("Unterminated placeholder in {template}"))?;
+        let name = rest[start + 2..start + end].trim();
+        let value = vars
+            .get(name)
+            .ok_or_else(|| anyhow!("Undefined variable {name} in {template}"))?;
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Collect the resources listed by the JSON manifest `manifest`:
+///
+/// ```json
+/// {
+///     "vars": {"env": "staging"},
+///     "resources": [
+///         {"path": "my_repo/key/{{env}}", "file": "keys/{{env}}.pem"},
+///         {"path": "my_repo/config/{{env}}", "value": "endpoint={{endpoint}}"}
+///     ]
+/// }
+/// ```
+///
+/// The `{{name}}` placeholders of the paths, files and values are replaced by
+/// the variables of `vars`, or else by the ones of the manifest.
+pub fn resources_from_manifest(
+    manifest: &Path,
+    vars: &HashMap<String, String>,
+) -> Result<Vec<Resource>> {
+    let content = std::fs::read_to_string(manifest)
+        .with_context(|| format!("Read manifest {}", manifest.display()))?;
+    let mut parsed: Manifest = serde_json::from_str(&content)
+        .with_context(|| format!("Parse manifest {}", manifest.display()))?;
+    parsed.vars.extend(vars.clone());
+    let base = manifest.parent().unwrap_or(Path::new(""));
+
+    parsed
+        .resources
+        .iter()
+        .map(|entry| {
+            let path = render(&entry.path, &parsed.vars)?;
+            check_path(&path)?;
+            let data = match (&entry.file, &entry.value) {
+                (Some(file), None) => {
+                    let file = base.join(render(file, &parsed.vars)?);
+                    std::fs::read(&file).with_context(|| format!("Read {}", file.display()))?
+                }
+                (None, Some(value)) => render(value, &parsed.vars)?.into_bytes(),
+                _ => bail!("Resource {path} needs either a file or a value"),
+            };
+            Ok(Resource { path, data })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_path() {
+        assert!(check_path("repo/type/tag").is_ok());
+        for path in ["type/tag", "a/b/c/d", "repo//tag", "repo/../tag", ""] {
+            assert!(check_path(path).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let vars = HashMap::from([("env".to_string(), "prod".to_string())]);
+        assert_eq!(
+            render("key/{{env}}-{{ env }}", &vars).unwrap(),
+            "key/prod-prod"
+        );
+        assert_eq!(render("no placeholder", &vars).unwrap(), "no placeholder");
+        assert!(render("{{unknown}}", &vars).is_err());
+        assert!(render("{{env", &vars).is_err());
+    }
+
+    #[test]
+    fn test_resources_from_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("repo/key")).unwrap();
+        std::fs::create_dir_all(dir.path().join("repo/.git")).unwrap();
+        std::fs::write(dir.path().join("repo/key/b"), "b").unwrap();
+        std::fs::write(dir.path().join("repo/key/a"), "a").unwrap();
+        std::fs::write(dir.path().join("repo/key/.hidden"), "").unwrap();
+        std::fs::write(dir.path().join("repo/.git/config"), "").unwrap();
+
+        let resources = resources_from_dir(dir.path()).unwrap();
+        assert_eq!(
+            resources,
+            vec![
+                Resource {
+                    path: "repo/key/a".into(),
+                    data: b"a".to_vec(),
+                },
+                Resource {
+                    path: "repo/key/b".into(),
+                    data: b"b".to_vec(),
+                },
+            ]
+        );
+
+        std::fs::write(dir.path().join("repo/misplaced"), "").unwrap();
+        assert!(resources_from_dir(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_resources_from_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("keys")).unwrap();
+        std::fs::write(dir.path().join("keys/prod.pem"), "key").unwrap();
+        let manifest = dir.path().join("manifest.json");
+        std::fs::write(
+            &manifest,
+            r#"{
+                "vars": {"env": "staging", "endpoint": "kbs"},
+                "resources": [
+                    {"path": "repo/key/{{env}}", "file": "keys/{{env}}.pem"},
+                    {"path": "repo/config/{{env}}", "value": "endpoint={{endpoint}}"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        // Missing keys/staging.pem
+        assert!(resources_from_manifest(&manifest, &HashMap::new()).is_err());
+
+        let vars = HashMap::from([("env".to_string(), "prod".to_string())]);
+        let resources = resources_from_manifest(&manifest, &vars).unwrap();
+        assert_eq!(
+            resources,
+            vec![
+                Resource {
+                    path: "repo/key/prod".into(),
+                    data: b"key".to_vec(),
+                },
+                Resource {
+                    path: "repo/config/prod".into(),
+                    data: b"endpoint=kbs".to_vec(),
+                },
+            ]
+        );
+    }
+}